@@ -0,0 +1,159 @@
+//! Access rules for clients, by their headers
+//!
+//! Operators may refuse websocket connections whose Origin or
+//! User-Agent matches a pattern, such as those of known scrapers, or
+//! limit how often such clients connect.  Rules are checked in order
+//! when a connection is upgraded, and the first matching rule applies.
+use crate::config::ClientRule;
+use crate::spam::wildcard_to_regex;
+use governor::{Quota, RateLimiter};
+use regex::Regex;
+use std::num::NonZeroU32;
+use std::sync::RwLock;
+
+type DirectLimiter = RateLimiter<
+    governor::state::NotKeyed,
+    governor::state::InMemoryState,
+    governor::clock::DefaultClock,
+>;
+
+/// Why a client was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    /// The client matched a rule refusing it
+    Blocked,
+    /// Clients matching a rule are connecting too often
+    RateLimited,
+}
+
+impl Refusal {
+    /// Label for metrics
+    #[must_use]
+    pub fn label(&self) -> &'static str {
+        match self {
+            Refusal::Blocked => "client_blocked",
+            Refusal::RateLimited => "client_rate_limited",
+        }
+    }
+
+    /// Explanation for the client
+    #[must_use]
+    pub fn message(&self) -> &'static str {
+        match self {
+            Refusal::Blocked => "this client is not allowed to connect",
+            Refusal::RateLimited => "too many connections from this client, try again later",
+        }
+    }
+}
+
+struct Rule {
+    origin: Option<Regex>,
+    user_agent: Option<Regex>,
+    /// Shared by every matching client; none are allowed if not set
+    limiter: Option<DirectLimiter>,
+}
+
+impl Rule {
+    fn matches(&self, origin: Option<&str>, user_agent: Option<&str>) -> bool {
+        let header = |pattern: &Option<Regex>, value: Option<&str>| match pattern {
+            Some(re) => value.is_some_and(|v| re.is_match(v)),
+            None => true,
+        };
+        header(&self.origin, origin) && header(&self.user_agent, user_agent)
+    }
+}
+
+fn pattern(wildcard: Option<&String>) -> Option<Regex> {
+    wildcard.and_then(|w| Regex::new(&wildcard_to_regex(w)).ok())
+}
+
+fn build_rules(rules: &[ClientRule]) -> Vec<Rule> {
+    rules
+        .iter()
+        .filter(|r| r.origin.is_some() || r.user_agent.is_some())
+        .map(|r| Rule {
+            origin: pattern(r.origin.as_ref()),
+            user_agent: pattern(r.user_agent.as_ref()),
+            limiter: r
+                .connections_per_min
+                .and_then(NonZeroU32::new)
+                .map(|n| RateLimiter::direct(Quota::per_minute(n))),
+        })
+        .collect()
+}
+
+/// Rules for admitting clients by their headers.
+pub struct AccessRules {
+    rules: RwLock<Vec<Rule>>,
+}
+
+impl AccessRules {
+    #[must_use]
+    pub fn new(rules: &[ClientRule]) -> Self {
+        AccessRules {
+            rules: RwLock::new(build_rules(rules)),
+        }
+    }
+
+    /// Replace the rules, when settings are reloaded.  Counts start
+    /// again from zero.
+    pub fn set_rules(&self, rules: &[ClientRule]) {
+        *self.rules.write().unwrap() = build_rules(rules);
+    }
+
+    /// Check a client's headers against the first rule they match,
+    /// counting the connection if that rule limits them.
+    pub fn check(&self, origin: Option<&str>, user_agent: Option<&str>) -> Result<(), Refusal> {
+        let rules = self.rules.read().unwrap();
+        match rules.iter().find(|r| r.matches(origin, user_agent)) {
+            None => Ok(()),
+            Some(Rule { limiter: None, .. }) => Err(Refusal::Blocked),
+            Some(Rule {
+                limiter: Some(lim), ..
+            }) => lim.check().map_err(|_| Refusal::RateLimited),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(origin: Option<&str>, user_agent: Option<&str>, per_min: Option<u32>) -> ClientRule {
+        ClientRule {
+            origin: origin.map(str::to_owned),
+            user_agent: user_agent.map(str::to_owned),
+            connections_per_min: per_min,
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_applies() {
+        let rules = AccessRules::new(&[
+            rule(None, Some("python-requests"), None),
+            rule(Some("https://*.example.com"), Some("bot"), Some(2)),
+            rule(Some("https://app.example.com"), None, None),
+        ]);
+        assert_eq!(
+            rules.check(None, Some("Python-Requests/2.31")),
+            Err(Refusal::Blocked)
+        );
+        assert_eq!(rules.check(None, None), Ok(()));
+        assert_eq!(
+            rules.check(Some("https://nostr.com"), Some("Mozilla/5.0")),
+            Ok(())
+        );
+        // limited clients share a rate
+        let bot = (Some("https://app.example.com"), Some("SearchBot/1.0"));
+        assert_eq!(rules.check(bot.0, bot.1), Ok(()));
+        assert_eq!(rules.check(bot.0, bot.1), Ok(()));
+        assert_eq!(rules.check(bot.0, bot.1), Err(Refusal::RateLimited));
+        // without the user agent, the origin is blocked by a later rule
+        assert_eq!(
+            rules.check(bot.0, Some("Mozilla/5.0")),
+            Err(Refusal::Blocked)
+        );
+        rules.set_rules(&[]);
+        assert_eq!(rules.check(None, Some("python-requests")), Ok(()));
+    }
+}
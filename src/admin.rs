@@ -0,0 +1,345 @@
+//! Relay administration by direct message
+//!
+//! Admins send commands as encrypted direct messages (NIP-04) to the
+//! relay's own pubkey, from a connection authenticated (NIP-42) as
+//! themselves.  The relay runs each command, and replies with a
+//! direct message that is stored and broadcast like any other event.
+use crate::bans::{Ban, BanRegistry, BanTarget};
+use crate::config::Settings;
+use crate::error::{Error, Result};
+use crate::event::{BroadcastEvent, Event};
+use crate::identity::RelayIdentity;
+use crate::maintenance;
+use crate::nip04::{self, KIND_DM};
+use crate::repo::NostrRepo;
+use crate::server::NostrMetrics;
+use crate::utils::{is_lower_hex, is_nip19, nip19_to_hex, unix_time};
+use crate::whitelist::{parse_pubkey, Whitelist};
+use secp256k1::{KeyPair, Secp256k1, SecretKey, XOnlyPublicKey};
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+const HELP: &str = "commands:
+ban <pubkey or IP address> [seconds] [reason]
+unban <pubkey or IP address>
+allow <pubkey>
+disallow <pubkey>
+delete <event id>
+announce <text>
+stats
+vacuum
+help";
+
+/// An admin command
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Command {
+    Ban {
+        target: BanTarget,
+        value: String,
+        seconds: Option<u64>,
+        reason: String,
+    },
+    Unban {
+        target: BanTarget,
+        value: String,
+    },
+    /// Add a pubkey to the whitelist
+    Allow(String),
+    /// Remove a pubkey from the whitelist
+    Disallow(String),
+    Delete(String),
+    /// Publish a note from the relay
+    Announce(String),
+    Stats,
+    /// Reclaim free space in the database
+    Vacuum,
+    Help,
+}
+
+/// A pubkey (hex, or npub) or IP address to ban.
+///
+/// # Errors
+///
+/// Will return `Err` with a message if the value is neither.
+pub fn ban_target(value: &str) -> std::result::Result<(BanTarget, String), String> {
+    if value.len() == 64 && is_lower_hex(value) {
+        Ok((BanTarget::Pubkey, value.to_owned()))
+    } else if is_nip19(value) {
+        nip19_to_hex(value)
+            .map(|pk| (BanTarget::Pubkey, pk))
+            .map_err(|_| format!("invalid pubkey: {value}"))
+    } else if let Ok(ip) = value.parse::<IpAddr>() {
+        Ok((BanTarget::Ip, ip.to_string()))
+    } else {
+        Err(format!("not a pubkey or IP address: {value}"))
+    }
+}
+
+impl Command {
+    /// Parse a command, or explain why it is invalid.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` with a message for the admin if the command
+    /// is unknown or malformed.
+    pub fn parse(text: &str) -> std::result::Result<Command, String> {
+        let mut words = text.split_whitespace();
+        let name = words.next().unwrap_or_default().to_lowercase();
+        let mut arg = |what: &str| words.next().ok_or_else(|| format!("missing {what}"));
+        match name.as_str() {
+            "ban" => {
+                let (target, value) = ban_target(arg("pubkey or IP address")?)?;
+                let mut rest: Vec<&str> = words.collect();
+                let seconds = rest.first().and_then(|s| s.parse::<u64>().ok());
+                if seconds.is_some() {
+                    rest.remove(0);
+                }
+                Ok(Command::Ban {
+                    target,
+                    value,
+                    seconds,
+                    reason: rest.join(" "),
+                })
+            }
+            "unban" => {
+                let (target, value) = ban_target(arg("pubkey or IP address")?)?;
+                Ok(Command::Unban { target, value })
+            }
+            "allow" => Ok(Command::Allow(parse_pubkey(arg("pubkey")?)?)),
+            "disallow" => Ok(Command::Disallow(parse_pubkey(arg("pubkey")?)?)),
+            "delete" => {
+                let id = arg("event id")?;
+                if id.len() != 64 || !is_lower_hex(id) {
+                    return Err(format!("invalid event id: {id}"));
+                }
+                Ok(Command::Delete(id.to_owned()))
+            }
+            "announce" => {
+                let note = text.trim().split_once(char::is_whitespace).map(|(_, t)| t.trim());
+                match note {
+                    Some(note) if !note.is_empty() => Ok(Command::Announce(note.to_owned())),
+                    _ => Err("missing text".to_owned()),
+                }
+            }
+            "stats" => Ok(Command::Stats),
+            "vacuum" => Ok(Command::Vacuum),
+            "help" | "" => Ok(Command::Help),
+            other => Err(format!("unknown command: {other}")),
+        }
+    }
+}
+
+/// The relay's keypair, and the admins it takes commands from.
+pub struct AdminChannel {
+    keypair: KeyPair,
+    secret: SecretKey,
+    pubkey: String,
+    admins: HashSet<String>,
+    repo: Arc<dyn NostrRepo>,
+    bans: Arc<BanRegistry>,
+    whitelist: Arc<Whitelist>,
+    metrics: NostrMetrics,
+    bcast_tx: broadcast::Sender<BroadcastEvent>,
+    identity: Option<Arc<RelayIdentity>>,
+}
+
+impl AdminChannel {
+    /// Create the channel, if a relay secret key and admins are
+    /// configured.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the secret key is invalid.
+    pub fn new(
+        settings: &Settings,
+        repo: Arc<dyn NostrRepo>,
+        bans: Arc<BanRegistry>,
+        whitelist: Arc<Whitelist>,
+        metrics: NostrMetrics,
+        bcast_tx: broadcast::Sender<BroadcastEvent>,
+        identity: Option<Arc<RelayIdentity>>,
+    ) -> Result<Option<AdminChannel>> {
+        let (Some(key), Some(admins)) = (settings.identity.key(&settings.admin), &settings.admin.pubkeys) else {
+            return Ok(None);
+        };
+        let secret = SecretKey::from_str(key)
+            .map_err(|_| Error::CustomError("invalid admin secret key".to_owned()))?;
+        let keypair = KeyPair::from_secret_key(&Secp256k1::signing_only(), secret);
+        Ok(Some(AdminChannel {
+            keypair,
+            secret,
+            pubkey: XOnlyPublicKey::from_keypair(&keypair).to_string(),
+            admins: admins.iter().cloned().collect(),
+            repo,
+            bans,
+            whitelist,
+            metrics,
+            bcast_tx,
+            identity,
+        }))
+    }
+
+    /// The relay's pubkey, which commands are sent to.
+    #[must_use]
+    pub fn pubkey(&self) -> &str {
+        &self.pubkey
+    }
+
+    /// Check if an event is a command from an admin.
+    #[must_use]
+    pub fn is_command(&self, event: &Event) -> bool {
+        event.kind == KIND_DM
+            && self.admins.contains(&event.pubkey)
+            && event.tag_values_by_name("p").contains(&self.pubkey)
+    }
+
+    /// Run a command, and reply to the admin that sent it.
+    pub async fn handle(&self, event: &Event) {
+        let response = match nip04::decrypt(&self.secret, &event.pubkey, &event.content) {
+            Ok(text) => match Command::parse(&text) {
+                Ok(cmd) => {
+                    info!("admin {:?} sent command: {:?}", event.get_author_prefix(), cmd);
+                    self.run(cmd).await
+                }
+                Err(msg) => msg,
+            },
+            Err(_) => "could not decrypt command".to_owned(),
+        };
+        if let Err(e) = self.reply(event, &response).await {
+            warn!("could not reply to admin command: {:?}", e);
+        }
+    }
+
+    async fn run(&self, cmd: Command) -> String {
+        match cmd {
+            Command::Ban {
+                target,
+                value,
+                seconds,
+                reason,
+            } => {
+                let ban = Ban {
+                    target,
+                    value,
+                    expires_at: seconds.map(|s| unix_time() + s),
+                    reason,
+                };
+                self.store_ban(ban, "banned").await
+            }
+            Command::Unban { target, value } => {
+                // a ban that has already expired replaces the old one.
+                let ban = Ban {
+                    target,
+                    value,
+                    expires_at: Some(unix_time()),
+                    reason: String::new(),
+                };
+                self.store_ban(ban, "unbanned").await
+            }
+            Command::Allow(pubkey) => match self.whitelist.set_allowed(&pubkey, true).await {
+                Ok(()) => format!("allowed {pubkey}"),
+                Err(e) => format!("could not allow pubkey: {e}"),
+            },
+            Command::Disallow(pubkey) => match self.whitelist.set_allowed(&pubkey, false).await {
+                Ok(()) => format!("disallowed {pubkey}"),
+                Err(e) => format!("could not disallow pubkey: {e}"),
+            },
+            Command::Delete(id) => match self.repo.delete_events(&[id]).await {
+                Ok(n) => format!("deleted {n} events"),
+                Err(e) => format!("could not delete event: {e}"),
+            },
+            Command::Announce(note) => match &self.identity {
+                Some(identity) => match identity.announce(&note).await {
+                    Ok(id) => format!("announced {id}"),
+                    Err(e) => format!("could not announce: {e}"),
+                },
+                None => "the relay has no identity to announce as".to_owned(),
+            },
+            Command::Stats => {
+                let bytes = match self.repo.used_bytes().await {
+                    Ok(b) => b.to_string(),
+                    Err(_) => "unknown".to_owned(),
+                };
+                format!(
+                    "connections: {}\nevents received: {}\ndatabase bytes: {}",
+                    self.metrics.connections.get(),
+                    self.metrics.cmd_event.get(),
+                    bytes
+                )
+            }
+            Command::Vacuum => match maintenance::vacuum(self.repo.as_ref(), &self.metrics).await {
+                Ok(bytes) => format!("vacuum released {bytes} bytes"),
+                Err(e) => format!("could not vacuum: {e}"),
+            },
+            Command::Help => HELP.to_owned(),
+        }
+    }
+
+    async fn store_ban(&self, ban: Ban, done: &str) -> String {
+        match self.repo.add_ban(&ban).await {
+            Ok(()) => {
+                let msg = format!("{done} {} {}", ban.target.as_str(), ban.value);
+                self.bans.add(ban);
+                msg
+            }
+            Err(e) => format!("could not store ban: {e}"),
+        }
+    }
+
+    /// Send an encrypted reply to a command.
+    async fn reply(&self, cmd: &Event, text: &str) -> Result<()> {
+        let tags = vec![
+            vec!["p".to_owned(), cmd.pubkey.clone()],
+            vec!["e".to_owned(), cmd.id.clone()],
+        ];
+        let content = nip04::encrypt(&self.secret, &cmd.pubkey, text)?;
+        let reply = Event::new_signed(&self.keypair, KIND_DM, tags, content);
+        self.repo.write_event(&reply).await?;
+        self.bcast_tx.send(reply.into()).ok();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUBKEY: &str = "35d26e4690cbe1a898af61cc3515661eb5fa763b57bd0b42e45099c8b32fd50f";
+
+    #[test]
+    fn commands_are_parsed() {
+        assert_eq!(
+            Command::parse(&format!("ban {PUBKEY} 3600 spamming replies")),
+            Ok(Command::Ban {
+                target: BanTarget::Pubkey,
+                value: PUBKEY.to_owned(),
+                seconds: Some(3600),
+                reason: "spamming replies".to_owned(),
+            })
+        );
+        assert_eq!(
+            Command::parse("  UNBAN 10.0.0.1 "),
+            Ok(Command::Unban {
+                target: BanTarget::Ip,
+                value: "10.0.0.1".to_owned(),
+            })
+        );
+        assert_eq!(Command::parse(&format!("allow {PUBKEY}")), Ok(Command::Allow(PUBKEY.to_owned())));
+        assert!(Command::parse("disallow 10.0.0.1").is_err());
+        assert_eq!(Command::parse(""), Ok(Command::Help));
+        assert_eq!(Command::parse("vacuum"), Ok(Command::Vacuum));
+        assert_eq!(
+            Command::parse("announce  Maintenance at 02:00  UTC "),
+            Ok(Command::Announce("Maintenance at 02:00  UTC".to_owned()))
+        );
+        assert!(Command::parse("announce ").is_err());
+        assert!(Command::parse("ban").is_err());
+        assert!(Command::parse("ban example.com").is_err());
+        assert!(Command::parse("delete 1234").is_err());
+        assert!(Command::parse("reboot").is_err());
+    }
+}
@@ -0,0 +1,204 @@
+//! Scheduled announcements
+//!
+//! Operators may have the relay publish events, such as maintenance
+//! notices or its rules, on a recurring schedule.  Schedules are
+//! written as for cron, and checked at the start of each minute
+//! (UTC).  Announcements are signed with the relay's keypair.
+use crate::config::{Announcement, Settings};
+use crate::identity::RelayIdentity;
+use crate::utils::unix_time;
+use chrono::{Datelike, TimeZone, Timelike, Utc};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+/// When a recurring event happens, to the minute.  Each field is the
+/// set of values it matches, as bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Both the day of the month and weekday were restricted, so
+    /// either may match (as in cron).
+    either_day: bool,
+}
+
+/// Parse one field of a schedule, with values from `low` to `high`.
+fn parse_field(field: &str, low: u64, high: u64) -> Option<u64> {
+    let mut bits = 0;
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((r, s)) => (r, s.parse::<u64>().ok().filter(|s| *s > 0)?),
+            None => (item, 1),
+        };
+        let (start, end) = match range {
+            "*" => (low, high),
+            _ => match range.split_once('-') {
+                Some((a, b)) => (a.parse().ok()?, b.parse().ok()?),
+                None => {
+                    let v = range.parse().ok()?;
+                    // a step from a single value runs to the end.
+                    (v, if step > 1 { high } else { v })
+                }
+            },
+        };
+        if start < low || end > high || start > end {
+            return None;
+        }
+        for v in (start..=end).step_by(step as usize) {
+            bits |= 1 << v;
+        }
+    }
+    Some(bits)
+}
+
+impl Schedule {
+    /// Parse a schedule such as `"0 12 * * 1"` (noon on Mondays).
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` with a message if the schedule is malformed.
+    pub fn parse(spec: &str) -> std::result::Result<Schedule, String> {
+        let expanded = match spec.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            other => other,
+        };
+        let invalid = || format!("invalid announcement schedule: {spec}");
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(invalid());
+        };
+        let field = |f: &str, low, high| parse_field(f, low, high).ok_or_else(invalid);
+        // Sunday is either 0 or 7.
+        let mut weekdays = field(weekday, 0, 7)?;
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        Ok(Schedule {
+            minutes: field(minute, 0, 59)?,
+            hours: field(hour, 0, 23)?,
+            days: field(day, 1, 31)?,
+            months: field(month, 1, 12)?,
+            weekdays,
+            either_day: !day.starts_with('*') && !weekday.starts_with('*'),
+        })
+    }
+
+    /// Does the minute containing a time (in seconds since the epoch)
+    /// match?
+    #[must_use]
+    pub fn matches(&self, time: u64) -> bool {
+        let Some(t) = i64::try_from(time)
+            .ok()
+            .and_then(|t| Utc.timestamp_opt(t, 0).single())
+        else {
+            return false;
+        };
+        let has = |bits: u64, v: u32| bits & (1 << v) != 0;
+        let day = has(self.days, t.day());
+        let weekday = has(self.weekdays, t.weekday().num_days_from_sunday());
+        let day_matches = if self.either_day {
+            day || weekday
+        } else {
+            day && weekday
+        };
+        has(self.minutes, t.minute())
+            && has(self.hours, t.hour())
+            && has(self.months, t.month())
+            && day_matches
+    }
+}
+
+/// Publish each configured announcement whenever its schedule
+/// matches.
+pub async fn announcement_scheduler(
+    identity: Arc<RelayIdentity>,
+    settings: Settings,
+    mut shutdown: broadcast::Receiver<()>,
+) {
+    let scheduled: Vec<(Schedule, &Announcement)> = settings
+        .identity
+        .announcements
+        .iter()
+        .flatten()
+        .filter_map(|a| match Schedule::parse(&a.schedule) {
+            Ok(s) => Some((s, a)),
+            Err(e) => {
+                warn!("ignoring announcement: {}", e);
+                None
+            }
+        })
+        .collect();
+    if scheduled.is_empty() {
+        return;
+    }
+    info!("publishing {} scheduled announcement(s)", scheduled.len());
+    let mut last_minute = None;
+    loop {
+        // wake at the start of the next minute.
+        let wait = 60 - unix_time() % 60;
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(wait)) => {},
+            _ = shutdown.recv() => return,
+        }
+        let now = unix_time();
+        if last_minute == Some(now / 60) {
+            continue;
+        }
+        last_minute = Some(now / 60);
+        for (schedule, a) in &scheduled {
+            if !schedule.matches(now) {
+                continue;
+            }
+            match identity
+                .publish(a.kind.unwrap_or(1), vec![], a.content.clone())
+                .await
+            {
+                Ok(id) => info!("published scheduled announcement {}", id),
+                Err(e) => warn!("could not publish scheduled announcement: {:?}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Monday, 13 November 2023, 12:00 UTC
+    const MONDAY_NOON: u64 = 1_699_876_800;
+
+    #[test]
+    fn schedules_match_like_cron() {
+        let weekly = Schedule::parse("0 12 * * 1").unwrap();
+        assert!(weekly.matches(MONDAY_NOON));
+        assert!(weekly.matches(MONDAY_NOON + 59));
+        assert!(!weekly.matches(MONDAY_NOON + 60));
+        assert!(!weekly.matches(MONDAY_NOON + 86400));
+        let quarter_hours = Schedule::parse("*/15 9-17 * * 1-5").unwrap();
+        assert!(quarter_hours.matches(MONDAY_NOON + 45 * 60));
+        assert!(!quarter_hours.matches(MONDAY_NOON + 50 * 60));
+        // Sunday may be written as 7
+        let sunday = Schedule::parse("0 12 * * 7").unwrap();
+        assert!(sunday.matches(MONDAY_NOON - 86400));
+        // a day of the month or a weekday may match
+        let either = Schedule::parse("0 12 1,15 * 1").unwrap();
+        assert!(either.matches(MONDAY_NOON));
+        assert!(either.matches(MONDAY_NOON + 2 * 86400));
+        assert!(!either.matches(MONDAY_NOON + 86400));
+        assert!(Schedule::parse("@daily")
+            .unwrap()
+            .matches(MONDAY_NOON - 12 * 3600));
+        assert!(Schedule::parse("0 12 * *").is_err());
+        assert!(Schedule::parse("60 * * * *").is_err());
+        assert!(Schedule::parse("*/0 * * * *").is_err());
+        assert!(Schedule::parse("0 12 0 * *").is_err());
+    }
+}
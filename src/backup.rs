@@ -0,0 +1,231 @@
+//! Database backups
+//!
+//! A consistent copy of the database is made periodically, and on
+//! request from an admin, and kept in a directory or an S3 bucket.
+//! Each backup is named by the time it was made; older backups are
+//! removed so that only the most recent are kept.
+//!
+//! SQLite databases are copied with the online backup API, and
+//! postgres databases are dumped with `pg_dump`.  Other databases
+//! are exported as gzip-compressed JSON events, one per line.
+use crate::archive::{ArchiveStore, FsStore, S3Store};
+use crate::config::Settings;
+use crate::error::{Error, Result};
+use crate::repo::export::{export_events, ExportFilter};
+use crate::repo::NostrRepo;
+use chrono::{DateTime, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+/// Start of the name of every backup
+const BACKUP_PREFIX: &str = "nostr-backup-";
+
+/// Suffix of a backup that is still being written
+const PARTIAL_SUFFIX: &str = ".tmp";
+
+/// Set while a backup is being made.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Name of a backup made at `time`.  Names sort by time.
+fn backup_name(time: DateTime<Utc>, extension: &str) -> String {
+    format!(
+        "{BACKUP_PREFIX}{}{extension}",
+        time.format("%Y%m%dT%H%M%SZ")
+    )
+}
+
+/// Is this the name of a complete backup?
+fn is_backup(name: &str) -> bool {
+    name.starts_with(BACKUP_PREFIX) && !name.ends_with(PARTIAL_SUFFIX)
+}
+
+/// The backups to remove, so that only the newest `keep` remain.  A
+/// `keep` of zero keeps them all.
+fn expired(mut names: Vec<String>, keep: usize) -> Vec<String> {
+    if keep == 0 {
+        return vec![];
+    }
+    names.sort();
+    names.truncate(names.len().saturating_sub(keep));
+    names
+}
+
+fn backup_dir(settings: &Settings) -> PathBuf {
+    match &settings.backup.directory {
+        Some(d) => PathBuf::from(d),
+        None => PathBuf::from(&settings.database.data_directory).join("backups"),
+    }
+}
+
+/// Build the backup store configured in `backup` settings.
+fn build_store(settings: &Settings) -> Result<Box<dyn ArchiveStore>> {
+    let cfg = &settings.backup;
+    Ok(match &cfg.s3_bucket {
+        Some(bucket) => Box::new(S3Store::connect(
+            settings,
+            bucket.clone(),
+            cfg.s3_prefix.clone(),
+            is_backup,
+        )?),
+        None => Box::new(FsStore::listing(backup_dir(settings), is_backup)?),
+    })
+}
+
+/// Dump a postgres database in `pg_dump`'s custom format, which
+/// `pg_restore` reads.
+async fn pg_dump(connection: &str, dest: &Path) -> Result<()> {
+    let status = tokio::process::Command::new("pg_dump")
+        .arg("--format=custom")
+        .arg("--file")
+        .arg(dest)
+        .arg("--dbname")
+        .arg(connection)
+        .status()
+        .await
+        .map_err(|e| Error::CustomError(format!("could not run pg_dump: {e}")))?;
+    if !status.success() {
+        return Err(Error::CustomError(format!("pg_dump failed: {status}")));
+    }
+    Ok(())
+}
+
+/// Export every event, compressed.
+async fn export_backup(repo: &dyn NostrRepo, dest: &Path) -> Result<()> {
+    let mut out = GzEncoder::new(BufWriter::new(File::create(dest)?), Compression::default());
+    let n = export_events(repo, &ExportFilter::default(), &mut out).await?;
+    out.finish()?.flush()?;
+    info!("exported {} events for backup", n);
+    Ok(())
+}
+
+/// Write a backup to `dest`, returning the file extension for the
+/// format it was written in.
+async fn write_backup(repo: &dyn NostrRepo, settings: &Settings, dest: &Path) -> Result<String> {
+    let db = &settings.database;
+    if db.engine == "postgres" {
+        pg_dump(&db.connection, dest).await?;
+        Ok(".pgdump".to_owned())
+    } else if repo.backup(dest).await? {
+        Ok(format!(".{}", db.engine))
+    } else {
+        export_backup(repo, dest).await?;
+        Ok(".jsonl.gz".to_owned())
+    }
+}
+
+/// Clears [`RUNNING`] when a backup ends, even if it is abandoned.
+struct Running;
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING.store(false, Ordering::Release);
+    }
+}
+
+/// Make a backup now, and remove backups beyond the number kept.
+/// Returns the name of the new backup.
+///
+/// # Errors
+///
+/// Will return `Err` if another backup is being made, or if the
+/// backup could not be written or stored.
+pub async fn backup(repo: &dyn NostrRepo, settings: &Settings) -> Result<String> {
+    if RUNNING.swap(true, Ordering::AcqRel) {
+        return Err(Error::CustomError(
+            "a backup is already being made".to_owned(),
+        ));
+    }
+    let _running = Running;
+    let start = Instant::now();
+    let store = build_store(settings)?;
+    let dir = backup_dir(settings);
+    std::fs::create_dir_all(&dir)?;
+    let now = Utc::now();
+    let partial = dir.join(backup_name(now, PARTIAL_SUFFIX));
+    let extension = match write_backup(repo, settings, &partial).await {
+        Ok(ext) => ext,
+        Err(e) => {
+            tokio::fs::remove_file(&partial).await.ok();
+            return Err(e);
+        }
+    };
+    let name = backup_name(now, &extension);
+    if settings.backup.s3_bucket.is_some() {
+        let data = tokio::fs::read(&partial).await;
+        tokio::fs::remove_file(&partial).await.ok();
+        store.put(&name, data?).await?;
+    } else {
+        tokio::fs::rename(&partial, dir.join(&name)).await?;
+    }
+    info!("backup {} made in {:?}", name, start.elapsed());
+    for old in expired(store.list().await?, settings.backup.keep) {
+        match store.delete(&old).await {
+            Ok(()) => info!("removed old backup {}", old),
+            Err(e) => warn!("could not remove old backup {}: {:?}", old, e),
+        }
+    }
+    Ok(name)
+}
+
+/// Periodically back up the database, if backups are enabled.
+pub async fn backup_scheduler(
+    repo: Arc<dyn NostrRepo>,
+    settings: Settings,
+    mut shutdown: broadcast::Receiver<()>,
+) {
+    let cfg = &settings.backup;
+    if !cfg.enabled {
+        return;
+    }
+    let interval = cfg.interval_duration().unwrap_or_else(|| {
+        warn!("could not parse backup interval, using 1 day");
+        Duration::from_secs(86400)
+    });
+    info!("backing up the database every {:?}", interval);
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {},
+            _ = shutdown.recv() => {
+                info!("shutting down backups");
+                return;
+            }
+        }
+        if let Err(e) = backup(repo.as_ref(), &settings).await {
+            warn!("backup failed: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn oldest_backups_expire() {
+        let time = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let name = backup_name(time, ".sqlite");
+        assert_eq!(name, "nostr-backup-20231114T221320Z.sqlite");
+        assert!(is_backup(&name));
+        assert!(!is_backup(&backup_name(time, PARTIAL_SUFFIX)));
+        assert!(!is_backup(
+            "1600000000-1600086400-abababababababab.jsonl.gz"
+        ));
+        let names: Vec<String> = [3, 1, 4, 2]
+            .iter()
+            .map(|d| backup_name(time + chrono::Duration::days(*d), ".jsonl.gz"))
+            .collect();
+        let old = expired(names.clone(), 2);
+        assert_eq!(old, vec![names[1].clone(), names[3].clone()]);
+        assert!(expired(names.clone(), 0).is_empty());
+        assert!(expired(names, 5).is_empty());
+    }
+}
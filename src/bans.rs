@@ -0,0 +1,228 @@
+//! Bans on client IP addresses and pubkeys
+//!
+//! Bans are stored in the database, and kept in memory for checking.
+//! Connections from a banned IP address are refused, and events from
+//! a banned pubkey are rejected.  Clients that repeatedly abuse the
+//! relay (sending unparseable or oversized messages, or spam) are
+//! banned by IP address for a while.  Events from shadow-banned
+//! pubkeys appear to be accepted, but are only shown to their author.
+use crate::config;
+use crate::utils::unix_time;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Mutex, RwLock};
+
+/// What a ban applies to
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum BanTarget {
+    Ip,
+    Pubkey,
+}
+
+impl BanTarget {
+    #[must_use]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Ip => "ip",
+            Self::Pubkey => "pubkey",
+        }
+    }
+
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<BanTarget> {
+        match name {
+            "ip" => Some(Self::Ip),
+            "pubkey" => Some(Self::Pubkey),
+            _ => None,
+        }
+    }
+}
+
+/// A banned IP address or pubkey
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct Ban {
+    pub target: BanTarget,
+    /// IP address, or hex pubkey
+    pub value: String,
+    /// When the ban ends (unix time), or never
+    pub expires_at: Option<u64>,
+    pub reason: String,
+}
+
+impl Ban {
+    #[must_use]
+    pub fn is_active(&self, now: u64) -> bool {
+        self.expires_at.is_none_or(|t| t > now)
+    }
+}
+
+/// Client behaviour counting towards an automatic ban
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Abuse {
+    /// A message that could not be parsed
+    ParseError,
+    /// A message over the size limit
+    Oversized,
+    /// An event rejected as spam
+    Spam,
+}
+
+impl Abuse {
+    /// Label for metrics
+    #[must_use]
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::ParseError => "parse_error",
+            Self::Oversized => "oversized",
+            Self::Spam => "spam",
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            Self::ParseError => "unparseable messages",
+            Self::Oversized => "oversized messages",
+            Self::Spam => "spam events",
+        }
+    }
+}
+
+/// Current bans, and recent abuse by each IP address
+pub struct BanRegistry {
+    settings: config::Bans,
+    bans: RwLock<HashMap<(BanTarget, String), Ban>>,
+    /// Shadow-banned pubkeys
+    shadow_banned: HashSet<String>,
+    /// Times of recent abuse, by IP address
+    strikes: Mutex<HashMap<String, VecDeque<u64>>>,
+}
+
+impl BanRegistry {
+    #[must_use]
+    pub fn new(settings: &config::Bans, bans: Vec<Ban>) -> BanRegistry {
+        BanRegistry {
+            settings: settings.clone(),
+            bans: RwLock::new(
+                bans.into_iter()
+                    .map(|b| ((b.target, b.value.clone()), b))
+                    .collect(),
+            ),
+            shadow_banned: settings.shadow_banned_pubkeys.iter().flatten().cloned().collect(),
+            strikes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Check if an IP address or pubkey is banned.
+    #[must_use]
+    pub fn is_banned(&self, target: BanTarget, value: &str) -> bool {
+        self.bans
+            .read()
+            .unwrap()
+            .get(&(target, value.to_owned()))
+            .is_some_and(|b| b.is_active(unix_time()))
+    }
+
+    /// Check if a pubkey is shadow-banned.
+    #[must_use]
+    pub fn is_shadow_banned(&self, pubkey: &str) -> bool {
+        self.shadow_banned.contains(pubkey)
+    }
+
+    /// Add a ban, replacing any other for the same IP address or pubkey.
+    pub fn add(&self, ban: Ban) {
+        let now = unix_time();
+        let mut bans = self.bans.write().unwrap();
+        bans.retain(|_, b| b.is_active(now));
+        bans.insert((ban.target, ban.value.clone()), ban);
+    }
+
+    /// Record abuse from an IP address.  Returns a temporary ban for
+    /// the address, which should be stored, once it has abused the
+    /// relay too often.
+    pub fn record_abuse(&self, ip: &str, abuse: Abuse) -> Option<Ban> {
+        self.record_abuse_at(ip, abuse, unix_time())
+    }
+
+    fn record_abuse_at(&self, ip: &str, abuse: Abuse, now: u64) -> Option<Ban> {
+        let threshold = self.settings.abuse_threshold.filter(|t| *t > 0)?;
+        let mut strikes = self.strikes.lock().unwrap();
+        let window_start = now.saturating_sub(self.settings.abuse_window_seconds);
+        // forget addresses that have behaved within the window.
+        strikes.retain(|_, times| times.back().is_some_and(|t| *t > window_start));
+        let times = strikes.entry(ip.to_owned()).or_default();
+        while times.front().is_some_and(|t| *t <= window_start) {
+            times.pop_front();
+        }
+        times.push_back(now);
+        if times.len() < threshold as usize {
+            return None;
+        }
+        strikes.remove(ip);
+        let ban = Ban {
+            target: BanTarget::Ip,
+            value: ip.to_owned(),
+            expires_at: Some(now + self.settings.ban_seconds),
+            reason: format!("too many {}", abuse.description()),
+        };
+        self.add(ban.clone());
+        Some(ban)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(threshold: u32) -> config::Bans {
+        config::Bans {
+            abuse_threshold: Some(threshold),
+            abuse_window_seconds: 60,
+            ban_seconds: 3600,
+            shadow_banned_pubkeys: Some(vec!["def".to_owned()]),
+        }
+    }
+
+    #[test]
+    fn stored_bans_are_enforced_until_expiry() {
+        let now = unix_time();
+        let reg = BanRegistry::new(
+            &settings(0),
+            vec![
+                Ban {
+                    target: BanTarget::Pubkey,
+                    value: "abc".to_owned(),
+                    expires_at: None,
+                    reason: "".to_owned(),
+                },
+                Ban {
+                    target: BanTarget::Ip,
+                    value: "1.1.1.1".to_owned(),
+                    expires_at: Some(now - 1),
+                    reason: "".to_owned(),
+                },
+            ],
+        );
+        assert!(reg.is_banned(BanTarget::Pubkey, "abc"));
+        assert!(!reg.is_banned(BanTarget::Ip, "abc"));
+        assert!(!reg.is_banned(BanTarget::Ip, "1.1.1.1"));
+        assert!(reg.is_shadow_banned("def"));
+        assert!(!reg.is_shadow_banned("abc"));
+        // automatic bans are disabled
+        assert!(reg.record_abuse("2.2.2.2", Abuse::Spam).is_none());
+    }
+
+    #[test]
+    fn repeated_abuse_is_banned() {
+        let reg = BanRegistry::new(&settings(3), vec![]);
+        let now = 1_000_000;
+        assert!(reg.record_abuse_at("1.1.1.1", Abuse::ParseError, now).is_none());
+        assert!(reg.record_abuse_at("1.1.1.1", Abuse::ParseError, now + 30).is_none());
+        // strikes outside the window are forgotten
+        assert!(reg.record_abuse_at("1.1.1.1", Abuse::Oversized, now + 61).is_none());
+        let ban = reg.record_abuse_at("1.1.1.1", Abuse::Oversized, now + 62).unwrap();
+        assert_eq!(ban.expires_at, Some(now + 62 + 3600));
+        assert_eq!(ban.reason, "too many oversized messages");
+        assert!(reg.record_abuse_at("2.2.2.2", Abuse::Spam, now + 62).is_none());
+    }
+}
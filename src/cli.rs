@@ -1,4 +1,4 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 #[derive(Parser)]
 #[command(about = "A nostr relay written in Rust", author = env!("CARGO_PKG_AUTHORS"), version = env!("CARGO_PKG_VERSION"))]
@@ -8,6 +8,7 @@ pub struct CLIArgs {
         long,
         help = "Use the <directory> as the location of the database",
         required = false,
+        global = true,
     )]
     pub db: Option<String>,
     #[arg(
@@ -15,6 +16,83 @@ pub struct CLIArgs {
         long,
         help = "Use the <file name> as the location of the config file",
         required = false,
+        global = true,
     )]
     pub config: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<Commands>,
+}
+
+/// What to do; the relay is run if no command is given.  Other
+/// commands work on the database from the config file, and exit.
+#[derive(Subcommand, Debug, PartialEq, Eq)]
+pub enum Commands {
+    /// Run the relay
+    Serve,
+    /// Copy all events and verification records into another database,
+    /// using the connection settings from the config file
+    Migrate {
+        #[arg(long, help = "Database engine to copy from", value_parser = ["sqlite"])]
+        from: String,
+        #[arg(long, help = "Database engine to copy to", value_parser = ["postgres"])]
+        to: String,
+    },
+    /// Write stored events to a file as JSON lines, oldest first,
+    /// using the database settings from the config file
+    Export {
+        #[arg(long, help = "Only export events created at or after this unix time")]
+        since: Option<u64>,
+        #[arg(long, help = "Only export events created at or before this unix time")]
+        until: Option<u64>,
+        #[arg(long, value_delimiter = ',', help = "Only export events of these kinds (comma-separated)")]
+        kinds: Option<Vec<u64>>,
+        #[arg(short, long, help = "File to write events to")]
+        output: String,
+    },
+    /// Load events from a file of JSON lines directly into the
+    /// database, using the database settings from the config file
+    Import {
+        #[arg(short, long, help = "File to read events from; defaults to stdin")]
+        input: Option<String>,
+        #[arg(long, help = "Do not check event ids and signatures")]
+        skip_verify: bool,
+        #[arg(long, default_value_t = 1000, help = "Number of events written in each transaction")]
+        batch_size: usize,
+    },
+    /// Add a pubkey to the whitelist stored in the database.  A
+    /// running relay reads the change within a few seconds.
+    Allow {
+        #[arg(help = "Pubkey to allow, in hex or as an npub")]
+        pubkey: String,
+    },
+    /// Remove a pubkey from the whitelist stored in the database
+    Disallow {
+        #[arg(help = "Pubkey to remove, in hex or as an npub")]
+        pubkey: String,
+    },
+    /// Ban a pubkey or IP address.  A running relay applies the ban
+    /// when it next starts; use the admin ban command to apply it at
+    /// once.
+    Ban {
+        #[arg(help = "Pubkey (in hex or as an npub) or IP address to ban")]
+        target: String,
+        #[arg(long, help = "End the ban after this many seconds")]
+        seconds: Option<u64>,
+        #[arg(long, default_value = "", help = "Reason for the ban")]
+        reason: String,
+    },
+    /// Reclaim the space of deleted events.  This may take a long
+    /// time, and is best done while the relay is stopped.
+    Compact,
+    /// Check the ids and signatures of all stored events, and the
+    /// database's indexes.  Exits with status 2 if any events are
+    /// invalid and were not deleted, or the indexes have problems.
+    VerifyDb {
+        #[arg(long, help = "Remove events that are not valid")]
+        delete: bool,
+    },
+    /// Count the events, authors, bans and other records stored
+    Stats,
+    /// Check that the config file can be read and is valid
+    ConfigCheck,
 }
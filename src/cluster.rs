@@ -0,0 +1,282 @@
+//! Clustering of relays that share a database
+//!
+//! Nodes in a cluster forward the events they accept to each other
+//! over plain TCP, so that subscribers on every node see them in real
+//! time.  Each node connects to its configured peers and sends them
+//! its events, one JSON object per line, after a line with the shared
+//! secret.  Events received from peers are only broadcast to local
+//! subscribers; they were already stored by the node that accepted
+//! them, and are never forwarded again.
+use crate::config::Settings;
+use crate::event::{BroadcastEvent, Event};
+use crate::mirror::RecentIds;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{broadcast, mpsc};
+use tracing::{debug, info, warn};
+
+/// Delay before the first reconnection attempt
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Longest delay between reconnection attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Time allowed to establish a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Events held for each peer while it is slow or unreachable
+const QUEUE_SIZE: usize = 10_000;
+
+/// Number of event ids received from peers that are remembered
+const RECENT_IDS: usize = 100_000;
+
+/// Ids of events received from other nodes, which must not be sent
+/// back out.
+type Received = Arc<Mutex<RecentIds>>;
+
+/// Share broadcast events with the other nodes of a cluster, until
+/// shutdown.
+pub async fn cluster(
+    settings: Settings,
+    bcast_tx: broadcast::Sender<BroadcastEvent>,
+    mut shutdown: broadcast::Receiver<()>,
+) {
+    let cfg = &settings.cluster;
+    let peers = cfg.peers.clone().unwrap_or_default();
+    if cfg.listen.is_none() && peers.is_empty() {
+        return;
+    }
+    let secret = cfg.secret.clone().unwrap_or_default();
+    if secret.is_empty() {
+        warn!("cluster secret is not set, any host that can connect may publish events");
+    }
+    let received: Received = Arc::new(Mutex::new(RecentIds::new(RECENT_IDS)));
+    let mut bcast_rx = bcast_tx.subscribe();
+    if let Some(addr) = &cfg.listen {
+        match TcpListener::bind(addr).await {
+            Ok(listener) => {
+                info!("accepting cluster events on: {}", addr);
+                tokio::spawn(accept_nodes(
+                    listener,
+                    secret.clone(),
+                    bcast_tx.clone(),
+                    received.clone(),
+                    shutdown.resubscribe(),
+                ));
+            }
+            Err(e) => warn!("could not listen for cluster events on {}: {:?}", addr, e),
+        }
+    }
+    // each peer task stops once its queue is dropped.
+    let queues: Vec<(String, mpsc::Sender<String>)> = peers
+        .into_iter()
+        .map(|addr| {
+            let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+            tokio::spawn(peer_task(addr.clone(), secret.clone(), rx));
+            (addr, tx)
+        })
+        .collect();
+    if !queues.is_empty() {
+        info!("sending events to {} cluster peers", queues.len());
+    }
+    loop {
+        tokio::select! {
+            res = bcast_rx.recv() => match res {
+                Ok(e) => {
+                    if queues.is_empty() || received.lock().unwrap().contains(&e.id) {
+                        continue;
+                    }
+                    for (addr, tx) in &queues {
+                        if let Err(TrySendError::Full(_)) = tx.try_send(e.json().to_owned()) {
+                            debug!("cluster queue for {} is full, dropping event", addr);
+                        }
+                    }
+                }
+                Err(RecvError::Lagged(n)) => {
+                    warn!("cluster fell behind the relay, {} events were not shared", n);
+                }
+                Err(RecvError::Closed) => return,
+            },
+            _ = shutdown.recv() => {
+                info!("shutting down cluster");
+                return;
+            }
+        }
+    }
+}
+
+/// Accept connections from other nodes.
+async fn accept_nodes(
+    listener: TcpListener,
+    secret: String,
+    bcast_tx: broadcast::Sender<BroadcastEvent>,
+    received: Received,
+    mut shutdown: broadcast::Receiver<()>,
+) {
+    loop {
+        tokio::select! {
+            res = listener.accept() => match res {
+                Ok((stream, addr)) => {
+                    debug!("cluster connection from {}", addr);
+                    tokio::spawn(receive_events(
+                        stream,
+                        addr.to_string(),
+                        secret.clone(),
+                        bcast_tx.clone(),
+                        received.clone(),
+                        shutdown.resubscribe(),
+                    ));
+                }
+                Err(e) => warn!("could not accept cluster connection: {:?}", e),
+            },
+            _ = shutdown.recv() => return,
+        }
+    }
+}
+
+/// Broadcast the events a node sends, once it has presented the secret.
+async fn receive_events(
+    stream: TcpStream,
+    addr: String,
+    secret: String,
+    bcast_tx: broadcast::Sender<BroadcastEvent>,
+    received: Received,
+    mut shutdown: broadcast::Receiver<()>,
+) {
+    let mut lines = BufReader::new(stream).lines();
+    match lines.next_line().await {
+        Ok(Some(s)) if s == secret => info!("cluster node connected from {}", addr),
+        _ => {
+            warn!("rejecting cluster connection from {}, wrong secret", addr);
+            return;
+        }
+    }
+    loop {
+        let line = tokio::select! {
+            line = lines.next_line() => line,
+            _ = shutdown.recv() => return,
+        };
+        let line = match line {
+            Ok(Some(line)) => line,
+            Ok(None) => {
+                info!("cluster node at {} disconnected", addr);
+                return;
+            }
+            Err(e) => {
+                warn!("lost cluster connection from {}: {:?}", addr, e);
+                return;
+            }
+        };
+        let Some(event) = parse_event(&line) else {
+            debug!("ignoring malformed event from cluster node {}", addr);
+            continue;
+        };
+        // remember the event before it is broadcast, so it is not
+        // sent back to the cluster.
+        if received.lock().unwrap().insert(&event.id) {
+            bcast_tx.send(BroadcastEvent::with_json(event, line)).ok();
+        }
+    }
+}
+
+/// Parse an event sent by another node, which has already validated it.
+fn parse_event(line: &str) -> Option<Event> {
+    let mut event: Event = serde_json::from_str(line).ok()?;
+    event.build_index();
+    event.update_delegation();
+    Some(event)
+}
+
+/// Keep a connection open to a peer, and send it queued events.
+async fn peer_task(addr: String, secret: String, mut rx: mpsc::Receiver<String>) {
+    let mut backoff = MIN_BACKOFF;
+    // an event taken from the queue, that could not be sent
+    let mut pending: Option<String> = None;
+    loop {
+        match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(&addr)).await {
+            Ok(Ok(stream)) => {
+                info!("connected to cluster peer {}", addr);
+                backoff = MIN_BACKOFF;
+                match send_events(stream, &secret, &mut rx, &mut pending).await {
+                    Ok(()) => return,
+                    Err(e) => warn!("lost connection to cluster peer {}: {:?}", addr, e),
+                }
+            }
+            Ok(Err(e)) => warn!("could not connect to cluster peer {}: {:?}", addr, e),
+            Err(_) => warn!("timed out connecting to cluster peer {}", addr),
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Send queued events over a connection, until the queue is closed
+/// (returning `Ok`) or the connection fails.
+async fn send_events(
+    mut stream: TcpStream,
+    secret: &str,
+    rx: &mut mpsc::Receiver<String>,
+    pending: &mut Option<String>,
+) -> std::io::Result<()> {
+    stream.write_all(format!("{secret}\n").as_bytes()).await?;
+    loop {
+        let json = match pending.take() {
+            Some(json) => json,
+            None => match rx.recv().await {
+                Some(json) => json,
+                None => return stream.shutdown().await,
+            },
+        };
+        if let Err(e) = stream.write_all(format!("{json}\n").as_bytes()).await {
+            *pending = Some(json);
+            return Err(e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn events_from_peers_are_broadcast_once() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (bcast_tx, mut bcast_rx) = broadcast::channel(16);
+        let (_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let received: Received = Arc::new(Mutex::new(RecentIds::new(10)));
+        tokio::spawn(accept_nodes(listener, "s3cret".to_owned(), bcast_tx, received.clone(), shutdown_rx));
+
+        let (tx, rx) = mpsc::channel(4);
+        tokio::spawn(peer_task(addr, "s3cret".to_owned(), rx));
+        let mut e = Event::simple_event();
+        e.id = "a".repeat(64);
+        let json = serde_json::to_string(&e).unwrap();
+        tx.send(json.clone()).await.unwrap();
+        tx.send(json).await.unwrap();
+        let got = tokio::time::timeout(Duration::from_secs(5), bcast_rx.recv()).await.unwrap().unwrap();
+        assert_eq!(got.id, e.id);
+        assert!(received.lock().unwrap().contains(&e.id));
+        // the duplicate was not broadcast again
+        assert!(tokio::time::timeout(Duration::from_millis(200), bcast_rx.recv()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn wrong_secret_is_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (bcast_tx, mut bcast_rx) = broadcast::channel(16);
+        let (_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let received: Received = Arc::new(Mutex::new(RecentIds::new(10)));
+        tokio::spawn(accept_nodes(listener, "s3cret".to_owned(), bcast_tx, received, shutdown_rx));
+
+        let (tx, rx) = mpsc::channel(4);
+        tokio::spawn(peer_task(addr, "guess".to_owned(), rx));
+        tx.send(serde_json::to_string(&Event::simple_event()).unwrap()).await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(500), bcast_rx.recv()).await.is_err());
+    }
+}
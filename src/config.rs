@@ -1,6 +1,8 @@
 //! Configuration file and settings management
 use config::{Config, ConfigError, File};
+use crate::subscription::ReqFilter;
 use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
 use std::time::Duration;
 use tracing::warn;
 
@@ -11,16 +13,45 @@ pub enum AntispamMode {
     Disabled,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// A spam classifier that may be chained in `antispam.policies`
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SpamPolicyKind {
+    Keywords,
+    Regex,
+    Burst,
+    Duplicate,
+    Repeat,
+    Domains,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct Antispam {
-    pub mode: AntispamMode,
+    pub mode: AntispamMode, // "keywords" enables the keyword policy, if no policies are listed
+    pub policies: Option<Vec<SpamPolicyKind>>, // spam policies to apply, in order
     pub keywords: Option<Vec<String>>,
+    pub patterns: Option<Vec<String>>, // regular expressions matching spam content
+    pub wildcards: Option<Vec<String>>, // wildcard patterns matching spam content
+    pub rules_file: Option<String>, // file of further patterns, reloaded when it changes
+    pub burst_events: usize, // most events an author may post within burst_seconds
+    pub burst_seconds: u64,
+    pub duplicate_seconds: u64, // how long content may not be repeated
+    pub repeat_max: usize, // times an author may repeat content within repeat_seconds
+    pub repeat_seconds: u64,
+    pub blocked_domains: Option<Vec<String>>, // events linking to these domains (or subdomains) are spam
+    pub allowed_domains: Option<Vec<String>>, // domains never blocked, even within a blocked domain
+    pub shadow_blocked_domains: bool, // if true, events linking to blocked domains are silently dropped
 }
 
 impl Antispam {
+    /// Spam policies to apply, in order.
     #[must_use]
-    pub fn use_keywords(&self) -> bool {
-        self.mode == AntispamMode::Keywords
+    pub fn policies(&self) -> Vec<SpamPolicyKind> {
+        match &self.policies {
+            Some(p) => p.clone(),
+            None if self.mode == AntispamMode::Keywords => vec![SpamPolicyKind::Keywords],
+            None => vec![],
+        }
     }
 }
 
@@ -32,6 +63,42 @@ pub struct Info {
     pub description: Option<String>,
     pub pubkey: Option<String>,
     pub contact: Option<String>,
+    pub icon: Option<String>, // URL of an image representing the relay
+    pub relay_countries: Option<Vec<String>>, // countries whose laws apply to the relay (ISO 3166-1 alpha-2)
+    pub payments_url: Option<String>, // where fees can be paid
+    pub fees: Option<Fees>, // fees advertised in the relay information document
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[allow(unused)]
+pub struct Fee {
+    pub amount: u64,
+    pub unit: String, // e.g. "msats"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub period: Option<u64>, // seconds covered by a subscription fee
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kinds: Option<Vec<u64>>, // event kinds covered by a publication fee
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[allow(unused)]
+pub struct Fees {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub admission: Option<Vec<Fee>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subscription: Option<Vec<Fee>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub publication: Option<Vec<Fee>>,
+}
+
+impl Fees {
+    /// Must clients pay before they can use the relay?
+    #[must_use]
+    pub fn payment_required(&self) -> bool {
+        [&self.admission, &self.subscription]
+            .iter()
+            .any(|f| f.iter().any(|v| !v.is_empty()))
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -40,55 +107,511 @@ pub struct Database {
     pub data_directory: String,
     pub engine: String,
     pub in_memory: bool,
+    pub shard_by_month: bool, // store sqlite events in one file per month
     pub min_conn: u32,
     pub max_conn: u32,
     pub connection: String,
+    pub connection_read: Option<String>, // postgres read replica, for queries
+    pub memory_max_events: usize, // events kept by the memory engine
+    pub memory_max_age: Option<String>, // drop events from the memory engine after this long
+    pub max_disk_bytes: Option<u64>, // remove the oldest events when the database grows beyond this
+    pub vacuum_window: Option<String>, // daily time range (UTC, "HH:MM-HH:MM") to reclaim free space in
+    pub write_batch_size: usize, // most events written in one transaction
+    pub write_batch_delay_ms: u64, // time to wait for more events to fill a batch
+    pub query_cache_entries: usize, // results of this many recent queries are kept in memory
+    pub query_cache_seconds: u64, // cached query results expire after this long
+    pub recent_events_minutes: u64, // events created this recently are also kept in memory
+}
+
+impl Database {
+    #[must_use]
+    pub fn memory_max_age_duration(&self) -> Option<Duration> {
+        self.memory_max_age
+            .as_ref()
+            .and_then(|x| parse_duration::parse(x).ok())
+    }
+}
+
+/// One address, or a list of them
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Addresses {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Addresses {
+    #[must_use]
+    pub fn list(&self) -> Vec<&str> {
+        match self {
+            Addresses::One(a) => vec![a.as_str()],
+            Addresses::Many(a) => a.iter().map(String::as_str).collect(),
+        }
+    }
+}
+
+/// An address and port to accept connections on
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Listener {
+    pub address: String,
+    pub port: u16,
+    pub metrics: Option<bool>, // serve prometheus metrics at /metrics (default true)
+    pub tls: Option<bool>, // serve TLS, if a certificate is configured (default true)
+}
+
+impl Listener {
+    /// The socket address to bind, if the address is a valid IPv4 or
+    /// IPv6 address.
+    #[must_use]
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        let ip = self.address.trim().trim_start_matches('[').trim_end_matches(']');
+        ip.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, self.port))
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[allow(unused)]
 pub struct Network {
     pub port: u16,
-    pub address: String,
+    pub address: Addresses, // address, or addresses, to listen on (with `port`)
+    pub listeners: Option<Vec<Listener>>, // more addresses to listen on, with their own settings
+    pub metrics: Option<bool>, // serve prometheus metrics on `address` (default true)
     pub remote_ip_header: Option<String>, // retrieve client IP from this HTTP header if present
     pub ping_interval_seconds: u32,
+    pub tls_cert: Option<String>, // PEM certificate chain, for serving TLS (wss://) directly
+    pub tls_key: Option<String>, // PEM private key for the certificate
+    pub http_transport: bool, // serve /events (SSE), /publish, /req and /e/<id>, for clients that cannot use websockets
+}
+
+impl Network {
+    /// Every address to listen on.
+    #[must_use]
+    pub fn all_listeners(&self) -> Vec<Listener> {
+        let main = self.address.list().into_iter().map(|address| Listener {
+            address: address.to_owned(),
+            port: self.port,
+            metrics: self.metrics,
+            tls: None,
+        });
+        main.chain(self.listeners.iter().flatten().cloned()).collect()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[allow(unused)]
 pub struct Options {
     pub reject_future_seconds: Option<usize>, // if defined, reject any events with a timestamp more than X seconds in the future
+    pub reject_past_seconds: Option<usize>, // if defined, reject any events with a timestamp more than X seconds in the past
+    pub store_past_events: bool, // if true, events rejected by reject_past_seconds are stored, but not sent to subscribers
+    pub min_pow_difficulty: Option<u32>, // if defined, reject events with less proof of work (NIP-13)
+    pub pow_exempt_whitelisted: bool, // if true, whitelisted pubkeys need no proof of work
+    pub pow_exempt_verified: bool, // if true, NIP-05 verified authors need no proof of work
+    pub multi_letter_tags: bool, // if true, index tags with longer names than one letter (such as "title") for queries
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[allow(unused)]
 pub struct Retention {
-    // TODO: implement
     pub max_events: Option<usize>,                // max events
-    pub max_bytes: Option<usize>,                 // max size
+    pub max_bytes: Option<usize>,                 // max size (TODO: implement)
     pub persist_days: Option<usize>,              // oldest message
     pub whitelist_addresses: Option<Vec<String>>, // whitelisted addresses (never delete)
+    pub prune_interval: String,                   // how often expired events are removed
+    pub rules: Option<Vec<RetentionRule>>,        // limits for particular kinds or authors
+}
+
+impl Retention {
+    #[must_use]
+    pub fn prune_interval_duration(&self) -> Option<Duration> {
+        parse_duration::parse(&self.prune_interval).ok()
+    }
+}
+
+/// A kind, or an inclusive range of kinds
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum KindRange {
+    Single(u64),
+    Range([u64; 2]),
+}
+
+impl KindRange {
+    #[must_use]
+    pub fn contains(&self, kind: u64) -> bool {
+        match self {
+            KindRange::Single(k) => *k == kind,
+            KindRange::Range([lo, hi]) => *lo <= kind && kind <= *hi,
+        }
+    }
+}
+
+/// Authors a retention rule applies to
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PubkeyClass {
+    /// Pubkeys in the authorization whitelist
+    Whitelisted,
+    /// Everyone else
+    Others,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[allow(unused)]
+pub struct RetentionRule {
+    pub kinds: Option<Vec<KindRange>>, // kinds covered (all, if not set)
+    pub authors: Option<PubkeyClass>,  // authors covered (all, if not set)
+    pub max_age: Option<String>,       // remove events older than this
+    pub max_count: Option<usize>,      // keep only this many of the newest events
+}
+
+impl RetentionRule {
+    #[must_use]
+    pub fn max_age_duration(&self) -> Option<Duration> {
+        self.max_age
+            .as_ref()
+            .and_then(|x| parse_duration::parse(x).ok())
+    }
+}
+
+/// Rate limits for publishing events of some kinds
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct EventRateRule {
+    pub kinds: Option<Vec<KindRange>>,    // kinds covered (all, if not set)
+    pub per_author_per_min: Option<u32>,  // events each author may publish per minute
+    pub per_ip_per_min: Option<u32>,      // events each IP address may publish per minute
+}
+
+/// Connections to refuse, or limit, by their headers
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ClientRule {
+    pub origin: Option<String>, // wildcard pattern matching the Origin header
+    pub user_agent: Option<String>, // wildcard pattern matching the User-Agent header
+    pub connections_per_min: Option<u32>, // new connections allowed per minute, for all matching clients (none, if not set)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[allow(unused)]
 pub struct Limits {
     pub messages_per_sec: Option<u32>, // Artificially slow down event writing to limit disk consumption (averaged over 1 minute)
-    pub subscriptions_per_min: Option<u32>, // Artificially slow down request (db query) creation to prevent abuse (averaged over 1 minute)
+    pub subscriptions_per_min: Option<u32>, // Refuse subscriptions (REQ) over this rate to prevent abuse (averaged over 1 minute)
+    pub max_connections: Option<usize>, // Most open websocket connections
+    pub max_connections_per_ip: Option<usize>, // Most open websocket connections from one IP address
+    pub max_subscriptions: usize, // Most open subscriptions for a connection
+    pub max_filters: Option<usize>, // Most filters in a subscription
+    pub max_ids_per_filter: Option<usize>, // Most ids in a subscription filter
+    pub max_authors_per_filter: Option<usize>, // Most authors in a subscription filter
+    pub max_limit: Option<u64>, // Highest limit of a subscription filter (higher limits are lowered)
     pub db_conns_per_client: Option<u32>, // How many concurrent database queries (not subscriptions) may a client have?
     pub max_blocking_threads: usize,
+    pub signature_threads: usize, // threads verifying event signatures (0 for one per CPU)
     pub max_event_bytes: Option<usize>, // Maximum size of an EVENT message
+    pub max_event_tags: Option<usize>, // Most tags in a published event
+    pub max_tag_value_bytes: Option<usize>, // Longest element of a tag in a published event
+    pub max_bytes_per_pubkey: Option<u64>, // Most bytes of events stored for each author
+    pub whitelisted_max_bytes_per_pubkey: Option<u64>, // max_bytes_per_pubkey for authors in the pubkey whitelist
+    pub paid_max_bytes_per_pubkey: Option<u64>, // max_bytes_per_pubkey for authors who paid for admission
     pub max_ws_message_bytes: Option<usize>,
     pub max_ws_frame_bytes: Option<usize>,
     pub broadcast_buffer: usize, // events to buffer for subscribers (prevents slow readers from consuming memory)
-    pub event_persist_buffer: usize, // events to buffer for database commits (block senders if database writes are too slow)
-    pub event_kind_blacklist: Option<Vec<u64>>,
+    pub client_bytes_per_sec: Option<u32>, // bandwidth for each client, in each direction
+    pub auth_client_bytes_per_sec: Option<u32>, // bandwidth for each client authenticated with NIP-42
+    pub outbound_buffer: usize, // messages to queue for each client (older realtime events are dropped for slow clients)
+    pub replay_missed_events: bool, // if true, query the database for stored events a slow client missed
+    pub event_persist_buffer: usize, // events to buffer for database commits (refuse client events if database writes are too slow)
+    pub event_kind_allowlist: Option<Vec<u64>>, // if set, only events of these kinds are accepted
+    pub event_kind_blocklist: Option<Vec<u64>>, // events of these kinds are refused
+    pub event_kind_blacklist: Option<Vec<u64>>, // former name of event_kind_blocklist, added to it when read
+    pub max_negentropy_records: usize, // most events a negentropy (NIP-77) session may reconcile
+    pub max_query_seconds: Option<u64>, // abandon (and log) subscription queries that run longer than this
+    pub event_rates: Option<Vec<EventRateRule>>, // per-author and per-IP limits on publishing events
+    pub client_rules: Option<Vec<ClientRule>>, // connections blocked or rate-limited by Origin or User-Agent
+}
+
+impl Limits {
+    /// Are events of a kind accepted by the kind allowlist and
+    /// blocklist?
+    #[must_use]
+    pub fn accepts_kind(&self, kind: u64) -> bool {
+        self.event_kind_allowlist.as_ref().is_none_or(|k| k.contains(&kind))
+            && !self.event_kind_blocklist.as_ref().is_some_and(|k| k.contains(&kind))
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[allow(unused)]
 pub struct Authorization {
     pub pubkey_whitelist: Option<Vec<String>>, // If present, only allow these pubkeys to publish events
+    pub nip42_auth: bool, // if true, send a NIP-42 AUTH challenge to every new connection
+    pub private_inbox: bool, // if true, only send gift-wrapped events (NIP-59) to their authenticated recipient
+    pub dm_read_protection: bool, // if true, only send DMs (kinds 4, 1059) to their authenticated author or recipient
+    pub nip05_domains: Option<Vec<String>>, // If present, pubkeys verified (NIP-05) at these domains may publish, as if whitelisted
+}
+
+impl Authorization {
+    /// Are only some pubkeys allowed to publish?
+    #[must_use]
+    pub fn restricts_publishing(&self) -> bool {
+        self.pubkey_whitelist.is_some() || self.nip05_domains.is_some()
+    }
+
+    /// May pubkeys verified at a NIP-05 domain publish?
+    #[must_use]
+    pub fn allows_nip05_domain(&self, domain: &str) -> bool {
+        self.nip05_domains.as_ref().is_some_and(|d| d.iter().any(|x| x == domain))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Plugin {
+    pub command: Option<String>, // executable that decides whether events are accepted
+    pub args: Option<Vec<String>>, // arguments for the executable
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Grpc {
+    pub endpoint: Option<String>, // authorization service (nauthz) to consult, e.g. "http://[::1]:50051"
+    pub admit_events: bool, // if true, check each submitted event with the service
+    pub admit_connections: bool, // if true, check each new connection with the service
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Groups {
+    pub enabled: bool, // if true, restrict h-tagged events to group members (NIP-29)
+    pub creators: Option<Vec<String>>, // If present, only these pubkeys may create groups
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Bans {
+    pub abuse_threshold: Option<u32>, // ban an IP address after this much abuse within the window (never, if not set)
+    pub abuse_window_seconds: u64, // period over which abuse is counted
+    pub ban_seconds: u64, // how long an automatic ban lasts
+    pub shadow_banned_pubkeys: Option<Vec<String>>, // events from these pubkeys are accepted, but only shown to their author
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Reputation {
+    pub enabled: bool, // if true, restrictions on authors depend on their reputation
+    pub max_age_days: u64, // an author gains a point for each day since first seen, up to this many
+    pub verified_points: i64, // points for a valid NIP-05 verification
+    pub report_penalty: i64, // points lost for each distinct reporter (NIP-56)
+    pub spam_penalty: i64, // points lost for each event rejected as spam
+    pub trusted_score: i64, // authors with at least this score skip rate limits and proof of work
+    pub untrusted_score: i64, // authors with at most this score face stricter limits
+    pub untrusted_rate_cost: u32, // each event from an untrusted author counts this many times against rate limits
+    pub untrusted_extra_pow: u32, // extra proof of work required of untrusted authors
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Reports {
+    pub enabled: bool, // if true, reports (NIP-56) are aggregated by reported pubkey and event
+    pub admin_pubkeys: Option<Vec<String>>, // pubkeys allowed to list reports at /admin/reports
+    pub trusted_reporters: Option<Vec<String>>, // reporters trusted to hide events, in addition to trusted authors
+    pub hide_threshold: Option<u32>, // hide an event once this many trusted reporters report it (never, if not set)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Admin {
+    pub secret_key: Option<String>, // older name for identity.secret_key
+    pub pubkeys: Option<Vec<String>>, // pubkeys allowed to send admin commands
+    pub dashboard: bool, // serve a status dashboard at /dashboard, for admins
+}
+
+/// Lightning backend that issues admission invoices
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Processor {
+    LNbits,
+    Lnd,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct PayToRelay {
+    pub enabled: bool, // if true, only pubkeys that paid the admission cost may publish
+    pub processor: Processor, // lightning backend ("lnbits" or "lnd")
+    pub node_url: String, // base URL of the LNbits instance, or the LND REST API
+    pub api_secret: String, // LNbits invoice key, or hex-encoded LND invoice macaroon
+    pub admission_cost: u64, // sats paid once for a pubkey to be admitted (0 admits every pubkey)
+    pub cost_per_event: u64, // msats debited from the author's balance for each stored event
+    pub terms_message: String, // terms of service, shown on the join page
+    pub check_interval: String, // how often unpaid invoices are checked
+}
+
+impl PayToRelay {
+    #[must_use]
+    pub fn check_interval_duration(&self) -> Option<Duration> {
+        parse_duration::parse(&self.check_interval).ok()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Tiers {
+    pub default: Option<String>, // tier of clients not assigned to any other
+    pub levels: Option<Vec<Tier>>, // tiers, in the order clients are assigned to them
+}
+
+/// Limits for a class of clients, and who belongs to it
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Tier {
+    pub name: String,
+    pub pubkeys: Option<Vec<String>>, // pubkeys assigned to this tier
+    #[serde(default)]
+    pub paid: bool, // if true, pubkeys that paid for admission are assigned
+    pub nip05_domains: Option<Vec<String>>, // pubkeys verified (NIP-05) at these domains are assigned
+    pub messages_per_sec: Option<u32>, // events a connection may publish per second
+    pub max_event_bytes: Option<usize>, // maximum size of a published event
+    pub kinds: Option<Vec<KindRange>>, // kinds that may be published (all, if not set)
+    pub max_age: Option<String>, // remove events by members older than this
+}
+
+impl Tier {
+    #[must_use]
+    pub fn max_age_duration(&self) -> Option<Duration> {
+        self.max_age
+            .as_ref()
+            .and_then(|x| parse_duration::parse(x).ok())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Media {
+    pub enabled: bool, // if true, accept file uploads at /upload (NIP-96)
+    pub storage_dir: String, // directory to store uploaded files
+    pub public_url: Option<String>, // HTTP URL media is served under; defaults to the relay URL
+    pub max_upload_bytes: Option<usize>, // maximum size of an uploaded file
+    pub blossom: bool, // if true, serve Blossom blob endpoints
+    pub quota_bytes: Option<u64>, // maximum total size of files uploaded by a pubkey
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Archive {
+    pub enabled: bool, // if true, move old events out of the database into archive segments
+    pub older_than: String, // events older than this are archived
+    pub interval: String, // how often the archiver runs
+    pub segment_events: usize, // maximum number of events in a segment
+    pub directory: Option<String>, // directory for segments; defaults to "archive" in the data directory
+    pub s3_bucket: Option<String>, // if set, store segments in this S3 bucket instead
+    pub s3_prefix: String, // key prefix for segments in the bucket
+    pub s3_region: String, // region of the bucket
+    pub s3_endpoint: Option<String>, // S3-compatible endpoint URL; defaults to AWS
+    pub s3_access_key: Option<String>, // defaults to the AWS_ACCESS_KEY_ID environment variable
+    pub s3_secret_key: Option<String>, // defaults to the AWS_SECRET_ACCESS_KEY environment variable
+    pub fetch_on_query: bool, // if true, search the archive for queries reaching before the hot window
+}
+
+impl Archive {
+    #[must_use]
+    pub fn older_than_duration(&self) -> Option<Duration> {
+        parse_duration::parse(&self.older_than).ok()
+    }
+
+    #[must_use]
+    pub fn interval_duration(&self) -> Option<Duration> {
+        parse_duration::parse(&self.interval).ok()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Backup {
+    pub enabled: bool, // if true, back up the database periodically
+    pub interval: String, // how often a backup is made
+    pub directory: Option<String>, // directory for backups; defaults to "backups" in the data directory
+    pub keep: usize, // number of backups kept, or 0 to keep them all
+    pub s3_bucket: Option<String>, // if set, upload backups to this S3 bucket instead
+    pub s3_prefix: String, // key prefix for backups in the bucket
+}
+
+impl Backup {
+    #[must_use]
+    pub fn interval_duration(&self) -> Option<Duration> {
+        parse_duration::parse(&self.interval).ok()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Replication {
+    pub peers: Option<Vec<ReplicationPeer>>, // relays that accepted events are forwarded to
+    pub queue_size: usize, // events held for each peer while it is slow or unreachable
+}
+
+/// A relay that events are replicated to
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ReplicationPeer {
+    /// Websocket URL of the relay
+    pub url: String,
+    /// Only forward events of these kinds
+    pub kinds: Option<Vec<KindRange>>,
+    /// Only forward events by these pubkeys
+    pub authors: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Mirror {
+    pub upstreams: Option<Vec<MirrorUpstream>>, // relays that events are copied from
+}
+
+/// A relay that events are mirrored from
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MirrorUpstream {
+    /// Websocket URL of the relay
+    pub url: String,
+    /// Subscription filters (new events of any kind, if not set)
+    pub filters: Option<Vec<ReqFilter>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Cluster {
+    pub listen: Option<String>, // address to accept events from other nodes on
+    pub peers: Option<Vec<String>>, // addresses of the other nodes
+    pub secret: Option<String>, // shared by all nodes, to authenticate connections
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Injection {
+    pub secret: Option<String>, // bearer secret for injecting events over HTTP; disabled if not set
+    pub local_only: bool, // if true, only accept injected events from loopback addresses
+    pub verify_signatures: bool, // if true, check the ids and signatures of injected events
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Identity {
+    pub secret_key: Option<String>, // hex secret key of the relay's own keypair (defaults to admin.secret_key)
+    pub publish_profile: bool, // publish a profile (kind 0) describing the relay at startup
+    pub publish_groups: bool, // publish the metadata, admins and members of groups (NIP-29) as they change
+    pub announcements: Option<Vec<Announcement>>, // events published by the relay on a schedule
+}
+
+/// An event the relay publishes on a schedule
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Announcement {
+    pub schedule: String, // cron-style schedule ("minute hour day month weekday"), in UTC
+    pub content: String,
+    pub kind: Option<u64>, // kind of the event (default 1, a note)
+}
+
+impl Identity {
+    /// Hex secret key of the relay's keypair, which may be configured
+    /// in the `admin` section instead.
+    #[must_use]
+    pub fn key<'a>(&'a self, admin: &'a Admin) -> Option<&'a str> {
+        self.secret_key.as_deref().or(admin.secret_key.as_deref())
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -97,6 +620,30 @@ pub struct Diagnostics {
     pub tracing: bool, // enables tokio console-subscriber
 }
 
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Never,
+    Hourly,
+    Daily,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Logging {
+    pub format: LogFormat, // "text" for readable lines, or "json" for one object per line
+    pub file: Option<String>, // write logs to this file, instead of standard output
+    pub rotation: LogRotation, // start a new log file "hourly", "daily", or "never"
+    pub max_files: Option<usize>, // most rotated log files to keep (all, if not set)
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
 #[serde(rename_all = "lowercase")]
 pub enum VerifiedUsersMode {
@@ -116,6 +663,11 @@ pub struct VerifiedUsers {
     pub verify_expiration_duration: Option<Duration>, // internal result of parsing verify_expiration
     pub verify_update_frequency_duration: Option<Duration>, // internal result of parsing verify_update_frequency
     pub max_consecutive_failures: usize, // maximum number of verification failures in a row, before ceasing future checks
+    pub max_concurrent_checks: usize, // most NIP-05 documents fetched at once
+    pub domain_requests_per_min: u32, // most NIP-05 documents fetched from one domain each minute (0 for no limit)
+    pub proxy: Option<String>, // SOCKS5 proxy (e.g. Tor) to fetch NIP-05 documents through, "socks5://host:port"
+    pub dns_over_https: Option<String>, // DNS over HTTPS server to resolve NIP-05 domains with, when there is no proxy
+    pub allow_private_addresses: bool, // fetch NIP-05 documents from loopback, private and link-local addresses
 }
 
 impl VerifiedUsers {
@@ -164,14 +716,34 @@ impl VerifiedUsers {
 pub struct Settings {
     pub info: Info,
     pub diagnostics: Diagnostics,
+    pub logging: Logging,
     pub database: Database,
     pub network: Network,
     pub limits: Limits,
     pub authorization: Authorization,
     pub verified_users: VerifiedUsers,
     pub retention: Retention,
+    pub archive: Archive,
+    pub backup: Backup,
+    pub replication: Replication,
+    pub mirror: Mirror,
+    pub cluster: Cluster,
+    pub injection: Injection,
+    pub identity: Identity,
     pub options: Options,
     pub antispam: Antispam,
+    pub groups: Groups,
+    pub bans: Bans,
+    pub reputation: Reputation,
+    pub reports: Reports,
+    pub admin: Admin,
+    pub pay_to_relay: PayToRelay,
+    pub tiers: Tiers,
+    pub media: Media,
+    pub grpc: Grpc,
+    pub plugin: Plugin,
+    #[serde(skip)]
+    pub config_file: Option<String>, // file the settings were read from, watched for changes
 }
 
 impl Settings {
@@ -198,29 +770,102 @@ impl Settings {
             Some(value) => value,
             None => &default_config_file_name,
         };
+        let mut settings = Self::read(default, config)?;
+        if let Err(e) = settings.validate() {
+            panic!("{}", e);
+        }
+        // initialize durations for verified users
+        settings.verified_users.init();
+        settings.config_file = Some(config.clone());
+        Ok(settings)
+    }
+
+    /// Read settings from a config file again, while the relay is
+    /// running.  Unlike at startup, invalid settings are an error.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the file cannot be read or parsed, or its
+    /// settings are not valid.
+    pub fn reload(config_file_name: &str) -> Result<Self, ConfigError> {
+        let mut settings = Self::read(&Self::default(), config_file_name)?;
+        settings.validate().map_err(ConfigError::Message)?;
+        settings.verified_users.init();
+        settings.config_file = Some(config_file_name.to_owned());
+        Ok(settings)
+    }
+
+    fn read(default: &Settings, config_file_name: &str) -> Result<Self, ConfigError> {
         let builder = Config::builder();
         let config: Config = builder
             // use defaults
             .add_source(Config::try_from(default)?)
             // override with file contents
-            .add_source(File::with_name(config))
+            .add_source(File::with_name(config_file_name))
             .build()?;
         let mut settings: Settings = config.try_deserialize()?;
+        let limits = &mut settings.limits;
+        if let Some(kinds) = limits.event_kind_blacklist.take() {
+            limits.event_kind_blocklist.get_or_insert_with(Vec::new).extend(kinds);
+        }
+        Ok(settings)
+    }
+
+    fn validate(&self) -> Result<(), String> {
         // ensure connection pool size is logical
-        assert!(
-            settings.database.min_conn <= settings.database.max_conn,
-            "Database min_conn setting ({}) cannot exceed max_conn ({})",
-            settings.database.min_conn,
-            settings.database.max_conn
-        );
+        if self.database.min_conn > self.database.max_conn {
+            return Err(format!(
+                "Database min_conn setting ({}) cannot exceed max_conn ({})",
+                self.database.min_conn, self.database.max_conn
+            ));
+        }
+        if let Some(window) = &self.database.vacuum_window {
+            crate::maintenance::VacuumWindow::parse(window)?;
+        }
+        if self.limits.client_rules.iter().flatten().any(|r| r.origin.is_none() && r.user_agent.is_none()) {
+            return Err("client_rules must each have an origin or user_agent pattern".to_owned());
+        }
+        for a in self.identity.announcements.iter().flatten() {
+            crate::announce::Schedule::parse(&a.schedule)?;
+        }
         // ensure durations parse
-        assert!(
-            settings.verified_users.is_valid(),
-            "VerifiedUsers time settings could not be parsed"
-        );
-        // initialize durations for verified users
-        settings.verified_users.init();
-        Ok(settings)
+        if !self.verified_users.is_valid() {
+            return Err("VerifiedUsers time settings could not be parsed".to_owned());
+        }
+        Ok(())
+    }
+
+    /// Take the settings that can change while the relay is running
+    /// from newly read settings: relay information, the pubkey
+    /// whitelist, rate limits, client rules and antispam policies.
+    pub fn apply_reloadable(&mut self, new: &Settings) {
+        self.info = new.info.clone();
+        self.authorization.pubkey_whitelist = new.authorization.pubkey_whitelist.clone();
+        let limits = &mut self.limits;
+        limits.messages_per_sec = new.limits.messages_per_sec;
+        limits.subscriptions_per_min = new.limits.subscriptions_per_min;
+        limits.client_bytes_per_sec = new.limits.client_bytes_per_sec;
+        limits.auth_client_bytes_per_sec = new.limits.auth_client_bytes_per_sec;
+        limits.max_event_bytes = new.limits.max_event_bytes;
+        limits.event_kind_allowlist = new.limits.event_kind_allowlist.clone();
+        limits.event_kind_blocklist = new.limits.event_kind_blocklist.clone();
+        limits.event_rates = new.limits.event_rates.clone();
+        limits.client_rules = new.limits.client_rules.clone();
+        self.antispam = new.antispam.clone();
+    }
+
+    /// Names of the sections (such as `limits`) that differ from
+    /// other settings.
+    #[must_use]
+    pub fn changed_sections(&self, other: &Settings) -> Vec<String> {
+        match (serde_json::to_value(self), serde_json::to_value(other)) {
+            (Ok(serde_json::Value::Object(a)), Ok(serde_json::Value::Object(b))) => a
+                .into_iter()
+                .filter(|(k, v)| b.get(k) != Some(v))
+                .map(|(k, _)| k)
+                .collect(),
+            _ => vec![],
+        }
     }
 }
 
@@ -233,36 +878,89 @@ impl Default for Settings {
                 description: None,
                 pubkey: None,
                 contact: None,
+                icon: None,
+                relay_countries: None,
+                payments_url: None,
+                fees: None,
             },
             diagnostics: Diagnostics { tracing: false },
+            logging: Logging {
+                format: LogFormat::Text,
+                file: None, // log to standard output
+                rotation: LogRotation::Daily,
+                max_files: Some(7),
+            },
             database: Database {
                 data_directory: ".".to_owned(),
                 engine: "sqlite".to_owned(),
                 in_memory: false,
+                shard_by_month: false,
                 min_conn: 4,
                 max_conn: 8,
                 connection: "".to_owned(),
+                connection_read: None,
+                memory_max_events: 10_000,
+                memory_max_age: None,
+                max_disk_bytes: None,
+                vacuum_window: None,
+                write_batch_size: 100,
+                write_batch_delay_ms: 0,
+                query_cache_entries: 0,
+                query_cache_seconds: 60,
+                recent_events_minutes: 0,
             },
             network: Network {
                 port: 8080,
                 ping_interval_seconds: 300,
-                address: "0.0.0.0".to_owned(),
+                address: Addresses::One("0.0.0.0".to_owned()),
+                listeners: None,
+                metrics: None,
                 remote_ip_header: None,
+                tls_cert: None,
+                tls_key: None,
+                http_transport: true,
             },
             limits: Limits {
                 messages_per_sec: None,
                 subscriptions_per_min: None,
+                max_connections: None,
+                max_connections_per_ip: None,
+                max_subscriptions: crate::conn::MAX_SUBSCRIPTIONS,
+                max_filters: None,
+                max_ids_per_filter: None,
+                max_authors_per_filter: None,
+                max_limit: None,
                 db_conns_per_client: None,
                 max_blocking_threads: 16,
+                signature_threads: 0,
                 max_event_bytes: Some(2 << 17),      // 128K
+                max_event_tags: None,
+                max_tag_value_bytes: None,
+                max_bytes_per_pubkey: None,
+                whitelisted_max_bytes_per_pubkey: None,
+                paid_max_bytes_per_pubkey: None,
                 max_ws_message_bytes: Some(2 << 17), // 128K
                 max_ws_frame_bytes: Some(2 << 17),   // 128K
                 broadcast_buffer: 16384,
+                client_bytes_per_sec: None,
+                auth_client_bytes_per_sec: None,
+                outbound_buffer: 1024,
+                replay_missed_events: false,
                 event_persist_buffer: 4096,
+                event_kind_allowlist: None,
+                event_kind_blocklist: None,
                 event_kind_blacklist: None,
+                max_negentropy_records: 500_000,
+                max_query_seconds: None,
+                event_rates: None,
+                client_rules: None,
             },
             authorization: Authorization {
                 pubkey_whitelist: None, // Allow any address to publish
+                nip42_auth: false,      // Do not challenge clients to authenticate
+                private_inbox: false,   // Gift-wrapped events are visible to anyone
+                dm_read_protection: false, // DMs are visible to anyone
+                nip05_domains: None,    // NIP-05 verification does not admit authors
             },
             verified_users: VerifiedUsers {
                 mode: VerifiedUsersMode::Disabled,
@@ -273,20 +971,154 @@ impl Default for Settings {
                 verify_expiration_duration: None,
                 verify_update_frequency_duration: None,
                 max_consecutive_failures: 20,
+                max_concurrent_checks: 8,
+                domain_requests_per_min: 60,
+                proxy: None,
+                dns_over_https: None,
+                allow_private_addresses: false,
             },
             retention: Retention {
                 max_events: None,          // max events
                 max_bytes: None,           // max size
                 persist_days: None,        // oldest message
                 whitelist_addresses: None, // whitelisted addresses (never delete)
+                prune_interval: "1 hour".to_owned(),
+                rules: None,
+            },
+            archive: Archive {
+                enabled: false,
+                older_than: "1 year".to_owned(),
+                interval: "1 day".to_owned(),
+                segment_events: 1000,
+                directory: None,
+                s3_bucket: None,
+                s3_prefix: String::new(),
+                s3_region: "us-east-1".to_owned(),
+                s3_endpoint: None,
+                s3_access_key: None,
+                s3_secret_key: None,
+                fetch_on_query: false,
+            },
+            backup: Backup {
+                enabled: false,
+                interval: "1 day".to_owned(),
+                directory: None,
+                keep: 7,
+                s3_bucket: None,
+                s3_prefix: "backups/".to_owned(),
+            },
+            replication: Replication {
+                peers: None, // No replication
+                queue_size: 10000,
+            },
+            mirror: Mirror {
+                upstreams: None, // No mirroring
+            },
+            cluster: Cluster {
+                listen: None, // Not clustered
+                peers: None,
+                secret: None,
+            },
+            injection: Injection {
+                secret: None, // No injection over HTTP
+                local_only: true,
+                verify_signatures: false,
+            },
+            identity: Identity {
+                secret_key: None, // The relay has no keypair
+                publish_profile: true,
+                publish_groups: true,
+                announcements: None,
             },
             options: Options {
                 reject_future_seconds: None, // Reject events in the future if defined
+                reject_past_seconds: None,
+                store_past_events: false,
+                min_pow_difficulty: None, // No proof of work required
+                pow_exempt_whitelisted: false,
+                pow_exempt_verified: false,
+                multi_letter_tags: false,
             },
             antispam: Antispam {
                 mode: AntispamMode::Disabled,
+                policies: None,
                 keywords: None,
+                patterns: None,
+                wildcards: None,
+                rules_file: None,
+                burst_events: 10,
+                burst_seconds: 10,
+                duplicate_seconds: 600,
+                repeat_max: 2,
+                repeat_seconds: 3600,
+                blocked_domains: None,
+                allowed_domains: None,
+                shadow_blocked_domains: false,
+            },
+            groups: Groups {
+                enabled: false, // Groups are not supported
+                creators: None, // Anyone may create a group
+            },
+            bans: Bans {
+                abuse_threshold: None, // No automatic bans
+                abuse_window_seconds: 60,
+                ban_seconds: 3600,
+                shadow_banned_pubkeys: None,
+            },
+            reputation: Reputation {
+                enabled: false,
+                max_age_days: 30,
+                verified_points: 20,
+                report_penalty: 5,
+                spam_penalty: 10,
+                trusted_score: 30,
+                untrusted_score: -10,
+                untrusted_rate_cost: 3,
+                untrusted_extra_pow: 8,
+            },
+            reports: Reports {
+                enabled: false,
+                admin_pubkeys: None,
+                trusted_reporters: None,
+                hide_threshold: None,
+            },
+            admin: Admin {
+                secret_key: None,
+                pubkeys: None,
+                dashboard: false,
+            },
+            pay_to_relay: PayToRelay {
+                enabled: false,
+                processor: Processor::LNbits,
+                node_url: String::new(),
+                api_secret: String::new(),
+                admission_cost: 1000,
+                cost_per_event: 0,
+                terms_message: "This relay is paid.  Admission fees are not refunded.".to_owned(),
+                check_interval: "30 seconds".to_owned(),
+            },
+            tiers: Tiers {
+                default: None,
+                levels: None,
+            },
+            media: Media {
+                enabled: false,
+                storage_dir: "media".to_owned(),
+                public_url: None,
+                max_upload_bytes: Some(10_485_760), // 10 MiB
+                blossom: false,
+                quota_bytes: None, // No limit on total uploads
+            },
+            grpc: Grpc {
+                endpoint: None, // No external authorization
+                admit_events: true,
+                admit_connections: false,
+            },
+            plugin: Plugin {
+                command: None, // No event policy plugin
+                args: None,
             },
+            config_file: None,
         }
     }
 }
@@ -1,15 +1,46 @@
 //! Client connection state
 use crate::close::Close;
+use crate::config::{Limits, Tier};
 use crate::error::Error;
 use crate::error::Result;
+use crate::event::Event;
 
 use crate::subscription::Subscription;
+use crate::tiers::{self, TierRefusal};
+use crate::utils::{host_str, unix_time};
+use governor::clock::DefaultClock;
+use governor::state::{InMemoryState, NotKeyed};
+use governor::{Quota, RateLimiter};
 use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::sync::Arc;
 use tracing::{debug, trace};
 use uuid::Uuid;
 
-/// A subscription identifier has a maximum length
-const MAX_SUBSCRIPTION_ID_LEN: usize = 256;
+/// A subscription identifier has a maximum length, in characters
+/// (NIP-01)
+pub const MAX_SUBSCRIPTION_ID_LEN: usize = 64;
+
+/// Maximum number of concurrent subscriptions for a connection
+pub const MAX_SUBSCRIPTIONS: usize = 32;
+
+/// Event kind for NIP-42 client authentication
+pub const AUTH_EVENT_KIND: u64 = 22242;
+
+/// Maximum clock skew (in seconds) allowed for an AUTH event
+const AUTH_MAX_SKEW_SECONDS: u64 = 600;
+
+/// NIP-42 authentication state of a connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Nip42AuthState {
+    /// The client has not been sent a challenge
+    NoAuth,
+    /// An AUTH challenge was sent, but not yet answered
+    Challenge(String),
+    /// The client authenticated as this pubkey, answering this
+    /// challenge
+    AuthPubkey { pubkey: String, challenge: String },
+}
 
 /// State for a client connection
 pub struct ClientConn {
@@ -21,6 +52,18 @@ pub struct ClientConn {
     subscriptions: HashMap<String, Subscription>,
     /// Per-connection maximum concurrent subscriptions
     max_subs: usize,
+    /// Maximum filters in a subscription
+    max_filters: Option<usize>,
+    /// Maximum ids in a filter
+    max_ids: Option<usize>,
+    /// Maximum authors in a filter
+    max_authors: Option<usize>,
+    /// NIP-42 authentication state
+    auth: Nip42AuthState,
+    /// Tier the client is assigned to
+    tier: Option<Arc<Tier>>,
+    /// Publishing rate limit of the tier
+    tier_limiter: Option<RateLimiter<NotKeyed, InMemoryState, DefaultClock>>,
 }
 
 impl Default for ClientConn {
@@ -38,14 +81,66 @@ impl ClientConn {
             client_ip_addr,
             client_id,
             subscriptions: HashMap::new(),
-            max_subs: 32,
+            max_subs: MAX_SUBSCRIPTIONS,
+            max_filters: None,
+            max_ids: None,
+            max_authors: None,
+            auth: Nip42AuthState::NoAuth,
+            tier: None,
+            tier_limiter: None,
+        }
+    }
+
+    /// Apply the configured subscription and filter limits.
+    pub fn set_limits(&mut self, limits: &Limits) {
+        self.max_subs = limits.max_subscriptions;
+        self.max_filters = limits.max_filters;
+        self.max_ids = limits.max_ids_per_filter;
+        self.max_authors = limits.max_authors_per_filter;
+    }
+
+    /// Assign the client to a tier, restarting its publishing rate
+    /// limit.
+    pub fn set_tier(&mut self, tier: Option<Arc<Tier>>) {
+        self.tier_limiter = tier
+            .as_ref()
+            .and_then(|t| t.messages_per_sec)
+            .and_then(NonZeroU32::new)
+            .map(|n| RateLimiter::direct(Quota::per_second(n)));
+        self.tier = tier;
+    }
+
+    /// Name of the client's tier, if it has one.
+    #[must_use]
+    pub fn tier_name(&self) -> Option<&str> {
+        self.tier.as_ref().map(|t| t.name.as_str())
+    }
+
+    /// Check if the client's tier allows it to publish an event,
+    /// counting the event against the tier's rate limit.
+    /// # Errors
+    ///
+    /// Will return `Err` with the reason the tier refuses the event.
+    pub fn check_tier(&self, event: &Event) -> std::result::Result<(), TierRefusal> {
+        let Some(tier) = &self.tier else {
+            return Ok(());
+        };
+        tiers::check_event(tier, event)?;
+        if let Some(lim) = &self.tier_limiter {
+            lim.check().map_err(|_| TierRefusal::RateLimited)?;
         }
+        Ok(())
     }
 
     #[must_use] pub fn subscriptions(&self) -> &HashMap<String, Subscription> {
         &self.subscriptions
     }
 
+    /// Most subscriptions the client may have open at once.
+    #[must_use] pub fn max_subscriptions(&self) -> usize {
+        self.max_subs
+    }
+
     /// Check if the given subscription already exists
     #[must_use] pub fn has_subscription(&self, sub: &Subscription) -> bool {
         self.subscriptions.values().any(|x| x == sub)
@@ -63,14 +158,108 @@ impl ClientConn {
         &self.client_ip_addr
     }
 
+    /// The pubkey this client authenticated as (NIP-42), if any.
+    #[must_use]
+    pub fn auth_pubkey(&self) -> Option<&String> {
+        match &self.auth {
+            Nip42AuthState::AuthPubkey { pubkey, .. } => Some(pubkey),
+            _ => None,
+        }
+    }
+
+    /// The outstanding AUTH challenge for this client, if any.
+    #[must_use]
+    pub fn auth_challenge(&self) -> Option<&String> {
+        match &self.auth {
+            Nip42AuthState::Challenge(challenge) => Some(challenge),
+            _ => None,
+        }
+    }
+
+    /// Is this client the authenticated recipient of a gift-wrapped
+    /// event (NIP-59)?  Recipients are named in `p` tags.
+    #[must_use]
+    pub fn is_gift_wrap_recipient(&self, event: &Event) -> bool {
+        self.auth_pubkey()
+            .iter()
+            .any(|pk| event.tag_values_by_name("p").contains(pk))
+    }
+
+    /// Is this client authenticated as the author or a recipient of a
+    /// direct message?
+    #[must_use]
+    pub fn is_dm_participant(&self, event: &Event) -> bool {
+        match self.auth_pubkey() {
+            Some(pk) => &event.pubkey == pk || self.is_gift_wrap_recipient(event),
+            None => false,
+        }
+    }
+
+    /// Create a new AUTH challenge, replacing any previous
+    /// authentication state.
+    pub fn generate_auth_challenge(&mut self) {
+        self.auth = Nip42AuthState::Challenge(Uuid::new_v4().to_string());
+    }
+
+    /// Verify a signed AUTH event against the outstanding challenge.
+    /// # Errors
+    ///
+    /// Will return `Err` if no challenge was issued, or if the event
+    /// is not a valid, recent, kind-22242 event that answers the
+    /// challenge for this relay.
+    pub fn authenticate(&mut self, event: &Event, relay_url: &str) -> Result<()> {
+        let sent_challenge = match &self.auth {
+            Nip42AuthState::Challenge(c) => c.clone(),
+            // an authenticated client may only authenticate again as
+            // the same pubkey, answering the same challenge.
+            Nip42AuthState::AuthPubkey { pubkey, challenge } => {
+                if pubkey != &event.pubkey {
+                    return Err(Error::AuthFailure);
+                }
+                challenge.clone()
+            }
+            // unexpected AUTH request
+            Nip42AuthState::NoAuth => return Err(Error::AuthFailure),
+        };
+        if event.validate().is_err() || event.kind != AUTH_EVENT_KIND {
+            return Err(Error::AuthFailure);
+        }
+        // the event must have been created recently
+        if event.created_at.abs_diff(unix_time()) > AUTH_MAX_SKEW_SECONDS {
+            return Err(Error::AuthFailure);
+        }
+        // the challenge must match what we sent
+        let challenge = event.tag_values_by_name("challenge");
+        if challenge.first() != Some(&sent_challenge) {
+            return Err(Error::AuthFailure);
+        }
+        // the relay tag must refer to this relay
+        let relay = event.tag_values_by_name("relay");
+        match (relay.first().and_then(|r| host_str(r)), host_str(relay_url)) {
+            (Some(received_relay), Some(our_relay)) if received_relay == our_relay => {}
+            _ => return Err(Error::AuthFailure),
+        }
+        self.auth = Nip42AuthState::AuthPubkey {
+            pubkey: event.pubkey.clone(),
+            challenge: sent_challenge,
+        };
+        trace!(
+            "authenticated pubkey {} (cid: {})",
+            event.get_author_prefix(),
+            self.get_client_prefix()
+        );
+        Ok(())
+    }
+
     /// Add a new subscription for this connection.
     /// # Errors
     ///
-    /// Will return `Err` if the client has too many subscriptions, or
-    /// if the provided name is excessively long.
+    /// Will return `Err` if the client has too many subscriptions, if
+    /// the provided name is excessively long, or if the subscription
+    /// has too many filters, ids, or authors.
     pub fn subscribe(&mut self, s: Subscription) -> Result<()> {
         let k = s.get_id();
-        let sub_id_len = k.len();
+        let sub_id_len = k.chars().count();
         // prevent arbitrarily long subscription identifiers from
         // being used.
         if sub_id_len > MAX_SUBSCRIPTION_ID_LEN {
@@ -80,6 +269,7 @@ impl ClientConn {
             );
             return Err(Error::SubIdMaxLengthError);
         }
+        self.check_filter_limits(&s)?;
         // check if an existing subscription exists, and replace if so
         if self.subscriptions.contains_key(&k) {
             self.subscriptions.remove(&k);
@@ -106,6 +296,36 @@ impl ClientConn {
         Ok(())
     }
 
+    /// Check that a count request keeps to the limits of a
+    /// subscription, with room for one while it runs.
+    pub fn check_count(&self, s: &Subscription) -> Result<()> {
+        self.check_filter_limits(s)?;
+        if !self.subscriptions.contains_key(&s.id) && self.subscriptions.len() >= self.max_subs {
+            return Err(Error::SubMaxExceededError);
+        }
+        Ok(())
+    }
+
+    /// Check a subscription against the filter limits.
+    fn check_filter_limits(&self, s: &Subscription) -> Result<()> {
+        if let Some(max) = self.max_filters {
+            if s.filters.len() > max {
+                return Err(Error::SubLimitError(format!("too many filters (max {max})")));
+            }
+        }
+        for f in &s.filters {
+            let ids = f.ids.as_ref().map_or(0, Vec::len);
+            if let Some(max) = self.max_ids.filter(|max| ids > *max) {
+                return Err(Error::SubLimitError(format!("too many ids in filter (max {max})")));
+            }
+            let authors = f.authors.as_ref().map_or(0, Vec::len);
+            if let Some(max) = self.max_authors.filter(|max| authors > *max) {
+                return Err(Error::SubLimitError(format!("too many authors in filter (max {max})")));
+            }
+        }
+        Ok(())
+    }
+
     /// Remove the subscription for this connection.
     pub fn unsubscribe(&mut self, c: &Close) {
         // TODO: return notice if subscription did not exist.
@@ -117,3 +337,206 @@ impl ClientConn {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin_hashes::{sha256, Hash};
+    use secp256k1::{KeyPair, Secp256k1, XOnlyPublicKey};
+    use serde_json::json;
+
+    const RELAY: &str = "wss://relay.example.com/";
+
+    // build a signed kind-22242 event answering a challenge
+    fn auth_event(challenge: &str, relay: &str, kind: u64, created_at: u64) -> Event {
+        let secp = Secp256k1::new();
+        let keypair = KeyPair::new(&secp, &mut secp256k1::rand::thread_rng());
+        let pubkey = XOnlyPublicKey::from_keypair(&keypair).to_string();
+        let tags = vec![
+            vec!["relay".to_owned(), relay.to_owned()],
+            vec!["challenge".to_owned(), challenge.to_owned()],
+        ];
+        let canonical = json!([0, pubkey, created_at, kind, tags, ""]).to_string();
+        let digest: sha256::Hash = sha256::Hash::hash(canonical.as_bytes());
+        let msg = secp256k1::Message::from_slice(digest.as_ref()).unwrap();
+        let sig = secp.sign_schnorr(&msg, &keypair);
+        Event {
+            id: format!("{digest:x}"),
+            pubkey,
+            delegated_by: None,
+            created_at,
+            kind,
+            tags,
+            content: "".to_owned(),
+            sig: sig.to_string(),
+            tagidx: None,
+        }
+    }
+
+    #[test]
+    fn auth_without_challenge() {
+        let mut conn = ClientConn::default();
+        let event = auth_event("abc", RELAY, AUTH_EVENT_KIND, unix_time());
+        assert!(conn.authenticate(&event, RELAY).is_err());
+        assert_eq!(conn.auth_pubkey(), None);
+    }
+
+    #[test]
+    fn auth_with_valid_event() {
+        let mut conn = ClientConn::default();
+        conn.generate_auth_challenge();
+        let challenge = conn.auth_challenge().unwrap().clone();
+        let event = auth_event(&challenge, RELAY, AUTH_EVENT_KIND, unix_time());
+        assert!(conn.authenticate(&event, RELAY).is_ok());
+        assert_eq!(conn.auth_pubkey(), Some(&event.pubkey));
+        assert_eq!(conn.auth_challenge(), None);
+    }
+
+    #[test]
+    fn auth_with_wrong_challenge() {
+        let mut conn = ClientConn::default();
+        conn.generate_auth_challenge();
+        let event = auth_event("not-the-challenge", RELAY, AUTH_EVENT_KIND, unix_time());
+        assert!(conn.authenticate(&event, RELAY).is_err());
+        assert_eq!(conn.auth_pubkey(), None);
+    }
+
+    #[test]
+    fn auth_with_wrong_relay() {
+        let mut conn = ClientConn::default();
+        conn.generate_auth_challenge();
+        let challenge = conn.auth_challenge().unwrap().clone();
+        let event = auth_event(&challenge, "wss://other.example.com", AUTH_EVENT_KIND, unix_time());
+        assert!(conn.authenticate(&event, RELAY).is_err());
+    }
+
+    #[test]
+    fn auth_with_wrong_kind() {
+        let mut conn = ClientConn::default();
+        conn.generate_auth_challenge();
+        let challenge = conn.auth_challenge().unwrap().clone();
+        let event = auth_event(&challenge, RELAY, 1, unix_time());
+        assert!(conn.authenticate(&event, RELAY).is_err());
+    }
+
+    #[test]
+    fn auth_with_stale_event() {
+        let mut conn = ClientConn::default();
+        conn.generate_auth_challenge();
+        let challenge = conn.auth_challenge().unwrap().clone();
+        let event = auth_event(&challenge, RELAY, AUTH_EVENT_KIND, unix_time() - 3600);
+        assert!(conn.authenticate(&event, RELAY).is_err());
+        let event = auth_event(&challenge, RELAY, AUTH_EVENT_KIND, u64::MAX);
+        assert!(conn.authenticate(&event, RELAY).is_err());
+    }
+
+    #[test]
+    fn reauth_is_checked() {
+        let mut conn = ClientConn::default();
+        conn.generate_auth_challenge();
+        let challenge = conn.auth_challenge().unwrap().clone();
+        let event = auth_event(&challenge, RELAY, AUTH_EVENT_KIND, unix_time());
+        conn.authenticate(&event, RELAY).unwrap();
+        // another pubkey, or an invalid event, is refused
+        let other = auth_event(&challenge, RELAY, AUTH_EVENT_KIND, unix_time());
+        assert!(conn.authenticate(&other, RELAY).is_err());
+        let mut forged = event.clone();
+        forged.created_at += 1;
+        assert!(conn.authenticate(&forged, RELAY).is_err());
+        assert!(conn.authenticate(&event, "wss://other.example.com").is_err());
+        assert_eq!(conn.auth_pubkey(), Some(&event.pubkey));
+        // the same pubkey may authenticate again
+        assert!(conn.authenticate(&event, RELAY).is_ok());
+    }
+
+    #[test]
+    fn gift_wrap_recipient() {
+        let mut conn = ClientConn::default();
+        conn.generate_auth_challenge();
+        let challenge = conn.auth_challenge().unwrap().clone();
+        let auth = auth_event(&challenge, RELAY, AUTH_EVENT_KIND, unix_time());
+        let mut wrap = Event::simple_event();
+        wrap.kind = 1059;
+        wrap.tags = vec![vec!["p".to_owned(), auth.pubkey.clone()]];
+        // unauthenticated clients are never recipients
+        assert!(!conn.is_gift_wrap_recipient(&wrap));
+        conn.authenticate(&auth, RELAY).unwrap();
+        assert!(conn.is_gift_wrap_recipient(&wrap));
+        wrap.tags = vec![vec!["p".to_owned(), "someone-else".to_owned()]];
+        assert!(!conn.is_gift_wrap_recipient(&wrap));
+    }
+
+    #[test]
+    fn dm_participant() {
+        let mut conn = ClientConn::default();
+        conn.generate_auth_challenge();
+        let challenge = conn.auth_challenge().unwrap().clone();
+        let auth = auth_event(&challenge, RELAY, AUTH_EVENT_KIND, unix_time());
+        let mut dm = Event::simple_event();
+        dm.kind = 4;
+        dm.pubkey = auth.pubkey.clone();
+        assert!(!conn.is_dm_participant(&dm));
+        conn.authenticate(&auth, RELAY).unwrap();
+        // author can read
+        assert!(conn.is_dm_participant(&dm));
+        // recipient can read
+        dm.pubkey = "someone-else".to_owned();
+        assert!(!conn.is_dm_participant(&dm));
+        dm.tags = vec![vec!["p".to_owned(), auth.pubkey.clone()]];
+        assert!(conn.is_dm_participant(&dm));
+    }
+
+    #[test]
+    fn subscription_limits() {
+        let mut limits = crate::config::Settings::default().limits;
+        limits.max_subscriptions = 1;
+        limits.max_filters = Some(2);
+        limits.max_authors_per_filter = Some(1);
+        let mut conn = ClientConn::default();
+        conn.set_limits(&limits);
+        let sub = |s: &str| serde_json::from_str::<Subscription>(s).unwrap();
+        assert!(matches!(
+            conn.subscribe(sub(r#"["REQ","a",{"kinds":[1]},{"kinds":[2]},{"kinds":[3]}]"#)),
+            Err(Error::SubLimitError(_))
+        ));
+        assert!(matches!(
+            conn.subscribe(sub(r#"["REQ","a",{"authors":["aa","bb"]}]"#)),
+            Err(Error::SubLimitError(_))
+        ));
+        assert!(conn.subscribe(sub(r#"["REQ","a",{"authors":["aa"]},{}]"#)).is_ok());
+        assert!(matches!(
+            conn.subscribe(sub(r#"["REQ","b",{}]"#)),
+            Err(Error::SubMaxExceededError)
+        ));
+        // counts keep to the same limits
+        assert!(matches!(
+            conn.check_count(&sub(r#"["REQ","a",{"authors":["aa","bb"]}]"#)),
+            Err(Error::SubLimitError(_))
+        ));
+        assert!(conn.check_count(&sub(r#"["REQ","a",{}]"#)).is_ok());
+        assert!(matches!(
+            conn.check_count(&sub(r#"["REQ","b",{}]"#)),
+            Err(Error::SubMaxExceededError)
+        ));
+    }
+
+    #[test]
+    fn tier_rate_limit() {
+        let mut conn = ClientConn::default();
+        let e = Event::simple_event();
+        assert!(conn.check_tier(&e).is_ok());
+        conn.set_tier(Some(Arc::new(Tier {
+            name: "free".to_owned(),
+            pubkeys: None,
+            paid: false,
+            nip05_domains: None,
+            messages_per_sec: Some(1),
+            max_event_bytes: None,
+            kinds: None,
+            max_age: None,
+        })));
+        assert_eq!(conn.tier_name(), Some("free"));
+        assert!(conn.check_tier(&e).is_ok());
+        assert_eq!(conn.check_tier(&e), Err(TierRefusal::RateLimited));
+    }
+}
@@ -0,0 +1,134 @@
+//! Limits on open websocket connections
+//!
+//! Connections are counted in total, and for each client IP address.
+//! A connection holds a [`ConnectionPermit`] while it is open; new
+//! connections are refused once either limit is reached.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Why a connection was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// The relay has too many connections
+    MaxConnections,
+    /// The client IP address has too many connections
+    MaxConnectionsPerIp,
+}
+
+impl Rejection {
+    /// Label for metrics
+    #[must_use]
+    pub fn label(&self) -> &'static str {
+        match self {
+            Rejection::MaxConnections => "max_connections",
+            Rejection::MaxConnectionsPerIp => "max_connections_per_ip",
+        }
+    }
+
+    /// Explanation for the client
+    #[must_use]
+    pub fn message(&self) -> &'static str {
+        match self {
+            Rejection::MaxConnections => "relay is at its connection limit, try again later",
+            Rejection::MaxConnectionsPerIp => "too many connections from this address",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counts {
+    total: usize,
+    by_ip: HashMap<String, usize>,
+}
+
+/// Open connection counts, shared by all connections.
+#[derive(Clone)]
+pub struct ConnectionLimits {
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    counts: Arc<Mutex<Counts>>,
+}
+
+impl ConnectionLimits {
+    #[must_use]
+    pub fn new(max_connections: Option<usize>, max_connections_per_ip: Option<usize>) -> Self {
+        ConnectionLimits {
+            max_connections,
+            max_connections_per_ip,
+            counts: Arc::new(Mutex::new(Counts::default())),
+        }
+    }
+
+    /// Count a new connection from an IP address, unless a limit has
+    /// been reached.
+    pub fn admit(&self, ip: &str) -> Result<ConnectionPermit, Rejection> {
+        let mut counts = self.counts.lock().unwrap();
+        if self.max_connections.is_some_and(|max| counts.total >= max) {
+            return Err(Rejection::MaxConnections);
+        }
+        let from_ip = counts.by_ip.get(ip).copied().unwrap_or(0);
+        if self.max_connections_per_ip.is_some_and(|max| from_ip >= max) {
+            return Err(Rejection::MaxConnectionsPerIp);
+        }
+        counts.total += 1;
+        counts.by_ip.insert(ip.to_owned(), from_ip + 1);
+        Ok(ConnectionPermit {
+            ip: ip.to_owned(),
+            counts: self.counts.clone(),
+        })
+    }
+
+    /// Open connections, and the number of addresses they are from.
+    #[must_use]
+    pub fn open(&self) -> (usize, usize) {
+        let counts = self.counts.lock().unwrap();
+        (counts.total, counts.by_ip.len())
+    }
+}
+
+/// An open connection, which is no longer counted once dropped.
+pub struct ConnectionPermit {
+    ip: String,
+    counts: Arc<Mutex<Counts>>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.total -= 1;
+        if let Some(n) = counts.by_ip.get_mut(&self.ip) {
+            *n -= 1;
+            if *n == 0 {
+                counts.by_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connections_per_ip_are_limited() {
+        let limits = ConnectionLimits::new(None, Some(2));
+        let a1 = limits.admit("1.1.1.1").unwrap();
+        let _a2 = limits.admit("1.1.1.1").unwrap();
+        assert_eq!(limits.admit("1.1.1.1").err(), Some(Rejection::MaxConnectionsPerIp));
+        assert!(limits.admit("2.2.2.2").is_ok());
+        assert_eq!(limits.open(), (2, 1));
+        drop(a1);
+        assert!(limits.admit("1.1.1.1").is_ok());
+    }
+
+    #[test]
+    fn total_connections_are_limited() {
+        let limits = ConnectionLimits::new(Some(2), Some(2));
+        let _a = limits.admit("1.1.1.1").unwrap();
+        let b = limits.admit("2.2.2.2").unwrap();
+        assert_eq!(limits.admit("3.3.3.3").err(), Some(Rejection::MaxConnections));
+        drop(b);
+        assert!(limits.admit("3.3.3.3").is_ok());
+        assert!(limits.counts.lock().unwrap().by_ip.get("2.2.2.2").is_none());
+    }
+}
@@ -1,34 +1,111 @@
 //! Event persistence and querying
+use crate::archive::{self, ArchiveRepo};
+use crate::bans::{Abuse, BanRegistry};
 use crate::config::Settings;
 use crate::error::{Error, Result};
-use crate::event::Event;
+use crate::event::{BroadcastEvent, Event};
+use crate::groups::{GroupRegistry, GroupUpdate};
+use crate::identity;
+use crate::mirror::RecentIds;
+use crate::nauthz;
 use crate::notice::Notice;
+use crate::payment::Payments;
+use crate::plugin::{EventPlugin, PluginAction};
+use crate::quota::{self, Quotas};
+use crate::repo::cache::CachedRepo;
+use crate::repo::deadline::DeadlineRepo;
+use crate::repo::lmdb::LmdbRepo;
+use crate::repo::memory::MemoryRepo;
+use crate::repo::mysql::{MysqlPool, MysqlRepo};
 use crate::repo::postgres::{PostgresPool, PostgresRepo};
+use crate::repo::recent::RecentRepo;
+use crate::repo::sharded::ShardedRepo;
 use crate::repo::sqlite::SqliteRepo;
-use crate::repo::NostrRepo;
-use crate::server::NostrMetrics;
-use governor::clock::Clock;
+use crate::repo::{fetch_events, NostrRepo};
+use crate::reports::Reports;
+use crate::reputation::{ReputationChange, Reputations};
+use crate::retention::{self, RetentionPolicy};
+use crate::server::{record_abuse, NostrMetrics};
+use crate::spam::{SpamFilter, Verdict};
+use crate::tiers::Tiers;
+use crate::utils::unix_time;
+use governor::clock::{Clock, DefaultClock};
+use governor::state::{InMemoryState, NotKeyed};
 use governor::{Quota, RateLimiter};
 use r2d2;
 use sqlx::pool::PoolOptions;
+use sqlx::mysql::MySqlConnectOptions;
 use sqlx::postgres::PgConnectOptions;
 use sqlx::ConnectOptions;
 use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, Instant};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, watch};
 use tracing::log::LevelFilter;
 use tracing::{debug, info, trace, warn};
 
 pub type SqlitePool = r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>;
 pub type PooledConnection = r2d2::PooledConnection<r2d2_sqlite::SqliteConnectionManager>;
 
+type WriteLimiter = RateLimiter<NotKeyed, InMemoryState, DefaultClock>;
+
 /// Events submitted from a client, with a return channel for notices
 pub struct SubmittedEvent {
     pub event: Event,
     pub notice_tx: tokio::sync::mpsc::Sender<Notice>,
     pub source_ip: String,
+    /// Pubkey the submitting client authenticated as (NIP-42)
+    pub auth_pubkey: Option<String>,
+    pub origin: Option<String>,
+    pub user_agent: Option<String>,
+    /// Send the event to subscribers once it is accepted
+    pub broadcast: bool,
 }
 
+/// Queue a client's event for the writer.  A full queue means writes
+/// are not keeping up, and the event is refused instead of making the
+/// client wait; it may try again later.
+///
+/// # Errors
+///
+/// Will return the notice to send the client, if the event could not
+/// be queued.
+pub fn submit_event(event_tx: &mpsc::Sender<SubmittedEvent>, submitted: SubmittedEvent) -> std::result::Result<(), Notice> {
+    match event_tx.try_send(submitted) {
+        Ok(()) => Ok(()),
+        Err(TrySendError::Full(s)) => {
+            debug!(id = %s.event.get_event_id_prefix(), "write queue is full, refusing event");
+            Err(Notice::error(s.event.id, "relay overloaded, try again"))
+        }
+        Err(TrySendError::Closed(s)) => Err(Notice::error(s.event.id, "relay is shutting down")),
+    }
+}
+
+/// An accepted event, waiting to be written in the next batch
+struct PendingWrite {
+    event: Event,
+    notice_tx: tokio::sync::mpsc::Sender<Notice>,
+    source_ip: String,
+    group_updates: Vec<GroupUpdate>,
+    broadcast: bool,
+    /// The author was charged for the event, and is refunded if it
+    /// is not stored
+    charged: bool,
+    start: Instant,
+}
+
+/// Number of recently stored event ids remembered by the writer, so
+/// that resubmitted events are acknowledged without checking or
+/// writing them again.  Events may since have been removed (by an
+/// admin, retention, quotas or archiving), so each is looked up
+/// before it is answered as a duplicate.
+const RECENT_STORED_IDS: usize = 100_000;
+
+/// Reason given for refusing an event of a kind the relay does not
+/// accept
+pub const KIND_NOT_ACCEPTED: &str = "event kind is not accepted by this relay";
+
 /// Database file
 pub const DB_FILE: &str = "nostr.db";
 
@@ -37,10 +114,65 @@ pub const DB_FILE: &str = "nostr.db";
 ///
 /// Will panic if the pool could not be created.
 pub async fn build_repo(settings: &Settings, metrics: NostrMetrics) -> Arc<dyn NostrRepo> {
-    match settings.database.engine.as_str() {
-        "sqlite" => Arc::new(build_sqlite_pool(settings, metrics).await),
-        "postgres" => Arc::new(build_postgres_pool(settings, metrics).await),
+    let repo: Arc<dyn NostrRepo> = match settings.database.engine.as_str() {
+        "sqlite" if settings.database.shard_by_month && !settings.database.in_memory => {
+            let repo = ShardedRepo::new(settings, metrics.clone());
+            repo.start().await.ok();
+            repo.migrate_up().await.ok();
+            Arc::new(repo)
+        }
+        "sqlite" => Arc::new(build_sqlite_pool(settings, metrics.clone()).await),
+        "postgres" => {
+            let repo = build_postgres_pool(settings, metrics.clone()).await;
+            repo.start().await.ok();
+            Arc::new(repo)
+        }
+        "mysql" => Arc::new(build_mysql_pool(settings, metrics.clone()).await),
+        "lmdb" => Arc::new(build_lmdb_repo(settings, metrics.clone()).await),
+        "memory" => {
+            let repo = MemoryRepo::new(settings, metrics.clone());
+            repo.start().await.ok();
+            Arc::new(repo)
+        }
         _ => panic!("Unknown database engine"),
+    };
+    let mut repo = with_archive(settings, repo);
+    if let Some(secs) = settings.limits.max_query_seconds {
+        repo = Arc::new(DeadlineRepo::new(repo, Duration::from_secs(secs), metrics));
+    }
+    if settings.database.recent_events_minutes > 0 {
+        let future = settings.options.reject_future_seconds.unwrap_or(0) as u64;
+        repo = Arc::new(RecentRepo::new(
+            repo,
+            Duration::from_secs(settings.database.recent_events_minutes * 60),
+            Duration::from_secs(future),
+        ));
+    }
+    if settings.database.query_cache_entries == 0 {
+        return repo;
+    }
+    Arc::new(CachedRepo::new(
+        repo,
+        settings.database.query_cache_entries,
+        Duration::from_secs(settings.database.query_cache_seconds),
+    ))
+}
+
+/// Also answer queries from the archive, if configured.
+fn with_archive(settings: &Settings, repo: Arc<dyn NostrRepo>) -> Arc<dyn NostrRepo> {
+    if !(settings.archive.enabled && settings.archive.fetch_on_query) {
+        return repo;
+    }
+    let Some(older_than) = settings.archive.older_than_duration() else {
+        warn!("could not parse archive older_than, archive will not be queried");
+        return repo;
+    };
+    match archive::build_store(settings) {
+        Ok(store) => Arc::new(ArchiveRepo::new(repo, store, older_than)),
+        Err(e) => {
+            warn!("could not open archive, it will not be queried: {:?}", e);
+            repo
+        }
     }
 }
 
@@ -51,7 +183,20 @@ async fn build_sqlite_pool(settings: &Settings, metrics: NostrMetrics) -> Sqlite
     repo
 }
 
-async fn build_postgres_pool(settings: &Settings, metrics: NostrMetrics) -> PostgresRepo {
+async fn build_lmdb_repo(settings: &Settings, metrics: NostrMetrics) -> LmdbRepo {
+    // Panic if the environment cannot be opened
+    let repo = LmdbRepo::new(settings, metrics).unwrap();
+    let version = repo.migrate_up().await.unwrap();
+    info!("LMDB migration completed, at v{}", version);
+    repo
+}
+
+/// Connect to the configured `PostgreSQL` database and run migrations.
+///
+/// # Panics
+///
+/// Will panic if the database cannot be reached, or migrations fail.
+pub async fn build_postgres_pool(settings: &Settings, metrics: NostrMetrics) -> PostgresRepo {
     let mut options: PgConnectOptions = settings.database.connection.as_str().parse().unwrap();
     options.log_statements(LevelFilter::Debug);
     options.log_slow_statements(LevelFilter::Warn, Duration::from_secs(60));
@@ -63,73 +208,198 @@ async fn build_postgres_pool(settings: &Settings, metrics: NostrMetrics) -> Post
         .connect_with(options)
         .await
         .unwrap();
-    let repo = PostgresRepo::new(pool, metrics);
+    let mut repo = PostgresRepo::new(pool, metrics).with_multi_letter_tags(settings.options.multi_letter_tags);
+    if let Some(conn_read) = &settings.database.connection_read {
+        let mut read_options: PgConnectOptions = conn_read.as_str().parse().unwrap();
+        read_options.log_statements(LevelFilter::Debug);
+        read_options.log_slow_statements(LevelFilter::Warn, Duration::from_secs(60));
+        // connect lazily, so an unavailable replica does not stop startup.
+        let read_pool: PostgresPool = PoolOptions::new()
+            .max_connections(settings.database.max_conn)
+            .min_connections(settings.database.min_conn)
+            .idle_timeout(Duration::from_secs(60))
+            .connect_lazy_with(read_options);
+        repo = repo.with_read_replica(read_pool);
+        info!("Postgres queries will use a read replica");
+    }
     // Panic on migration failure
     let version = repo.migrate_up().await.unwrap();
     info!("Postgres migration completed, at v{}", version);
     repo
 }
 
+/// Connect to the configured `MySQL` or `MariaDB` database and run
+/// migrations.
+///
+/// # Panics
+///
+/// Will panic if the database cannot be reached, or migrations fail.
+pub async fn build_mysql_pool(settings: &Settings, metrics: NostrMetrics) -> MysqlRepo {
+    let mut options: MySqlConnectOptions = settings.database.connection.as_str().parse().unwrap();
+    options.log_statements(LevelFilter::Debug);
+    options.log_slow_statements(LevelFilter::Warn, Duration::from_secs(60));
+
+    let pool: MysqlPool = PoolOptions::new()
+        .max_connections(settings.database.max_conn)
+        .min_connections(settings.database.min_conn)
+        .idle_timeout(Duration::from_secs(60))
+        .connect_with(options)
+        .await
+        .unwrap();
+    let repo = MysqlRepo::new(pool, metrics).with_multi_letter_tags(settings.options.multi_letter_tags);
+    // Panic on migration failure
+    let version = repo.migrate_up().await.unwrap();
+    info!("MySQL migration completed, at v{}", version);
+    repo
+}
+
 /// Spawn a database writer that persists events to the `SQLite` store.
+#[allow(clippy::too_many_arguments)]
 pub async fn db_writer(
     repo: Arc<dyn NostrRepo>,
-    settings: Settings,
+    mut settings_rx: watch::Receiver<Settings>,
     mut event_rx: tokio::sync::mpsc::Receiver<SubmittedEvent>,
-    bcast_tx: tokio::sync::broadcast::Sender<Event>,
+    bcast_tx: tokio::sync::broadcast::Sender<BroadcastEvent>,
     metadata_tx: tokio::sync::broadcast::Sender<Event>,
     mut shutdown: tokio::sync::broadcast::Receiver<()>,
     metrics: NostrMetrics,
+    groups: Arc<GroupRegistry>,
+    bans: Arc<BanRegistry>,
+    reputations: Arc<Reputations>,
+    reports: Arc<Reports>,
+    payments: Option<Arc<Payments>>,
 ) -> Result<()> {
+    let mut settings = settings_rx.borrow_and_update().clone();
     // are we performing NIP-05 checking?
-    let nip05_active = settings.verified_users.is_active();
+    let nip05_active =
+        settings.verified_users.is_active() || settings.authorization.nip05_domains.is_some();
     // are we requriing NIP-05 user verification?
     let nip05_enabled = settings.verified_users.is_enabled();
-    // are we requiring keyword filter?
-    let antispam_keywords_enabled = settings.antispam.use_keywords();
+    // the relay's own events are not held to the restrictions on
+    // authors.
+    let relay_pubkey = identity::relay_pubkey(&settings);
+    // spam policies, if configured
+    let mut spam_filter = SpamFilter::from_settings(&settings.antispam);
 
     //upgrade_db(&mut pool.get()?)?;
 
-    // Make a copy of the whitelist
-    let whitelist = &settings.authorization.pubkey_whitelist.clone();
+    // event policy plugin, if configured
+    let mut plugin = EventPlugin::from_settings(&settings.plugin);
+
+    // external authorization service, if configured
+    let mut admission = nauthz::client_for(&settings.grpc.endpoint, settings.grpc.admit_events);
 
     // get rate limit settings
-    let rps_setting = settings.limits.messages_per_sec;
     let mut most_recent_rate_limit = Instant::now();
-    let mut lim_opt = None;
+    let mut lim_opt = write_limiter(settings.limits.messages_per_sec);
     let clock = governor::clock::QuantaClock::default();
-    if let Some(rps) = rps_setting {
-        if rps > 0 {
-            info!("Enabling rate limits for event creation ({}/sec)", rps);
-            let quota = core::num::NonZeroU32::new(rps * 60).unwrap();
-            lim_opt = Some(RateLimiter::direct(Quota::per_minute(quota)));
+    // apply the rate limit, if defined, to events that were written.
+    let mut limit_rate = |lim_opt: &Option<WriteLimiter>, written: usize| {
+        let Some(lim) = lim_opt else {
+            return;
+        };
+        for _ in 0..written {
+            if let Err(n) = lim.check() {
+                let wait_for = n.wait_time_from(clock.now());
+                // check if we have recently logged rate
+                // limits, but print out a message only once
+                // per second.
+                if most_recent_rate_limit.elapsed().as_secs() > 10 {
+                    warn!(
+                        "rate limit reached for event creation (sleep for {:?}) (suppressing future messages for 10 seconds)",
+                        wait_for
+                    );
+                    // reset last rate limit message
+                    most_recent_rate_limit = Instant::now();
+                }
+                // block event writes, allowing them to queue up
+                thread::sleep(wait_for);
+            }
         }
-    }
+    };
+    let batch_size = settings.database.write_batch_size.max(1);
+    let batch_delay = Duration::from_millis(settings.database.write_batch_delay_ms);
+    let mut pending: Vec<PendingWrite> = Vec::with_capacity(batch_size);
+    let mut batch_deadline = tokio::time::Instant::now();
+    let mut stored = RecentIds::new(RECENT_STORED_IDS);
+    let quotas = Quotas::new(repo.clone());
     loop {
         if shutdown.try_recv().is_ok() {
             info!("shutting down database writer");
             break;
         }
-        // call blocking read on channel
-        let next_event = event_rx.recv().await;
-        // if the channel has closed, we will never get work
-        if next_event.is_none() {
-            break;
+        // pick up reloaded settings
+        if settings_rx.has_changed().unwrap_or(false) {
+            let reloaded = settings_rx.borrow_and_update().clone();
+            if reloaded.antispam != settings.antispam {
+                spam_filter = SpamFilter::from_settings(&reloaded.antispam);
+            }
+            if reloaded.limits.messages_per_sec != settings.limits.messages_per_sec {
+                lim_opt = write_limiter(reloaded.limits.messages_per_sec);
+            }
+            settings = reloaded;
         }
-        // track if an event write occurred; this is used to
-        // update the rate limiter
-        let mut event_write = false;
-        let subm_event = next_event.unwrap();
+        // add events to the pending batch until it is full, or no more
+        // arrive before its deadline, and then write it.
+        let mut next_event = None;
+        if pending.is_empty() {
+            // call blocking read on channel
+            next_event = event_rx.recv().await;
+            // if the channel has closed, we will never get work
+            if next_event.is_none() {
+                break;
+            }
+            batch_deadline = tokio::time::Instant::now() + batch_delay;
+        } else if pending.len() < batch_size {
+            // events already queued are taken, even after the deadline.
+            match tokio::time::timeout_at(batch_deadline, event_rx.recv()).await {
+                Ok(None) => break,
+                Ok(next) => next_event = next,
+                Err(_) => {}
+            }
+        }
+        let Some(subm_event) = next_event else {
+            let written = write_batch(repo.as_ref(), std::mem::take(&mut pending), &mut stored, &quotas, &bcast_tx, &groups, &reputations, &reports, payments.as_deref()).await;
+            limit_rate(&lim_opt, written);
+            continue;
+        };
         let event = subm_event.event;
         let notice_tx = subm_event.notice_tx;
+        // events stored recently are duplicates, and need no checks,
+        // unless they have since been removed.
+        if stored.contains(&event.id) {
+            let ids = [event.id.clone()];
+            if matches!(fetch_events(repo.as_ref(), &ids).await, Ok(found) if found.is_empty()) {
+                stored.remove(&event.id);
+            } else {
+                trace!(id = %event.get_event_id_prefix(), "ignoring recently stored duplicate event");
+                notice_tx.try_send(Notice::duplicate(event.id)).ok();
+                continue;
+            }
+        }
+        let from_relay = relay_pubkey.as_ref() == Some(&event.pubkey);
         // check if this event is authorized.
-        if let Some(allowed_addrs) = whitelist {
-            // TODO: incorporate delegated pubkeys
-            // if the event address is not in allowed_addrs.
-            if !allowed_addrs.contains(&event.pubkey) {
-                debug!(
-                    "rejecting event: {}, unauthorized author",
-                    event.get_event_id_prefix()
-                );
+        let mut awaiting_nip05 = false;
+        if settings.authorization.restricts_publishing() && !from_relay {
+            // an event is allowed if the author or its delegator is
+            // whitelisted, or if it was submitted by a client
+            // authenticated as a whitelisted pubkey.
+            let allowed_addrs = settings.authorization.pubkey_whitelist.as_deref().unwrap_or_default();
+            let auth_allowed = subm_event
+                .auth_pubkey
+                .iter()
+                .chain(event.delegated_by.iter())
+                .any(|pk| allowed_addrs.contains(pk));
+            let listed = allowed_addrs.contains(&event.pubkey) || auth_allowed;
+            // metadata naming an address at an allowed domain goes on
+            // to the verifier, which stores it if the address checks
+            // out.
+            awaiting_nip05 = !listed
+                && event
+                    .get_nip05_addr()
+                    .is_some_and(|n| settings.authorization.allows_nip05_domain(n.domain()));
+            if !listed && !awaiting_nip05 && !nip05_admitted(&repo, &settings, &event).await {
+                debug!(id = %event.get_event_id_prefix(), pubkey = %event.pubkey, "rejecting event, unauthorized author");
                 notice_tx
                     .try_send(Notice::blocked(
                         event.id,
@@ -140,44 +410,114 @@ pub async fn db_writer(
             }
         }
 
-        // Check that event kind isn't blacklisted
-        let kinds_blacklist = &settings.limits.event_kind_blacklist.clone();
-        if let Some(event_kind_blacklist) = kinds_blacklist {
-            if event_kind_blacklist.contains(&event.kind) {
-                debug!(
-                    "rejecting event: {}, blacklisted kind: {}",
-                    &event.get_event_id_prefix(),
-                    &event.kind
-                );
-                notice_tx
-                    .try_send(Notice::blocked(event.id, "event kind is blocked by relay"))
-                    .ok();
+        // on a paid relay, authors must have paid for admission.
+        if let Some(payments) = payments.as_ref().filter(|_| !from_relay) {
+            if !payments.is_admitted(&event.pubkey).await {
+                debug!(id = %event.get_event_id_prefix(), pubkey = %event.pubkey, "rejecting event, author not admitted");
+                let msg = format!("pubkey has not paid for admission; join at {}", payments.join_url());
+                notice_tx.try_send(Notice::restricted(event.id, &msg)).ok();
+                continue;
+            }
+            // and, when events have a cost, enough balance to pay it.
+            // they are charged once the event is ready to be queued.
+            if !payments.has_balance(&event.pubkey).await {
+                debug!(id = %event.get_event_id_prefix(), pubkey = %event.pubkey, "rejecting event, author balance too low");
+                let msg = format!("balance is too low; top up at {}", payments.join_url());
+                notice_tx.try_send(Notice::payment_required(event.id, &msg)).ok();
                 continue;
             }
         }
 
-        // drop events include keywords.
-        if antispam_keywords_enabled {
-            let start = Instant::now();
-            if Event::should_drop(settings.antispam.keywords.clone(), &event.content) {
-                info!(
-                    "rejecting spam event: {:?} form: {:?} in: {:?}",
-                    event.get_event_id_prefix(),
-                    event.get_author_prefix(),
-                    start.elapsed(),
-                );
-                metrics
-                    .spams
-                    .with_label_values(&[&event.get_author_prefix()])
-                    .inc();
-                notice_tx
-                    .try_send(Notice::blocked(
-                        event.id,
-                        "this event maybe spam, we droped it.",
-                    ))
-                    .ok();
+        // Check that the event kind is accepted.  Client events were
+        // checked when they were received, but the kinds may have
+        // been reloaded since, and mirrored events were not.
+        if !settings.limits.accepts_kind(event.kind) {
+            debug!(id = %event.get_event_id_prefix(), kind = event.kind, "rejecting event, kind not accepted");
+            notice_tx
+                .try_send(Notice::blocked(event.id, KIND_NOT_ACCEPTED))
+                .ok();
+            continue;
+        }
+
+        // check group (NIP-29) membership and moderation rights
+        let group_updates = match groups.authorize(&event) {
+            Ok(updates) => updates,
+            Err(msg) => {
+                debug!(id = %event.get_event_id_prefix(), reason = msg, "rejecting event, group restriction");
+                notice_tx.try_send(Notice::restricted(event.id, msg)).ok();
                 continue;
+            }
+        };
+
+        // drop events classified as spam.
+        if let Some((policy, verdict)) = spam_filter.check(&event) {
+            info!(
+                id = %event.get_event_id_prefix(),
+                pubkey = %event.pubkey,
+                ip = %subm_event.source_ip,
+                policy,
+                "rejecting spam event"
+            );
+            metrics
+                .spams
+                .with_label_values(&[&event.get_author_prefix()])
+                .inc();
+            match verdict {
+                Verdict::Reject(reason) => {
+                    record_abuse(&repo, &bans, &metrics, &subm_event.source_ip, Abuse::Spam).await;
+                    reputations.record(&event.pubkey, ReputationChange::Spam).await;
+                    notice_tx.try_send(Notice::blocked(event.id, &reason)).ok();
+                }
+                _ => {
+                    notice_tx.try_send(Notice::saved(event.id)).ok();
+                }
+            }
+            continue;
+        }
+
+        // consult the event policy plugin
+        if let Some(ref mut p) = plugin {
+            match p.check(&event, &subm_event.source_ip).await {
+                Some(resp) if resp.action == PluginAction::Reject => {
+                    debug!(id = %event.get_event_id_prefix(), "rejecting event, denied by plugin");
+                    let msg = resp.msg.unwrap_or_else(|| "event denied by relay policy".to_owned());
+                    let msg = msg.strip_prefix("blocked: ").unwrap_or(&msg);
+                    notice_tx.try_send(Notice::blocked(event.id, msg)).ok();
+                    continue;
+                }
+                Some(resp) if resp.action == PluginAction::ShadowReject => {
+                    debug!(id = %event.get_event_id_prefix(), "shadow-rejecting event");
+                    notice_tx.try_send(Notice::saved(event.id)).ok();
+                    continue;
+                }
+                Some(_) => {}
+                None => warn!("event plugin unavailable, accepting event"),
+            }
+        }
+
+        // consult the external authorization service last, since it
+        // requires a network round-trip.
+        if let Some(ref mut client) = admission {
+            let client_info = nauthz::Client {
+                ip_addr: subm_event.source_ip.clone(),
+                origin: subm_event.origin.clone(),
+                user_agent: subm_event.user_agent.clone(),
             };
+            match client
+                .admit_event(&event, client_info, subm_event.auth_pubkey.clone())
+                .await
+            {
+                Ok(reply) if reply.is_denied() => {
+                    debug!(id = %event.get_event_id_prefix(), "rejecting event, denied by authorization service");
+                    let msg = reply
+                        .message
+                        .unwrap_or_else(|| "event denied by relay policy".to_owned());
+                    notice_tx.try_send(Notice::blocked(event.id, &msg)).ok();
+                    continue;
+                }
+                Ok(_) => {}
+                Err(e) => warn!("authorization service failed, permitting event: {:?}", e),
+            }
         }
 
         // send any metadata events to the NIP-05 verifier
@@ -188,9 +528,36 @@ pub async fn db_writer(
             // event and broadcast it itself.
             metadata_tx.send(event.clone()).ok();
         }
+        if awaiting_nip05 && !nip05_admitted(&repo, &settings, &event).await {
+            debug!(id = %event.get_event_id_prefix(), pubkey = %event.pubkey, "rejecting event, awaiting NIP-05 verification");
+            notice_tx
+                .try_send(Notice::blocked(
+                    event.id,
+                    "NIP-05 verification needed to publish events",
+                ))
+                .ok();
+            continue;
+        }
 
-        // check for  NIP-05 verification
+        // a delegated event is allowed if the delegator is verified,
+        // even when the signing key is not.
+        let mut delegator_verified = false;
         if nip05_enabled {
+            if let Some(delegator) = &event.delegated_by {
+                if let Ok(uv) = repo.get_latest_user_verification(delegator).await {
+                    if uv.is_valid(&settings.verified_users) {
+                        info!(
+                            "new event from verified delegator ({:?},{:?})",
+                            uv.name.to_string(),
+                            event.get_author_prefix()
+                        );
+                        delegator_verified = true;
+                    }
+                }
+            }
+        }
+        // check for  NIP-05 verification
+        if nip05_enabled && !delegator_verified && !from_relay {
             match repo.get_latest_user_verification(&event.pubkey).await {
                 Ok(uv) => {
                     if uv.is_valid(&settings.verified_users) {
@@ -229,6 +596,9 @@ pub async fn db_writer(
                 }
                 Err(e) => {
                     warn!("checking nip05 verification status failed: {:?}", e);
+                    notice_tx
+                        .try_send(Notice::error(event.id, "could not check NIP-05 verification"))
+                        .ok();
                     continue;
                 }
             }
@@ -236,68 +606,293 @@ pub async fn db_writer(
         // TODO: cache recent list of authors to remove a DB call.
         let start = Instant::now();
         if event.is_ephemeral() {
-            bcast_tx.send(event.clone()).ok();
+            // ephemeral events (NIP-16) are only broadcast, never stored
+            if subm_event.broadcast {
+                bcast_tx.send(event.clone().into()).ok();
+            }
+            metrics.ephemeral_events.inc();
+            notice_tx.try_send(Notice::saved(event.id.clone())).ok();
             debug!(
-                "published ephemeral event: {:?} from: {:?} in: {:?}",
-                event.get_event_id_prefix(),
-                event.get_author_prefix(),
-                start.elapsed()
+                id = %event.get_event_id_prefix(),
+                kind = event.kind,
+                pubkey = %event.pubkey,
+                duration_ms = start.elapsed().as_millis() as u64,
+                "published ephemeral event"
             );
-            event_write = true;
-        } else {
-            match repo.write_event(&event).await {
-                Ok(updated) => {
-                    if updated == 0 {
-                        trace!("ignoring duplicate or deleted event");
-                        notice_tx.try_send(Notice::duplicate(event.id)).ok();
-                    } else {
-                        info!(
-                            "persisted event: {:?} (kind: {}) from: {:?} in: {:?} (IP: {:?})",
-                            event.get_event_id_prefix(),
-                            event.kind,
-                            event.get_author_prefix(),
-                            start.elapsed(),
-                            subm_event.source_ip,
-                        );
-                        event_write = true;
-                        // send this out to all clients
-                        bcast_tx.send(event.clone()).ok();
-                        notice_tx.try_send(Notice::saved(event.id)).ok();
+            limit_rate(&lim_opt, 1);
+            continue;
+        }
+        // authors may only store so many bytes of events.
+        let whitelisted = from_relay
+            || settings
+                .authorization
+                .pubkey_whitelist
+                .as_ref()
+                .is_some_and(|w| w.contains(&event.pubkey));
+        let paid = match &payments {
+            Some(p) if settings.limits.paid_max_bytes_per_pubkey.is_some() => p.is_admitted(&event.pubkey).await,
+            _ => false,
+        };
+        if let Some(q) = quota::quota_for(&settings.limits, whitelisted, paid) {
+            if quotas.exceeds(&event, q).await {
+                debug!(id = %event.get_event_id_prefix(), pubkey = %event.pubkey, "rejecting event, storage quota exceeded");
+                notice_tx.try_send(Notice::blocked(event.id, "storage quota exceeded")).ok();
+                continue;
+            }
+        }
+        // charge for the event before it is queued, so that events in
+        // the same batch cannot spend the same balance.
+        let charged = match payments.as_ref().filter(|_| !from_relay) {
+            Some(payments) if !payments.charge(&event.pubkey).await => {
+                debug!(id = %event.get_event_id_prefix(), pubkey = %event.pubkey, "rejecting event, author balance too low");
+                let msg = format!("balance is too low; top up at {}", payments.join_url());
+                notice_tx.try_send(Notice::payment_required(event.id, &msg)).ok();
+                continue;
+            }
+            Some(_) => true,
+            None => false,
+        };
+        // group management events end a batch, so that later events
+        // are authorized against the updated group state.
+        let ends_batch = !group_updates.is_empty();
+        pending.push(PendingWrite {
+            event,
+            notice_tx,
+            source_ip: subm_event.source_ip,
+            group_updates,
+            broadcast: subm_event.broadcast,
+            charged,
+            start,
+        });
+        if ends_batch {
+            let written = write_batch(repo.as_ref(), std::mem::take(&mut pending), &mut stored, &quotas, &bcast_tx, &groups, &reputations, &reports, payments.as_deref()).await;
+            limit_rate(&lim_opt, written);
+        }
+    }
+    let written = write_batch(repo.as_ref(), pending, &mut stored, &quotas, &bcast_tx, &groups, &reputations, &reports, payments.as_deref()).await;
+    limit_rate(&lim_opt, written);
+    info!("database connection closed");
+    Ok(())
+}
+
+/// Is the author or delegator of an event verified (NIP-05) at a
+/// domain that admits authors to publish?
+async fn nip05_admitted(repo: &Arc<dyn NostrRepo>, settings: &Settings, event: &Event) -> bool {
+    if settings.authorization.nip05_domains.is_none() {
+        return false;
+    }
+    for pubkey in std::iter::once(&event.pubkey).chain(event.delegated_by.iter()) {
+        if let Ok(uv) = repo.get_latest_user_verification(pubkey).await {
+            if uv.admits_author(settings) {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// Limit on events written, if `messages_per_sec` is set.
+fn write_limiter(messages_per_sec: Option<u32>) -> Option<WriteLimiter> {
+    let rps = messages_per_sec.filter(|rps| *rps > 0)?;
+    info!("Enabling rate limits for event creation ({}/sec)", rps);
+    let quota = core::num::NonZeroU32::new(rps * 60).unwrap();
+    Some(RateLimiter::direct(Quota::per_minute(quota)))
+}
+
+/// Write a batch of accepted events in one transaction, then notify
+/// each submitter and broadcast the new events.  If the transaction
+/// fails, events are retried one at a time, so a single bad event does
+/// not fail the rest.  Ids of stored events are remembered in
+/// `stored`.  Returns the number of events written.
+#[allow(clippy::too_many_arguments)]
+async fn write_batch(
+    repo: &dyn NostrRepo,
+    batch: Vec<PendingWrite>,
+    stored: &mut RecentIds,
+    quotas: &Quotas,
+    bcast_tx: &tokio::sync::broadcast::Sender<BroadcastEvent>,
+    groups: &GroupRegistry,
+    reputations: &Reputations,
+    reports: &Reports,
+    payments: Option<&Payments>,
+) -> usize {
+    if batch.is_empty() {
+        return 0;
+    }
+    let events: Vec<Event> = batch.iter().map(|p| p.event.clone()).collect();
+    let results: Vec<Result<u64>> = match repo.write_events(&events).await {
+        Ok(counts) => counts.into_iter().map(Ok).collect(),
+        Err(err) if events.len() > 1 => {
+            warn!("batch insert of {} events failed ({:?}), writing events individually", events.len(), err);
+            let mut results = Vec::with_capacity(events.len());
+            for e in &events {
+                results.push(repo.write_event(e).await);
+            }
+            results
+        }
+        Err(err) => vec![Err(err)],
+    };
+    if batch.len() > 1 {
+        debug!(
+            events = batch.len(),
+            duration_ms = batch[0].start.elapsed().as_millis() as u64,
+            "wrote batch of events"
+        );
+    }
+    let mut written = 0;
+    for (p, result) in batch.into_iter().zip(results) {
+        let event = p.event;
+        // events that were not stored are not paid for.
+        let was_stored = matches!(result, Ok(n) if n > 0);
+        if let Some(payments) = payments.filter(|_| p.charged && !was_stored) {
+            payments.refund(&event.pubkey).await;
+        }
+        match result {
+            Ok(0) => {
+                if repo.is_event_deleted(&event.id).await.unwrap_or(false) {
+                    debug!(id = %event.get_event_id_prefix(), "rejecting deleted event");
+                    p.notice_tx
+                        .try_send(Notice::blocked(event.id, "event was deleted by its author"))
+                        .ok();
+                } else {
+                    trace!("ignoring duplicate event");
+                    stored.insert(&event.id);
+                    p.notice_tx.try_send(Notice::duplicate(event.id)).ok();
+                }
+            }
+            Ok(_) => {
+                info!(
+                    id = %event.get_event_id_prefix(),
+                    kind = event.kind,
+                    pubkey = %event.pubkey,
+                    ip = %p.source_ip,
+                    duration_ms = p.start.elapsed().as_millis() as u64,
+                    "persisted event"
+                );
+                written += 1;
+                stored.insert(&event.id);
+                quotas.event_stored(&event);
+                // events deleted by this one are no longer stored
+                if event.kind == 5 {
+                    for id in event.tag_values_by_name("e") {
+                        stored.remove(&id);
+                    }
+                }
+                // update group state for accepted management events
+                for update in &p.group_updates {
+                    match repo.apply_group_update(update).await {
+                        Ok(()) => groups.apply(update),
+                        Err(err) => warn!("group update failed: {:?}", err),
                     }
                 }
-                Err(err) => {
-                    warn!("event insert failed: {:?}", err);
-                    let msg = "relay experienced an error trying to publish the latest event";
-                    notice_tx.try_send(Notice::error(event.id, msg)).ok();
+                reputations.event_stored(&event).await;
+                reports.event_stored(&event).await;
+                // send this out to all clients
+                if p.broadcast {
+                    bcast_tx.send(event.clone().into()).ok();
                 }
+                p.notice_tx.try_send(Notice::saved(event.id)).ok();
+            }
+            Err(err) => {
+                warn!("event insert failed: {:?}", err);
+                let msg = "relay experienced an error trying to publish the latest event";
+                p.notice_tx.try_send(Notice::error(event.id, msg)).ok();
             }
         }
+    }
+    written
+}
 
-        // use rate limit, if defined, and if an event was actually written.
-        if event_write {
-            if let Some(ref lim) = lim_opt {
-                if let Err(n) = lim.check() {
-                    let wait_for = n.wait_time_from(clock.now());
-                    // check if we have recently logged rate
-                    // limits, but print out a message only once
-                    // per second.
-                    if most_recent_rate_limit.elapsed().as_secs() > 10 {
-                        warn!(
-                            "rate limit reached for event creation (sleep for {:?}) (suppressing future messages for 10 seconds)",
-                            wait_for
-                        );
-                        // reset last rate limit message
-                        most_recent_rate_limit = Instant::now();
-                    }
-                    // block event writes, allowing them to queue up
-                    thread::sleep(wait_for);
-                    continue;
-                }
+/// Periodically remove events that the retention policy (or their
+/// author's tier) does not keep, and the oldest events if the database
+/// is over its size cap.
+pub async fn db_pruner(
+    repo: Arc<dyn NostrRepo>,
+    settings: Settings,
+    tiers: Option<Arc<Tiers>>,
+    mut shutdown: tokio::sync::broadcast::Receiver<()>,
+) {
+    let policy = RetentionPolicy::from_settings(&settings);
+    let max_bytes = settings.database.max_disk_bytes;
+    let active = policy.is_active() || tiers.as_ref().is_some_and(|t| t.has_retention());
+    if !active && max_bytes.is_none() {
+        return;
+    }
+    let interval = settings
+        .retention
+        .prune_interval_duration()
+        .unwrap_or_else(|| {
+            warn!("could not parse retention prune_interval, using 1 hour");
+            Duration::from_secs(3600)
+        });
+    info!("retention pruning every {:?}", interval);
+    loop {
+        let start = Instant::now();
+        if active {
+            match retention::prune(repo.as_ref(), &policy, tiers.as_deref(), unix_time()).await {
+                Ok(0) => debug!("no events expired ({:?})", start.elapsed()),
+                Ok(n) => info!("removed {} expired events in {:?}", n, start.elapsed()),
+                Err(e) => warn!("retention pruning failed: {:?}", e),
+            }
+        }
+        if let Some(max_bytes) = max_bytes {
+            if let Err(e) = retention::evict_oldest(repo.as_ref(), &policy, max_bytes).await {
+                warn!("database size cap enforcement failed: {:?}", e);
+            }
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {},
+            _ = shutdown.recv() => {
+                info!("shutting down retention pruner");
+                return;
+            }
+        }
+    }
+}
+
+/// Periodically move events older than the hot window into the
+/// archive, if archiving is enabled.
+pub async fn db_archiver(
+    repo: Arc<dyn NostrRepo>,
+    settings: Settings,
+    mut shutdown: tokio::sync::broadcast::Receiver<()>,
+) {
+    let cfg = &settings.archive;
+    if !cfg.enabled {
+        return;
+    }
+    let Some(older_than) = cfg.older_than_duration() else {
+        warn!("could not parse archive older_than, archiver disabled");
+        return;
+    };
+    let store = match archive::build_store(&settings) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("could not open archive, archiver disabled: {:?}", e);
+            return;
+        }
+    };
+    let interval = cfg.interval_duration().unwrap_or_else(|| {
+        warn!("could not parse archive interval, using 1 day");
+        Duration::from_secs(86400)
+    });
+    info!("archiving events older than {:?}, every {:?}", older_than, interval);
+    loop {
+        let start = Instant::now();
+        let cutoff = unix_time().saturating_sub(older_than.as_secs());
+        match archive::archive_events(repo.as_ref(), store.as_ref(), cutoff, cfg.segment_events).await {
+            Ok(0) => debug!("no events to archive ({:?})", start.elapsed()),
+            Ok(n) => info!("archived {} events in {:?}", n, start.elapsed()),
+            Err(e) => warn!("archiving failed: {:?}", e),
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {},
+            _ = shutdown.recv() => {
+                info!("shutting down archiver");
+                return;
             }
         }
     }
-    info!("database connection closed");
-    Ok(())
 }
 
 /// Serialized event associated with a specific subscription request.
@@ -111,7 +111,12 @@ impl ConditionQuery {
     let tok = format!("nostr:delegation:{delegatee}:{cond_query}");
     // form SHA256 hash
     let digest: sha256::Hash = sha256::Hash::hash(tok.as_bytes());
-    let sig = schnorr::Signature::from_str(sigstr).unwrap();
+    let sig = if let Ok(sig) = schnorr::Signature::from_str(sigstr) {
+        sig
+    } else {
+        debug!("client sent malformed delegation signature");
+        return None;
+    };
     if let Ok(msg) = secp256k1::Message::from_slice(digest.as_ref()) {
         if let Ok(pubkey) = XOnlyPublicKey::from_str(delegator) {
             let verify = SECP.verify_schnorr(&sig, &msg, &pubkey);
@@ -28,16 +28,22 @@ pub enum Error {
     #[error("Event could not canonicalize")]
     EventCouldNotCanonicalize,
     #[error("Event too large")]
-    EventMaxLengthError(usize),
+    EventMaxLengthError(usize, Option<String>),
+    #[error("Event could not be parsed: {1}")]
+    EventParseError(String, String),
     #[error("Subscription identifier max length exceeded")]
     SubIdMaxLengthError,
     #[error("Maximum concurrent subscription count reached")]
     SubMaxExceededError,
+    #[error("Subscription exceeds limits: {0}")]
+    SubLimitError(String),
+    #[error("Subscription could not be parsed: {1}")]
+    SubParseError(String, String),
     // this should be used if the JSON is invalid
     #[error("JSON parsing failed")]
     JsonParseFailed(serde_json::Error),
     #[error("WebSocket proto error")]
-    WebsocketError(WsError),
+    WebsocketError(Box<WsError>),
     #[error("Command unknown")]
     CommandUnknownError,
     #[error("SQL error")]
@@ -62,6 +68,16 @@ pub enum Error {
     HexError(hex::FromHexError),
     #[error("Delegation parse error")]
     DelegationParseError,
+    #[error("Authentication failed")]
+    AuthFailure,
+    #[error("gRPC error")]
+    GrpcError(Box<tonic::Status>),
+    #[error("LMDB error")]
+    LmdbError(Box<heed::Error>),
+    #[error("IO error")]
+    IoError(std::io::Error),
+    #[error("Negentropy error: {0}")]
+    NegentropyError(String),
     #[error("Unknown/Undocumented")]
     UnknownError,
 }
@@ -78,6 +94,24 @@ impl From<hex::FromHexError> for Error {
     }
 }
 
+impl From<heed::Error> for Error {
+    fn from(e: heed::Error) -> Self {
+        Error::LmdbError(Box::new(e))
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::IoError(e)
+    }
+}
+
+impl From<tonic::Status> for Error {
+    fn from(s: tonic::Status) -> Self {
+        Error::GrpcError(Box::new(s))
+    }
+}
+
 impl From<hyper::Error> for Error {
     fn from(h: hyper::Error) -> Self {
         Error::HyperError(h)
@@ -120,7 +154,7 @@ impl From<serde_json::Error> for Error {
 impl From<WsError> for Error {
     /// Wrap Websocket error
     fn from(r: WsError) -> Self {
-        Error::WebsocketError(r)
+        Error::WebsocketError(Box::new(r))
     }
 }
 
@@ -1,23 +1,23 @@
 //! Event parsing and validation
-use crate::config::Settings;
 use crate::delegation::validate_delegation;
 use crate::error::Error::{
-    CommandUnknownError, EventCouldNotCanonicalize, EventInvalidId, EventInvalidSignature,
-    EventMalformedPubkey,
+    CommandUnknownError, DelegationParseError, EventCouldNotCanonicalize, EventInvalidId,
 };
 use crate::error::Result;
 use crate::nip05;
+use crate::signature::{SignatureScheme, SignedDigest, SCHNORR};
 use crate::utils::unix_time;
 use bitcoin_hashes::{sha256, Hash};
 use lazy_static::lazy_static;
-use secp256k1::{schnorr, Secp256k1, VerifyOnly, XOnlyPublicKey};
+use secp256k1::{KeyPair, Secp256k1, VerifyOnly, XOnlyPublicKey};
 use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::value::Value;
-use serde_json::Number;
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::str::FromStr;
-use tracing::{debug, info};
+use std::ops::Deref;
+use std::sync::Arc;
+use tracing::debug;
 
 lazy_static! {
     /// Secp256k1 verification instance.
@@ -29,13 +29,89 @@ lazy_static! {
 pub struct EventCmd {
     cmd: String, // expecting static "EVENT"
     event: Event,
+    // Canonical serialization, if computed while parsing.
+    #[serde(skip)]
+    canonical: Option<String>,
 }
 
 impl EventCmd {
+    /// Wrap an event received from elsewhere for publishing.
+    #[must_use]
+    pub fn new(event: Event) -> Self {
+        EventCmd {
+            cmd: "EVENT".to_owned(),
+            event,
+            canonical: None,
+        }
+    }
+
+    /// Parse an `EVENT` message.  Fields are borrowed from the message
+    /// where possible, and the canonical serialization used to verify
+    /// the event id is written from them before the event is copied.
+    pub fn from_json(msg: &str) -> Result<Self> {
+        let RawEventCmd(cmd, raw) = serde_json::from_str(msg)?;
+        if cmd != "EVENT" {
+            return Err(CommandUnknownError);
+        }
+        let canonical = serde_json::to_string(&(
+            0,
+            &raw.pubkey,
+            raw.created_at,
+            raw.kind,
+            &raw.tags,
+            &raw.content,
+        ))?;
+        Ok(EventCmd {
+            cmd: cmd.into_owned(),
+            event: raw.into_event(),
+            canonical: Some(canonical),
+        })
+    }
+
     #[must_use]
     pub fn event_id(&self) -> &str {
         &self.event.id
     }
+
+    /// Check if this is an `EVENT` command, for an event to publish.
+    #[must_use]
+    pub fn is_event(&self) -> bool {
+        self.cmd == "EVENT"
+    }
+
+    #[must_use]
+    pub fn sig(&self) -> &str {
+        &self.event.sig
+    }
+
+    /// The canonical serialization of the event, which its id is the
+    /// hash of.
+    #[must_use]
+    pub fn canonical(&self) -> Option<Cow<'_, str>> {
+        match &self.canonical {
+            Some(c) => Some(Cow::Borrowed(c)),
+            None => self.event.to_canonical().map(Cow::Owned),
+        }
+    }
+
+    /// Convert an `EVENT` command into an event, once its id and
+    /// signature are known to be valid.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the event has a delegation tag that does
+    /// not validate.
+    pub fn into_verified(self) -> Result<EventWrapper> {
+        let mut e = self.event;
+        e.build_index();
+        e.update_delegation();
+        // a delegation tag that does not validate is an
+        // invalid event, not an undelegated one.
+        if e.delegated_by.is_none() && e.has_delegation_tag() {
+            return Err(DelegationParseError);
+        }
+        Ok(EventWrapper::WrappedEvent(e))
+    }
 }
 
 /// Parsed nostr event.
@@ -57,6 +133,57 @@ pub struct Event {
     pub tagidx: Option<HashMap<char, HashSet<String>>>,
 }
 
+/// Event command, with fields borrowed from the message.
+#[derive(Deserialize)]
+struct RawEventCmd<'a>(#[serde(borrow)] Cow<'a, str>, #[serde(borrow)] RawEvent<'a>);
+
+/// Event, with fields borrowed from the message.  Strings containing
+/// escapes are unescaped into owned copies.
+#[derive(Deserialize)]
+struct RawEvent<'a> {
+    #[serde(borrow)]
+    id: Cow<'a, str>,
+    #[serde(borrow)]
+    pubkey: Cow<'a, str>,
+    created_at: u64,
+    kind: u64,
+    #[serde(borrow, deserialize_with = "raw_tag_from_string")]
+    tags: Vec<Vec<Cow<'a, str>>>,
+    #[serde(borrow)]
+    content: Cow<'a, str>,
+    #[serde(borrow)]
+    sig: Cow<'a, str>,
+}
+
+impl RawEvent<'_> {
+    fn into_event(self) -> Event {
+        Event {
+            id: self.id.into_owned(),
+            pubkey: self.pubkey.into_owned(),
+            delegated_by: None,
+            created_at: self.created_at,
+            kind: self.kind,
+            tags: self
+                .tags
+                .into_iter()
+                .map(|t| t.into_iter().map(Cow::into_owned).collect())
+                .collect(),
+            content: self.content.into_owned(),
+            sig: self.sig.into_owned(),
+            tagidx: None,
+        }
+    }
+}
+
+/// Borrowing variant of [`tag_from_string`].
+fn raw_tag_from_string<'de: 'a, 'a, D>(deserializer: D) -> Result<Vec<Vec<Cow<'a, str>>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let opt: Option<Vec<Vec<Cow<'a, str>>>> = Option::deserialize(deserializer)?;
+    Ok(opt.unwrap_or_default())
+}
+
 /// Simple tag type for array of array of strings.
 type Tag = Vec<Vec<String>>;
 
@@ -69,6 +196,24 @@ where
     Ok(opt.unwrap_or_default())
 }
 
+/// Are events of this kind replaced by newer versions from the same
+/// author (including parameterized replaceable events)?
+#[must_use]
+pub fn is_replaceable_kind(kind: u64) -> bool {
+    kind == 0 || kind == 3 || kind == 41 || (10000..20000).contains(&kind) || (30000..40000).contains(&kind)
+}
+
+/// Longest tag name that is indexed.
+pub const MAX_TAG_NAME_LEN: usize = 64;
+
+/// Is a tag searchable in the database?  Single-letter tags always
+/// are, and tags with longer names if they are indexed.
+#[must_use]
+pub fn searchable_tagname(tagname: &str, multi_letter_tags: bool) -> bool {
+    single_char_tagname(tagname).is_some()
+        || (multi_letter_tags && !tagname.is_empty() && tagname.len() <= MAX_TAG_NAME_LEN)
+}
+
 /// Attempt to form a single-char tag name.
 #[must_use]
 pub fn single_char_tagname(tagname: &str) -> Option<char> {
@@ -89,23 +234,72 @@ pub fn single_char_tagname(tagname: &str) -> Option<char> {
     }
 }
 
+/// Parsed event, distinguished by the command it arrived with.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum EventWrapper {
+    /// An event to be published (`EVENT`)
+    WrappedEvent(Event),
+    /// A NIP-42 authentication event (`AUTH`)
+    WrappedAuth(Event),
+}
+
 /// Convert network event to parsed/validated event.
-impl From<EventCmd> for Result<Event> {
-    fn from(ec: EventCmd) -> Result<Event> {
+impl From<EventCmd> for Result<EventWrapper> {
+    fn from(ec: EventCmd) -> Result<EventWrapper> {
         // ensure command is correct
         if ec.cmd == "EVENT" {
-            ec.event.validate().map(|_| {
-                let mut e = ec.event;
-                e.build_index();
-                e.update_delegation();
-                e
-            })
+            let valid = match &ec.canonical {
+                Some(c) => ec.event.validate_canonical(c),
+                None => ec.event.validate(),
+            };
+            valid.and_then(|()| ec.into_verified())
+        } else if ec.cmd == "AUTH" {
+            // authentication events are validated against the
+            // connection's challenge, and never stored.
+            Ok(EventWrapper::WrappedAuth(ec.event))
         } else {
             Err(CommandUnknownError)
         }
     }
 }
 
+/// An event broadcast to subscribers, serialized once when it is
+/// sent, instead of by every connection it is delivered to.  Cloning
+/// only copies a reference.
+#[derive(Debug, Clone)]
+pub struct BroadcastEvent(Arc<(Event, String)>);
+
+impl BroadcastEvent {
+    /// Use the JSON the event was received as, instead of serializing
+    /// it again.
+    #[must_use]
+    pub fn with_json(event: Event, json: String) -> Self {
+        BroadcastEvent(Arc::new((event, json)))
+    }
+
+    /// Serialized event
+    #[must_use]
+    pub fn json(&self) -> &str {
+        &self.0 .1
+    }
+}
+
+impl From<Event> for BroadcastEvent {
+    fn from(event: Event) -> Self {
+        // events contain only strings and numbers, so always serialize.
+        let json = serde_json::to_string(&event).unwrap_or_default();
+        BroadcastEvent::with_json(event, json)
+    }
+}
+
+impl Deref for BroadcastEvent {
+    type Target = Event;
+
+    fn deref(&self) -> &Event {
+        &self.0 .0
+    }
+}
+
 impl Event {
     #[cfg(test)]
     #[must_use]
@@ -128,6 +322,18 @@ impl Event {
         self.kind == 0
     }
 
+    /// Is this a gift-wrapped event (NIP-59)?
+    #[must_use]
+    pub fn is_gift_wrap(&self) -> bool {
+        self.kind == 1059
+    }
+
+    /// Is this a direct message (NIP-04 or gift-wrapped NIP-17)?
+    #[must_use]
+    pub fn is_direct_message(&self) -> bool {
+        self.kind == 4 || self.is_gift_wrap()
+    }
+
     /// Should this event be persisted?
     #[must_use]
     pub fn is_ephemeral(&self) -> bool {
@@ -231,6 +437,14 @@ impl Event {
         }
     }
 
+    /// Does this event claim a delegation (NIP-26)?
+    #[must_use]
+    pub fn has_delegation_tag(&self) -> bool {
+        self.tags
+            .iter()
+            .any(|t| t.first().map(String::as_str) == Some("delegation"))
+    }
+
     /// Update delegation status
     pub fn update_delegation(&mut self) {
         self.delegated_by = self.delegated_author();
@@ -300,6 +514,66 @@ impl Event {
         true
     }
 
+    /// Is the event older than allowed, by more than
+    /// `reject_past_seconds`?
+    #[must_use]
+    pub fn is_too_old(&self, reject_past_seconds: Option<usize>) -> bool {
+        reject_past_seconds.is_some_and(|past| self.created_at.saturating_add(past as u64) < unix_time())
+    }
+
+    /// Check the number of tags, and the length of each tag element,
+    /// against their limits.
+    ///
+    /// # Errors
+    ///
+    /// Will return the reason to give the client, if a limit is
+    /// exceeded.
+    pub fn check_tag_limits(&self, max_tags: Option<usize>, max_value_bytes: Option<usize>) -> std::result::Result<(), String> {
+        if let Some(max) = max_tags {
+            if self.tags.len() > max {
+                return Err(format!("too many tags ({} > {max})", self.tags.len()));
+            }
+        }
+        if let Some(max) = max_value_bytes {
+            if let Some(long) = self.tags.iter().flatten().find(|v| v.len() > max) {
+                return Err(format!("tag value too long ({} > {max} bytes)", long.len()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Proof of work (NIP-13): the number of leading zero bits in
+    /// the event id.
+    #[must_use]
+    pub fn pow_difficulty(&self) -> u32 {
+        let mut bits = 0;
+        for c in self.id.chars() {
+            match c.to_digit(16) {
+                Some(0) => bits += 4,
+                Some(d) => return bits + d.leading_zeros() - 28,
+                None => break,
+            }
+        }
+        bits
+    }
+
+    /// Check if this event carries at least the given proof of work.
+    /// It needs a nonce tag, and if the tag commits to a target
+    /// difficulty, that must be met too (so an id that is lucky at a
+    /// lower target is not accepted).
+    #[must_use]
+    pub fn has_pow(&self, min_difficulty: u32) -> bool {
+        let Some(nonce) = self.tags.iter().find(|t| t.len() > 1 && t[0] == "nonce") else {
+            return false;
+        };
+        if let Some(target) = nonce.get(2) {
+            if !target.parse::<u32>().is_ok_and(|t| t >= min_difficulty) {
+                return false;
+            }
+        }
+        self.pow_difficulty() >= min_difficulty
+    }
+
     /// Check if this event has a valid signature.
     pub fn validate(&self) -> Result<()> {
         // TODO: return a Result with a reason for invalid events
@@ -313,7 +587,45 @@ impl Event {
             debug!("could not canonicalize");
             return Err(EventCouldNotCanonicalize);
         }
-        let c = c_opt.unwrap();
+        self.validate_canonical(&c_opt.unwrap())
+    }
+
+    /// Create an event authored by a keypair, created now, and sign
+    /// it.
+    #[must_use]
+    pub fn new_signed(keypair: &KeyPair, kind: u64, tags: Vec<Vec<String>>, content: String) -> Event {
+        let mut event = Event {
+            id: String::new(),
+            pubkey: XOnlyPublicKey::from_keypair(keypair).to_string(),
+            delegated_by: None,
+            created_at: unix_time(),
+            kind,
+            tags,
+            content,
+            sig: String::new(),
+            tagidx: None,
+        };
+        // an event of strings and numbers always serializes.
+        let c = event.to_canonical().unwrap_or_default();
+        let digest: sha256::Hash = sha256::Hash::hash(c.as_bytes());
+        event.id = format!("{digest:x}");
+        let msg = secp256k1::Message::from_slice(digest.as_ref()).unwrap();
+        event.sig = Secp256k1::signing_only().sign_schnorr(&msg, keypair).to_string();
+        event.build_index();
+        event
+    }
+
+    /// Check if this event has a valid signature, given its canonical
+    /// serialization.
+    fn validate_canonical(&self, c: &str) -> Result<()> {
+        let signed = self.signed_digest(c)?;
+        // * validate the message digest (sig) using the pubkey & computed sha256 message hash.
+        SCHNORR.verify(&signed)
+    }
+
+    /// The signed message hash of this event, once its id is checked
+    /// against it.
+    fn signed_digest(&self, c: &str) -> Result<SignedDigest<'_>> {
         // * compute the sha256sum.
         let digest: sha256::Hash = sha256::Hash::hash(c.as_bytes());
         let hex_digest = format!("{digest:x}");
@@ -322,57 +634,51 @@ impl Event {
             debug!("event id does not match digest");
             return Err(EventInvalidId);
         }
-        // * validate the message digest (sig) using the pubkey & computed sha256 message hash.
-        let sig = schnorr::Signature::from_str(&self.sig).unwrap();
-        if let Ok(msg) = secp256k1::Message::from_slice(digest.as_ref()) {
-            if let Ok(pubkey) = XOnlyPublicKey::from_str(&self.pubkey) {
-                SECP.verify_schnorr(&sig, &msg, &pubkey)
-                    .map_err(|_| EventInvalidSignature)
-            } else {
-                debug!("client sent malformed pubkey");
-                Err(EventMalformedPubkey)
+        Ok(SignedDigest {
+            digest: digest.into_inner(),
+            sig: &self.sig,
+            pubkey: &self.pubkey,
+        })
+    }
+
+    /// Check the ids and signatures of many events, verifying the
+    /// signatures as one batch.  Results are in the order of the
+    /// events.
+    #[must_use]
+    pub fn validate_all(events: &[Event]) -> Vec<Result<()>> {
+        let mut results: Vec<Result<()>> = Vec::with_capacity(events.len());
+        let mut batch = vec![];
+        let mut batched = vec![];
+        for (i, e) in events.iter().enumerate() {
+            let canonical = e.to_canonical().ok_or(EventCouldNotCanonicalize);
+            match canonical.and_then(|c| e.signed_digest(&c)) {
+                Ok(signed) => {
+                    batch.push(signed);
+                    batched.push(i);
+                    results.push(Ok(()));
+                }
+                Err(err) => results.push(Err(err)),
             }
-        } else {
-            info!("error converting digest to secp256k1 message");
-            Err(EventInvalidSignature)
         }
+        for (i, res) in batched.into_iter().zip(SCHNORR.verify_batch(&batch)) {
+            results[i] = res;
+        }
+        results
     }
 
     /// Convert event to canonical representation for signing.
     fn to_canonical(&self) -> Option<String> {
-        // create a JsonValue for each event element
-        let mut c: Vec<Value> = vec![];
-        // id must be set to 0
-        let id = Number::from(0_u64);
-        c.push(serde_json::Value::Number(id));
-        // public key
-        c.push(Value::String(self.pubkey.clone()));
-        // creation time
-        let created_at = Number::from(self.created_at);
-        c.push(serde_json::Value::Number(created_at));
-        // kind
-        let kind = Number::from(self.kind);
-        c.push(serde_json::Value::Number(kind));
-        // tags
-        c.push(self.tags_to_canonical());
-        // content
-        c.push(Value::String(self.content.clone()));
-        serde_json::to_string(&Value::Array(c)).ok()
-    }
-
-    /// Convert tags to a canonical form for signing.
-    fn tags_to_canonical(&self) -> Value {
-        let mut tags = Vec::<Value>::new();
-        // iterate over self tags,
-        for t in &self.tags {
-            // each tag is a vec of strings
-            let mut a = Vec::<Value>::new();
-            for v in t.iter() {
-                a.push(serde_json::Value::String(v.clone()));
-            }
-            tags.push(serde_json::Value::Array(a));
-        }
-        serde_json::Value::Array(tags)
+        // [0, pubkey, created_at, kind, tags, content], with no
+        // whitespace.  id must be set to 0.
+        serde_json::to_string(&(
+            0,
+            &self.pubkey,
+            self.created_at,
+            self.kind,
+            &self.tags,
+            &self.content,
+        ))
+        .ok()
     }
 
     /// Determine if the given tag and value set intersect with tags in this event.
@@ -391,14 +697,23 @@ impl Event {
         }
     }
 
+    /// Determine if the given tag and value set intersect with tags in
+    /// this event, for tag names of any length.  Only single-letter
+    /// tags are indexed, so others are found by scanning the tags.
     #[must_use]
-    pub fn should_drop(keywords: Option<Vec<String>>, text: &String) -> bool {
-        for keyword in keywords.unwrap() {
-            if text.to_lowercase().contains(&keyword.to_lowercase()) {
-                return true;
-            };
-        }
-        return false;
+    pub fn tag_val_intersect(&self, tagname: &str, check: &HashSet<String>) -> bool {
+        self.tags
+            .iter()
+            .any(|t| t.len() > 1 && t[0] == tagname && check.contains(&t[1]))
+    }
+
+    /// Determine if this event has the given tag and value.
+    #[must_use]
+    pub fn has_tag_val(&self, tagname: char, val: &str) -> bool {
+        self.tagidx
+            .as_ref()
+            .and_then(|idx| idx.get(&tagname))
+            .is_some_and(|valset| valset.contains(val))
     }
 }
 
@@ -413,6 +728,25 @@ mod tests {
         assert_eq!(event.id, "0");
     }
 
+    #[test]
+    fn signed_event_validates() {
+        let secp = Secp256k1::new();
+        let keypair = KeyPair::new(&secp, &mut secp256k1::rand::thread_rng());
+        let event = Event::new_signed(&keypair, 4, vec![vec!["p".to_owned(), "ab".to_owned()]], "hi".to_owned());
+        assert!(event.validate().is_ok());
+        assert_eq!(event.tag_values_by_name("p"), vec!["ab".to_owned()]);
+    }
+
+    #[test]
+    fn broadcast_event_serialized_once() {
+        let event = Event::simple_event();
+        let shared = BroadcastEvent::from(event.clone());
+        assert_eq!(shared.json(), serde_json::to_string(&event).unwrap());
+        assert_eq!(shared.id, event.id);
+        // clones share the same serialization
+        assert!(std::ptr::eq(shared.clone().json(), shared.json()));
+    }
+
     #[test]
     fn event_serialize() -> Result<()> {
         // serialize an event to JSON string
@@ -422,6 +756,48 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn backdated_event() {
+        let mut event = Event::simple_event();
+        assert!(!event.is_too_old(None));
+        assert!(event.is_too_old(Some(86400)));
+        event.created_at = unix_time() - 60;
+        assert!(!event.is_too_old(Some(86400)));
+    }
+
+    #[test]
+    fn tag_limits() {
+        let mut event = Event::simple_event();
+        event.tags = vec![
+            vec!["p".to_owned(), "ab".to_owned()],
+            vec!["t".to_owned(), "x".repeat(100)],
+        ];
+        assert!(event.check_tag_limits(None, None).is_ok());
+        assert!(event.check_tag_limits(Some(2), Some(100)).is_ok());
+        assert_eq!(event.check_tag_limits(Some(1), None).unwrap_err(), "too many tags (2 > 1)");
+        assert_eq!(
+            event.check_tag_limits(None, Some(99)).unwrap_err(),
+            "tag value too long (100 > 99 bytes)"
+        );
+    }
+
+    #[test]
+    fn pow_difficulty() {
+        let mut event = Event::simple_event();
+        event.id = "000006d8c378af1779d2feebc7603a125d99eca0ccf1085959b307f64e5dd358".to_owned();
+        assert_eq!(event.pow_difficulty(), 21);
+        // a nonce tag is required
+        assert!(!event.has_pow(20));
+        event.tags = vec![vec!["nonce".to_owned(), "776797".to_owned(), "20".to_owned()]];
+        assert!(event.has_pow(20));
+        assert!(!event.has_pow(22));
+        // the committed target must be met
+        event.tags = vec![vec!["nonce".to_owned(), "776797".to_owned(), "16".to_owned()]];
+        assert!(!event.has_pow(20));
+        event.tags = vec![vec!["nonce".to_owned(), "776797".to_owned()]];
+        assert!(event.has_pow(21));
+    }
+
     #[test]
     fn empty_event_tag_match() {
         let event = Event::simple_event();
@@ -473,6 +849,27 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn event_cmd_from_json() -> Result<()> {
+        let raw_json = r#"{"id":"1384757da583e6129ce831c3d7afc775a33a090578f888dd0d010328ad047d0c","pubkey":"bbbd9711d357df4f4e498841fd796535c95c8e751fa35355008a911c41265fca","created_at":1612650459,"kind":1,"tags":null,"content":"hello world","sig":"59d0cc47ab566e81f72fe5f430bcfb9b3c688cb0093d1e6daa49201c00d28ecc3651468b7938642869ed98c0f1b262998e49a05a6ed056c0d92b193f4e93bc21"}"#;
+        let ec = EventCmd::from_json(&format!(r#"["EVENT",{raw_json}]"#))?;
+        assert_eq!(ec.event, serde_json::from_str::<Event>(raw_json)?);
+        assert_eq!(ec.canonical, ec.event.to_canonical());
+        assert!(Result::<EventWrapper>::from(ec).is_ok());
+        assert!(EventCmd::from_json(&format!(r#"["AUTH",{raw_json}]"#)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn event_cmd_from_json_with_escapes() -> Result<()> {
+        let msg = r#"["EVENT",{"id":"0","pubkey":"0","created_at":1,"kind":1,"tags":[["t","a\"b"]],"content":"line\nbreak \u00e9","sig":"0"}]"#;
+        let ec = EventCmd::from_json(msg)?;
+        assert_eq!(ec.event.content, "line\nbreak é");
+        assert_eq!(ec.event.tags, vec![vec!["t".to_owned(), "a\"b".to_owned()]]);
+        assert_eq!(ec.canonical, ec.event.to_canonical());
+        Ok(())
+    }
+
     #[test]
     fn event_canonical() {
         let e = Event {
@@ -684,4 +1081,69 @@ mod tests {
         event.tags = vec![vec!["e".to_owned()]];
         assert_eq!(event.distinct_param(), Some("".to_string()));
     }
+
+    // sign an event with the given keypair, delegated by another
+    fn delegated_event(delegator: &secp256k1::KeyPair, delegatee: &secp256k1::KeyPair, conditions: &str) -> Event {
+        let secp = Secp256k1::new();
+        let delegator_pk = XOnlyPublicKey::from_keypair(delegator).to_string();
+        let delegatee_pk = XOnlyPublicKey::from_keypair(delegatee).to_string();
+        let token = format!("nostr:delegation:{delegatee_pk}:{conditions}");
+        let token_msg = secp256k1::Message::from_slice(sha256::Hash::hash(token.as_bytes()).as_ref()).unwrap();
+        let token_sig = secp.sign_schnorr(&token_msg, delegator);
+        let mut e = Event::simple_event();
+        e.pubkey = delegatee_pk;
+        e.created_at = 1_000;
+        e.kind = 1;
+        e.tags = vec![vec!["delegation".to_owned(), delegator_pk, conditions.to_owned(), token_sig.to_string()]];
+        let c = e.to_canonical().unwrap();
+        let digest: sha256::Hash = sha256::Hash::hash(c.as_bytes());
+        e.id = format!("{digest:x}");
+        let msg = secp256k1::Message::from_slice(digest.as_ref()).unwrap();
+        e.sig = secp.sign_schnorr(&msg, delegatee).to_string();
+        e
+    }
+
+    #[test]
+    fn delegated_event_accepted() {
+        let secp = Secp256k1::new();
+        let delegator = secp256k1::KeyPair::new(&secp, &mut secp256k1::rand::thread_rng());
+        let delegatee = secp256k1::KeyPair::new(&secp, &mut secp256k1::rand::thread_rng());
+        let event = delegated_event(&delegator, &delegatee, "kind=1&created_at>500");
+        let cmd = EventCmd::new(event);
+        match Result::<EventWrapper>::from(cmd) {
+            Ok(EventWrapper::WrappedEvent(e)) => {
+                assert_eq!(e.delegated_by, Some(XOnlyPublicKey::from_keypair(&delegator).to_string()));
+            }
+            _ => panic!("delegated event should be accepted"),
+        }
+    }
+
+    #[test]
+    fn delegated_event_rejected() {
+        let secp = Secp256k1::new();
+        let delegator = secp256k1::KeyPair::new(&secp, &mut secp256k1::rand::thread_rng());
+        let delegatee = secp256k1::KeyPair::new(&secp, &mut secp256k1::rand::thread_rng());
+        // the conditions do not permit this event's kind
+        let event = delegated_event(&delegator, &delegatee, "kind=7");
+        let cmd = EventCmd::new(event);
+        assert!(matches!(Result::<EventWrapper>::from(cmd), Err(DelegationParseError)));
+    }
+
+    #[test]
+    fn batches_match_single_checks() {
+        let secp = Secp256k1::new();
+        let keypair = KeyPair::new(&secp, &mut secp256k1::rand::thread_rng());
+        let mut events: Vec<Event> = (0..100)
+            .map(|n| Event::new_signed(&keypair, 1, vec![], n.to_string()))
+            .collect();
+        // a signature from another event does not verify
+        events[7].sig = events[8].sig.clone();
+        events[70].pubkey = "zz".to_owned();
+        let results = Event::validate_all(&events);
+        assert_eq!(results.len(), events.len());
+        for (e, r) in events.iter().zip(&results) {
+            assert_eq!(r.is_ok(), e.validate().is_ok());
+        }
+        assert_eq!(results.iter().filter(|r| r.is_err()).count(), 2);
+    }
 }
@@ -0,0 +1,340 @@
+//! Relay-based groups (NIP-29)
+use crate::config;
+use crate::event::Event;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+
+/// Add a user to a group (admin only)
+pub const KIND_PUT_USER: u64 = 9000;
+/// Remove a user from a group (admin only)
+pub const KIND_REMOVE_USER: u64 = 9001;
+/// Edit group metadata (admin only)
+pub const KIND_EDIT_METADATA: u64 = 9002;
+/// Create a new group
+pub const KIND_CREATE_GROUP: u64 = 9007;
+/// Delete a group (admin only)
+pub const KIND_DELETE_GROUP: u64 = 9008;
+/// Request to join a group
+pub const KIND_JOIN_REQUEST: u64 = 9021;
+/// Leave a group
+pub const KIND_LEAVE_REQUEST: u64 = 9022;
+
+/// Role of a pubkey within a group
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupRole {
+    Admin,
+    Member,
+}
+
+impl GroupRole {
+    #[must_use]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Admin => "admin",
+            Self::Member => "member",
+        }
+    }
+
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<GroupRole> {
+        match name {
+            "admin" => Some(Self::Admin),
+            "member" => Some(Self::Member),
+            _ => None,
+        }
+    }
+}
+
+/// A group and its membership
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct Group {
+    pub id: String,
+    /// Members (including admins) and their roles
+    pub members: HashMap<String, GroupRole>,
+}
+
+impl Group {
+    #[must_use]
+    pub fn is_member(&self, pubkey: &str) -> bool {
+        self.members.contains_key(pubkey)
+    }
+
+    #[must_use]
+    pub fn is_admin(&self, pubkey: &str) -> bool {
+        self.members.get(pubkey) == Some(&GroupRole::Admin)
+    }
+}
+
+/// Change to group state resulting from an accepted event
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum GroupUpdate {
+    Create { group: String, admin: String },
+    Delete { group: String },
+    /// Set the role of a pubkey, or remove them (`None`)
+    SetMember { group: String, pubkey: String, role: Option<GroupRole> },
+}
+
+/// The group identifier of an event (first `h` tag value)
+#[must_use]
+pub fn group_id(event: &Event) -> Option<String> {
+    event
+        .tags
+        .iter()
+        .find(|t| t.len() >= 2 && t[0] == "h")
+        .map(|t| t[1].clone())
+}
+
+/// In-memory view of group membership, used to authorize reads and
+/// writes without a database round-trip.  The repository remains
+/// the source of truth, and is loaded at startup.
+#[derive(Debug, Default)]
+pub struct GroupRegistry {
+    enabled: bool,
+    creators: Option<HashSet<String>>,
+    groups: RwLock<HashMap<String, Group>>,
+}
+
+impl GroupRegistry {
+    #[must_use]
+    pub fn new(settings: &config::Groups, groups: Vec<Group>) -> GroupRegistry {
+        GroupRegistry {
+            enabled: settings.enabled,
+            creators: settings
+                .creators
+                .as_ref()
+                .map(|c| c.iter().cloned().collect()),
+            groups: RwLock::new(groups.into_iter().map(|g| (g.id.clone(), g)).collect()),
+        }
+    }
+
+    #[must_use]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// A group and its current membership.
+    #[must_use]
+    pub fn get(&self, id: &str) -> Option<Group> {
+        self.groups.read().unwrap().get(id).cloned()
+    }
+
+    /// Determine if an event may be published, and the group state
+    /// changes that result from it.
+    pub fn authorize(&self, event: &Event) -> Result<Vec<GroupUpdate>, &'static str> {
+        if !self.enabled {
+            return Ok(vec![]);
+        }
+        let gid = match group_id(event) {
+            Some(g) => g,
+            None => return Ok(vec![]),
+        };
+        let groups = self.groups.read().unwrap();
+        let author = &event.pubkey;
+        if event.kind == KIND_CREATE_GROUP {
+            if groups.contains_key(&gid) {
+                return Err("group already exists");
+            }
+            if let Some(creators) = &self.creators {
+                if !creators.contains(author) {
+                    return Err("pubkey is not allowed to create groups");
+                }
+            }
+            return Ok(vec![GroupUpdate::Create {
+                group: gid,
+                admin: author.clone(),
+            }]);
+        }
+        let group = groups.get(&gid).ok_or("group does not exist")?;
+        match event.kind {
+            KIND_PUT_USER | KIND_REMOVE_USER | KIND_EDIT_METADATA | KIND_DELETE_GROUP
+                if !group.is_admin(author) =>
+            {
+                Err("only group admins may moderate a group")
+            }
+            // roles may follow the pubkey in a `p` tag
+            KIND_PUT_USER => Ok(event
+                .tags
+                .iter()
+                .filter(|t| t.len() >= 2 && t[0] == "p")
+                .map(|t| GroupUpdate::SetMember {
+                    group: gid.clone(),
+                    pubkey: t[1].clone(),
+                    role: Some(
+                        t.get(2)
+                            .and_then(|r| GroupRole::from_name(r))
+                            .unwrap_or(GroupRole::Member),
+                    ),
+                })
+                .collect()),
+            KIND_REMOVE_USER => Ok(event
+                .tag_values_by_name("p")
+                .into_iter()
+                .map(|pubkey| GroupUpdate::SetMember {
+                    group: gid.clone(),
+                    pubkey,
+                    role: None,
+                })
+                .collect()),
+            KIND_DELETE_GROUP => Ok(vec![GroupUpdate::Delete { group: gid }]),
+            // join requests are recorded for admins to act upon
+            KIND_JOIN_REQUEST => Ok(vec![]),
+            KIND_LEAVE_REQUEST => Ok(vec![GroupUpdate::SetMember {
+                group: gid,
+                pubkey: author.clone(),
+                role: None,
+            }]),
+            _ if group.is_member(author) => Ok(vec![]),
+            _ => Err("pubkey is not a member of this group"),
+        }
+    }
+
+    /// Apply a change to the in-memory group state.
+    pub fn apply(&self, update: &GroupUpdate) {
+        let mut groups = self.groups.write().unwrap();
+        match update {
+            GroupUpdate::Create { group, admin } => {
+                let mut g = Group {
+                    id: group.clone(),
+                    ..Default::default()
+                };
+                g.members.insert(admin.clone(), GroupRole::Admin);
+                groups.insert(group.clone(), g);
+            }
+            GroupUpdate::Delete { group } => {
+                groups.remove(group);
+            }
+            GroupUpdate::SetMember {
+                group,
+                pubkey,
+                role,
+            } => {
+                if let Some(g) = groups.get_mut(group) {
+                    match role {
+                        Some(r) => {
+                            g.members.insert(pubkey.clone(), *r);
+                        }
+                        None => {
+                            g.members.remove(pubkey);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Determine if a (possibly unauthenticated) client may read an event.
+    #[must_use]
+    pub fn can_read(&self, event: &Event, pubkey: Option<&String>) -> bool {
+        if !self.enabled {
+            return true;
+        }
+        match group_id(event) {
+            Some(gid) => match pubkey {
+                Some(pk) => self
+                    .groups
+                    .read()
+                    .unwrap()
+                    .get(&gid)
+                    .iter()
+                    .any(|g| g.is_member(pk)),
+                None => false,
+            },
+            None => true,
+        }
+    }
+
+    /// Determine if a client may read a serialized event.
+    #[must_use]
+    pub fn can_read_json(&self, event_json: &str, pubkey: Option<&String>) -> bool {
+        // avoid parsing events that cannot be group events
+        if !self.enabled || !event_json.contains("\"h\"") {
+            return true;
+        }
+        match serde_json::from_str::<Event>(event_json) {
+            Ok(e) => self.can_read(&e, pubkey),
+            Err(_) => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group_event(author: &str, kind: u64, group: &str, p: &[&str]) -> Event {
+        let mut e = Event::simple_event();
+        e.pubkey = author.to_owned();
+        e.kind = kind;
+        e.tags = vec![vec!["h".to_owned(), group.to_owned()]];
+        for pk in p {
+            e.tags.push(vec!["p".to_owned(), (*pk).to_owned()]);
+        }
+        e
+    }
+
+    fn registry() -> GroupRegistry {
+        let settings = config::Groups {
+            enabled: true,
+            creators: None,
+        };
+        GroupRegistry::new(&settings, vec![])
+    }
+
+    fn apply_all(reg: &GroupRegistry, e: &Event) {
+        for u in reg.authorize(e).unwrap() {
+            reg.apply(&u);
+        }
+    }
+
+    #[test]
+    fn non_group_events_allowed() {
+        let reg = registry();
+        let e = Event::simple_event();
+        assert_eq!(reg.authorize(&e), Ok(vec![]));
+        assert!(reg.can_read(&e, None));
+    }
+
+    #[test]
+    fn create_and_moderate() {
+        let reg = registry();
+        apply_all(&reg, &group_event("alice", KIND_CREATE_GROUP, "g", &[]));
+        // unknown groups cannot be posted to
+        assert!(reg.authorize(&group_event("alice", 1, "other", &[])).is_err());
+        // non-members cannot post or read
+        let post = group_event("bob", 1, "g", &[]);
+        assert!(reg.authorize(&post).is_err());
+        assert!(!reg.can_read(&post, Some(&"bob".to_owned())));
+        assert!(!reg.can_read(&post, None));
+        // only admins can add users
+        assert!(reg.authorize(&group_event("bob", KIND_PUT_USER, "g", &["bob"])).is_err());
+        apply_all(&reg, &group_event("alice", KIND_PUT_USER, "g", &["bob"]));
+        assert_eq!(reg.authorize(&post), Ok(vec![]));
+        assert!(reg.can_read(&post, Some(&"bob".to_owned())));
+        // removed users lose access
+        apply_all(&reg, &group_event("alice", KIND_REMOVE_USER, "g", &["bob"]));
+        assert!(reg.authorize(&post).is_err());
+    }
+
+    #[test]
+    fn duplicate_group_rejected() {
+        let reg = registry();
+        let create = group_event("alice", KIND_CREATE_GROUP, "g", &[]);
+        apply_all(&reg, &create);
+        assert!(reg.authorize(&create).is_err());
+        apply_all(&reg, &group_event("alice", KIND_DELETE_GROUP, "g", &[]));
+        assert!(reg.authorize(&create).is_ok());
+    }
+
+    #[test]
+    fn restricted_creators() {
+        let settings = config::Groups {
+            enabled: true,
+            creators: Some(vec!["alice".to_owned()]),
+        };
+        let reg = GroupRegistry::new(&settings, vec![]);
+        assert!(reg.authorize(&group_event("bob", KIND_CREATE_GROUP, "g", &[])).is_err());
+        assert!(reg.authorize(&group_event("alice", KIND_CREATE_GROUP, "g", &[])).is_ok());
+    }
+}
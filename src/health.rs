@@ -0,0 +1,163 @@
+//! Health and readiness checks
+//!
+//! `/healthz` reports whether the relay is alive: the database answers
+//! a trivial query, and the event writer and subscription matcher are
+//! running.  `/readyz` also requires that new events are not backing
+//! up, in the writer's queue or in the broadcast to subscribers.  Both
+//! answer with the status of each component as JSON, and with 503 when
+//! a check fails, for Kubernetes probes and load balancers.
+use crate::db::SubmittedEvent;
+use crate::event::BroadcastEvent;
+use crate::repo::NostrRepo;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc};
+
+/// Longest the database may take to answer.
+const DB_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A queue this full (in percent) is falling behind.
+const BACKLOG_PERCENT: usize = 90;
+
+/// Status of one part of the relay
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Component {
+    pub ok: bool,
+    /// Why the check failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Time taken to answer, for the database
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u128>,
+    /// Items waiting, for queues
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queued: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capacity: Option<usize>,
+}
+
+impl Component {
+    fn failed(error: &str) -> Component {
+        Component {
+            ok: false,
+            error: Some(error.to_owned()),
+            latency_ms: None,
+            queued: None,
+            capacity: None,
+        }
+    }
+
+    /// Status of a queue, which fails if it has stopped, or (when
+    /// checking readiness) is nearly full.
+    fn queue(stopped: bool, queued: usize, capacity: usize, ready: bool) -> Component {
+        let error = if stopped {
+            Some("stopped".to_owned())
+        } else if ready && queued * 100 >= capacity * BACKLOG_PERCENT {
+            Some("falling behind".to_owned())
+        } else {
+            None
+        };
+        Component {
+            ok: error.is_none(),
+            error,
+            latency_ms: None,
+            queued: Some(queued),
+            capacity: Some(capacity),
+        }
+    }
+}
+
+/// Status of the relay, and each of its components
+#[derive(Debug, Clone, Serialize)]
+pub struct Health {
+    pub ok: bool,
+    pub components: BTreeMap<&'static str, Component>,
+}
+
+/// What is needed to check the relay's health.
+pub struct HealthChecks {
+    repo: Arc<dyn NostrRepo>,
+    event_tx: mpsc::Sender<SubmittedEvent>,
+    bcast_tx: broadcast::Sender<BroadcastEvent>,
+    broadcast_capacity: usize,
+}
+
+impl HealthChecks {
+    #[must_use]
+    pub fn new(
+        repo: Arc<dyn NostrRepo>,
+        event_tx: mpsc::Sender<SubmittedEvent>,
+        bcast_tx: broadcast::Sender<BroadcastEvent>,
+        broadcast_capacity: usize,
+    ) -> HealthChecks {
+        HealthChecks {
+            repo,
+            event_tx,
+            bcast_tx,
+            broadcast_capacity,
+        }
+    }
+
+    /// Check the relay is alive or, with `ready`, that it can keep up
+    /// with new traffic.
+    pub async fn check(&self, ready: bool) -> Health {
+        let mut components = BTreeMap::new();
+        components.insert("database", self.check_database().await);
+        let writer_capacity = self.event_tx.max_capacity();
+        components.insert(
+            "writer",
+            Component::queue(
+                self.event_tx.is_closed(),
+                writer_capacity - self.event_tx.capacity(),
+                writer_capacity,
+                ready,
+            ),
+        );
+        // the subscription matcher is always subscribed.
+        components.insert(
+            "broadcast",
+            Component::queue(
+                self.bcast_tx.receiver_count() == 0,
+                self.bcast_tx.len(),
+                self.broadcast_capacity,
+                ready,
+            ),
+        );
+        Health {
+            ok: components.values().all(|c| c.ok),
+            components,
+        }
+    }
+
+    async fn check_database(&self) -> Component {
+        let start = Instant::now();
+        match tokio::time::timeout(DB_TIMEOUT, self.repo.ping()).await {
+            Ok(Ok(())) => Component {
+                ok: true,
+                error: None,
+                latency_ms: Some(start.elapsed().as_millis()),
+                queued: None,
+                capacity: None,
+            },
+            Ok(Err(e)) => Component::failed(&e.to_string()),
+            Err(_) => Component::failed("timed out"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queues_fail_when_stopped_or_behind() {
+        assert!(Component::queue(false, 10, 100, true).ok);
+        assert!(!Component::queue(true, 0, 100, false).ok);
+        let behind = Component::queue(false, 95, 100, true);
+        assert_eq!(behind.error.as_deref(), Some("falling behind"));
+        // a backlog does not mean the relay is dead
+        assert!(Component::queue(false, 100, 100, false).ok);
+    }
+}
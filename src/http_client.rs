@@ -0,0 +1,480 @@
+//! HTTP client for outbound requests
+//!
+//! Requests may go through a SOCKS5 proxy, such as Tor, which
+//! resolves names itself and so can reach `.onion` addresses.
+//! Without a proxy, names may be resolved with DNS over HTTPS (the
+//! JSON API offered by Cloudflare and Google) instead of the system
+//! resolver.
+//!
+//! Unless private addresses are allowed, connections to loopback,
+//! private, link-local and other non-public addresses are refused, so
+//! that requests for names chosen by users cannot probe the relay's
+//! own network.
+use crate::error::{Error, Result};
+use hyper::body::HttpBody;
+use hyper::client::connect::dns::{GaiResolver, Name};
+use hyper::client::HttpConnector;
+use hyper::service::Service;
+use hyper::{Body, Client, Request, Uri};
+use hyper_tls::HttpsConnector;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+type BoxFuture<T> = Pin<Box<dyn Future<Output = std::result::Result<T, BoxError>> + Send>>;
+
+/// Client for HTTP and HTTPS requests, connecting as configured.
+pub type HttpsClient = Client<HttpsConnector<Connector>, Body>;
+
+/// How long a DNS over HTTPS lookup may take.
+const DOH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest DNS over HTTPS response read.
+const MAX_DOH_RESPONSE: usize = 64 * 1024;
+
+/// Build a client that connects through a SOCKS5 proxy
+/// (`socks5://host:port`), or resolves names with a DNS over HTTPS
+/// server (such as `https://cloudflare-dns.com/dns-query`), or
+/// connects directly.  Names resolved through a proxy cannot be
+/// checked, so only addresses given literally are refused there.
+///
+/// # Errors
+///
+/// Will return `Err` if the proxy or DNS server address is invalid.
+pub fn https_client(
+    proxy: Option<&str>,
+    dns_over_https: Option<&str>,
+    allow_private: bool,
+) -> Result<HttpsClient> {
+    let route = match (proxy, dns_over_https) {
+        (Some(proxy), _) => Route::Socks(SocksConnector::new(proxy)?),
+        (None, Some(url)) => {
+            url.parse::<Uri>()
+                .map_err(|_| Error::CustomError(format!("invalid DNS over HTTPS URL: {url}")))?;
+            let resolver = DohResolver {
+                client: Client::builder().build::<_, Body>(HttpsConnector::new()),
+                url: url.to_owned(),
+            };
+            let mut http = HttpConnector::new_with_resolver(Checked::new(resolver, allow_private));
+            http.enforce_http(false);
+            Route::Doh(http)
+        }
+        (None, None) => {
+            let resolver = Checked::new(GaiResolver::new(), allow_private);
+            let mut http = HttpConnector::new_with_resolver(resolver);
+            http.enforce_http(false);
+            Route::Direct(http)
+        }
+    };
+    let connector = Connector {
+        route,
+        allow_private,
+    };
+    Ok(Client::builder().build::<_, Body>(HttpsConnector::new_with_connector(connector)))
+}
+
+/// Is an address reachable on the public internet?  Loopback,
+/// private, link-local, shared (carrier-grade NAT), multicast and
+/// reserved addresses are not.
+#[must_use]
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(v4) => is_public_v4(v4),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(a == 0
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_multicast()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        // shared address space (100.64.0.0/10)
+        || (a == 100 && (b & 0xc0) == 64)
+        // protocol assignments (192.0.0.0/24)
+        || ip.octets()[..3] == [192, 0, 0]
+        // benchmarking (198.18.0.0/15)
+        || (a == 198 && (b & 0xfe) == 18)
+        // reserved (240.0.0.0/4)
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // unique local (fc00::/7)
+        || (first & 0xfe00) == 0xfc00
+        // link-local (fe80::/10)
+        || (first & 0xffc0) == 0xfe80
+        // documentation (2001:db8::/32)
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+}
+
+/// Refused connection to a non-public address.
+fn private_error(host: &str) -> BoxError {
+    format!("refusing to connect to private address of {host}").into()
+}
+
+/// Opens the TCP connection for a request
+#[derive(Clone)]
+pub struct Connector {
+    route: Route,
+    allow_private: bool,
+}
+
+#[derive(Clone)]
+enum Route {
+    Direct(HttpConnector<Checked<GaiResolver>>),
+    Doh(HttpConnector<Checked<DohResolver>>),
+    Socks(SocksConnector),
+}
+
+impl Service<Uri> for Connector {
+    type Response = TcpStream;
+    type Error = BoxError;
+    type Future = BoxFuture<TcpStream>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<std::result::Result<(), BoxError>> {
+        match &mut self.route {
+            Route::Direct(c) => c.poll_ready(cx).map_err(Into::into),
+            Route::Doh(c) => c.poll_ready(cx).map_err(Into::into),
+            Route::Socks(_) => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        // addresses in the URL are connected to without resolving.
+        let host = uri.host().unwrap_or_default();
+        let literal = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = literal.parse::<IpAddr>() {
+            if !self.allow_private && !is_public(ip) {
+                let err = private_error(host);
+                return Box::pin(async move { Err(err) });
+            }
+        }
+        match &mut self.route {
+            Route::Direct(c) => {
+                let connecting = c.call(uri);
+                Box::pin(async move { connecting.await.map_err(Into::into) })
+            }
+            Route::Doh(c) => {
+                let connecting = c.call(uri);
+                Box::pin(async move { connecting.await.map_err(Into::into) })
+            }
+            Route::Socks(c) => {
+                let proxy = c.proxy.clone();
+                Box::pin(async move {
+                    let host = uri.host().ok_or("request has no host")?;
+                    let port = uri
+                        .port_u16()
+                        .unwrap_or(if uri.scheme_str() == Some("https") {
+                            443
+                        } else {
+                            80
+                        });
+                    Ok(socks5_connect(&proxy, host, port).await?)
+                })
+            }
+        }
+    }
+}
+
+/// Connects through a SOCKS5 proxy, which resolves names
+#[derive(Clone)]
+pub struct SocksConnector {
+    proxy: String,
+}
+
+impl SocksConnector {
+    fn new(proxy: &str) -> Result<SocksConnector> {
+        let addr = proxy
+            .strip_prefix("socks5://")
+            .or_else(|| proxy.strip_prefix("socks5h://"))
+            .ok_or_else(|| {
+                Error::CustomError(format!("proxy must be socks5://host:port: {proxy}"))
+            })?;
+        Ok(SocksConnector {
+            proxy: addr.trim_end_matches('/').to_owned(),
+        })
+    }
+}
+
+fn socks_error(msg: &str) -> io::Error {
+    io::Error::other(format!("SOCKS5 proxy: {msg}"))
+}
+
+/// Open a connection to a host through a SOCKS5 proxy, without
+/// authentication.  The host name is sent to the proxy to resolve.
+async fn socks5_connect(proxy: &str, host: &str, port: u16) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy).await?;
+    // offer only "no authentication"
+    stream.write_all(&[5, 1, 0]).await?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice != [5, 0] {
+        return Err(socks_error("authentication required"));
+    }
+    let host_len = u8::try_from(host.len()).map_err(|_| socks_error("host name too long"))?;
+    let mut request = vec![5, 1, 0, 3, host_len];
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != 5 || reply[1] != 0 {
+        return Err(socks_error(&format!(
+            "connect failed with code {}",
+            reply[1]
+        )));
+    }
+    // skip the bound address and port
+    let bound_len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => usize::from(stream.read_u8().await?),
+        _ => return Err(socks_error("invalid address type")),
+    };
+    let mut bound = vec![0u8; bound_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(stream)
+}
+
+/// Resolves names, and drops any non-public addresses unless they
+/// are allowed.
+#[derive(Clone)]
+pub struct Checked<R> {
+    resolver: R,
+    allow_private: bool,
+}
+
+impl<R> Checked<R> {
+    fn new(resolver: R, allow_private: bool) -> Self {
+        Checked {
+            resolver,
+            allow_private,
+        }
+    }
+}
+
+impl<R> Service<Name> for Checked<R>
+where
+    R: Service<Name>,
+    R::Response: Iterator<Item = SocketAddr>,
+    R::Error: Into<BoxError>,
+    R::Future: Send + 'static,
+{
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = BoxError;
+    type Future = BoxFuture<Self::Response>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<std::result::Result<(), BoxError>> {
+        self.resolver.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let allow_private = self.allow_private;
+        let resolving = self.resolver.call(name.clone());
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = resolving
+                .await
+                .map_err(Into::into)?
+                .filter(|a| allow_private || is_public(a.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(private_error(name.as_str()));
+            }
+            Ok(addrs.into_iter())
+        })
+    }
+}
+
+/// Read a response body, up to a size limit.
+///
+/// # Errors
+///
+/// Will return `Err` if the body could not be read, or is larger than
+/// the limit.
+pub async fn read_limited(mut body: Body, limit: usize) -> Result<Vec<u8>> {
+    if body.size_hint().lower() > limit as u64 {
+        return Err(Error::CustomError("response too large".to_owned()));
+    }
+    let mut bytes = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if bytes.len() + chunk.len() > limit {
+            return Err(Error::CustomError("response too large".to_owned()));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+/// Resolves names with a DNS over HTTPS server
+#[derive(Clone)]
+pub struct DohResolver {
+    client: Client<HttpsConnector<HttpConnector>, Body>,
+    url: String,
+}
+
+impl Service<Name> for DohResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = BoxError;
+    type Future = BoxFuture<Self::Response>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<std::result::Result<(), BoxError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let client = self.client.clone();
+        let url = self.url.clone();
+        Box::pin(async move {
+            let mut addrs = vec![];
+            for record in ["A", "AAAA"] {
+                addrs.extend(doh_lookup(&client, &url, name.as_str(), record).await?);
+            }
+            if addrs.is_empty() {
+                return Err(format!("no address found for {}", name.as_str()).into());
+            }
+            Ok(addrs.into_iter())
+        })
+    }
+}
+
+async fn doh_lookup(
+    client: &Client<HttpsConnector<HttpConnector>, Body>,
+    url: &str,
+    host: &str,
+    record: &str,
+) -> std::result::Result<Vec<SocketAddr>, BoxError> {
+    let uri: Uri = format!("{url}?name={host}&type={record}").parse()?;
+    let req = Request::get(uri)
+        .header("Accept", "application/dns-json")
+        .body(Body::empty())?;
+    let response = tokio::time::timeout(DOH_TIMEOUT, client.request(req)).await??;
+    if !response.status().is_success() {
+        return Err(format!("DNS over HTTPS server returned {}", response.status()).into());
+    }
+    let body = read_limited(response.into_body(), MAX_DOH_RESPONSE).await?;
+    Ok(answer_addrs(&body)?)
+}
+
+/// Addresses in the answer of a DNS JSON response.  The port is set
+/// by the connector.
+fn answer_addrs(body: &[u8]) -> Result<Vec<SocketAddr>> {
+    let response: serde_json::Value = serde_json::from_slice(body)?;
+    let answers = response.get("Answer").and_then(serde_json::Value::as_array);
+    Ok(answers
+        .into_iter()
+        .flatten()
+        // A and AAAA records; others (such as CNAME) are skipped.
+        .filter(|a| {
+            matches!(
+                a.get("type").and_then(serde_json::Value::as_u64),
+                Some(1 | 28)
+            )
+        })
+        .filter_map(|a| a.get("data")?.as_str()?.parse::<IpAddr>().ok())
+        .map(|ip| SocketAddr::new(ip, 0))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn doh_answers_are_parsed() {
+        let body = br#"{"Status":0,"Answer":[
+            {"name":"example.com","type":5,"TTL":60,"data":"cdn.example.com."},
+            {"name":"cdn.example.com","type":1,"TTL":60,"data":"93.184.216.34"},
+            {"name":"cdn.example.com","type":28,"TTL":60,"data":"2606:2800:220:1::1"}]}"#;
+        let addrs = answer_addrs(body).unwrap();
+        assert_eq!(addrs.len(), 2);
+        assert_eq!(addrs[0].ip().to_string(), "93.184.216.34");
+        assert!(answer_addrs(br#"{"Status":3}"#).unwrap().is_empty());
+    }
+
+    #[test]
+    fn private_addresses_are_found() {
+        for private in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(private.parse().unwrap()), "{private}");
+        }
+        for public in ["93.184.216.34", "1.1.1.1", "2606:4700::1111"] {
+            assert!(is_public(public.parse().unwrap()), "{public}");
+        }
+    }
+
+    #[tokio::test]
+    async fn private_addresses_are_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url: Uri = format!("http://{}/", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        let client = https_client(None, None, false).unwrap();
+        let err = client.get(url.clone()).await.unwrap_err();
+        assert!(err.is_connect());
+        // allowed, the connection is made
+        let client = https_client(None, None, true).unwrap();
+        let requested = tokio::spawn(async move { client.get(url).await });
+        assert!(listener.accept().await.is_ok());
+        requested.abort();
+    }
+
+    #[tokio::test]
+    async fn socks5_sends_host_name() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            conn.read_exact(&mut greeting).await.unwrap();
+            conn.write_all(&[5, 0]).await.unwrap();
+            let mut request = vec![0u8; 5 + 10 + 2];
+            conn.read_exact(&mut request).await.unwrap();
+            conn.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            conn.write_all(b"hello").await.unwrap();
+            request
+        });
+        let mut stream = socks5_connect(&proxy, "abcd.onion", 80).await.unwrap();
+        let request = server.await.unwrap();
+        assert_eq!(&request[..5], &[5, 1, 0, 3, 10]);
+        assert_eq!(&request[5..15], b"abcd.onion");
+        assert_eq!(&request[15..], &80u16.to_be_bytes());
+        // the stream continues after the reply
+        let mut data = [0u8; 5];
+        stream.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"hello");
+        assert!(SocksConnector::new("http://localhost:9050").is_err());
+    }
+}
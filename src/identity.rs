@@ -0,0 +1,333 @@
+//! Events published by the relay itself
+//!
+//! A relay with its own keypair signs events as itself: a profile
+//! (kind 0) describing the relay, announcements from admins, and the
+//! metadata, admins and members of each group (NIP-29).  They are
+//! sent to the database writer like injected events, and broadcast
+//! once written.  The writer does not hold the relay's events to the
+//! whitelist, NIP-05 verification, paid admission or storage quotas.
+use crate::config::{Info, Settings};
+use crate::db::SubmittedEvent;
+use crate::error::{Error, Result};
+use crate::event::{BroadcastEvent, Event};
+use crate::groups::{self, Group, GroupRegistry, GroupRole};
+use crate::inject;
+use crate::notice::Notice;
+use secp256k1::{KeyPair, Secp256k1, SecretKey, XOnlyPublicKey};
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc};
+use tracing::{info, warn};
+
+/// Address recorded for events the relay publishes
+const RELAY_SOURCE: &str = "relay";
+
+/// Relay-signed group metadata (NIP-29)
+pub const KIND_GROUP_METADATA: u64 = 39000;
+/// Relay-signed list of group admins (NIP-29)
+pub const KIND_GROUP_ADMINS: u64 = 39001;
+/// Relay-signed list of group members (NIP-29)
+pub const KIND_GROUP_MEMBERS: u64 = 39002;
+
+/// Metadata tags copied from a request to edit a group
+const METADATA_TAGS: [&str; 7] = [
+    "name", "about", "picture", "public", "private", "open", "closed",
+];
+
+fn keypair(secret_key: &str) -> Result<KeyPair> {
+    let secret = SecretKey::from_str(secret_key)
+        .map_err(|_| Error::CustomError("invalid relay secret key".to_owned()))?;
+    Ok(KeyPair::from_secret_key(&Secp256k1::signing_only(), secret))
+}
+
+/// The relay's pubkey, if it has a valid keypair.
+#[must_use]
+pub fn relay_pubkey(settings: &Settings) -> Option<String> {
+    let key = settings.identity.key(&settings.admin)?;
+    keypair(key)
+        .ok()
+        .map(|k| XOnlyPublicKey::from_keypair(&k).to_string())
+}
+
+/// The relay's keypair, and the writer its events are sent to.
+pub struct RelayIdentity {
+    keypair: KeyPair,
+    pubkey: String,
+    event_tx: mpsc::Sender<SubmittedEvent>,
+}
+
+impl RelayIdentity {
+    /// Load the relay's keypair, if one is configured.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the secret key is invalid.
+    pub fn new(
+        settings: &Settings,
+        event_tx: mpsc::Sender<SubmittedEvent>,
+    ) -> Result<Option<RelayIdentity>> {
+        let Some(key) = settings.identity.key(&settings.admin) else {
+            return Ok(None);
+        };
+        let keypair = keypair(key)?;
+        Ok(Some(RelayIdentity {
+            keypair,
+            pubkey: XOnlyPublicKey::from_keypair(&keypair).to_string(),
+            event_tx,
+        }))
+    }
+
+    /// The relay's pubkey.
+    #[must_use]
+    pub fn pubkey(&self) -> &str {
+        &self.pubkey
+    }
+
+    /// Sign an event as the relay, without publishing it.
+    #[must_use]
+    pub fn sign(&self, kind: u64, tags: Vec<Vec<String>>, content: String) -> Event {
+        Event::new_signed(&self.keypair, kind, tags, content)
+    }
+
+    /// Sign and publish an event, and wait for it to be written.
+    /// Returns the id of the event.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` with the writer's reason if the event was
+    /// refused, or if the relay has stopped.
+    pub async fn publish(
+        &self,
+        kind: u64,
+        tags: Vec<Vec<String>>,
+        content: String,
+    ) -> Result<String> {
+        let event = inject::prepare(self.sign(kind, tags, content))?;
+        match inject::inject(&self.event_tx, vec![event], RELAY_SOURCE)
+            .await
+            .pop()
+        {
+            Some(Notice::EventResult(r)) if r.status.to_bool() => Ok(r.id),
+            Some(Notice::EventResult(r)) => Err(Error::CustomError(r.msg)),
+            _ => Err(Error::CustomError("event was not written".to_owned())),
+        }
+    }
+
+    /// Publish a note (kind 1) from the relay.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the note was not written.
+    pub async fn announce(&self, text: &str) -> Result<String> {
+        self.publish(1, vec![], text.to_owned()).await
+    }
+
+    /// Publish a profile describing the relay, from its information
+    /// document settings.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the profile was not written.
+    pub async fn publish_profile(&self, info: &Info) -> Result<String> {
+        self.publish(0, vec![], profile(info)).await
+    }
+}
+
+/// Profile (kind 0) content describing the relay.
+fn profile(info: &Info) -> String {
+    let mut fields = serde_json::Map::new();
+    for (name, value) in [
+        ("name", &info.name),
+        ("about", &info.description),
+        ("picture", &info.icon),
+    ] {
+        if let Some(v) = value {
+            fields.insert(name.to_owned(), v.clone().into());
+        }
+    }
+    serde_json::Value::Object(fields).to_string()
+}
+
+fn d_tag(group: &str) -> Vec<String> {
+    vec!["d".to_owned(), group.to_owned()]
+}
+
+/// Tags of a group's metadata, from the request that created or
+/// edited it.  A new group is named by its id.
+fn metadata_tags(request: &Event, group: &str) -> Vec<Vec<String>> {
+    let mut tags = vec![d_tag(group)];
+    tags.extend(
+        request
+            .tags
+            .iter()
+            .filter(|t| !t.is_empty() && METADATA_TAGS.contains(&t[0].as_str()))
+            .cloned(),
+    );
+    if !tags.iter().any(|t| t[0] == "name") {
+        tags.push(vec!["name".to_owned(), group.to_owned()]);
+    }
+    tags
+}
+
+/// Tags of a group's lists of admins and members.  A deleted group
+/// has empty lists.
+fn member_tags(group: &Group) -> [(u64, Vec<Vec<String>>); 2] {
+    let mut members: Vec<(&String, &GroupRole)> = group.members.iter().collect();
+    members.sort_by_key(|(pk, _)| *pk);
+    let mut admins = vec![d_tag(&group.id)];
+    admins.extend(
+        members
+            .iter()
+            .filter(|(_, r)| **r == GroupRole::Admin)
+            .map(|(pk, r)| vec!["p".to_owned(), (*pk).clone(), r.as_str().to_owned()]),
+    );
+    let mut all = vec![d_tag(&group.id)];
+    all.extend(
+        members
+            .iter()
+            .map(|(pk, _)| vec!["p".to_owned(), (*pk).clone()]),
+    );
+    [(KIND_GROUP_ADMINS, admins), (KIND_GROUP_MEMBERS, all)]
+}
+
+/// The relay's events describing a group, after a request that
+/// changed it was accepted.
+fn group_events(request: &Event, group: &Group) -> Vec<(u64, Vec<Vec<String>>)> {
+    let mut events = vec![];
+    if matches!(
+        request.kind,
+        groups::KIND_CREATE_GROUP | groups::KIND_EDIT_METADATA
+    ) {
+        events.push((KIND_GROUP_METADATA, metadata_tags(request, &group.id)));
+    }
+    if request.kind != groups::KIND_EDIT_METADATA {
+        events.extend(member_tags(group));
+    }
+    events
+}
+
+/// Publish a group's metadata and lists whenever a request changing
+/// them is accepted, if configured.
+pub async fn publish_groups(
+    identity: Arc<RelayIdentity>,
+    groups: Arc<GroupRegistry>,
+    settings: Settings,
+    mut bcast_rx: broadcast::Receiver<BroadcastEvent>,
+    mut shutdown: broadcast::Receiver<()>,
+) {
+    if !settings.identity.publish_groups || !groups.is_enabled() {
+        return;
+    }
+    info!("publishing group lists as {}", identity.pubkey());
+    loop {
+        let request = tokio::select! {
+            received = bcast_rx.recv() => match received {
+                Ok(b) => b,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("group list publisher missed {} events", n);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            _ = shutdown.recv() => return,
+        };
+        if !matches!(
+            request.kind,
+            groups::KIND_PUT_USER
+                | groups::KIND_REMOVE_USER
+                | groups::KIND_EDIT_METADATA
+                | groups::KIND_CREATE_GROUP
+                | groups::KIND_DELETE_GROUP
+                | groups::KIND_LEAVE_REQUEST
+        ) {
+            continue;
+        }
+        let Some(gid) = groups::group_id(&request) else {
+            continue;
+        };
+        let group = groups.get(&gid).unwrap_or(Group {
+            id: gid,
+            ..Default::default()
+        });
+        for (kind, tags) in group_events(&request, &group) {
+            if let Err(e) = identity.publish(kind, tags, String::new()).await {
+                warn!("could not publish lists for group {:?}: {:?}", group.id, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(kind: u64, tags: &[&[&str]]) -> Event {
+        let mut event = Event::simple_event();
+        event.kind = kind;
+        event.tags = tags
+            .iter()
+            .map(|t| t.iter().map(|s| (*s).to_owned()).collect())
+            .collect();
+        event
+    }
+
+    #[test]
+    fn group_lists_follow_requests() {
+        let mut group = Group {
+            id: "g".to_owned(),
+            ..Default::default()
+        };
+        group.members.insert("bob".to_owned(), GroupRole::Member);
+        group.members.insert("alice".to_owned(), GroupRole::Admin);
+        let create = request(groups::KIND_CREATE_GROUP, &[&["h", "g"]]);
+        let events = group_events(&create, &group);
+        assert_eq!(
+            events.iter().map(|(k, _)| *k).collect::<Vec<u64>>(),
+            vec![KIND_GROUP_METADATA, KIND_GROUP_ADMINS, KIND_GROUP_MEMBERS]
+        );
+        assert_eq!(
+            events[0].1,
+            vec![d_tag("g"), vec!["name".to_owned(), "g".to_owned()]]
+        );
+        assert_eq!(events[1].1[1], vec!["p", "alice", "admin"]);
+        assert_eq!(events[2].1.len(), 3);
+        // edits only change the metadata
+        let edit = request(
+            groups::KIND_EDIT_METADATA,
+            &[
+                &["h", "g"],
+                &["name", "Garden"],
+                &["p", "carol"],
+                &["closed"],
+            ],
+        );
+        let events = group_events(&edit, &group);
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0].1,
+            vec![
+                d_tag("g"),
+                vec!["name".to_owned(), "Garden".to_owned()],
+                vec!["closed".to_owned()]
+            ]
+        );
+        // a deleted group has no members
+        let deleted = Group {
+            id: "g".to_owned(),
+            ..Default::default()
+        };
+        let events = group_events(
+            &request(groups::KIND_DELETE_GROUP, &[&["h", "g"]]),
+            &deleted,
+        );
+        assert!(events.iter().all(|(_, tags)| tags == &vec![d_tag("g")]));
+    }
+
+    #[test]
+    fn profile_describes_the_relay() {
+        let info = Settings::default().info;
+        let content: serde_json::Value = serde_json::from_str(&profile(&info)).unwrap();
+        assert_eq!(content["name"], "Unnamed nostr-rs-relay");
+        assert!(content.get("about").is_none());
+    }
+}
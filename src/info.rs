@@ -1,6 +1,8 @@
 //! Relay metadata using NIP-11
 /// Relay Info
 use crate::config;
+use crate::conn::MAX_SUBSCRIPTION_ID_LEN;
+use crate::payment;
 use serde::{Deserialize, Serialize};
 
 pub const CARGO_PKG_VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
@@ -19,25 +21,330 @@ pub struct RelayInfo {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub contact: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub supported_nips: Option<Vec<i64>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub software: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limitation: Option<Limitation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retention: Option<Vec<RetentionInfo>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub relay_countries: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payments_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fees: Option<config::Fees>,
+}
+
+/// Limits imposed on clients by the relay
+#[derive(Debug, Serialize, Deserialize, Default)]
+#[allow(unused)]
+pub struct Limitation {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_message_length: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_subscriptions: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_filters: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_subid_length: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_ids_per_filter: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_authors_per_filter: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_limit: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_event_tags: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_pow_difficulty: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_required: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payment_required: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub restricted_writes: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at_upper_limit: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at_lower_limit: Option<usize>,
+}
+
+/// How long events are kept by the relay
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[allow(unused)]
+pub struct RetentionInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kinds: Option<Vec<config::KindRange>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub count: Option<usize>,
+}
+
+impl From<&config::Settings> for Limitation {
+    fn from(c: &config::Settings) -> Self {
+        Limitation {
+            max_message_length: c.limits.max_ws_message_bytes,
+            max_subscriptions: Some(c.limits.max_subscriptions),
+            max_filters: c.limits.max_filters,
+            max_subid_length: Some(MAX_SUBSCRIPTION_ID_LEN),
+            max_ids_per_filter: c.limits.max_ids_per_filter,
+            max_authors_per_filter: c.limits.max_authors_per_filter,
+            max_limit: c.limits.max_limit,
+            max_event_tags: c.limits.max_event_tags,
+            min_pow_difficulty: c.options.min_pow_difficulty,
+            // clients only authenticate to read private messages
+            auth_required: Some(false),
+            payment_required: Some(
+                c.pay_to_relay.enabled || c.info.fees.iter().any(config::Fees::payment_required),
+            ),
+            restricted_writes: Some(
+                c.authorization.restricts_publishing()
+                    || c.verified_users.is_enabled()
+                    || c.pay_to_relay.enabled,
+            ),
+            created_at_upper_limit: c.options.reject_future_seconds,
+            // backdated events that are stored are not refused.
+            created_at_lower_limit: c.options.reject_past_seconds.filter(|_| !c.options.store_past_events),
+        }
+    }
+}
+
+/// Highest event kind (NIP-01)
+const MAX_KIND: u64 = 65535;
+
+/// Kinds refused by the kind allowlist or blocklist, advertised as
+/// not retained at all.
+fn refused_kinds(limits: &config::Limits) -> Option<RetentionInfo> {
+    if limits.event_kind_allowlist.is_none() && limits.event_kind_blocklist.is_none() {
+        return None;
+    }
+    let mut kinds = vec![];
+    let mut start = None;
+    for kind in 0..=MAX_KIND + 1 {
+        let refused = kind <= MAX_KIND && !limits.accepts_kind(kind);
+        match (refused, start) {
+            (true, None) => start = Some(kind),
+            (false, Some(lo)) => {
+                let hi = kind - 1;
+                kinds.push(if lo == hi {
+                    config::KindRange::Single(lo)
+                } else {
+                    config::KindRange::Range([lo, hi])
+                });
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if kinds.is_empty() {
+        return None;
+    }
+    Some(RetentionInfo {
+        kinds: Some(kinds),
+        time: Some(0),
+        count: None,
+    })
+}
+
+/// Advertised retention, if any limits are configured.  Rules for a
+/// class of authors do not apply to every client, so are not listed.
+fn retention(r: &config::Retention, limits: &config::Limits) -> Option<Vec<RetentionInfo>> {
+    let mut entries: Vec<RetentionInfo> = refused_kinds(limits).into_iter().collect();
+    entries.extend(
+        r.rules
+            .iter()
+            .flatten()
+            .filter(|rule| rule.authors.is_none())
+            .map(|rule| RetentionInfo {
+                kinds: rule.kinds.clone(),
+                time: rule.max_age_duration().map(|d| d.as_secs()),
+                count: rule.max_count,
+            }),
+    );
+    if r.persist_days.is_some() || r.max_events.is_some() {
+        entries.push(RetentionInfo {
+            kinds: None,
+            time: r.persist_days.map(|d| d as u64 * 86400),
+            count: r.max_events,
+        });
+    }
+    if entries.iter().all(|e| e.time.is_none() && e.count.is_none()) {
+        return None;
+    }
+    Some(entries)
 }
 
 /// Convert an Info configuration into public Relay Info
-impl From<config::Info> for RelayInfo {
-    fn from(i: config::Info) -> Self {
+impl From<config::Settings> for RelayInfo {
+    fn from(mut c: config::Settings) -> Self {
+        // a paid relay advertises its fees, and where to pay them.
+        if c.pay_to_relay.enabled {
+            let p = &c.pay_to_relay;
+            let fee = |amount: u64| config::Fee {
+                amount,
+                unit: "msats".to_owned(),
+                period: None,
+                kinds: None,
+            };
+            let fees = c.info.fees.get_or_insert_with(config::Fees::default);
+            if p.admission_cost > 0 {
+                fees.admission.get_or_insert_with(|| vec![fee(p.admission_cost * 1000)]);
+            }
+            if p.cost_per_event > 0 {
+                fees.publication.get_or_insert_with(|| vec![fee(p.cost_per_event)]);
+            }
+            if c.info.payments_url.is_none() {
+                c.info.payments_url = payment::join_url(&c);
+            }
+        }
+        let mut supported_nips = vec![1, 2, 9, 11, 12, 15, 16, 20, 22, 26, 33, 45, 50, 65, 77, 119];
+        if c.authorization.nip42_auth {
+            supported_nips.push(42);
+        }
+        if c.options.min_pow_difficulty.is_some() {
+            supported_nips.push(13);
+        }
+        if c.groups.enabled {
+            supported_nips.push(29);
+        }
+        if c.reports.enabled {
+            supported_nips.push(56);
+        }
+        if c.authorization.private_inbox {
+            supported_nips.push(59);
+        }
+        supported_nips.sort_unstable();
+        let limitation = Limitation::from(&c);
+        let retention = retention(&c.retention, &c.limits);
+        let i = c.info;
         RelayInfo {
             id: i.relay_url,
             name: i.name,
             description: i.description,
             pubkey: i.pubkey,
             contact: i.contact,
-            supported_nips: Some(vec![1, 2, 9, 11, 12, 15, 16, 20, 22, 33]),
+            icon: i.icon,
+            supported_nips: Some(supported_nips),
             software: Some("https://git.sr.ht/~gheartsfield/nostr-rs-relay".to_owned()),
             version: CARGO_PKG_VERSION.map(std::borrow::ToOwned::to_owned),
+            limitation: Some(limitation),
+            retention,
+            relay_countries: i.relay_countries,
+            payments_url: i.payments_url,
+            fees: i.fees,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Fee, Fees, KindRange, PubkeyClass, RetentionRule, Settings};
+    use crate::conn::MAX_SUBSCRIPTIONS;
+
+    #[test]
+    fn default_limitation() {
+        let info = RelayInfo::from(Settings::default());
+        let lim = info.limitation.unwrap();
+        assert_eq!(lim.max_subscriptions, Some(MAX_SUBSCRIPTIONS));
+        assert_eq!(lim.max_filters, None);
+        assert_eq!(lim.min_pow_difficulty, None);
+        assert_eq!(lim.payment_required, Some(false));
+        assert_eq!(lim.restricted_writes, Some(false));
+        assert!(info.retention.is_none());
+        assert!(info.fees.is_none());
+    }
+
+    #[test]
+    fn fees_and_retention() {
+        let mut settings = Settings::default();
+        settings.info.fees = Some(Fees {
+            admission: Some(vec![Fee {
+                amount: 1000,
+                unit: "msats".to_owned(),
+                period: None,
+                kinds: None,
+            }]),
+            ..Default::default()
+        });
+        settings.retention.persist_days = Some(2);
+        settings.authorization.pubkey_whitelist = Some(vec![]);
+        let info = RelayInfo::from(settings);
+        let lim = info.limitation.unwrap();
+        assert_eq!(lim.payment_required, Some(true));
+        assert_eq!(lim.restricted_writes, Some(true));
+        assert_eq!(
+            info.retention,
+            Some(vec![RetentionInfo {
+                kinds: None,
+                time: Some(172_800),
+                count: None
+            }])
+        );
+        let json = serde_json::to_value(info.fees).unwrap();
+        assert_eq!(json["admission"][0]["amount"], 1000);
+        assert!(json.get("publication").is_none());
+    }
+
+    #[test]
+    fn paid_admission_is_advertised() {
+        let mut settings = Settings::default();
+        settings.info.relay_url = Some("wss://relay.example.com/".to_owned());
+        settings.pay_to_relay.enabled = true;
+        settings.pay_to_relay.admission_cost = 2100;
+        settings.pay_to_relay.cost_per_event = 50;
+        let info = RelayInfo::from(settings);
+        assert_eq!(info.payments_url.as_deref(), Some("https://relay.example.com/join"));
+        let fees = info.fees.unwrap();
+        assert_eq!(fees.admission.unwrap()[0].amount, 2_100_000);
+        assert_eq!(fees.publication.unwrap()[0].amount, 50);
+        let lim = info.limitation.unwrap();
+        assert_eq!(lim.payment_required, Some(true));
+        assert_eq!(lim.restricted_writes, Some(true));
+    }
+
+    #[test]
+    fn retention_rules() {
+        let mut settings = Settings::default();
+        settings.retention.max_events = Some(5000);
+        settings.retention.rules = Some(vec![
+            RetentionRule {
+                kinds: Some(vec![KindRange::Single(0), KindRange::Range([10000, 19999])]),
+                authors: None,
+                max_age: None,
+                max_count: None,
+            },
+            RetentionRule {
+                kinds: None,
+                authors: Some(PubkeyClass::Others),
+                max_age: Some("1 hour".to_owned()),
+                max_count: None,
+            },
+        ]);
+        let info = RelayInfo::from(settings);
+        let json = serde_json::to_value(info.retention).unwrap();
+        assert_eq!(json, serde_json::json!([{"kinds": [0, [10000, 19999]]}, {"count": 5000}]));
+    }
+
+    #[test]
+    fn refused_kinds_are_not_retained() {
+        let mut settings = Settings::default();
+        settings.limits.event_kind_allowlist = Some(vec![0, 1, 3, 7]);
+        settings.limits.event_kind_blocklist = Some(vec![3]);
+        assert!(settings.limits.accepts_kind(1));
+        assert!(!settings.limits.accepts_kind(3));
+        assert!(!settings.limits.accepts_kind(4));
+        let info = RelayInfo::from(settings);
+        let json = serde_json::to_value(info.retention).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([{"kinds": [[2, 6], [8, 65535]], "time": 0}])
+        );
+    }
+}
@@ -0,0 +1,172 @@
+//! Events injected by trusted local services
+//!
+//! Bots and bridges running alongside the relay can publish events
+//! without a websocket connection: with [`Relay::inject`] when the
+//! relay is embedded, or with an HTTP POST to `/inject` presenting the
+//! configured secret.  Injected events go straight to the database
+//! writer, and are broadcast once written.  They are trusted to have
+//! been checked already, so their ids and signatures are only checked
+//! if configured.
+//!
+//! [`Relay::inject`]: crate::relay::Relay::inject
+use crate::db::SubmittedEvent;
+use crate::error::Result;
+use crate::event::{Event, EventCmd, EventWrapper};
+use crate::notice::Notice;
+use tokio::sync::mpsc;
+
+/// Index an event for matching, and resolve its delegation.
+///
+/// # Errors
+///
+/// Will return `Err` if the event has a delegation tag that does not
+/// validate.
+pub fn prepare(event: Event) -> Result<Event> {
+    match EventCmd::new(event).into_verified()? {
+        EventWrapper::WrappedEvent(e) | EventWrapper::WrappedAuth(e) => Ok(e),
+    }
+}
+
+/// Send prepared events to the database writer, waiting for room in
+/// its queue instead of refusing them, and then wait for its verdict
+/// on each.  Events are broadcast once written.
+pub async fn inject(
+    event_tx: &mpsc::Sender<SubmittedEvent>,
+    events: Vec<Event>,
+    source: &str,
+) -> Vec<Notice> {
+    let mut pending = Vec::with_capacity(events.len());
+    for event in events {
+        let id = event.id.clone();
+        let (notice_tx, notice_rx) = mpsc::channel::<Notice>(1);
+        let submitted = SubmittedEvent {
+            event,
+            notice_tx,
+            source_ip: source.to_owned(),
+            auth_pubkey: None,
+            origin: None,
+            user_agent: None,
+            broadcast: true,
+        };
+        let queued = event_tx.send(submitted).await.is_ok();
+        pending.push((id, queued.then_some(notice_rx)));
+    }
+    let mut verdicts = Vec::with_capacity(pending.len());
+    for (id, notice_rx) in pending {
+        let verdict = match notice_rx {
+            Some(mut rx) => rx.recv().await,
+            None => None,
+        };
+        verdicts.push(verdict.unwrap_or_else(|| Notice::error(id, "event was not written")));
+    }
+    verdicts
+}
+
+/// Inject events sent one per line, skipping blank lines.  Returns a
+/// verdict for each event line, in order; lines that are not events
+/// are refused.
+pub async fn inject_lines(
+    event_tx: &mpsc::Sender<SubmittedEvent>,
+    text: &str,
+    source: &str,
+    verify: bool,
+) -> Vec<Notice> {
+    let parsed: Vec<std::result::Result<Event, Notice>> = text
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| {
+            serde_json::from_str::<Event>(l)
+                .map_err(|_| Notice::invalid(String::new(), "could not parse event"))
+        })
+        .collect();
+    let (events, refused) = check(parsed, verify).await;
+    let mut injected = inject(event_tx, events, source).await.into_iter();
+    refused
+        .into_iter()
+        .map(|r| r.unwrap_or_else(|| injected.next().expect("a verdict for every event")))
+        .collect()
+}
+
+/// Split parsed lines into the events to inject, and the verdict for
+/// each line, which is `None` for lines to be injected.
+async fn check(
+    parsed: Vec<std::result::Result<Event, Notice>>,
+    verify: bool,
+) -> (Vec<Event>, Vec<Option<Notice>>) {
+    let parsed = if verify {
+        tokio::task::spawn_blocking(move || {
+            let events: Vec<Event> = parsed
+                .iter()
+                .filter_map(|p| p.as_ref().ok().cloned())
+                .collect();
+            let mut checks = Event::validate_all(&events).into_iter();
+            parsed
+                .into_iter()
+                .map(|p| {
+                    let e = p?;
+                    match checks.next() {
+                        Some(Err(err)) => Err(Notice::invalid(e.id, &format!("{err}"))),
+                        _ => Ok(e),
+                    }
+                })
+                .collect()
+        })
+        .await
+        .expect("checking injected events")
+    } else {
+        parsed
+    };
+    let mut events = vec![];
+    let mut verdicts = vec![];
+    for p in parsed {
+        match p.and_then(|e| {
+            let id = e.id.clone();
+            prepare(e).map_err(|err| Notice::invalid(id, &format!("{err}")))
+        }) {
+            Ok(e) => {
+                events.push(e);
+                verdicts.push(None);
+            }
+            Err(notice) => verdicts.push(Some(notice)),
+        }
+    }
+    (events, verdicts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secp256k1::{KeyPair, Secp256k1};
+
+    fn accepted(notice: &Notice) -> bool {
+        matches!(notice, Notice::EventResult(r) if r.status.to_bool())
+    }
+
+    #[tokio::test]
+    async fn each_line_gets_a_verdict() {
+        let secp = Secp256k1::new();
+        let keypair = KeyPair::new(&secp, &mut secp256k1::rand::thread_rng());
+        let good = Event::new_signed(&keypair, 1, vec![], "good".to_owned());
+        let mut forged = Event::new_signed(&keypair, 1, vec![], "forged".to_owned());
+        forged.content = "changed".to_owned();
+        let text = [
+            serde_json::to_string(&good).unwrap(),
+            String::new(),
+            "not an event".to_owned(),
+            serde_json::to_string(&forged).unwrap(),
+        ]
+        .join("\n");
+        // a writer that saves everything it is sent
+        let (event_tx, mut event_rx) = mpsc::channel::<SubmittedEvent>(4);
+        tokio::spawn(async move {
+            while let Some(s) = event_rx.recv().await {
+                s.notice_tx.send(Notice::saved(s.event.id)).await.ok();
+            }
+        });
+        let verdicts = inject_lines(&event_tx, &text, "test", true).await;
+        assert!(verdicts.iter().map(accepted).eq([true, false, false]));
+        // unchecked, the forged event is trusted
+        let verdicts = inject_lines(&event_tx, &text, "test", false).await;
+        assert!(verdicts.iter().map(accepted).eq([true, false, true]));
+    }
+}
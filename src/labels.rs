@@ -0,0 +1,110 @@
+//! Metric labels
+//!
+//! Prometheus keeps a time series for every label value, so labels
+//! taken from clients must be bounded.  Event kinds are grouped into
+//! the common kinds and the ranges of NIP-01, and client origins and
+//! user agents are limited to the most frequent few.
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Kinds counted under their own number.
+const COMMON_KINDS: &[u64] = &[
+    0, 1, 3, 4, 5, 6, 7, 16, 1059, 1984, 9734, 9735, 10002, 22242, 27235, 30023,
+];
+
+/// Longest label value taken from a client header.
+const MAX_LABEL_LEN: usize = 64;
+
+/// Label for an event kind: its number for common kinds, or the kind
+/// range it falls in.
+#[must_use]
+pub fn kind_label(kind: u64) -> String {
+    if COMMON_KINDS.contains(&kind) {
+        return kind.to_string();
+    }
+    match kind {
+        0..=9999 => "regular",
+        10000..=19999 => "replaceable",
+        20000..=29999 => "ephemeral",
+        30000..=39999 => "addressable",
+        _ => "other",
+    }
+    .to_owned()
+}
+
+/// Label values limited to the most frequent, so that clients cannot
+/// create unbounded time series.  Values are counted as they are
+/// seen; those outside the top `max` are labeled `other`.  Only a
+/// few times that many values are remembered, forgetting the least
+/// frequent.
+pub struct TopLabels {
+    max: usize,
+    counts: Mutex<HashMap<String, u64>>,
+}
+
+impl TopLabels {
+    #[must_use]
+    pub fn new(max: usize) -> Self {
+        TopLabels {
+            max,
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count a value, returning the label to use for it.
+    pub fn label(&self, value: Option<&str>) -> String {
+        let Some(value) = value.filter(|v| !v.is_empty()) else {
+            return "none".to_owned();
+        };
+        let value: String = value.chars().take(MAX_LABEL_LEN).collect();
+        let mut counts = self.counts.lock().unwrap();
+        if !counts.contains_key(&value) && counts.len() >= self.max * 4 {
+            let least = counts.iter().min_by_key(|(_, n)| **n).map(|(v, _)| v.clone());
+            if let Some(least) = least {
+                counts.remove(&least);
+            }
+        }
+        let count = counts.entry(value.clone()).or_default();
+        *count += 1;
+        let count = *count;
+        if counts.values().filter(|n| **n > count).count() < self.max {
+            value
+        } else {
+            "other".to_owned()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kinds_are_bucketed() {
+        assert_eq!(kind_label(1), "1");
+        assert_eq!(kind_label(1111), "regular");
+        assert_eq!(kind_label(10050), "replaceable");
+        assert_eq!(kind_label(20001), "ephemeral");
+        assert_eq!(kind_label(30023), "30023");
+        assert_eq!(kind_label(31000), "addressable");
+        assert_eq!(kind_label(50000), "other");
+    }
+
+    #[test]
+    fn only_frequent_values_are_labeled() {
+        let labels = TopLabels::new(2);
+        assert_eq!(labels.label(Some("a")), "a");
+        assert_eq!(labels.label(Some("a")), "a");
+        assert_eq!(labels.label(Some("b")), "b");
+        assert_eq!(labels.label(Some("b")), "b");
+        // two values are already more frequent
+        assert_eq!(labels.label(Some("c")), "other");
+        assert_eq!(labels.label(None), "none");
+        // many rare values do not grow the counts without bound
+        for i in 0..100 {
+            labels.label(Some(&i.to_string()));
+        }
+        assert!(labels.counts.lock().unwrap().len() <= 8);
+        assert_eq!(labels.label(Some("a")), "a");
+    }
+}
@@ -1,17 +1,61 @@
+pub mod access;
+pub mod admin;
+pub mod announce;
+pub mod archive;
+pub mod backup;
+pub mod bans;
 pub mod cli;
 pub mod close;
+pub mod cluster;
 pub mod config;
 pub mod conn;
+pub mod connlimit;
 pub mod db;
 pub mod delegation;
 pub mod error;
 pub mod event;
+pub mod groups;
+pub mod health;
 pub mod hexrange;
+pub mod http_client;
+pub mod identity;
 pub mod info;
+pub mod inject;
+pub mod labels;
+pub mod logging;
+pub mod maintenance;
+pub mod matcher;
+pub mod media;
+pub mod message;
+pub mod nauthz;
+pub mod mirror;
+pub mod negentropy;
+pub mod nip04;
 pub mod nip05;
+pub mod nip65;
 pub mod notice;
+pub mod outbox;
+pub mod payment;
+pub mod plugin;
+pub mod quota;
+pub mod ratelimit;
+pub mod relay;
+pub mod reload;
+pub mod replication;
 pub mod repo;
+pub mod reports;
+pub mod reputation;
+pub mod retention;
+pub mod signature;
+pub mod spam;
+pub mod stats;
+pub mod status;
 pub mod subscription;
+pub mod throttle;
+pub mod tiers;
+pub mod tls;
 pub mod utils;
+pub mod verify;
+pub mod whitelist;
 // Public API for creating relays programatically
 pub mod server;
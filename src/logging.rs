@@ -0,0 +1,198 @@
+//! Log output
+//!
+//! Logs are written to standard output, or to a file that is rotated
+//! hourly or daily.  They are readable text, or JSON objects (one per
+//! line) holding the fields of each log event and of the connection
+//! it happened on, for log collectors such as Loki or Elasticsearch.
+use crate::config::{LogFormat, LogRotation, Logging};
+use crate::error::{Error, Result};
+use chrono::{DateTime, Utc};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::EnvFilter;
+
+/// Name of the period a time falls in, used as the suffix of the
+/// file it is rotated to.
+fn period(rotation: LogRotation, time: DateTime<Utc>) -> Option<String> {
+    match rotation {
+        LogRotation::Never => None,
+        LogRotation::Hourly => Some(time.format("%Y-%m-%d-%H").to_string()),
+        LogRotation::Daily => Some(time.format("%Y-%m-%d").to_string()),
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+struct Active {
+    file: File,
+    period: Option<String>,
+}
+
+/// A log file, which is renamed with the period it covers when a new
+/// period starts.  The file being written always has the configured
+/// name, so it can be followed with `tail -f`.
+struct RollingFile {
+    path: PathBuf,
+    rotation: LogRotation,
+    max_files: Option<usize>,
+    active: Mutex<Active>,
+}
+
+impl RollingFile {
+    fn open(path: &str, rotation: LogRotation, max_files: Option<usize>) -> io::Result<RollingFile> {
+        let path = PathBuf::from(path);
+        // a file left from before covers the time it was last written.
+        let modified = fs::metadata(&path)
+            .and_then(|m| m.modified())
+            .map_or_else(|_| Utc::now(), DateTime::<Utc>::from);
+        let file = open_append(&path)?;
+        Ok(RollingFile {
+            active: Mutex::new(Active {
+                file,
+                period: period(rotation, modified),
+            }),
+            path,
+            rotation,
+            max_files,
+        })
+    }
+
+    fn write_at(&self, buf: &[u8], now: DateTime<Utc>) -> io::Result<()> {
+        let mut active = self.active.lock().unwrap();
+        let current = period(self.rotation, now);
+        if current != active.period {
+            if let Some(ended) = active.period.take() {
+                // logs are kept in the current file if it cannot be
+                // rotated; there is nowhere else to report why.
+                if let Err(e) = self.rotate(&ended, &mut active) {
+                    eprintln!("could not rotate log file {:?}: {}", self.path, e);
+                }
+            }
+            active.period = current;
+        }
+        active.file.write_all(buf)
+    }
+
+    fn rotate(&self, ended: &str, active: &mut Active) -> io::Result<()> {
+        active.file.flush()?;
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(format!(".{ended}"));
+        fs::rename(&self.path, rotated)?;
+        active.file = open_append(&self.path)?;
+        if let Some(max) = self.max_files {
+            for old in self.rotated_files()?.iter().rev().skip(max) {
+                fs::remove_file(old)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Files rotated from this one, oldest first.
+    fn rotated_files(&self) -> io::Result<Vec<PathBuf>> {
+        let dir = match self.path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        let prefix = match self.path.file_name() {
+            Some(name) => format!("{}.", name.to_string_lossy()),
+            None => return Ok(vec![]),
+        };
+        let mut files: Vec<PathBuf> = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+            .map(|entry| entry.path())
+            .collect();
+        // periods are named so that they sort by time.
+        files.sort();
+        Ok(files)
+    }
+}
+
+/// Writer for a rotated log file
+#[derive(Clone)]
+struct LogFile(Arc<RollingFile>);
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write_at(buf, Utc::now())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.active.lock().unwrap().file.flush()
+    }
+}
+
+impl MakeWriter for LogFile {
+    type Writer = LogFile;
+
+    fn make_writer(&self) -> LogFile {
+        self.clone()
+    }
+}
+
+/// Start logging, with the level set by `RUST_LOG`.
+///
+/// # Errors
+///
+/// Will return `Err` if the log file cannot be opened, or logging has
+/// already started.
+pub fn init(logging: &Logging) -> Result<()> {
+    let builder = tracing_subscriber::fmt().with_env_filter(EnvFilter::from_default_env());
+    let file = match &logging.file {
+        Some(path) => Some(LogFile(Arc::new(RollingFile::open(
+            path,
+            logging.rotation,
+            logging.max_files,
+        )?))),
+        None => None,
+    };
+    // spans hold the fields of a connection, and are included in each
+    // of its events.
+    let result = match (logging.format, file) {
+        (LogFormat::Text, None) => builder.try_init(),
+        (LogFormat::Text, Some(file)) => builder.with_ansi(false).with_writer(file).try_init(),
+        (LogFormat::Json, None) => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .try_init(),
+        (LogFormat::Json, Some(file)) => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .with_writer(file)
+            .try_init(),
+    };
+    result.map_err(|e| Error::CustomError(format!("could not start logging: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn files_are_rotated_and_pruned() {
+        let dir = std::env::temp_dir().join(format!("log-rotate-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("relay.log");
+        let log = RollingFile::open(path.to_str().unwrap(), LogRotation::Daily, Some(2)).unwrap();
+        log.active.lock().unwrap().period = Some("2024-01-01".to_owned());
+        for day in 1..=4 {
+            let time = Utc.with_ymd_and_hms(2024, 1, day, 12, 0, 0).unwrap();
+            log.write_at(format!("day {day}\n").as_bytes(), time).unwrap();
+        }
+        let rotated = log.rotated_files().unwrap();
+        let names: Vec<_> = rotated.iter().map(|p| p.file_name().unwrap().to_string_lossy().into_owned()).collect();
+        assert_eq!(names, vec!["relay.log.2024-01-02", "relay.log.2024-01-03"]);
+        assert_eq!(fs::read_to_string(&rotated[1]).unwrap(), "day 3\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "day 4\n");
+        fs::remove_dir_all(dir).ok();
+    }
+}
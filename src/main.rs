@@ -1,21 +1,45 @@
-//! Server process
+//! Server process, and tools for the relay database
 use clap::Parser;
-use nostr_rs_relay::cli::CLIArgs;
-use nostr_rs_relay::config;
-use nostr_rs_relay::server::start_server;
+use nostr_rs_relay::admin::ban_target;
+use nostr_rs_relay::bans::Ban;
+use nostr_rs_relay::cli::{CLIArgs, Commands};
+use nostr_rs_relay::config::{self, Settings};
+use nostr_rs_relay::db::{build_postgres_pool, build_repo};
+use nostr_rs_relay::error::Result;
+use nostr_rs_relay::logging;
+use nostr_rs_relay::repo::check::check_events;
+use nostr_rs_relay::repo::copy::sqlite_to_postgres;
+use nostr_rs_relay::repo::export::{export_events, ExportFilter};
+use nostr_rs_relay::repo::import::import_events;
+use nostr_rs_relay::repo::stats::repo_stats;
+use nostr_rs_relay::repo::NostrRepo;
+use nostr_rs_relay::server::{create_metrics, start_server};
+use nostr_rs_relay::utils::unix_time;
+use nostr_rs_relay::whitelist::parse_pubkey;
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, BufRead, BufReader, BufWriter};
 use std::sync::mpsc as syncmpsc;
 use std::sync::mpsc::{Receiver as MpscReceiver, Sender as MpscSender};
+use std::sync::Arc;
 use std::thread;
-use tracing::info;
+use tracing::{error, info};
 use console_subscriber::ConsoleLayer;
 
-/// Start running a Nostr relay server.
+/// Run a Nostr relay server, or a tool for its database.
 fn main() {
     let args = CLIArgs::parse();
 
     // get config file name from args
     let config_file_arg = args.config;
 
+    // checking the config file needs no logging or database, and
+    // must not fall back to the defaults.
+    if args.command == Some(Commands::ConfigCheck) {
+        config_check(config_file_arg.as_deref().unwrap_or("config.toml"));
+        return;
+    }
+
     // configure settings from the config file (defaults to config.toml)
     // replace default settings with those read from the config file
     let mut settings = config::Settings::new(&config_file_arg);
@@ -24,9 +48,9 @@ fn main() {
     if settings.diagnostics.tracing {
         // enable tracing with tokio-console
         ConsoleLayer::builder().with_default_env().init();
-    } else {
-        // standard logging
-        tracing_subscriber::fmt::try_init().unwrap();
+    } else if let Err(e) = logging::init(&settings.logging) {
+        eprintln!("{e}");
+        std::process::exit(1);
     }
     info!("Starting up from main");
 
@@ -37,6 +61,144 @@ fn main() {
     if let Some(db_dir) = db_dir_arg {
         settings.database.data_directory = db_dir;
     }
+    match args.command.unwrap_or(Commands::Serve) {
+        Commands::Serve => serve(settings),
+        // clap has already checked the engines are sqlite and
+        // postgres.
+        Commands::Migrate { .. } => migrate(&settings),
+        Commands::Export {
+            since,
+            until,
+            kinds,
+            output,
+        } => {
+            let file = output.clone();
+            let n = run_tool(&settings, "export", |repo| async move {
+                let mut out = BufWriter::new(File::create(&file)?);
+                let filter = ExportFilter { since, until, kinds };
+                export_events(repo.as_ref(), &filter, &mut out).await
+            });
+            info!("exported {} events to {}", n, output);
+        }
+        Commands::Import {
+            input,
+            skip_verify,
+            batch_size,
+        } => {
+            run_tool(&settings, "import", |repo| async move {
+                let reader: Box<dyn BufRead> = match &input {
+                    Some(path) => Box::new(BufReader::new(File::open(path)?)),
+                    None => Box::new(io::stdin().lock()),
+                };
+                import_events(repo.as_ref(), reader, !skip_verify, batch_size).await
+            });
+        }
+        Commands::Allow { pubkey } => set_allowed(&settings, &pubkey, true),
+        Commands::Disallow { pubkey } => set_allowed(&settings, &pubkey, false),
+        Commands::Ban {
+            target,
+            seconds,
+            reason,
+        } => {
+            let (target, value) = ban_target(&target).unwrap_or_else(|msg| exit_with(&msg));
+            let banned = format!("{} {}", target.as_str(), value);
+            let ban = Ban {
+                target,
+                value,
+                expires_at: seconds.map(|s| unix_time() + s),
+                reason,
+            };
+            run_tool(&settings, "ban", |repo| async move { repo.add_ban(&ban).await });
+            info!("banned {}", banned);
+        }
+        Commands::Compact => {
+            run_tool(&settings, "compact", |repo| async move { repo.compact().await });
+            info!("compacted the database");
+        }
+        Commands::VerifyDb { delete } => {
+            let stats = run_tool(&settings, "verify", |repo| async move {
+                check_events(repo.as_ref(), delete).await
+            });
+            if (stats.invalid > 0 && !delete) || stats.index_problems > 0 {
+                std::process::exit(2);
+            }
+        }
+        Commands::Stats => {
+            let stats = run_tool(&settings, "stats", |repo| async move {
+                repo_stats(repo.as_ref()).await
+            });
+            println!("{stats}");
+        }
+        Commands::ConfigCheck => unreachable!("checked before loading settings"),
+    }
+}
+
+/// Log an error, and exit.
+fn exit_with(msg: &str) -> ! {
+    error!("{}", msg);
+    std::process::exit(1);
+}
+
+/// Run a tool against the database from the settings, exiting if it
+/// fails.
+fn run_tool<T, F, Fut>(settings: &Settings, name: &str, tool: F) -> T
+where
+    F: FnOnce(Arc<dyn NostrRepo>) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let result = rt.block_on(async {
+        let (_, metrics) = create_metrics();
+        let repo = build_repo(settings, metrics).await;
+        tool(repo).await
+    });
+    result.unwrap_or_else(|e| exit_with(&format!("{name} failed: {e:?}")))
+}
+
+/// Read and validate a config file, reporting the result.
+fn config_check(file: &str) {
+    match Settings::reload(file) {
+        Ok(_) => println!("{file} is valid"),
+        Err(e) => {
+            eprintln!("{file} is not valid: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Copy the database to postgres.
+fn migrate(settings: &Settings) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let result = rt.block_on(async {
+        let (_, metrics) = create_metrics();
+        let target = build_postgres_pool(settings, metrics.clone()).await;
+        sqlite_to_postgres(settings, &target, metrics).await
+    });
+    match result {
+        Ok(stats) => info!(
+            "copied {} events ({} new), {} verification records, {} groups",
+            stats.events, stats.events_written, stats.verifications, stats.groups
+        ),
+        Err(e) => exit_with(&format!("migration failed: {e:?}")),
+    }
+}
+
+/// Change the stored whitelist.
+fn set_allowed(settings: &Settings, pubkey: &str, allowed: bool) {
+    let pubkey = parse_pubkey(pubkey).unwrap_or_else(|msg| exit_with(&msg));
+    run_tool(settings, "whitelist change", |repo| {
+        let pubkey = pubkey.clone();
+        async move { repo.set_pubkey_allowed(&pubkey, allowed).await }
+    });
+    if allowed {
+        info!("added {} to the whitelist", pubkey);
+    } else {
+        info!("removed {} from the whitelist", pubkey);
+    }
+}
+
+/// Run the relay until it shuts down.
+fn serve(settings: Settings) {
     // we should have a 'control plane' channel to monitor and bump
     // the server.  this will let us do stuff like clear the database,
     // shutdown, etc.; for now all this does is initiate shutdown if
@@ -0,0 +1,139 @@
+//! Scheduled database maintenance
+//!
+//! Deleted and replaced events leave free space in the database.  It
+//! is reclaimed by an online vacuum, run once a day during a
+//! configured low-traffic window, or on request from an admin.
+use crate::config::Settings;
+use crate::error::Result;
+use crate::repo::NostrRepo;
+use crate::server::NostrMetrics;
+use crate::utils::unix_time;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+/// How often to check whether the window has started.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// A daily time range, in minutes after midnight (UTC).  The range
+/// may cross midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VacuumWindow {
+    start: u64,
+    end: u64,
+}
+
+impl VacuumWindow {
+    /// Parse a range such as `"03:00-05:00"`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` with a message if the range is malformed.
+    pub fn parse(range: &str) -> std::result::Result<VacuumWindow, String> {
+        let minutes = |t: &str| -> Option<u64> {
+            let (h, m) = t.trim().split_once(':')?;
+            let (h, m) = (h.parse::<u64>().ok()?, m.parse::<u64>().ok()?);
+            (h < 24 && m < 60).then_some(h * 60 + m)
+        };
+        let invalid = || format!("vacuum_window must be \"HH:MM-HH:MM\": {range}");
+        let (start, end) = range.split_once('-').ok_or_else(invalid)?;
+        match (minutes(start), minutes(end)) {
+            (Some(start), Some(end)) if start != end => Ok(VacuumWindow { start, end }),
+            _ => Err(invalid()),
+        }
+    }
+
+    fn contains(&self, minute: u64) -> bool {
+        if self.start < self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+
+    /// The day (since the epoch) on which the window that a time
+    /// falls in started, if it falls in one.
+    fn day_started(&self, now: u64) -> Option<u64> {
+        let day = now / SECS_PER_DAY;
+        let minute = (now % SECS_PER_DAY) / 60;
+        if !self.contains(minute) {
+            return None;
+        }
+        // after midnight, in a window that started the day before.
+        Some(if minute < self.start { day - 1 } else { day })
+    }
+}
+
+/// Vacuum the database, and count the space released.
+///
+/// # Errors
+///
+/// Will return `Err` if the database could not be vacuumed.
+pub async fn vacuum(repo: &dyn NostrRepo, metrics: &NostrMetrics) -> Result<u64> {
+    let start = Instant::now();
+    let reclaimed = repo.vacuum().await?;
+    metrics.db_vacuums.inc();
+    metrics.db_reclaimed_bytes.inc_by(reclaimed);
+    info!("vacuum released {} bytes in {:?}", reclaimed, start.elapsed());
+    Ok(reclaimed)
+}
+
+/// Vacuum the database once during each day's window, if one is
+/// configured.
+pub async fn vacuum_scheduler(
+    repo: Arc<dyn NostrRepo>,
+    settings: Settings,
+    metrics: NostrMetrics,
+    mut shutdown: broadcast::Receiver<()>,
+) {
+    let Some(range) = settings.database.vacuum_window else {
+        return;
+    };
+    let Ok(window) = VacuumWindow::parse(&range) else {
+        warn!("ignoring invalid vacuum_window: {:?}", range);
+        return;
+    };
+    info!("vacuuming the database daily, between {} (UTC)", range);
+    let mut last_day = None;
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            _ = shutdown.recv() => return,
+        }
+        let Some(day) = window.day_started(unix_time()) else {
+            continue;
+        };
+        if last_day == Some(day) {
+            continue;
+        }
+        last_day = Some(day);
+        if let Err(e) = vacuum(repo.as_ref(), &metrics).await {
+            warn!("scheduled vacuum failed: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_may_cross_midnight() {
+        let night = VacuumWindow::parse("23:30-01:00").unwrap();
+        let day = 20_000 * SECS_PER_DAY;
+        assert_eq!(night.day_started(day + 23 * 3600 + 45 * 60), Some(20_000));
+        // the same window, after midnight
+        assert_eq!(night.day_started(day + SECS_PER_DAY + 30 * 60), Some(20_000));
+        assert_eq!(night.day_started(day + SECS_PER_DAY + 3600), None);
+        let early = VacuumWindow::parse("03:00-05:00").unwrap();
+        assert_eq!(early.day_started(day + 4 * 3600), Some(20_000));
+        assert_eq!(early.day_started(day + 5 * 3600), None);
+        assert!(VacuumWindow::parse("3am-5am").is_err());
+        assert!(VacuumWindow::parse("03:00-03:00").is_err());
+        assert!(VacuumWindow::parse("24:00-01:00").is_err());
+    }
+}
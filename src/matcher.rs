@@ -0,0 +1,451 @@
+//! Matching of broadcast events against every open subscription
+//!
+//! A single task keeps the subscriptions of all connections in an
+//! inverted index, keyed by the most selective field of each filter
+//! (ids, then authors, then a tag, then kinds).  Each broadcast event
+//! is only checked against the subscriptions found under its own id,
+//! author, tags and kind, plus those whose filters have none of these
+//! fields, and the matches are delivered to each connection's queue.
+//! Connections still apply their own visibility rules (groups, DMs)
+//! before sending an event.
+//!
+//! Events are dropped for a connection whose queue is full, and for
+//! every connection when matching falls behind the broadcast channel.
+//! What was missed is reported along with the next event delivered to
+//! the connection, so that it can tell its client.
+use crate::event::{BroadcastEvent, Event};
+use crate::subscription::{ReqFilter, Subscription};
+use crate::utils::unix_time;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{broadcast, mpsc};
+use tracing::{info, trace, warn};
+
+/// Identifier of a connection registered with the matcher
+pub(crate) type ConnId = u64;
+
+/// A subscription of a connection
+type SubKey = (ConnId, String);
+
+/// A broadcast event, with the ids of the connection's subscriptions
+/// it matched.
+#[derive(Debug)]
+pub struct Matched {
+    pub event: BroadcastEvent,
+    pub sub_ids: Vec<String>,
+    /// Events the connection missed since the last one delivered
+    pub missed: Option<Missed>,
+}
+
+/// Realtime events that were not delivered to a connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Missed {
+    /// Events dropped.  When matching fell behind, this counts every
+    /// event the matcher skipped, not only those that would have
+    /// matched.
+    pub events: u64,
+    /// Earliest creation time of the events, in seconds since 1970
+    pub since: u64,
+    /// Subscriptions that may have missed events
+    pub sub_ids: BTreeSet<String>,
+}
+
+/// Record events missed by a connection, adding to those not yet
+/// reported.
+fn record_missed(
+    missed: &mut HashMap<ConnId, Missed>,
+    conn: ConnId,
+    events: u64,
+    since: u64,
+    sub_ids: impl IntoIterator<Item = String>,
+) {
+    let m = missed.entry(conn).or_insert_with(|| Missed {
+        events: 0,
+        since,
+        sub_ids: BTreeSet::new(),
+    });
+    m.events += events;
+    m.since = m.since.min(since);
+    m.sub_ids.extend(sub_ids);
+}
+
+enum Command {
+    Connect(ConnId, mpsc::Sender<Matched>),
+    Subscribe(ConnId, Subscription),
+    Unsubscribe(ConnId, String),
+    Disconnect(ConnId),
+}
+
+/// Where a filter is found in the index
+#[derive(Debug, PartialEq, Eq)]
+enum FilterKeys<'a> {
+    Ids(&'a [String]),
+    Authors(&'a [String]),
+    Tag(char, &'a HashSet<String>),
+    Kinds(&'a [u64]),
+    Unindexed,
+}
+
+impl<'a> FilterKeys<'a> {
+    /// Index keys for the most selective field of a filter.  An event
+    /// can only match the filter if it is found under one of them.
+    /// An event matching an `&` tag has all of its values, so it is
+    /// found under any of them.
+    fn of(f: &'a ReqFilter) -> Self {
+        if let Some(ids) = &f.ids {
+            FilterKeys::Ids(ids)
+        } else if let Some(authors) = &f.authors {
+            FilterKeys::Authors(authors)
+        } else if let Some((c, vals)) = f.tags.iter().chain(f.and_tags.iter()).flatten().min_by_key(|(_, v)| v.len()) {
+            FilterKeys::Tag(*c, vals)
+        } else if let Some(kinds) = &f.kinds {
+            FilterKeys::Kinds(kinds)
+        } else {
+            FilterKeys::Unindexed
+        }
+    }
+}
+
+/// Subscriptions of every connection, indexed for matching
+#[derive(Default)]
+pub(crate) struct SubscriptionIndex {
+    subs: HashMap<ConnId, HashMap<String, Subscription>>,
+    /// id and author prefixes
+    ids: HashMap<String, HashSet<SubKey>>,
+    authors: HashMap<String, HashSet<SubKey>>,
+    tags: HashMap<char, HashMap<String, HashSet<SubKey>>>,
+    kinds: HashMap<u64, HashSet<SubKey>>,
+    /// subscriptions with a filter that has no indexed fields
+    unindexed: HashSet<SubKey>,
+}
+
+impl SubscriptionIndex {
+    /// Add a subscription, replacing any with the same id.
+    pub(crate) fn insert(&mut self, conn: ConnId, sub: Subscription) {
+        self.remove(conn, &sub.id);
+        let key: SubKey = (conn, sub.id.clone());
+        for f in &sub.filters {
+            match FilterKeys::of(f) {
+                FilterKeys::Ids(ids) => ids.iter().for_each(|p| {
+                    self.ids.entry(p.clone()).or_default().insert(key.clone());
+                }),
+                FilterKeys::Authors(authors) => authors.iter().for_each(|p| {
+                    self.authors.entry(p.clone()).or_default().insert(key.clone());
+                }),
+                FilterKeys::Tag(c, vals) => {
+                    let by_val = self.tags.entry(c).or_default();
+                    vals.iter().for_each(|v| {
+                        by_val.entry(v.clone()).or_default().insert(key.clone());
+                    });
+                }
+                FilterKeys::Kinds(kinds) => kinds.iter().for_each(|k| {
+                    self.kinds.entry(*k).or_default().insert(key.clone());
+                }),
+                FilterKeys::Unindexed => {
+                    self.unindexed.insert(key.clone());
+                }
+            }
+        }
+        self.subs.entry(conn).or_default().insert(sub.id.clone(), sub);
+    }
+
+    pub(crate) fn remove(&mut self, conn: ConnId, sub_id: &str) {
+        let Some(sub) = self.subs.get_mut(&conn).and_then(|s| s.remove(sub_id)) else {
+            return;
+        };
+        let key: SubKey = (conn, sub.id.clone());
+        for f in &sub.filters {
+            match FilterKeys::of(f) {
+                FilterKeys::Ids(ids) => ids.iter().for_each(|p| unlink(&mut self.ids, p, &key)),
+                FilterKeys::Authors(authors) => authors.iter().for_each(|p| unlink(&mut self.authors, p, &key)),
+                FilterKeys::Tag(c, vals) => {
+                    if let Some(by_val) = self.tags.get_mut(&c) {
+                        vals.iter().for_each(|v| unlink(by_val, v, &key));
+                        if by_val.is_empty() {
+                            self.tags.remove(&c);
+                        }
+                    }
+                }
+                FilterKeys::Kinds(kinds) => kinds.iter().for_each(|k| unlink(&mut self.kinds, k, &key)),
+                FilterKeys::Unindexed => {
+                    self.unindexed.remove(&key);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn remove_conn(&mut self, conn: ConnId) {
+        let ids: Vec<String> = self
+            .subs
+            .get(&conn)
+            .map(|s| s.keys().cloned().collect())
+            .unwrap_or_default();
+        for id in ids {
+            self.remove(conn, &id);
+        }
+        self.subs.remove(&conn);
+    }
+
+    /// Subscriptions that match an event, grouped by connection.
+    pub(crate) fn matches(&self, e: &Event) -> HashMap<ConnId, Vec<String>> {
+        let mut sets: Vec<&HashSet<SubKey>> = vec![&self.unindexed];
+        for n in 1..=e.id.len() {
+            sets.extend(e.id.get(..n).and_then(|p| self.ids.get(p)));
+        }
+        for author in std::iter::once(&e.pubkey).chain(e.delegated_by.iter()) {
+            for n in 1..=author.len() {
+                sets.extend(author.get(..n).and_then(|p| self.authors.get(p)));
+            }
+        }
+        if let Some(tagidx) = &e.tagidx {
+            for (c, vals) in tagidx {
+                if let Some(by_val) = self.tags.get(c) {
+                    sets.extend(vals.iter().filter_map(|v| by_val.get(v)));
+                }
+            }
+        }
+        sets.extend(self.kinds.get(&e.kind));
+        let candidates: HashSet<&SubKey> = sets.into_iter().flatten().collect();
+        let mut matches: HashMap<ConnId, Vec<String>> = HashMap::new();
+        for (conn, sub_id) in candidates {
+            let interested = self
+                .subs
+                .get(conn)
+                .and_then(|s| s.get(sub_id))
+                .is_some_and(|s| s.interested_in_event(e));
+            if interested {
+                matches.entry(*conn).or_default().push(sub_id.clone());
+            }
+        }
+        matches
+    }
+
+    /// Ids of a connection's subscriptions.
+    pub(crate) fn sub_ids(&self, conn: ConnId) -> impl Iterator<Item = &String> {
+        self.subs.get(&conn).into_iter().flat_map(|s| s.keys())
+    }
+}
+
+/// Remove a subscription from one entry of an index.
+fn unlink<K: std::hash::Hash + Eq + ?Sized, Q>(map: &mut HashMap<Q, HashSet<SubKey>>, k: &K, key: &SubKey)
+where
+    Q: std::borrow::Borrow<K> + std::hash::Hash + Eq,
+{
+    if let Some(set) = map.get_mut(k) {
+        set.remove(key);
+        if set.is_empty() {
+            map.remove(k);
+        }
+    }
+}
+
+/// Handle for registering connections with the matcher task
+#[derive(Clone)]
+pub struct Matcher {
+    cmd_tx: mpsc::UnboundedSender<Command>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl Matcher {
+    /// Start matching events from the broadcast channel, until shutdown.
+    #[must_use]
+    pub fn start(bcast_rx: broadcast::Receiver<BroadcastEvent>, shutdown: broadcast::Receiver<()>) -> Matcher {
+        let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+        tokio::spawn(run(cmd_rx, bcast_rx, shutdown));
+        Matcher {
+            cmd_tx,
+            next_id: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Register a connection, returning its handle and the queue its
+    /// matching events are delivered on.  Events are dropped when the
+    /// queue is full.
+    #[must_use]
+    pub fn connect(&self, queue_size: usize) -> (MatcherConn, mpsc::Receiver<Matched>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::channel(queue_size.max(1));
+        self.cmd_tx.send(Command::Connect(id, tx)).ok();
+        let conn = MatcherConn {
+            id,
+            cmd_tx: self.cmd_tx.clone(),
+        };
+        (conn, rx)
+    }
+}
+
+/// Subscriptions of one connection.  They are all removed when this
+/// is dropped.
+pub struct MatcherConn {
+    id: ConnId,
+    cmd_tx: mpsc::UnboundedSender<Command>,
+}
+
+impl MatcherConn {
+    /// Match new events against a subscription, replacing any with the
+    /// same id.
+    pub fn subscribe(&self, sub: Subscription) {
+        self.cmd_tx.send(Command::Subscribe(self.id, sub)).ok();
+    }
+
+    pub fn unsubscribe(&self, sub_id: &str) {
+        self.cmd_tx.send(Command::Unsubscribe(self.id, sub_id.to_owned())).ok();
+    }
+}
+
+impl Drop for MatcherConn {
+    fn drop(&mut self) {
+        self.cmd_tx.send(Command::Disconnect(self.id)).ok();
+    }
+}
+
+async fn run(
+    mut cmd_rx: mpsc::UnboundedReceiver<Command>,
+    mut bcast_rx: broadcast::Receiver<BroadcastEvent>,
+    mut shutdown: broadcast::Receiver<()>,
+) {
+    let mut index = SubscriptionIndex::default();
+    let mut conns: HashMap<ConnId, mpsc::Sender<Matched>> = HashMap::new();
+    // events dropped for each connection, not yet reported to it.
+    let mut missed: HashMap<ConnId, Missed> = HashMap::new();
+    // when the last event was taken from the broadcast channel; any
+    // skipped after it were received later.
+    let mut last_received = unix_time();
+    loop {
+        tokio::select! {
+            // subscription changes are applied before any event that
+            // follows them.
+            biased;
+            Some(cmd) = cmd_rx.recv() => match cmd {
+                Command::Connect(id, tx) => {
+                    conns.insert(id, tx);
+                }
+                Command::Subscribe(id, sub) => index.insert(id, sub),
+                Command::Unsubscribe(id, sub_id) => index.remove(id, &sub_id),
+                Command::Disconnect(id) => {
+                    index.remove_conn(id);
+                    conns.remove(&id);
+                    missed.remove(&id);
+                }
+            },
+            res = bcast_rx.recv() => match res {
+                Ok(event) => {
+                    last_received = unix_time();
+                    for (id, sub_ids) in index.matches(&event) {
+                        let Some(tx) = conns.get(&id) else {
+                            continue;
+                        };
+                        let matched = Matched {
+                            event: event.clone(),
+                            sub_ids,
+                            missed: missed.remove(&id),
+                        };
+                        if let Err(TrySendError::Full(m) | TrySendError::Closed(m)) = tx.try_send(matched) {
+                            trace!("connection queue is full, dropping event: {:?}", event.get_event_id_prefix());
+                            if let Some(earlier) = m.missed {
+                                missed.insert(id, earlier);
+                            }
+                            record_missed(&mut missed, id, 1, event.created_at, m.sub_ids);
+                        }
+                    }
+                }
+                Err(RecvError::Lagged(n)) => {
+                    warn!("subscription matching fell behind, {} events were not delivered", n);
+                    for &id in conns.keys() {
+                        let sub_ids: Vec<String> = index.sub_ids(id).cloned().collect();
+                        if !sub_ids.is_empty() {
+                            record_missed(&mut missed, id, n, last_received, sub_ids);
+                        }
+                    }
+                }
+                Err(RecvError::Closed) => return,
+            },
+            _ = shutdown.recv() => {
+                info!("shutting down subscription matcher");
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sub(id: &str, filters: &str) -> Subscription {
+        serde_json::from_str(&format!(r#"["REQ","{id}",{filters}]"#)).unwrap()
+    }
+
+    fn event(id: &str, pubkey: &str, kind: u64, tags: &[(&str, &str)]) -> Event {
+        let mut e = Event::simple_event();
+        e.id = id.to_owned();
+        e.pubkey = pubkey.to_owned();
+        e.kind = kind;
+        e.tags = tags.iter().map(|(n, v)| vec![(*n).to_owned(), (*v).to_owned()]).collect();
+        e.build_index();
+        e
+    }
+
+    #[test]
+    fn filters_use_most_selective_field() {
+        let f = |s: &str| -> ReqFilter { serde_json::from_str(s).unwrap() };
+        assert!(matches!(FilterKeys::of(&f(r#"{"ids":["ab"],"kinds":[1]}"#)), FilterKeys::Ids(_)));
+        assert!(matches!(FilterKeys::of(&f(r##"{"authors":["ab"],"#p":["cd"]}"##)), FilterKeys::Authors(_)));
+        assert!(matches!(FilterKeys::of(&f(r##"{"#t":["x","y"],"#p":["cd"],"kinds":[1]}"##)), FilterKeys::Tag('p', _)));
+        assert!(matches!(FilterKeys::of(&f(r#"{"kinds":[1]}"#)), FilterKeys::Kinds(_)));
+        assert!(matches!(FilterKeys::of(&f(r#"{"since":10}"#)), FilterKeys::Unindexed));
+    }
+
+    #[test]
+    fn events_match_indexed_subscriptions() {
+        let mut index = SubscriptionIndex::default();
+        index.insert(1, sub("by-id", r#"{"ids":["abc"]}"#));
+        index.insert(1, sub("by-author", r#"{"authors":["12"],"kinds":[7]}"#));
+        index.insert(2, sub("by-tag", r##"{"#t":["nostr"]}"##));
+        index.insert(2, sub("by-kind", r#"{"kinds":[1]},{"kinds":[3]}"#));
+        index.insert(3, sub("all", r#"{"limit":10}"#));
+
+        let e = event("abcdef", "1234", 7, &[("t", "nostr")]);
+        let mut m = index.matches(&e);
+        m.values_mut().for_each(|ids| ids.sort());
+        assert_eq!(m[&1], vec!["by-author", "by-id"]);
+        assert_eq!(m[&2], vec!["by-tag"]);
+        assert_eq!(m[&3], vec!["all"]);
+
+        let m = index.matches(&event("ff", "99", 3, &[]));
+        assert_eq!(m[&2], vec!["by-kind"]);
+        assert!(!m.contains_key(&1));
+    }
+
+    #[test]
+    fn removed_subscriptions_no_longer_match() {
+        let mut index = SubscriptionIndex::default();
+        index.insert(1, sub("a", r#"{"kinds":[1]}"#));
+        index.insert(1, sub("b", r#"{"authors":["12"]}"#));
+        index.insert(2, sub("a", r#"{"kinds":[1]}"#));
+        // replacing a subscription drops its old filters
+        index.insert(1, sub("a", r#"{"kinds":[2]}"#));
+        let e = event("ff", "1234", 1, &[]);
+        assert_eq!(index.matches(&e)[&1], vec!["b"]);
+        index.remove(1, "b");
+        index.remove_conn(2);
+        assert!(index.matches(&e).is_empty());
+        assert!(index.kinds.get(&1).is_none());
+        assert!(index.authors.is_empty());
+    }
+
+    #[test]
+    fn missed_events_accumulate() {
+        let mut missed = HashMap::new();
+        record_missed(&mut missed, 1, 1, 500, vec!["b".to_owned()]);
+        record_missed(&mut missed, 1, 3, 400, vec!["a".to_owned(), "b".to_owned()]);
+        record_missed(&mut missed, 2, 1, 600, vec!["a".to_owned()]);
+        let m = &missed[&1];
+        assert_eq!((m.events, m.since), (4, 400));
+        assert_eq!(m.sub_ids.iter().collect::<Vec<_>>(), vec!["a", "b"]);
+        assert_eq!(missed[&2].events, 1);
+    }
+}
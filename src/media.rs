@@ -0,0 +1,489 @@
+//! HTTP file storage (NIP-96), authorized with HTTP auth events (NIP-98)
+//!
+//! Uploads are stored on disk, named by the SHA-256 hash of their
+//! contents, and served back at `/media/<hash>`.  Responses include
+//! NIP-94 file metadata tags.
+//!
+//! The same storage backs an optional Blossom blob server, where
+//! blobs are addressed directly at `/<hash>`.  Each blob records the
+//! pubkeys that uploaded it, so that quotas and deletion can be
+//! enforced per pubkey.
+use crate::config::Settings;
+use crate::error::{Error, Result};
+use crate::event::Event;
+use crate::utils::{is_lower_hex, unix_time};
+use bitcoin_hashes::{sha256, Hash};
+use serde_json::json;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+/// HTTP authorization event kind (NIP-98)
+pub const HTTP_AUTH_KIND: u64 = 27235;
+
+/// Blossom authorization event kind
+pub const BLOB_AUTH_KIND: u64 = 24242;
+
+/// Maximum age (in either direction) of an HTTP auth event.
+const HTTP_AUTH_MAX_SKEW_SECONDS: u64 = 60;
+
+/// Content type for files without one.
+pub const DEFAULT_MIME: &str = "application/octet-stream";
+
+/// Public HTTP URL that media routes are served under, without a
+/// trailing slash.  Uses `media.public_url` if set, otherwise the
+/// relay URL with an HTTP scheme.
+#[must_use]
+pub fn base_url(settings: &Settings) -> Option<String> {
+    match &settings.media.public_url {
+        Some(u) => Some(u.trim_end_matches('/').to_owned()),
+        None => relay_http_url(settings),
+    }
+}
+
+/// HTTP URL of the relay, derived from its websocket URL.
+#[must_use]
+pub fn relay_http_url(settings: &Settings) -> Option<String> {
+    let relay = settings.info.relay_url.as_ref()?;
+    let url = if let Some(rest) = relay.strip_prefix("wss://") {
+        format!("https://{rest}")
+    } else if let Some(rest) = relay.strip_prefix("ws://") {
+        format!("http://{rest}")
+    } else {
+        relay.clone()
+    };
+    Some(url.trim_end_matches('/').to_owned())
+}
+
+/// Hex-encoded SHA-256 hash of a payload.
+#[must_use]
+pub fn hash_hex(data: &[u8]) -> String {
+    format!("{:x}", sha256::Hash::hash(data))
+}
+
+/// Verify an `Authorization` header (NIP-98) for a request, and
+/// return the authorized pubkey.
+///
+/// # Errors
+///
+/// Will return `Err` if the header is missing the `Nostr` scheme, or
+/// does not contain a valid, recent auth event for this URL, method,
+/// and payload.
+pub fn verify_http_auth(header: &str, url: &str, method: &str, payload: &[u8]) -> Result<String> {
+    let event = auth_event(header, HTTP_AUTH_KIND)?;
+    if event.created_at.abs_diff(unix_time()) > HTTP_AUTH_MAX_SKEW_SECONDS {
+        return Err(Error::AuthFailure);
+    }
+    if event.tag_values_by_name("u").first().map(String::as_str) != Some(url) {
+        return Err(Error::AuthFailure);
+    }
+    match event.tag_values_by_name("method").first() {
+        Some(m) if m.eq_ignore_ascii_case(method) => {}
+        _ => return Err(Error::AuthFailure),
+    }
+    // a payload hash is optional, but must match if provided
+    if let Some(p) = event.tag_values_by_name("payload").first() {
+        if p != &hash_hex(payload) {
+            return Err(Error::AuthFailure);
+        }
+    }
+    Ok(event.pubkey)
+}
+
+/// Decode and validate the event in a `Nostr` authorization header.
+fn auth_event(header: &str, kind: u64) -> Result<Event> {
+    let encoded = header.strip_prefix("Nostr ").ok_or(Error::AuthFailure)?;
+    let decoded = base64::decode(encoded.trim()).map_err(|_| Error::AuthFailure)?;
+    let event: Event = serde_json::from_slice(&decoded).map_err(|_| Error::AuthFailure)?;
+    if event.kind != kind || event.validate().is_err() {
+        return Err(Error::AuthFailure);
+    }
+    Ok(event)
+}
+
+/// Verify a Blossom `Authorization` header for an action (`t` tag)
+/// on a blob, and return the authorized pubkey.
+///
+/// # Errors
+///
+/// Will return `Err` if the header does not contain a valid,
+/// unexpired auth event for this action.  If a blob hash is given,
+/// it must be listed in an `x` tag.
+pub fn verify_blob_auth(header: &str, action: &str, hash: Option<&str>) -> Result<String> {
+    let event = auth_event(header, BLOB_AUTH_KIND)?;
+    let curr_time = unix_time();
+    if event.created_at > curr_time + HTTP_AUTH_MAX_SKEW_SECONDS {
+        return Err(Error::AuthFailure);
+    }
+    // auth events must expire
+    match event.tag_values_by_name("expiration").first().map(|e| e.parse::<u64>()) {
+        Some(Ok(exp)) if exp > curr_time => {}
+        _ => return Err(Error::AuthFailure),
+    }
+    if !event.tag_values_by_name("t").iter().any(|t| t == action) {
+        return Err(Error::AuthFailure);
+    }
+    if let Some(h) = hash {
+        if !event.tag_values_by_name("x").iter().any(|x| x == h) {
+            return Err(Error::AuthFailure);
+        }
+    }
+    Ok(event.pubkey)
+}
+
+/// The blob hash addressed by a Blossom path (`/<sha256>`, with an
+/// optional file extension).
+#[must_use]
+pub fn blob_hash(path: &str) -> Option<&str> {
+    let name = path.strip_prefix('/')?;
+    let hash = name.split_once('.').map_or(name, |(h, _)| h);
+    if hash.len() == 64 && is_lower_hex(hash) {
+        Some(hash)
+    } else {
+        None
+    }
+}
+
+/// Blossom descriptor for a stored blob.
+#[must_use]
+pub fn blob_descriptor(base_url: &str, hash: &str, mime: &str, size: usize) -> serde_json::Value {
+    json!({
+        "url": format!("{base_url}/{hash}"),
+        "sha256": hash,
+        "size": size,
+        "type": mime,
+        "uploaded": unix_time(),
+    })
+}
+
+/// Boundary of a `multipart/form-data` content type.
+#[must_use]
+pub fn multipart_boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';').map(str::trim);
+    if !params.next()?.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params
+        .find_map(|p| p.strip_prefix("boundary="))
+        .map(|b| b.trim_matches('"').to_owned())
+        .filter(|b| !b.is_empty())
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Extract the `file` field of a multipart form, and its content
+/// type, if provided.
+#[must_use]
+pub fn multipart_file(body: &[u8], boundary: &str) -> Option<(Vec<u8>, Option<String>)> {
+    let first = format!("--{boundary}");
+    let delim = format!("\r\n--{boundary}");
+    let mut rest = &body[find(body, first.as_bytes())? + first.len()..];
+    // the closing delimiter is followed by "--"
+    while !rest.starts_with(b"--") {
+        let end = find(rest, delim.as_bytes())?;
+        let part = &rest[..end];
+        let part = part.strip_prefix(b"\r\n").unwrap_or(part);
+        let headers_end = find(part, b"\r\n\r\n")?;
+        let headers = String::from_utf8_lossy(&part[..headers_end]);
+        let mut is_file = false;
+        let mut mime = None;
+        for line in headers.split("\r\n") {
+            let (name, value) = match line.split_once(':') {
+                Some(nv) => nv,
+                None => continue,
+            };
+            if name.eq_ignore_ascii_case("content-disposition") {
+                is_file = value.split(';').any(|p| p.trim() == "name=\"file\"");
+            } else if name.eq_ignore_ascii_case("content-type") {
+                mime = Some(value.trim().to_owned());
+            }
+        }
+        if is_file {
+            return Some((part[headers_end + 4..].to_vec(), mime));
+        }
+        rest = &rest[end + delim.len()..];
+    }
+    None
+}
+
+/// NIP-96 response for a successful upload.
+#[must_use]
+pub fn upload_response(url: &str, hash: &str, mime: &str, size: usize) -> serde_json::Value {
+    json!({
+        "status": "success",
+        "message": "Upload successful.",
+        "nip94_event": {
+            "tags": [
+                ["url", url],
+                ["ox", hash],
+                ["x", hash],
+                ["m", mime],
+                ["size", size.to_string()],
+            ],
+            "content": "",
+        },
+    })
+}
+
+/// Local disk storage for uploaded files.
+///
+/// Files are named by hash, with their content type stored
+/// alongside.  Uploaders are recorded as empty files at
+/// `owners/<pubkey>/<hash>`.
+#[derive(Debug, Clone)]
+pub struct MediaStore {
+    dir: PathBuf,
+}
+
+impl MediaStore {
+    #[must_use]
+    pub fn new(settings: &Settings) -> MediaStore {
+        MediaStore {
+            dir: PathBuf::from(&settings.media.storage_dir),
+        }
+    }
+
+    /// Store a file, returning its hash.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the file could not be written.
+    pub async fn store(&self, data: &[u8], mime: Option<&str>) -> std::io::Result<String> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let hash = hash_hex(data);
+        tokio::fs::write(self.dir.join(&hash), data).await?;
+        tokio::fs::write(self.dir.join(format!("{hash}.type")), mime.unwrap_or(DEFAULT_MIME))
+            .await?;
+        Ok(hash)
+    }
+
+    /// Load a file and its content type by hash.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the file exists, but could not be read.
+    pub async fn load(&self, hash: &str) -> std::io::Result<Option<(Vec<u8>, String)>> {
+        // only hashes are valid names, which also prevents path traversal
+        if hash.len() != 64 || !is_lower_hex(hash) {
+            return Ok(None);
+        }
+        let data = match tokio::fs::read(self.dir.join(hash)).await {
+            Ok(d) => d,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mime = tokio::fs::read_to_string(self.dir.join(format!("{hash}.type")))
+            .await
+            .unwrap_or_else(|_| DEFAULT_MIME.to_owned());
+        Ok(Some((data, mime)))
+    }
+
+    fn owner_dir(&self, pubkey: &str) -> PathBuf {
+        self.dir.join("owners").join(pubkey)
+    }
+
+    /// Record a pubkey as an uploader of a file.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the ownership record could not be written.
+    pub async fn add_owner(&self, pubkey: &str, hash: &str) -> std::io::Result<()> {
+        let dir = self.owner_dir(pubkey);
+        tokio::fs::create_dir_all(&dir).await?;
+        tokio::fs::write(dir.join(hash), b"").await
+    }
+
+    /// Total size of files uploaded by a pubkey.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the ownership records could not be read.
+    pub async fn usage(&self, pubkey: &str) -> std::io::Result<u64> {
+        let mut entries = match tokio::fs::read_dir(self.owner_dir(pubkey)).await {
+            Ok(e) => e,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        let mut total = 0;
+        while let Some(entry) = entries.next_entry().await? {
+            if let Ok(meta) = tokio::fs::metadata(self.dir.join(entry.file_name())).await {
+                total += meta.len();
+            }
+        }
+        Ok(total)
+    }
+
+    /// Could a pubkey store another file of this size?
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the ownership records could not be read.
+    pub async fn within_quota(&self, pubkey: &str, size: usize, quota: Option<u64>) -> std::io::Result<bool> {
+        match quota {
+            Some(q) => Ok(self.usage(pubkey).await? + size as u64 <= q),
+            None => Ok(true),
+        }
+    }
+
+    /// Remove a pubkey's ownership of a file, and the file itself
+    /// once no uploaders remain.  Returns `false` if the pubkey did
+    /// not upload the file.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the file or its records could not be removed.
+    pub async fn delete(&self, pubkey: &str, hash: &str) -> std::io::Result<bool> {
+        match tokio::fs::remove_file(self.owner_dir(pubkey).join(hash)).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        }
+        let mut owners = tokio::fs::read_dir(self.dir.join("owners")).await?;
+        while let Some(owner) = owners.next_entry().await? {
+            if tokio::fs::metadata(owner.path().join(hash)).await.is_ok() {
+                return Ok(true);
+            }
+        }
+        tokio::fs::remove_file(self.dir.join(hash)).await?;
+        tokio::fs::remove_file(self.dir.join(format!("{hash}.type"))).await.ok();
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secp256k1::{KeyPair, Secp256k1, XOnlyPublicKey};
+
+    const URL: &str = "https://media.example.com/upload";
+
+    fn auth_header(tags: Vec<Vec<String>>, created_at: u64) -> String {
+        auth_header_kind(HTTP_AUTH_KIND, tags, created_at)
+    }
+
+    fn auth_header_kind(kind: u64, tags: Vec<Vec<String>>, created_at: u64) -> String {
+        let secp = Secp256k1::new();
+        let keypair = KeyPair::new(&secp, &mut secp256k1::rand::thread_rng());
+        let pubkey = XOnlyPublicKey::from_keypair(&keypair).to_string();
+        let canonical = json!([0, pubkey, created_at, kind, tags, ""]).to_string();
+        let digest: sha256::Hash = sha256::Hash::hash(canonical.as_bytes());
+        let msg = secp256k1::Message::from_slice(digest.as_ref()).unwrap();
+        let sig = secp.sign_schnorr(&msg, &keypair);
+        let event = json!({
+            "id": format!("{digest:x}"),
+            "pubkey": pubkey,
+            "created_at": created_at,
+            "kind": kind,
+            "tags": tags,
+            "content": "",
+            "sig": sig.to_string(),
+        });
+        format!("Nostr {}", base64::encode(event.to_string()))
+    }
+
+    fn tag(name: &str, value: &str) -> Vec<String> {
+        vec![name.to_owned(), value.to_owned()]
+    }
+
+    #[test]
+    fn http_auth_accepted() {
+        let header = auth_header(vec![tag("u", URL), tag("method", "POST")], unix_time());
+        assert!(verify_http_auth(&header, URL, "POST", b"data").is_ok());
+    }
+
+    #[test]
+    fn http_auth_rejected() {
+        // wrong url
+        let header = auth_header(vec![tag("u", "https://other.com/upload"), tag("method", "POST")], unix_time());
+        assert!(verify_http_auth(&header, URL, "POST", b"").is_err());
+        // wrong method
+        let header = auth_header(vec![tag("u", URL), tag("method", "GET")], unix_time());
+        assert!(verify_http_auth(&header, URL, "POST", b"").is_err());
+        // stale
+        let header = auth_header(vec![tag("u", URL), tag("method", "POST")], unix_time() - 600);
+        assert!(verify_http_auth(&header, URL, "POST", b"").is_err());
+        // far in the future
+        let header = auth_header(vec![tag("u", URL), tag("method", "POST")], u64::MAX);
+        assert!(verify_http_auth(&header, URL, "POST", b"").is_err());
+        // payload mismatch
+        let header = auth_header(
+            vec![tag("u", URL), tag("method", "POST"), tag("payload", &hash_hex(b"a"))],
+            unix_time(),
+        );
+        assert!(verify_http_auth(&header, URL, "POST", b"b").is_err());
+        assert!(verify_http_auth(&header, URL, "POST", b"a").is_ok());
+        // wrong scheme
+        assert!(verify_http_auth("Basic abc", URL, "POST", b"").is_err());
+    }
+
+    #[test]
+    fn parse_multipart() {
+        let ct = "multipart/form-data; boundary=\"XyZ\"";
+        let boundary = multipart_boundary(ct).unwrap();
+        assert_eq!(boundary, "XyZ");
+        assert_eq!(multipart_boundary("image/png"), None);
+        let body = b"--XyZ\r\n\
+            Content-Disposition: form-data; name=\"caption\"\r\n\r\n\
+            hello\r\n\
+            --XyZ\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"a.png\"\r\n\
+            Content-Type: image/png\r\n\r\n\
+            \x89PNG\r\ndata\r\n\
+            --XyZ--\r\n";
+        let (data, mime) = multipart_file(body, &boundary).unwrap();
+        assert_eq!(data, b"\x89PNG\r\ndata");
+        assert_eq!(mime.as_deref(), Some("image/png"));
+    }
+
+    #[test]
+    fn multipart_without_file() {
+        let body = b"--XyZ\r\n\
+            Content-Disposition: form-data; name=\"caption\"; filename=\"file\"\r\n\r\n\
+            hello\r\n\
+            --XyZ--\r\n";
+        assert_eq!(multipart_file(body, "XyZ"), None);
+    }
+
+    #[test]
+    fn blob_auth() {
+        let hash = hash_hex(b"blob");
+        let exp = (unix_time() + 60).to_string();
+        let header = auth_header_kind(
+            BLOB_AUTH_KIND,
+            vec![tag("t", "delete"), tag("x", &hash), tag("expiration", &exp)],
+            unix_time(),
+        );
+        assert!(verify_blob_auth(&header, "delete", Some(&hash)).is_ok());
+        // wrong action, or wrong blob
+        assert!(verify_blob_auth(&header, "upload", None).is_err());
+        assert!(verify_blob_auth(&header, "delete", Some(&hash_hex(b"other"))).is_err());
+        // expired, or without an expiration
+        let old = (unix_time() - 1).to_string();
+        let header = auth_header_kind(
+            BLOB_AUTH_KIND,
+            vec![tag("t", "upload"), tag("expiration", &old)],
+            unix_time() - 100,
+        );
+        assert!(verify_blob_auth(&header, "upload", None).is_err());
+        let header = auth_header_kind(BLOB_AUTH_KIND, vec![tag("t", "upload")], unix_time());
+        assert!(verify_blob_auth(&header, "upload", None).is_err());
+        // NIP-98 events are not accepted
+        let header = auth_header_kind(
+            HTTP_AUTH_KIND,
+            vec![tag("t", "upload"), tag("expiration", &exp)],
+            unix_time(),
+        );
+        assert!(verify_blob_auth(&header, "upload", None).is_err());
+    }
+
+    #[test]
+    fn blob_paths() {
+        let hash = hash_hex(b"blob");
+        assert_eq!(blob_hash(&format!("/{hash}")), Some(hash.as_str()));
+        assert_eq!(blob_hash(&format!("/{hash}.png")), Some(hash.as_str()));
+        assert_eq!(blob_hash("/upload"), None);
+        assert_eq!(blob_hash(&format!("/{}", hash.to_uppercase())), None);
+        assert_eq!(blob_hash(&format!("/../{hash}")), None);
+    }
+}
@@ -0,0 +1,107 @@
+//! Messages sent to clients
+//!
+//! Every message the relay sends is built from an [`OutboundMessage`],
+//! so that subscription ids, reasons and other strings from clients
+//! are always escaped as JSON.
+use crate::notice::Notice;
+use serde_json::{json, Value};
+
+/// A relay-to-client message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutboundMessage<'a> {
+    /// An event for a subscription, already serialized
+    Event { sub_id: &'a str, event: &'a str },
+    /// End of stored events for a subscription
+    Eose { sub_id: &'a str },
+    /// A subscription was ended by the relay
+    Closed { sub_id: &'a str, reason: &'a str },
+    Notice { message: &'a str },
+    /// The result of publishing an event
+    Ok {
+        event_id: &'a str,
+        accepted: bool,
+        reason: &'a str,
+    },
+    /// An authentication challenge (NIP-42)
+    Auth { challenge: &'a str },
+    /// The number of events matching a count request (NIP-45)
+    Count { sub_id: &'a str, count: u64 },
+    /// A negentropy reply, in hex (NIP-77)
+    NegMsg { sub_id: &'a str, message: &'a str },
+    /// The end of a negentropy sync
+    NegErr { sub_id: &'a str, reason: &'a str },
+}
+
+impl OutboundMessage<'_> {
+    /// The message as JSON text.
+    #[must_use]
+    pub fn to_json(&self) -> String {
+        let value = match *self {
+            // events are serialized once, and sent to every matching
+            // subscription; only the id is added here.
+            OutboundMessage::Event { sub_id, event } => {
+                return format!("[\"EVENT\",{},{event}]", Value::from(sub_id));
+            }
+            OutboundMessage::Eose { sub_id } => json!(["EOSE", sub_id]),
+            OutboundMessage::Closed { sub_id, reason } => json!(["CLOSED", sub_id, reason]),
+            OutboundMessage::Notice { message } => json!(["NOTICE", message]),
+            OutboundMessage::Ok {
+                event_id,
+                accepted,
+                reason,
+            } => json!(["OK", event_id, accepted, reason]),
+            OutboundMessage::Auth { challenge } => json!(["AUTH", challenge]),
+            OutboundMessage::Count { sub_id, count } => json!(["COUNT", sub_id, {"count": count}]),
+            OutboundMessage::NegMsg { sub_id, message } => json!(["NEG-MSG", sub_id, message]),
+            OutboundMessage::NegErr { sub_id, reason } => json!(["NEG-ERR", sub_id, reason]),
+        };
+        value.to_string()
+    }
+}
+
+impl<'a> From<&'a Notice> for OutboundMessage<'a> {
+    fn from(notice: &'a Notice) -> Self {
+        match notice {
+            Notice::Message(msg) => OutboundMessage::Notice { message: msg },
+            Notice::EventResult(res) => OutboundMessage::Ok {
+                event_id: &res.id,
+                accepted: res.status.to_bool(),
+                reason: &res.msg,
+            },
+            Notice::AuthChallenge(challenge) => OutboundMessage::Auth { challenge },
+            Notice::Closed(res) => OutboundMessage::Closed {
+                sub_id: &res.id,
+                reason: &res.msg,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strings_are_escaped() {
+        let event = r#"{"id":"abc","content":"hi"}"#;
+        let msg = OutboundMessage::Event {
+            sub_id: "a\"],[\"x",
+            event,
+        };
+        let parsed: Value = serde_json::from_str(&msg.to_json()).unwrap();
+        assert_eq!(parsed, json!(["EVENT", "a\"],[\"x", {"id": "abc", "content": "hi"}]));
+        let closed = Notice::closed(
+            "sub\\".to_owned(),
+            "too slow",
+            crate::notice::EventResultStatus::RateLimited,
+        );
+        assert_eq!(
+            OutboundMessage::from(&closed).to_json(),
+            r#"["CLOSED","sub\\","rate-limited: too slow"]"#
+        );
+        assert_eq!(
+            OutboundMessage::Count { sub_id: "c", count: 3 }.to_json(),
+            r#"["COUNT","c",{"count":3}]"#
+        );
+    }
+}
@@ -0,0 +1,329 @@
+//! Mirroring of events from upstream relays
+//!
+//! Each configured upstream has a task that keeps a subscription open
+//! with its filters, reconnecting with increasing delays when the
+//! connection drops.  Events it sends are validated and handed to the
+//! database writer as if a client had published them, so the relay's
+//! own policies still apply.  An event already received from any
+//! upstream is only submitted once, and its signature is only checked
+//! once while it is among the recently verified.
+use crate::config::{MirrorUpstream, Settings};
+use crate::db::SubmittedEvent;
+use crate::error::Result;
+use crate::event::{Event, EventCmd, EventWrapper};
+use crate::notice::Notice;
+use crate::subscription::ReqFilter;
+use crate::utils::unix_time;
+use crate::verify::SignatureVerifier;
+use futures::{SinkExt, StreamExt};
+use serde_json::Value;
+use std::collections::{HashSet, VecDeque};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc};
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use tracing::{debug, info, trace, warn};
+use tungstenite::error::Error as WsError;
+use tungstenite::Message;
+
+/// Delay before the first reconnection attempt
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Longest delay between reconnection attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Time allowed to establish a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Number of recently mirrored event ids remembered
+const RECENT_IDS: usize = 100_000;
+
+/// Subscription id used with upstream relays
+const SUB_ID: &str = "mirror";
+
+type UpstreamStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Event ids that were recently seen, oldest first
+pub(crate) struct RecentIds {
+    ids: HashSet<String>,
+    order: VecDeque<String>,
+    capacity: usize,
+}
+
+impl RecentIds {
+    pub(crate) fn new(capacity: usize) -> Self {
+        RecentIds {
+            ids: HashSet::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub(crate) fn contains(&self, id: &str) -> bool {
+        self.ids.contains(id)
+    }
+
+    /// Remember an id, returning false if it was already known.
+    pub(crate) fn insert(&mut self, id: &str) -> bool {
+        if self.ids.contains(id) {
+            return false;
+        }
+        if self.order.len() >= self.capacity {
+            if let Some(old) = self.order.pop_front() {
+                self.ids.remove(&old);
+            }
+        }
+        self.ids.insert(id.to_owned());
+        self.order.push_back(id.to_owned());
+        true
+    }
+
+    /// Forget an id.  Its place in the order is kept until it would
+    /// have been evicted.
+    pub(crate) fn remove(&mut self, id: &str) {
+        self.ids.remove(id);
+    }
+}
+
+/// Filters to subscribe with, resuming after the newest event seen
+/// on a previous connection.
+fn subscription_filters(upstream: &MirrorUpstream, since: Option<u64>, now: u64) -> Vec<ReqFilter> {
+    match &upstream.filters {
+        Some(filters) => filters
+            .iter()
+            .cloned()
+            .map(|mut f| {
+                if since.is_some() {
+                    f.since = f.since.max(since);
+                }
+                f
+            })
+            .collect(),
+        // without filters, only new events are mirrored.
+        None => vec![ReqFilter {
+            ids: None,
+            kinds: None,
+            since: Some(since.unwrap_or(now)),
+            until: None,
+            authors: None,
+            limit: None,
+            tags: None,
+            and_tags: None,
+            long_tags: None,
+            search: None,
+            force_no_match: false,
+        }],
+    }
+}
+
+/// Submit events from every configured upstream to the database
+/// writer, until shutdown.
+pub async fn mirror(
+    settings: Settings,
+    event_tx: mpsc::Sender<SubmittedEvent>,
+    verifier: SignatureVerifier,
+    mut shutdown: broadcast::Receiver<()>,
+) {
+    let upstreams = settings.mirror.upstreams.clone().unwrap_or_default();
+    if upstreams.is_empty() {
+        return;
+    }
+    info!("mirroring events from {} upstreams", upstreams.len());
+    // each upstream task stops once this receiver is dropped.
+    let (mirror_tx, mut mirror_rx) = mpsc::channel::<(Event, String)>(1024);
+    for upstream in upstreams {
+        tokio::spawn(upstream_task(
+            upstream,
+            settings.options.reject_future_seconds,
+            verifier.clone(),
+            mirror_tx.clone(),
+        ));
+    }
+    drop(mirror_tx);
+    // results from the database writer
+    let (notice_tx, mut notice_rx) = mpsc::channel::<Notice>(1024);
+    let mut recent = RecentIds::new(RECENT_IDS);
+    loop {
+        tokio::select! {
+            next = mirror_rx.recv() => {
+                let Some((event, url)) = next else {
+                    return;
+                };
+                if !recent.insert(&event.id) {
+                    trace!("skipping already mirrored event: {:?}", event.get_event_id_prefix());
+                    continue;
+                }
+                let submit_event = SubmittedEvent {
+                    event,
+                    notice_tx: notice_tx.clone(),
+                    source_ip: url,
+                    auth_pubkey: None,
+                    origin: None,
+                    user_agent: None,
+                    broadcast: true,
+                };
+                if event_tx.send(submit_event).await.is_err() {
+                    return;
+                }
+            },
+            Some(notice) = notice_rx.recv() => {
+                if let Notice::EventResult(r) = notice {
+                    if !r.status.to_bool() {
+                        debug!("mirrored event {} was not stored: {}", r.id, r.msg);
+                    }
+                }
+            },
+            _ = shutdown.recv() => {
+                info!("shutting down mirroring");
+                return;
+            }
+        }
+    }
+}
+
+/// Keep a subscription open to an upstream, and pass on the events it
+/// sends.
+async fn upstream_task(
+    upstream: MirrorUpstream,
+    reject_future_seconds: Option<usize>,
+    verifier: SignatureVerifier,
+    tx: mpsc::Sender<(Event, String)>,
+) {
+    let url = upstream.url.clone();
+    let mut backoff = MIN_BACKOFF;
+    // creation time of the newest event received
+    let mut since: Option<u64> = None;
+    while !tx.is_closed() {
+        match tokio::time::timeout(CONNECT_TIMEOUT, connect_async(&url)).await {
+            Ok(Ok((ws, _))) => {
+                info!("connected to mirror upstream {}", url);
+                backoff = MIN_BACKOFF;
+                let filters = subscription_filters(&upstream, since, unix_time());
+                match receive_events(&url, ws, filters, reject_future_seconds, &verifier, &mut since, &tx).await {
+                    Ok(()) => return,
+                    Err(e) => warn!("lost connection to mirror upstream {}: {:?}", url, e),
+                }
+            }
+            Ok(Err(e)) => warn!("could not connect to mirror upstream {}: {:?}", url, e),
+            Err(_) => warn!("timed out connecting to mirror upstream {}", url),
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Subscribe over a connection and pass on valid events, until the
+/// mirror stops (returning `Ok`) or the connection fails.
+async fn receive_events(
+    url: &str,
+    ws: UpstreamStream,
+    filters: Vec<ReqFilter>,
+    reject_future_seconds: Option<usize>,
+    verifier: &SignatureVerifier,
+    since: &mut Option<u64>,
+    tx: &mpsc::Sender<(Event, String)>,
+) -> Result<(), WsError> {
+    let (mut sink, mut stream) = ws.split();
+    let mut req = vec![Value::from("REQ"), Value::from(SUB_ID)];
+    req.extend(filters.iter().filter_map(|f| serde_json::to_value(f).ok()));
+    sink.send(Message::Text(Value::Array(req).to_string())).await?;
+    while let Some(msg) = stream.next().await {
+        let Message::Text(text) = msg? else {
+            continue;
+        };
+        let Ok(Value::Array(mut msg)) = serde_json::from_str::<Value>(&text) else {
+            continue;
+        };
+        match msg.first().and_then(Value::as_str) {
+            Some("EVENT") if msg.len() >= 3 => {
+                let Some(event) = parse_event(msg.swap_remove(2), reject_future_seconds, verifier).await else {
+                    debug!("ignoring invalid event from mirror upstream {}", url);
+                    continue;
+                };
+                let created_at = event.created_at.min(unix_time());
+                *since = Some(since.map_or(created_at, |s| s.max(created_at)));
+                if tx.send((event, url.to_owned())).await.is_err() {
+                    sink.close().await.ok();
+                    return Ok(());
+                }
+            }
+            Some("EOSE") => debug!("mirror upstream {} sent stored events", url),
+            Some("CLOSED") => {
+                let reason = msg.get(2).and_then(Value::as_str).unwrap_or_default();
+                warn!("mirror upstream {} closed the subscription: {}", url, reason);
+                return Err(WsError::ConnectionClosed);
+            }
+            Some("NOTICE") => {
+                let notice = msg.get(1).and_then(Value::as_str).unwrap_or_default();
+                debug!("notice from mirror upstream {}: {}", url, notice);
+            }
+            _ => {}
+        }
+    }
+    Err(WsError::ConnectionClosed)
+}
+
+/// Validate an event sent by an upstream.
+async fn parse_event(
+    val: Value,
+    reject_future_seconds: Option<usize>,
+    verifier: &SignatureVerifier,
+) -> Option<Event> {
+    let event: Event = serde_json::from_value(val).ok()?;
+    match verifier.verify(EventCmd::new(event)).await {
+        Ok(EventWrapper::WrappedEvent(e)) if e.is_valid_timestamp(reject_future_seconds) => Some(e),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recent_ids_evict_oldest() {
+        let mut recent = RecentIds::new(2);
+        assert!(recent.insert("a"));
+        assert!(!recent.insert("a"));
+        assert!(recent.insert("b"));
+        assert!(recent.insert("c"));
+        // "a" was forgotten to make room for "c"
+        assert!(recent.insert("a"));
+        assert!(!recent.insert("c"));
+        recent.remove("c");
+        assert!(!recent.contains("c"));
+        assert!(recent.contains("a"));
+    }
+
+    #[test]
+    fn filters_resume_from_newest_event() {
+        let mut upstream = MirrorUpstream {
+            url: "wss://relay.example.com".to_owned(),
+            filters: None,
+        };
+        let filters = subscription_filters(&upstream, None, 1000);
+        assert_eq!(filters.len(), 1);
+        assert_eq!(filters[0].since, Some(1000));
+        assert_eq!(subscription_filters(&upstream, Some(500), 1000)[0].since, Some(500));
+        upstream.filters = Some(vec![
+            serde_json::from_str(r#"{"kinds":[0,1]}"#).unwrap(),
+            serde_json::from_str(r#"{"kinds":[3],"since":800}"#).unwrap(),
+        ]);
+        let filters = subscription_filters(&upstream, None, 1000);
+        assert_eq!(filters[0].since, None);
+        assert_eq!(filters[1].since, Some(800));
+        let filters = subscription_filters(&upstream, Some(500), 1000);
+        assert_eq!(filters[0].since, Some(500));
+        assert_eq!(filters[0].kinds, Some(vec![0, 1]));
+        assert_eq!(filters[1].since, Some(800));
+    }
+
+    #[tokio::test]
+    async fn invalid_events_are_dropped() {
+        let lookups = prometheus::IntCounterVec::new(prometheus::Opts::new("lookups", "lookups"), &["result"]).unwrap();
+        let verifier = SignatureVerifier::start(1, lookups);
+        let event = serde_json::to_value(Event::simple_event()).unwrap();
+        assert!(parse_event(event, None, &verifier).await.is_none());
+        assert!(parse_event(Value::from("not an event"), None, &verifier).await.is_none());
+    }
+}
@@ -0,0 +1,237 @@
+//! External authorization service (gRPC)
+//!
+//! When configured, a gRPC service is consulted before admitting
+//! submitted events and/or new connections, so that policy (spam
+//! filtering, payment checks, etc.) can live outside the relay.  The
+//! protocol is defined in `proto/nauthz.proto`; messages are encoded
+//! here directly with prost.
+use crate::error::{Error, Result};
+use crate::event::Event;
+use http::uri::PathAndQuery;
+use std::time::Duration;
+use tonic::codec::ProstCodec;
+use tonic::transport::{Channel, Endpoint};
+use tracing::{info, warn};
+
+const EVENT_ADMIT_PATH: &str = "/nauthz.Authorization/EventAdmit";
+const CONNECTION_ADMIT_PATH: &str = "/nauthz.Authorization/ConnectionAdmit";
+
+/// Time to wait for the service, before permitting a request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Authorization decision
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum Decision {
+    Unspecified = 0,
+    Permit = 1,
+    Deny = 2,
+}
+
+#[derive(Clone, PartialEq, Eq, prost::Message)]
+pub struct TagEntry {
+    #[prost(string, repeated, tag = "1")]
+    pub values: Vec<String>,
+}
+
+/// Event in wire format
+#[derive(Clone, PartialEq, Eq, prost::Message)]
+pub struct EventMsg {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub pubkey: String,
+    #[prost(uint64, tag = "3")]
+    pub created_at: u64,
+    #[prost(uint64, tag = "4")]
+    pub kind: u64,
+    #[prost(string, tag = "5")]
+    pub content: String,
+    #[prost(message, repeated, tag = "6")]
+    pub tags: Vec<TagEntry>,
+    #[prost(string, tag = "7")]
+    pub sig: String,
+}
+
+impl From<&Event> for EventMsg {
+    fn from(e: &Event) -> Self {
+        EventMsg {
+            id: e.id.clone(),
+            pubkey: e.pubkey.clone(),
+            created_at: e.created_at,
+            kind: e.kind,
+            content: e.content.clone(),
+            tags: e
+                .tags
+                .iter()
+                .map(|t| TagEntry { values: t.clone() })
+                .collect(),
+            sig: e.sig.clone(),
+        }
+    }
+}
+
+/// Client connection details
+#[derive(Clone, PartialEq, Eq, prost::Message)]
+pub struct Client {
+    #[prost(string, tag = "1")]
+    pub ip_addr: String,
+    #[prost(string, optional, tag = "2")]
+    pub origin: Option<String>,
+    #[prost(string, optional, tag = "3")]
+    pub user_agent: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Eq, prost::Message)]
+pub struct EventRequest {
+    #[prost(message, optional, tag = "1")]
+    pub event: Option<EventMsg>,
+    #[prost(message, optional, tag = "2")]
+    pub client: Option<Client>,
+    #[prost(string, optional, tag = "3")]
+    pub auth_pubkey: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Eq, prost::Message)]
+pub struct ConnectionRequest {
+    #[prost(message, optional, tag = "1")]
+    pub client: Option<Client>,
+}
+
+/// Reply to an event or connection admission request
+#[derive(Clone, PartialEq, Eq, prost::Message)]
+pub struct Reply {
+    #[prost(enumeration = "Decision", tag = "1")]
+    pub decision: i32,
+    #[prost(string, optional, tag = "2")]
+    pub message: Option<String>,
+}
+
+impl Reply {
+    /// Was the request denied?  Unknown decisions are permitted.
+    #[must_use]
+    pub fn is_denied(&self) -> bool {
+        Decision::from_i32(self.decision) == Some(Decision::Deny)
+    }
+}
+
+/// Build a client for the configured service, if any, and if it is
+/// enabled for this purpose.
+#[must_use]
+pub fn client_for(endpoint: &Option<String>, enabled: bool) -> Option<AdmissionClient> {
+    match endpoint {
+        Some(ep) if enabled => match AdmissionClient::new(ep) {
+            Ok(c) => {
+                info!("using authorization service: {}", ep);
+                Some(c)
+            }
+            Err(e) => {
+                warn!("authorization service disabled: {:?}", e);
+                None
+            }
+        },
+        _ => None,
+    }
+}
+
+/// Client for an authorization service
+#[derive(Clone, Debug)]
+pub struct AdmissionClient {
+    grpc: tonic::client::Grpc<Channel>,
+}
+
+impl AdmissionClient {
+    /// Create a client for a service endpoint.  Connections are
+    /// made lazily, so an unavailable service does not prevent the
+    /// relay from starting.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the endpoint is not a valid URI.
+    pub fn new(endpoint: &str) -> Result<AdmissionClient> {
+        let channel = Endpoint::from_shared(endpoint.to_owned())
+            .map_err(|e| Error::CustomError(format!("invalid gRPC endpoint: {e}")))?
+            .connect_timeout(REQUEST_TIMEOUT)
+            .timeout(REQUEST_TIMEOUT)
+            .connect_lazy();
+        Ok(AdmissionClient {
+            grpc: tonic::client::Grpc::new(channel),
+        })
+    }
+
+    async fn call<T: prost::Message + 'static>(&mut self, path: &'static str, req: T) -> Result<Reply> {
+        self.grpc
+            .ready()
+            .await
+            .map_err(|e| Error::CustomError(format!("gRPC service not ready: {e}")))?;
+        let codec: ProstCodec<T, Reply> = ProstCodec::default();
+        let reply = self
+            .grpc
+            .unary(tonic::Request::new(req), PathAndQuery::from_static(path), codec)
+            .await?;
+        Ok(reply.into_inner())
+    }
+
+    /// Ask whether an event should be admitted.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the service could not be reached.
+    pub async fn admit_event(&mut self, event: &Event, client: Client, auth_pubkey: Option<String>) -> Result<Reply> {
+        let req = EventRequest {
+            event: Some(EventMsg::from(event)),
+            client: Some(client),
+            auth_pubkey,
+        };
+        self.call(EVENT_ADMIT_PATH, req).await
+    }
+
+    /// Ask whether a new connection should be accepted.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the service could not be reached.
+    pub async fn admit_connection(&mut self, client: Client) -> Result<Reply> {
+        let req = ConnectionRequest {
+            client: Some(client),
+        };
+        self.call(CONNECTION_ADMIT_PATH, req).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message;
+
+    #[test]
+    fn event_roundtrip() {
+        let mut e = Event::simple_event();
+        e.tags = vec![vec!["p".to_owned(), "abc".to_owned()]];
+        let req = EventRequest {
+            event: Some(EventMsg::from(&e)),
+            client: Some(Client {
+                ip_addr: "127.0.0.1".to_owned(),
+                origin: None,
+                user_agent: Some("test".to_owned()),
+            }),
+            auth_pubkey: None,
+        };
+        let decoded = EventRequest::decode(req.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(decoded, req);
+        assert_eq!(decoded.event.unwrap().tags[0].values, e.tags[0]);
+    }
+
+    #[test]
+    fn reply_decisions() {
+        let reply = |decision| Reply {
+            decision,
+            message: None,
+        };
+        assert!(reply(Decision::Deny as i32).is_denied());
+        assert!(!reply(Decision::Permit as i32).is_denied());
+        // unknown decisions are not treated as denials
+        assert!(!reply(Decision::Unspecified as i32).is_denied());
+        assert!(!reply(42).is_denied());
+    }
+}
@@ -0,0 +1,682 @@
+//! Negentropy set reconciliation (NIP-77)
+//!
+//! A client opens a session with a filter and an initial negentropy
+//! message.  The relay collects the timestamps and ids of matching
+//! events into a sorted storage index, and answers each message with
+//! fingerprints of the ranges where the two sets may differ, or with
+//! the ids in ranges small enough to list.  The client learns which
+//! events each side is missing, and only needs to transfer those.
+use crate::error::{Error, Result};
+use crate::event::Event;
+use crate::repo::NostrRepo;
+use crate::subscription::{ReqFilter, Subscription};
+use bitcoin_hashes::{sha256, Hash};
+use serde::de::Unexpected;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+
+/// Negentropy protocol version implemented
+const PROTOCOL_VERSION: u8 = 0x61;
+
+/// Size of an event id
+const ID_SIZE: usize = 32;
+
+/// Size of a range fingerprint
+const FINGERPRINT_SIZE: usize = 16;
+
+/// Number of sub-ranges a differing range is split into
+const BUCKETS: usize = 16;
+
+/// Largest message sent to a client, in bytes (before hex encoding)
+const FRAME_SIZE_LIMIT: usize = 60_000;
+
+/// Maximum number of concurrent sessions for a connection
+pub const MAX_NEG_SESSIONS: usize = 4;
+
+/// Range modes
+const MODE_SKIP: u64 = 0;
+const MODE_FINGERPRINT: u64 = 1;
+const MODE_ID_LIST: u64 = 2;
+
+/// An event in the storage index.  Items are ordered by timestamp,
+/// then by id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Item {
+    pub created_at: u64,
+    pub id: [u8; ID_SIZE],
+}
+
+impl Item {
+    /// Build an item from an event's timestamp and hex id.
+    #[must_use]
+    pub fn new(created_at: u64, id: &str) -> Option<Item> {
+        let bytes = hex::decode(id).ok()?;
+        Some(Item {
+            created_at,
+            id: bytes.try_into().ok()?,
+        })
+    }
+}
+
+/// The upper end of a range.  Ids are abbreviated to the shortest
+/// prefix that separates neighbouring items; the rest is zeros.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Bound {
+    item: Item,
+    id_len: usize,
+}
+
+impl Bound {
+    fn new(created_at: u64) -> Bound {
+        Bound {
+            item: Item {
+                created_at,
+                id: [0; ID_SIZE],
+            },
+            id_len: 0,
+        }
+    }
+
+    /// The shortest bound that sorts after `prev`, and not after `curr`.
+    fn between(prev: &Item, curr: &Item) -> Bound {
+        if curr.created_at != prev.created_at {
+            return Bound::new(curr.created_at);
+        }
+        let shared = prev.id.iter().zip(curr.id.iter()).take_while(|(a, b)| a == b).count();
+        let id_len = (shared + 1).min(ID_SIZE);
+        let mut item = Item {
+            created_at: curr.created_at,
+            id: [0; ID_SIZE],
+        };
+        item.id[..id_len].copy_from_slice(&curr.id[..id_len]);
+        Bound { item, id_len }
+    }
+}
+
+/// Sorted timestamps and ids of the events a session reconciles
+#[derive(Debug, Clone, Default)]
+pub struct NegentropyStorage {
+    items: Vec<Item>,
+}
+
+impl NegentropyStorage {
+    #[must_use]
+    pub fn new(mut items: Vec<Item>) -> Self {
+        items.sort_unstable();
+        items.dedup();
+        NegentropyStorage { items }
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Index of the first item in `from..to` that is not below `bound`.
+    fn lower_bound(&self, from: usize, to: usize, bound: &Item) -> usize {
+        from + self.items[from..to].partition_point(|i| i < bound)
+    }
+
+    /// Fingerprint of the items in `from..to`: the ids summed as
+    /// 256-bit little-endian integers, followed by the count, hashed.
+    fn fingerprint(&self, from: usize, to: usize) -> [u8; FINGERPRINT_SIZE] {
+        let mut sum = [0u8; ID_SIZE];
+        for item in &self.items[from..to] {
+            let mut carry = 0u16;
+            for (s, b) in sum.iter_mut().zip(item.id.iter()) {
+                let total = u16::from(*s) + u16::from(*b) + carry;
+                *s = (total & 0xff) as u8;
+                carry = total >> 8;
+            }
+        }
+        let mut input = sum.to_vec();
+        encode_varint((to - from) as u64, &mut input);
+        let hash = sha256::Hash::hash(&input);
+        let mut fp = [0u8; FINGERPRINT_SIZE];
+        fp.copy_from_slice(&hash[..FINGERPRINT_SIZE]);
+        fp
+    }
+
+    /// Respond to a reconciliation message from a client.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the message is malformed.
+    pub fn reconcile(&self, query: &[u8]) -> Result<Vec<u8>> {
+        let mut reader = Reader::new(query);
+        let mut writer = Writer::default();
+        let version = reader.byte()?;
+        if !(0x60..=0x6f).contains(&version) {
+            return Err(negentropy_error("invalid protocol version"));
+        }
+        // an unsupported version is answered with the one we speak.
+        let mut out = vec![PROTOCOL_VERSION];
+        if version != PROTOCOL_VERSION {
+            return Ok(out);
+        }
+        let mut prev_bound = Bound::new(0);
+        let mut prev_index = 0;
+        let mut skip = false;
+        while !reader.is_empty() {
+            let mut o = vec![];
+            let curr_bound = reader.bound()?;
+            let mode = reader.varint()?;
+            let lower = prev_index;
+            let mut upper = self.lower_bound(prev_index, self.items.len(), &curr_bound.item);
+            match mode {
+                MODE_SKIP => skip = true,
+                MODE_FINGERPRINT => {
+                    let theirs = reader.bytes(FINGERPRINT_SIZE)?;
+                    if theirs == self.fingerprint(lower, upper) {
+                        skip = true;
+                    } else {
+                        if std::mem::take(&mut skip) {
+                            writer.bound(&prev_bound, &mut o);
+                            encode_varint(MODE_SKIP, &mut o);
+                        }
+                        self.split_range(lower, upper, &curr_bound, &mut writer, &mut o);
+                    }
+                }
+                MODE_ID_LIST => {
+                    // the ids the client has do not change our answer,
+                    // which is always every id we have in the range.
+                    let count = reader.varint()?;
+                    for _ in 0..count {
+                        reader.bytes(ID_SIZE)?;
+                    }
+                    if std::mem::take(&mut skip) {
+                        writer.bound(&prev_bound, &mut o);
+                        encode_varint(MODE_SKIP, &mut o);
+                    }
+                    let mut ids = vec![];
+                    let mut end_bound = curr_bound;
+                    for (i, item) in self.items[lower..upper].iter().enumerate() {
+                        if exceeds_frame(out.len() + o.len() + ids.len()) {
+                            end_bound = Bound { item: *item, id_len: ID_SIZE };
+                            upper = lower + i;
+                            break;
+                        }
+                        ids.extend_from_slice(&item.id);
+                    }
+                    writer.bound(&end_bound, &mut o);
+                    encode_varint(MODE_ID_LIST, &mut o);
+                    encode_varint((ids.len() / ID_SIZE) as u64, &mut o);
+                    o.extend_from_slice(&ids);
+                    out.append(&mut o);
+                }
+                _ => return Err(negentropy_error("unexpected range mode")),
+            }
+            if exceeds_frame(out.len() + o.len()) {
+                // stop here, and have the client continue with the
+                // rest of the items in a following round.
+                let rest = self.fingerprint(upper, self.items.len());
+                writer.bound(&Bound::new(u64::MAX), &mut out);
+                encode_varint(MODE_FINGERPRINT, &mut out);
+                out.extend_from_slice(&rest);
+                break;
+            }
+            out.append(&mut o);
+            prev_index = upper;
+            prev_bound = curr_bound;
+        }
+        Ok(out)
+    }
+
+    /// Describe a range that differs: list its ids if it is small, or
+    /// send fingerprints of evenly sized sub-ranges.
+    fn split_range(&self, lower: usize, upper: usize, upper_bound: &Bound, writer: &mut Writer, o: &mut Vec<u8>) {
+        let count = upper - lower;
+        if count < BUCKETS * 2 {
+            writer.bound(upper_bound, o);
+            encode_varint(MODE_ID_LIST, o);
+            encode_varint(count as u64, o);
+            for item in &self.items[lower..upper] {
+                o.extend_from_slice(&item.id);
+            }
+            return;
+        }
+        let per_bucket = count / BUCKETS;
+        let with_extra = count % BUCKETS;
+        let mut curr = lower;
+        for i in 0..BUCKETS {
+            let size = per_bucket + usize::from(i < with_extra);
+            let fp = self.fingerprint(curr, curr + size);
+            curr += size;
+            let bound = if curr == upper {
+                *upper_bound
+            } else {
+                Bound::between(&self.items[curr - 1], &self.items[curr])
+            };
+            writer.bound(&bound, o);
+            encode_varint(MODE_FINGERPRINT, o);
+            o.extend_from_slice(&fp);
+        }
+    }
+}
+
+fn negentropy_error(msg: &str) -> Error {
+    Error::NegentropyError(msg.to_owned())
+}
+
+fn exceeds_frame(len: usize) -> bool {
+    len > FRAME_SIZE_LIMIT - 200
+}
+
+/// Append a variable-length integer: base 128, most significant
+/// digit first, with the high bit set on all but the last byte.
+fn encode_varint(mut n: u64, out: &mut Vec<u8>) {
+    let mut digits = vec![(n & 0x7f) as u8];
+    n >>= 7;
+    while n > 0 {
+        digits.push((n & 0x7f) as u8 | 0x80);
+        n >>= 7;
+    }
+    out.extend(digits.iter().rev());
+}
+
+/// Decoder for client messages.  Timestamps are sent as differences
+/// from the previous one, so the reader keeps track of it.
+struct Reader<'a> {
+    buf: &'a [u8],
+    last_timestamp: u64,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Reader { buf, last_timestamp: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(negentropy_error("message ended unexpectedly"));
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut n: u64 = 0;
+        loop {
+            let b = self.byte()?;
+            n = n
+                .checked_mul(128)
+                .ok_or_else(|| negentropy_error("integer too large"))?
+                | u64::from(b & 0x7f);
+            if b & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+    }
+
+    /// Timestamps are sent as one more than the increase over the
+    /// previous timestamp, with zero meaning infinity.
+    fn timestamp(&mut self) -> Result<u64> {
+        let delta = self.varint()?;
+        if delta == 0 || self.last_timestamp == u64::MAX {
+            self.last_timestamp = u64::MAX;
+        } else {
+            self.last_timestamp = self.last_timestamp.saturating_add(delta - 1);
+        }
+        Ok(self.last_timestamp)
+    }
+
+    fn bound(&mut self) -> Result<Bound> {
+        let created_at = self.timestamp()?;
+        let id_len = usize::try_from(self.varint()?).unwrap_or(usize::MAX);
+        if id_len > ID_SIZE {
+            return Err(negentropy_error("bound id too long"));
+        }
+        let mut bound = Bound::new(created_at);
+        bound.item.id[..id_len].copy_from_slice(self.bytes(id_len)?);
+        bound.id_len = id_len;
+        Ok(bound)
+    }
+}
+
+/// Encoder for responses, tracking the previous timestamp written.
+#[derive(Default)]
+struct Writer {
+    last_timestamp: u64,
+}
+
+impl Writer {
+    fn bound(&mut self, bound: &Bound, out: &mut Vec<u8>) {
+        let ts = bound.item.created_at;
+        if ts == u64::MAX {
+            self.last_timestamp = u64::MAX;
+            encode_varint(0, out);
+        } else {
+            encode_varint(ts.saturating_sub(self.last_timestamp) + 1, out);
+            self.last_timestamp = ts;
+        }
+        encode_varint(bound.id_len as u64, out);
+        out.extend_from_slice(&bound.item.id[..bound.id_len]);
+    }
+}
+
+/// Collect the events matching a filter into a storage index.
+/// Returns `None` if more than `max_records` events match.  Events
+/// that `visible` rejects are left out, so that their ids are not
+/// revealed.
+///
+/// # Errors
+///
+/// Will return `Err` if the database query fails.
+pub async fn load_storage(
+    repo: &dyn NostrRepo,
+    filter: ReqFilter,
+    max_records: usize,
+    client_id: String,
+    visible: impl Fn(&Event) -> bool,
+) -> Result<Option<NegentropyStorage>> {
+    let mut filter = filter;
+    // one more than allowed, to find out if there are too many.
+    let cap = max_records as u64 + 1;
+    filter.limit = Some(filter.limit.map_or(cap, |l| l.min(cap)));
+    let sub = Subscription {
+        id: "negentropy".to_owned(),
+        filters: vec![filter],
+    };
+    let (query_tx, mut query_rx) = tokio::sync::mpsc::channel(1024);
+    let (_abandon_tx, abandon_rx) = tokio::sync::oneshot::channel();
+    let query = repo.query_subscription(sub, client_id, query_tx, abandon_rx);
+    let collect = async move {
+        let mut items = vec![];
+        while let Some(res) = query_rx.recv().await {
+            if res.event == "EOSE" {
+                break;
+            }
+            let Ok(e) = serde_json::from_str::<Event>(&res.event) else {
+                continue;
+            };
+            if !visible(&e) {
+                continue;
+            }
+            if let Some(item) = Item::new(e.created_at, &e.id) {
+                items.push(item);
+            }
+            if items.len() > max_records {
+                return None;
+            }
+        }
+        Some(NegentropyStorage::new(items))
+    };
+    let (res, storage) = tokio::join!(query, collect);
+    res?;
+    Ok(storage)
+}
+
+/// Parse the elements of a `[<cmd>, <sub_id>, ...]` array, checking
+/// the command.
+fn parse_neg_request<'de, D>(deserializer: D, expected_cmd: &str, len: usize) -> Result<Vec<Value>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let v: Value = Deserialize::deserialize(deserializer)?;
+    let Value::Array(elems) = v else {
+        return Err(serde::de::Error::custom("not array"));
+    };
+    if elems.first().and_then(Value::as_str) != Some(expected_cmd) {
+        return Err(serde::de::Error::custom(format!("missing {expected_cmd} command")));
+    }
+    if elems.len() != len {
+        return Err(serde::de::Error::invalid_length(elems.len(), &"a negentropy message"));
+    }
+    if !elems[1].is_string() {
+        return Err(serde::de::Error::custom("missing subscription id"));
+    }
+    Ok(elems)
+}
+
+/// Decode the hex message of a negentropy request.
+fn hex_message<'de, D>(val: &Value) -> Result<Vec<u8>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    val.as_str()
+        .and_then(|s| hex::decode(s).ok())
+        .ok_or_else(|| serde::de::Error::invalid_type(Unexpected::Other("message"), &"a hex string"))
+}
+
+/// Request to start reconciling the events matching a filter
+#[derive(Serialize, PartialEq, Eq, Debug, Clone)]
+pub struct NegOpen {
+    pub id: String,
+    pub filter: ReqFilter,
+    pub message: Vec<u8>,
+}
+
+impl<'de> Deserialize<'de> for NegOpen {
+    fn deserialize<D>(deserializer: D) -> Result<NegOpen, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let mut elems = parse_neg_request(deserializer, "NEG-OPEN", 4)?;
+        let message = hex_message::<D>(&elems[3])?;
+        let filter: ReqFilter = serde_json::from_value(elems[2].take())
+            .map_err(|_| serde::de::Error::custom("could not parse filter"))?;
+        Ok(NegOpen {
+            id: elems[1].as_str().unwrap_or_default().to_owned(),
+            filter,
+            message,
+        })
+    }
+}
+
+/// Next message of a reconciliation
+#[derive(Serialize, PartialEq, Eq, Debug, Clone)]
+pub struct NegMsg {
+    pub id: String,
+    pub message: Vec<u8>,
+}
+
+impl<'de> Deserialize<'de> for NegMsg {
+    fn deserialize<D>(deserializer: D) -> Result<NegMsg, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let elems = parse_neg_request(deserializer, "NEG-MSG", 3)?;
+        Ok(NegMsg {
+            id: elems[1].as_str().unwrap_or_default().to_owned(),
+            message: hex_message::<D>(&elems[2])?,
+        })
+    }
+}
+
+/// Request to end a reconciliation
+#[derive(Serialize, PartialEq, Eq, Debug, Clone)]
+pub struct NegClose {
+    pub id: String,
+}
+
+impl<'de> Deserialize<'de> for NegClose {
+    fn deserialize<D>(deserializer: D) -> Result<NegClose, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let elems = parse_neg_request(deserializer, "NEG-CLOSE", 2)?;
+        Ok(NegClose {
+            id: elems[1].as_str().unwrap_or_default().to_owned(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn item(created_at: u64, n: u16) -> Item {
+        let mut id = [0u8; ID_SIZE];
+        id[..2].copy_from_slice(&n.to_be_bytes());
+        id[31] = 0xff;
+        Item { created_at, id }
+    }
+
+    fn number(id: &[u8; ID_SIZE]) -> u16 {
+        u16::from_be_bytes([id[0], id[1]])
+    }
+
+    /// Items numbered in a range, three to each timestamp.
+    fn storage(range: impl Iterator<Item = u16>) -> NegentropyStorage {
+        NegentropyStorage::new(range.map(|n| item(1000 + u64::from(n / 3), n)).collect())
+    }
+
+    /// The client side of the protocol, as far as needed to check
+    /// which ids are found to differ.
+    fn client_reconcile(ours: &NegentropyStorage, msg: &[u8], have: &mut HashSet<Item>, need: &mut HashSet<[u8; 32]>) -> Option<Vec<u8>> {
+        let mut reader = Reader::new(msg);
+        let mut writer = Writer::default();
+        assert_eq!(reader.byte().unwrap(), PROTOCOL_VERSION);
+        let mut out = vec![PROTOCOL_VERSION];
+        let mut prev_index = 0;
+        let mut skip = Bound::new(0);
+        let mut pending_skip = false;
+        while !reader.is_empty() {
+            let bound = reader.bound().unwrap();
+            let mode = reader.varint().unwrap();
+            let upper = ours.lower_bound(prev_index, ours.len(), &bound.item);
+            match mode {
+                MODE_SKIP => pending_skip = true,
+                MODE_FINGERPRINT => {
+                    let theirs = reader.bytes(FINGERPRINT_SIZE).unwrap();
+                    if theirs == ours.fingerprint(prev_index, upper) {
+                        pending_skip = true;
+                    } else {
+                        if std::mem::take(&mut pending_skip) {
+                            writer.bound(&skip, &mut out);
+                            encode_varint(MODE_SKIP, &mut out);
+                        }
+                        ours.split_range(prev_index, upper, &bound, &mut writer, &mut out);
+                    }
+                }
+                MODE_ID_LIST => {
+                    let count = reader.varint().unwrap();
+                    let theirs: HashSet<[u8; 32]> = (0..count)
+                        .map(|_| reader.bytes(ID_SIZE).unwrap().try_into().unwrap())
+                        .collect();
+                    for i in &ours.items[prev_index..upper] {
+                        if !theirs.contains(&i.id) {
+                            have.insert(*i);
+                        }
+                    }
+                    let mine: HashSet<[u8; 32]> = ours.items[prev_index..upper].iter().map(|i| i.id).collect();
+                    need.extend(theirs.difference(&mine));
+                    pending_skip = true;
+                }
+                _ => panic!("bad mode"),
+            }
+            prev_index = upper;
+            skip = bound;
+        }
+        (out.len() > 1).then_some(out)
+    }
+
+    fn initial_message(s: &NegentropyStorage) -> Vec<u8> {
+        let mut out = vec![PROTOCOL_VERSION];
+        let mut writer = Writer::default();
+        writer.bound(&Bound::new(u64::MAX), &mut out);
+        encode_varint(MODE_FINGERPRINT, &mut out);
+        out.extend_from_slice(&s.fingerprint(0, s.len()));
+        out
+    }
+
+    fn sync(client: &NegentropyStorage, relay: &NegentropyStorage) -> (HashSet<Item>, HashSet<[u8; 32]>) {
+        let mut have = HashSet::new();
+        let mut need = HashSet::new();
+        let mut msg = initial_message(client);
+        for _ in 0..20 {
+            let reply = relay.reconcile(&msg).unwrap();
+            match client_reconcile(client, &reply, &mut have, &mut need) {
+                Some(next) => msg = next,
+                None => return (have, need),
+            }
+        }
+        panic!("reconciliation did not finish");
+    }
+
+    #[test]
+    fn varint_round_trip() {
+        for n in [0, 1, 127, 128, 300, 1 << 35, u64::MAX >> 1] {
+            let mut buf = vec![];
+            encode_varint(n, &mut buf);
+            assert_eq!(Reader::new(&buf).varint().unwrap(), n);
+        }
+        let mut buf = vec![];
+        encode_varint(300, &mut buf);
+        assert_eq!(buf, vec![0x82, 0x2c]);
+    }
+
+    #[test]
+    fn identical_sets_finish_at_once() {
+        let s = storage(0..200);
+        let reply = s.reconcile(&initial_message(&s)).unwrap();
+        assert_eq!(reply, vec![PROTOCOL_VERSION]);
+    }
+
+    #[test]
+    fn differences_are_found() {
+        let client = storage((0..200).filter(|n| n % 7 != 0));
+        let relay = storage(5..220);
+        let (have, need) = sync(&client, &relay);
+        let have_ids: HashSet<u16> = have.iter().map(|i| number(&i.id)).collect();
+        let need_ids: HashSet<u16> = need.iter().map(number).collect();
+        assert_eq!(have_ids, (1..5).collect());
+        assert_eq!(need_ids, (5..220).filter(|n| n % 7 == 0 || *n >= 200).collect());
+    }
+
+    #[test]
+    fn empty_relay() {
+        let client = storage(0..50);
+        let (have, need) = sync(&client, &NegentropyStorage::default());
+        assert_eq!(have.len(), 50);
+        assert!(need.is_empty());
+    }
+
+    #[test]
+    fn large_differences_span_several_messages() {
+        // more ids than fit in one frame
+        let relay = storage(0..5000);
+        let (have, need) = sync(&NegentropyStorage::default(), &relay);
+        assert!(have.is_empty());
+        assert_eq!(need.len(), 5000);
+    }
+
+    #[test]
+    fn other_versions_are_answered_with_ours() {
+        let s = storage(0..10);
+        assert_eq!(s.reconcile(&[0x62]).unwrap(), vec![PROTOCOL_VERSION]);
+        assert!(s.reconcile(&[0x01]).is_err());
+        assert!(s.reconcile(&[]).is_err());
+        // a truncated fingerprint
+        assert!(s.reconcile(&[PROTOCOL_VERSION, 0x00, 0x00, 0x01, 0xaa]).is_err());
+    }
+
+    #[test]
+    fn parse_messages() {
+        let open: NegOpen = serde_json::from_str(r#"["NEG-OPEN","s1",{"kinds":[1]},"6100"]"#).unwrap();
+        assert_eq!(open.id, "s1");
+        assert_eq!(open.filter.kinds, Some(vec![1]));
+        assert_eq!(open.message, vec![0x61, 0x00]);
+        let msg: NegMsg = serde_json::from_str(r#"["NEG-MSG","s1","61"]"#).unwrap();
+        assert_eq!(msg.message, vec![0x61]);
+        let close: NegClose = serde_json::from_str(r#"["NEG-CLOSE","s1"]"#).unwrap();
+        assert_eq!(close.id, "s1");
+        assert!(serde_json::from_str::<NegMsg>(r#"["NEG-MSG","s1","zz"]"#).is_err());
+        assert!(serde_json::from_str::<NegClose>(r#"["CLOSE","s1"]"#).is_err());
+    }
+}
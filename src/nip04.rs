@@ -0,0 +1,80 @@
+//! Encrypted direct messages (NIP-04)
+//!
+//! Message content is encrypted with AES-256-CBC, keyed by the
+//! x-coordinate of the ECDH point shared by the sender and receiver,
+//! and encoded as `<base64 ciphertext>?iv=<base64 iv>`.
+use crate::error::{Error, Result};
+use openssl::symm::{self, Cipher};
+use secp256k1::ecdh::SharedSecret;
+use secp256k1::{PublicKey, SecretKey, XOnlyPublicKey};
+use std::str::FromStr;
+
+/// Encrypted direct message
+pub const KIND_DM: u64 = 4;
+
+/// Key shared between a secret key and a (hex x-only) pubkey.
+fn shared_key(secret: &SecretKey, pubkey: &str) -> Result<[u8; 32]> {
+    let xonly = XOnlyPublicKey::from_str(pubkey).map_err(|_| Error::CustomError("invalid pubkey".to_owned()))?;
+    // nostr pubkeys are x-only; either point with that x gives the
+    // same shared x-coordinate.
+    let point = PublicKey::from_slice(&[&[0x02], &xonly.serialize()[..]].concat())
+        .map_err(|_| Error::CustomError("invalid pubkey".to_owned()))?;
+    let shared = SharedSecret::new_with_hash(&point, secret, |x, _| x.into());
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&shared[..32]);
+    Ok(key)
+}
+
+/// Encrypt a message from the owner of a secret key to a pubkey.
+///
+/// # Errors
+///
+/// Will return `Err` if the pubkey is invalid.
+pub fn encrypt(secret: &SecretKey, pubkey: &str, text: &str) -> Result<String> {
+    let key = shared_key(secret, pubkey)?;
+    let iv: [u8; 16] = rand::random();
+    let ciphertext = symm::encrypt(Cipher::aes_256_cbc(), &key, Some(&iv), text.as_bytes())
+        .map_err(|_| Error::CustomError("encryption failed".to_owned()))?;
+    Ok(format!("{}?iv={}", base64::encode(ciphertext), base64::encode(iv)))
+}
+
+/// Decrypt a message sent from a pubkey to the owner of a secret key.
+///
+/// # Errors
+///
+/// Will return `Err` if the pubkey or content is invalid, or the
+/// message was not encrypted for this key.
+pub fn decrypt(secret: &SecretKey, pubkey: &str, content: &str) -> Result<String> {
+    let invalid = || Error::CustomError("invalid encrypted message".to_owned());
+    let (ciphertext, iv) = content.split_once("?iv=").ok_or_else(invalid)?;
+    let ciphertext = base64::decode(ciphertext).map_err(|_| invalid())?;
+    let iv = base64::decode(iv).map_err(|_| invalid())?;
+    if iv.len() != 16 {
+        return Err(invalid());
+    }
+    let key = shared_key(secret, pubkey)?;
+    let plaintext = symm::decrypt(Cipher::aes_256_cbc(), &key, Some(&iv), &ciphertext).map_err(|_| invalid())?;
+    String::from_utf8(plaintext).map_err(|_| invalid())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secp256k1::{KeyPair, Secp256k1};
+
+    #[test]
+    fn messages_decrypt_for_either_party() {
+        let secp = Secp256k1::new();
+        let alice = KeyPair::new(&secp, &mut secp256k1::rand::thread_rng());
+        let bob = KeyPair::new(&secp, &mut secp256k1::rand::thread_rng());
+        let alice_pk = XOnlyPublicKey::from_keypair(&alice).to_string();
+        let bob_pk = XOnlyPublicKey::from_keypair(&bob).to_string();
+        let alice_sk = SecretKey::from_keypair(&alice);
+        let bob_sk = SecretKey::from_keypair(&bob);
+        let content = encrypt(&alice_sk, &bob_pk, "stats").unwrap();
+        assert!(content.contains("?iv="));
+        assert_eq!(decrypt(&bob_sk, &alice_pk, &content).unwrap(), "stats");
+        assert!(decrypt(&bob_sk, &bob_pk, &content).is_err());
+        assert!(decrypt(&bob_sk, &alice_pk, "garbage").is_err());
+    }
+}
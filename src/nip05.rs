@@ -4,21 +4,73 @@
 //! address with their public key, in metadata events.  This module
 //! consumes a stream of metadata events, and keeps a database table
 //! updated with the current NIP-05 verification status.
-use crate::config::VerifiedUsers;
+use crate::config::{Settings, VerifiedUsers};
 use crate::error::{Error, Result};
-use crate::event::Event;
+use crate::event::{BroadcastEvent, Event};
+use crate::http_client::{https_client, read_limited, HttpsClient};
 use crate::repo::NostrRepo;
+use crate::utils::unix_time;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use hyper::body::HttpBody;
-use hyper::client::connect::HttpConnector;
-use hyper::Client;
-use hyper_tls::HttpsConnector;
+use serde::{Deserialize, Serialize};
 use std::time::Duration;
 use std::time::Instant;
 use std::time::SystemTime;
+use tokio::sync::mpsc;
 use tokio::time::Interval;
 use tracing::{debug, info, warn};
 
+/// Delay before the first retry of a check that could not be
+/// completed; it doubles with each failure.
+const RETRY_BASE_SECS: u64 = 60;
+
+/// Longest delay between retries.
+const RETRY_MAX_SECS: u64 = 6 * 60 * 60;
+
+/// Most redirects followed when fetching a NIP-05 document.
+const MAX_REDIRECTS: usize = 3;
+
+/// Largest NIP-05 document read.
+const MAX_DOCUMENT_SIZE: usize = 1024 * 1024;
+
+/// A metadata event waiting for its NIP-05 address to be checked.
+/// Queued events are kept in the database, so that none are lost to
+/// a burst of new authors or a restart.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct QueuedVerification {
+    pub event: Event,
+    /// Checks that could not be completed
+    pub attempts: u64,
+    /// Unix time the next check is due
+    pub retry_at: u64,
+}
+
+/// Seconds to wait before checking again, after a number of checks
+/// that could not be completed.
+fn retry_backoff(attempts: u64) -> u64 {
+    RETRY_BASE_SECS
+        .saturating_mul(1 << attempts.saturating_sub(1).min(16))
+        .min(RETRY_MAX_SECS)
+}
+
+/// A check being made by a worker
+#[derive(Debug)]
+enum Check {
+    /// The address in a queued metadata event
+    New(QueuedVerification),
+    /// An address verified before
+    Reverify(VerificationRecord),
+}
+
+impl Check {
+    fn pubkey(&self) -> &str {
+        match self {
+            Check::New(q) => &q.event.pubkey,
+            Check::Reverify(v) => &v.address,
+        }
+    }
+}
+
 /// NIP-05 verifier state
 pub struct Verifier {
     /// Repository for saving/retrieving events and records
@@ -26,17 +78,26 @@ pub struct Verifier {
     /// Metadata events for us to inspect
     metadata_rx: tokio::sync::broadcast::Receiver<Event>,
     /// Newly validated events get written and then broadcast on this channel to subscribers
-    event_tx: tokio::sync::broadcast::Sender<Event>,
+    event_tx: tokio::sync::broadcast::Sender<BroadcastEvent>,
     /// Settings
-    settings: crate::config::Settings,
+    settings: Arc<Settings>,
     /// HTTP client
-    client: hyper::Client<HttpsConnector<HttpConnector>, hyper::Body>,
+    client: HttpsClient,
     /// After all accounts are updated, wait this long before checking again.
     wait_after_finish: Duration,
     /// Minimum amount of time between HTTP queries
     http_wait_duration: Duration,
     /// Interval for updating verification records
     reverify_interval: Interval,
+    /// Interval for starting queued checks that are due
+    queue_interval: Interval,
+    /// Pubkeys with a check in progress
+    in_flight: HashSet<String>,
+    /// When each domain was last queried
+    domain_queried: HashMap<String, Instant>,
+    /// Completed checks, from the workers
+    results_tx: mpsc::Sender<(Check, UserWebVerificationStatus)>,
+    results_rx: mpsc::Receiver<(Check, UserWebVerificationStatus)>,
 }
 
 /// A NIP-05 identifier is a local part and domain.
@@ -52,11 +113,22 @@ impl Nip05Name {
         self.local == "_"
     }
 
-    /// Determine the URL to query for verification
+    #[must_use] pub fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    /// Is the domain a Tor onion service?
+    #[must_use] pub fn is_onion(&self) -> bool {
+        self.domain.ends_with(".onion")
+    }
+
+    /// Determine the URL to query for verification.  Onion services
+    /// are authenticated by their address, and rarely serve HTTPS.
     fn to_url(&self) -> Option<http::Uri> {
+        let scheme = if self.is_onion() { "http" } else { "https" };
         format!(
-            "https://{}/.well-known/nostr.json?name={}",
-            self.domain, self.local
+            "{}://{}/.well-known/nostr.json?name={}",
+            scheme, self.domain, self.local
         )
             .parse::<http::Uri>()
             .ok()
@@ -124,13 +196,17 @@ impl Verifier {
     pub fn new(
         repo: Arc<dyn NostrRepo>,
         metadata_rx: tokio::sync::broadcast::Receiver<Event>,
-        event_tx: tokio::sync::broadcast::Sender<Event>,
-        settings: crate::config::Settings,
+        event_tx: tokio::sync::broadcast::Sender<BroadcastEvent>,
+        settings: Settings,
     ) -> Result<Self> {
         info!("creating NIP-05 verifier");
-        // setup hyper client
-        let https = HttpsConnector::new();
-        let client = Client::builder().build::<_, hyper::Body>(https);
+        // setup hyper client, through a proxy if configured
+        let verified_users = &settings.verified_users;
+        let client = https_client(
+            verified_users.proxy.as_deref(),
+            verified_users.dns_over_https.as_deref(),
+            verified_users.allow_private_addresses,
+        )?;
 
         // After all accounts have been re-verified, don't check again
         // for this long.
@@ -142,115 +218,26 @@ impl Verifier {
         // there is no work to be done, it will be reset to a longer
         // duration.
         let reverify_interval = tokio::time::interval(http_wait_duration);
+        let queue_interval = tokio::time::interval(http_wait_duration);
+        let workers = settings.verified_users.max_concurrent_checks.max(1);
+        let (results_tx, results_rx) = mpsc::channel(workers);
         Ok(Verifier {
             repo,
             metadata_rx,
             event_tx,
-            settings,
+            settings: Arc::new(settings),
             client,
             wait_after_finish,
             http_wait_duration,
             reverify_interval,
+            queue_interval,
+            in_flight: HashSet::new(),
+            domain_queried: HashMap::new(),
+            results_tx,
+            results_rx,
         })
     }
 
-    /// Perform web verification against a NIP-05 name and address.
-    pub async fn get_web_verification(
-        &mut self,
-        nip: &Nip05Name,
-        pubkey: &str,
-    ) -> UserWebVerificationStatus {
-        self.get_web_verification_res(nip, pubkey)
-            .await
-            .unwrap_or(UserWebVerificationStatus::Unknown)
-    }
-
-    /// Perform web verification against an `Event` (must be metadata).
-    pub async fn get_web_verification_from_event(
-        &mut self,
-        e: &Event,
-    ) -> UserWebVerificationStatus {
-        let nip_parse = e.get_nip05_addr();
-        if let Some(nip) = nip_parse {
-            self.get_web_verification_res(&nip, &e.pubkey)
-                .await
-                .unwrap_or(UserWebVerificationStatus::Unknown)
-        } else {
-            UserWebVerificationStatus::Unknown
-        }
-    }
-
-    /// Perform web verification, with a `Result` return.
-    async fn get_web_verification_res(
-        &mut self,
-        nip: &Nip05Name,
-        pubkey: &str,
-    ) -> Result<UserWebVerificationStatus> {
-        // determine if this domain should be checked
-        if !is_domain_allowed(
-            &nip.domain,
-            &self.settings.verified_users.domain_whitelist,
-            &self.settings.verified_users.domain_blacklist,
-        ) {
-            return Ok(UserWebVerificationStatus::DomainNotAllowed);
-        }
-        let url = nip
-            .to_url()
-            .ok_or_else(|| Error::CustomError("invalid NIP-05 URL".to_owned()))?;
-        let req = hyper::Request::builder()
-            .method(hyper::Method::GET)
-            .uri(url)
-            .header("Accept", "application/json")
-            .header(
-                "User-Agent",
-                format!(
-                    "nostr-rs-relay/{} NIP-05 Verifier",
-                    crate::info::CARGO_PKG_VERSION.unwrap()
-                ),
-            )
-            .body(hyper::Body::empty())
-            .expect("request builder");
-
-        let response_fut = self.client.request(req);
-
-        if let Ok(response_res) = tokio::time::timeout(Duration::from_secs(5), response_fut).await {
-            // limit size of verification document to 1MB.
-            const MAX_ALLOWED_RESPONSE_SIZE: u64 = 1024 * 1024;
-            let response = response_res?;
-            // determine content length from response
-            let response_content_length = match response.body().size_hint().upper() {
-                Some(v) => v,
-                None => MAX_ALLOWED_RESPONSE_SIZE + 1, // reject missing content length
-            };
-            // TODO: test how hyper handles the client providing an inaccurate content-length.
-            if response_content_length <= MAX_ALLOWED_RESPONSE_SIZE {
-                let (parts, body) = response.into_parts();
-                // TODO: consider redirects
-                if parts.status == http::StatusCode::OK {
-                    // parse body, determine if the username / key / address is present
-                    let body_bytes = hyper::body::to_bytes(body).await?;
-                    let body_matches = body_contains_user(&nip.local, pubkey, &body_bytes)?;
-                    if body_matches {
-                        return Ok(UserWebVerificationStatus::Verified);
-                    }
-                    // successful response, parsed as a nip-05
-                    // document, but this name/pubkey was not
-                    // present.
-                    return Ok(UserWebVerificationStatus::Unverified);
-                }
-            } else {
-                info!(
-                    "content length missing or exceeded limits for account: {:?}",
-                    nip.to_string()
-                );
-            }
-        } else {
-            info!("timeout verifying account {:?}", nip);
-            return Ok(UserWebVerificationStatus::Unknown);
-        }
-        Ok(UserWebVerificationStatus::Unknown)
-    }
-
     /// Perform NIP-05 verifier tasks.
     pub async fn run(&mut self) {
         // use this to schedule periodic re-validation tasks
@@ -268,38 +255,7 @@ impl Verifier {
         tokio::select! {
             m = self.metadata_rx.recv() => {
                 match m {
-                    Ok(e) => {
-                        if let Some(naddr) = e.get_nip05_addr() {
-                            info!("got metadata event for ({:?},{:?})", naddr.to_string() ,e.get_author_prefix());
-                            // Process a new author, checking if they are verified:
-                            let check_verified = self.repo.get_latest_user_verification(&e.pubkey).await;
-                            // ensure the event we got is more recent than the one we have, otherwise we can ignore it.
-                            if let Ok(last_check) = check_verified {
-                                if e.created_at <= last_check.event_created {
-                                    // this metadata is from the same author as an existing verification.
-                                    // it is older than what we have, so we can ignore it.
-                                    debug!("received older metadata event for author {:?}", e.get_author_prefix());
-                                    return Ok(());
-                                }
-                            }
-                            // old, or no existing record for this user.  In either case, we just create a new one.
-                            let start = Instant::now();
-                            let v = self.get_web_verification_from_event(&e).await;
-                            info!(
-                                "checked name {:?}, result: {:?}, in: {:?}",
-                                naddr.to_string(),
-                                v,
-                                start.elapsed()
-                            );
-                            // sleep to limit how frequently we make HTTP requests for new metadata events.  This should limit us to 4 req/sec.
-                            tokio::time::sleep(Duration::from_millis(250)).await;
-                            // if this user was verified, we need to write the
-                            // record, persist the event, and broadcast.
-                            if let UserWebVerificationStatus::Verified = v {
-                                self.create_new_verified_user(&naddr.to_string(), &e).await?;
-                            }
-                        }
-                    },
+                    Ok(e) => self.enqueue(e).await?,
                     Err(tokio::sync::broadcast::error::RecvError::Lagged(c)) => {
                         warn!("incoming metadata events overwhelmed buffer, {} events dropped",c);
                     }
@@ -308,6 +264,18 @@ impl Verifier {
                     }
                 }
             },
+            Some((check, status)) = self.results_rx.recv() => {
+                self.in_flight.remove(check.pubkey());
+                match check {
+                    Check::New(q) => self.finish_new(q, status).await?,
+                    Check::Reverify(v) => self.finish_reverify(&v, status).await?,
+                }
+                self.dispatch().await?;
+            },
+            _ = self.queue_interval.tick() => {
+                // start queued checks that are due, including retries.
+                self.dispatch().await?;
+            },
             _ = self.reverify_interval.tick() => {
                 // check and see if there is an old account that needs
                 // to be reverified
@@ -317,13 +285,144 @@ impl Verifier {
         Ok(())
     }
 
+    /// Queue a metadata event for its address to be checked.
+    async fn enqueue(&mut self, e: Event) -> Result<()> {
+        let Some(naddr) = e.get_nip05_addr() else {
+            return Ok(());
+        };
+        info!("got metadata event for ({:?},{:?})", naddr.to_string() ,e.get_author_prefix());
+        // Process a new author, checking if they are verified:
+        let check_verified = self.repo.get_latest_user_verification(&e.pubkey).await;
+        // ensure the event we got is more recent than the one we have, otherwise we can ignore it.
+        if let Ok(last_check) = check_verified {
+            if e.created_at <= last_check.event_created {
+                // this metadata is from the same author as an existing verification.
+                // it is older than what we have, so we can ignore it.
+                debug!("received older metadata event for author {:?}", e.get_author_prefix());
+                return Ok(());
+            }
+        }
+        // old, or no existing record for this user.  In either case, we just create a new one.
+        let queued = QueuedVerification {
+            event: e,
+            attempts: 0,
+            retry_at: unix_time(),
+        };
+        self.repo.put_queued_verification(&queued).await?;
+        self.dispatch().await
+    }
+
+    /// Most checks made at once.
+    fn max_workers(&self) -> usize {
+        self.settings.verified_users.max_concurrent_checks.max(1)
+    }
+
+    /// Can a domain be queried now?  If so, it is recorded as
+    /// queried.
+    fn domain_ready(&mut self, domain: &str) -> bool {
+        let per_min = self.settings.verified_users.domain_requests_per_min;
+        if per_min == 0 {
+            return true;
+        }
+        let wait = Duration::from_secs(60) / per_min;
+        if self.domain_queried.get(domain).is_some_and(|t| t.elapsed() < wait) {
+            return false;
+        }
+        if self.domain_queried.len() >= 10_000 {
+            self.domain_queried.retain(|_, t| t.elapsed() < wait);
+        }
+        self.domain_queried.insert(domain.to_owned(), Instant::now());
+        true
+    }
+
+    /// Start checks of queued events that are due, while workers are
+    /// free.
+    async fn dispatch(&mut self) -> Result<()> {
+        let free = self.max_workers().saturating_sub(self.in_flight.len());
+        if free == 0 {
+            return Ok(());
+        }
+        // checks in progress are still queued, and events for domains
+        // queried recently wait, so read more than can be started.
+        let limit = self.in_flight.len() + free * 4;
+        for q in self.repo.get_queued_verifications(unix_time(), limit).await? {
+            if self.in_flight.len() >= self.max_workers() {
+                break;
+            }
+            if self.in_flight.contains(&q.event.pubkey) {
+                continue;
+            }
+            let Some(nip) = q.event.get_nip05_addr() else {
+                self.repo.remove_queued_verification(&q.event.pubkey, &q.event.id).await?;
+                continue;
+            };
+            if self.domain_ready(nip.domain()) {
+                self.start(Check::New(q), nip);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check an address on a worker task, sending the result back to
+    /// the verifier.
+    fn start(&mut self, check: Check, nip: Nip05Name) {
+        let pubkey = check.pubkey().to_owned();
+        self.in_flight.insert(pubkey.clone());
+        let client = self.client.clone();
+        let settings = self.settings.clone();
+        let results_tx = self.results_tx.clone();
+        tokio::task::spawn(async move {
+            let start = Instant::now();
+            let status = get_web_verification(&client, &settings, &nip, &pubkey).await;
+            info!(
+                "checked name {:?}, result: {:?}, in: {:?}",
+                nip.to_string(),
+                status,
+                start.elapsed()
+            );
+            results_tx.send((check, status)).await.ok();
+        });
+    }
+
+    /// Record the result of checking a queued event.
+    async fn finish_new(&mut self, q: QueuedVerification, status: UserWebVerificationStatus) -> Result<()> {
+        let max_failures = self.settings.verified_users.max_consecutive_failures as u64;
+        match status {
+            UserWebVerificationStatus::Verified => {
+                // if this user was verified, we need to write the
+                // record, persist the event, and broadcast.
+                if let Some(naddr) = q.event.get_nip05_addr() {
+                    self.create_new_verified_user(&naddr.to_string(), &q.event).await?;
+                }
+            }
+            UserWebVerificationStatus::Unknown if q.attempts + 1 < max_failures => {
+                // server may be offline; try again later, backing off.
+                let attempts = q.attempts + 1;
+                let retry = QueuedVerification {
+                    retry_at: unix_time() + retry_backoff(attempts),
+                    attempts,
+                    event: q.event,
+                };
+                return self.repo.put_queued_verification(&retry).await;
+            }
+            UserWebVerificationStatus::Unknown => {
+                info!(
+                    "giving up on verifying {:?} after {} failures",
+                    q.event.get_author_prefix(),
+                    q.attempts + 1
+                );
+            }
+            UserWebVerificationStatus::Unverified | UserWebVerificationStatus::DomainNotAllowed => {}
+        }
+        self.repo.remove_queued_verification(&q.event.pubkey, &q.event.id).await
+    }
+
     /// Reverify the oldest user verification record.
     async fn do_reverify(&mut self) -> Result<()> {
         let reverify_setting = self
             .settings
             .verified_users
             .verify_update_frequency_duration;
-        let max_failures = self.settings.verified_users.max_consecutive_failures;
         // get from settings, but default to 6hrs between re-checking an account
         let reverify_dur = reverify_setting.unwrap_or_else(|| Duration::from_secs(60 * 60 * 6));
         // find all verification records that have success or failure OLDER than the reverify_dur.
@@ -335,45 +434,15 @@ impl Verifier {
             .unwrap_or(0);
         let vr = self.repo.get_oldest_user_verification(earliest_epoch).await;
         match vr {
-            Ok(ref v) => {
-                let new_status = self.get_web_verification(&v.name, &v.address).await;
-                match new_status {
-                    UserWebVerificationStatus::Verified => {
-                        // freshly verified account, update the
-                        // timestamp.
-                        self.repo.update_verification_timestamp(v.rowid)
-                            .await?;
-                        info!("verification updated for {}", v.to_string());
-
-                    }
-                    UserWebVerificationStatus::DomainNotAllowed
-			| UserWebVerificationStatus::Unknown => {
-                            // server may be offline, or temporarily
-                            // blocked by the config file.  Note the
-                            // failure so we can process something
-                            // else.
-
-                            // have we had enough failures to give up?
-                            if v.failure_count >= max_failures as u64 {
-				info!(
-                                    "giving up on verifying {:?} after {} failures",
-                                    v.name, v.failure_count
-				);
-				self.repo.delete_verification(v.rowid)
-                                    .await?;
-                            } else {
-				// record normal failure, incrementing failure count
-				info!("verification failed for {}", v.to_string());
-				self.repo.fail_verification(v.rowid).await?;
-                            }
-			}
-                    UserWebVerificationStatus::Unverified => {
-                        // domain has removed the verification, drop
-                        // the record on our side.
-                        info!("verification rescinded for {}", v.to_string());
-                        self.repo.delete_verification(v.rowid)
-                            .await?;
-                    }
+            Ok(v) => {
+                // the record stays the oldest until its check is
+                // done, and waits for a free worker.
+                if self.in_flight.contains(&v.address) || self.in_flight.len() >= self.max_workers() {
+                    return Ok(());
+                }
+                let nip = v.name.clone();
+                if self.domain_ready(nip.domain()) {
+                    self.start(Check::Reverify(v), nip);
                 }
             }
             Err(Error::SqlError(rusqlite::Error::QueryReturnedNoRows)) => {
@@ -392,6 +461,50 @@ impl Verifier {
         Ok(())
     }
 
+    /// Record the result of reverifying a record.
+    async fn finish_reverify(&mut self, v: &VerificationRecord, status: UserWebVerificationStatus) -> Result<()> {
+        let max_failures = self.settings.verified_users.max_consecutive_failures;
+        match status {
+            UserWebVerificationStatus::Verified => {
+                // freshly verified account, update the
+                // timestamp.
+                self.repo.update_verification_timestamp(v.rowid)
+                    .await?;
+                info!("verification updated for {}", v.to_string());
+
+            }
+            UserWebVerificationStatus::DomainNotAllowed
+                | UserWebVerificationStatus::Unknown => {
+                    // server may be offline, or temporarily
+                    // blocked by the config file.  Note the
+                    // failure so we can process something
+                    // else.
+
+                    // have we had enough failures to give up?
+                    if v.failure_count >= max_failures as u64 {
+                        info!(
+                            "giving up on verifying {:?} after {} failures",
+                            v.name, v.failure_count
+                        );
+                        self.repo.delete_verification(v.rowid)
+                            .await?;
+                    } else {
+                        // record normal failure, incrementing failure count
+                        info!("verification failed for {}", v.to_string());
+                        self.repo.fail_verification(v.rowid).await?;
+                    }
+                }
+            UserWebVerificationStatus::Unverified => {
+                // domain has removed the verification, drop
+                // the record on our side.
+                info!("verification rescinded for {}", v.to_string());
+                self.repo.delete_verification(v.rowid)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
     /// Persist an event, create a verification record, and broadcast.
     // TODO: have more event-writing logic handled in the db module.
     // Right now, these events avoid the rate limit.  That is
@@ -401,9 +514,11 @@ impl Verifier {
     // calls to get them off the async executors.
     async fn create_new_verified_user(&mut self, name: &str, event: &Event) -> Result<()> {
         let start = Instant::now();
-        // we should only do this if we are enabled.  if we are
+        // we should only do this if we are enabled, or verification
+        // admits authors that were not otherwise allowed.  if we are
         // disabled/passive, the event has already been persisted.
-        let should_write_event = self.settings.verified_users.is_enabled();
+        let should_write_event = self.settings.verified_users.is_enabled()
+            || self.settings.authorization.nip05_domains.is_some();
         if should_write_event {
             match self.repo.write_event(event).await {
                 Ok(updated) => {
@@ -413,7 +528,7 @@ impl Verifier {
                             event.get_event_id_prefix(),
                             start.elapsed()
                         );
-                        self.event_tx.send(event.clone()).ok();
+                        self.event_tx.send(event.clone().into()).ok();
                     }
                 }
                 Err(err) => {
@@ -430,6 +545,114 @@ impl Verifier {
     }
 }
 
+/// Perform web verification against a NIP-05 name and address.
+async fn get_web_verification(
+    client: &HttpsClient,
+    settings: &Settings,
+    nip: &Nip05Name,
+    pubkey: &str,
+) -> UserWebVerificationStatus {
+    get_web_verification_res(client, settings, nip, pubkey)
+        .await
+        .unwrap_or(UserWebVerificationStatus::Unknown)
+}
+
+/// Perform web verification, with a `Result` return.
+async fn get_web_verification_res(
+    client: &HttpsClient,
+    settings: &Settings,
+    nip: &Nip05Name,
+    pubkey: &str,
+) -> Result<UserWebVerificationStatus> {
+    // determine if this domain should be checked
+    let verified_users = &settings.verified_users;
+    let domain_allowed = verified_users.is_active()
+        && is_domain_allowed(
+            &nip.domain,
+            &verified_users.domain_whitelist,
+            &verified_users.domain_blacklist,
+        );
+    if !domain_allowed && !settings.authorization.allows_nip05_domain(&nip.domain) {
+        return Ok(UserWebVerificationStatus::DomainNotAllowed);
+    }
+    // onion services are only reachable through a Tor proxy.
+    if nip.is_onion() && verified_users.proxy.is_none() {
+        debug!("no proxy configured to verify onion address {:?}", nip.to_string());
+        return Ok(UserWebVerificationStatus::DomainNotAllowed);
+    }
+    let mut url = nip
+        .to_url()
+        .ok_or_else(|| Error::CustomError("invalid NIP-05 URL".to_owned()))?;
+    for _ in 0..=MAX_REDIRECTS {
+        let req = hyper::Request::builder()
+            .method(hyper::Method::GET)
+            .uri(url.clone())
+            .header("Accept", "application/json")
+            .header(
+                "User-Agent",
+                format!(
+                    "nostr-rs-relay/{} NIP-05 Verifier",
+                    crate::info::CARGO_PKG_VERSION.unwrap()
+                ),
+            )
+            .body(hyper::Body::empty())
+            .expect("request builder");
+
+        let response_fut = client.request(req);
+        let Ok(response_res) = tokio::time::timeout(Duration::from_secs(5), response_fut).await
+        else {
+            info!("timeout verifying account {:?}", nip);
+            return Ok(UserWebVerificationStatus::Unknown);
+        };
+        let (parts, body) = response_res?.into_parts();
+        if parts.status.is_redirection() {
+            let location = parts.headers.get(http::header::LOCATION);
+            match location.and_then(|l| redirect_target(&url, l.to_str().ok()?)) {
+                Some(target) => {
+                    url = target;
+                    continue;
+                }
+                None => {
+                    info!("invalid redirect verifying account {:?}", nip.to_string());
+                    return Ok(UserWebVerificationStatus::Unknown);
+                }
+            }
+        }
+        if parts.status != http::StatusCode::OK {
+            return Ok(UserWebVerificationStatus::Unknown);
+        }
+        // parse body, determine if the username / key / address is present
+        let Ok(body_bytes) = read_limited(body, MAX_DOCUMENT_SIZE).await else {
+            info!("document too large or unreadable for account: {:?}", nip.to_string());
+            return Ok(UserWebVerificationStatus::Unknown);
+        };
+        if body_contains_user(&nip.local, pubkey, &hyper::body::Bytes::from(body_bytes))? {
+            return Ok(UserWebVerificationStatus::Verified);
+        }
+        // successful response, parsed as a nip-05 document, but this
+        // name/pubkey was not present.
+        return Ok(UserWebVerificationStatus::Unverified);
+    }
+    info!("too many redirects verifying account {:?}", nip.to_string());
+    Ok(UserWebVerificationStatus::Unknown)
+}
+
+/// Where a redirect leads, if it may be followed.  Redirects must use
+/// HTTPS, so that a document fetched securely is not then fetched in
+/// the clear; onion services may redirect within themselves.
+fn redirect_target(from: &http::Uri, location: &str) -> Option<http::Uri> {
+    let target = if location.starts_with('/') && !location.starts_with("//") {
+        format!("{}://{}{location}", from.scheme_str()?, from.authority()?)
+    } else {
+        location.to_owned()
+    };
+    let target = target.parse::<http::Uri>().ok()?;
+    let same_onion = from.scheme_str() == Some("http")
+        && target.scheme_str() == Some("http")
+        && target.host() == from.host();
+    (target.scheme_str() == Some("https") || same_onion).then_some(target)
+}
+
 /// Result of checking user's verification status against DNS/HTTP.
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum UserWebVerificationStatus {
@@ -492,6 +715,17 @@ impl VerificationRecord {
         )
     }
 
+    /// Check if the record is recent enough to be considered valid,
+    /// and the domain admits authors to publish.
+    #[must_use] pub fn admits_author(&self, settings: &crate::config::Settings) -> bool {
+        if let Some(e) = &settings.verified_users.verify_expiration_duration {
+            if !self.is_current(e) {
+                return false;
+            }
+        }
+        settings.authorization.allows_nip05_domain(&self.name.domain)
+    }
+
     /// Check if this record has been validated since the given
     /// duration.
     fn is_current(&self, d: &Duration) -> bool {
@@ -588,5 +822,87 @@ mod tests {
                     .unwrap()
             )
         );
+        let onion = Nip05Name::try_from("bob@abcdefgh.onion").unwrap();
+        assert_eq!(
+            onion.to_url(),
+            Some("http://abcdefgh.onion/.well-known/nostr.json?name=bob".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn redirects_stay_on_https() {
+        let from: http::Uri = "https://example.com/.well-known/nostr.json?name=bob".parse().unwrap();
+        assert_eq!(
+            redirect_target(&from, "/nostr.json?name=bob"),
+            Some("https://example.com/nostr.json?name=bob".parse().unwrap())
+        );
+        assert!(redirect_target(&from, "https://other.example/nostr.json").is_some());
+        assert_eq!(redirect_target(&from, "http://example.com/nostr.json"), None);
+        assert_eq!(redirect_target(&from, "//example.com/nostr.json"), None);
+        let onion: http::Uri = "http://abcdefgh.onion/.well-known/nostr.json".parse().unwrap();
+        assert!(redirect_target(&onion, "/nostr.json").is_some());
+        assert_eq!(redirect_target(&onion, "http://example.com/nostr.json"), None);
+    }
+
+    #[test]
+    fn retries_back_off() {
+        assert_eq!(retry_backoff(1), RETRY_BASE_SECS);
+        assert_eq!(retry_backoff(3), RETRY_BASE_SECS * 4);
+        assert_eq!(retry_backoff(100), RETRY_MAX_SECS);
+    }
+
+    #[tokio::test]
+    async fn queries_are_limited_per_domain() {
+        let (_, metrics) = crate::server::create_metrics();
+        let mut settings = Settings::default();
+        settings.verified_users.domain_requests_per_min = 1;
+        let repo = Arc::new(crate::repo::memory::MemoryRepo::new(&settings, metrics));
+        let (_, metadata_rx) = tokio::sync::broadcast::channel(1);
+        let (event_tx, _) = tokio::sync::broadcast::channel(1);
+        let mut verifier = Verifier::new(repo.clone(), metadata_rx, event_tx, settings).unwrap();
+        assert!(verifier.domain_ready("example.com"));
+        assert!(!verifier.domain_ready("example.com"));
+        assert!(verifier.domain_ready("example.org"));
+        // queued metadata is only replaced by newer metadata
+        let mut event = Event::simple_event();
+        event.pubkey = "a".repeat(64);
+        event.id = "1".repeat(64);
+        event.created_at = 100;
+        let queued = |event: &Event| QueuedVerification {
+            event: event.clone(),
+            attempts: 0,
+            retry_at: 0,
+        };
+        repo.put_queued_verification(&queued(&event)).await.unwrap();
+        let mut older = event.clone();
+        older.id = "2".repeat(64);
+        older.created_at = 50;
+        repo.put_queued_verification(&queued(&older)).await.unwrap();
+        repo.remove_queued_verification(&older.pubkey, &older.id).await.unwrap();
+        let due = repo.get_queued_verifications(unix_time(), 10).await.unwrap();
+        assert_eq!(due, vec![queued(&event)]);
+    }
+
+    #[test]
+    fn domains_admit_authors() {
+        let mut settings = crate::config::Settings::default();
+        settings.verified_users.verify_expiration_duration = Some(Duration::from_secs(3600));
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        let mut record = VerificationRecord {
+            rowid: 1,
+            name: Nip05Name::try_from("bob@example.com").unwrap(),
+            address: "a".repeat(64),
+            event: "e".repeat(64),
+            event_created: now,
+            last_success: Some(now),
+            last_failure: None,
+            failure_count: 0,
+        };
+        assert!(!record.admits_author(&settings));
+        settings.authorization.nip05_domains = Some(vec!["example.com".to_owned()]);
+        assert!(record.admits_author(&settings));
+        // expired verifications do not
+        record.last_success = Some(now - 7200);
+        assert!(!record.admits_author(&settings));
     }
 }
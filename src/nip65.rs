@@ -0,0 +1,110 @@
+//! Relay list metadata (NIP-65)
+//!
+//! NIP-65 defines a replaceable event (kind 10002) where authors list
+//! the relays they read from and write to.  These are served over
+//! HTTP, and can be used as hints for locating an author's events on
+//! other relays.
+use crate::event::Event;
+use serde::{Deserialize, Serialize};
+
+/// Relay list metadata event kind
+pub const KIND_RELAY_LIST: u64 = 10002;
+
+/// A relay advertised in a relay list.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct RelayListEntry {
+    pub url: String,
+    pub read: bool,
+    pub write: bool,
+}
+
+/// A relay list, as returned by the HTTP endpoint.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct RelayList {
+    pub pubkey: String,
+    pub created_at: u64,
+    pub relays: Vec<RelayListEntry>,
+}
+
+impl RelayList {
+    /// Relays the author reads from (where they expect mentions).
+    pub fn read_relays(&self) -> impl Iterator<Item = &str> {
+        self.relays.iter().filter(|r| r.read).map(|r| r.url.as_str())
+    }
+
+    /// Relays the author writes to (where their events can be found).
+    pub fn write_relays(&self) -> impl Iterator<Item = &str> {
+        self.relays.iter().filter(|r| r.write).map(|r| r.url.as_str())
+    }
+}
+
+impl TryFrom<&Event> for RelayList {
+    type Error = ();
+
+    /// Extract the `r` tags of a relay list event.  A relay with no
+    /// marker is used for both reading and writing.
+    fn try_from(event: &Event) -> Result<Self, Self::Error> {
+        if event.kind != KIND_RELAY_LIST {
+            return Err(());
+        }
+        let relays = event
+            .tags
+            .iter()
+            .filter(|t| t.len() >= 2 && t[0] == "r")
+            .map(|t| {
+                let marker = t.get(2).map(String::as_str);
+                RelayListEntry {
+                    url: t[1].clone(),
+                    read: marker != Some("write"),
+                    write: marker != Some("read"),
+                }
+            })
+            .collect();
+        Ok(RelayList {
+            pubkey: event.pubkey.clone(),
+            created_at: event.created_at,
+            relays,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn r_tag(url: &str, marker: Option<&str>) -> Vec<String> {
+        let mut t = vec!["r".to_owned(), url.to_owned()];
+        if let Some(m) = marker {
+            t.push(m.to_owned());
+        }
+        t
+    }
+
+    #[test]
+    fn parse_relay_list() {
+        let mut e = Event::simple_event();
+        e.kind = KIND_RELAY_LIST;
+        e.tags = vec![
+            r_tag("wss://both.example.com", None),
+            r_tag("wss://read.example.com", Some("read")),
+            r_tag("wss://write.example.com", Some("write")),
+            vec!["p".to_owned(), "abc".to_owned()],
+        ];
+        let rl = RelayList::try_from(&e).unwrap();
+        assert_eq!(rl.relays.len(), 3);
+        assert_eq!(
+            rl.read_relays().collect::<Vec<_>>(),
+            vec!["wss://both.example.com", "wss://read.example.com"]
+        );
+        assert_eq!(
+            rl.write_relays().collect::<Vec<_>>(),
+            vec!["wss://both.example.com", "wss://write.example.com"]
+        );
+    }
+
+    #[test]
+    fn wrong_kind() {
+        let e = Event::simple_event();
+        assert!(RelayList::try_from(&e).is_err());
+    }
+}
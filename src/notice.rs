@@ -5,6 +5,10 @@ pub enum EventResultStatus {
     Blocked,
     RateLimited,
     Error,
+    Restricted,
+    AuthRequired,
+    Pow,
+    PaymentRequired,
 }
 
 pub struct EventResult {
@@ -16,13 +20,16 @@ pub struct EventResult {
 pub enum Notice {
     Message(String),
     EventResult(EventResult),
+    AuthChallenge(String),
+    /// A subscription was refused or ended by the relay (`CLOSED`)
+    Closed(EventResult),
 }
 
 impl EventResultStatus {
     #[must_use] pub fn to_bool(&self) -> bool {
         match self {
             Self::Duplicate | Self::Saved => true,
-            Self::Invalid |Self::Blocked | Self::RateLimited | Self::Error => false,
+            Self::Invalid |Self::Blocked | Self::RateLimited | Self::Error | Self::Restricted | Self::AuthRequired | Self::Pow | Self::PaymentRequired => false,
         }
     }
 
@@ -34,6 +41,10 @@ impl EventResultStatus {
             Self::Blocked => "blocked",
             Self::RateLimited => "rate-limited",
             Self::Error => "error",
+            Self::Restricted => "restricted",
+            Self::AuthRequired => "auth-required",
+            Self::Pow => "pow",
+            Self::PaymentRequired => "payment-required",
         }
     }
 }
@@ -64,14 +75,36 @@ impl Notice {
         Notice::prefixed(id, msg, EventResultStatus::RateLimited)
     }
 
+    #[must_use] pub fn pow(id: String, msg: &str) -> Notice {
+        Notice::prefixed(id, msg, EventResultStatus::Pow)
+    }
+
+    #[must_use] pub fn restricted(id: String, msg: &str) -> Notice {
+        Notice::prefixed(id, msg, EventResultStatus::Restricted)
+    }
+
+    #[must_use] pub fn auth_required(id: String, msg: &str) -> Notice {
+        Notice::prefixed(id, msg, EventResultStatus::AuthRequired)
+    }
+
+    #[must_use] pub fn payment_required(id: String, msg: &str) -> Notice {
+        Notice::prefixed(id, msg, EventResultStatus::PaymentRequired)
+    }
+
     #[must_use] pub fn duplicate(id: String) -> Notice {
-        Notice::prefixed(id, "", EventResultStatus::Duplicate)
+        Notice::prefixed(id, "already have this event", EventResultStatus::Duplicate)
     }
 
     #[must_use] pub fn error(id: String, msg: &str) -> Notice {
         Notice::prefixed(id, msg, EventResultStatus::Error)
     }
 
+    /// Close a subscription, with a reason prefixed by its status.
+    #[must_use] pub fn closed(sub_id: String, msg: &str, status: EventResultStatus) -> Notice {
+        let msg = format!("{}: {}", status.prefix(), msg);
+        Notice::Closed(EventResult { id: sub_id, msg, status })
+    }
+
     #[must_use] pub fn saved(id: String) -> Notice {
         Notice::EventResult(EventResult {
             id,
@@ -0,0 +1,150 @@
+//! Outbound messages for websocket clients
+//!
+//! Messages for a client are queued, and written to its websocket by
+//! a separate task, so a slow client does not stall the handling of
+//! its own requests.  Query results and notices wait for room in the
+//! queue.  Realtime events do not wait; when a client has fallen
+//! behind, its oldest undelivered realtime events are dropped.
+use crate::throttle::Throttle;
+use futures::{Sink, SinkExt};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+use tungstenite::protocol::Message;
+
+/// Queue of messages for one client.
+pub struct Outbox {
+    responses: mpsc::Sender<Message>,
+    realtime: Arc<RealtimeQueue>,
+}
+
+impl Outbox {
+    /// Start a task writing queued messages to a websocket, no faster
+    /// than the throttle allows.  Each queue holds up to `capacity`
+    /// messages.  The websocket is closed when the outbox is dropped,
+    /// after sending any queued responses.
+    pub fn start<S>(sink: S, capacity: usize, throttle: Arc<Throttle>) -> (Outbox, JoinHandle<()>)
+    where
+        S: Sink<Message> + Unpin + Send + 'static,
+    {
+        let capacity = capacity.max(1);
+        let (responses, responses_rx) = mpsc::channel(capacity);
+        let realtime = Arc::new(RealtimeQueue::new(capacity));
+        let handle = tokio::spawn(write(sink, responses_rx, realtime.clone(), throttle));
+        (Outbox { responses, realtime }, handle)
+    }
+
+    /// Queue a response, waiting for room if the client is behind.
+    pub async fn send(&self, msg: Message) {
+        // the writer only stops once the websocket fails.
+        self.responses.send(msg).await.ok();
+    }
+
+    /// Queue a realtime event without waiting.  Returns true if an
+    /// older realtime event was dropped to make room.
+    pub fn send_realtime(&self, msg: Message) -> bool {
+        self.realtime.push(msg)
+    }
+}
+
+/// Realtime events, dropped oldest first when full.
+struct RealtimeQueue {
+    queue: Mutex<VecDeque<Message>>,
+    capacity: usize,
+    ready: Notify,
+}
+
+impl RealtimeQueue {
+    fn new(capacity: usize) -> Self {
+        RealtimeQueue {
+            queue: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            ready: Notify::new(),
+        }
+    }
+
+    fn push(&self, msg: Message) -> bool {
+        let dropped = {
+            let mut queue = self.queue.lock().unwrap();
+            let dropped = queue.len() >= self.capacity && queue.pop_front().is_some();
+            queue.push_back(msg);
+            dropped
+        };
+        self.ready.notify_one();
+        dropped
+    }
+
+    async fn pop(&self) -> Message {
+        loop {
+            let next = self.queue.lock().unwrap().pop_front();
+            if let Some(msg) = next {
+                return msg;
+            }
+            self.ready.notified().await;
+        }
+    }
+}
+
+/// Write queued messages until the outbox is dropped, the websocket
+/// fails, or a close message is sent.
+async fn write<S>(
+    mut sink: S,
+    mut responses: mpsc::Receiver<Message>,
+    realtime: Arc<RealtimeQueue>,
+    throttle: Arc<Throttle>,
+)
+where
+    S: Sink<Message> + Unpin,
+{
+    loop {
+        let msg = tokio::select! {
+            msg = responses.recv() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+            msg = realtime.pop() => msg,
+        };
+        let close = matches!(msg, Message::Close(_));
+        throttle.consume(msg.len()).await;
+        if sink.send(msg).await.is_err() || close {
+            break;
+        }
+    }
+    sink.close().await.ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    fn text(s: &str) -> Message {
+        Message::Text(s.to_owned())
+    }
+
+    #[test]
+    fn oldest_realtime_events_are_dropped() {
+        let q = RealtimeQueue::new(2);
+        assert!(!q.push(text("a")));
+        assert!(!q.push(text("b")));
+        assert!(q.push(text("c")));
+        let queued: Vec<Message> = q.queue.lock().unwrap().iter().cloned().collect();
+        assert_eq!(queued, vec![text("b"), text("c")]);
+    }
+
+    #[tokio::test]
+    async fn responses_are_written_before_close() {
+        let (sink, stream) = futures::channel::mpsc::unbounded::<Message>();
+        let (outbox, handle) = Outbox::start(sink, 4, Arc::new(Throttle::new(None)));
+        outbox.send(text("a")).await;
+        outbox.send(text("b")).await;
+        assert!(!outbox.send_realtime(text("c")));
+        drop(outbox);
+        handle.await.unwrap();
+        let mut written: Vec<Message> = stream.collect().await;
+        // realtime events may be interleaved with responses.
+        written.sort_by_key(|m| m.to_string());
+        assert!(written.starts_with(&[text("a"), text("b")]));
+    }
+}
@@ -0,0 +1,616 @@
+//! Paid admission, with Lightning invoices
+//!
+//! A pubkey may publish once it has paid an admission invoice.  Users
+//! pay at `/join`, with an invoice shown on the page or by LNURL-pay
+//! (LUD-06).  Invoices are issued by an LNbits instance or an LND
+//! node, stored in the database, and checked until they are paid or
+//! expire.  Paying an invoice admits its pubkey.
+//!
+//! Events may also have a cost, debited from their author's balance
+//! as they are stored.  Admitted pubkeys top up their balance with
+//! further invoices.
+use crate::config::{self, Processor, Settings};
+use crate::error::{Error, Result};
+use crate::media::relay_http_url;
+use crate::repo::NostrRepo;
+use crate::utils::{is_lower_hex, is_nip19, nip19_to_hex, unix_time};
+use async_trait::async_trait;
+use bech32::{ToBase32, Variant};
+use bitcoin_hashes::{sha256, Hash};
+use hyper::client::connect::HttpConnector;
+use hyper::{Body, Client, Method, Request};
+use hyper_tls::HttpsConnector;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// Seconds an invoice may be paid in
+const INVOICE_SECONDS: u64 = 3600;
+
+/// Largest balance top-up, in msats
+const MAX_TOPUP_MSAT: u64 = 100_000_000;
+
+/// Longest wait for a response from the lightning backend
+const NODE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A pubkey that has asked to use the relay
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct Account {
+    pub pubkey: String,
+    /// Has the admission fee been paid?
+    pub admitted: bool,
+    /// Credit for publishing events
+    #[serde(default)]
+    pub balance_msat: u64,
+    pub created_at: u64,
+}
+
+/// A stored invoice
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct Invoice {
+    /// Hex payment hash, which identifies the invoice
+    pub payment_hash: String,
+    /// Pubkey the invoice pays for
+    pub pubkey: String,
+    /// Encoded (BOLT-11) payment request
+    pub bolt11: String,
+    pub amount_msat: u64,
+    /// Credits the pubkey's balance, instead of paying for admission
+    #[serde(default)]
+    pub topup: bool,
+    pub paid: bool,
+    pub created_at: u64,
+    pub expires_at: u64,
+}
+
+/// An invoice, as issued by a lightning backend
+pub struct IssuedInvoice {
+    pub payment_hash: String,
+    pub bolt11: String,
+}
+
+/// A lightning backend that issues invoices
+#[async_trait]
+pub trait PaymentProcessor: Send + Sync {
+    /// Issue an invoice.  A description hash, if given, commits the
+    /// invoice to LNURL metadata instead of the memo.
+    async fn create_invoice(&self, amount_msat: u64, memo: &str, description_hash: Option<&[u8]>) -> Result<IssuedInvoice>;
+
+    /// Check if an invoice has been paid
+    async fn is_paid(&self, payment_hash: &str) -> Result<bool>;
+}
+
+/// JSON API of a lightning backend, authorized by a header.
+struct NodeClient {
+    client: Client<HttpsConnector<HttpConnector>, Body>,
+    base: String,
+    auth_header: &'static str,
+    secret: String,
+}
+
+impl NodeClient {
+    fn new(settings: &config::PayToRelay, auth_header: &'static str) -> Self {
+        NodeClient {
+            client: Client::builder().build::<_, Body>(HttpsConnector::new()),
+            base: settings.node_url.trim_end_matches('/').to_owned(),
+            auth_header,
+            secret: settings.api_secret.clone(),
+        }
+    }
+
+    /// Send a request, and return the response body.  Any status
+    /// other than success is an error.
+    async fn send(&self, method: Method, path: &str, body: Option<Value>) -> Result<Value> {
+        let req = Request::builder()
+            .method(method.clone())
+            .uri(format!("{}{}", self.base, path))
+            .header(self.auth_header, &self.secret)
+            .header("Content-Type", "application/json")
+            .body(body.map_or_else(Body::empty, |b| Body::from(b.to_string())))
+            .map_err(|e| Error::CustomError(format!("could not build payment request: {e}")))?;
+        let res = tokio::time::timeout(NODE_TIMEOUT, self.client.request(req))
+            .await
+            .map_err(|_| Error::CustomError("lightning backend timed out".to_owned()))??;
+        let status = res.status();
+        let data = hyper::body::to_bytes(res.into_body()).await?;
+        if !status.is_success() {
+            return Err(Error::CustomError(format!("{method} {path} failed with status {status}")));
+        }
+        Ok(serde_json::from_slice(&data)?)
+    }
+}
+
+fn missing(field: &str) -> Error {
+    Error::CustomError(format!("lightning backend response has no {field}"))
+}
+
+/// Invoices issued by an LNbits wallet
+struct LNbits(NodeClient);
+
+#[async_trait]
+impl PaymentProcessor for LNbits {
+    async fn create_invoice(&self, amount_msat: u64, memo: &str, description_hash: Option<&[u8]>) -> Result<IssuedInvoice> {
+        let mut body = json!({
+            "out": false,
+            // LNbits invoices are in whole sats.
+            "amount": amount_msat.div_ceil(1000),
+            "memo": memo,
+            "expiry": INVOICE_SECONDS,
+        });
+        if let Some(h) = description_hash {
+            body["description_hash"] = json!(hex::encode(h));
+        }
+        let res = self.0.send(Method::POST, "/api/v1/payments", Some(body)).await?;
+        let bolt11 = res["payment_request"].as_str().or_else(|| res["bolt11"].as_str());
+        Ok(IssuedInvoice {
+            payment_hash: res["payment_hash"].as_str().ok_or_else(|| missing("payment_hash"))?.to_owned(),
+            bolt11: bolt11.ok_or_else(|| missing("payment_request"))?.to_owned(),
+        })
+    }
+
+    async fn is_paid(&self, payment_hash: &str) -> Result<bool> {
+        let res = self.0.send(Method::GET, &format!("/api/v1/payments/{payment_hash}"), None).await?;
+        Ok(res["paid"].as_bool().unwrap_or(false))
+    }
+}
+
+/// Invoices issued by an LND node, over its REST API
+struct Lnd(NodeClient);
+
+#[async_trait]
+impl PaymentProcessor for Lnd {
+    async fn create_invoice(&self, amount_msat: u64, memo: &str, description_hash: Option<&[u8]>) -> Result<IssuedInvoice> {
+        let mut body = json!({
+            "value_msat": amount_msat.to_string(),
+            "memo": memo,
+            "expiry": INVOICE_SECONDS.to_string(),
+        });
+        if let Some(h) = description_hash {
+            body["description_hash"] = json!(base64::encode(h));
+        }
+        let res = self.0.send(Method::POST, "/v1/invoices", Some(body)).await?;
+        let r_hash = res["r_hash"].as_str().ok_or_else(|| missing("r_hash"))?;
+        let r_hash = base64::decode(r_hash).map_err(|_| missing("valid r_hash"))?;
+        Ok(IssuedInvoice {
+            payment_hash: hex::encode(r_hash),
+            bolt11: res["payment_request"].as_str().ok_or_else(|| missing("payment_request"))?.to_owned(),
+        })
+    }
+
+    async fn is_paid(&self, payment_hash: &str) -> Result<bool> {
+        let res = self.0.send(Method::GET, &format!("/v1/invoice/{payment_hash}"), None).await?;
+        Ok(res["state"].as_str() == Some("SETTLED"))
+    }
+}
+
+/// URL of the join page, if the relay URL is configured.
+#[must_use]
+pub fn join_url(settings: &Settings) -> Option<String> {
+    relay_http_url(settings).map(|base| format!("{base}/join"))
+}
+
+/// Encode a URL as an LNURL (LUD-01).
+#[must_use]
+pub fn lnurl(url: &str) -> String {
+    bech32::encode("lnurl", url.as_bytes().to_base32(), Variant::Bech32)
+        .unwrap_or_default()
+        .to_uppercase()
+}
+
+/// Parse a hex or npub pubkey, as entered by a user.
+#[must_use]
+pub fn parse_pubkey(value: &str) -> Option<String> {
+    let value = value.trim();
+    if value.len() == 64 && is_lower_hex(value) {
+        Some(value.to_owned())
+    } else if is_nip19(value) {
+        nip19_to_hex(value).ok().filter(|pk| pk.len() == 64)
+    } else {
+        None
+    }
+}
+
+/// Value of a parameter in a URL query string.
+#[must_use]
+pub fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|kv| kv.split_once('='))
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v)
+}
+
+/// Escape text for an HTML page.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Paid admission and balances: accounts, invoices, and the join
+/// page.
+pub struct Payments {
+    settings: config::PayToRelay,
+    relay_name: String,
+    base_url: String,
+    repo: Arc<dyn NostrRepo>,
+    processor: Box<dyn PaymentProcessor>,
+    /// Pubkeys known to be admitted
+    admitted: Mutex<HashSet<String>>,
+}
+
+impl Payments {
+    /// Create the payment subsystem, if paid admission is enabled.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the relay URL is not configured.
+    pub fn new(settings: &Settings, repo: Arc<dyn NostrRepo>) -> Result<Option<Payments>> {
+        if !settings.pay_to_relay.enabled {
+            return Ok(None);
+        }
+        let base_url = relay_http_url(settings)
+            .ok_or_else(|| Error::CustomError("paid admission requires info.relay_url".to_owned()))?;
+        let p = &settings.pay_to_relay;
+        let processor: Box<dyn PaymentProcessor> = match p.processor {
+            Processor::LNbits => Box::new(LNbits(NodeClient::new(p, "X-Api-Key"))),
+            Processor::Lnd => Box::new(Lnd(NodeClient::new(p, "Grpc-Metadata-macaroon"))),
+        };
+        Ok(Some(Payments {
+            settings: p.clone(),
+            relay_name: settings.info.name.clone().unwrap_or_else(|| base_url.clone()),
+            base_url,
+            repo,
+            processor,
+            admitted: Mutex::new(HashSet::new()),
+        }))
+    }
+
+    /// Admission cost, in msats
+    #[must_use]
+    pub fn admission_msat(&self) -> u64 {
+        self.settings.admission_cost * 1000
+    }
+
+    /// URL of the join page
+    #[must_use]
+    pub fn join_url(&self) -> String {
+        format!("{}/join", self.base_url)
+    }
+
+    /// Check if a pubkey has paid for admission.  Every pubkey is
+    /// admitted when admission is free.
+    pub async fn is_admitted(&self, pubkey: &str) -> bool {
+        if self.settings.admission_cost == 0 || self.admitted.lock().unwrap().contains(pubkey) {
+            return true;
+        }
+        match self.repo.get_account(pubkey).await {
+            Ok(Some(a)) if a.admitted => {
+                self.admitted.lock().unwrap().insert(pubkey.to_owned());
+                true
+            }
+            Ok(_) => false,
+            Err(e) => {
+                warn!("could not load account: {:?}", e);
+                false
+            }
+        }
+    }
+
+    /// Balance of a pubkey, in msats
+    pub async fn balance(&self, pubkey: &str) -> u64 {
+        match self.repo.get_account(pubkey).await {
+            Ok(a) => a.map_or(0, |a| a.balance_msat),
+            Err(e) => {
+                warn!("could not load account: {:?}", e);
+                0
+            }
+        }
+    }
+
+    /// Check if a pubkey can pay for another event.
+    pub async fn has_balance(&self, pubkey: &str) -> bool {
+        self.settings.cost_per_event == 0 || self.balance(pubkey).await >= self.settings.cost_per_event
+    }
+
+    /// Charge a pubkey for an event about to be stored.  Returns
+    /// false (and charges nothing) if its balance is too low.
+    pub async fn charge(&self, pubkey: &str) -> bool {
+        if self.settings.cost_per_event == 0 {
+            return true;
+        }
+        match self.repo.debit_account(pubkey, self.settings.cost_per_event).await {
+            Ok(charged) => charged,
+            Err(e) => {
+                warn!("could not debit account: {:?}", e);
+                false
+            }
+        }
+    }
+
+    /// Refund the charge for an event that was not stored.
+    pub async fn refund(&self, pubkey: &str) {
+        if self.settings.cost_per_event == 0 {
+            return;
+        }
+        if let Err(e) = self.repo.credit_account(pubkey, self.settings.cost_per_event).await {
+            warn!("could not refund account: {:?}", e);
+        }
+    }
+
+    async fn issue(&self, pubkey: &str, amount_msat: u64, topup: bool, description_hash: Option<&[u8]>) -> Result<Invoice> {
+        let memo = self.memo(topup);
+        let issued = self
+            .processor
+            .create_invoice(amount_msat, &memo, description_hash)
+            .await?;
+        let now = unix_time();
+        let invoice = Invoice {
+            payment_hash: issued.payment_hash,
+            pubkey: pubkey.to_owned(),
+            bolt11: issued.bolt11,
+            amount_msat,
+            topup,
+            paid: false,
+            created_at: now,
+            expires_at: now + INVOICE_SECONDS,
+        };
+        self.repo.add_invoice(&invoice).await?;
+        info!("issued invoice {:?} for {:?}", invoice.payment_hash, pubkey);
+        Ok(invoice)
+    }
+
+    fn memo(&self, topup: bool) -> String {
+        if topup {
+            format!("Balance top-up on {}", self.relay_name)
+        } else {
+            format!("Admission to {}", self.relay_name)
+        }
+    }
+
+    /// An admission invoice for a pubkey: its latest unpaid invoice,
+    /// or a new one.  Returns `None` if the latest invoice turns out
+    /// to be paid.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if an invoice could not be issued or stored.
+    pub async fn admission_invoice(&self, pubkey: &str) -> Result<Option<Invoice>> {
+        // leave a few minutes to pay an invoice that is shown again.
+        let usable_after = unix_time() + 300;
+        let pending = self
+            .repo
+            .get_unpaid_invoices()
+            .await?
+            .into_iter()
+            .filter(|i| i.pubkey == pubkey && !i.topup && i.expires_at > usable_after)
+            .max_by_key(|i| i.created_at);
+        if let Some(invoice) = pending {
+            if self.check_invoice(&invoice).await? {
+                return Ok(None);
+            }
+            return Ok(Some(invoice));
+        }
+        self.issue(pubkey, self.admission_msat(), false, None).await.map(Some)
+    }
+
+    /// An invoice crediting a pubkey's balance.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if an invoice could not be issued.
+    pub async fn topup_invoice(&self, pubkey: &str, amount_msat: u64) -> Result<Invoice> {
+        self.issue(pubkey, amount_msat, true, None).await
+    }
+
+    /// Why an amount cannot be paid by a pubkey: admission must be
+    /// paid in full, and top-ups must be in range.
+    pub async fn amount_error(&self, pubkey: &str, amount_msat: u64) -> Option<String> {
+        if !self.is_admitted(pubkey).await {
+            return (amount_msat != self.admission_msat())
+                .then(|| format!("amount must be {} sats", self.settings.admission_cost));
+        }
+        (!(1000..=MAX_TOPUP_MSAT).contains(&amount_msat))
+            .then(|| format!("amount must be between 1 and {} sats", MAX_TOPUP_MSAT / 1000))
+    }
+
+    /// LNURL for paying a pubkey's admission, or topping up its
+    /// balance once admitted.
+    #[must_use]
+    pub fn lnurl(&self, pubkey: &str) -> String {
+        lnurl(&format!("{}/lnurlp/{}", self.base_url, pubkey))
+    }
+
+    fn lnurl_metadata(&self, topup: bool) -> String {
+        json!([["text/plain", self.memo(topup)]]).to_string()
+    }
+
+    /// LNURL-pay request (LUD-06) for a pubkey's admission, or a
+    /// top-up if it has been admitted
+    pub async fn lnurl_pay_request(&self, pubkey: &str) -> Value {
+        let topup = self.is_admitted(pubkey).await;
+        let (min, max) = if topup {
+            (1000, MAX_TOPUP_MSAT)
+        } else {
+            (self.admission_msat(), self.admission_msat())
+        };
+        json!({
+            "tag": "payRequest",
+            "callback": format!("{}/lnurlp/{}/callback", self.base_url, pubkey),
+            "minSendable": min,
+            "maxSendable": max,
+            "metadata": self.lnurl_metadata(topup),
+        })
+    }
+
+    /// An invoice for an LNURL-pay callback, committed to the
+    /// metadata of the pay request.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if an invoice could not be issued.
+    pub async fn lnurl_invoice(&self, pubkey: &str, amount_msat: u64) -> Result<Invoice> {
+        let topup = self.is_admitted(pubkey).await;
+        let hash = sha256::Hash::hash(self.lnurl_metadata(topup).as_bytes());
+        self.issue(pubkey, amount_msat, topup, Some(hash.as_ref())).await
+    }
+
+    /// Check if an invoice has been paid, and admit its pubkey (or
+    /// credit its balance) if so.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the backend or database could not be
+    /// reached.
+    pub async fn check_invoice(&self, invoice: &Invoice) -> Result<bool> {
+        if invoice.paid {
+            return Ok(true);
+        }
+        if !self.processor.is_paid(&invoice.payment_hash).await? {
+            return Ok(false);
+        }
+        if self.repo.invoice_paid(&invoice.payment_hash).await? {
+            if invoice.topup {
+                info!("credited {:?} with {} msats", invoice.pubkey, invoice.amount_msat);
+            } else {
+                info!("admitted {:?} (invoice {:?} paid)", invoice.pubkey, invoice.payment_hash);
+            }
+        }
+        self.admitted.lock().unwrap().insert(invoice.pubkey.clone());
+        Ok(true)
+    }
+
+    /// Check every unpaid invoice that has not expired.
+    pub async fn check_unpaid(&self) {
+        let now = unix_time();
+        let invoices = match self.repo.get_unpaid_invoices().await {
+            Ok(i) => i,
+            Err(e) => {
+                warn!("could not load unpaid invoices: {:?}", e);
+                return;
+            }
+        };
+        for invoice in invoices.iter().filter(|i| i.expires_at > now) {
+            if let Err(e) = self.check_invoice(invoice).await {
+                debug!("could not check invoice {:?}: {:?}", invoice.payment_hash, e);
+            }
+        }
+    }
+
+    /// An HTML page, with the relay's name as its title.
+    fn page(&self, content: &str) -> String {
+        let name = escape(&self.relay_name);
+        format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{name}</title></head>\n<body><h1>Join {name}</h1>\n{content}\n</body></html>\n"
+        )
+    }
+
+    /// The join page, asking for a pubkey, with an optional error.
+    #[must_use]
+    pub fn join_form(&self, error: Option<&str>) -> String {
+        let error = error.map(|e| format!("<p><strong>{}</strong></p>\n", escape(e))).unwrap_or_default();
+        let cost = if self.settings.cost_per_event > 0 {
+            format!("{} msats are charged for each event published.", self.settings.cost_per_event)
+        } else {
+            String::new()
+        };
+        self.page(&format!(
+            "<p>{}</p>\n<p>Admission costs {} sats.  {}</p>\n{}<form method=\"get\" action=\"/join\">\n<label>Pubkey (npub or hex): <input name=\"pubkey\" size=\"70\"></label>\n<button type=\"submit\">Continue</button>\n</form>",
+            escape(&self.settings.terms_message),
+            self.settings.admission_cost,
+            cost,
+            error
+        ))
+    }
+
+    /// The join page, for a pubkey that has been admitted.  Shows
+    /// its balance and a top-up form, if events have a cost.
+    pub async fn admitted_page(&self, pubkey: &str) -> String {
+        if self.settings.cost_per_event == 0 {
+            return self.page("<p>This pubkey has been admitted.  You may now publish to the relay.</p>");
+        }
+        let balance = self.balance(pubkey).await;
+        self.page(&format!(
+            "<p>This pubkey has been admitted.  Each event published costs {} msats.</p>\n<p>Balance: {} msats ({} events).</p>\n<form method=\"get\" action=\"/join\">\n<input type=\"hidden\" name=\"pubkey\" value=\"{}\">\n<label>Top up (sats): <input name=\"topup\" size=\"10\"></label>\n<button type=\"submit\">Get invoice</button>\n</form>\n<p>Or top up with LNURL:</p>\n<p>{}</p>",
+            self.settings.cost_per_event,
+            balance,
+            balance / self.settings.cost_per_event,
+            pubkey,
+            pay_link(&self.lnurl(pubkey))
+        ))
+    }
+
+    /// The join page, with an invoice to pay.
+    #[must_use]
+    pub fn invoice_page(&self, invoice: &Invoice) -> String {
+        if invoice.topup {
+            return self.page(&format!(
+                "<p>Pay {} sats to top up this pubkey's balance:</p>\n<p>{}</p>\n<p>Your balance is credited shortly after you pay.  <a href=\"/join?pubkey={}\">Check your balance</a>.</p>",
+                invoice.amount_msat / 1000,
+                pay_link(&invoice.bolt11),
+                invoice.pubkey
+            ));
+        }
+        self.page(&format!(
+            "<p>Pay {} sats to admit this pubkey:</p>\n<p>{}</p>\n<p>Or pay with LNURL:</p>\n<p>{}</p>\n<p>Reload this page once you have paid.</p>",
+            invoice.amount_msat / 1000,
+            pay_link(&invoice.bolt11),
+            pay_link(&self.lnurl(&invoice.pubkey))
+        ))
+    }
+}
+
+/// A link to pay with a lightning wallet.
+fn pay_link(uri: &str) -> String {
+    format!("<a href=\"lightning:{uri}\">lightning:{uri}</a>")
+}
+
+/// Check unpaid invoices periodically, until shutdown.
+pub async fn check_invoices(payments: Arc<Payments>, mut shutdown: tokio::sync::broadcast::Receiver<()>) {
+    let interval = payments
+        .settings
+        .check_interval_duration()
+        .unwrap_or_else(|| {
+            warn!("could not parse pay_to_relay check_interval, using 30 seconds");
+            Duration::from_secs(30)
+        });
+    loop {
+        payments.check_unpaid().await;
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {},
+            _ = shutdown.recv() => {
+                info!("shutting down invoice checker");
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lnurl_encoding() {
+        // example from LUD-01
+        assert_eq!(
+            lnurl("https://service.com/api?q=3fc3645b439ce8e7f2553a69e5267081d96dcd340693afabe04be7b0ccd178df"),
+            "LNURL1DP68GURN8GHJ7UM9WFMXJCM99E3K7MF0V9CXJ0M385EKVCENXC6R2C35XVUKXEFCV5MKVV34X5EKZD3EV56NYD3HXQURZEPEXEJXXEPNXSCRVWFNV9NXZCN9XQ6XYEFHVGCXXCMYXYMNSERXFQ5FNS"
+        );
+    }
+
+    #[test]
+    fn pubkeys_and_query_params() {
+        let hex = "35d26e4690cbe1a898af61cc3515661eb5fa763b57bd0b42e45099c8b32fd50f";
+        let npub = "npub1xhfxu35se0s63x90v8xr29txr66l5a3m277skshy2zvu3ve0658sla4xw3";
+        assert_eq!(parse_pubkey(hex).as_deref(), Some(hex));
+        assert_eq!(parse_pubkey(npub).as_deref(), Some(hex));
+        assert_eq!(parse_pubkey("example"), None);
+        assert_eq!(query_param("pubkey=abc&amount=1000", "amount"), Some("1000"));
+        assert_eq!(query_param("pubkey=abc", "amount"), None);
+    }
+}
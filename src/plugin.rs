@@ -0,0 +1,181 @@
+//! Event policy plugins
+//!
+//! A plugin is an external process that decides whether submitted
+//! events are accepted, using the same line-oriented JSON protocol
+//! as strfry write policy plugins.  Each event is written to the
+//! plugin's stdin as a request object, and the plugin replies on
+//! stdout with the event id and an action.  The plugin is restarted
+//! if it exits or stops responding.
+use crate::config;
+use crate::event::Event;
+use crate::utils::unix_time;
+use serde::{Deserialize, Serialize};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tracing::{info, warn};
+
+/// Time to wait for a plugin response, before restarting it.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Request sent to the plugin for each event
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct PluginRequest<'a> {
+    #[serde(rename = "type")]
+    req_type: &'static str,
+    event: &'a Event,
+    received_at: u64,
+    source_type: &'static str,
+    source_info: &'a str,
+}
+
+/// Action chosen by the plugin
+#[derive(Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum PluginAction {
+    Accept,
+    Reject,
+    /// Tell the client the event was accepted, but discard it
+    ShadowReject,
+}
+
+/// Response read from the plugin
+#[derive(Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct PluginResponse {
+    pub id: String,
+    pub action: PluginAction,
+    #[serde(default)]
+    pub msg: Option<String>,
+}
+
+/// Serialize the request for an event, as a single line.
+fn request_line(event: &Event, source_ip: &str) -> String {
+    let source_type = if source_ip.contains(':') { "IP6" } else { "IP4" };
+    let req = PluginRequest {
+        req_type: "new",
+        event,
+        received_at: unix_time(),
+        source_type,
+        source_info: source_ip,
+    };
+    let mut line = serde_json::to_string(&req).unwrap_or_default();
+    line.push('\n');
+    line
+}
+
+/// A running plugin process
+struct PluginProcess {
+    // kept so the process is killed when dropped
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: Lines<BufReader<ChildStdout>>,
+}
+
+/// Event policy plugin, started on first use.
+pub struct EventPlugin {
+    command: String,
+    args: Vec<String>,
+    process: Option<PluginProcess>,
+}
+
+impl EventPlugin {
+    /// Plugin for the configured command, if any.
+    #[must_use]
+    pub fn from_settings(settings: &config::Plugin) -> Option<EventPlugin> {
+        settings.command.as_ref().map(|command| EventPlugin {
+            command: command.clone(),
+            args: settings.args.clone().unwrap_or_default(),
+            process: None,
+        })
+    }
+
+    fn spawn(&self) -> std::io::Result<PluginProcess> {
+        info!("starting event plugin: {}", self.command);
+        let mut child = Command::new(&self.command)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdin = child.stdin.take().ok_or(std::io::ErrorKind::BrokenPipe)?;
+        let stdout = child.stdout.take().ok_or(std::io::ErrorKind::BrokenPipe)?;
+        Ok(PluginProcess {
+            _child: child,
+            stdin,
+            stdout: BufReader::new(stdout).lines(),
+        })
+    }
+
+    async fn request(&mut self, line: &str, id: &str) -> std::io::Result<PluginResponse> {
+        if self.process.is_none() {
+            self.process = Some(self.spawn()?);
+        }
+        let process = self.process.as_mut().ok_or(std::io::ErrorKind::NotConnected)?;
+        process.stdin.write_all(line.as_bytes()).await?;
+        process.stdin.flush().await?;
+        loop {
+            let next = tokio::time::timeout(RESPONSE_TIMEOUT, process.stdout.next_line())
+                .await
+                .map_err(|_| std::io::ErrorKind::TimedOut)??;
+            let resp_line = next.ok_or(std::io::ErrorKind::UnexpectedEof)?;
+            match serde_json::from_str::<PluginResponse>(&resp_line) {
+                Ok(resp) if resp.id == id => return Ok(resp),
+                Ok(resp) => warn!("ignoring plugin response for another event: {}", resp.id),
+                Err(e) => warn!("ignoring unparseable plugin response: {:?}", e),
+            }
+        }
+    }
+
+    /// Ask the plugin for a decision on an event.  A plugin that has
+    /// crashed or hung is restarted and asked again; if that fails,
+    /// `None` is returned.
+    pub async fn check(&mut self, event: &Event, source_ip: &str) -> Option<PluginResponse> {
+        let line = request_line(event, source_ip);
+        for attempt in 0..2 {
+            match self.request(&line, &event.id).await {
+                Ok(resp) => return Some(resp),
+                Err(e) => {
+                    warn!("event plugin failed (attempt {}): {:?}", attempt + 1, e);
+                    // drop (and kill) the process, so it is restarted
+                    self.process = None;
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_format() {
+        let e = Event::simple_event();
+        let line = request_line(&e, "127.0.0.1");
+        assert!(line.ends_with('\n'));
+        let v: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(v["type"], "new");
+        assert_eq!(v["sourceType"], "IP4");
+        assert_eq!(v["sourceInfo"], "127.0.0.1");
+        assert_eq!(v["event"]["id"], e.id.as_str());
+        assert!(v["receivedAt"].is_u64());
+        let line = request_line(&e, "::1");
+        assert!(line.contains("\"sourceType\":\"IP6\""));
+    }
+
+    #[test]
+    fn response_parse() {
+        let r: PluginResponse =
+            serde_json::from_str(r#"{"id":"abc","action":"shadowReject"}"#).unwrap();
+        assert_eq!(r.action, PluginAction::ShadowReject);
+        assert_eq!(r.msg, None);
+        let r: PluginResponse =
+            serde_json::from_str(r#"{"id":"abc","action":"reject","msg":"blocked: no"}"#).unwrap();
+        assert_eq!(r.action, PluginAction::Reject);
+        assert_eq!(r.msg.as_deref(), Some("blocked: no"));
+        assert!(serde_json::from_str::<PluginResponse>(r#"{"id":"abc","action":"maybe"}"#).is_err());
+    }
+}
@@ -0,0 +1,140 @@
+//! Storage quotas for authors
+//!
+//! Each author may store up to a configured number of bytes of
+//! events, with separate quotas for pubkeys in the whitelist and for
+//! authors who paid for admission.  Usage is read from the database,
+//! and counted up as events are written until it is read again, so
+//! events deleted or replaced in the meantime are still counted.
+use crate::config;
+use crate::event::Event;
+use crate::repo::NostrRepo;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// How long usage read from the database is used before it is read
+/// again.
+const CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Most cached usages before expired ones are removed.
+const CACHE_SIZE: usize = 10_000;
+
+struct Usage {
+    at: Instant,
+    bytes: u64,
+}
+
+/// Quota of an author, in bytes, if they have one.  Quotas for
+/// whitelisted and paid authors replace the default; any quota of 0
+/// is unlimited.
+#[must_use]
+pub fn quota_for(limits: &config::Limits, whitelisted: bool, paid: bool) -> Option<u64> {
+    let whitelisted = limits.whitelisted_max_bytes_per_pubkey.filter(|_| whitelisted);
+    let paid = limits.paid_max_bytes_per_pubkey.filter(|_| paid);
+    whitelisted
+        .or(paid)
+        .or(limits.max_bytes_per_pubkey)
+        .filter(|q| *q > 0)
+}
+
+/// Bytes stored by each author, cached from the database.
+pub struct Quotas {
+    repo: Arc<dyn NostrRepo>,
+    cache: Mutex<HashMap<String, Usage>>,
+}
+
+impl Quotas {
+    #[must_use]
+    pub fn new(repo: Arc<dyn NostrRepo>) -> Self {
+        Quotas {
+            repo,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Would storing an event take its author over a quota?  Events
+    /// are allowed if usage cannot be read.
+    pub async fn exceeds(&self, event: &Event, quota: u64) -> bool {
+        let used = match self.used(&event.pubkey).await {
+            Ok(used) => used,
+            Err(e) => {
+                warn!("could not load storage used by author: {:?}", e);
+                return false;
+            }
+        };
+        used + event_size(event) > quota
+    }
+
+    async fn used(&self, pubkey: &str) -> crate::error::Result<u64> {
+        if let Some(u) = self.cache.lock().unwrap().get(pubkey) {
+            if u.at.elapsed() < CACHE_TTL {
+                return Ok(u.bytes);
+            }
+        }
+        let bytes = self.repo.author_bytes(pubkey).await?;
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= CACHE_SIZE {
+            cache.retain(|_, u| u.at.elapsed() < CACHE_TTL);
+        }
+        cache.insert(
+            pubkey.to_owned(),
+            Usage {
+                at: Instant::now(),
+                bytes,
+            },
+        );
+        Ok(bytes)
+    }
+
+    /// Count a newly stored event towards its author's usage.
+    pub fn event_stored(&self, event: &Event) {
+        if let Some(u) = self.cache.lock().unwrap().get_mut(&event.pubkey) {
+            u.bytes += event_size(event);
+        }
+    }
+}
+
+/// Size of an event, as it is stored.
+fn event_size(event: &Event) -> u64 {
+    serde_json::to_string(event).map_or(0, |j| j.len() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+    use crate::repo::memory::MemoryRepo;
+    use crate::server::create_metrics;
+
+    #[test]
+    fn quotas_by_class() {
+        let mut limits = Settings::default().limits;
+        assert_eq!(quota_for(&limits, true, true), None);
+        limits.max_bytes_per_pubkey = Some(1000);
+        limits.whitelisted_max_bytes_per_pubkey = Some(0);
+        limits.paid_max_bytes_per_pubkey = Some(5000);
+        assert_eq!(quota_for(&limits, false, false), Some(1000));
+        assert_eq!(quota_for(&limits, false, true), Some(5000));
+        // whitelisted authors are unlimited, even if they paid
+        assert_eq!(quota_for(&limits, true, true), None);
+    }
+
+    #[tokio::test]
+    async fn stored_events_are_counted() {
+        let (_, metrics) = create_metrics();
+        let repo = Arc::new(MemoryRepo::new(&Settings::default(), metrics));
+        let quotas = Quotas::new(repo.clone());
+        let mut event = Event::simple_event();
+        event.id = "1".repeat(64);
+        event.pubkey = "a".repeat(64);
+        let size = event_size(&event);
+        assert!(!quotas.exceeds(&event, size).await);
+        repo.write_event(&event).await.unwrap();
+        quotas.event_stored(&event);
+        // the cached usage was counted up, without reading it again
+        assert!(quotas.exceeds(&event, size * 2 - 1).await);
+        assert!(!quotas.exceeds(&event, size * 2).await);
+        assert_eq!(repo.author_bytes(&event.pubkey).await.unwrap(), size);
+    }
+}
@@ -0,0 +1,178 @@
+//! Rate limits on published events
+//!
+//! Each configured rule covers some kinds of events, and limits how
+//! many of them an author, or a client IP address, may publish per
+//! minute.  An event is counted against the first rule covering its
+//! kind.  Limits are shared by all connections.  Depending on their
+//! reputation, an author's events may count for more than one, or
+//! not at all.
+use crate::config::{EventRateRule, KindRange};
+use crate::event::Event;
+use governor::clock::DefaultClock;
+use governor::state::keyed::DefaultKeyedStateStore;
+use governor::{Quota, RateLimiter};
+use std::num::NonZeroU32;
+use std::sync::RwLock;
+
+type KeyedLimiter = RateLimiter<String, DefaultKeyedStateStore<String>, DefaultClock>;
+
+/// What an event exceeded the rate limit of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimited {
+    Author,
+    Ip,
+}
+
+impl RateLimited {
+    /// Label for metrics
+    #[must_use]
+    pub fn label(&self) -> &'static str {
+        match self {
+            RateLimited::Author => "author",
+            RateLimited::Ip => "ip",
+        }
+    }
+
+    /// Explanation for the client
+    #[must_use]
+    pub fn message(&self) -> &'static str {
+        match self {
+            RateLimited::Author => "too many events from this author, slow down",
+            RateLimited::Ip => "too many events from this address, slow down",
+        }
+    }
+}
+
+struct Rule {
+    kinds: Option<Vec<KindRange>>,
+    per_author: Option<KeyedLimiter>,
+    per_ip: Option<KeyedLimiter>,
+}
+
+/// Event rate limits for every author and IP address.
+pub struct EventRateLimiter {
+    rules: RwLock<Vec<Rule>>,
+}
+
+fn keyed(per_min: Option<u32>) -> Option<KeyedLimiter> {
+    per_min
+        .and_then(NonZeroU32::new)
+        .map(|n| RateLimiter::keyed(Quota::per_minute(n)))
+}
+
+fn build_rules(rules: &[EventRateRule]) -> Vec<Rule> {
+    rules
+        .iter()
+        .map(|r| Rule {
+            kinds: r.kinds.clone(),
+            per_author: keyed(r.per_author_per_min),
+            per_ip: keyed(r.per_ip_per_min),
+        })
+        .collect()
+}
+
+impl EventRateLimiter {
+    #[must_use]
+    pub fn new(rules: &[EventRateRule]) -> Self {
+        EventRateLimiter {
+            rules: RwLock::new(build_rules(rules)),
+        }
+    }
+
+    /// Replace the rules, when settings are reloaded.  Counts start
+    /// again from zero.
+    pub fn set_rules(&self, rules: &[EventRateRule]) {
+        *self.rules.write().unwrap() = build_rules(rules);
+    }
+
+    /// Count an event published from an IP address, unless it is
+    /// over a limit.  The event counts `author_cost` times against its
+    /// author's limit.
+    pub fn check(&self, event: &Event, ip: &str, author_cost: u32) -> Result<(), RateLimited> {
+        let covers = |r: &&Rule| r.kinds.as_ref().is_none_or(|k| k.iter().any(|k| k.contains(event.kind)));
+        let rules = self.rules.read().unwrap();
+        let Some(rule) = rules.iter().find(covers) else {
+            return Ok(());
+        };
+        if let Some(lim) = &rule.per_ip {
+            lim.check_key(&ip.to_owned()).map_err(|_| RateLimited::Ip)?;
+        }
+        if let (Some(lim), Some(cost)) = (&rule.per_author, NonZeroU32::new(author_cost)) {
+            // a cost over the whole quota is never allowed.
+            lim.check_key_n(&event.pubkey, cost).map_err(|_| RateLimited::Author)?;
+        }
+        Ok(())
+    }
+
+    /// Forget authors and addresses that are no longer limited.
+    pub fn prune(&self) {
+        for r in self.rules.read().unwrap().iter() {
+            for lim in [&r.per_author, &r.per_ip].into_iter().flatten() {
+                lim.retain_recent();
+                lim.shrink_to_fit();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(kind: u64, pubkey: &str) -> Event {
+        let mut e = Event::simple_event();
+        e.kind = kind;
+        e.pubkey = pubkey.to_owned();
+        e
+    }
+
+    #[test]
+    fn authors_are_limited_by_kind() {
+        let limiter = EventRateLimiter::new(&[
+            EventRateRule {
+                kinds: Some(vec![KindRange::Range([20000, 29999])]),
+                per_author_per_min: None,
+                per_ip_per_min: None,
+            },
+            EventRateRule {
+                kinds: None,
+                per_author_per_min: Some(2),
+                per_ip_per_min: None,
+            },
+        ]);
+        assert!(limiter.check(&event(1, "a"), "ip", 1).is_ok());
+        assert!(limiter.check(&event(7, "a"), "ip", 1).is_ok());
+        assert_eq!(limiter.check(&event(1, "a"), "ip", 1), Err(RateLimited::Author));
+        // ephemeral events are covered by the unlimited rule
+        assert!(limiter.check(&event(20001, "a"), "ip", 1).is_ok());
+        assert!(limiter.check(&event(1, "b"), "ip", 1).is_ok());
+    }
+
+    #[test]
+    fn addresses_are_limited() {
+        let limiter = EventRateLimiter::new(&[EventRateRule {
+            kinds: None,
+            per_author_per_min: Some(10),
+            per_ip_per_min: Some(1),
+        }]);
+        assert!(limiter.check(&event(1, "a"), "1.1.1.1", 1).is_ok());
+        assert_eq!(limiter.check(&event(1, "b"), "1.1.1.1", 1), Err(RateLimited::Ip));
+        assert!(limiter.check(&event(1, "b"), "2.2.2.2", 1).is_ok());
+    }
+
+    #[test]
+    fn authors_are_charged_by_cost() {
+        let limiter = EventRateLimiter::new(&[EventRateRule {
+            kinds: None,
+            per_author_per_min: Some(4),
+            per_ip_per_min: None,
+        }]);
+        assert!(limiter.check(&event(1, "a"), "ip", 3).is_ok());
+        assert_eq!(limiter.check(&event(1, "a"), "ip", 3), Err(RateLimited::Author));
+        // trusted authors are not counted
+        for _ in 0..10 {
+            assert!(limiter.check(&event(1, "b"), "ip", 0).is_ok());
+        }
+        assert_eq!(limiter.check(&event(1, "c"), "ip", 5), Err(RateLimited::Author));
+    }
+}
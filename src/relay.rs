@@ -0,0 +1,295 @@
+//! Embedding a relay in another application
+//!
+//! [`Relay::builder`] starts a relay on its own threads, as the
+//! binary would run it, and returns a handle for publishing events to
+//! it, subscribing to its events, and shutting it down.  The handle
+//! can be used from any async runtime, since the work is done on the
+//! relay's own.
+use crate::config::Settings;
+use crate::db::{self, SubmittedEvent};
+use crate::error::{Error, Result};
+use crate::event::{BroadcastEvent, Event, EventCmd, EventWrapper};
+use crate::identity::RelayIdentity;
+use crate::inject;
+use crate::matcher::{Matched, Matcher, MatcherConn};
+use crate::notice::{EventResult, Notice};
+use crate::repo::NostrRepo;
+use crate::server::run_server;
+use crate::subscription::Subscription;
+use crate::verify::SignatureVerifier;
+use std::sync::mpsc as syncmpsc;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use tokio::runtime::Handle;
+use tokio::sync::{mpsc, oneshot};
+
+/// Address recorded for events published through the handle
+const EMBEDDED_SOURCE: &str = "embedded";
+
+/// Most stored events queued for a subscription before the query waits
+const QUERY_QUEUE: usize = 20_000;
+
+/// The parts of a running relay that a handle uses.
+#[derive(Clone)]
+pub(crate) struct RelayParts {
+    pub runtime: Handle,
+    pub settings: Settings,
+    pub repo: Arc<dyn NostrRepo>,
+    pub event_tx: mpsc::Sender<SubmittedEvent>,
+    pub verifier: SignatureVerifier,
+    pub matcher: Matcher,
+    pub identity: Option<Arc<RelayIdentity>>,
+}
+
+/// How an embedded relay is run
+pub(crate) struct Embedding {
+    /// Serve clients on the configured addresses
+    pub listen: bool,
+    /// Receives the relay's parts once it has started
+    pub ready: syncmpsc::Sender<RelayParts>,
+}
+
+/// Settings for a relay to embed.
+#[derive(Debug, Clone)]
+pub struct RelayBuilder {
+    settings: Settings,
+    listen: bool,
+}
+
+impl RelayBuilder {
+    /// Use these settings, instead of the defaults.
+    #[must_use]
+    pub fn settings(mut self, settings: Settings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    /// Read settings from a config file, instead of using the
+    /// defaults.  Changes to the file are applied while the relay
+    /// runs.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the file cannot be read or is not valid.
+    pub fn config_file(mut self, path: &str) -> Result<Self> {
+        self.settings = Settings::reload(path)
+            .map_err(|e| Error::CustomError(format!("{path} is not valid: {e}")))?;
+        Ok(self)
+    }
+
+    /// Serve websocket and HTTP clients on the configured addresses
+    /// (the default), or only the handle.
+    #[must_use]
+    pub fn listen(mut self, listen: bool) -> Self {
+        self.listen = listen;
+        self
+    }
+
+    /// Start the relay, and wait until it is ready for events.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the relay could not start.
+    pub fn start(self) -> Result<Relay> {
+        let (ready_tx, ready_rx) = syncmpsc::channel();
+        let (ctrl_tx, ctrl_rx) = syncmpsc::channel();
+        let embedding = Embedding {
+            listen: self.listen,
+            ready: ready_tx,
+        };
+        let settings = self.settings;
+        let thread = thread::Builder::new()
+            .name("nostr-relay".to_owned())
+            .spawn(move || run_server(&settings, ctrl_rx, Some(embedding)))?;
+        match ready_rx.recv() {
+            Ok(parts) => Ok(Relay {
+                parts,
+                ctrl_tx,
+                thread: Some(thread),
+            }),
+            // the relay stopped before it was ready.
+            Err(_) => match thread.join() {
+                Ok(Err(e)) => Err(e),
+                _ => Err(Error::CustomError(
+                    "relay stopped while starting".to_owned(),
+                )),
+            },
+        }
+    }
+}
+
+/// A relay running inside this process.  Dropping it shuts the relay
+/// down without waiting for it.
+pub struct Relay {
+    parts: RelayParts,
+    ctrl_tx: syncmpsc::Sender<()>,
+    thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl Relay {
+    /// Start building a relay, with the default settings.
+    #[must_use]
+    pub fn builder() -> RelayBuilder {
+        RelayBuilder {
+            settings: Settings::default(),
+            listen: true,
+        }
+    }
+
+    /// Settings the relay was started with.
+    #[must_use]
+    pub fn settings(&self) -> &Settings {
+        &self.parts.settings
+    }
+
+    /// The relay's own keypair, for publishing events as the relay,
+    /// if one is configured.
+    #[must_use]
+    pub fn identity(&self) -> Option<&RelayIdentity> {
+        self.parts.identity.as_deref()
+    }
+
+    /// Publish an event, and wait for the relay's verdict, as sent to
+    /// a client in an `OK` message.  The event is written and
+    /// broadcast like one from a client, but checks made on client
+    /// connections (authentication, rate limits and bans by address)
+    /// do not apply.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the event's id or signature is not valid,
+    /// or if the relay has stopped.
+    pub async fn submit(&self, event: Event) -> Result<EventResult> {
+        self.write(event, true).await
+    }
+
+    /// Publish an event that has already been checked, without
+    /// checking its id and signature again (see [`crate::inject`]).
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the event has a delegation tag that does
+    /// not validate, or if the relay has stopped.
+    pub async fn inject(&self, event: Event) -> Result<EventResult> {
+        self.write(event, false).await
+    }
+
+    async fn write(&self, event: Event, verify: bool) -> Result<EventResult> {
+        let parts = self.parts.clone();
+        let writing = async move {
+            let event = if verify {
+                match parts.verifier.verify(EventCmd::new(event)).await? {
+                    EventWrapper::WrappedEvent(e) => e,
+                    EventWrapper::WrappedAuth(_) => return Err(Error::CommandUnknownError),
+                }
+            } else {
+                inject::prepare(event)?
+            };
+            match inject::inject(&parts.event_tx, vec![event], EMBEDDED_SOURCE)
+                .await
+                .pop()
+            {
+                Some(Notice::EventResult(result)) => Ok(result),
+                _ => Err(Error::CustomError("event was not written".to_owned())),
+            }
+        };
+        self.parts.runtime.spawn(writing).await?
+    }
+
+    /// Receive stored events matching a subscription, then new ones
+    /// as they are published.  Events are not hidden from the handle
+    /// as private messages and group events are from other clients.
+    #[must_use]
+    pub fn subscribe(&self, sub: Subscription) -> RelaySubscription {
+        let (conn, matched_rx) = self
+            .parts
+            .matcher
+            .connect(self.parts.settings.limits.broadcast_buffer);
+        conn.subscribe(sub.clone());
+        let (query_tx, query_rx) = mpsc::channel::<db::QueryResult>(QUERY_QUEUE);
+        let (abandon_tx, abandon_rx) = oneshot::channel::<()>();
+        if sub.needs_historical_events() {
+            let repo = self.parts.repo.clone();
+            self.parts.runtime.spawn(async move {
+                repo.query_subscription(sub, EMBEDDED_SOURCE.to_owned(), query_tx, abandon_rx)
+                    .await
+                    .ok();
+            });
+        }
+        RelaySubscription {
+            _conn: conn,
+            matched_rx,
+            query_rx,
+            abandon_tx: Some(abandon_tx),
+        }
+    }
+
+    /// Shut the relay down, and wait until it has stopped.  This
+    /// blocks the calling thread.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the relay stopped because of an error.
+    pub fn shutdown(mut self) -> Result<()> {
+        self.ctrl_tx.send(()).ok();
+        match self.thread.take().map(JoinHandle::join) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => Err(Error::CustomError("relay thread panicked".to_owned())),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        self.ctrl_tx.send(()).ok();
+    }
+}
+
+/// Something delivered to a subscription
+#[derive(Debug, Clone)]
+pub enum SubscriptionMessage {
+    /// A matching event, with its JSON
+    Event(BroadcastEvent),
+    /// All stored events have been sent; only new events follow
+    Eose,
+}
+
+/// Events for a subscription made through a [`Relay`].  The
+/// subscription ends when this is dropped.
+pub struct RelaySubscription {
+    _conn: MatcherConn,
+    matched_rx: mpsc::Receiver<Matched>,
+    query_rx: mpsc::Receiver<db::QueryResult>,
+    abandon_tx: Option<oneshot::Sender<()>>,
+}
+
+impl RelaySubscription {
+    /// The next message, or `None` once the relay has stopped.
+    pub async fn recv(&mut self) -> Option<SubscriptionMessage> {
+        loop {
+            tokio::select! {
+                Some(result) = self.query_rx.recv() => {
+                    if result.event == "EOSE" {
+                        return Some(SubscriptionMessage::Eose);
+                    }
+                    if let Ok(event) = serde_json::from_str::<Event>(&result.event) {
+                        return Some(SubscriptionMessage::Event(BroadcastEvent::with_json(event, result.event)));
+                    }
+                },
+                Some(matched) = self.matched_rx.recv() => {
+                    return Some(SubscriptionMessage::Event(matched.event));
+                },
+                else => return None,
+            }
+        }
+    }
+}
+
+impl Drop for RelaySubscription {
+    fn drop(&mut self) {
+        if let Some(tx) = self.abandon_tx.take() {
+            tx.send(()).ok();
+        }
+    }
+}
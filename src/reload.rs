@@ -0,0 +1,160 @@
+//! Configuration reload
+//!
+//! When the relay was started with a config file, the file is checked
+//! for changes every few seconds, and read again on SIGHUP.  Relay
+//! information, the pubkey whitelist, rate limits and antispam
+//! policies take effect without a restart, including for open
+//! connections.  Changes to other settings are logged, and wait for
+//! the next restart.  A file that cannot be read, or holds invalid
+//! settings, is logged and the current settings are kept.
+use crate::config::Settings;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{broadcast, watch};
+use tracing::{info, warn};
+
+/// How often the config file is checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(Path::new(path)).and_then(|m| m.modified()).ok()
+}
+
+/// The outcome of reading the config file again
+#[derive(Debug)]
+struct Reload {
+    /// Settings to run with
+    running: Settings,
+    /// Sections of the running settings that changed
+    applied: Vec<String>,
+    /// Sections that changed in the file, but need a restart
+    ignored: Vec<String>,
+}
+
+/// Apply newly read settings to the running ones.  `loaded` is what
+/// the file held before, so that settings overridden at startup (such
+/// as the database directory) are not reported as changed.
+fn reload(loaded: &Settings, running: &Settings, new: &Settings) -> Reload {
+    let mut reloadable = loaded.clone();
+    reloadable.apply_reloadable(new);
+    let mut next = running.clone();
+    next.apply_reloadable(new);
+    // the running whitelist is stored in the database; pubkeys added
+    // to the file are added to it, and none are removed.
+    if let (Some(wl), Some(listed)) = (
+        &running.authorization.pubkey_whitelist,
+        &new.authorization.pubkey_whitelist,
+    ) {
+        let before = loaded.authorization.pubkey_whitelist.as_deref().unwrap_or_default();
+        let mut merged = wl.clone();
+        merged.extend(
+            listed
+                .iter()
+                .filter(|pk| !before.contains(pk) && !wl.contains(pk))
+                .cloned(),
+        );
+        next.authorization.pubkey_whitelist = Some(merged);
+    }
+    Reload {
+        applied: running.changed_sections(&next),
+        ignored: reloadable.changed_sections(new),
+        running: next,
+    }
+}
+
+/// Watch the config file, sending the running settings to every
+/// subscriber when they change.
+pub async fn watch_config(
+    path: String,
+    settings_tx: Arc<watch::Sender<Settings>>,
+    mut shutdown: broadcast::Receiver<()>,
+) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("could not listen for SIGHUP: {:?}", e);
+            return;
+        }
+    };
+    let mut loaded = Settings::reload(&path).unwrap_or_else(|_| settings_tx.borrow().clone());
+    let mut last_modified = modified(&path);
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    info!("watching {} for configuration changes", path);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let m = modified(&path);
+                if m == last_modified {
+                    continue;
+                }
+                last_modified = m;
+            },
+            _ = hangup.recv() => info!("reloading configuration due to SIGHUP"),
+            _ = shutdown.recv() => return,
+        }
+        let new = match Settings::reload(&path) {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("could not reload {}, keeping current settings: {}", path, e);
+                continue;
+            }
+        };
+        let result = reload(&loaded, &settings_tx.borrow(), &new);
+        loaded = new;
+        if !result.ignored.is_empty() {
+            warn!("changes to {:?} in {} need a restart", result.ignored, path);
+        }
+        if !result.applied.is_empty() {
+            info!("reloaded {:?} from {}", result.applied, path);
+            settings_tx.send_replace(result.running);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_reloadable_settings_change() {
+        let loaded = Settings::default();
+        let mut running = loaded.clone();
+        running.database.data_directory = "/var/lib/relay".to_owned();
+        let mut new = loaded.clone();
+        new.info.name = Some("renamed".to_owned());
+        new.limits.messages_per_sec = Some(5);
+        new.limits.max_connections = Some(10);
+        new.antispam.keywords = Some(vec!["casino".to_owned()]);
+        let result = reload(&loaded, &running, &new);
+        assert_eq!(result.running.info.name.as_deref(), Some("renamed"));
+        assert_eq!(result.running.limits.messages_per_sec, Some(5));
+        assert_eq!(result.running.limits.max_connections, None);
+        assert_eq!(result.running.database.data_directory, "/var/lib/relay");
+        assert_eq!(result.applied, vec!["info", "limits", "antispam"]);
+        assert_eq!(result.ignored, vec!["limits"]);
+        // reading the same file again changes nothing
+        let again = reload(&new, &result.running, &new);
+        assert!(again.applied.is_empty() && again.ignored.is_empty());
+    }
+
+    #[test]
+    fn whitelist_is_only_added_to() {
+        let mut loaded = Settings::default();
+        loaded.authorization.pubkey_whitelist = Some(vec!["a".to_owned(), "b".to_owned()]);
+        // "b" was disallowed, and "c" allowed, while running
+        let mut running = loaded.clone();
+        running.authorization.pubkey_whitelist = Some(vec!["a".to_owned(), "c".to_owned()]);
+        let mut new = loaded.clone();
+        new.authorization.pubkey_whitelist = Some(vec!["b".to_owned(), "d".to_owned()]);
+        let result = reload(&loaded, &running, &new);
+        assert_eq!(
+            result.running.authorization.pubkey_whitelist,
+            Some(vec!["a".to_owned(), "c".to_owned(), "d".to_owned()])
+        );
+        new.authorization.pubkey_whitelist = None;
+        let result = reload(&loaded, &running, &new);
+        assert_eq!(result.running.authorization.pubkey_whitelist, None);
+    }
+}
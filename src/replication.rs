@@ -0,0 +1,192 @@
+//! Live replication of accepted events to peer relays
+//!
+//! Each configured peer has a task that keeps a websocket connection
+//! open and publishes the events queued for it, reconnecting with
+//! increasing delays when the connection drops.  Events are queued
+//! from the same broadcast channel that delivers new events to
+//! clients.  A peer that falls behind has new events dropped once its
+//! queue is full, so that it can never hold up the relay.
+use crate::config::{ReplicationPeer, Settings};
+use crate::event::{BroadcastEvent, Event};
+use futures::{SinkExt, StreamExt};
+use serde_json::Value;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{broadcast, mpsc};
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use tracing::{debug, info, warn};
+use tungstenite::error::Error as WsError;
+use tungstenite::Message;
+
+/// Delay before the first reconnection attempt
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Longest delay between reconnection attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Time allowed to establish a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+type PeerStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Does a peer want an event?
+fn wants(peer: &ReplicationPeer, e: &Event) -> bool {
+    peer.kinds
+        .as_ref()
+        .is_none_or(|ks| ks.iter().any(|k| k.contains(e.kind)))
+        && peer.authors.as_ref().is_none_or(|a| a.contains(&e.pubkey))
+}
+
+/// Events waiting to be sent to a peer
+struct PeerQueue {
+    peer: ReplicationPeer,
+    tx: mpsc::Sender<String>,
+    dropped: u64,
+}
+
+/// Forward events from the broadcast channel to every configured
+/// peer, until shutdown.
+pub async fn replicate(
+    settings: Settings,
+    mut bcast_rx: broadcast::Receiver<BroadcastEvent>,
+    mut shutdown: broadcast::Receiver<()>,
+) {
+    let peers = settings.replication.peers.clone().unwrap_or_default();
+    if peers.is_empty() {
+        return;
+    }
+    let mut queues: Vec<PeerQueue> = peers
+        .into_iter()
+        .map(|peer| {
+            let (tx, rx) = mpsc::channel(settings.replication.queue_size.max(1));
+            // each peer task stops once its queue is dropped.
+            tokio::spawn(peer_task(peer.url.clone(), rx));
+            PeerQueue { peer, tx, dropped: 0 }
+        })
+        .collect();
+    info!("replicating events to {} peers", queues.len());
+    loop {
+        tokio::select! {
+            res = bcast_rx.recv() => match res {
+                Ok(e) => {
+                    for q in queues.iter_mut().filter(|q| wants(&q.peer, &e)) {
+                        if let Err(TrySendError::Full(_)) = q.tx.try_send(e.json().to_owned()) {
+                            q.dropped += 1;
+                            if q.dropped.is_power_of_two() {
+                                warn!("replication queue for {} is full, {} events dropped", q.peer.url, q.dropped);
+                            }
+                        }
+                    }
+                }
+                Err(RecvError::Lagged(n)) => {
+                    warn!("replication fell behind the relay, {} events were not forwarded", n);
+                }
+                Err(RecvError::Closed) => return,
+            },
+            _ = shutdown.recv() => {
+                info!("shutting down replication");
+                return;
+            }
+        }
+    }
+}
+
+/// Keep a connection open to a peer, and send it queued events.
+async fn peer_task(url: String, mut rx: mpsc::Receiver<String>) {
+    let mut backoff = MIN_BACKOFF;
+    // an event taken from the queue, that could not be sent
+    let mut pending: Option<String> = None;
+    loop {
+        match tokio::time::timeout(CONNECT_TIMEOUT, connect_async(&url)).await {
+            Ok(Ok((ws, _))) => {
+                info!("connected to replication peer {}", url);
+                backoff = MIN_BACKOFF;
+                match send_events(&url, ws, &mut rx, &mut pending).await {
+                    Ok(()) => return,
+                    Err(e) => warn!("lost connection to replication peer {}: {:?}", url, e),
+                }
+            }
+            Ok(Err(e)) => warn!("could not connect to replication peer {}: {:?}", url, e),
+            Err(_) => warn!("timed out connecting to replication peer {}", url),
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Publish queued events over a connection, until the queue is closed
+/// (returning `Ok`) or the connection fails.
+async fn send_events(
+    url: &str,
+    ws: PeerStream,
+    rx: &mut mpsc::Receiver<String>,
+    pending: &mut Option<String>,
+) -> Result<(), WsError> {
+    let (mut sink, mut stream) = ws.split();
+    loop {
+        let json = match pending.take() {
+            Some(json) => json,
+            None => tokio::select! {
+                next = rx.recv() => match next {
+                    Some(json) => json,
+                    None => {
+                        sink.close().await.ok();
+                        return Ok(());
+                    }
+                },
+                msg = stream.next() => match msg {
+                    Some(Ok(Message::Text(reply))) => {
+                        log_reply(url, &reply);
+                        continue;
+                    }
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return Err(e),
+                    None => return Err(WsError::ConnectionClosed),
+                },
+            },
+        };
+        if let Err(e) = sink.send(Message::Text(format!("[\"EVENT\",{json}]"))).await {
+            *pending = Some(json);
+            return Err(e);
+        }
+    }
+}
+
+/// Log events a peer refused.
+fn log_reply(url: &str, reply: &str) {
+    let Ok(Value::Array(msg)) = serde_json::from_str::<Value>(reply) else {
+        return;
+    };
+    if msg.first().and_then(Value::as_str) == Some("OK") && msg.get(2).and_then(Value::as_bool) == Some(false) {
+        let id = msg.get(1).and_then(Value::as_str).unwrap_or_default();
+        let reason = msg.get(3).and_then(Value::as_str).unwrap_or_default();
+        debug!("replication peer {} refused event {}: {}", url, id, reason);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::KindRange;
+
+    #[test]
+    fn peer_filters() {
+        let mut e = Event::simple_event();
+        e.kind = 1;
+        e.pubkey = "a".repeat(64);
+        let mut peer = ReplicationPeer {
+            url: "wss://relay.example.com".to_owned(),
+            kinds: None,
+            authors: None,
+        };
+        assert!(wants(&peer, &e));
+        peer.kinds = Some(vec![KindRange::Single(0), KindRange::Range([30000, 39999])]);
+        assert!(!wants(&peer, &e));
+        e.kind = 30023;
+        assert!(wants(&peer, &e));
+        peer.authors = Some(vec!["b".repeat(64)]);
+        assert!(!wants(&peer, &e));
+    }
+}
@@ -0,0 +1,555 @@
+//! Cache of recent query results
+//!
+//! Many clients request the same events, particularly profiles
+//! (kind 0) and contact lists (kind 3) of popular authors.  Results of
+//! small queries are kept in memory, keyed by their filters, and
+//! replayed to later subscriptions with the same filters.  Writing an
+//! event that matches a cached query removes it, as does any deletion;
+//! entries also expire, to bound staleness from changes made outside
+//! this process (expiring events, other relays sharing the database).
+use crate::bans::Ban;
+use crate::db::QueryResult;
+use crate::error::Result;
+use crate::event::Event;
+use crate::groups::{Group, GroupUpdate};
+use crate::matcher::{ConnId, SubscriptionIndex};
+use crate::nip05::{QueuedVerification, VerificationRecord};
+use crate::payment::{Account, Invoice};
+use crate::repo::{EventSummary, NostrRepo, ScanOrder};
+use crate::reports::Report;
+use crate::reputation::{ReputationChange, ReputationRecord};
+use crate::subscription::{ReqFilter, Subscription};
+use async_trait::async_trait;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+/// Largest result set that is cached
+const MAX_CACHED_EVENTS: usize = 500;
+
+/// Index entries for cached results
+const CACHED: ConnId = 0;
+
+/// Index entries for queries that are still running
+const RUNNING: ConnId = 1;
+
+/// Tag filters in a canonical order, for use in a cache key.
+fn sorted_tags<K: Ord>(tags: Option<HashMap<K, HashSet<String>>>) -> BTreeMap<K, BTreeSet<String>> {
+    tags.unwrap_or_default()
+        .into_iter()
+        .map(|(k, vals)| (k, vals.into_iter().collect()))
+        .collect()
+}
+
+/// Canonical form of a filter, which is the same for any filter
+/// matching the same events.
+fn filter_key(f: &ReqFilter) -> String {
+    let mut f = f.clone();
+    for vals in [&mut f.ids, &mut f.authors].into_iter().flatten() {
+        vals.sort();
+        vals.dedup();
+    }
+    if let Some(kinds) = &mut f.kinds {
+        kinds.sort_unstable();
+        kinds.dedup();
+    }
+    let tags = sorted_tags(f.tags.take());
+    let and_tags = sorted_tags(f.and_tags.take());
+    let long_tags = sorted_tags(f.long_tags.take());
+    format!(
+        "{}{}&{}{}",
+        serde_json::to_string(&f).unwrap_or_default(),
+        serde_json::to_string(&tags).unwrap_or_default(),
+        serde_json::to_string(&and_tags).unwrap_or_default(),
+        serde_json::to_string(&long_tags).unwrap_or_default()
+    )
+}
+
+/// Cache key for a set of filters, independent of their order.
+fn query_key(filters: &[ReqFilter]) -> String {
+    let mut keys: Vec<String> = filters.iter().map(filter_key).collect();
+    keys.sort();
+    keys.dedup();
+    keys.join(",")
+}
+
+struct CacheEntry {
+    events: Arc<Vec<String>>,
+    stored: Instant,
+    /// position in the recently used order
+    used: u64,
+}
+
+/// Cached results, and the queries that may add to them
+struct QueryCache {
+    capacity: usize,
+    ttl: Duration,
+    entries: HashMap<String, CacheEntry>,
+    /// keys of entries, least recently used first
+    lru: BTreeMap<u64, String>,
+    tick: u64,
+    /// filters of cached results, and of running queries, which are
+    /// checked against written events
+    index: SubscriptionIndex,
+    /// running queries that saw a matching write, whose results must
+    /// not be cached
+    stale: HashMap<String, bool>,
+    next_query: u64,
+}
+
+impl QueryCache {
+    fn new(capacity: usize, ttl: Duration) -> Self {
+        QueryCache {
+            capacity,
+            ttl,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            index: SubscriptionIndex::default(),
+            stale: HashMap::new(),
+            next_query: 0,
+        }
+    }
+
+    /// Cached results for a query, if they have not expired.
+    fn get(&mut self, key: &str) -> Option<Arc<Vec<String>>> {
+        let entry = self.entries.get_mut(key)?;
+        if entry.stored.elapsed() > self.ttl {
+            self.remove(key);
+            return None;
+        }
+        self.tick += 1;
+        self.lru.remove(&entry.used);
+        entry.used = self.tick;
+        self.lru.insert(self.tick, key.to_owned());
+        Some(entry.events.clone())
+    }
+
+    /// Track a query that is about to run, returning its id.
+    fn begin(&mut self, filters: Vec<ReqFilter>) -> String {
+        self.next_query += 1;
+        let id = self.next_query.to_string();
+        self.index.insert(RUNNING, Subscription { id: id.clone(), filters });
+        self.stale.insert(id.clone(), false);
+        id
+    }
+
+    /// Stop tracking a query, caching its results unless a matching
+    /// event was written while it ran.
+    fn finish(&mut self, query: &str, key: String, filters: Vec<ReqFilter>, events: Option<Vec<String>>) {
+        self.index.remove(RUNNING, query);
+        let stale = self.stale.remove(query).unwrap_or(true);
+        let Some(events) = events else {
+            return;
+        };
+        if stale || self.capacity == 0 {
+            return;
+        }
+        self.remove(&key);
+        while self.entries.len() >= self.capacity {
+            let Some((_, oldest)) = self.lru.pop_first() else {
+                break;
+            };
+            self.remove(&oldest);
+        }
+        self.tick += 1;
+        self.lru.insert(self.tick, key.clone());
+        self.index.insert(CACHED, Subscription { id: key.clone(), filters });
+        self.entries.insert(
+            key,
+            CacheEntry {
+                events: Arc::new(events),
+                stored: Instant::now(),
+                used: self.tick,
+            },
+        );
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.used);
+            self.index.remove(CACHED, key);
+        }
+    }
+
+    /// Forget results that a written event could change.
+    fn invalidate(&mut self, e: &Event) {
+        // a deletion could hide events from any query.
+        if e.kind == 5 {
+            self.clear();
+            return;
+        }
+        for (conn, ids) in self.index.matches(e) {
+            for id in ids {
+                if conn == CACHED {
+                    self.remove(&id);
+                } else if let Some(stale) = self.stale.get_mut(&id) {
+                    *stale = true;
+                }
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        for id in self.entries.keys().cloned().collect::<Vec<_>>() {
+            self.remove(&id);
+        }
+        self.stale.values_mut().for_each(|s| *s = true);
+    }
+}
+
+/// A repository that answers repeated queries from memory.
+/// Everything else is passed through to the database.
+pub struct CachedRepo {
+    inner: Arc<dyn NostrRepo>,
+    cache: Arc<Mutex<QueryCache>>,
+}
+
+impl CachedRepo {
+    pub fn new(inner: Arc<dyn NostrRepo>, capacity: usize, ttl: Duration) -> Self {
+        info!("caching results of up to {} queries for {:?}", capacity, ttl);
+        CachedRepo {
+            inner,
+            cache: Arc::new(Mutex::new(QueryCache::new(capacity, ttl))),
+        }
+    }
+
+    fn invalidate(&self, events: &[Event]) {
+        let mut cache = self.cache.lock().unwrap();
+        events.iter().for_each(|e| cache.invalidate(e));
+    }
+}
+
+#[async_trait]
+impl NostrRepo for CachedRepo {
+    async fn start(&self) -> Result<()> {
+        self.inner.start().await
+    }
+
+    async fn migrate_up(&self) -> Result<usize> {
+        self.inner.migrate_up().await
+    }
+
+    async fn write_event(&self, e: &Event) -> Result<u64> {
+        let res = self.inner.write_event(e).await;
+        self.invalidate(std::slice::from_ref(e));
+        res
+    }
+
+    async fn write_events(&self, events: &[Event]) -> Result<Vec<u64>> {
+        let res = self.inner.write_events(events).await;
+        self.invalidate(events);
+        res
+    }
+
+    async fn query_subscription(
+        &self,
+        sub: Subscription,
+        client_id: String,
+        query_tx: tokio::sync::mpsc::Sender<QueryResult>,
+        mut abandon_query_rx: tokio::sync::oneshot::Receiver<()>,
+    ) -> Result<()> {
+        if sub.filters.iter().any(|f| f.force_no_match) {
+            return self
+                .inner
+                .query_subscription(sub, client_id, query_tx, abandon_query_rx)
+                .await;
+        }
+        let key = query_key(&sub.filters);
+        // any client that doesn't accept results for 2 seconds
+        // gets dropped.
+        let abort_cutoff = Duration::from_secs(2);
+        let cached = self.cache.lock().unwrap().get(&key);
+        if let Some(events) = cached {
+            debug!("query answered from cache (cid: {}, sub: {:?}, rows: {})", client_id, sub.id, events.len());
+            tokio::spawn(async move {
+                let results = events.iter().cloned().chain(std::iter::once("EOSE".to_owned()));
+                for event in results {
+                    if abandon_query_rx.try_recv().is_ok() {
+                        return;
+                    }
+                    let res = QueryResult {
+                        sub_id: sub.get_id(),
+                        event,
+                    };
+                    if tokio::time::timeout(abort_cutoff, query_tx.send(res)).await.is_err() {
+                        info!("aborting cached query due to slow client (cid: {}, sub: {:?})", client_id, sub.id);
+                        return;
+                    }
+                }
+            });
+            return Ok(());
+        }
+        // results are collected as they are passed along, and cached
+        // if the query completes.
+        let query = self.cache.lock().unwrap().begin(sub.filters.clone());
+        let (db_tx, mut db_rx) = tokio::sync::mpsc::channel::<QueryResult>(query_tx.max_capacity());
+        let (db_abandon_tx, db_abandon_rx) = tokio::sync::oneshot::channel();
+        if let Err(e) = self
+            .inner
+            .query_subscription(sub.clone(), client_id.clone(), db_tx, db_abandon_rx)
+            .await
+        {
+            self.cache.lock().unwrap().finish(&query, key, sub.filters, None);
+            return Err(e);
+        }
+        let cache = self.cache.clone();
+        tokio::spawn(async move {
+            let mut events = Some(vec![]);
+            let mut complete = false;
+            while let Some(res) = db_rx.recv().await {
+                if abandon_query_rx.try_recv().is_ok() {
+                    db_abandon_tx.send(()).ok();
+                    break;
+                }
+                let eose = res.event == "EOSE";
+                if !eose {
+                    events = events.filter(|ev| ev.len() < MAX_CACHED_EVENTS).map(|mut ev| {
+                        ev.push(res.event.clone());
+                        ev
+                    });
+                }
+                if tokio::time::timeout(abort_cutoff, query_tx.send(res)).await.is_err() {
+                    info!("aborting query due to slow client (cid: {}, sub: {:?})", client_id, sub.id);
+                    db_abandon_tx.send(()).ok();
+                    break;
+                }
+                if eose {
+                    complete = true;
+                    break;
+                }
+            }
+            let events = events.filter(|_| complete);
+            cache.lock().unwrap().finish(&query, key, sub.filters, events);
+        });
+        Ok(())
+    }
+
+    async fn count_events_by_filter(&self, filters: Vec<ReqFilter>) -> Result<u64> {
+        self.inner.count_events_by_filter(filters).await
+    }
+
+    async fn is_event_deleted(&self, id: &str) -> Result<bool> {
+        self.inner.is_event_deleted(id).await
+    }
+
+    async fn get_relay_list(&self, pub_key: &str) -> Result<Option<Event>> {
+        self.inner.get_relay_list(pub_key).await
+    }
+
+    async fn apply_group_update(&self, update: &GroupUpdate) -> Result<()> {
+        self.inner.apply_group_update(update).await
+    }
+
+    async fn get_groups(&self) -> Result<Vec<Group>> {
+        self.inner.get_groups().await
+    }
+
+    async fn add_ban(&self, ban: &Ban) -> Result<()> {
+        self.inner.add_ban(ban).await
+    }
+
+    async fn get_bans(&self) -> Result<Vec<Ban>> {
+        self.inner.get_bans().await
+    }
+
+    async fn get_allowed_pubkeys(&self) -> Result<Vec<String>> {
+        self.inner.get_allowed_pubkeys().await
+    }
+
+    async fn set_pubkey_allowed(&self, pubkey: &str, allowed: bool) -> Result<()> {
+        self.inner.set_pubkey_allowed(pubkey, allowed).await
+    }
+
+    async fn get_reputation(&self, pubkey: &str) -> Result<ReputationRecord> {
+        self.inner.get_reputation(pubkey).await
+    }
+
+    async fn update_reputation(&self, pubkey: &str, change: ReputationChange) -> Result<()> {
+        self.inner.update_reputation(pubkey, change).await
+    }
+
+    async fn add_report(&self, report: &Report) -> Result<()> {
+        self.inner.add_report(report).await
+    }
+
+    async fn get_reports(&self, event_id: Option<&str>) -> Result<Vec<Report>> {
+        self.inner.get_reports(event_id).await
+    }
+
+    async fn hide_event(&self, id: &str) -> Result<bool> {
+        let res = self.inner.hide_event(id).await;
+        self.cache.lock().unwrap().clear();
+        res
+    }
+
+    async fn get_account(&self, pubkey: &str) -> Result<Option<Account>> {
+        self.inner.get_account(pubkey).await
+    }
+
+    async fn add_invoice(&self, invoice: &Invoice) -> Result<()> {
+        self.inner.add_invoice(invoice).await
+    }
+
+    async fn get_unpaid_invoices(&self) -> Result<Vec<Invoice>> {
+        self.inner.get_unpaid_invoices().await
+    }
+
+    async fn invoice_paid(&self, payment_hash: &str) -> Result<bool> {
+        self.inner.invoice_paid(payment_hash).await
+    }
+
+    async fn debit_account(&self, pubkey: &str, amount_msat: u64) -> Result<bool> {
+        self.inner.debit_account(pubkey, amount_msat).await
+    }
+
+    async fn credit_account(&self, pubkey: &str, amount_msat: u64) -> Result<()> {
+        self.inner.credit_account(pubkey, amount_msat).await
+    }
+
+    async fn optimize_db(&self) -> Result<()> {
+        self.inner.optimize_db().await
+    }
+
+    async fn compact(&self) -> Result<()> {
+        self.inner.compact().await
+    }
+
+    async fn vacuum(&self) -> Result<u64> {
+        self.inner.vacuum().await
+    }
+
+    async fn backup(&self, dest: &Path) -> Result<bool> {
+        self.inner.backup(dest).await
+    }
+
+    async fn ping(&self) -> Result<()> {
+        self.inner.ping().await
+    }
+
+    async fn check_integrity(&self) -> Result<Vec<String>> {
+        self.inner.check_integrity().await
+    }
+
+    async fn event_summaries(&self, order: ScanOrder, after: Option<&EventSummary>, limit: usize) -> Result<Vec<EventSummary>> {
+        self.inner.event_summaries(order, after, limit).await
+    }
+
+    async fn used_bytes(&self) -> Result<u64> {
+        self.inner.used_bytes().await
+    }
+
+    async fn author_bytes(&self, pubkey: &str) -> Result<u64> {
+        self.inner.author_bytes(pubkey).await
+    }
+
+    async fn delete_events(&self, ids: &[String]) -> Result<u64> {
+        let res = self.inner.delete_events(ids).await;
+        self.cache.lock().unwrap().clear();
+        res
+    }
+
+    async fn create_verification_record(&self, event_id: &str, name: &str) -> Result<()> {
+        self.inner.create_verification_record(event_id, name).await
+    }
+
+    async fn update_verification_timestamp(&self, id: u64) -> Result<()> {
+        self.inner.update_verification_timestamp(id).await
+    }
+
+    async fn fail_verification(&self, id: u64) -> Result<()> {
+        self.inner.fail_verification(id).await
+    }
+
+    async fn delete_verification(&self, id: u64) -> Result<()> {
+        self.inner.delete_verification(id).await
+    }
+
+    async fn get_latest_user_verification(&self, pub_key: &str) -> Result<VerificationRecord> {
+        self.inner.get_latest_user_verification(pub_key).await
+    }
+
+    async fn get_oldest_user_verification(&self, before: u64) -> Result<VerificationRecord> {
+        self.inner.get_oldest_user_verification(before).await
+    }
+
+    async fn put_queued_verification(&self, queued: &QueuedVerification) -> Result<()> {
+        self.inner.put_queued_verification(queued).await
+    }
+
+    async fn get_queued_verifications(&self, due: u64, limit: usize) -> Result<Vec<QueuedVerification>> {
+        self.inner.get_queued_verifications(due, limit).await
+    }
+
+    async fn remove_queued_verification(&self, pubkey: &str, event_id: &str) -> Result<()> {
+        self.inner.remove_queued_verification(pubkey, event_id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filters(s: &str) -> Vec<ReqFilter> {
+        serde_json::from_str(s).unwrap()
+    }
+
+    fn profile(pubkey: &str) -> Event {
+        let mut e = Event::simple_event();
+        e.pubkey = pubkey.to_owned();
+        e.kind = 0;
+        e.build_index();
+        e
+    }
+
+    #[test]
+    fn equivalent_filters_share_a_key() {
+        let a = filters(r##"[{"authors":["bb","aa"],"kinds":[3,0],"#p":["y","x"]},{"ids":["cc"]}]"##);
+        let b = filters(r##"[{"ids":["cc"]},{"#p":["x","y"],"kinds":[0,3],"authors":["aa","bb","aa"]}]"##);
+        assert_eq!(query_key(&a), query_key(&b));
+        assert_ne!(query_key(&a), query_key(&filters(r#"[{"authors":["aa"],"kinds":[0,3]}]"#)));
+    }
+
+    #[test]
+    fn matching_writes_invalidate_results() {
+        let mut cache = QueryCache::new(10, Duration::from_secs(60));
+        let f = filters(r#"[{"authors":["aa"],"kinds":[0]}]"#);
+        let key = query_key(&f);
+        let q = cache.begin(f.clone());
+        cache.finish(&q, key.clone(), f.clone(), Some(vec!["{}".to_owned()]));
+        assert_eq!(cache.get(&key).unwrap().len(), 1);
+        // an event from another author leaves the results alone
+        cache.invalidate(&profile("bb"));
+        assert!(cache.get(&key).is_some());
+        cache.invalidate(&profile("aa"));
+        assert!(cache.get(&key).is_none());
+
+        // results of a query that saw a matching write are not cached
+        let q = cache.begin(f.clone());
+        cache.invalidate(&profile("aa"));
+        cache.finish(&q, key.clone(), f, Some(vec![]));
+        assert!(cache.get(&key).is_none());
+    }
+
+    #[test]
+    fn least_recently_used_results_are_evicted() {
+        let mut cache = QueryCache::new(2, Duration::from_secs(60));
+        let keys: Vec<(String, Vec<ReqFilter>)> = ["aa", "bb", "cc"]
+            .iter()
+            .map(|a| filters(&format!(r#"[{{"authors":["{a}"]}}]"#)))
+            .map(|f| (query_key(&f), f))
+            .collect();
+        for (key, f) in &keys[..2] {
+            let q = cache.begin(f.clone());
+            cache.finish(&q, key.clone(), f.clone(), Some(vec![]));
+        }
+        assert!(cache.get(&keys[0].0).is_some());
+        let q = cache.begin(keys[2].1.clone());
+        cache.finish(&q, keys[2].0.clone(), keys[2].1.clone(), Some(vec![]));
+        assert!(cache.get(&keys[0].0).is_some());
+        assert!(cache.get(&keys[1].0).is_none());
+        assert!(cache.get(&keys[2].0).is_some());
+    }
+}
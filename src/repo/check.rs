@@ -0,0 +1,73 @@
+//! Check the events stored in a relay database
+use crate::error::Result;
+use crate::event::Event;
+use crate::repo::{fetch_events, NostrRepo, ScanOrder};
+use tracing::{info, warn};
+
+/// Number of events read from the database at a time.
+const BATCH_SIZE: usize = 1000;
+
+/// Results of checking stored events.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CheckStats {
+    /// Events checked
+    pub checked: u64,
+    /// Events that could not be parsed, or whose id or signature is
+    /// not valid
+    pub invalid: u64,
+    /// Invalid events removed
+    pub deleted: u64,
+    /// Problems found with the database's indexes
+    pub index_problems: u64,
+}
+
+/// Check the id and signature of every stored (non-hidden) event,
+/// reading them in batches, and then the database's own indexes.
+/// Invalid events and index problems are logged, and invalid events
+/// are removed if `delete` is set.  Index problems are left for the
+/// database's own repair tools.
+pub async fn check_events(repo: &dyn NostrRepo, delete: bool) -> Result<CheckStats> {
+    let mut stats = CheckStats::default();
+    let mut last = None;
+    loop {
+        let page = repo
+            .event_summaries(ScanOrder::OldestFirst, last.as_ref(), BATCH_SIZE)
+            .await?;
+        let ids: Vec<String> = page.iter().map(|e| e.id.clone()).collect();
+        let stored = fetch_events(repo, &ids).await?;
+        let mut events = vec![];
+        let mut invalid = vec![];
+        for (id, json) in stored {
+            match serde_json::from_str::<Event>(&json) {
+                Ok(e) => events.push(e),
+                Err(_) => invalid.push(id),
+            }
+        }
+        stats.checked += (events.len() + invalid.len()) as u64;
+        for (e, res) in events.iter().zip(Event::validate_all(&events)) {
+            if res.is_err() {
+                invalid.push(e.id.clone());
+            }
+        }
+        for id in &invalid {
+            warn!("stored event {} is not valid", id);
+        }
+        stats.invalid += invalid.len() as u64;
+        if delete && !invalid.is_empty() {
+            stats.deleted += repo.delete_events(&invalid).await?;
+        }
+        if page.len() < BATCH_SIZE {
+            break;
+        }
+        last = page.last().cloned();
+    }
+    for problem in repo.check_integrity().await? {
+        warn!("index problem: {}", problem);
+        stats.index_problems += 1;
+    }
+    info!(
+        "checked {} events ({} invalid, {} deleted), {} index problems",
+        stats.checked, stats.invalid, stats.deleted, stats.index_problems
+    );
+    Ok(stats)
+}
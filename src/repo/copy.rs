@@ -0,0 +1,161 @@
+//! Copy a relay database from `SQLite` to `PostgreSQL`
+use crate::config::Settings;
+use crate::error::{Error, Result};
+use crate::event::Event;
+use crate::groups::Group;
+use crate::nip05::{Nip05Name, VerificationRecord};
+use crate::repo::postgres::PostgresRepo;
+use crate::repo::sqlite::{build_pool, SqlitePool, SqliteRepo, DB_FILE};
+use crate::repo::NostrRepo;
+use crate::server::NostrMetrics;
+use indicatif::{ProgressBar, ProgressStyle};
+use rusqlite::OpenFlags;
+use std::path::Path;
+use std::time::Instant;
+use tokio::task;
+use tracing::{info, warn};
+
+/// Number of events read from `SQLite` at a time.
+const BATCH_SIZE: i64 = 1000;
+
+/// Counts of copied records.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CopyStats {
+    /// Events read from the source
+    pub events: u64,
+    /// Events that were not already present in the target
+    pub events_written: u64,
+    /// NIP-05 verification records
+    pub verifications: u64,
+    /// Groups (NIP-29)
+    pub groups: u64,
+}
+
+/// Read a batch of events, starting after the given row id.
+async fn read_events(pool: &SqlitePool, after: i64) -> Result<Vec<(i64, String)>> {
+    let conn = pool.get()?;
+    task::spawn_blocking(move || {
+        let mut stmt =
+            conn.prepare_cached("SELECT id, content FROM event WHERE id > ? ORDER BY id LIMIT ?;")?;
+        let rows = stmt
+            .query_map([after, BATCH_SIZE], |r| Ok((r.get(0)?, r.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<(i64, String)>>>()?;
+        Ok(rows)
+    })
+    .await?
+}
+
+/// Read all verification records, with the event they refer to.
+async fn read_verifications(pool: &SqlitePool) -> Result<Vec<VerificationRecord>> {
+    let conn = pool.get()?;
+    task::spawn_blocking(move || {
+        let mut stmt = conn.prepare(
+            "SELECT v.id, v.name, e.event_hash, e.author, e.created_at, v.verified_at, v.failed_at, v.failure_count \
+             FROM user_verification v INNER JOIN event e ON e.id = v.metadata_event ORDER BY v.id;",
+        )?;
+        let mut rows = stmt.query([])?;
+        let mut recs = vec![];
+        while let Some(r) = rows.next()? {
+            let name: String = r.get(1)?;
+            let Ok(name) = Nip05Name::try_from(name.as_str()) else {
+                warn!("skipping verification record with invalid name: {:?}", name);
+                continue;
+            };
+            recs.push(VerificationRecord {
+                rowid: r.get(0)?,
+                name,
+                event: hex::encode(r.get::<_, Vec<u8>>(2)?),
+                address: hex::encode(r.get::<_, Vec<u8>>(3)?),
+                event_created: r.get(4)?,
+                last_success: r.get(5)?,
+                last_failure: r.get(6)?,
+                failure_count: r.get::<_, Option<u64>>(7)?.unwrap_or(0),
+            });
+        }
+        Ok(recs)
+    })
+    .await?
+}
+
+/// Count the events in the source database.
+async fn count_events(pool: &SqlitePool) -> Result<u64> {
+    let conn = pool.get()?;
+    task::spawn_blocking(move || {
+        let count: u64 = conn.query_row("SELECT COUNT(*) FROM event;", [], |r| r.get(0))?;
+        Ok(count)
+    })
+    .await?
+}
+
+/// Copy all events, NIP-05 verification records, and groups from the
+/// `SQLite` database in the configured data directory into the
+/// configured `PostgreSQL` database.  Records already present in the
+/// target are left alone, so an interrupted copy can be run again.
+pub async fn sqlite_to_postgres(
+    settings: &Settings,
+    target: &PostgresRepo,
+    metrics: NostrMetrics,
+) -> Result<CopyStats> {
+    let start = Instant::now();
+    if settings.database.in_memory {
+        return Err(Error::CustomError(
+            "cannot copy from an in-memory database".to_owned(),
+        ));
+    }
+    let db_path = Path::new(&settings.database.data_directory).join(DB_FILE);
+    if !db_path.exists() {
+        return Err(Error::CustomError(format!(
+            "no SQLite database found at {}",
+            db_path.display()
+        )));
+    }
+    // bring the source schema up to date, so the queries below work.
+    let source = SqliteRepo::new(settings, metrics);
+    source.migrate_up().await?;
+    let pool = build_pool("copy-reader", settings, OpenFlags::SQLITE_OPEN_READ_ONLY, 1, 1, false);
+
+    let mut stats = CopyStats::default();
+    let bar = ProgressBar::new(count_events(&pool).await?).with_message("copying events");
+    bar.set_style(
+        ProgressStyle::with_template(
+            "[{elapsed_precise}] {bar:40.white/blue} {pos:>7}/{len:7} [{percent}%] {msg}",
+        )
+        .unwrap(),
+    );
+    // Events are copied in the order they were stored.  Replaceable
+    // events and deletions are handled by the target as they are
+    // written, just as they were when first received.
+    let mut last_id = 0;
+    loop {
+        let batch = read_events(&pool, last_id).await?;
+        if batch.is_empty() {
+            break;
+        }
+        for (id, content) in batch {
+            last_id = id;
+            bar.inc(1);
+            stats.events += 1;
+            let mut event: Event = match serde_json::from_str(&content) {
+                Ok(e) => e,
+                Err(e) => {
+                    warn!("skipping unparseable event (row {}): {:?}", id, e);
+                    continue;
+                }
+            };
+            event.update_delegation();
+            stats.events_written += target.write_event(&event).await?;
+        }
+    }
+    bar.finish();
+
+    for rec in read_verifications(&pool).await? {
+        stats.verifications += target.import_verification_record(&rec).await?;
+    }
+    let groups: Vec<Group> = source.get_groups().await?;
+    for g in &groups {
+        target.import_group(g).await?;
+        stats.groups += 1;
+    }
+    info!("copy completed in {:?}: {:?}", start.elapsed(), stats);
+    Ok(stats)
+}
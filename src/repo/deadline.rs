@@ -0,0 +1,283 @@
+//! Time limit for subscription queries
+//!
+//! Queries that are still returning results after the configured
+//! time are abandoned, and logged along with their filters, so that
+//! operators can find the clients sending expensive requests.  The
+//! client receives the results found so far, followed by EOSE.
+use crate::bans::Ban;
+use crate::db::QueryResult;
+use crate::error::Result;
+use crate::event::Event;
+use crate::groups::{Group, GroupUpdate};
+use crate::nip05::{QueuedVerification, VerificationRecord};
+use crate::payment::{Account, Invoice};
+use crate::repo::{EventSummary, NostrRepo, ScanOrder};
+use crate::reports::Report;
+use crate::reputation::{ReputationChange, ReputationRecord};
+use crate::server::NostrMetrics;
+use crate::subscription::{ReqFilter, Subscription};
+use async_trait::async_trait;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// A repository that abandons queries running longer than a limit.
+/// Everything else is passed through to the database.
+pub struct DeadlineRepo {
+    inner: Arc<dyn NostrRepo>,
+    max_query_time: Duration,
+    metrics: NostrMetrics,
+}
+
+impl DeadlineRepo {
+    pub fn new(inner: Arc<dyn NostrRepo>, max_query_time: Duration, metrics: NostrMetrics) -> Self {
+        DeadlineRepo {
+            inner,
+            max_query_time,
+            metrics,
+        }
+    }
+}
+
+#[async_trait]
+impl NostrRepo for DeadlineRepo {
+    async fn start(&self) -> Result<()> {
+        self.inner.start().await
+    }
+
+    async fn migrate_up(&self) -> Result<usize> {
+        self.inner.migrate_up().await
+    }
+
+    async fn write_event(&self, e: &Event) -> Result<u64> {
+        self.inner.write_event(e).await
+    }
+
+    async fn write_events(&self, events: &[Event]) -> Result<Vec<u64>> {
+        self.inner.write_events(events).await
+    }
+
+    async fn query_subscription(
+        &self,
+        sub: Subscription,
+        client_id: String,
+        query_tx: tokio::sync::mpsc::Sender<QueryResult>,
+        mut abandon_query_rx: tokio::sync::oneshot::Receiver<()>,
+    ) -> Result<()> {
+        let start = Instant::now();
+        let deadline = tokio::time::Instant::from_std(start + self.max_query_time);
+        let (db_tx, mut db_rx) = tokio::sync::mpsc::channel::<QueryResult>(query_tx.max_capacity());
+        let (db_abandon_tx, db_abandon_rx) = tokio::sync::oneshot::channel();
+        self.inner
+            .query_subscription(sub.clone(), client_id.clone(), db_tx, db_abandon_rx)
+            .await?;
+        let metrics = self.metrics.clone();
+        tokio::spawn(async move {
+            // any client that doesn't accept results for 2 seconds
+            // gets dropped.
+            let abort_cutoff = Duration::from_secs(2);
+            // the client may drop its abandon channel without using it.
+            let mut can_abandon = true;
+            loop {
+                tokio::select! {
+                    res = db_rx.recv() => {
+                        let Some(res) = res else {
+                            return;
+                        };
+                        let eose = res.event == "EOSE";
+                        if tokio::time::timeout(abort_cutoff, query_tx.send(res)).await.is_err() {
+                            info!("aborting query due to slow client (cid: {}, sub: {:?})", client_id, sub.id);
+                            db_abandon_tx.send(()).ok();
+                            return;
+                        }
+                        if eose {
+                            return;
+                        }
+                    },
+                    abandoned = &mut abandon_query_rx, if can_abandon => {
+                        if abandoned.is_ok() {
+                            db_abandon_tx.send(()).ok();
+                            return;
+                        }
+                        can_abandon = false;
+                    },
+                    () = tokio::time::sleep_until(deadline) => break,
+                }
+            }
+            db_abandon_tx.send(()).ok();
+            metrics.query_aborts.with_label_values(&["timeout"]).inc();
+            warn!(
+                "aborting query running for {:?} (cid: {}, sub: {:?}, filters: {})",
+                start.elapsed(),
+                client_id,
+                sub.id,
+                serde_json::to_string(&sub.filters).unwrap_or_default()
+            );
+            query_tx
+                .send(QueryResult {
+                    sub_id: sub.get_id(),
+                    event: "EOSE".to_string(),
+                })
+                .await
+                .ok();
+        });
+        Ok(())
+    }
+
+    async fn count_events_by_filter(&self, filters: Vec<ReqFilter>) -> Result<u64> {
+        self.inner.count_events_by_filter(filters).await
+    }
+
+    async fn is_event_deleted(&self, id: &str) -> Result<bool> {
+        self.inner.is_event_deleted(id).await
+    }
+
+    async fn get_relay_list(&self, pub_key: &str) -> Result<Option<Event>> {
+        self.inner.get_relay_list(pub_key).await
+    }
+
+    async fn apply_group_update(&self, update: &GroupUpdate) -> Result<()> {
+        self.inner.apply_group_update(update).await
+    }
+
+    async fn get_groups(&self) -> Result<Vec<Group>> {
+        self.inner.get_groups().await
+    }
+
+    async fn add_ban(&self, ban: &Ban) -> Result<()> {
+        self.inner.add_ban(ban).await
+    }
+
+    async fn get_bans(&self) -> Result<Vec<Ban>> {
+        self.inner.get_bans().await
+    }
+
+    async fn get_allowed_pubkeys(&self) -> Result<Vec<String>> {
+        self.inner.get_allowed_pubkeys().await
+    }
+
+    async fn set_pubkey_allowed(&self, pubkey: &str, allowed: bool) -> Result<()> {
+        self.inner.set_pubkey_allowed(pubkey, allowed).await
+    }
+
+    async fn get_reputation(&self, pubkey: &str) -> Result<ReputationRecord> {
+        self.inner.get_reputation(pubkey).await
+    }
+
+    async fn update_reputation(&self, pubkey: &str, change: ReputationChange) -> Result<()> {
+        self.inner.update_reputation(pubkey, change).await
+    }
+
+    async fn add_report(&self, report: &Report) -> Result<()> {
+        self.inner.add_report(report).await
+    }
+
+    async fn get_reports(&self, event_id: Option<&str>) -> Result<Vec<Report>> {
+        self.inner.get_reports(event_id).await
+    }
+
+    async fn hide_event(&self, id: &str) -> Result<bool> {
+        self.inner.hide_event(id).await
+    }
+
+    async fn get_account(&self, pubkey: &str) -> Result<Option<Account>> {
+        self.inner.get_account(pubkey).await
+    }
+
+    async fn add_invoice(&self, invoice: &Invoice) -> Result<()> {
+        self.inner.add_invoice(invoice).await
+    }
+
+    async fn get_unpaid_invoices(&self) -> Result<Vec<Invoice>> {
+        self.inner.get_unpaid_invoices().await
+    }
+
+    async fn invoice_paid(&self, payment_hash: &str) -> Result<bool> {
+        self.inner.invoice_paid(payment_hash).await
+    }
+
+    async fn debit_account(&self, pubkey: &str, amount_msat: u64) -> Result<bool> {
+        self.inner.debit_account(pubkey, amount_msat).await
+    }
+
+    async fn credit_account(&self, pubkey: &str, amount_msat: u64) -> Result<()> {
+        self.inner.credit_account(pubkey, amount_msat).await
+    }
+
+    async fn optimize_db(&self) -> Result<()> {
+        self.inner.optimize_db().await
+    }
+
+    async fn compact(&self) -> Result<()> {
+        self.inner.compact().await
+    }
+
+    async fn vacuum(&self) -> Result<u64> {
+        self.inner.vacuum().await
+    }
+
+    async fn backup(&self, dest: &Path) -> Result<bool> {
+        self.inner.backup(dest).await
+    }
+
+    async fn ping(&self) -> Result<()> {
+        self.inner.ping().await
+    }
+
+    async fn check_integrity(&self) -> Result<Vec<String>> {
+        self.inner.check_integrity().await
+    }
+
+    async fn event_summaries(&self, order: ScanOrder, after: Option<&EventSummary>, limit: usize) -> Result<Vec<EventSummary>> {
+        self.inner.event_summaries(order, after, limit).await
+    }
+
+    async fn used_bytes(&self) -> Result<u64> {
+        self.inner.used_bytes().await
+    }
+
+    async fn author_bytes(&self, pubkey: &str) -> Result<u64> {
+        self.inner.author_bytes(pubkey).await
+    }
+
+    async fn delete_events(&self, ids: &[String]) -> Result<u64> {
+        self.inner.delete_events(ids).await
+    }
+
+    async fn create_verification_record(&self, event_id: &str, name: &str) -> Result<()> {
+        self.inner.create_verification_record(event_id, name).await
+    }
+
+    async fn update_verification_timestamp(&self, id: u64) -> Result<()> {
+        self.inner.update_verification_timestamp(id).await
+    }
+
+    async fn fail_verification(&self, id: u64) -> Result<()> {
+        self.inner.fail_verification(id).await
+    }
+
+    async fn delete_verification(&self, id: u64) -> Result<()> {
+        self.inner.delete_verification(id).await
+    }
+
+    async fn get_latest_user_verification(&self, pub_key: &str) -> Result<VerificationRecord> {
+        self.inner.get_latest_user_verification(pub_key).await
+    }
+
+    async fn get_oldest_user_verification(&self, before: u64) -> Result<VerificationRecord> {
+        self.inner.get_oldest_user_verification(before).await
+    }
+
+    async fn put_queued_verification(&self, queued: &QueuedVerification) -> Result<()> {
+        self.inner.put_queued_verification(queued).await
+    }
+
+    async fn get_queued_verifications(&self, due: u64, limit: usize) -> Result<Vec<QueuedVerification>> {
+        self.inner.get_queued_verifications(due, limit).await
+    }
+
+    async fn remove_queued_verification(&self, pubkey: &str, event_id: &str) -> Result<()> {
+        self.inner.remove_queued_verification(pubkey, event_id).await
+    }
+}
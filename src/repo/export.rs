@@ -0,0 +1,93 @@
+//! Export events from a relay database as JSON lines
+use crate::error::Result;
+use crate::repo::{fetch_events, EventSummary, NostrRepo, ScanOrder};
+use std::io::Write;
+
+/// Number of events read from the database at a time.
+const BATCH_SIZE: usize = 1000;
+
+/// Which events to export.  Times are inclusive.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ExportFilter {
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub kinds: Option<Vec<u64>>,
+}
+
+impl ExportFilter {
+    fn matches(&self, e: &EventSummary) -> bool {
+        self.since.is_none_or(|t| e.created_at >= t)
+            && self.until.is_none_or(|t| e.created_at <= t)
+            && self.kinds.as_ref().is_none_or(|k| k.contains(&e.kind))
+    }
+}
+
+/// Write matching events to `out`, oldest first, one JSON event per
+/// line.  Events are read in batches, so the whole database is never
+/// held in memory.  Hidden (deleted) events are not exported.
+/// Returns the number of events written.
+pub async fn export_events(repo: &dyn NostrRepo, filter: &ExportFilter, out: &mut impl Write) -> Result<u64> {
+    let mut written = 0;
+    // start just before the first event created at `since`.
+    let mut last = filter.since.map(|t| EventSummary {
+        id: String::new(),
+        pubkey: String::new(),
+        kind: 0,
+        created_at: t,
+    });
+    loop {
+        let page = repo
+            .event_summaries(ScanOrder::OldestFirst, last.as_ref(), BATCH_SIZE)
+            .await?;
+        let done = page.len() < BATCH_SIZE || page.last().is_some_and(|e| filter.until.is_some_and(|t| e.created_at > t));
+        let ids: Vec<String> = page
+            .iter()
+            .filter(|e| filter.matches(e))
+            .map(|e| e.id.clone())
+            .collect();
+        if !ids.is_empty() {
+            let events = fetch_events(repo, &ids).await?;
+            for id in &ids {
+                if let Some(json) = events.get(id) {
+                    out.write_all(json.as_bytes())?;
+                    out.write_all(b"\n")?;
+                    written += 1;
+                }
+            }
+        }
+        if done {
+            out.flush()?;
+            return Ok(written);
+        }
+        last = page.last().cloned();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(kind: u64, created_at: u64) -> EventSummary {
+        EventSummary {
+            id: "a".repeat(64),
+            pubkey: "b".repeat(64),
+            kind,
+            created_at,
+        }
+    }
+
+    #[test]
+    fn filter_bounds_are_inclusive() {
+        let filter = ExportFilter {
+            since: Some(100),
+            until: Some(200),
+            kinds: Some(vec![1, 7]),
+        };
+        assert!(filter.matches(&summary(1, 100)));
+        assert!(filter.matches(&summary(7, 200)));
+        assert!(!filter.matches(&summary(1, 99)));
+        assert!(!filter.matches(&summary(1, 201)));
+        assert!(!filter.matches(&summary(0, 150)));
+        assert!(ExportFilter::default().matches(&summary(0, 0)));
+    }
+}
@@ -0,0 +1,139 @@
+//! Import events into a relay database from JSON lines
+use crate::error::Result;
+use crate::event::Event;
+use crate::repo::NostrRepo;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::io::BufRead;
+use std::time::Instant;
+use tracing::{debug, info, warn};
+
+/// Counts of imported events.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ImportStats {
+    /// Valid events read from the input
+    pub events: u64,
+    /// Events that were not already present
+    pub written: u64,
+    /// Lines that were not valid events
+    pub invalid: u64,
+    /// Events that could not be written
+    pub failed: u64,
+}
+
+/// Parse an event from a line of input.  The event id and signature
+/// are checked later, with the rest of its batch.
+fn parse_event(line: &str) -> Option<Event> {
+    let mut e: Event = serde_json::from_str(line).ok()?;
+    e.build_index();
+    e.update_delegation();
+    // a delegation tag that does not validate is an invalid event,
+    // not an undelegated one.
+    if e.delegated_by.is_none() && e.has_delegation_tag() {
+        return None;
+    }
+    Some(e)
+}
+
+/// Remove events with an invalid id or signature from a batch,
+/// verifying their signatures together.
+fn retain_valid(batch: &mut Vec<Event>, stats: &mut ImportStats) {
+    let mut valid = Event::validate_all(batch).into_iter().map(|r| r.is_ok());
+    let before = batch.len();
+    batch.retain(|_| valid.next().unwrap_or(false));
+    stats.invalid += (before - batch.len()) as u64;
+}
+
+/// Write a batch of events, verified first if `verify` is set.  If
+/// the batch fails, its events are retried one at a time, so a single
+/// bad event does not lose the rest.
+async fn write_batch(repo: &dyn NostrRepo, batch: &mut Vec<Event>, verify: bool, stats: &mut ImportStats) {
+    if verify {
+        retain_valid(batch, stats);
+    }
+    stats.events += batch.len() as u64;
+    match repo.write_events(batch).await {
+        Ok(counts) => stats.written += counts.iter().sum::<u64>(),
+        Err(err) => {
+            warn!("batch write failed ({:?}), writing events individually", err);
+            for e in batch {
+                match repo.write_event(e).await {
+                    Ok(n) => stats.written += n,
+                    Err(err) => {
+                        warn!("could not write event {:?}: {:?}", e.get_event_id_prefix(), err);
+                        stats.failed += 1;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Read events from `input`, one JSON event per line, and write them
+/// to the repository in transactions of `batch_size` events.  Events
+/// do not pass through the websocket, rate limit, or authorization
+/// checks, and ephemeral events are ignored.
+pub async fn import_events(
+    repo: &dyn NostrRepo,
+    input: impl BufRead,
+    verify: bool,
+    batch_size: usize,
+) -> Result<ImportStats> {
+    let start = Instant::now();
+    let batch_size = batch_size.max(1);
+    let mut stats = ImportStats::default();
+    let mut batch = Vec::with_capacity(batch_size);
+    let bar = ProgressBar::new_spinner().with_message("importing events");
+    bar.set_style(ProgressStyle::with_template("[{elapsed_precise}] {spinner} {pos:>7} {msg}").unwrap());
+    for (n, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse_event(&line) {
+            Some(e) if e.is_ephemeral() => {}
+            Some(e) => batch.push(e),
+            None => {
+                debug!("ignoring invalid event on line {}", n + 1);
+                stats.invalid += 1;
+            }
+        }
+        bar.inc(1);
+        if batch.len() >= batch_size {
+            write_batch(repo, &mut batch, verify, &mut stats).await;
+            batch.clear();
+        }
+    }
+    if !batch.is_empty() {
+        write_batch(repo, &mut batch, verify, &mut stats).await;
+    }
+    bar.finish();
+    info!(
+        "imported {} events ({} new, {} invalid, {} failed) in {:?}",
+        stats.events,
+        stats.written,
+        stats.invalid,
+        stats.failed,
+        start.elapsed()
+    );
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIGNED: &str = r#"{"id":"1384757da583e6129ce831c3d7afc775a33a090578f888dd0d010328ad047d0c","pubkey":"bbbd9711d357df4f4e498841fd796535c95c8e751fa35355008a911c41265fca","created_at":1612650459,"kind":1,"tags":null,"content":"hello world","sig":"59d0cc47ab566e81f72fe5f430bcfb9b3c688cb0093d1e6daa49201c00d28ecc3651468b7938642869ed98c0f1b262998e49a05a6ed056c0d92b193f4e93bc21"}"#;
+
+    #[test]
+    fn invalid_events_are_dropped() {
+        // tampered content no longer matches the id and signature
+        let tampered = SIGNED.replace("hello world", "goodbye");
+        let mut batch = vec![parse_event(SIGNED).unwrap(), parse_event(&tampered).unwrap()];
+        let mut stats = ImportStats::default();
+        retain_valid(&mut batch, &mut stats);
+        assert_eq!(batch.len(), 1);
+        assert_eq!(batch[0].content, "hello world");
+        assert_eq!(stats.invalid, 1);
+        assert!(parse_event("not json").is_none());
+    }
+}
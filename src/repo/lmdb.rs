@@ -0,0 +1,1536 @@
+//! Event persistence and querying, using LMDB
+//!
+//! Events are stored as JSON, keyed by a sequence number assigned at
+//! insertion.  Queries are answered from one of several indexes, each
+//! of which has keys ending in the event `created_at` and sequence
+//! number, so that a range scan over a key prefix returns events in
+//! time order:
+//!
+//! * `created`: every visible event
+//! * `pubkey`: author (and delegator) pubkey
+//! * `pubkey_kind`: author (and delegator) pubkey and kind
+//! * `kind`: event kind
+//! * `tag`: single-letter tag name and value
+//!
+//! Events are located by id through the `ids` table.  Deleted (NIP-09)
+//! events are removed from the indexes but retained, as in the
+//! `SQLite` repository.
+use crate::bans::Ban;
+use crate::config::Settings;
+use crate::db::QueryResult;
+use crate::error::{Error, Result};
+use crate::event::{single_char_tagname, Event};
+use crate::groups::{Group, GroupRole, GroupUpdate};
+use crate::nip05::{Nip05Name, QueuedVerification, VerificationRecord};
+use crate::nip65::KIND_RELAY_LIST;
+use crate::payment::{Account, Invoice};
+use crate::repo::planner::{self, Access};
+use crate::repo::{no_rows, now_jitter, EventSummary, NostrRepo, ScanOrder};
+use crate::reports::Report;
+use crate::reputation::{ReputationChange, ReputationRecord};
+use crate::server::NostrMetrics;
+use crate::subscription::{ReqFilter, Subscription};
+use crate::utils::{is_hex, unix_time};
+use async_trait::async_trait;
+use heed::types::{Bytes, Str, Unit};
+use heed::{Database, Env, EnvOpenOptions, RoTxn, RwTxn};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::ops::Bound;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::task;
+use tracing::{debug, info, trace};
+
+/// Directory (under the data directory) holding the LMDB environment.
+pub const LMDB_DIR: &str = "nostr.lmdb";
+
+/// Maximum size of the database.  This is only reserved address
+/// space; the file grows as events are added.
+const MAP_SIZE: usize = 1 << 40;
+
+/// Current version of the table layout.
+const DB_VERSION: usize = 1;
+
+/// An index, with keys ending in `created_at` and sequence number.
+type Index = Database<Bytes, Unit>;
+
+/// Handles to every table in the environment.
+#[derive(Clone, Copy)]
+struct Tables {
+    /// Counters and the layout version
+    meta: Database<Str, Bytes>,
+    /// Sequence number to event JSON
+    events: Database<Bytes, Str>,
+    /// Event id to sequence number
+    ids: Database<Bytes, Bytes>,
+    /// Ids of deleted events
+    hidden: Database<Bytes, Unit>,
+    /// Deletion requests, keyed by target event id and author
+    deletions: Database<Bytes, Unit>,
+    created: Index,
+    pubkey: Index,
+    pubkey_kind: Index,
+    kind: Index,
+    tag: Index,
+    /// Group id to members
+    groups: Database<Str, Str>,
+    /// Target and value ("ip:1.2.3.4") to ban
+    bans: Database<Str, Str>,
+    /// Pubkeys in the whitelist
+    allowed: Database<Str, Unit>,
+    /// Pubkey to reputation
+    reputation: Database<Str, Str>,
+    /// Reported event, pubkey and reporter ("id:pubkey:reporter", with
+    /// an empty id for reports of a pubkey) to report
+    reports: Database<Str, Str>,
+    /// Pubkey to account
+    accounts: Database<Str, Str>,
+    /// Payment hash to invoice
+    invoices: Database<Str, Str>,
+    /// Verification row id to record
+    verifications: Database<Bytes, Str>,
+    /// Pubkey and verification row id
+    verification_pubkey: Database<Bytes, Unit>,
+    /// Pubkey to metadata event waiting for verification
+    verification_queue: Database<Str, Str>,
+}
+
+/// A NIP-05 verification record, as stored.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct StoredVerification {
+    name: String,
+    event: String,
+    address: String,
+    event_created: u64,
+    verified_at: Option<u64>,
+    failed_at: Option<u64>,
+    failure_count: u64,
+}
+
+impl StoredVerification {
+    fn to_record(&self, rowid: u64) -> Option<VerificationRecord> {
+        Some(VerificationRecord {
+            rowid,
+            name: Nip05Name::try_from(self.name.as_str()).ok()?,
+            address: self.address.clone(),
+            event: self.event.clone(),
+            event_created: self.event_created,
+            last_success: self.verified_at,
+            last_failure: self.failed_at,
+            failure_count: self.failure_count,
+        })
+    }
+}
+
+/// Index key suffix; sorts by time, then insertion order.
+fn time_suffix(created_at: u64, seq: u64) -> [u8; 16] {
+    let mut k = [0; 16];
+    k[..8].copy_from_slice(&created_at.to_be_bytes());
+    k[8..].copy_from_slice(&seq.to_be_bytes());
+    k
+}
+
+/// Read the `created_at` and sequence number from an index key.
+fn split_suffix(key: &[u8]) -> Option<(u64, u64)> {
+    let n = key.len().checked_sub(16)?;
+    let created_at = u64::from_be_bytes(key[n..n + 8].try_into().ok()?);
+    let seq = u64::from_be_bytes(key[n + 8..].try_into().ok()?);
+    Some((created_at, seq))
+}
+
+fn kind_prefix(kind: u64) -> Vec<u8> {
+    kind.to_be_bytes().to_vec()
+}
+
+fn pubkey_kind_prefix(pubkey: &[u8], kind: u64) -> Vec<u8> {
+    let mut k = pubkey.to_vec();
+    k.extend_from_slice(&kind.to_be_bytes());
+    k
+}
+
+/// Tag names and values are length-prefixed, so that a prefix of one
+/// value never matches another.
+fn tag_prefix(name: &str, value: &str) -> Vec<u8> {
+    let mut k = Vec::with_capacity(name.len() + value.len() + 5);
+    k.push(name.len() as u8);
+    k.extend_from_slice(name.as_bytes());
+    k.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    k.extend_from_slice(value.as_bytes());
+    k
+}
+
+/// Convert a hex prefix to bytes.  An odd trailing digit is dropped,
+/// so the byte prefix may match more than the hex prefix.  Returns
+/// whether the prefix is a complete 32-byte value.
+fn hex_prefix(prefix: &str) -> Option<(Vec<u8>, bool)> {
+    if !is_hex(prefix) {
+        return None;
+    }
+    let even = &prefix[..prefix.len() - prefix.len() % 2];
+    hex::decode(even).ok().map(|b| (b, prefix.len() == 64))
+}
+
+fn seq_key(seq: u64) -> [u8; 8] {
+    seq.to_be_bytes()
+}
+
+/// A key prefix in an index to scan.  When `exact`, every key is the
+/// prefix followed by the time suffix, so a range scan returns events
+/// in time order.
+struct Source {
+    index: Index,
+    prefix: Vec<u8>,
+    exact: bool,
+}
+
+#[derive(Clone)]
+pub struct LmdbRepo {
+    env: Env,
+    tables: Tables,
+    metrics: NostrMetrics,
+}
+
+impl LmdbRepo {
+    /// Open (or create) the environment in the data directory.
+    pub fn new(settings: &Settings, metrics: NostrMetrics) -> Result<LmdbRepo> {
+        let path = Path::new(&settings.database.data_directory).join(LMDB_DIR);
+        std::fs::create_dir_all(&path).map_err(|_| Error::DatabaseDirError)?;
+        // the environment must not be opened twice in one process;
+        // the repository is built once, and cloned.
+        let env = unsafe {
+            EnvOpenOptions::new()
+                .map_size(MAP_SIZE)
+                .max_dbs(32)
+                .max_readers(1024)
+                .open(&path)?
+        };
+        let mut txn = env.write_txn()?;
+        let tables = Tables {
+            meta: env.create_database(&mut txn, Some("meta"))?,
+            events: env.create_database(&mut txn, Some("events"))?,
+            ids: env.create_database(&mut txn, Some("ids"))?,
+            hidden: env.create_database(&mut txn, Some("hidden"))?,
+            deletions: env.create_database(&mut txn, Some("deletions"))?,
+            created: env.create_database(&mut txn, Some("created"))?,
+            pubkey: env.create_database(&mut txn, Some("pubkey"))?,
+            pubkey_kind: env.create_database(&mut txn, Some("pubkey_kind"))?,
+            kind: env.create_database(&mut txn, Some("kind"))?,
+            tag: env.create_database(&mut txn, Some("tag"))?,
+            groups: env.create_database(&mut txn, Some("groups"))?,
+            bans: env.create_database(&mut txn, Some("bans"))?,
+            allowed: env.create_database(&mut txn, Some("allowed"))?,
+            reputation: env.create_database(&mut txn, Some("reputation"))?,
+            reports: env.create_database(&mut txn, Some("reports"))?,
+            accounts: env.create_database(&mut txn, Some("accounts"))?,
+            invoices: env.create_database(&mut txn, Some("invoices"))?,
+            verifications: env.create_database(&mut txn, Some("verifications"))?,
+            verification_pubkey: env.create_database(&mut txn, Some("verification_pubkey"))?,
+            verification_queue: env.create_database(&mut txn, Some("verification_queue"))?,
+        };
+        txn.commit()?;
+        info!("opened LMDB environment at {:?}", path);
+        Ok(LmdbRepo {
+            env,
+            tables,
+            metrics,
+        })
+    }
+
+    /// Increment and return a counter from the meta table.
+    fn next_counter(&self, txn: &mut RwTxn, name: &str) -> Result<u64> {
+        let current = match self.tables.meta.get(txn, name)? {
+            Some(b) => u64::from_be_bytes(b.try_into().map_err(|_| Error::UnknownError)?),
+            None => 0,
+        };
+        let next = current + 1;
+        self.tables.meta.put(txn, name, &next.to_be_bytes())?;
+        Ok(next)
+    }
+
+    /// Every index key for an event.
+    fn index_entries(&self, e: &Event, seq: u64) -> Vec<(Index, Vec<u8>)> {
+        let t = &self.tables;
+        let suffix = time_suffix(e.created_at, seq);
+        let with_suffix = |mut k: Vec<u8>| {
+            k.extend_from_slice(&suffix);
+            k
+        };
+        let mut entries = vec![
+            (t.created, suffix.to_vec()),
+            (t.kind, with_suffix(kind_prefix(e.kind))),
+        ];
+        // delegated events are found under both pubkeys, just as
+        // filters match either.
+        let mut pubkeys = vec![&e.pubkey];
+        if let Some(d) = &e.delegated_by {
+            pubkeys.push(d);
+        }
+        for pk in pubkeys.into_iter().filter_map(|p| hex::decode(p).ok()) {
+            entries.push((t.pubkey_kind, with_suffix(pubkey_kind_prefix(&pk, e.kind))));
+            entries.push((t.pubkey, with_suffix(pk)));
+        }
+        let mut tags = HashSet::new();
+        for tag in e.tags.iter().filter(|t| t.len() >= 2) {
+            if single_char_tagname(&tag[0]).is_some() && tags.insert((&tag[0], &tag[1])) {
+                entries.push((t.tag, with_suffix(tag_prefix(&tag[0], &tag[1]))));
+            }
+        }
+        // parameterized replaceable events without a `d` value are
+        // keyed by the empty string.
+        if e.distinct_param().as_deref() == Some("") && tags.insert((&"d".to_owned(), &String::new())) {
+            entries.push((t.tag, with_suffix(tag_prefix("d", ""))));
+        }
+        entries
+    }
+
+    /// Load and parse the event with a given sequence number.
+    fn load_event(&self, txn: &RoTxn, seq: u64) -> Result<Option<Event>> {
+        match self.tables.events.get(txn, &seq_key(seq))? {
+            Some(json) => {
+                let mut e: Event = serde_json::from_str(json)?;
+                e.build_index();
+                Ok(Some(e))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Sequence number of an event, by id.
+    fn lookup_seq(&self, txn: &RoTxn, id: &[u8]) -> Result<Option<u64>> {
+        Ok(self
+            .tables
+            .ids
+            .get(txn, id)?
+            .and_then(|b| b.try_into().ok())
+            .map(u64::from_be_bytes))
+    }
+
+    fn insert_event(&self, txn: &mut RwTxn, e: &Event, id: &[u8], seq: u64) -> Result<()> {
+        let json = serde_json::to_string(e)?;
+        self.tables.events.put(txn, &seq_key(seq), &json)?;
+        self.tables.ids.put(txn, id, &seq_key(seq))?;
+        for (index, key) in self.index_entries(e, seq) {
+            index.put(txn, &key, &())?;
+        }
+        Ok(())
+    }
+
+    /// Remove an event from the indexes, and mark it deleted.
+    fn hide_event(&self, txn: &mut RwTxn, e: &Event, id: &[u8], seq: u64) -> Result<()> {
+        for (index, key) in self.index_entries(e, seq) {
+            index.delete(txn, &key)?;
+        }
+        self.tables.hidden.put(txn, id, &())?;
+        Ok(())
+    }
+
+    /// Remove an event entirely, along with any verification records
+    /// that refer to it.
+    fn remove_event(&self, txn: &mut RwTxn, e: &Event, seq: u64) -> Result<()> {
+        let t = &self.tables;
+        let id = hex::decode(&e.id)?;
+        for (index, key) in self.index_entries(e, seq) {
+            index.delete(txn, &key)?;
+        }
+        t.events.delete(txn, &seq_key(seq))?;
+        t.ids.delete(txn, &id)?;
+        t.hidden.delete(txn, &id)?;
+        let pubkey = hex::decode(&e.pubkey)?;
+        let mut orphaned = vec![];
+        for item in t.verification_pubkey.prefix_iter(txn, &pubkey)? {
+            let (key, _) = item?;
+            let rowid = key[key.len() - 8..].to_vec();
+            if let Some(json) = t.verifications.get(txn, &rowid)? {
+                let v: StoredVerification = serde_json::from_str(json)?;
+                if v.event == e.id {
+                    orphaned.push((key.to_vec(), rowid));
+                }
+            }
+        }
+        for (key, rowid) in orphaned {
+            t.verification_pubkey.delete(txn, &key)?;
+            t.verifications.delete(txn, &rowid)?;
+        }
+        Ok(())
+    }
+
+    /// Existing events that the given (parameterized) replaceable
+    /// event would replace; same author, kind, and `d` value.
+    fn replaceable_versions(&self, txn: &RoTxn, e: &Event, author: &[u8]) -> Result<Vec<(Event, u64)>> {
+        let prefix = pubkey_kind_prefix(author, e.kind);
+        let d_tag = e.distinct_param();
+        let mut found = vec![];
+        for item in self.tables.pubkey_kind.prefix_iter(txn, &prefix)? {
+            let (key, _) = item?;
+            let Some((_, seq)) = split_suffix(key) else { continue };
+            if let Some(other) = self.load_event(txn, seq)? {
+                if other.pubkey == e.pubkey && other.distinct_param() == d_tag {
+                    found.push((other, seq));
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    /// Summaries of stored events.  Hidden events are not in the time
+    /// index, so are read from their own table and merged in.
+    fn summaries(&self, order: ScanOrder, after: Option<&EventSummary>, limit: usize) -> Result<Vec<EventSummary>> {
+        let txn = self.env.read_txn()?;
+        let newest_first = order == ScanOrder::NewestFirst;
+        let bound = match (after, newest_first) {
+            (Some(a), true) => Bound::Included(time_suffix(a.created_at, u64::MAX)),
+            (Some(a), false) => Bound::Included(time_suffix(a.created_at, 0)),
+            (None, _) => Bound::Unbounded,
+        };
+        let bound = bound.as_ref().map(|k| &k[..]);
+        let items: Box<dyn Iterator<Item = heed::Result<(&[u8], ())>>> = if newest_first {
+            Box::new(self.tables.created.rev_range(&txn, &(Bound::Unbounded, bound))?)
+        } else {
+            Box::new(self.tables.created.range(&txn, &(bound, Bound::Unbounded))?)
+        };
+        let is_after = |e: &Event| {
+            after.is_none_or(|a| {
+                let (key, after_key) = ((e.created_at, &e.id), (a.created_at, &a.id));
+                if newest_first { key < after_key } else { key > after_key }
+            })
+        };
+        let summary = |e: Event| EventSummary {
+            id: e.id,
+            pubkey: e.pubkey,
+            kind: e.kind,
+            created_at: e.created_at,
+        };
+        let mut found: Vec<EventSummary> = vec![];
+        for item in items {
+            let (key, _) = item?;
+            let Some((created_at, seq)) = split_suffix(key) else { continue };
+            // the index orders events with the same timestamp by
+            // sequence, not id; read all of them before sorting.
+            if found.len() >= limit && found.last().is_some_and(|l| l.created_at != created_at) {
+                break;
+            }
+            let Some(e) = self.load_event(&txn, seq)? else { continue };
+            if is_after(&e) {
+                found.push(summary(e));
+            }
+        }
+        // any hidden event ordered after `after` may be among the
+        // first `limit`; the others sort after those read above.
+        for item in self.tables.hidden.iter(&txn)? {
+            let (id, ()) = item?;
+            let Some(seq) = self.lookup_seq(&txn, id)? else { continue };
+            let Some(e) = self.load_event(&txn, seq)? else { continue };
+            if is_after(&e) {
+                found.push(summary(e));
+            }
+        }
+        found.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+        if newest_first {
+            found.reverse();
+        }
+        found.truncate(limit);
+        Ok(found)
+    }
+
+    fn remove_events(&self, ids: &[String]) -> Result<u64> {
+        let mut txn = self.env.write_txn()?;
+        let mut count = 0;
+        for id in ids.iter().filter_map(|id| hex::decode(id).ok()) {
+            let Some(seq) = self.lookup_seq(&txn, &id)? else { continue };
+            if let Some(e) = self.load_event(&txn, seq)? {
+                self.remove_event(&mut txn, &e, seq)?;
+                count += 1;
+            }
+        }
+        txn.commit()?;
+        Ok(count)
+    }
+
+    /// Find ids that do not lead to their event, and index entries
+    /// for events that are not stored.
+    fn integrity_problems(&self) -> Result<Vec<String>> {
+        let t = self.tables;
+        let txn = self.env.read_txn()?;
+        let mut problems = vec![];
+        for item in t.events.iter(&txn)? {
+            let (seq, json) = item?;
+            let Ok(e) = serde_json::from_str::<Event>(json) else {
+                problems.push(format!("event {seq:?} cannot be parsed"));
+                continue;
+            };
+            let indexed = hex::decode(&e.id).ok().map(|id| t.ids.get(&txn, &id)).transpose()?;
+            if indexed.flatten() != Some(seq) {
+                problems.push(format!("event {} is not found by its id", e.id));
+            }
+        }
+        for item in t.ids.iter(&txn)? {
+            let (id, seq) = item?;
+            if t.events.get(&txn, seq)?.is_none() {
+                problems.push(format!("id {} refers to a missing event", hex::encode(id)));
+            }
+        }
+        let indexes = [
+            ("created", t.created),
+            ("pubkey", t.pubkey),
+            ("pubkey_kind", t.pubkey_kind),
+            ("kind", t.kind),
+            ("tag", t.tag),
+        ];
+        for (name, index) in indexes {
+            let mut missing = 0;
+            for item in index.iter(&txn)? {
+                let (key, ()) = item?;
+                let found = match split_suffix(key) {
+                    Some((_, seq)) => t.events.get(&txn, &seq_key(seq))?.is_some(),
+                    None => false,
+                };
+                if !found {
+                    missing += 1;
+                }
+            }
+            if missing > 0 {
+                problems.push(format!("{missing} {name} index entries refer to missing events"));
+            }
+        }
+        Ok(problems)
+    }
+
+    fn persist_event(&self, e: &Event) -> Result<u64> {
+        Ok(self.persist_events(std::slice::from_ref(e))?.iter().sum())
+    }
+
+    /// Persist a batch of events in a single transaction.
+    fn persist_events(&self, events: &[Event]) -> Result<Vec<u64>> {
+        let mut txn = self.env.write_txn()?;
+        let mut counts = Vec::with_capacity(events.len());
+        for e in events {
+            counts.push(self.persist_in(&mut txn, e)?);
+        }
+        txn.commit()?;
+        Ok(counts)
+    }
+
+    fn persist_in(&self, txn: &mut RwTxn, e: &Event) -> Result<u64> {
+        let t = self.tables;
+        let id = hex::decode(&e.id)?;
+        let author = hex::decode(&e.pubkey)?;
+        // ignore if the event hash is a duplicate.
+        if self.lookup_seq(txn, &id)?.is_some() {
+            return Ok(0);
+        }
+        // don't insert replaceable events that are older than the
+        // version we already have.
+        let replaced = if e.is_replaceable() || e.distinct_param().is_some() {
+            let versions = self.replaceable_versions(txn, e, &author)?;
+            if versions.iter().any(|(old, _)| old.created_at >= e.created_at) {
+                return Ok(0);
+            }
+            versions
+        } else {
+            vec![]
+        };
+        let seq = self.next_counter(txn, "event_seq")?;
+        self.insert_event(txn, e, &id, seq)?;
+        if !replaced.is_empty() {
+            for (old, old_seq) in &replaced {
+                self.remove_event(txn, old, *old_seq)?;
+            }
+            info!(
+                "removed {} older replaceable kind {} events for author: {:?}",
+                replaced.len(),
+                e.kind,
+                e.get_author_prefix()
+            );
+        }
+        let mut ins_count = 1;
+        if e.kind == 5 {
+            // record the deletion, so events that arrive later are
+            // hidden, and hide the referenced events from the same author.
+            let mut hid = 0;
+            for target in e.tag_values_by_name("e").iter().filter(|x| is_hex(x) && x.len() == 64) {
+                let target_id = hex::decode(target)?;
+                let mut del_key = target_id.clone();
+                del_key.extend_from_slice(&author);
+                t.deletions.put(txn, &del_key, &())?;
+                if t.hidden.get(txn, &target_id)?.is_some() {
+                    continue;
+                }
+                if let Some(target_seq) = self.lookup_seq(txn, &target_id)? {
+                    match self.load_event(txn, target_seq)? {
+                        Some(target_ev) if target_ev.kind != 5 && target_ev.pubkey == e.pubkey => {
+                            self.hide_event(txn, &target_ev, &target_id, target_seq)?;
+                            hid += 1;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            info!("hid {} deleted events for author {:?}", hid, e.get_author_prefix());
+        } else {
+            // check if a deletion has already been recorded for this event.
+            let mut del_key = id.clone();
+            del_key.extend_from_slice(&author);
+            if t.deletions.get(txn, &del_key)?.is_some() {
+                info!(
+                    "hid event: {:?} due to existing deletion by author: {:?}",
+                    e.get_event_id_prefix(),
+                    e.get_author_prefix()
+                );
+                self.hide_event(txn, e, &id, seq)?;
+                // event was deleted, so let caller know nothing new
+                // arrived, preventing this from being sent to active
+                // subscriptions
+                ins_count = 0;
+            }
+        }
+        Ok(ins_count)
+    }
+
+    /// Index ranges to read, for the planned access to a filter.
+    fn sources(&self, f: &ReqFilter, access: Access) -> Vec<Source> {
+        let t = &self.tables;
+        let exact = |index: Index, prefix: Vec<u8>| Source { index, prefix, exact: true };
+        match (access, &f.authors, &f.tags, &f.kinds) {
+            (Access::Authors, Some(authors), _, kinds) => {
+                let authors: Vec<(Vec<u8>, bool)> = authors.iter().filter_map(|a| hex_prefix(a)).collect();
+                match kinds {
+                    Some(kinds) if authors.iter().all(|(_, full)| *full) => authors
+                        .iter()
+                        .flat_map(|(pk, _)| kinds.iter().map(|k| exact(t.pubkey_kind, pubkey_kind_prefix(pk, *k))))
+                        .collect(),
+                    _ => authors
+                        .into_iter()
+                        .map(|(prefix, full)| Source { index: t.pubkey, prefix, exact: full })
+                        .collect(),
+                }
+            }
+            (Access::Tag(c), _, Some(tags), _) => {
+                let name = c.to_string();
+                tags.get(&c)
+                    .into_iter()
+                    .flatten()
+                    .map(|v| exact(t.tag, tag_prefix(&name, v)))
+                    .collect()
+            }
+            (Access::Kinds, _, _, Some(kinds)) => kinds.iter().map(|k| exact(t.kind, kind_prefix(*k))).collect(),
+            _ => vec![exact(t.created, vec![])],
+        }
+    }
+
+    /// Find the events matching a filter, as `(created_at, seq)` pairs.
+    /// With a limit, the newest events are returned, newest first;
+    /// otherwise all matches are returned, oldest first.
+    fn filter_matches(&self, txn: &RoTxn, f: &ReqFilter) -> Result<Vec<(u64, u64)>> {
+        let access = planner::plan(f).access;
+        if access == Access::Nothing {
+            return Ok(vec![]);
+        }
+        let mut seen = HashSet::new();
+        let mut found = vec![];
+        let mut check = |seq: u64| -> Result<bool> {
+            if !seen.insert(seq) {
+                return Ok(false);
+            }
+            match self.load_event(txn, seq)? {
+                Some(e) if f.interested_in_event(&e) => {
+                    found.push((e.created_at, seq));
+                    Ok(true)
+                }
+                _ => Ok(false),
+            }
+        };
+        if let (Access::Ids, Some(ids)) = (access, &f.ids) {
+            for (prefix, _) in ids.iter().filter_map(|i| hex_prefix(i)) {
+                for item in self.tables.ids.prefix_iter(txn, &prefix)? {
+                    let (id, seq) = item?;
+                    if self.tables.hidden.get(txn, id)?.is_some() {
+                        continue;
+                    }
+                    if let Ok(seq) = seq.try_into() {
+                        check(u64::from_be_bytes(seq))?;
+                    }
+                }
+            }
+        } else {
+            // since and until are exclusive
+            let lower = f.since.map_or(0, |s| s.saturating_add(1));
+            for source in self.sources(f, access) {
+                if !source.exact {
+                    for item in source.index.prefix_iter(txn, &source.prefix)? {
+                        let (key, _) = item?;
+                        if let Some((_, seq)) = split_suffix(key) {
+                            check(seq)?;
+                        }
+                    }
+                    continue;
+                }
+                let mut start = source.prefix.clone();
+                start.extend_from_slice(&lower.to_be_bytes());
+                let end = match f.until {
+                    Some(until) => {
+                        let mut k = source.prefix.clone();
+                        k.extend_from_slice(&until.to_be_bytes());
+                        Bound::Excluded(k)
+                    }
+                    None => {
+                        let mut k = source.prefix.clone();
+                        k.extend_from_slice(&[0xff; 16]);
+                        Bound::Included(k)
+                    }
+                };
+                let range = (Bound::Included(start.as_slice()), end.as_ref().map(Vec::as_slice));
+                let mut matched = 0;
+                if let Some(limit) = f.limit {
+                    for item in source.index.rev_range(txn, &range)? {
+                        let (key, _) = item?;
+                        if let Some((_, seq)) = split_suffix(key) {
+                            if check(seq)? {
+                                matched += 1;
+                                if matched >= limit {
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                } else {
+                    for item in source.index.range(txn, &range)? {
+                        let (key, _) = item?;
+                        if let Some((_, seq)) = split_suffix(key) {
+                            check(seq)?;
+                        }
+                    }
+                }
+            }
+        }
+        if let Some(limit) = f.limit {
+            found.sort_unstable_by(|a, b| b.cmp(a));
+            found.truncate(limit as usize);
+        } else {
+            found.sort_unstable();
+        }
+        Ok(found)
+    }
+
+    fn load_verification(&self, txn: &RoTxn, rowid: u64) -> Result<Option<StoredVerification>> {
+        match self.tables.verifications.get(txn, &seq_key(rowid))? {
+            Some(json) => Ok(Some(serde_json::from_str(json)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Apply a change to a stored verification record.
+    fn modify_verification<F>(&self, id: u64, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut StoredVerification),
+    {
+        let mut txn = self.env.write_txn()?;
+        if let Some(mut v) = self.load_verification(&txn, id)? {
+            f(&mut v);
+            self.tables
+                .verifications
+                .put(&mut txn, &seq_key(id), &serde_json::to_string(&v)?)?;
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    fn remove_verification(&self, txn: &mut RwTxn, rowid: u64, v: &StoredVerification) -> Result<()> {
+        let mut key = hex::decode(&v.address)?;
+        key.extend_from_slice(&seq_key(rowid));
+        self.tables.verification_pubkey.delete(txn, &key)?;
+        self.tables.verifications.delete(txn, &seq_key(rowid))?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl NostrRepo for LmdbRepo {
+    async fn start(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn migrate_up(&self) -> Result<usize> {
+        let repo = self.clone();
+        task::spawn_blocking(move || {
+            let mut txn = repo.env.write_txn()?;
+            let version = match repo.tables.meta.get(&txn, "version")? {
+                Some(v) => u64::from_be_bytes(v.try_into().map_err(|_| Error::UnknownError)?) as usize,
+                None => 0,
+            };
+            if version < DB_VERSION {
+                repo.tables
+                    .meta
+                    .put(&mut txn, "version", &(DB_VERSION as u64).to_be_bytes())?;
+                info!("LMDB tables initialized at v{}", DB_VERSION);
+            }
+            txn.commit()?;
+            Ok(DB_VERSION)
+        })
+        .await?
+    }
+
+    async fn write_event(&self, e: &Event) -> Result<u64> {
+        let start = Instant::now();
+        let repo = self.clone();
+        let e = e.clone();
+        let count = task::spawn_blocking(move || repo.persist_event(&e)).await?;
+        self.metrics
+            .write_events
+            .observe(start.elapsed().as_secs_f64());
+        count
+    }
+
+    async fn write_events(&self, events: &[Event]) -> Result<Vec<u64>> {
+        let start = Instant::now();
+        let repo = self.clone();
+        let events = events.to_vec();
+        let count = task::spawn_blocking(move || repo.persist_events(&events)).await?;
+        self.metrics
+            .write_events
+            .observe(start.elapsed().as_secs_f64());
+        count
+    }
+
+    async fn query_subscription(
+        &self,
+        sub: Subscription,
+        client_id: String,
+        query_tx: tokio::sync::mpsc::Sender<QueryResult>,
+        mut abandon_query_rx: tokio::sync::oneshot::Receiver<()>,
+    ) -> Result<()> {
+        let pre_spawn_start = Instant::now();
+        let repo = self.clone();
+        task::spawn_blocking(move || {
+            let metrics = &repo.metrics;
+            // any client that doesn't cause us to generate new rows in 2
+            // seconds gets dropped.
+            let abort_cutoff = Duration::from_secs(2);
+            let mut row_count: usize = 0;
+            let txn = repo.env.read_txn()?;
+            for filter in &sub.filters {
+                let filter_start = Instant::now();
+                let filter = &planner::plan(filter).limited(filter);
+                let matches = repo.filter_matches(&txn, filter)?;
+                debug!(
+                    "filter matched {} events in {:?} (cid: {}, sub: {:?})",
+                    matches.len(),
+                    filter_start.elapsed(),
+                    client_id,
+                    sub.id
+                );
+                let mut last_successful_send = Instant::now();
+                for (_, seq) in matches {
+                    // check if this is still active; every 100 rows
+                    if row_count.is_multiple_of(100) && abandon_query_rx.try_recv().is_ok() {
+                        debug!("query cancelled by client (cid: {}, sub: {:?})", client_id, sub.id);
+                        return Ok(());
+                    }
+                    let Some(event_json) = repo.tables.events.get(&txn, &seq_key(seq))? else {
+                        continue;
+                    };
+                    row_count += 1;
+                    while query_tx.capacity() == 0 {
+                        // the queue is full
+                        trace!("db reader thread is stalled");
+                        if last_successful_send + abort_cutoff < Instant::now() {
+                            info!(
+                                "aborting database query due to slow client (cid: {}, sub: {:?})",
+                                client_id, sub.id
+                            );
+                            metrics.query_aborts.with_label_values(&["slowclient"]).inc();
+                            return Ok(());
+                        }
+                        thread::sleep(Duration::from_millis(500));
+                    }
+                    query_tx
+                        .blocking_send(QueryResult {
+                            sub_id: sub.get_id(),
+                            event: event_json.to_owned(),
+                        })
+                        .ok();
+                    last_successful_send = Instant::now();
+                }
+                metrics.query_db.observe(filter_start.elapsed().as_secs_f64());
+            }
+            debug!(
+                "query completed in {:?} (cid: {}, sub: {:?}, rows: {})",
+                pre_spawn_start.elapsed(),
+                client_id,
+                sub.id,
+                row_count
+            );
+            query_tx
+                .blocking_send(QueryResult {
+                    sub_id: sub.get_id(),
+                    event: "EOSE".to_string(),
+                })
+                .ok();
+            metrics.query_sub.observe(pre_spawn_start.elapsed().as_secs_f64());
+            let ok: Result<()> = Ok(());
+            ok
+        });
+        Ok(())
+    }
+
+    async fn count_events_by_filter(&self, filters: Vec<ReqFilter>) -> Result<u64> {
+        let start = Instant::now();
+        let repo = self.clone();
+        task::spawn_blocking(move || {
+            let txn = repo.env.read_txn()?;
+            let mut seqs = HashSet::new();
+            for f in &filters {
+                seqs.extend(repo.filter_matches(&txn, f)?.into_iter().map(|(_, seq)| seq));
+            }
+            repo.metrics.query_db.observe(start.elapsed().as_secs_f64());
+            Ok(seqs.len() as u64)
+        })
+        .await?
+    }
+
+    async fn is_event_deleted(&self, id: &str) -> Result<bool> {
+        let id = hex::decode(id)?;
+        let txn = self.env.read_txn()?;
+        if self.tables.hidden.get(&txn, &id)?.is_none() {
+            return Ok(false);
+        }
+        // other hidden events lack a deletion from their author
+        let Some(e) = self.lookup_seq(&txn, &id)?.map(|seq| self.load_event(&txn, seq)).transpose()?.flatten() else {
+            return Ok(false);
+        };
+        let mut del_key = id;
+        del_key.extend_from_slice(&hex::decode(&e.pubkey)?);
+        Ok(self.tables.deletions.get(&txn, &del_key)?.is_some())
+    }
+
+    async fn get_relay_list(&self, pub_key: &str) -> Result<Option<Event>> {
+        let prefix = pubkey_kind_prefix(&hex::decode(pub_key)?, KIND_RELAY_LIST);
+        let txn = self.env.read_txn()?;
+        // relay lists are replaceable, so there is at most one per author.
+        for item in self.tables.pubkey_kind.rev_prefix_iter(&txn, &prefix)? {
+            let (key, _) = item?;
+            if let Some((_, seq)) = split_suffix(key) {
+                match self.load_event(&txn, seq)? {
+                    Some(e) if e.pubkey == pub_key => return Ok(Some(e)),
+                    _ => {}
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    async fn apply_group_update(&self, update: &GroupUpdate) -> Result<()> {
+        let repo = self.clone();
+        let update = update.clone();
+        task::spawn_blocking(move || {
+            let groups = repo.tables.groups;
+            let mut txn = repo.env.write_txn()?;
+            let load = |txn: &RoTxn, group: &str| -> Result<Option<HashMap<String, GroupRole>>> {
+                match groups.get(txn, group)? {
+                    Some(json) => Ok(Some(serde_json::from_str(json)?)),
+                    None => Ok(None),
+                }
+            };
+            match &update {
+                GroupUpdate::Create { group, admin } => {
+                    let mut members = load(&txn, group)?.unwrap_or_default();
+                    members.insert(admin.clone(), GroupRole::Admin);
+                    groups.put(&mut txn, group, &serde_json::to_string(&members)?)?;
+                }
+                GroupUpdate::Delete { group } => {
+                    groups.delete(&mut txn, group)?;
+                }
+                GroupUpdate::SetMember { group, pubkey, role } => {
+                    if let Some(mut members) = load(&txn, group)? {
+                        match role {
+                            Some(r) => members.insert(pubkey.clone(), *r),
+                            None => members.remove(pubkey),
+                        };
+                        groups.put(&mut txn, group, &serde_json::to_string(&members)?)?;
+                    }
+                }
+            }
+            txn.commit()?;
+            debug!("applied group update: {:?}", update);
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn get_groups(&self) -> Result<Vec<Group>> {
+        let txn = self.env.read_txn()?;
+        let mut groups = vec![];
+        for item in self.tables.groups.iter(&txn)? {
+            let (id, json) = item?;
+            groups.push(Group {
+                id: id.to_owned(),
+                members: serde_json::from_str(json)?,
+            });
+        }
+        Ok(groups)
+    }
+
+    async fn add_ban(&self, ban: &Ban) -> Result<()> {
+        let repo = self.clone();
+        let ban = ban.clone();
+        task::spawn_blocking(move || {
+            let bans = repo.tables.bans;
+            let now = unix_time();
+            let mut txn = repo.env.write_txn()?;
+            let mut expired = vec![];
+            for item in bans.iter(&txn)? {
+                let (key, json) = item?;
+                let b: Ban = serde_json::from_str(json)?;
+                if !b.is_active(now) {
+                    expired.push(key.to_owned());
+                }
+            }
+            for key in &expired {
+                bans.delete(&mut txn, key)?;
+            }
+            let key = format!("{}:{}", ban.target.as_str(), ban.value);
+            bans.put(&mut txn, &key, &serde_json::to_string(&ban)?)?;
+            txn.commit()?;
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn get_bans(&self) -> Result<Vec<Ban>> {
+        let txn = self.env.read_txn()?;
+        let now = unix_time();
+        let mut bans = vec![];
+        for item in self.tables.bans.iter(&txn)? {
+            let (_, json) = item?;
+            let b: Ban = serde_json::from_str(json)?;
+            if b.is_active(now) {
+                bans.push(b);
+            }
+        }
+        Ok(bans)
+    }
+
+    async fn get_allowed_pubkeys(&self) -> Result<Vec<String>> {
+        let txn = self.env.read_txn()?;
+        let mut pubkeys = vec![];
+        for item in self.tables.allowed.iter(&txn)? {
+            let (pubkey, ()) = item?;
+            pubkeys.push(pubkey.to_owned());
+        }
+        Ok(pubkeys)
+    }
+
+    async fn set_pubkey_allowed(&self, pubkey: &str, allowed: bool) -> Result<()> {
+        let repo = self.clone();
+        let pubkey = pubkey.to_owned();
+        task::spawn_blocking(move || {
+            let mut txn = repo.env.write_txn()?;
+            if allowed {
+                repo.tables.allowed.put(&mut txn, &pubkey, &())?;
+            } else {
+                repo.tables.allowed.delete(&mut txn, &pubkey)?;
+            }
+            txn.commit()?;
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn get_reputation(&self, pubkey: &str) -> Result<ReputationRecord> {
+        let txn = self.env.read_txn()?;
+        match self.tables.reputation.get(&txn, pubkey)? {
+            Some(json) => Ok(serde_json::from_str(json)?),
+            None => Ok(ReputationRecord::default()),
+        }
+    }
+
+    async fn update_reputation(&self, pubkey: &str, change: ReputationChange) -> Result<()> {
+        let repo = self.clone();
+        let pubkey = pubkey.to_owned();
+        task::spawn_blocking(move || {
+            let reputation = repo.tables.reputation;
+            let mut txn = repo.env.write_txn()?;
+            let mut record: ReputationRecord = match reputation.get(&txn, &pubkey)? {
+                Some(json) => serde_json::from_str(json)?,
+                None => ReputationRecord::default(),
+            };
+            record.apply(change);
+            reputation.put(&mut txn, &pubkey, &serde_json::to_string(&record)?)?;
+            txn.commit()?;
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn add_report(&self, report: &Report) -> Result<()> {
+        let key = format!(
+            "{}:{}:{}",
+            report.event_id.as_deref().unwrap_or_default(),
+            report.pubkey,
+            report.reporter
+        );
+        let json = serde_json::to_string(report)?;
+        let repo = self.clone();
+        task::spawn_blocking(move || {
+            let mut txn = repo.env.write_txn()?;
+            repo.tables.reports.put(&mut txn, &key, &json)?;
+            txn.commit()?;
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn get_reports(&self, event_id: Option<&str>) -> Result<Vec<Report>> {
+        let txn = self.env.read_txn()?;
+        let items = match event_id {
+            Some(id) => self.tables.reports.prefix_iter(&txn, &format!("{id}:"))?,
+            None => self.tables.reports.prefix_iter(&txn, "")?,
+        };
+        let mut reports = vec![];
+        for item in items {
+            let (_, json) = item?;
+            reports.push(serde_json::from_str(json)?);
+        }
+        Ok(reports)
+    }
+
+    async fn hide_event(&self, id: &str) -> Result<bool> {
+        let id = hex::decode(id)?;
+        let repo = self.clone();
+        task::spawn_blocking(move || {
+            let mut txn = repo.env.write_txn()?;
+            if repo.tables.hidden.get(&txn, &id)?.is_some() {
+                return Ok(false);
+            }
+            let event = match repo.lookup_seq(&txn, &id)? {
+                Some(seq) => repo.load_event(&txn, seq)?.map(|e| (e, seq)),
+                None => None,
+            };
+            let Some((event, seq)) = event else {
+                return Ok(false);
+            };
+            repo.hide_event(&mut txn, &event, &id, seq)?;
+            txn.commit()?;
+            Ok(true)
+        })
+        .await?
+    }
+
+    async fn get_account(&self, pubkey: &str) -> Result<Option<Account>> {
+        let txn = self.env.read_txn()?;
+        match self.tables.accounts.get(&txn, pubkey)? {
+            Some(json) => Ok(Some(serde_json::from_str(json)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn add_invoice(&self, invoice: &Invoice) -> Result<()> {
+        let repo = self.clone();
+        let invoice = invoice.clone();
+        task::spawn_blocking(move || {
+            let mut txn = repo.env.write_txn()?;
+            if repo.tables.accounts.get(&txn, &invoice.pubkey)?.is_none() {
+                let account = Account {
+                    pubkey: invoice.pubkey.clone(),
+                    admitted: false,
+                    balance_msat: 0,
+                    created_at: invoice.created_at,
+                };
+                repo.tables.accounts.put(&mut txn, &invoice.pubkey, &serde_json::to_string(&account)?)?;
+            }
+            repo.tables.invoices.put(&mut txn, &invoice.payment_hash, &serde_json::to_string(&invoice)?)?;
+            txn.commit()?;
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn get_unpaid_invoices(&self) -> Result<Vec<Invoice>> {
+        let txn = self.env.read_txn()?;
+        let mut invoices = vec![];
+        for item in self.tables.invoices.iter(&txn)? {
+            let (_, json) = item?;
+            let invoice: Invoice = serde_json::from_str(json)?;
+            if !invoice.paid {
+                invoices.push(invoice);
+            }
+        }
+        Ok(invoices)
+    }
+
+    async fn invoice_paid(&self, payment_hash: &str) -> Result<bool> {
+        let repo = self.clone();
+        let payment_hash = payment_hash.to_owned();
+        task::spawn_blocking(move || {
+            let mut txn = repo.env.write_txn()?;
+            let mut invoice: Invoice = match repo.tables.invoices.get(&txn, &payment_hash)? {
+                Some(json) => serde_json::from_str(json)?,
+                None => return Ok(false),
+            };
+            if invoice.paid {
+                return Ok(false);
+            }
+            invoice.paid = true;
+            repo.tables.invoices.put(&mut txn, &payment_hash, &serde_json::to_string(&invoice)?)?;
+            let mut account: Account = match repo.tables.accounts.get(&txn, &invoice.pubkey)? {
+                Some(json) => serde_json::from_str(json)?,
+                None => Account {
+                    pubkey: invoice.pubkey.clone(),
+                    admitted: false,
+                    balance_msat: 0,
+                    created_at: invoice.created_at,
+                },
+            };
+            account.admitted = true;
+            if invoice.topup {
+                account.balance_msat += invoice.amount_msat;
+            }
+            repo.tables.accounts.put(&mut txn, &invoice.pubkey, &serde_json::to_string(&account)?)?;
+            txn.commit()?;
+            Ok(true)
+        })
+        .await?
+    }
+
+    async fn debit_account(&self, pubkey: &str, amount_msat: u64) -> Result<bool> {
+        let repo = self.clone();
+        let pubkey = pubkey.to_owned();
+        task::spawn_blocking(move || {
+            let mut txn = repo.env.write_txn()?;
+            let mut account: Account = match repo.tables.accounts.get(&txn, &pubkey)? {
+                Some(json) => serde_json::from_str(json)?,
+                None => return Ok(false),
+            };
+            if account.balance_msat < amount_msat {
+                return Ok(false);
+            }
+            account.balance_msat -= amount_msat;
+            repo.tables.accounts.put(&mut txn, &pubkey, &serde_json::to_string(&account)?)?;
+            txn.commit()?;
+            Ok(true)
+        })
+        .await?
+    }
+
+    async fn credit_account(&self, pubkey: &str, amount_msat: u64) -> Result<()> {
+        let repo = self.clone();
+        let pubkey = pubkey.to_owned();
+        task::spawn_blocking(move || {
+            let mut txn = repo.env.write_txn()?;
+            if let Some(json) = repo.tables.accounts.get(&txn, &pubkey)? {
+                let mut account: Account = serde_json::from_str(json)?;
+                account.balance_msat += amount_msat;
+                repo.tables.accounts.put(&mut txn, &pubkey, &serde_json::to_string(&account)?)?;
+            }
+            txn.commit()?;
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn optimize_db(&self) -> Result<()> {
+        // LMDB needs no maintenance; free pages are reused.
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<()> {
+        self.env.read_txn()?;
+        Ok(())
+    }
+
+    async fn check_integrity(&self) -> Result<Vec<String>> {
+        let repo = self.clone();
+        task::spawn_blocking(move || repo.integrity_problems()).await?
+    }
+
+    async fn event_summaries(&self, order: ScanOrder, after: Option<&EventSummary>, limit: usize) -> Result<Vec<EventSummary>> {
+        let repo = self.clone();
+        let after = after.cloned();
+        task::spawn_blocking(move || repo.summaries(order, after.as_ref(), limit)).await?
+    }
+
+    async fn used_bytes(&self) -> Result<u64> {
+        // the map file does not shrink, but freed pages are reused.
+        Ok(self.env.non_free_pages_size()?)
+    }
+
+    async fn author_bytes(&self, pubkey: &str) -> Result<u64> {
+        let repo = self.clone();
+        let pubkey = pubkey.to_owned();
+        task::spawn_blocking(move || {
+            let author = hex::decode(&pubkey)?;
+            let txn = repo.env.read_txn()?;
+            let mut used = 0;
+            // the index also holds events the author delegated, and
+            // omits hidden events.
+            for item in repo.tables.pubkey.prefix_iter(&txn, &author)? {
+                let (key, ()) = item?;
+                let Some((_, seq)) = split_suffix(key) else {
+                    continue;
+                };
+                if let Some(json) = repo.tables.events.get(&txn, &seq_key(seq))? {
+                    let e: Event = serde_json::from_str(json)?;
+                    if e.pubkey == pubkey {
+                        used += json.len() as u64;
+                    }
+                }
+            }
+            Ok(used)
+        })
+        .await?
+    }
+
+    async fn delete_events(&self, ids: &[String]) -> Result<u64> {
+        let repo = self.clone();
+        let ids = ids.to_vec();
+        task::spawn_blocking(move || repo.remove_events(&ids)).await?
+    }
+
+    async fn create_verification_record(&self, event_id: &str, name: &str) -> Result<()> {
+        let repo = self.clone();
+        let event_id = event_id.to_owned();
+        let name = name.to_owned();
+        task::spawn_blocking(move || {
+            let mut txn = repo.env.write_txn()?;
+            let seq = repo.lookup_seq(&txn, &hex::decode(&event_id)?)?;
+            let event = match seq {
+                Some(seq) => repo.load_event(&txn, seq)?,
+                None => None,
+            }
+            .ok_or_else(|| Error::CustomError(format!("no event found for verification: {event_id}")))?;
+            // if we create a new one, we should get rid of any old ones.
+            let mut old = vec![];
+            for item in repo.tables.verifications.iter(&txn)? {
+                let (rowid, json) = item?;
+                let v: StoredVerification = serde_json::from_str(json)?;
+                if v.name == name {
+                    old.push((u64::from_be_bytes(rowid.try_into().map_err(|_| Error::UnknownError)?), v));
+                }
+            }
+            for (rowid, v) in &old {
+                repo.remove_verification(&mut txn, *rowid, v)?;
+            }
+            let rowid = repo.next_counter(&mut txn, "verification_seq")?;
+            let v = StoredVerification {
+                name: name.to_owned(),
+                event: event.id.clone(),
+                address: event.pubkey.clone(),
+                event_created: event.created_at,
+                verified_at: Some(unix_time()),
+                failed_at: None,
+                failure_count: 0,
+            };
+            repo.tables
+                .verifications
+                .put(&mut txn, &seq_key(rowid), &serde_json::to_string(&v)?)?;
+            let mut key = hex::decode(&event.pubkey)?;
+            key.extend_from_slice(&seq_key(rowid));
+            repo.tables.verification_pubkey.put(&mut txn, &key, &())?;
+            txn.commit()?;
+            info!("saved new verification record for ({:?})", name);
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn update_verification_timestamp(&self, id: u64) -> Result<()> {
+        let repo = self.clone();
+        task::spawn_blocking(move || {
+            // add some jitter to the verification to prevent everything from stacking up together.
+            let verify_time = now_jitter(600);
+            // update verification time and reset any failure count
+            repo.modify_verification(id, |v| {
+                v.verified_at = Some(verify_time);
+                v.failure_count = 0;
+            })?;
+            info!("verification updated for {}", id);
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn fail_verification(&self, id: u64) -> Result<()> {
+        let repo = self.clone();
+        task::spawn_blocking(move || {
+            let now = unix_time();
+            repo.modify_verification(id, |v| {
+                v.failed_at = Some(now);
+                v.failure_count += 1;
+            })
+        })
+        .await?
+    }
+
+    async fn delete_verification(&self, id: u64) -> Result<()> {
+        let repo = self.clone();
+        task::spawn_blocking(move || {
+            let mut txn = repo.env.write_txn()?;
+            if let Some(v) = repo.load_verification(&txn, id)? {
+                repo.remove_verification(&mut txn, id, &v)?;
+            }
+            txn.commit()?;
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn get_latest_user_verification(&self, pub_key: &str) -> Result<VerificationRecord> {
+        let prefix = hex::decode(pub_key)?;
+        let txn = self.env.read_txn()?;
+        let mut latest: Option<VerificationRecord> = None;
+        for item in self.tables.verification_pubkey.prefix_iter(&txn, &prefix)? {
+            let (key, _) = item?;
+            let rowid = u64::from_be_bytes(key[key.len() - 8..].try_into().map_err(|_| Error::UnknownError)?);
+            let Some(rec) = self.load_verification(&txn, rowid)?.and_then(|v| v.to_record(rowid)) else {
+                continue;
+            };
+            let order = |r: &VerificationRecord| (r.event_created, r.last_success, r.last_failure);
+            if latest.as_ref().is_none_or(|l| order(&rec) > order(l)) {
+                latest = Some(rec);
+            }
+        }
+        latest.ok_or_else(no_rows)
+    }
+
+    async fn get_oldest_user_verification(&self, before: u64) -> Result<VerificationRecord> {
+        let txn = self.env.read_txn()?;
+        let mut oldest: Option<VerificationRecord> = None;
+        for item in self.tables.verifications.iter(&txn)? {
+            let (rowid, json) = item?;
+            let rowid = u64::from_be_bytes(rowid.try_into().map_err(|_| Error::UnknownError)?);
+            let v: StoredVerification = serde_json::from_str(json)?;
+            let Some(rec) = v.to_record(rowid) else { continue };
+            let due = |t: Option<u64>| t.is_none_or(|t| t < before);
+            if !(due(rec.last_success) && due(rec.last_failure)) {
+                continue;
+            }
+            // records never verified (or never failed) sort first
+            let order = |r: &VerificationRecord| (r.last_success, r.last_failure);
+            if oldest.as_ref().is_none_or(|o| order(&rec) < order(o)) {
+                oldest = Some(rec);
+            }
+        }
+        oldest.ok_or_else(no_rows)
+    }
+
+    async fn put_queued_verification(&self, queued: &QueuedVerification) -> Result<()> {
+        let repo = self.clone();
+        let queued = queued.clone();
+        task::spawn_blocking(move || {
+            let table = repo.tables.verification_queue;
+            let mut txn = repo.env.write_txn()?;
+            let pubkey = queued.event.pubkey.as_str();
+            if let Some(json) = table.get(&txn, pubkey)? {
+                let existing: QueuedVerification = serde_json::from_str(json)?;
+                if existing.event.created_at > queued.event.created_at {
+                    return Ok(());
+                }
+            }
+            table.put(&mut txn, pubkey, &serde_json::to_string(&queued)?)?;
+            txn.commit()?;
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn get_queued_verifications(&self, due: u64, limit: usize) -> Result<Vec<QueuedVerification>> {
+        let txn = self.env.read_txn()?;
+        let mut queued = vec![];
+        for item in self.tables.verification_queue.iter(&txn)? {
+            let (_, json) = item?;
+            let q: QueuedVerification = serde_json::from_str(json)?;
+            if q.retry_at <= due {
+                queued.push(q);
+            }
+        }
+        queued.sort_by_key(|q| q.retry_at);
+        queued.truncate(limit);
+        Ok(queued)
+    }
+
+    async fn remove_queued_verification(&self, pubkey: &str, event_id: &str) -> Result<()> {
+        let repo = self.clone();
+        let pubkey = pubkey.to_owned();
+        let event_id = event_id.to_owned();
+        task::spawn_blocking(move || {
+            let table = repo.tables.verification_queue;
+            let mut txn = repo.env.write_txn()?;
+            if let Some(json) = table.get(&txn, &pubkey)? {
+                let existing: QueuedVerification = serde_json::from_str(json)?;
+                if existing.event.id == event_id {
+                    table.delete(&mut txn, &pubkey)?;
+                }
+            }
+            txn.commit()?;
+            Ok(())
+        })
+        .await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_key_suffix() {
+        let mut key = tag_prefix("e", "abc");
+        key.extend_from_slice(&time_suffix(1_700_000_000, 42));
+        assert_eq!(split_suffix(&key), Some((1_700_000_000, 42)));
+        assert_eq!(split_suffix(&[0; 4]), None);
+        // keys sort by time within a prefix
+        assert!(time_suffix(1, 9) < time_suffix(2, 1));
+    }
+
+    #[test]
+    fn tag_prefixes_distinct() {
+        // a value must not be a key prefix of a longer value
+        assert!(!tag_prefix("t", "nostrich").starts_with(&tag_prefix("t", "nostr")));
+        assert!(!tag_prefix("t", "x").starts_with(&tag_prefix("p", "x")));
+    }
+
+    #[test]
+    fn hex_prefixes() {
+        assert_eq!(hex_prefix("abc"), Some((vec![0xab], false)));
+        assert_eq!(hex_prefix(&"a".repeat(64)).map(|p| p.1), Some(true));
+        assert_eq!(hex_prefix("xyz"), None);
+    }
+
+    #[tokio::test]
+    async fn summaries_include_hidden_events() {
+        let dir = std::env::temp_dir().join(format!("nostr-rs-relay-lmdb-{}", std::process::id()));
+        let mut settings = Settings::default();
+        settings.database.data_directory = dir.to_string_lossy().into_owned();
+        let repo = LmdbRepo::new(&settings, crate::server::create_metrics().1).unwrap();
+        repo.migrate_up().await.unwrap();
+        let mut ids = vec![];
+        for t in 1..=3u64 {
+            let mut e = Event::simple_event();
+            e.id = format!("{t:064x}");
+            e.pubkey = "a".repeat(64);
+            e.kind = 1;
+            e.created_at = t;
+            repo.write_event(&e).await.unwrap();
+            ids.push(e.id);
+        }
+        assert!(NostrRepo::hide_event(&repo, &ids[1]).await.unwrap());
+        assert!(!NostrRepo::hide_event(&repo, &ids[1]).await.unwrap());
+        // an event hidden by moderation was not deleted by its author
+        assert!(!repo.is_event_deleted(&ids[1]).await.unwrap());
+        let first = repo.event_summaries(ScanOrder::OldestFirst, None, 2).await.unwrap();
+        let rest = repo.event_summaries(ScanOrder::OldestFirst, first.last(), 2).await.unwrap();
+        let listed: Vec<String> = first.into_iter().chain(rest).map(|s| s.id).collect();
+        assert_eq!(listed, ids);
+        let newest = repo.event_summaries(ScanOrder::NewestFirst, None, 1).await.unwrap();
+        assert_eq!(newest[0].id, ids[2]);
+        let mut del = Event::simple_event();
+        del.id = format!("{:064x}", 9);
+        del.pubkey = "a".repeat(64);
+        del.kind = 5;
+        del.tags = vec![vec!["e".to_owned(), ids[0].clone()]];
+        repo.write_event(&del).await.unwrap();
+        assert!(repo.is_event_deleted(&ids[0]).await.unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,724 @@
+//! Event persistence and querying, in memory only
+//!
+//! Events are kept in a ring buffer of bounded size; when it is full,
+//! or events are older than the configured age, the events received
+//! earliest are dropped.  Nothing is written to disk, so this suits
+//! test relays and ephemeral chat relays.
+use crate::bans::{Ban, BanTarget};
+use crate::config::Settings;
+use crate::db::QueryResult;
+use crate::error::{Error, Result};
+use crate::event::Event;
+use crate::groups::{Group, GroupRole, GroupUpdate};
+use crate::nip05::{Nip05Name, QueuedVerification, VerificationRecord};
+use crate::nip65::KIND_RELAY_LIST;
+use crate::payment::{Account, Invoice};
+use crate::repo::{no_rows, now_jitter, EventSummary, NostrRepo, ScanOrder};
+use crate::reports::Report;
+use crate::reputation::{ReputationChange, ReputationRecord};
+use crate::server::NostrMetrics;
+use crate::subscription::{ReqFilter, Subscription};
+use crate::utils::{is_hex, unix_time};
+use async_trait::async_trait;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+/// An event held in the buffer.
+struct Stored {
+    event: Event,
+    json: String,
+    received: Instant,
+    /// Deleted by its author (NIP-09)
+    hidden: bool,
+}
+
+#[derive(Default)]
+struct State {
+    /// Events, in the order received
+    events: VecDeque<Stored>,
+    ids: HashSet<String>,
+    /// Deletion requests in the buffer, by target event id and author
+    deletions: HashMap<(String, String), usize>,
+    groups: BTreeMap<String, HashMap<String, GroupRole>>,
+    bans: HashMap<(BanTarget, String), Ban>,
+    allowed_pubkeys: HashSet<String>,
+    reputation: HashMap<String, ReputationRecord>,
+    /// Reports, by reporter, reported pubkey and event
+    reports: HashMap<(String, String, Option<String>), Report>,
+    accounts: HashMap<String, Account>,
+    /// Invoices, by payment hash
+    invoices: HashMap<String, Invoice>,
+    verifications: BTreeMap<u64, VerificationRecord>,
+    next_verification: u64,
+    /// Metadata events waiting for verification, by author
+    verification_queue: HashMap<String, QueuedVerification>,
+}
+
+/// Targets of a deletion event, paired with its author.
+fn deletion_targets(e: &Event) -> Vec<(String, String)> {
+    e.tag_values_by_name("e")
+        .into_iter()
+        .filter(|x| is_hex(x) && x.len() == 64)
+        .map(|x| (x, e.pubkey.clone()))
+        .collect()
+}
+
+impl State {
+    /// Drop references to an event that has left the buffer.
+    fn forget(&mut self, e: &Event) {
+        self.ids.remove(&e.id);
+        if e.kind == 5 {
+            for key in deletion_targets(e) {
+                if let Some(n) = self.deletions.get_mut(&key) {
+                    *n -= 1;
+                    if *n == 0 {
+                        self.deletions.remove(&key);
+                    }
+                }
+            }
+        }
+        self.verifications.retain(|_, v| v.event != e.id);
+    }
+
+    /// Drop the earliest events, to stay within the size and age limits.
+    fn evict(&mut self, max_events: usize, max_age: Option<Duration>) {
+        let now = Instant::now();
+        while let Some(front) = self.events.front() {
+            let expired = max_age.is_some_and(|age| now.duration_since(front.received) > age);
+            if self.events.len() <= max_events && !expired {
+                break;
+            }
+            if let Some(s) = self.events.pop_front() {
+                self.forget(&s.event);
+            }
+        }
+    }
+
+    fn persist(&mut self, e: &Event) -> Result<u64> {
+        // ignore if the event hash is a duplicate.
+        if self.ids.contains(&e.id) {
+            return Ok(0);
+        }
+        // replaceable events replace older versions, and are ignored
+        // if a newer version exists.
+        if e.is_replaceable() || e.distinct_param().is_some() {
+            let d_tag = e.distinct_param();
+            let same = |s: &Stored| {
+                s.event.pubkey == e.pubkey && s.event.kind == e.kind && s.event.distinct_param() == d_tag
+            };
+            if self.events.iter().any(|s| same(s) && s.event.created_at >= e.created_at) {
+                return Ok(0);
+            }
+            let mut removed = vec![];
+            self.events.retain(|s| {
+                if same(s) {
+                    removed.push(s.event.clone());
+                    false
+                } else {
+                    true
+                }
+            });
+            for old in &removed {
+                self.forget(old);
+            }
+            if !removed.is_empty() {
+                info!(
+                    "removed {} older replaceable kind {} events for author: {:?}",
+                    removed.len(),
+                    e.kind,
+                    e.get_author_prefix()
+                );
+            }
+        }
+        let mut hidden = false;
+        if e.kind == 5 {
+            // hide the referenced events from the same author.
+            let targets = deletion_targets(e);
+            let mut hid = 0;
+            for s in self.events.iter_mut() {
+                let targeted = targets.iter().any(|(id, pk)| *id == s.event.id && *pk == s.event.pubkey);
+                if !s.hidden && s.event.kind != 5 && targeted {
+                    s.hidden = true;
+                    hid += 1;
+                }
+            }
+            for key in targets {
+                *self.deletions.entry(key).or_default() += 1;
+            }
+            info!("hid {} deleted events for author {:?}", hid, e.get_author_prefix());
+        } else if self.deletions.contains_key(&(e.id.clone(), e.pubkey.clone())) {
+            info!(
+                "hid event: {:?} due to existing deletion by author: {:?}",
+                e.get_event_id_prefix(),
+                e.get_author_prefix()
+            );
+            hidden = true;
+        }
+        self.ids.insert(e.id.clone());
+        self.events.push_back(Stored {
+            event: e.clone(),
+            json: serde_json::to_string(e)?,
+            received: Instant::now(),
+            hidden,
+        });
+        // a deleted event is not new, so it is not sent to active
+        // subscriptions
+        Ok(u64::from(!hidden))
+    }
+
+    /// Visible events matching a filter.  With a limit, the newest
+    /// events are returned, newest first; otherwise all matches are
+    /// returned, oldest first.
+    fn filter_matches(&self, f: &ReqFilter, max_age: Option<Duration>) -> Vec<&Stored> {
+        if f.limit == Some(0) {
+            return vec![];
+        }
+        let now = Instant::now();
+        let mut found: Vec<&Stored> = self
+            .events
+            .iter()
+            .filter(|s| !s.hidden && f.interested_in_event(&s.event))
+            .filter(|s| max_age.is_none_or(|age| now.duration_since(s.received) <= age))
+            .collect();
+        if let Some(limit) = f.limit {
+            found.sort_by_key(|s| std::cmp::Reverse(s.event.created_at));
+            found.truncate(limit as usize);
+        } else {
+            found.sort_by_key(|s| s.event.created_at);
+        }
+        found
+    }
+}
+
+#[derive(Clone)]
+pub struct MemoryRepo {
+    state: Arc<RwLock<State>>,
+    max_events: usize,
+    max_age: Option<Duration>,
+    metrics: NostrMetrics,
+}
+
+impl MemoryRepo {
+    #[must_use]
+    pub fn new(settings: &Settings, metrics: NostrMetrics) -> MemoryRepo {
+        MemoryRepo {
+            state: Arc::new(RwLock::new(State::default())),
+            max_events: settings.database.memory_max_events,
+            max_age: settings.database.memory_max_age_duration(),
+            metrics,
+        }
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, State> {
+        self.state.read().unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, State> {
+        self.state.write().unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+#[async_trait]
+impl NostrRepo for MemoryRepo {
+    async fn start(&self) -> Result<()> {
+        info!(
+            "memory database holds {} events (max age: {:?})",
+            self.max_events, self.max_age
+        );
+        Ok(())
+    }
+
+    async fn migrate_up(&self) -> Result<usize> {
+        Ok(0)
+    }
+
+    async fn write_event(&self, e: &Event) -> Result<u64> {
+        let start = Instant::now();
+        let count = {
+            let mut state = self.write();
+            let count = state.persist(e);
+            state.evict(self.max_events, self.max_age);
+            count
+        };
+        self.metrics
+            .write_events
+            .observe(start.elapsed().as_secs_f64());
+        count
+    }
+
+    async fn query_subscription(
+        &self,
+        sub: Subscription,
+        client_id: String,
+        query_tx: tokio::sync::mpsc::Sender<QueryResult>,
+        mut abandon_query_rx: tokio::sync::oneshot::Receiver<()>,
+    ) -> Result<()> {
+        let start = Instant::now();
+        let results: Vec<String> = {
+            let state = self.read();
+            sub.filters
+                .iter()
+                .flat_map(|f| state.filter_matches(f, self.max_age))
+                .map(|s| s.json.clone())
+                .collect()
+        };
+        self.metrics.query_db.observe(start.elapsed().as_secs_f64());
+        let metrics = self.metrics.clone();
+        tokio::spawn(async move {
+            // any client that doesn't accept results for 2 seconds
+            // gets dropped.
+            let abort_cutoff = Duration::from_secs(2);
+            let row_count = results.len();
+            for event in results {
+                if abandon_query_rx.try_recv().is_ok() {
+                    debug!("query cancelled by client (cid: {}, sub: {:?})", client_id, sub.id);
+                    return;
+                }
+                let res = QueryResult {
+                    sub_id: sub.get_id(),
+                    event,
+                };
+                if tokio::time::timeout(abort_cutoff, query_tx.send(res)).await.is_err() {
+                    info!(
+                        "aborting database query due to slow client (cid: {}, sub: {:?})",
+                        client_id, sub.id
+                    );
+                    metrics.query_aborts.with_label_values(&["slowclient"]).inc();
+                    return;
+                }
+            }
+            debug!(
+                "query completed in {:?} (cid: {}, sub: {:?}, rows: {})",
+                start.elapsed(),
+                client_id,
+                sub.id,
+                row_count
+            );
+            query_tx
+                .send(QueryResult {
+                    sub_id: sub.get_id(),
+                    event: "EOSE".to_string(),
+                })
+                .await
+                .ok();
+            metrics.query_sub.observe(start.elapsed().as_secs_f64());
+        });
+        Ok(())
+    }
+
+    async fn count_events_by_filter(&self, filters: Vec<ReqFilter>) -> Result<u64> {
+        let state = self.read();
+        let ids: HashSet<&str> = filters
+            .iter()
+            .flat_map(|f| state.filter_matches(f, self.max_age))
+            .map(|s| s.event.id.as_str())
+            .collect();
+        Ok(ids.len() as u64)
+    }
+
+    async fn is_event_deleted(&self, id: &str) -> Result<bool> {
+        let state = self.read();
+        Ok(state
+            .events
+            .iter()
+            .any(|s| s.hidden && s.event.id == id && state.deletions.contains_key(&(s.event.id.clone(), s.event.pubkey.clone()))))
+    }
+
+    async fn get_relay_list(&self, pub_key: &str) -> Result<Option<Event>> {
+        // relay lists are replaceable, so there is at most one per author.
+        Ok(self
+            .read()
+            .events
+            .iter()
+            .find(|s| !s.hidden && s.event.kind == KIND_RELAY_LIST && s.event.pubkey == pub_key)
+            .map(|s| s.event.clone()))
+    }
+
+    async fn apply_group_update(&self, update: &GroupUpdate) -> Result<()> {
+        let mut state = self.write();
+        match update {
+            GroupUpdate::Create { group, admin } => {
+                state
+                    .groups
+                    .entry(group.clone())
+                    .or_default()
+                    .insert(admin.clone(), GroupRole::Admin);
+            }
+            GroupUpdate::Delete { group } => {
+                state.groups.remove(group);
+            }
+            GroupUpdate::SetMember { group, pubkey, role } => {
+                if let Some(members) = state.groups.get_mut(group) {
+                    match role {
+                        Some(r) => members.insert(pubkey.clone(), *r),
+                        None => members.remove(pubkey),
+                    };
+                }
+            }
+        }
+        debug!("applied group update: {:?}", update);
+        Ok(())
+    }
+
+    async fn get_groups(&self) -> Result<Vec<Group>> {
+        Ok(self
+            .read()
+            .groups
+            .iter()
+            .map(|(id, members)| Group {
+                id: id.clone(),
+                members: members.clone(),
+            })
+            .collect())
+    }
+
+    async fn add_ban(&self, ban: &Ban) -> Result<()> {
+        let now = unix_time();
+        let mut state = self.write();
+        state.bans.retain(|_, b| b.is_active(now));
+        state.bans.insert((ban.target, ban.value.clone()), ban.clone());
+        Ok(())
+    }
+
+    async fn get_bans(&self) -> Result<Vec<Ban>> {
+        let now = unix_time();
+        Ok(self
+            .read()
+            .bans
+            .values()
+            .filter(|b| b.is_active(now))
+            .cloned()
+            .collect())
+    }
+
+    async fn get_allowed_pubkeys(&self) -> Result<Vec<String>> {
+        Ok(self.read().allowed_pubkeys.iter().cloned().collect())
+    }
+
+    async fn set_pubkey_allowed(&self, pubkey: &str, allowed: bool) -> Result<()> {
+        let mut state = self.write();
+        if allowed {
+            state.allowed_pubkeys.insert(pubkey.to_owned());
+        } else {
+            state.allowed_pubkeys.remove(pubkey);
+        }
+        Ok(())
+    }
+
+    async fn get_reputation(&self, pubkey: &str) -> Result<ReputationRecord> {
+        Ok(self.read().reputation.get(pubkey).cloned().unwrap_or_default())
+    }
+
+    async fn update_reputation(&self, pubkey: &str, change: ReputationChange) -> Result<()> {
+        self.write()
+            .reputation
+            .entry(pubkey.to_owned())
+            .or_default()
+            .apply(change);
+        Ok(())
+    }
+
+    async fn add_report(&self, report: &Report) -> Result<()> {
+        let key = (report.reporter.clone(), report.pubkey.clone(), report.event_id.clone());
+        self.write().reports.insert(key, report.clone());
+        Ok(())
+    }
+
+    async fn get_reports(&self, event_id: Option<&str>) -> Result<Vec<Report>> {
+        Ok(self
+            .read()
+            .reports
+            .values()
+            .filter(|r| event_id.is_none() || r.event_id.as_deref() == event_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn hide_event(&self, id: &str) -> Result<bool> {
+        let mut state = self.write();
+        match state.events.iter_mut().find(|s| s.event.id == id && !s.hidden) {
+            Some(s) => {
+                s.hidden = true;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn get_account(&self, pubkey: &str) -> Result<Option<Account>> {
+        Ok(self.read().accounts.get(pubkey).cloned())
+    }
+
+    async fn add_invoice(&self, invoice: &Invoice) -> Result<()> {
+        let mut state = self.write();
+        state
+            .accounts
+            .entry(invoice.pubkey.clone())
+            .or_insert_with(|| Account {
+                pubkey: invoice.pubkey.clone(),
+                admitted: false,
+                balance_msat: 0,
+                created_at: invoice.created_at,
+            });
+        state.invoices.insert(invoice.payment_hash.clone(), invoice.clone());
+        Ok(())
+    }
+
+    async fn get_unpaid_invoices(&self) -> Result<Vec<Invoice>> {
+        Ok(self.read().invoices.values().filter(|i| !i.paid).cloned().collect())
+    }
+
+    async fn invoice_paid(&self, payment_hash: &str) -> Result<bool> {
+        let mut state = self.write();
+        let Some(invoice) = state.invoices.get_mut(payment_hash).filter(|i| !i.paid) else {
+            return Ok(false);
+        };
+        invoice.paid = true;
+        let (pubkey, credit) = (invoice.pubkey.clone(), if invoice.topup { invoice.amount_msat } else { 0 });
+        if let Some(account) = state.accounts.get_mut(&pubkey) {
+            account.admitted = true;
+            account.balance_msat += credit;
+        }
+        Ok(true)
+    }
+
+    async fn debit_account(&self, pubkey: &str, amount_msat: u64) -> Result<bool> {
+        let mut state = self.write();
+        match state.accounts.get_mut(pubkey).filter(|a| a.balance_msat >= amount_msat) {
+            Some(account) => {
+                account.balance_msat -= amount_msat;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn credit_account(&self, pubkey: &str, amount_msat: u64) -> Result<()> {
+        if let Some(account) = self.write().accounts.get_mut(pubkey) {
+            account.balance_msat += amount_msat;
+        }
+        Ok(())
+    }
+
+    async fn optimize_db(&self) -> Result<()> {
+        self.write().evict(self.max_events, self.max_age);
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<()> {
+        // always available
+        Ok(())
+    }
+
+    async fn event_summaries(&self, order: ScanOrder, after: Option<&EventSummary>, limit: usize) -> Result<Vec<EventSummary>> {
+        let state = self.read();
+        let is_after = |created_at: u64, id: &String, a: &EventSummary| match order {
+            ScanOrder::NewestFirst => (created_at, id) < (a.created_at, &a.id),
+            ScanOrder::OldestFirst => (created_at, id) > (a.created_at, &a.id),
+        };
+        let mut found: Vec<EventSummary> = state
+            .events
+            .iter()
+            .filter(|s| after.is_none_or(|a| is_after(s.event.created_at, &s.event.id, a)))
+            .map(|s| EventSummary {
+                id: s.event.id.clone(),
+                pubkey: s.event.pubkey.clone(),
+                kind: s.event.kind,
+                created_at: s.event.created_at,
+            })
+            .collect();
+        found.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+        if order == ScanOrder::NewestFirst {
+            found.reverse();
+        }
+        found.truncate(limit);
+        Ok(found)
+    }
+
+    async fn used_bytes(&self) -> Result<u64> {
+        Ok(self.read().events.iter().map(|s| s.json.len() as u64).sum())
+    }
+
+    async fn author_bytes(&self, pubkey: &str) -> Result<u64> {
+        Ok(self
+            .read()
+            .events
+            .iter()
+            .filter(|s| !s.hidden && s.event.pubkey == pubkey)
+            .map(|s| s.json.len() as u64)
+            .sum())
+    }
+
+    async fn delete_events(&self, ids: &[String]) -> Result<u64> {
+        let ids: HashSet<&String> = ids.iter().collect();
+        let mut state = self.write();
+        let mut removed = vec![];
+        state.events.retain(|s| {
+            if ids.contains(&s.event.id) {
+                removed.push(s.event.clone());
+                false
+            } else {
+                true
+            }
+        });
+        for e in &removed {
+            state.forget(e);
+        }
+        Ok(removed.len() as u64)
+    }
+
+    async fn create_verification_record(&self, event_id: &str, name: &str) -> Result<()> {
+        let nip05 = Nip05Name::try_from(name)?;
+        let mut state = self.write();
+        let event = state
+            .events
+            .iter()
+            .find(|s| s.event.id == event_id)
+            .map(|s| s.event.clone())
+            .ok_or_else(|| Error::CustomError(format!("no event found for verification: {event_id}")))?;
+        // if we create a new one, we should get rid of any old ones.
+        state.verifications.retain(|_, v| v.name.to_string() != name);
+        state.next_verification += 1;
+        let rowid = state.next_verification;
+        state.verifications.insert(
+            rowid,
+            VerificationRecord {
+                rowid,
+                name: nip05,
+                address: event.pubkey,
+                event: event.id,
+                event_created: event.created_at,
+                last_success: Some(unix_time()),
+                last_failure: None,
+                failure_count: 0,
+            },
+        );
+        info!("saved new verification record for ({:?})", name);
+        Ok(())
+    }
+
+    async fn update_verification_timestamp(&self, id: u64) -> Result<()> {
+        // add some jitter to the verification to prevent everything from stacking up together.
+        let verify_time = now_jitter(600);
+        if let Some(v) = self.write().verifications.get_mut(&id) {
+            v.last_success = Some(verify_time);
+            v.failure_count = 0;
+        }
+        info!("verification updated for {}", id);
+        Ok(())
+    }
+
+    async fn fail_verification(&self, id: u64) -> Result<()> {
+        if let Some(v) = self.write().verifications.get_mut(&id) {
+            v.last_failure = Some(unix_time());
+            v.failure_count += 1;
+        }
+        Ok(())
+    }
+
+    async fn delete_verification(&self, id: u64) -> Result<()> {
+        self.write().verifications.remove(&id);
+        Ok(())
+    }
+
+    async fn get_latest_user_verification(&self, pub_key: &str) -> Result<VerificationRecord> {
+        self.read()
+            .verifications
+            .values()
+            .filter(|v| v.address == pub_key)
+            .max_by_key(|v| (v.event_created, v.last_success, v.last_failure))
+            .cloned()
+            .ok_or_else(no_rows)
+    }
+
+    async fn get_oldest_user_verification(&self, before: u64) -> Result<VerificationRecord> {
+        let due = |t: Option<u64>| t.is_none_or(|t| t < before);
+        // records never verified (or never failed) sort first
+        self.read()
+            .verifications
+            .values()
+            .filter(|v| due(v.last_success) && due(v.last_failure))
+            .min_by_key(|v| (v.last_success, v.last_failure))
+            .cloned()
+            .ok_or_else(no_rows)
+    }
+
+    async fn put_queued_verification(&self, queued: &QueuedVerification) -> Result<()> {
+        let mut state = self.write();
+        let pubkey = queued.event.pubkey.clone();
+        if state
+            .verification_queue
+            .get(&pubkey)
+            .is_none_or(|q| q.event.created_at <= queued.event.created_at)
+        {
+            state.verification_queue.insert(pubkey, queued.clone());
+        }
+        Ok(())
+    }
+
+    async fn get_queued_verifications(&self, due: u64, limit: usize) -> Result<Vec<QueuedVerification>> {
+        let mut queued: Vec<QueuedVerification> = self
+            .read()
+            .verification_queue
+            .values()
+            .filter(|q| q.retry_at <= due)
+            .cloned()
+            .collect();
+        queued.sort_by_key(|q| q.retry_at);
+        queued.truncate(limit);
+        Ok(queued)
+    }
+
+    async fn remove_queued_verification(&self, pubkey: &str, event_id: &str) -> Result<()> {
+        let mut state = self.write();
+        if state.verification_queue.get(pubkey).is_some_and(|q| q.event.id == event_id) {
+            state.verification_queue.remove(pubkey);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(id: char, kind: u64, created_at: u64) -> Event {
+        let mut e = Event::simple_event();
+        e.id = id.to_string().repeat(64);
+        e.pubkey = "a".repeat(64);
+        e.kind = kind;
+        e.created_at = created_at;
+        e
+    }
+
+    #[test]
+    fn ring_buffer_evicts_oldest() {
+        let mut state = State::default();
+        for (i, id) in ['1', '2', '3'].into_iter().enumerate() {
+            state.persist(&event(id, 1, i as u64)).unwrap();
+            state.evict(2, None);
+        }
+        assert_eq!(state.events.len(), 2);
+        assert!(!state.ids.contains(&"1".repeat(64)));
+        // an evicted event can be stored again
+        assert_eq!(state.persist(&event('1', 1, 0)).unwrap(), 1);
+    }
+
+    #[test]
+    fn replaceable_and_deleted() {
+        let mut state = State::default();
+        state.persist(&event('1', 0, 10)).unwrap();
+        assert_eq!(state.persist(&event('2', 0, 5)).unwrap(), 0);
+        assert_eq!(state.persist(&event('3', 0, 20)).unwrap(), 1);
+        assert_eq!(state.events.len(), 1);
+        let mut del = event('4', 5, 30);
+        del.tags = vec![vec!["e".to_owned(), "5".repeat(64)]];
+        state.persist(&del).unwrap();
+        // the deletion arrived first, so the event is hidden
+        assert_eq!(state.persist(&event('5', 1, 25)).unwrap(), 0);
+        let all: ReqFilter = serde_json::from_str("{}").unwrap();
+        assert_eq!(state.filter_matches(&all, None).len(), 2);
+    }
+}
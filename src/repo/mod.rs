@@ -1,16 +1,64 @@
+use crate::bans::Ban;
 use crate::db::QueryResult;
-use crate::error::Result;
+use crate::error::{Error, Result};
 use crate::event::Event;
-use crate::nip05::VerificationRecord;
-use crate::subscription::Subscription;
+use crate::groups::{Group, GroupUpdate};
+use crate::nip05::{QueuedVerification, VerificationRecord};
+use crate::payment::{Account, Invoice};
+use crate::reports::Report;
+use crate::reputation::{ReputationChange, ReputationRecord};
+use crate::subscription::{ReqFilter, Subscription};
 use crate::utils::unix_time;
 use async_trait::async_trait;
 use rand::Rng;
+use std::collections::HashMap;
+use std::path::Path;
 
 pub mod sqlite;
 pub mod sqlite_migration;
 pub mod postgres;
 pub mod postgres_migration;
+pub mod cache;
+pub mod check;
+pub mod copy;
+pub mod deadline;
+pub mod export;
+pub mod import;
+pub mod lmdb;
+pub mod memory;
+pub mod mysql;
+pub mod mysql_migration;
+pub mod planner;
+pub mod recent;
+pub mod sharded;
+pub mod stats;
+
+/// Identifying fields of a stored event.  Events are ordered by
+/// `created_at`, then by id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventSummary {
+    pub id: String,
+    pub pubkey: String,
+    pub kind: u64,
+    pub created_at: u64,
+}
+
+/// Order of an event scan
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanOrder {
+    NewestFirst,
+    OldestFirst,
+}
+
+impl ScanOrder {
+    /// Comparison operator and sort direction for a SQL keyset query.
+    pub(crate) fn sql(self) -> (&'static str, &'static str) {
+        match self {
+            ScanOrder::NewestFirst => ("<", "DESC"),
+            ScanOrder::OldestFirst => (">", "ASC"),
+        }
+    }
+}
 
 #[async_trait]
 pub trait NostrRepo: Send + Sync {
@@ -23,6 +71,17 @@ pub trait NostrRepo: Send + Sync {
     /// Persist event to database
     async fn write_event(&self, e: &Event) -> Result<u64>;
 
+    /// Persist a batch of events, returning the rows added for each.
+    /// Backends that support it write the whole batch in a single
+    /// transaction, so an error may mean none were written.
+    async fn write_events(&self, events: &[Event]) -> Result<Vec<u64>> {
+        let mut counts = Vec::with_capacity(events.len());
+        for e in events {
+            counts.push(self.write_event(e).await?);
+        }
+        Ok(counts)
+    }
+
     /// Perform a database query using a subscription.
     ///
     /// The [`Subscription`] is converted into a SQL query.  Each result
@@ -37,9 +96,140 @@ pub trait NostrRepo: Send + Sync {
         mut abandon_query_rx: tokio::sync::oneshot::Receiver<()>,
     ) -> Result<()>;
 
+    /// Count the distinct events matching any of the given filters.
+    async fn count_events_by_filter(&self, filters: Vec<ReqFilter>) -> Result<u64>;
+
+    /// Check if an event has been deleted by its author (NIP-09).
+    /// Events hidden for other reasons, such as moderation, are not.
+    async fn is_event_deleted(&self, id: &str) -> Result<bool>;
+
+    /// Get the latest relay list (NIP-65) published by a pubkey
+    async fn get_relay_list(&self, pub_key: &str) -> Result<Option<Event>>;
+
+    /// Persist a change to group state (NIP-29)
+    async fn apply_group_update(&self, update: &GroupUpdate) -> Result<()>;
+
+    /// Get all groups and their members
+    async fn get_groups(&self) -> Result<Vec<Group>>;
+
+    /// Store a ban, replacing any other for the same IP address or
+    /// pubkey.  Expired bans are removed.
+    async fn add_ban(&self, ban: &Ban) -> Result<()>;
+
+    /// Get all bans that have not expired
+    async fn get_bans(&self) -> Result<Vec<Ban>>;
+
+    /// Get the pubkeys stored in the whitelist
+    async fn get_allowed_pubkeys(&self) -> Result<Vec<String>>;
+
+    /// Add a pubkey to the stored whitelist, or remove it
+    async fn set_pubkey_allowed(&self, pubkey: &str, allowed: bool) -> Result<()>;
+
+    /// Get the stored reputation of a pubkey (empty, if none)
+    async fn get_reputation(&self, pubkey: &str) -> Result<ReputationRecord>;
+
+    /// Update the stored reputation of a pubkey
+    async fn update_reputation(&self, pubkey: &str, change: ReputationChange) -> Result<()>;
+
+    /// Store a report, replacing any earlier one by the same reporter
+    /// of the same pubkey and event
+    async fn add_report(&self, report: &Report) -> Result<()>;
+
+    /// Get all stored reports, or those of one event
+    async fn get_reports(&self, event_id: Option<&str>) -> Result<Vec<Report>>;
+
+    /// Get the distinct pubkeys that have reported a pubkey, or any of
+    /// its events
+    async fn get_reporters(&self, pubkey: &str) -> Result<Vec<String>> {
+        let mut reporters: Vec<String> = self
+            .get_reports(None)
+            .await?
+            .into_iter()
+            .filter(|r| r.pubkey == pubkey)
+            .map(|r| r.reporter)
+            .collect();
+        reporters.sort();
+        reporters.dedup();
+        Ok(reporters)
+    }
+
+    /// Hide an event from queries.  Returns false if it was not
+    /// found, or was already hidden.
+    async fn hide_event(&self, id: &str) -> Result<bool>;
+
+    /// Get the account of a pubkey, if it has one
+    async fn get_account(&self, pubkey: &str) -> Result<Option<Account>>;
+
+    /// Store a new invoice, creating an account for its pubkey if it
+    /// has none
+    async fn add_invoice(&self, invoice: &Invoice) -> Result<()>;
+
+    /// Get all unpaid invoices
+    async fn get_unpaid_invoices(&self) -> Result<Vec<Invoice>>;
+
+    /// Mark an invoice paid, admit its pubkey, and credit its balance
+    /// for a top-up.  Returns false if it was not found, or was
+    /// already paid.
+    async fn invoice_paid(&self, payment_hash: &str) -> Result<bool>;
+
+    /// Debit the balance of a pubkey.  Returns false (and debits
+    /// nothing) if the balance is too low.
+    async fn debit_account(&self, pubkey: &str, amount_msat: u64) -> Result<bool>;
+
+    /// Credit the balance of a pubkey that has an account, such as to
+    /// refund a debit.
+    async fn credit_account(&self, pubkey: &str, amount_msat: u64) -> Result<()>;
+
     /// Perform normal maintenance
     async fn optimize_db(&self) -> Result<()>;
 
+    /// Reclaim the space of deleted events, which may take a long
+    /// time and block writes.  Backends that reuse free space only
+    /// perform normal maintenance.
+    async fn compact(&self) -> Result<()> {
+        self.optimize_db().await
+    }
+
+    /// Reclaim free space while the relay runs, without blocking
+    /// writes for long.  Returns the number of bytes released.
+    async fn vacuum(&self) -> Result<u64> {
+        self.optimize_db().await?;
+        Ok(0)
+    }
+
+    /// Write a consistent copy of the database to a file, while the
+    /// relay runs.  Returns false if the backend cannot copy itself,
+    /// in which case its events are exported instead.
+    async fn backup(&self, _dest: &Path) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Check that the database answers a trivial query.
+    async fn ping(&self) -> Result<()>;
+
+    /// Check that the database's indexes agree with its events,
+    /// returning a description of each problem found.  Backends that
+    /// cannot check report none.
+    async fn check_integrity(&self) -> Result<Vec<String>> {
+        Ok(vec![])
+    }
+
+    /// Get summaries of stored events (including hidden ones), in the
+    /// given order.  Only events ordered after `after` are returned, so
+    /// the last summary of one page can be used to fetch the next.
+    async fn event_summaries(&self, order: ScanOrder, after: Option<&EventSummary>, limit: usize) -> Result<Vec<EventSummary>>;
+
+    /// Approximate number of bytes used to store events.  This shrinks
+    /// as events are deleted, even if the database files do not.
+    async fn used_bytes(&self) -> Result<u64>;
+
+    /// Bytes of the visible stored events of an author, as JSON.
+    async fn author_bytes(&self, pubkey: &str) -> Result<u64>;
+
+    /// Permanently remove events, along with their tags and any
+    /// verification records that refer to them.
+    async fn delete_events(&self, ids: &[String]) -> Result<u64>;
+
     /// Create a new verification record connected to a specific event
     async fn create_verification_record(&self, event_id: &str, name: &str) -> Result<()>;
 
@@ -57,6 +247,79 @@ pub trait NostrRepo: Send + Sync {
 
     /// Get oldest verification before timestamp
     async fn get_oldest_user_verification(&self, before: u64) -> Result<VerificationRecord>;
+
+    /// Queue a metadata event for NIP-05 verification, replacing any
+    /// queued for the same author that is not newer.
+    async fn put_queued_verification(&self, queued: &QueuedVerification) -> Result<()>;
+
+    /// Get queued verifications that are due, oldest first.
+    async fn get_queued_verifications(&self, due: u64, limit: usize) -> Result<Vec<QueuedVerification>>;
+
+    /// Remove a queued verification, unless a newer event replaced it.
+    async fn remove_queued_verification(&self, pubkey: &str, event_id: &str) -> Result<()>;
+}
+
+#[derive(serde::Deserialize)]
+struct EventId {
+    id: String,
+}
+
+/// Maximum ids in a single fetch query.  `SQLite` rejects expressions
+/// nested deeper than 1000, and each id adds an `OR` term.
+const FETCH_BATCH: usize = 250;
+
+/// Serialized (non-hidden) events with the given ids, fetched with
+/// subscription queries and keyed by id.
+pub(crate) async fn fetch_events(repo: &dyn NostrRepo, ids: &[String]) -> Result<HashMap<String, String>> {
+    let mut events = HashMap::new();
+    for chunk in ids.chunks(FETCH_BATCH) {
+        let sub = Subscription {
+            id: "fetch".to_owned(),
+            filters: vec![ReqFilter {
+                ids: Some(chunk.to_vec()),
+                kinds: None,
+                since: None,
+                until: None,
+                authors: None,
+                limit: Some(chunk.len() as u64),
+                tags: None,
+                and_tags: None,
+                long_tags: None,
+                search: None,
+                force_no_match: false,
+            }],
+        };
+        let (query_tx, mut query_rx) = tokio::sync::mpsc::channel(chunk.len() + 1);
+        let (_abandon_tx, abandon_rx) = tokio::sync::oneshot::channel();
+        repo.query_subscription(sub, "internal".to_owned(), query_tx, abandon_rx)
+            .await?;
+        while let Some(res) = query_rx.recv().await {
+            if res.event == "EOSE" {
+                break;
+            }
+            if let Ok(e) = serde_json::from_str::<EventId>(&res.event) {
+                events.insert(e.id, res.event);
+            }
+        }
+    }
+    Ok(events)
+}
+
+/// Serialized events matching a subscription, in the order they are
+/// returned by the repository.  Each filter should have a limit.
+pub(crate) async fn collect_events(repo: &dyn NostrRepo, sub: Subscription, client_id: String) -> Result<Vec<String>> {
+    let capacity: u64 = sub.filters.iter().map(|f| f.limit.unwrap_or(FETCH_BATCH as u64)).sum();
+    let (query_tx, mut query_rx) = tokio::sync::mpsc::channel(capacity as usize + 1);
+    let (_abandon_tx, abandon_rx) = tokio::sync::oneshot::channel();
+    repo.query_subscription(sub, client_id, query_tx, abandon_rx).await?;
+    let mut events = vec![];
+    while let Some(res) = query_rx.recv().await {
+        if res.event == "EOSE" {
+            break;
+        }
+        events.push(res.event);
+    }
+    Ok(events)
 }
 
 // Current time, with a slight forward jitter in seconds
@@ -67,3 +330,9 @@ pub(crate) fn now_jitter(sec: u64) -> u64 {
     let now = unix_time();
     now.saturating_add(jitter_amount)
 }
+
+/// A NIP-05 verification lookup found nothing.  Callers treat this
+/// error as "no record", as for the `SQLite` repository.
+pub(crate) fn no_rows() -> Error {
+    Error::SqlError(rusqlite::Error::QueryReturnedNoRows)
+}
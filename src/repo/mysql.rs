@@ -0,0 +1,1353 @@
+//! `MySQL` / `MariaDB` event storage
+use crate::bans::{Ban, BanTarget};
+use crate::db::QueryResult;
+use crate::error::Result;
+use crate::event::{searchable_tagname, Event};
+use crate::groups::{Group, GroupRole, GroupUpdate};
+use crate::nip05::{Nip05Name, QueuedVerification, VerificationRecord};
+use crate::nip65::KIND_RELAY_LIST;
+use crate::payment::{Account, Invoice};
+use crate::repo::planner::{self, Access};
+use crate::repo::{now_jitter, EventSummary, NostrRepo, ScanOrder};
+use crate::reports::Report;
+use crate::reputation::{ReputationChange, ReputationRecord};
+use crate::subscription::{ReqFilter, Subscription};
+use crate::utils::unix_time;
+use async_std::stream::StreamExt;
+use async_trait::async_trait;
+use sqlx::mysql::MySqlRow;
+use sqlx::Error::RowNotFound;
+use sqlx::{Error, Execute, FromRow, MySql, QueryBuilder, Row, Transaction};
+use std::time::{Duration, Instant};
+
+use crate::error;
+use crate::hexrange::{hex_range, HexSearch};
+use crate::repo::mysql_migration::run_migrations;
+use crate::server::NostrMetrics;
+use crate::utils::{is_hex, is_lower_hex};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot::Receiver;
+use tracing::log::trace;
+use tracing::{debug, error, info};
+
+pub type MysqlPool = sqlx::pool::Pool<MySql>;
+
+/// Longest tag value that is indexed; this is the width of the
+/// `tag.value` column.
+const MAX_TAG_VALUE_LEN: usize = 1024;
+
+pub struct MysqlRepo {
+    conn: MysqlPool,
+    metrics: NostrMetrics,
+    multi_letter_tags: bool,
+}
+
+impl MysqlRepo {
+    pub fn new(c: MysqlPool, m: NostrMetrics) -> MysqlRepo {
+        MysqlRepo {
+            conn: c,
+            metrics: m,
+            multi_letter_tags: false,
+        }
+    }
+
+    /// Index tags with multi-letter names, as well as single-letter
+    /// tags.
+    #[must_use]
+    pub fn with_multi_letter_tags(mut self, enabled: bool) -> MysqlRepo {
+        self.multi_letter_tags = enabled;
+        self
+    }
+}
+
+/// Stored form of a tag value.  Lowercase, even-length hex is stored
+/// as the bytes it encodes; anything else as its UTF-8 bytes.
+fn tag_value_bytes(v: &str) -> Vec<u8> {
+    if is_lower_hex(v) && v.len().is_multiple_of(2) {
+        if let Ok(b) = hex::decode(v) {
+            return b;
+        }
+    }
+    v.as_bytes().to_vec()
+}
+
+/// Escape a search term for use in a `LIKE` pattern.
+fn like_pattern(term: &str) -> String {
+    let mut p = String::with_capacity(term.len() + 2);
+    p.push('%');
+    for c in term.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            p.push('\\');
+        }
+        p.push(c);
+    }
+    p.push('%');
+    p
+}
+
+/// Persist an event within a transaction, returning rows added.
+async fn persist_event(tx: &mut Transaction<'_, MySql>, e: &Event, multi_letter_tags: bool) -> Result<u64> {
+    // get relevant fields from event and convert to blobs.
+    let id_blob = hex::decode(&e.id).ok();
+    let pubkey_blob: Option<Vec<u8>> = hex::decode(&e.pubkey).ok();
+    let delegator_blob: Option<Vec<u8>> =
+        e.delegated_by.as_ref().and_then(|d| hex::decode(d).ok());
+    let event_str = serde_json::to_string(&e).unwrap();
+
+    // determine if this event would be shadowed by an existing
+    // replaceable event or parameterized replaceable event.
+    if e.is_replaceable() {
+        let repl_count = sqlx::query(
+            "SELECT e.id FROM event e WHERE e.pub_key=? AND e.kind=? AND e.created_at >= ? LIMIT 1")
+            .bind(&pubkey_blob)
+            .bind(e.kind as i64)
+            .bind(e.created_at as i64)
+            .fetch_optional(&mut *tx)
+            .await?;
+        if repl_count.is_some() {
+            return Ok(0);
+        }
+    }
+    if let Some(d_tag) = e.distinct_param() {
+        let repl_count = sqlx::query(
+            "SELECT e.id FROM event e INNER JOIN tag t ON e.id=t.event_id WHERE e.pub_key=? AND e.kind=? AND t.name='d' AND t.value=? AND e.created_at >= ? LIMIT 1")
+            .bind(&pubkey_blob)
+            .bind(e.kind as i64)
+            .bind(tag_value_bytes(&d_tag))
+            .bind(e.created_at as i64)
+            .fetch_optional(&mut *tx)
+            .await?;
+        // if any rows were returned, then some newer event with
+        // the same author/kind/tag value exist, and we can ignore
+        // this event.
+        if repl_count.is_some() {
+            return Ok(0);
+        }
+    }
+
+    // ignore if the event hash is a duplicate.  The no-op update
+    // leaves the affected row count at zero for duplicates.
+    let mut ins_count = sqlx::query(
+        "INSERT INTO event (id, pub_key, created_at, kind, content, delegated_by, first_seen, search_text) \
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?) ON DUPLICATE KEY UPDATE id=id",
+    )
+    .bind(&id_blob)
+    .bind(&pubkey_blob)
+    .bind(e.created_at as i64)
+    .bind(e.kind as i64)
+    .bind(event_str.into_bytes())
+    .bind(delegator_blob)
+    .bind(unix_time() as i64)
+    .bind(&e.content)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    if ins_count == 0 {
+        // if the event was a duplicate, no need to insert event or
+        // pubkey references.  This will abort the txn.
+        return Ok(0);
+    }
+
+    // add all tags to the tag table
+    let tag_insert = "INSERT INTO tag (event_id, name, value) VALUES (?, ?, ?) \
+                      ON DUPLICATE KEY UPDATE event_id=event_id";
+    for tag in e.tags.iter() {
+        // ensure we have 2 values.
+        if tag.len() >= 2 {
+            let tag_name = &tag[0];
+            // only single-char tags are searchable, unless
+            // multi-letter tags are indexed
+            if !searchable_tagname(tag_name, multi_letter_tags) {
+                continue;
+            }
+            let tag_val = tag_value_bytes(&tag[1]);
+            if tag_val.len() > MAX_TAG_VALUE_LEN {
+                trace!("not indexing long tag value for event {:?}", e.get_event_id_prefix());
+                continue;
+            }
+            sqlx::query(tag_insert)
+                .bind(&id_blob)
+                .bind(tag_name)
+                .bind(tag_val)
+                .execute(&mut *tx)
+                .await?;
+        }
+    }
+    // parameterized replaceable events without a `d` value are
+    // keyed by the empty string; record that so that newer
+    // versions can locate (and replace) this one.
+    if e.distinct_param().as_deref() == Some("") && !e.tags.iter().any(|t| t.len() >= 2 && t[0] == "d" && t[1].is_empty()) {
+        sqlx::query(tag_insert)
+            .bind(&id_blob)
+            .bind("d")
+            .bind(Vec::<u8>::new())
+            .execute(&mut *tx)
+            .await?;
+    }
+    // at this point, the new event is the most recent version of
+    // any replaceable event; older versions can be removed.
+    if e.is_replaceable() {
+        let update_count = sqlx::query("DELETE FROM event WHERE kind=? AND pub_key=? AND id != ?")
+            .bind(e.kind as i64)
+            .bind(&pubkey_blob)
+            .bind(&id_blob)
+            .execute(&mut *tx)
+            .await?.rows_affected();
+        if update_count > 0 {
+            info!(
+                "hid {} older replaceable kind {} events for author: {:?}",
+                update_count,
+                e.kind,
+                e.get_author_prefix()
+            );
+        }
+    }
+    // parameterized replaceable events
+    if let Some(d_tag) = e.distinct_param() {
+        // MySQL cannot delete from a table named in a subquery,
+        // unless the subquery is materialized as a derived table.
+        let update_count = sqlx::query("DELETE FROM event WHERE id IN (SELECT id FROM \
+            (SELECT e.id FROM event e INNER JOIN tag t ON e.id=t.event_id \
+            WHERE e.kind=? AND e.pub_key=? AND t.name='d' AND t.value=? AND e.id != ?) AS older)")
+            .bind(e.kind as i64)
+            .bind(&pubkey_blob)
+            .bind(tag_value_bytes(&d_tag))
+            .bind(&id_blob)
+            .execute(&mut *tx)
+            .await?.rows_affected();
+        if update_count > 0 {
+            info!(
+                "removed {} older parameterized replaceable kind {} events for author: {:?}",
+                update_count,
+                e.kind,
+                e.get_author_prefix()
+            );
+        }
+    }
+    // if this event is a deletion, hide the referenced events from the same author.
+    if e.kind == 5 {
+        let event_candidates = e.tag_values_by_name("e");
+        let pub_keys: Vec<Vec<u8>> = event_candidates
+            .iter()
+            .filter(|x| is_hex(x) && x.len() == 64)
+            .filter_map(|x| hex::decode(x).ok())
+            .collect();
+
+        // an empty IN list is a syntax error, and there is nothing to hide.
+        if !pub_keys.is_empty() {
+            let mut builder = QueryBuilder::new(
+                "UPDATE event SET hidden = TRUE WHERE kind != 5 AND pub_key = ",
+            );
+            builder.push_bind(&pubkey_blob);
+            builder.push(" AND id IN (");
+
+            let mut sep = builder.separated(", ");
+            for pk in pub_keys {
+                sep.push_bind(pk);
+            }
+            sep.push_unseparated(")");
+
+            let update_count = builder.build().execute(&mut *tx).await?.rows_affected();
+            info!(
+                "hid {} deleted events for author {:?}",
+                update_count,
+                e.get_author_prefix()
+            );
+        }
+    } else {
+        // check if a deletion has already been recorded for this event.
+        // Only relevant for non-deletion events
+        let del_count = sqlx::query(
+            "SELECT e.id FROM event e \
+        INNER JOIN tag t ON e.id = t.event_id \
+        WHERE e.pub_key = ? AND t.name = 'e' AND e.kind = 5 AND t.value = ? LIMIT 1",
+        )
+        .bind(&pubkey_blob)
+        .bind(&id_blob)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        // check if a the query returned a result, meaning we should
+        // hid the current event
+        if del_count.is_some() {
+            // a deletion already existed, mark original event as hidden.
+            info!(
+                "hid event: {:?} due to existing deletion by author: {:?}",
+                e.get_event_id_prefix(),
+                e.get_author_prefix()
+            );
+            sqlx::query("UPDATE event SET hidden = TRUE WHERE id = ?")
+                .bind(&id_blob)
+                .execute(&mut *tx)
+                .await?;
+            // event was deleted, so let caller know nothing new
+            // arrived, preventing this from being sent to active
+            // subscriptions
+            ins_count = 0;
+        }
+    }
+    Ok(ins_count)
+}
+
+#[async_trait]
+impl NostrRepo for MysqlRepo {
+    async fn start(&self) -> Result<()> {
+        info!("not implemented");
+        Ok(())
+    }
+
+    async fn migrate_up(&self) -> Result<usize> {
+        Ok(run_migrations(&self.conn).await?)
+    }
+
+    async fn write_event(&self, e: &Event) -> Result<u64> {
+        // start transaction
+        let mut tx = self.conn.begin().await?;
+        let start = Instant::now();
+        let ins_count = persist_event(&mut tx, e, self.multi_letter_tags).await?;
+        tx.commit().await?;
+        self.metrics
+            .write_events
+            .observe(start.elapsed().as_secs_f64());
+        Ok(ins_count)
+    }
+
+    /// Persist a batch of events in one transaction
+    async fn write_events(&self, events: &[Event]) -> Result<Vec<u64>> {
+        let mut tx = self.conn.begin().await?;
+        let start = Instant::now();
+        let mut counts = Vec::with_capacity(events.len());
+        for e in events {
+            counts.push(persist_event(&mut tx, e, self.multi_letter_tags).await?);
+        }
+        tx.commit().await?;
+        self.metrics
+            .write_events
+            .observe(start.elapsed().as_secs_f64());
+        Ok(counts)
+    }
+
+    async fn query_subscription(
+        &self,
+        sub: Subscription,
+        client_id: String,
+        query_tx: Sender<QueryResult>,
+        mut abandon_query_rx: Receiver<()>,
+    ) -> Result<()> {
+        let start = Instant::now();
+        let mut row_count: usize = 0;
+        let metrics = &self.metrics;
+
+        for filter in sub.filters.iter() {
+            let start = Instant::now();
+            // generate SQL query
+            let Some(mut q_filter) = query_from_filter(filter) else {
+                debug!("Failed to generate query!");
+                continue;
+            };
+
+            debug!("SQL generated in {:?}", start.elapsed());
+
+            // cutoff for displaying slow queries
+            let slow_cutoff = Duration::from_millis(2000);
+
+            // any client that doesn't cause us to generate new rows in 5
+            // seconds gets dropped.
+            let abort_cutoff = Duration::from_secs(5);
+
+            let start = Instant::now();
+            let mut last_successful_send = Instant::now();
+
+            // execute the query. Don't cache, since queries vary so much.
+            let q_build = q_filter.build();
+            let sql = q_build.sql();
+            let mut results = q_build.fetch(&self.conn);
+
+            let mut first_result = true;
+            while let Some(row) = results.next().await {
+                let row = match row {
+                    Ok(r) => r,
+                    Err(e) => {
+                        error!("Query failed: {} {} {:?}", e, sql, filter);
+                        break;
+                    }
+                };
+                let first_event_elapsed = start.elapsed();
+                if first_result {
+                    debug!(
+                        "first result in {:?} (cid: {}, sub: {:?})",
+                        first_event_elapsed, client_id, sub.id
+                    );
+                    first_result = false;
+                }
+
+                // logging for slow queries; show sub and SQL.
+                // to reduce logging; only show 1/16th of clients (leading 0)
+                if first_event_elapsed >= slow_cutoff && client_id.starts_with("00") {
+                    debug!(
+                        "query req (slow): {:?} (cid: {}, sub: {:?})",
+                        &sub, client_id, sub.id
+                    );
+                }
+
+                // check if this is still active; every 100 rows
+                if row_count.is_multiple_of(100) && abandon_query_rx.try_recv().is_ok() {
+                    debug!(
+                        "query cancelled by client (cid: {}, sub: {:?})",
+                        client_id, sub.id
+                    );
+                    return Ok(());
+                }
+
+                row_count += 1;
+                let event_json: Vec<u8> = row.get(0);
+                while query_tx.capacity() == 0 {
+                    // the queue is full
+                    trace!("db reader thread is stalled");
+                    if last_successful_send + abort_cutoff < Instant::now() {
+                        // the queue has been full for too long, abort
+                        info!("aborting database query due to slow client");
+                        metrics
+                            .query_aborts
+                            .with_label_values(&["slowclient"])
+                            .inc();
+                        return Ok(());
+                    }
+                    // give the queue a chance to clear before trying again
+                    async_std::task::sleep(Duration::from_millis(100)).await;
+                }
+
+                query_tx
+                    .send(QueryResult {
+                        sub_id: sub.get_id(),
+                        event: String::from_utf8(event_json).unwrap_or_default(),
+                    })
+                    .await
+                    .ok();
+                last_successful_send = Instant::now();
+            }
+        }
+        query_tx
+            .send(QueryResult {
+                sub_id: sub.get_id(),
+                event: "EOSE".to_string(),
+            })
+            .await
+            .ok();
+        self.metrics
+            .query_sub
+            .observe(start.elapsed().as_secs_f64());
+        debug!(
+            "query completed in {:?} (cid: {}, sub: {:?}, rows: {})",
+            start.elapsed(),
+            client_id,
+            sub.id,
+            row_count
+        );
+        Ok(())
+    }
+
+    async fn count_events_by_filter(&self, filters: Vec<ReqFilter>) -> Result<u64> {
+        let start = Instant::now();
+        let count: i64 = match count_query_from_filters(&filters) {
+            Some(mut q) => q.build().fetch_one(&self.conn).await?.try_get(0)?,
+            None => 0,
+        };
+        self.metrics
+            .query_db
+            .observe(start.elapsed().as_secs_f64());
+        debug!("count query completed in {:?} (count: {})", start.elapsed(), count);
+        Ok(count as u64)
+    }
+
+    async fn is_event_deleted(&self, id: &str) -> Result<bool> {
+        // deleted events are retained, but hidden; other hidden
+        // events lack a deletion from their author.
+        let id_blob = hex::decode(id).ok();
+        let row = sqlx::query("SELECT d.id FROM event e JOIN event d ON d.pub_key = e.pub_key JOIN tag t ON d.id = t.event_id \
+            WHERE e.id = ? AND e.hidden = TRUE AND d.kind = 5 AND t.name = 'e' AND t.value = ? LIMIT 1")
+            .bind(&id_blob)
+            .bind(&id_blob)
+            .fetch_optional(&self.conn)
+            .await?;
+        Ok(row.is_some())
+    }
+
+    async fn get_relay_list(&self, pub_key: &str) -> Result<Option<Event>> {
+        let row = sqlx::query("SELECT e.content FROM event e WHERE e.pub_key = ? AND e.kind = ? AND e.hidden = FALSE ORDER BY e.created_at DESC LIMIT 1")
+            .bind(hex::decode(pub_key).ok())
+            .bind(KIND_RELAY_LIST as i64)
+            .fetch_optional(&self.conn)
+            .await?;
+        match row {
+            Some(r) => {
+                let content: Vec<u8> = r.get(0);
+                Ok(Some(serde_json::from_slice(&content)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn apply_group_update(&self, update: &GroupUpdate) -> Result<()> {
+        let mut tx = self.conn.begin().await?;
+        let upsert_member = "INSERT INTO group_member (group_id, pub_key, `role`) VALUES (?, ?, ?) \
+                             ON DUPLICATE KEY UPDATE `role` = VALUES(`role`)";
+        match update {
+            GroupUpdate::Create { group, admin } => {
+                sqlx::query("INSERT INTO relay_group (id, created_at) VALUES (?, ?) ON DUPLICATE KEY UPDATE id=id")
+                    .bind(group)
+                    .bind(unix_time() as i64)
+                    .execute(&mut tx)
+                    .await?;
+                sqlx::query(upsert_member)
+                    .bind(group)
+                    .bind(hex::decode(admin).ok())
+                    .bind(GroupRole::Admin.as_str())
+                    .execute(&mut tx)
+                    .await?;
+            }
+            GroupUpdate::Delete { group } => {
+                sqlx::query("DELETE FROM relay_group WHERE id = ?")
+                    .bind(group)
+                    .execute(&mut tx)
+                    .await?;
+            }
+            GroupUpdate::SetMember { group, pubkey, role: Some(role) } => {
+                sqlx::query(upsert_member)
+                    .bind(group)
+                    .bind(hex::decode(pubkey).ok())
+                    .bind(role.as_str())
+                    .execute(&mut tx)
+                    .await?;
+            }
+            GroupUpdate::SetMember { group, pubkey, role: None } => {
+                sqlx::query("DELETE FROM group_member WHERE group_id = ? AND pub_key = ?")
+                    .bind(group)
+                    .bind(hex::decode(pubkey).ok())
+                    .execute(&mut tx)
+                    .await?;
+            }
+        }
+        tx.commit().await?;
+        debug!("applied group update: {:?}", update);
+        Ok(())
+    }
+
+    async fn get_groups(&self) -> Result<Vec<Group>> {
+        let rows = sqlx::query("SELECT g.id, m.pub_key, m.`role` FROM relay_group g LEFT JOIN group_member m ON g.id = m.group_id ORDER BY g.id")
+            .fetch_all(&self.conn)
+            .await?;
+        let mut groups: Vec<Group> = vec![];
+        for row in rows {
+            let id: String = row.get(0);
+            if groups.last().map(|g| &g.id) != Some(&id) {
+                groups.push(Group { id, ..Default::default() });
+            }
+            let pubkey: Option<Vec<u8>> = row.get(1);
+            let role: Option<String> = row.get(2);
+            if let (Some(g), Some(pk), Some(r)) = (groups.last_mut(), pubkey, role.as_deref().and_then(GroupRole::from_name)) {
+                g.members.insert(hex::encode(pk), r);
+            }
+        }
+        Ok(groups)
+    }
+
+    async fn add_ban(&self, ban: &Ban) -> Result<()> {
+        let mut tx = self.conn.begin().await?;
+        sqlx::query("DELETE FROM ban WHERE expires_at <= ?")
+            .bind(unix_time() as i64)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("INSERT INTO ban (target, value, expires_at, reason) VALUES (?, ?, ?, ?) \
+                     ON DUPLICATE KEY UPDATE expires_at = VALUES(expires_at), reason = VALUES(reason)")
+            .bind(ban.target.as_str())
+            .bind(&ban.value)
+            .bind(ban.expires_at.map(|t| t as i64))
+            .bind(&ban.reason)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_bans(&self) -> Result<Vec<Ban>> {
+        let rows = sqlx::query("SELECT target, value, expires_at, reason FROM ban WHERE expires_at IS NULL OR expires_at > ?")
+            .bind(unix_time() as i64)
+            .fetch_all(&self.conn)
+            .await?;
+        Ok(rows
+            .iter()
+            .filter_map(|row| {
+                let expires_at: Option<i64> = row.get(2);
+                Some(Ban {
+                    target: BanTarget::from_name(row.get(0))?,
+                    value: row.get(1),
+                    expires_at: expires_at.map(|t| t as u64),
+                    reason: row.get(3),
+                })
+            })
+            .collect())
+    }
+
+    async fn get_allowed_pubkeys(&self) -> Result<Vec<String>> {
+        let rows = sqlx::query("SELECT pub_key FROM allowed_pubkey")
+            .fetch_all(&self.conn)
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| hex::encode(row.get::<Vec<u8>, _>(0)))
+            .collect())
+    }
+
+    async fn set_pubkey_allowed(&self, pubkey: &str, allowed: bool) -> Result<()> {
+        let pubkey = hex::decode(pubkey)?;
+        if allowed {
+            sqlx::query("INSERT IGNORE INTO allowed_pubkey (pub_key, created_at) VALUES (?, ?)")
+                .bind(pubkey)
+                .bind(unix_time() as i64)
+                .execute(&self.conn)
+                .await?;
+        } else {
+            sqlx::query("DELETE FROM allowed_pubkey WHERE pub_key = ?")
+                .bind(pubkey)
+                .execute(&self.conn)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn get_reputation(&self, pubkey: &str) -> Result<ReputationRecord> {
+        let row = sqlx::query("SELECT first_seen, reports, spam_hits FROM reputation WHERE pub_key = ?")
+            .bind(hex::decode(pubkey)?)
+            .fetch_optional(&self.conn)
+            .await?;
+        Ok(row
+            .map(|row| {
+                let first_seen: Option<i64> = row.get(0);
+                let reports: i64 = row.get(1);
+                let spam_hits: i64 = row.get(2);
+                ReputationRecord {
+                    first_seen: first_seen.map(|t| t as u64),
+                    reports: reports as u64,
+                    spam_hits: spam_hits as u64,
+                }
+            })
+            .unwrap_or_default())
+    }
+
+    async fn update_reputation(&self, pubkey: &str, change: ReputationChange) -> Result<()> {
+        let (first_seen, reports, spam_hits) = match change {
+            ReputationChange::Seen(t) => (Some(t as i64), None, 0i64),
+            ReputationChange::Reported(n) => (None, Some(n as i64), 0),
+            ReputationChange::Spam => (None, None, 1),
+        };
+        sqlx::query("INSERT INTO reputation (pub_key, first_seen, reports, spam_hits) VALUES (?, ?, COALESCE(?, 0), ?) \
+                     ON DUPLICATE KEY UPDATE first_seen = COALESCE(first_seen, VALUES(first_seen)), \
+                     reports = COALESCE(?, reports), spam_hits = spam_hits + VALUES(spam_hits)")
+            .bind(hex::decode(pubkey)?)
+            .bind(first_seen)
+            .bind(reports)
+            .bind(spam_hits)
+            .bind(reports)
+            .execute(&self.conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn add_report(&self, report: &Report) -> Result<()> {
+        let event_id = match &report.event_id {
+            Some(id) => hex::decode(id)?,
+            None => vec![],
+        };
+        sqlx::query("INSERT INTO report (reporter, pub_key, event_id, report_type, created_at) VALUES (?, ?, ?, ?, ?) \
+                     ON DUPLICATE KEY UPDATE report_type = VALUES(report_type), created_at = VALUES(created_at)")
+            .bind(hex::decode(&report.reporter)?)
+            .bind(hex::decode(&report.pubkey)?)
+            .bind(event_id)
+            .bind(&report.report_type)
+            .bind(report.created_at as i64)
+            .execute(&self.conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_reports(&self, event_id: Option<&str>) -> Result<Vec<Report>> {
+        let sql = "SELECT reporter, pub_key, event_id, report_type, created_at FROM report";
+        let rows = match event_id {
+            Some(id) => sqlx::query(&format!("{sql} WHERE event_id = ?"))
+                .bind(hex::decode(id)?)
+                .fetch_all(&self.conn)
+                .await?,
+            None => sqlx::query(sql).fetch_all(&self.conn).await?,
+        };
+        Ok(rows
+            .iter()
+            .map(|row| {
+                let event_id: Vec<u8> = row.get(2);
+                let created_at: i64 = row.get(4);
+                Report {
+                    reporter: hex::encode(row.get::<Vec<u8>, _>(0)),
+                    pubkey: hex::encode(row.get::<Vec<u8>, _>(1)),
+                    event_id: (!event_id.is_empty()).then(|| hex::encode(event_id)),
+                    report_type: row.get(3),
+                    created_at: created_at as u64,
+                }
+            })
+            .collect())
+    }
+
+    async fn hide_event(&self, id: &str) -> Result<bool> {
+        let res = sqlx::query("UPDATE event SET hidden = TRUE WHERE id = ? AND hidden = FALSE")
+            .bind(hex::decode(id)?)
+            .execute(&self.conn)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn get_account(&self, pubkey: &str) -> Result<Option<Account>> {
+        let row = sqlx::query("SELECT admitted, balance_msat, created_at FROM account WHERE pub_key = ?")
+            .bind(hex::decode(pubkey)?)
+            .fetch_optional(&self.conn)
+            .await?;
+        Ok(row.map(|row| {
+            let balance_msat: i64 = row.get(1);
+            let created_at: i64 = row.get(2);
+            Account {
+                pubkey: pubkey.to_owned(),
+                admitted: row.get(0),
+                balance_msat: balance_msat as u64,
+                created_at: created_at as u64,
+            }
+        }))
+    }
+
+    async fn add_invoice(&self, invoice: &Invoice) -> Result<()> {
+        let pubkey = hex::decode(&invoice.pubkey)?;
+        let mut tx = self.conn.begin().await?;
+        sqlx::query("INSERT IGNORE INTO account (pub_key, admitted, created_at) VALUES (?, FALSE, ?)")
+            .bind(&pubkey)
+            .bind(invoice.created_at as i64)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("INSERT INTO invoice (payment_hash, pub_key, bolt11, amount_msat, topup, paid, created_at, expires_at) VALUES (?, ?, ?, ?, ?, FALSE, ?, ?)")
+            .bind(hex::decode(&invoice.payment_hash)?)
+            .bind(&pubkey)
+            .bind(&invoice.bolt11)
+            .bind(invoice.amount_msat as i64)
+            .bind(invoice.topup)
+            .bind(invoice.created_at as i64)
+            .bind(invoice.expires_at as i64)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_unpaid_invoices(&self) -> Result<Vec<Invoice>> {
+        let rows = sqlx::query("SELECT payment_hash, pub_key, bolt11, amount_msat, topup, created_at, expires_at FROM invoice WHERE paid = FALSE")
+            .fetch_all(&self.conn)
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| {
+                let amount_msat: i64 = row.get(3);
+                let created_at: i64 = row.get(5);
+                let expires_at: i64 = row.get(6);
+                Invoice {
+                    payment_hash: hex::encode(row.get::<Vec<u8>, _>(0)),
+                    pubkey: hex::encode(row.get::<Vec<u8>, _>(1)),
+                    bolt11: row.get(2),
+                    amount_msat: amount_msat as u64,
+                    topup: row.get(4),
+                    paid: false,
+                    created_at: created_at as u64,
+                    expires_at: expires_at as u64,
+                }
+            })
+            .collect())
+    }
+
+    async fn invoice_paid(&self, payment_hash: &str) -> Result<bool> {
+        let payment_hash = hex::decode(payment_hash)?;
+        let mut tx = self.conn.begin().await?;
+        let res = sqlx::query("UPDATE invoice SET paid = TRUE WHERE payment_hash = ? AND paid = FALSE")
+            .bind(&payment_hash)
+            .execute(&mut tx)
+            .await?;
+        if res.rows_affected() == 0 {
+            return Ok(false);
+        }
+        sqlx::query("UPDATE account a JOIN invoice i ON i.pub_key = a.pub_key \
+                     SET a.admitted = TRUE, a.balance_msat = a.balance_msat + IF(i.topup, i.amount_msat, 0) \
+                     WHERE i.payment_hash = ?")
+            .bind(&payment_hash)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn debit_account(&self, pubkey: &str, amount_msat: u64) -> Result<bool> {
+        let res = sqlx::query("UPDATE account SET balance_msat = balance_msat - ? WHERE pub_key = ? AND balance_msat >= ?")
+            .bind(amount_msat as i64)
+            .bind(hex::decode(pubkey)?)
+            .bind(amount_msat as i64)
+            .execute(&self.conn)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn credit_account(&self, pubkey: &str, amount_msat: u64) -> Result<()> {
+        sqlx::query("UPDATE account SET balance_msat = balance_msat + ? WHERE pub_key = ?")
+            .bind(amount_msat as i64)
+            .bind(hex::decode(pubkey)?)
+            .execute(&self.conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn optimize_db(&self) -> Result<()> {
+        let start = Instant::now();
+        sqlx::query("ANALYZE TABLE event, tag, user_verification")
+            .execute(&self.conn)
+            .await?;
+        info!("optimize ran in {:?}", start.elapsed());
+        Ok(())
+    }
+
+    async fn compact(&self) -> Result<()> {
+        let start = Instant::now();
+        // rebuilds each table, and updates its statistics.
+        sqlx::query("OPTIMIZE TABLE event, tag, user_verification")
+            .execute(&self.conn)
+            .await?;
+        info!("optimize table ran in {:?}", start.elapsed());
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<()> {
+        sqlx::query("SELECT 1").execute(&self.conn).await?;
+        Ok(())
+    }
+
+    async fn check_integrity(&self) -> Result<Vec<String>> {
+        // each table reports "OK", or its errors.
+        let rows = sqlx::query("CHECK TABLE event, tag, user_verification")
+            .fetch_all(&self.conn)
+            .await?;
+        Ok(rows
+            .iter()
+            .filter_map(|r| {
+                let table: String = r.try_get("Table").ok()?;
+                let msg_type: String = r.try_get("Msg_type").ok()?;
+                let text: String = r.try_get("Msg_text").ok()?;
+                (msg_type != "status" || text != "OK").then(|| format!("{table}: {text}"))
+            })
+            .collect())
+    }
+
+    async fn event_summaries(&self, order: ScanOrder, after: Option<&EventSummary>, limit: usize) -> Result<Vec<EventSummary>> {
+        let (cmp, dir) = order.sql();
+        let rows = match after {
+            Some(a) => sqlx::query(&format!("SELECT id, pub_key, kind, created_at FROM event WHERE created_at {cmp} ? OR (created_at = ? AND id {cmp} ?) ORDER BY created_at {dir}, id {dir} LIMIT ?"))
+                .bind(a.created_at as i64)
+                .bind(a.created_at as i64)
+                .bind(hex::decode(&a.id).ok())
+                .bind(limit as i64)
+                .fetch_all(&self.conn)
+                .await?,
+            None => sqlx::query(&format!("SELECT id, pub_key, kind, created_at FROM event ORDER BY created_at {dir}, id {dir} LIMIT ?"))
+                .bind(limit as i64)
+                .fetch_all(&self.conn)
+                .await?,
+        };
+        Ok(rows
+            .iter()
+            .map(|r| EventSummary {
+                id: hex::encode(r.get::<Vec<u8>, _>(0)),
+                pubkey: hex::encode(r.get::<Vec<u8>, _>(1)),
+                kind: r.get::<i64, _>(2) as u64,
+                created_at: r.get::<i64, _>(3) as u64,
+            })
+            .collect())
+    }
+
+    async fn used_bytes(&self) -> Result<u64> {
+        // InnoDB tablespaces do not shrink when rows are deleted, so
+        // measure the stored events themselves.
+        let used: i64 = sqlx::query_scalar("SELECT CAST(COALESCE(SUM(LENGTH(content)), 0) AS SIGNED) FROM event")
+            .fetch_one(&self.conn)
+            .await?;
+        Ok(used as u64)
+    }
+
+    async fn author_bytes(&self, pubkey: &str) -> Result<u64> {
+        let used: i64 = sqlx::query_scalar("SELECT CAST(COALESCE(SUM(LENGTH(content)), 0) AS SIGNED) FROM event WHERE pub_key = ? AND hidden = FALSE")
+            .bind(hex::decode(pubkey)?)
+            .fetch_one(&self.conn)
+            .await?;
+        Ok(used as u64)
+    }
+
+    async fn delete_events(&self, ids: &[String]) -> Result<u64> {
+        let mut tx = self.conn.begin().await?;
+        let mut count = 0;
+        // tags and verification records are removed by foreign key cascades.
+        for id in ids {
+            count += sqlx::query("DELETE FROM event WHERE id = ?")
+                .bind(hex::decode(id).ok())
+                .execute(&mut tx)
+                .await?
+                .rows_affected();
+        }
+        tx.commit().await?;
+        Ok(count)
+    }
+
+    async fn create_verification_record(&self, event_id: &str, name: &str) -> Result<()> {
+        let mut tx = self.conn.begin().await?;
+
+        sqlx::query("DELETE FROM user_verification WHERE name = ?")
+            .bind(name)
+            .execute(&mut tx)
+            .await?;
+
+        sqlx::query("INSERT INTO user_verification (event_id, name, verified_at) VALUES (?, ?, ?)")
+            .bind(hex::decode(event_id).ok())
+            .bind(name)
+            .bind(unix_time() as i64)
+            .execute(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+        info!("saved new verification record for ({:?})", name);
+        Ok(())
+    }
+
+    async fn update_verification_timestamp(&self, id: u64) -> Result<()> {
+        // add some jitter to the verification to prevent everything from stacking up together.
+        let verify_time = now_jitter(600);
+
+        // update verification time and reset any failure count
+        sqlx::query("UPDATE user_verification SET verified_at = ?, fail_count = 0 WHERE id = ?")
+            .bind(verify_time as i64)
+            .bind(id as i64)
+            .execute(&self.conn)
+            .await?;
+
+        info!("verification updated for {}", id);
+        Ok(())
+    }
+
+    async fn fail_verification(&self, id: u64) -> Result<()> {
+        sqlx::query("UPDATE user_verification SET failed_at = ?, fail_count = fail_count + 1 WHERE id = ?")
+            .bind(unix_time() as i64)
+            .bind(id as i64)
+            .execute(&self.conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_verification(&self, id: u64) -> Result<()> {
+        sqlx::query("DELETE FROM user_verification WHERE id = ?")
+            .bind(id as i64)
+            .execute(&self.conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_latest_user_verification(&self, pub_key: &str) -> Result<VerificationRecord> {
+        let query = r#"SELECT
+            v.id,
+            v.name,
+            e.id as event_id,
+            e.pub_key,
+            e.created_at,
+            v.verified_at,
+            v.failed_at,
+            v.fail_count
+            FROM user_verification v
+            INNER JOIN event e ON e.id = v.event_id
+            WHERE e.pub_key = ?
+            ORDER BY e.created_at DESC, v.verified_at DESC, v.failed_at DESC
+            LIMIT 1"#;
+        sqlx::query_as::<_, VerificationRecord>(query)
+            .bind(hex::decode(pub_key).ok())
+            .fetch_optional(&self.conn)
+            .await?
+            .ok_or(error::Error::SqlxError(RowNotFound))
+    }
+
+    async fn get_oldest_user_verification(&self, before: u64) -> Result<VerificationRecord> {
+        let query = r#"SELECT
+            v.id,
+            v.name,
+            e.id as event_id,
+            e.pub_key,
+            e.created_at,
+            v.verified_at,
+            v.failed_at,
+            v.fail_count
+            FROM user_verification v
+            INNER JOIN event e ON e.id = v.event_id
+                WHERE (v.verified_at < ? OR v.verified_at IS NULL)
+                AND (v.failed_at < ? OR v.failed_at IS NULL)
+            ORDER BY v.verified_at ASC, v.failed_at ASC
+            LIMIT 1"#;
+        sqlx::query_as::<_, VerificationRecord>(query)
+            .bind(before as i64)
+            .bind(before as i64)
+            .fetch_optional(&self.conn)
+            .await?
+            .ok_or(error::Error::SqlxError(RowNotFound))
+    }
+
+    async fn put_queued_verification(&self, queued: &QueuedVerification) -> Result<()> {
+        // columns are assigned in order, so created_at is compared
+        // before it is replaced.
+        sqlx::query("INSERT INTO verification_queue (pub_key, event_id, created_at, event, attempts, retry_at) VALUES (?, ?, ?, ?, ?, ?) \
+                     ON DUPLICATE KEY UPDATE \
+                     event_id = IF(VALUES(created_at) >= created_at, VALUES(event_id), event_id), \
+                     event = IF(VALUES(created_at) >= created_at, VALUES(event), event), \
+                     attempts = IF(VALUES(created_at) >= created_at, VALUES(attempts), attempts), \
+                     retry_at = IF(VALUES(created_at) >= created_at, VALUES(retry_at), retry_at), \
+                     created_at = GREATEST(VALUES(created_at), created_at)")
+            .bind(hex::decode(&queued.event.pubkey)?)
+            .bind(hex::decode(&queued.event.id)?)
+            .bind(queued.event.created_at as i64)
+            .bind(serde_json::to_string(&queued.event)?)
+            .bind(queued.attempts as i64)
+            .bind(queued.retry_at as i64)
+            .execute(&self.conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_queued_verifications(&self, due: u64, limit: usize) -> Result<Vec<QueuedVerification>> {
+        let rows = sqlx::query("SELECT event, attempts, retry_at FROM verification_queue WHERE retry_at <= ? ORDER BY retry_at ASC LIMIT ?")
+            .bind(due as i64)
+            .bind(limit as i64)
+            .fetch_all(&self.conn)
+            .await?;
+        let mut queued = vec![];
+        for row in rows {
+            let json: String = row.get(0);
+            let attempts: i64 = row.get(1);
+            let retry_at: i64 = row.get(2);
+            queued.push(QueuedVerification {
+                event: serde_json::from_str(&json)?,
+                attempts: attempts as u64,
+                retry_at: retry_at as u64,
+            });
+        }
+        Ok(queued)
+    }
+
+    async fn remove_queued_verification(&self, pubkey: &str, event_id: &str) -> Result<()> {
+        sqlx::query("DELETE FROM verification_queue WHERE pub_key = ? AND event_id = ?")
+            .bind(hex::decode(pubkey)?)
+            .bind(hex::decode(event_id)?)
+            .execute(&self.conn)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Create a dynamic SQL query and params from a subscription filter.
+fn query_from_filter(f: &ReqFilter) -> Option<QueryBuilder<'static, MySql>> {
+    // if the filter is malformed, or can't match, don't return anything.
+    let access = planner::plan(f).access;
+    if access == Access::Nothing {
+        return None;
+    }
+
+    let mut query = QueryBuilder::new("SELECT e.content, e.created_at FROM event e ");
+    if let Some(idx) = index_hint(f, access) {
+        query.push(format!("USE INDEX ({idx}) "));
+    }
+    query.push("WHERE ");
+    push_filter_conditions(&mut query, f);
+
+    // Apply per-filter limit to this query.
+    // The use of a LIMIT implies a DESC order, to capture only the most recent events.
+    if let Some(lim) = f.limit {
+        query.push(" ORDER BY e.created_at DESC LIMIT ");
+        query.push(lim.min(1000));
+    } else {
+        query.push(" ORDER BY e.created_at ASC LIMIT ");
+        query.push(1000);
+    }
+    Some(query)
+}
+
+/// Decide if there is an index that should be suggested, for the
+/// planned access to a filter.  Authors are matched on `pub_key` or
+/// `delegated_by`, which needs both their indexes, so they are left
+/// to the optimizer.
+fn index_hint(f: &ReqFilter, access: Access) -> Option<&'static str> {
+    match access {
+        // without a hint, the newest events of a few kinds are found
+        // by scanning all events by creation time.
+        Access::Kinds => Some("event_kind_idx"),
+        Access::Scan if f.since.is_some() || f.until.is_some() => Some("event_created_at_idx"),
+        _ => None,
+    }
+}
+
+/// Create a SQL query counting events matching any of the filters.
+fn count_query_from_filters(filters: &[ReqFilter]) -> Option<QueryBuilder<'static, MySql>> {
+    let filters: Vec<&ReqFilter> = filters.iter().filter(|f| !f.force_no_match).collect();
+    if filters.is_empty() {
+        return None;
+    }
+    let mut query = QueryBuilder::new("SELECT COUNT(*) FROM event e WHERE ");
+    for (i, f) in filters.iter().enumerate() {
+        if i > 0 {
+            query.push(" OR ");
+        }
+        query.push("(");
+        push_filter_conditions(&mut query, f);
+        query.push(")");
+    }
+    Some(query)
+}
+
+/// Append the WHERE conditions for a filter to a query.  Every
+/// condition is followed by ` AND `, ending with the hidden check.
+fn push_filter_conditions(query: &mut QueryBuilder<'static, MySql>, f: &ReqFilter) {
+    // Query for "authors", allowing prefix matches
+    if let Some(auth_vec) = &f.authors {
+        // filter out non-hex values
+        let auth_vec: Vec<&String> = auth_vec.iter().filter(|a| is_hex(a)).collect();
+
+        if !auth_vec.is_empty() {
+            query.push("(");
+
+            // shortcut authors into "IN" query
+            let any_is_range = auth_vec.iter().any(|pk| pk.len() != 64);
+            if !any_is_range {
+                query.push("e.pub_key IN (");
+                let mut pk_sep = query.separated(", ");
+                for pk in auth_vec.iter() {
+                    pk_sep.push_bind(hex::decode(pk).ok());
+                }
+                query.push(") OR e.delegated_by IN (");
+                let mut pk_delegated_sep = query.separated(", ");
+                for pk in auth_vec.iter() {
+                    pk_delegated_sep.push_bind(hex::decode(pk).ok());
+                }
+                query.push(")");
+            } else {
+                let mut range_authors = query.separated(" OR ");
+                for auth in auth_vec {
+                    match hex_range(auth) {
+                        Some(HexSearch::Exact(ex)) => {
+                            range_authors
+                                .push("(e.pub_key = ")
+                                .push_bind_unseparated(ex.clone())
+                                .push_unseparated(" OR e.delegated_by = ")
+                                .push_bind_unseparated(ex)
+                                .push_unseparated(")");
+                        }
+                        Some(HexSearch::Range(lower, upper)) => {
+                            range_authors
+                                .push("((e.pub_key > ")
+                                .push_bind_unseparated(lower.clone())
+                                .push_unseparated(" AND e.pub_key < ")
+                                .push_bind_unseparated(upper.clone())
+                                .push_unseparated(") OR (e.delegated_by > ")
+                                .push_bind_unseparated(lower)
+                                .push_unseparated(" AND e.delegated_by < ")
+                                .push_bind_unseparated(upper)
+                                .push_unseparated("))");
+                        }
+                        Some(HexSearch::LowerOnly(lower)) => {
+                            range_authors
+                                .push("(e.pub_key > ")
+                                .push_bind_unseparated(lower.clone())
+                                .push_unseparated(" OR e.delegated_by > ")
+                                .push_bind_unseparated(lower)
+                                .push_unseparated(")");
+                        }
+                        None => {
+                            info!("Could not parse hex range from author {:?}", auth);
+                            range_authors.push("FALSE");
+                        }
+                    }
+                }
+            }
+            query.push(") AND ");
+        }
+    }
+
+    // Query for Kind
+    if let Some(ks) = &f.kinds {
+        if !ks.is_empty() {
+            query.push("e.kind IN (");
+            let mut list_query = query.separated(", ");
+            for k in ks.iter() {
+                list_query.push_bind(*k as i64);
+            }
+            query.push(") AND ");
+        }
+    }
+
+    // Query for event, allowing prefix matches
+    if let Some(id_vec) = &f.ids {
+        // filter out non-hex values
+        let id_vec: Vec<&String> = id_vec.iter().filter(|a| is_hex(a)).collect();
+
+        if !id_vec.is_empty() {
+            query.push("(");
+            // shortcut ids into "IN" query
+            let any_is_range = id_vec.iter().any(|pk| pk.len() != 64);
+            if !any_is_range {
+                query.push("e.id IN (");
+                let mut sep = query.separated(", ");
+                for id in id_vec.iter() {
+                    sep.push_bind(hex::decode(id).ok());
+                }
+                query.push(")");
+            } else {
+                // take each id and convert to a hex search
+                let mut id_query = query.separated(" OR ");
+                for id in id_vec {
+                    match hex_range(id) {
+                        Some(HexSearch::Exact(ex)) => {
+                            id_query
+                                .push("(e.id = ")
+                                .push_bind_unseparated(ex)
+                                .push_unseparated(")");
+                        }
+                        Some(HexSearch::Range(lower, upper)) => {
+                            id_query
+                                .push("(e.id > ")
+                                .push_bind_unseparated(lower)
+                                .push_unseparated(" AND e.id < ")
+                                .push_bind_unseparated(upper)
+                                .push_unseparated(")");
+                        }
+                        Some(HexSearch::LowerOnly(lower)) => {
+                            id_query
+                                .push("(e.id > ")
+                                .push_bind_unseparated(lower)
+                                .push_unseparated(")");
+                        }
+                        None => {
+                            info!("Could not parse hex range from id {:?}", id);
+                            id_query.push("FALSE");
+                        }
+                    }
+                }
+            }
+            query.push(") AND ");
+        }
+    }
+
+    // Query for tags
+    for (key, val) in &f.tag_conditions() {
+        if val.is_empty() {
+            continue;
+        }
+        query
+            .push("e.id IN (SELECT t.event_id FROM tag t WHERE t.name = ")
+            .push_bind(key.to_string())
+            .push(" AND t.value IN (");
+        let mut tag_query = query.separated(", ");
+        for v in val.iter() {
+            tag_query.push_bind(tag_value_bytes(v));
+        }
+        query.push(")) AND ");
+    }
+
+    // Query for full-text search; every term must appear in the
+    // content, matching case-insensitively through the column collation.
+    if let Some(terms) = f.search_terms() {
+        for t in terms {
+            query
+                .push("e.search_text LIKE ")
+                .push_bind(like_pattern(&t))
+                .push(" AND ");
+        }
+    }
+
+    // Query for timestamp
+    if let Some(since) = f.since {
+        query.push("e.created_at > ").push_bind(since as i64).push(" AND ");
+    }
+
+    // Query for timestamp
+    if let Some(until) = f.until {
+        query.push("e.created_at < ").push_bind(until as i64).push(" AND ");
+    }
+
+    // never display hidden events
+    query.push("e.hidden = FALSE");
+}
+
+impl FromRow<'_, MySqlRow> for VerificationRecord {
+    fn from_row(row: &'_ MySqlRow) -> std::result::Result<Self, Error> {
+        let name = Nip05Name::try_from(row.try_get::<'_, String, &str>("name")?.as_str()).or(Err(RowNotFound))?;
+        Ok(VerificationRecord {
+            rowid: row.try_get::<'_, i64, &str>("id")? as u64,
+            name,
+            address: hex::encode(row.try_get::<'_, Vec<u8>, &str>("pub_key")?),
+            event: hex::encode(row.try_get::<'_, Vec<u8>, &str>("event_id")?),
+            event_created: row.try_get::<'_, i64, &str>("created_at")? as u64,
+            last_success: row.try_get::<'_, Option<i64>, &str>("verified_at")?.map(|t| t as u64),
+            last_failure: row.try_get::<'_, Option<i64>, &str>("failed_at")?.map(|t| t as u64),
+            failure_count: row.try_get::<'_, i32, &str>("fail_count")? as u64,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(json: &str) -> ReqFilter {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn filter_sql() {
+        let f = filter(r##"{"authors":["abcd"],"kinds":[1,7],"#t":["nostr"],"since":10,"limit":5}"##);
+        let mut q = query_from_filter(&f).unwrap();
+        assert_eq!(
+            q.build().sql(),
+            "SELECT e.content, e.created_at FROM event e WHERE \
+             (((e.pub_key > ? AND e.pub_key < ?) OR (e.delegated_by > ? AND e.delegated_by < ?))) AND \
+             e.kind IN (?, ?) AND \
+             e.id IN (SELECT t.event_id FROM tag t WHERE t.name = ? AND t.value IN (?)) AND \
+             e.created_at > ? AND e.hidden = FALSE ORDER BY e.created_at DESC LIMIT 5"
+        );
+        let mut q = query_from_filter(&filter(r#"{"kinds":[1],"limit":10}"#)).unwrap();
+        assert_eq!(
+            q.build().sql(),
+            "SELECT e.content, e.created_at FROM event e USE INDEX (event_kind_idx) WHERE \
+             e.kind IN (?) AND e.hidden = FALSE ORDER BY e.created_at DESC LIMIT 10"
+        );
+        // an empty filter only excludes hidden events
+        let mut q = query_from_filter(&filter("{}")).unwrap();
+        assert_eq!(
+            q.build().sql(),
+            "SELECT e.content, e.created_at FROM event e WHERE e.hidden = FALSE ORDER BY e.created_at ASC LIMIT 1000"
+        );
+    }
+
+    #[test]
+    fn count_and_search_sql() {
+        let filters = vec![filter(r#"{"search":"50% Off"}"#), filter(r#"{"ids":[]}"#)];
+        let mut q = count_query_from_filters(&filters).unwrap();
+        assert_eq!(
+            q.build().sql(),
+            "SELECT COUNT(*) FROM event e WHERE \
+             (e.search_text LIKE ? AND e.search_text LIKE ? AND e.hidden = FALSE) OR (e.hidden = FALSE)"
+        );
+        assert_eq!(like_pattern("50%"), "%50\\%%");
+        assert_eq!(tag_value_bytes("abcd"), vec![0xab, 0xcd]);
+        assert_eq!(tag_value_bytes("abc"), b"abc".to_vec());
+        assert_eq!(tag_value_bytes("ABCD"), b"ABCD".to_vec());
+    }
+}
@@ -0,0 +1,364 @@
+use crate::repo::mysql::MysqlPool;
+use async_trait::async_trait;
+use sqlx::{Executor, MySql, Transaction};
+
+#[async_trait]
+pub trait Migration {
+    fn serial_number(&self) -> i64;
+    async fn run(&self, tx: &mut Transaction<MySql>);
+}
+
+/// A migration made of plain SQL statements.  `MySQL` runs a single
+/// statement per query, so each one is listed separately.
+struct SimpleSqlMigration {
+    pub serial_number: i64,
+    pub sql: Vec<&'static str>,
+}
+
+#[async_trait]
+impl Migration for SimpleSqlMigration {
+    fn serial_number(&self) -> i64 {
+        self.serial_number
+    }
+
+    async fn run(&self, tx: &mut Transaction<MySql>) {
+        for sql in self.sql.iter() {
+            tx.execute(*sql).await.unwrap();
+        }
+    }
+}
+
+/// Execute all migrations on the database.
+pub async fn run_migrations(db: &MysqlPool) -> crate::error::Result<usize> {
+    prepare_migrations_table(db).await;
+    run_migration(m001::migration(), db).await;
+    run_migration(m002::migration(), db).await;
+    run_migration(m003::migration(), db).await;
+    run_migration(m004::migration(), db).await;
+    run_migration(m005::migration(), db).await;
+    run_migration(m006::migration(), db).await;
+    run_migration(m007::migration(), db).await;
+    run_migration(m008::migration(), db).await;
+    run_migration(m009::migration(), db).await;
+    Ok(current_version(db).await as usize)
+}
+
+async fn current_version(db: &MysqlPool) -> i64 {
+    sqlx::query_scalar("SELECT max(serial_number) FROM migrations;")
+        .fetch_one(db)
+        .await
+        .unwrap()
+}
+
+async fn prepare_migrations_table(db: &MysqlPool) {
+    sqlx::query("CREATE TABLE IF NOT EXISTS migrations (serial_number BIGINT)")
+        .execute(db)
+        .await
+        .unwrap();
+}
+
+async fn run_migration(migration: impl Migration, db: &MysqlPool) -> bool {
+    let row: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) AS count FROM migrations WHERE serial_number = ?")
+            .bind(migration.serial_number())
+            .fetch_one(db)
+            .await
+            .unwrap();
+
+    if row > 0 {
+        return false;
+    }
+
+    // DDL statements commit implicitly in MySQL, so the transaction
+    // only guards the version record.
+    let mut transaction = db.begin().await.unwrap();
+    migration.run(&mut transaction).await;
+
+    sqlx::query("INSERT INTO migrations VALUES (?)")
+        .bind(migration.serial_number())
+        .execute(&mut transaction)
+        .await
+        .unwrap();
+
+    transaction.commit().await.unwrap();
+    true
+}
+
+mod m001 {
+    use crate::repo::mysql_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 1;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Events table.  Timestamps are unix seconds.
+CREATE TABLE IF NOT EXISTS event (
+	id VARBINARY(32) NOT NULL,
+	pub_key VARBINARY(32) NOT NULL,
+	created_at BIGINT NOT NULL,
+	kind BIGINT NOT NULL,
+	content LONGBLOB NOT NULL,
+	hidden BOOLEAN NOT NULL DEFAULT FALSE,
+	delegated_by VARBINARY(32) NULL,
+	first_seen BIGINT NOT NULL,
+	search_text MEDIUMTEXT CHARACTER SET utf8mb4 NOT NULL,
+	PRIMARY KEY (id),
+	INDEX event_created_at_idx (created_at, kind),
+	INDEX event_pub_key_idx (pub_key, kind, created_at),
+	INDEX event_kind_idx (kind, created_at),
+	INDEX event_delegated_by_idx (delegated_by)
+) ENGINE=InnoDB
+        "#,
+                r#"
+-- Tags table
+CREATE TABLE IF NOT EXISTS tag (
+	id BIGINT NOT NULL AUTO_INCREMENT,
+	event_id VARBINARY(32) NOT NULL,
+	name VARCHAR(64) CHARACTER SET utf8mb4 COLLATE utf8mb4_bin NOT NULL,
+	value VARBINARY(1024) NOT NULL,
+	PRIMARY KEY (id),
+	UNIQUE KEY tag_unique_idx (event_id, name, value),
+	INDEX tag_value_idx (value, name),
+	CONSTRAINT tag_fk FOREIGN KEY (event_id) REFERENCES event(id) ON DELETE CASCADE
+) ENGINE=InnoDB
+        "#,
+                r#"
+-- NIP-05 Verfication table
+CREATE TABLE IF NOT EXISTS user_verification (
+	id BIGINT NOT NULL AUTO_INCREMENT,
+	event_id VARBINARY(32) NOT NULL,
+	name VARCHAR(255) NOT NULL,
+	verified_at BIGINT NULL,
+	failed_at BIGINT NULL,
+	fail_count INT NOT NULL DEFAULT 0,
+	PRIMARY KEY (id),
+	INDEX user_verification_event_id_idx (event_id),
+	INDEX user_verification_name_idx (name),
+	CONSTRAINT user_verification_fk FOREIGN KEY (event_id) REFERENCES event(id) ON DELETE CASCADE
+) ENGINE=InnoDB
+        "#,
+                r#"
+-- Relay-based groups (NIP-29)
+CREATE TABLE IF NOT EXISTS relay_group (
+	id VARCHAR(255) CHARACTER SET utf8mb4 COLLATE utf8mb4_bin NOT NULL,
+	created_at BIGINT NOT NULL,
+	PRIMARY KEY (id)
+) ENGINE=InnoDB
+        "#,
+                r#"
+CREATE TABLE IF NOT EXISTS group_member (
+	group_id VARCHAR(255) CHARACTER SET utf8mb4 COLLATE utf8mb4_bin NOT NULL,
+	pub_key VARBINARY(32) NOT NULL,
+	`role` VARCHAR(16) NOT NULL,
+	PRIMARY KEY (group_id, pub_key),
+	CONSTRAINT group_member_fk FOREIGN KEY (group_id) REFERENCES relay_group(id) ON DELETE CASCADE
+) ENGINE=InnoDB
+        "#,
+            ],
+        }
+    }
+}
+
+mod m002 {
+    use crate::repo::mysql_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 2;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Banned IP addresses and pubkeys
+CREATE TABLE IF NOT EXISTS ban (
+	target VARCHAR(16) NOT NULL,
+	value VARCHAR(255) NOT NULL,
+	expires_at BIGINT NULL,
+	reason VARCHAR(255) CHARACTER SET utf8mb4 NOT NULL,
+	PRIMARY KEY (target, value)
+) ENGINE=InnoDB
+        "#,
+            ],
+        }
+    }
+}
+
+mod m003 {
+    use crate::repo::mysql_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 3;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Reputation of event authors
+CREATE TABLE IF NOT EXISTS reputation (
+	pub_key VARBINARY(32) NOT NULL,
+	first_seen BIGINT NULL,
+	reports BIGINT NOT NULL DEFAULT 0,
+	spam_hits BIGINT NOT NULL DEFAULT 0,
+	PRIMARY KEY (pub_key)
+) ENGINE=InnoDB
+        "#,
+            ],
+        }
+    }
+}
+
+mod m004 {
+    use crate::repo::mysql_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 4;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Reports (NIP-56) of pubkeys and events; event_id is empty for
+-- reports of a pubkey.
+CREATE TABLE IF NOT EXISTS report (
+	reporter VARBINARY(32) NOT NULL,
+	pub_key VARBINARY(32) NOT NULL,
+	event_id VARBINARY(32) NOT NULL,
+	report_type VARCHAR(64) CHARACTER SET utf8mb4 NOT NULL,
+	created_at BIGINT NOT NULL,
+	PRIMARY KEY (reporter, pub_key, event_id),
+	INDEX report_event_idx (event_id)
+) ENGINE=InnoDB
+        "#,
+            ],
+        }
+    }
+}
+
+mod m005 {
+    use crate::repo::mysql_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 5;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Accounts of pubkeys that have requested paid admission
+CREATE TABLE IF NOT EXISTS account (
+	pub_key VARBINARY(32) NOT NULL,
+	admitted BOOLEAN NOT NULL DEFAULT FALSE,
+	created_at BIGINT NOT NULL,
+	PRIMARY KEY (pub_key)
+) ENGINE=InnoDB
+        "#,
+                r#"
+-- Lightning invoices issued for admission
+CREATE TABLE IF NOT EXISTS invoice (
+	payment_hash VARBINARY(32) NOT NULL,
+	pub_key VARBINARY(32) NOT NULL,
+	bolt11 TEXT NOT NULL,
+	amount_msat BIGINT NOT NULL,
+	paid BOOLEAN NOT NULL DEFAULT FALSE,
+	created_at BIGINT NOT NULL,
+	expires_at BIGINT NOT NULL,
+	PRIMARY KEY (payment_hash),
+	INDEX invoice_paid_idx (paid)
+) ENGINE=InnoDB
+        "#,
+            ],
+        }
+    }
+}
+
+mod m006 {
+    use crate::repo::mysql_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 6;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Balances for paying per event
+ALTER TABLE account ADD COLUMN balance_msat BIGINT NOT NULL DEFAULT 0
+        "#,
+                r#"
+-- Invoices that top up a balance, instead of paying for admission
+ALTER TABLE invoice ADD COLUMN topup BOOLEAN NOT NULL DEFAULT FALSE
+        "#,
+            ],
+        }
+    }
+}
+
+mod m007 {
+    use crate::repo::mysql_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 7;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- The newest events of any kind by some authors
+CREATE INDEX event_pub_key_created_at_idx ON event (pub_key, created_at)
+        "#,
+            ],
+        }
+    }
+}
+
+mod m008 {
+    use crate::repo::mysql_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 8;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Pubkeys in the whitelist, changed while the relay runs
+CREATE TABLE IF NOT EXISTS allowed_pubkey (
+	pub_key VARBINARY(32) NOT NULL,
+	created_at BIGINT NOT NULL,
+	PRIMARY KEY (pub_key)
+) ENGINE=InnoDB
+        "#,
+            ],
+        }
+    }
+}
+
+mod m009 {
+    use crate::repo::mysql_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 9;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Metadata events waiting for NIP-05 verification
+CREATE TABLE IF NOT EXISTS verification_queue (
+	pub_key VARBINARY(32) NOT NULL,
+	event_id VARBINARY(32) NOT NULL,
+	created_at BIGINT NOT NULL,
+	event MEDIUMTEXT CHARACTER SET utf8mb4 NOT NULL,
+	attempts BIGINT NOT NULL DEFAULT 0,
+	retry_at BIGINT NOT NULL,
+	PRIMARY KEY (pub_key),
+	INDEX verification_queue_retry_idx (retry_at)
+) ENGINE=InnoDB
+        "#,
+            ],
+        }
+    }
+}
@@ -0,0 +1,198 @@
+//! Query planning for subscription filters
+//!
+//! A filter is answered by reading one index: its event ids, its
+//! authors, the values of one of its tags, its kinds, or events by
+//! creation time.  The planner estimates how many events each usable
+//! index would read, and picks the cheapest.  Filters that cannot
+//! match anything are not run, and filters that would read a large
+//! part of the database are limited to the newest events.
+use crate::subscription::ReqFilter;
+use crate::utils::unix_time;
+
+/// Assumed number of stored events
+const EVENTS: u64 = 10_000_000;
+
+/// Assumed number of authors
+const AUTHORS: u64 = 100_000;
+
+/// Assumed events per author, tag value, and kind
+const EVENTS_PER_AUTHOR: u64 = 1_000;
+const EVENTS_PER_TAG_VALUE: u64 = 1_000;
+const EVENTS_PER_KIND: u64 = 1_000_000;
+
+/// Assumed age of the oldest events, in seconds (two years)
+const HISTORY_SECONDS: u64 = 2 * 365 * 24 * 60 * 60;
+
+/// Filters estimated to read more events than this are unbounded
+pub const UNBOUNDED_ROWS: u64 = 100_000;
+
+/// Most events returned for an unbounded filter
+pub const UNBOUNDED_LIMIT: u64 = 5_000;
+
+/// Index used to answer a filter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// The filter cannot match any event
+    Nothing,
+    Ids,
+    Authors,
+    /// The values of one tag
+    Tag(char),
+    Kinds,
+    /// Events by creation time
+    Scan,
+}
+
+/// How a filter is answered
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Plan {
+    pub access: Access,
+    /// Estimated events read
+    pub rows: u64,
+    /// Most events to return, which may be lower than the filter's
+    /// own limit
+    pub limit: Option<u64>,
+}
+
+impl Plan {
+    /// Check if the filter's limit was lowered.
+    #[must_use]
+    pub fn degraded(&self, f: &ReqFilter) -> bool {
+        self.limit != f.limit
+    }
+
+    /// The filter, with the planned limit.
+    #[must_use]
+    pub fn limited(&self, f: &ReqFilter) -> ReqFilter {
+        let mut f = f.clone();
+        f.limit = self.limit;
+        f
+    }
+}
+
+/// Plan a filter.
+#[must_use]
+pub fn plan(f: &ReqFilter) -> Plan {
+    plan_at(f, unix_time())
+}
+
+fn plan_at(f: &ReqFilter, now: u64) -> Plan {
+    if matches_nothing(f) {
+        return Plan {
+            access: Access::Nothing,
+            rows: 0,
+            limit: Some(0),
+        };
+    }
+    let mut candidates = vec![];
+    if let Some(ids) = &f.ids {
+        candidates.push((Access::Ids, ids.iter().map(|p| prefix_matches(p, EVENTS)).sum()));
+    }
+    if let Some(authors) = &f.authors {
+        let rows: u64 = authors
+            .iter()
+            .map(|p| prefix_matches(p, AUTHORS) * EVENTS_PER_AUTHOR)
+            .sum();
+        candidates.push((Access::Authors, in_range(f, rows, now)));
+    }
+    if let Some((c, vals)) = f.tags.as_ref().and_then(|t| t.iter().min_by_key(|(_, v)| v.len())) {
+        let rows = vals.len() as u64 * EVENTS_PER_TAG_VALUE;
+        candidates.push((Access::Tag(*c), in_range(f, rows, now)));
+    }
+    if let Some(kinds) = &f.kinds {
+        let rows = kinds.len() as u64 * EVENTS_PER_KIND;
+        candidates.push((Access::Kinds, in_range(f, rows, now)));
+    }
+    candidates.push((Access::Scan, in_range(f, EVENTS, now)));
+    // earlier candidates win ties.
+    let (access, rows) = candidates
+        .into_iter()
+        .reduce(|best, c| if c.1 < best.1 { c } else { best })
+        .unwrap_or((Access::Scan, EVENTS));
+    let limit = if rows > UNBOUNDED_ROWS {
+        Some(f.limit.map_or(UNBOUNDED_LIMIT, |l| l.min(UNBOUNDED_LIMIT)))
+    } else {
+        f.limit
+    };
+    Plan { access, rows, limit }
+}
+
+/// Check if a filter can never match.
+fn matches_nothing(f: &ReqFilter) -> bool {
+    f.force_no_match
+        || f.limit == Some(0)
+        || f.ids.as_ref().is_some_and(Vec::is_empty)
+        || f.authors.as_ref().is_some_and(Vec::is_empty)
+        || f.kinds.as_ref().is_some_and(Vec::is_empty)
+        || f.tags.as_ref().is_some_and(|t| t.values().any(|v| v.is_empty()))
+        // since and until are exclusive
+        || matches!((f.since, f.until), (Some(s), Some(u)) if u <= s.saturating_add(1))
+}
+
+/// Estimated number of items matching a hex prefix, out of a
+/// population of full-length values.
+fn prefix_matches(prefix: &str, population: u64) -> u64 {
+    let bits = 4 * prefix.len().min(64) as u32;
+    population.checked_shr(bits).unwrap_or(0).max(1)
+}
+
+/// Scale an estimate by the fraction of history in a filter's time range.
+fn in_range(f: &ReqFilter, rows: u64, now: u64) -> u64 {
+    let oldest = now.saturating_sub(HISTORY_SECONDS);
+    let from = f.since.unwrap_or(oldest).max(oldest);
+    let to = f.until.unwrap_or(now).min(now);
+    let span = to.saturating_sub(from);
+    let scaled = u128::from(rows) * u128::from(span) / u128::from(HISTORY_SECONDS);
+    u64::try_from(scaled).unwrap_or(rows).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn filter(s: &str) -> ReqFilter {
+        serde_json::from_str(s).unwrap()
+    }
+
+    fn access(s: &str) -> Access {
+        plan_at(&filter(s), NOW).access
+    }
+
+    #[test]
+    fn most_selective_index_is_chosen() {
+        let pk = "a".repeat(64);
+        assert_eq!(access(&format!(r#"{{"ids":["{pk}"],"authors":["{pk}"]}}"#)), Access::Ids);
+        assert_eq!(access(&format!(r#"{{"authors":["{pk}"],"kinds":[1]}}"#)), Access::Authors);
+        assert_eq!(access(r##"{"#e":["x"],"kinds":[1,7]}"##), Access::Tag('e'));
+        assert_eq!(access(r##"{"#t":["a","b"],"#p":["x"]}"##), Access::Tag('p'));
+        assert_eq!(access(r#"{"kinds":[1]}"#), Access::Kinds);
+        assert_eq!(access(r#"{"since":1699999000}"#), Access::Scan);
+        // a single character prefix is less selective than kinds
+        assert_eq!(access(r#"{"authors":["a"],"kinds":[1]}"#), Access::Kinds);
+    }
+
+    #[test]
+    fn empty_filters_are_not_run() {
+        assert_eq!(access(r#"{"ids":[]}"#), Access::Nothing);
+        assert_eq!(access(r#"{"kinds":[1],"limit":0}"#), Access::Nothing);
+        assert_eq!(access(r#"{"since":100,"until":101}"#), Access::Nothing);
+        assert_eq!(access(r#"{"since":100,"until":102}"#), Access::Scan);
+    }
+
+    #[test]
+    fn unbounded_filters_are_limited() {
+        let f = filter(r#"{"kinds":[1]}"#);
+        let p = plan_at(&f, NOW);
+        assert_eq!(p.limit, Some(UNBOUNDED_LIMIT));
+        assert!(p.degraded(&f));
+        assert_eq!(p.limited(&f).limit, Some(UNBOUNDED_LIMIT));
+        assert_eq!(plan_at(&filter(r#"{"limit":1000000}"#), NOW).limit, Some(UNBOUNDED_LIMIT));
+        // recent events of a kind are bounded
+        let f = filter(r#"{"kinds":[1],"since":1699999000}"#);
+        assert!(!plan_at(&f, NOW).degraded(&f));
+        let f = filter(r#"{"authors":["aaaaaaaa"]}"#);
+        assert_eq!(plan_at(&f, NOW).limit, None);
+    }
+}
@@ -1,15 +1,24 @@
+use crate::bans::{Ban, BanTarget};
 use crate::db::QueryResult;
 use crate::error::Result;
-use crate::event::{single_char_tagname, Event};
-use crate::nip05::{Nip05Name, VerificationRecord};
-use crate::repo::{now_jitter, NostrRepo};
+use crate::event::{searchable_tagname, Event};
+use crate::groups::{Group, GroupRole, GroupUpdate};
+use crate::nip05::{Nip05Name, QueuedVerification, VerificationRecord};
+use crate::nip65::KIND_RELAY_LIST;
+use crate::payment::{Account, Invoice};
+use crate::repo::planner::{self, Access};
+use crate::repo::{now_jitter, EventSummary, NostrRepo, ScanOrder};
+use crate::reports::Report;
+use crate::reputation::{ReputationChange, ReputationRecord};
 use crate::subscription::{ReqFilter, Subscription};
 use async_std::stream::StreamExt;
 use async_trait::async_trait;
 use chrono::{DateTime, TimeZone, Utc};
 use sqlx::postgres::PgRow;
 use sqlx::Error::RowNotFound;
-use sqlx::{Error, Execute, FromRow, Postgres, QueryBuilder, Row};
+use sqlx::{Error, Execute, FromRow, Postgres, QueryBuilder, Row, Transaction};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use crate::error;
@@ -20,196 +29,292 @@ use crate::utils::{is_hex, is_lower_hex};
 use tokio::sync::mpsc::Sender;
 use tokio::sync::oneshot::Receiver;
 use tracing::log::trace;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 pub type PostgresPool = sqlx::pool::Pool<Postgres>;
 
+/// How often a read replica is checked
+const REPLICA_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Time a read replica has to answer a check
+const REPLICA_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A read replica, used for queries while it is healthy
+struct ReadReplica {
+    pool: PostgresPool,
+    healthy: Arc<AtomicBool>,
+}
+
 pub struct PostgresRepo {
     conn: PostgresPool,
+    reader: Option<ReadReplica>,
     metrics: NostrMetrics,
+    multi_letter_tags: bool,
 }
 
 impl PostgresRepo {
     pub fn new(c: PostgresPool, m: NostrMetrics) -> PostgresRepo {
         PostgresRepo {
             conn: c,
+            reader: None,
             metrics: m,
+            multi_letter_tags: false,
         }
     }
-}
 
-#[async_trait]
-impl NostrRepo for PostgresRepo {
-    async fn start(&self) -> Result<()> {
-        info!("not implemented");
-        Ok(())
+    /// Index tags with multi-letter names, as well as single-letter
+    /// tags.
+    #[must_use]
+    pub fn with_multi_letter_tags(mut self, enabled: bool) -> PostgresRepo {
+        self.multi_letter_tags = enabled;
+        self
     }
 
-    async fn migrate_up(&self) -> Result<usize> {
-        Ok(run_migrations(&self.conn).await?)
+    /// Send subscription and count queries to a read replica.  It is
+    /// only used once a health check (started by `start`) passes.
+    #[must_use]
+    pub fn with_read_replica(mut self, pool: PostgresPool) -> PostgresRepo {
+        self.reader = Some(ReadReplica {
+            pool,
+            healthy: Arc::new(AtomicBool::new(false)),
+        });
+        self
     }
 
-    async fn write_event(&self, e: &Event) -> Result<u64> {
-        // start transaction
-        let mut tx = self.conn.begin().await?;
-        let start = Instant::now();
-
-        // get relevant fields from event and convert to blobs.
-        let id_blob = hex::decode(&e.id).ok();
-        let pubkey_blob: Option<Vec<u8>> = hex::decode(&e.pubkey).ok();
-        let delegator_blob: Option<Vec<u8>> =
-            e.delegated_by.as_ref().and_then(|d| hex::decode(d).ok());
-        let event_str = serde_json::to_string(&e).unwrap();
-
-        // determine if this event would be shadowed by an existing
-        // replaceable event or parameterized replaceable event.
-        if e.is_replaceable() {
-            let repl_count = sqlx::query(
-                "SELECT e.id FROM event e WHERE e.pub_key=$1 AND e.kind=$2 AND e.created_at >= $3 LIMIT 1;")
-                .bind(&pubkey_blob)
-                .bind(e.kind as i64)
-                .bind(Utc.timestamp_opt(e.created_at as i64, 0).unwrap())
-                .fetch_optional(&mut tx)
-                .await?;
-            if repl_count.is_some() {
-                return Ok(0);
-            }
+    /// Pool for read-only queries: the replica if it is healthy,
+    /// otherwise the primary.
+    fn read_pool(&self) -> &PostgresPool {
+        match &self.reader {
+            Some(r) if r.healthy.load(Ordering::Relaxed) => &r.pool,
+            _ => &self.conn,
         }
-        if let Some(d_tag) = e.distinct_param() {
-            let repl_count: i64 = if is_lower_hex(&d_tag) && (d_tag.len() % 2 == 0) {
-                sqlx::query_scalar(
-                    "SELECT count(*) AS count FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.pub_key=$1 AND e.kind=$2 AND t.name='d' AND t.value_hex=$3 AND e.created_at >= $4 LIMIT 1;")
-                    .bind(hex::decode(&e.pubkey).ok())
-                    .bind(e.kind as i64)
-                    .bind(hex::decode(d_tag).ok())
-                    .bind(Utc.timestamp_opt(e.created_at as i64, 0).unwrap())
-                    .fetch_one(&mut tx)
-                    .await?
-            } else {
-                sqlx::query_scalar(
-                    "SELECT count(*) AS count FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.pub_key=$1 AND e.kind=$2 AND t.name='d' AND t.value=$3 AND e.created_at >= $4 LIMIT 1;")
-                    .bind(hex::decode(&e.pubkey).ok())
-                    .bind(e.kind as i64)
-                    .bind(d_tag.as_bytes())
-                    .bind(Utc.timestamp_opt(e.created_at as i64, 0).unwrap())
-                    .fetch_one(&mut tx)
-                    .await?
-            };
-            // if any rows were returned, then some newer event with
-            // the same author/kind/tag value exist, and we can ignore
-            // this event.
-            if repl_count > 0 {
-                return Ok(0);
+    }
+
+    /// Stop using the replica after a query on it failed, until the
+    /// next health check passes.
+    fn read_failed(&self, pool: &PostgresPool) {
+        if let Some(r) = &self.reader {
+            if std::ptr::eq(pool, &r.pool) && r.healthy.swap(false, Ordering::Relaxed) {
+                warn!("query on read replica failed, sending queries to the primary");
             }
         }
+    }
 
-        // ignore if the event hash is a duplicate.
-        let mut ins_count = sqlx::query(
-            r#"INSERT INTO "event"
-(id, pub_key, created_at, kind, "content", delegated_by)
-VALUES($1, $2, $3, $4, $5, $6)
-ON CONFLICT (id) DO NOTHING"#,
+    /// Insert a verification record copied from another database,
+    /// keeping its timestamps.  Records for events that are not
+    /// present, or that already exist, are skipped.
+    pub async fn import_verification_record(&self, rec: &VerificationRecord) -> Result<u64> {
+        let to_time = |t: Option<u64>| t.and_then(|t| Utc.timestamp_opt(t as i64, 0).single());
+        let event_id = hex::decode(&rec.event).ok();
+        let count = sqlx::query(
+            r#"INSERT INTO user_verification (event_id, "name", verified_at, failed_at, fail_count)
+SELECT $1, $2, $3, $4, $5
+WHERE EXISTS (SELECT 1 FROM "event" WHERE id = $1)
+AND NOT EXISTS (SELECT 1 FROM user_verification WHERE event_id = $1 AND "name" = $2)"#,
         )
-        .bind(&id_blob)
-        .bind(&pubkey_blob)
-        .bind(Utc.timestamp_opt(e.created_at as i64, 0).unwrap())
-        .bind(e.kind as i64)
-        .bind(event_str.into_bytes())
-        .bind(delegator_blob)
-        .execute(&mut tx)
+        .bind(event_id)
+        .bind(rec.name.to_string())
+        .bind(to_time(rec.last_success))
+        .bind(to_time(rec.last_failure))
+        .bind(rec.failure_count as i32)
+        .execute(&self.conn)
         .await?
         .rows_affected();
+        Ok(count)
+    }
+
+    /// Insert a group and its members copied from another database.
+    pub async fn import_group(&self, group: &Group) -> Result<()> {
+        let mut tx = self.conn.begin().await?;
+        sqlx::query(r#"INSERT INTO "relay_group" (id, created_at) VALUES ($1, now()) ON CONFLICT (id) DO NOTHING"#)
+            .bind(&group.id)
+            .execute(&mut tx)
+            .await?;
+        for (pubkey, role) in &group.members {
+            sqlx::query(r#"INSERT INTO "group_member" (group_id, pub_key, "role") VALUES ($1, $2, $3)
+ON CONFLICT (group_id, pub_key) DO UPDATE SET "role" = EXCLUDED."role""#)
+                .bind(&group.id)
+                .bind(hex::decode(pubkey).ok())
+                .bind(role.as_str())
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+/// Persist an event within a transaction, returning rows added.
+async fn persist_event(tx: &mut Transaction<'_, Postgres>, e: &Event, multi_letter_tags: bool) -> Result<u64> {
+    // get relevant fields from event and convert to blobs.
+    let id_blob = hex::decode(&e.id).ok();
+    let pubkey_blob: Option<Vec<u8>> = hex::decode(&e.pubkey).ok();
+    let delegator_blob: Option<Vec<u8>> =
+        e.delegated_by.as_ref().and_then(|d| hex::decode(d).ok());
+    let event_str = serde_json::to_string(&e).unwrap();
 
-        if ins_count == 0 {
-            // if the event was a duplicate, no need to insert event or
-            // pubkey references.  This will abort the txn.
+    // determine if this event would be shadowed by an existing
+    // replaceable event or parameterized replaceable event.
+    if e.is_replaceable() {
+        let repl_count = sqlx::query(
+            "SELECT e.id FROM event e WHERE e.pub_key=$1 AND e.kind=$2 AND e.created_at >= $3 LIMIT 1;")
+            .bind(&pubkey_blob)
+            .bind(e.kind as i64)
+            .bind(Utc.timestamp_opt(e.created_at as i64, 0).unwrap())
+            .fetch_optional(&mut *tx)
+            .await?;
+        if repl_count.is_some() {
             return Ok(0);
         }
+    }
+    if let Some(d_tag) = e.distinct_param() {
+        let repl_count: i64 = if is_lower_hex(&d_tag) && (d_tag.len() % 2 == 0) {
+            sqlx::query_scalar(
+                "SELECT count(*) AS count FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.pub_key=$1 AND e.kind=$2 AND t.name='d' AND t.value_hex=$3 AND e.created_at >= $4 LIMIT 1;")
+                .bind(hex::decode(&e.pubkey).ok())
+                .bind(e.kind as i64)
+                .bind(hex::decode(d_tag).ok())
+                .bind(Utc.timestamp_opt(e.created_at as i64, 0).unwrap())
+                .fetch_one(&mut *tx)
+                .await?
+        } else {
+            sqlx::query_scalar(
+                "SELECT count(*) AS count FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.pub_key=$1 AND e.kind=$2 AND t.name='d' AND t.value=$3 AND e.created_at >= $4 LIMIT 1;")
+                .bind(hex::decode(&e.pubkey).ok())
+                .bind(e.kind as i64)
+                .bind(d_tag.as_bytes())
+                .bind(Utc.timestamp_opt(e.created_at as i64, 0).unwrap())
+                .fetch_one(&mut *tx)
+                .await?
+        };
+        // if any rows were returned, then some newer event with
+        // the same author/kind/tag value exist, and we can ignore
+        // this event.
+        if repl_count > 0 {
+            return Ok(0);
+        }
+    }
 
-        // add all tags to the tag table
-        for tag in e.tags.iter() {
-            // ensure we have 2 values.
-            if tag.len() >= 2 {
-                let tag_name = &tag[0];
-                let tag_val = &tag[1];
-                // only single-char tags are searchable
-                let tag_char_opt = single_char_tagname(tag_name);
-                let query = "INSERT INTO tag (event_id, \"name\", value) VALUES($1, $2, $3) \
-                    ON CONFLICT (event_id, \"name\", value) DO NOTHING";
-                match &tag_char_opt {
-                    Some(_) => {
-                        // if tag value is lowercase hex;
-                        if is_lower_hex(tag_val) && (tag_val.len() % 2 == 0) {
-                            sqlx::query(query)
-                                .bind(&id_blob)
-                                .bind(tag_name)
-                                .bind(hex::decode(tag_val).ok())
-                                .execute(&mut tx)
-                                .await?;
-                        } else {
-                            sqlx::query(query)
-                                .bind(&id_blob)
-                                .bind(tag_name)
-                                .bind(tag_val.as_bytes())
-                                .execute(&mut tx)
-                                .await?;
-                        }
-                    }
-                    None => {}
+    // ignore if the event hash is a duplicate.
+    let mut ins_count = sqlx::query(
+        r#"INSERT INTO "event"
+(id, pub_key, created_at, kind, "content", delegated_by, search_tsv)
+VALUES($1, $2, $3, $4, $5, $6, to_tsvector('simple', $7))
+ON CONFLICT (id) DO NOTHING"#,
+    )
+    .bind(&id_blob)
+    .bind(&pubkey_blob)
+    .bind(Utc.timestamp_opt(e.created_at as i64, 0).unwrap())
+    .bind(e.kind as i64)
+    .bind(event_str.into_bytes())
+    .bind(delegator_blob)
+    .bind(&e.content)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    if ins_count == 0 {
+        // if the event was a duplicate, no need to insert event or
+        // pubkey references.  This will abort the txn.
+        return Ok(0);
+    }
+
+    // add all tags to the tag table
+    for tag in e.tags.iter() {
+        // ensure we have 2 values.
+        if tag.len() >= 2 {
+            let tag_name = &tag[0];
+            let tag_val = &tag[1];
+            // only single-char tags are searchable, unless
+            // multi-letter tags are indexed
+            if searchable_tagname(tag_name, multi_letter_tags) {
+                // if tag value is lowercase hex, store it in the
+                // value_hex column, so it cannot collide with a
+                // plain value that has the same bytes.
+                if is_lower_hex(tag_val) && (tag_val.len() % 2 == 0) {
+                    sqlx::query("INSERT INTO tag (event_id, \"name\", value_hex) VALUES($1, $2, $3) \
+                        ON CONFLICT DO NOTHING")
+                        .bind(&id_blob)
+                        .bind(tag_name)
+                        .bind(hex::decode(tag_val).ok())
+                        .execute(&mut *tx)
+                        .await?;
+                } else {
+                    sqlx::query("INSERT INTO tag (event_id, \"name\", value) VALUES($1, $2, $3) \
+                        ON CONFLICT (event_id, \"name\", value) DO NOTHING")
+                        .bind(&id_blob)
+                        .bind(tag_name)
+                        .bind(tag_val.as_bytes())
+                        .execute(&mut *tx)
+                        .await?;
                 }
             }
         }
-        if e.is_replaceable() {
-            let update_count = sqlx::query("DELETE FROM \"event\" WHERE kind=$1 and pub_key = $2 and id not in (select id from \"event\" where kind=$1 and pub_key=$2 order by created_at desc limit 1);")
+    }
+    // parameterized replaceable events without a `d` value are
+    // keyed by the empty string; record that so that newer
+    // versions can locate (and replace) this one.
+    if e.distinct_param().as_deref() == Some("") && !e.tags.iter().any(|t| t.len() >= 2 && t[0] == "d" && t[1].is_empty()) {
+        sqlx::query("INSERT INTO tag (event_id, \"name\", value_hex) VALUES($1, 'd', $2) \
+                ON CONFLICT DO NOTHING")
+            .bind(&id_blob)
+            .bind(Vec::<u8>::new())
+            .execute(&mut *tx)
+            .await?;
+    }
+    if e.is_replaceable() {
+        let update_count = sqlx::query("DELETE FROM \"event\" WHERE kind=$1 and pub_key = $2 and id not in (select id from \"event\" where kind=$1 and pub_key=$2 order by created_at desc limit 1);")
+            .bind(e.kind as i64)
+            .bind(hex::decode(&e.pubkey).ok())
+            .execute(&mut *tx)
+            .await?.rows_affected();
+        if update_count > 0 {
+            info!(
+                "hid {} older replaceable kind {} events for author: {:?}",
+                update_count,
+                e.kind,
+                e.get_author_prefix()
+            );
+        }
+    }
+    // parameterized replaceable events
+    // check for parameterized replaceable events that would be hidden; don't insert these either.
+    if let Some(d_tag) = e.distinct_param() {
+        let update_count = if is_lower_hex(&d_tag) && (d_tag.len() % 2 == 0) {
+            sqlx::query("DELETE FROM event WHERE kind=$1 AND pub_key=$2 AND id IN (SELECT e.id FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.kind=$1 AND e.pub_key=$2 AND t.name='d' AND t.value_hex=$3 ORDER BY created_at DESC OFFSET 1);")
                 .bind(e.kind as i64)
                 .bind(hex::decode(&e.pubkey).ok())
-                .execute(&mut tx)
-                .await?.rows_affected();
-            if update_count > 0 {
-                info!(
-                    "hid {} older replaceable kind {} events for author: {:?}",
-                    update_count,
-                    e.kind,
-                    e.get_author_prefix()
-                );
-            }
-        }
-        // parameterized replaceable events
-        // check for parameterized replaceable events that would be hidden; don't insert these either.
-        if let Some(d_tag) = e.distinct_param() {
-            let update_count = if is_lower_hex(&d_tag) && (d_tag.len() % 2 == 0) {
-                sqlx::query("DELETE FROM event WHERE kind=$1 AND pub_key=$2 AND id IN (SELECT e.id FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.kind=$1 AND e.pub_key=$2 AND t.name='d' AND t.value_hex=$3 ORDER BY created_at DESC OFFSET 1);")
-                    .bind(e.kind as i64)
-                    .bind(hex::decode(&e.pubkey).ok())
-                    .bind(hex::decode(d_tag).ok())
-                    .execute(&mut tx)
-                    .await?.rows_affected()
-            } else {
-                sqlx::query("DELETE FROM event WHERE kind=$1 AND pub_key=$2 AND id IN (SELECT e.id FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.kind=$1 AND e.pub_key=$2 AND t.name='d' AND t.value=$3 ORDER BY created_at DESC OFFSET 1);")
-                    .bind(e.kind as i64)
-                    .bind(hex::decode(&e.pubkey).ok())
-                    .bind(d_tag.as_bytes())
-                    .execute(&mut tx)
-                    .await?.rows_affected()
-            };
-            if update_count > 0 {
-                info!(
-                    "removed {} older parameterized replaceable kind {} events for author: {:?}",
-                    update_count,
-                    e.kind,
-                    e.get_author_prefix()
-                );
-            }
+                .bind(hex::decode(d_tag).ok())
+                .execute(&mut *tx)
+                .await?.rows_affected()
+        } else {
+            sqlx::query("DELETE FROM event WHERE kind=$1 AND pub_key=$2 AND id IN (SELECT e.id FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.kind=$1 AND e.pub_key=$2 AND t.name='d' AND t.value=$3 ORDER BY created_at DESC OFFSET 1);")
+                .bind(e.kind as i64)
+                .bind(hex::decode(&e.pubkey).ok())
+                .bind(d_tag.as_bytes())
+                .execute(&mut *tx)
+                .await?.rows_affected()
+        };
+        if update_count > 0 {
+            info!(
+                "removed {} older parameterized replaceable kind {} events for author: {:?}",
+                update_count,
+                e.kind,
+                e.get_author_prefix()
+            );
         }
-        // if this event is a deletion, hide the referenced events from the same author.
-        if e.kind == 5 {
-            let event_candidates = e.tag_values_by_name("e");
-            let pub_keys: Vec<Vec<u8>> = event_candidates
-                .iter()
-                .filter(|x| is_hex(x) && x.len() == 64)
-                .filter_map(|x| hex::decode(x).ok())
-                .collect();
+    }
+    // if this event is a deletion, hide the referenced events from the same author.
+    if e.kind == 5 {
+        let event_candidates = e.tag_values_by_name("e");
+        let pub_keys: Vec<Vec<u8>> = event_candidates
+            .iter()
+            .filter(|x| is_hex(x) && x.len() == 64)
+            .filter_map(|x| hex::decode(x).ok())
+            .collect();
 
+        // an empty IN list is a syntax error, and there is nothing to hide.
+        if !pub_keys.is_empty() {
             let mut builder = QueryBuilder::new(
                 "UPDATE \"event\" SET hidden = 1::bit(1) WHERE kind != 5 AND pub_key = ",
             );
@@ -222,44 +327,82 @@ ON CONFLICT (id) DO NOTHING"#,
             }
             sep.push_unseparated(")");
 
-            let update_count = builder.build().execute(&mut tx).await?.rows_affected();
+            let update_count = builder.build().execute(&mut *tx).await?.rows_affected();
             info!(
                 "hid {} deleted events for author {:?}",
                 update_count,
                 e.get_author_prefix()
             );
-        } else {
-            // check if a deletion has already been recorded for this event.
-            // Only relevant for non-deletion events
-            let del_count = sqlx::query(
-                "SELECT e.id FROM \"event\" e \
-            LEFT JOIN tag t ON e.id = t.event_id \
-            WHERE e.pub_key = $1 AND t.\"name\" = 'e' AND e.kind = 5 AND t.value = $2 LIMIT 1",
-            )
-            .bind(&pubkey_blob)
-            .bind(&id_blob)
-            .fetch_optional(&mut tx)
-            .await?;
+        }
+    } else {
+        // check if a deletion has already been recorded for this event.
+        // Only relevant for non-deletion events
+        let del_count = sqlx::query(
+            "SELECT e.id FROM \"event\" e \
+        LEFT JOIN tag t ON e.id = t.event_id \
+        WHERE e.pub_key = $1 AND t.\"name\" = 'e' AND e.kind = 5 AND t.value_hex = $2 LIMIT 1",
+        )
+        .bind(&pubkey_blob)
+        .bind(&id_blob)
+        .fetch_optional(&mut *tx)
+        .await?;
 
-            // check if a the query returned a result, meaning we should
-            // hid the current event
-            if del_count.is_some() {
-                // a deletion already existed, mark original event as hidden.
-                info!(
-                    "hid event: {:?} due to existing deletion by author: {:?}",
-                    e.get_event_id_prefix(),
-                    e.get_author_prefix()
-                );
-                sqlx::query("UPDATE \"event\" SET hidden = 1::bit(1) WHERE id = $1")
-                    .bind(&id_blob)
-                    .execute(&mut tx)
-                    .await?;
-                // event was deleted, so let caller know nothing new
-                // arrived, preventing this from being sent to active
-                // subscriptions
-                ins_count = 0;
-            }
+        // check if a the query returned a result, meaning we should
+        // hid the current event
+        if del_count.is_some() {
+            // a deletion already existed, mark original event as hidden.
+            info!(
+                "hid event: {:?} due to existing deletion by author: {:?}",
+                e.get_event_id_prefix(),
+                e.get_author_prefix()
+            );
+            sqlx::query("UPDATE \"event\" SET hidden = 1::bit(1) WHERE id = $1")
+                .bind(&id_blob)
+                .execute(&mut *tx)
+                .await?;
+            // event was deleted, so let caller know nothing new
+            // arrived, preventing this from being sent to active
+            // subscriptions
+            ins_count = 0;
+        }
+    }
+    Ok(ins_count)
+}
+
+/// Periodically check a read replica, moving queries to the primary
+/// while it is unavailable, and back once it recovers.
+async fn monitor_replica(pool: PostgresPool, healthy: Arc<AtomicBool>) {
+    loop {
+        let check = tokio::time::timeout(REPLICA_CHECK_TIMEOUT, sqlx::query("SELECT 1").execute(&pool)).await;
+        let ok = matches!(check, Ok(Ok(_)));
+        let was_ok = healthy.swap(ok, Ordering::Relaxed);
+        if ok && !was_ok {
+            info!("read replica is available, sending queries to it");
+        } else if !ok && was_ok {
+            warn!("read replica is unavailable, sending queries to the primary");
+        }
+        tokio::time::sleep(REPLICA_CHECK_INTERVAL).await;
+    }
+}
+
+#[async_trait]
+impl NostrRepo for PostgresRepo {
+    async fn start(&self) -> Result<()> {
+        if let Some(r) = &self.reader {
+            tokio::spawn(monitor_replica(r.pool.clone(), r.healthy.clone()));
         }
+        Ok(())
+    }
+
+    async fn migrate_up(&self) -> Result<usize> {
+        Ok(run_migrations(&self.conn).await?)
+    }
+
+    async fn write_event(&self, e: &Event) -> Result<u64> {
+        // start transaction
+        let mut tx = self.conn.begin().await?;
+        let start = Instant::now();
+        let ins_count = persist_event(&mut tx, e, self.multi_letter_tags).await?;
         tx.commit().await?;
         self.metrics
             .write_events
@@ -267,6 +410,21 @@ ON CONFLICT (id) DO NOTHING"#,
         Ok(ins_count)
     }
 
+    /// Persist a batch of events in one transaction
+    async fn write_events(&self, events: &[Event]) -> Result<Vec<u64>> {
+        let mut tx = self.conn.begin().await?;
+        let start = Instant::now();
+        let mut counts = Vec::with_capacity(events.len());
+        for e in events {
+            counts.push(persist_event(&mut tx, e, self.multi_letter_tags).await?);
+        }
+        tx.commit().await?;
+        self.metrics
+            .write_events
+            .observe(start.elapsed().as_secs_f64());
+        Ok(counts)
+    }
+
     async fn query_subscription(
         &self,
         sub: Subscription,
@@ -277,6 +435,7 @@ ON CONFLICT (id) DO NOTHING"#,
         let start = Instant::now();
         let mut row_count: usize = 0;
         let metrics = &self.metrics;
+        let pool = self.read_pool();
 
         for filter in sub.filters.iter() {
             let start = Instant::now();
@@ -304,12 +463,13 @@ ON CONFLICT (id) DO NOTHING"#,
             let mut q_filter = q_filter.unwrap();
             let q_build = q_filter.build();
             let sql = q_build.sql();
-            let mut results = q_build.fetch(&self.conn);
+            let mut results = q_build.fetch(pool);
 
             let mut first_result = true;
             while let Some(row) = results.next().await {
                 if let Err(e) = row {
                     error!("Query failed: {} {} {:?}", e, sql, filter);
+                    self.read_failed(pool);
                     break;
                 }
                 let first_event_elapsed = start.elapsed();
@@ -404,11 +564,447 @@ ON CONFLICT (id) DO NOTHING"#,
         Ok(())
     }
 
+    async fn count_events_by_filter(&self, filters: Vec<ReqFilter>) -> Result<u64> {
+        let start = Instant::now();
+        let pool = self.read_pool();
+        let count: i64 = match count_query_from_filters(&filters) {
+            Some(mut q) => match q.build().fetch_one(pool).await {
+                Ok(row) => row.try_get(0)?,
+                Err(e) => {
+                    self.read_failed(pool);
+                    return Err(e.into());
+                }
+            },
+            None => 0,
+        };
+        self.metrics
+            .query_db
+            .observe(start.elapsed().as_secs_f64());
+        debug!("count query completed in {:?} (count: {})", start.elapsed(), count);
+        Ok(count as u64)
+    }
+
+    async fn is_event_deleted(&self, id: &str) -> Result<bool> {
+        // deleted events are retained, but hidden; other hidden
+        // events lack a deletion from their author.
+        let row = sqlx::query("SELECT d.id FROM \"event\" e JOIN \"event\" d ON d.pub_key = e.pub_key JOIN tag t ON d.id = t.event_id \
+            WHERE e.id = $1 AND e.hidden = 1::bit(1) AND d.kind = 5 AND t.\"name\" = 'e' AND t.value_hex = $1 LIMIT 1")
+            .bind(hex::decode(id).ok())
+            .fetch_optional(&self.conn)
+            .await?;
+        Ok(row.is_some())
+    }
+
+    async fn get_relay_list(&self, pub_key: &str) -> Result<Option<Event>> {
+        let row = sqlx::query(r#"SELECT e."content" FROM "event" e WHERE e.pub_key = $1 AND e.kind = $2 AND e.hidden != 1::bit(1) ORDER BY e.created_at DESC LIMIT 1"#)
+            .bind(hex::decode(pub_key).ok())
+            .bind(KIND_RELAY_LIST as i64)
+            .fetch_optional(&self.conn)
+            .await?;
+        match row {
+            Some(r) => {
+                let content: Vec<u8> = r.get(0);
+                Ok(Some(serde_json::from_slice(&content)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn apply_group_update(&self, update: &GroupUpdate) -> Result<()> {
+        let mut tx = self.conn.begin().await?;
+        let upsert_member = r#"INSERT INTO "group_member" (group_id, pub_key, "role") VALUES ($1, $2, $3)
+ON CONFLICT (group_id, pub_key) DO UPDATE SET "role" = EXCLUDED."role""#;
+        match update {
+            GroupUpdate::Create { group, admin } => {
+                sqlx::query(r#"INSERT INTO "relay_group" (id, created_at) VALUES ($1, now()) ON CONFLICT (id) DO NOTHING"#)
+                    .bind(group)
+                    .execute(&mut tx)
+                    .await?;
+                sqlx::query(upsert_member)
+                    .bind(group)
+                    .bind(hex::decode(admin).ok())
+                    .bind(GroupRole::Admin.as_str())
+                    .execute(&mut tx)
+                    .await?;
+            }
+            GroupUpdate::Delete { group } => {
+                sqlx::query(r#"DELETE FROM "relay_group" WHERE id = $1"#)
+                    .bind(group)
+                    .execute(&mut tx)
+                    .await?;
+            }
+            GroupUpdate::SetMember { group, pubkey, role: Some(role) } => {
+                sqlx::query(upsert_member)
+                    .bind(group)
+                    .bind(hex::decode(pubkey).ok())
+                    .bind(role.as_str())
+                    .execute(&mut tx)
+                    .await?;
+            }
+            GroupUpdate::SetMember { group, pubkey, role: None } => {
+                sqlx::query(r#"DELETE FROM "group_member" WHERE group_id = $1 AND pub_key = $2"#)
+                    .bind(group)
+                    .bind(hex::decode(pubkey).ok())
+                    .execute(&mut tx)
+                    .await?;
+            }
+        }
+        tx.commit().await?;
+        debug!("applied group update: {:?}", update);
+        Ok(())
+    }
+
+    async fn get_groups(&self) -> Result<Vec<Group>> {
+        let rows = sqlx::query(r#"SELECT g.id, m.pub_key, m."role" FROM "relay_group" g LEFT JOIN "group_member" m ON g.id = m.group_id ORDER BY g.id"#)
+            .fetch_all(&self.conn)
+            .await?;
+        let mut groups: Vec<Group> = vec![];
+        for row in rows {
+            let id: String = row.get(0);
+            if groups.last().map(|g| &g.id) != Some(&id) {
+                groups.push(Group { id, ..Default::default() });
+            }
+            let pubkey: Option<Vec<u8>> = row.get(1);
+            let role: Option<String> = row.get(2);
+            if let (Some(g), Some(pk), Some(r)) = (groups.last_mut(), pubkey, role.as_deref().and_then(GroupRole::from_name)) {
+                g.members.insert(hex::encode(pk), r);
+            }
+        }
+        Ok(groups)
+    }
+
+    async fn add_ban(&self, ban: &Ban) -> Result<()> {
+        let mut tx = self.conn.begin().await?;
+        sqlx::query(r#"DELETE FROM "ban" WHERE expires_at <= now()"#)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query(r#"INSERT INTO "ban" (target, value, expires_at, reason) VALUES ($1, $2, $3, $4)
+ON CONFLICT (target, value) DO UPDATE SET expires_at = EXCLUDED.expires_at, reason = EXCLUDED.reason"#)
+            .bind(ban.target.as_str())
+            .bind(&ban.value)
+            .bind(ban.expires_at.map(|t| Utc.timestamp_opt(t as i64, 0).unwrap()))
+            .bind(&ban.reason)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_bans(&self) -> Result<Vec<Ban>> {
+        let rows = sqlx::query(r#"SELECT target, value, expires_at, reason FROM "ban" WHERE expires_at IS NULL OR expires_at > now()"#)
+            .fetch_all(&self.conn)
+            .await?;
+        Ok(rows
+            .iter()
+            .filter_map(|row| {
+                let expires_at: Option<DateTime<Utc>> = row.get(2);
+                Some(Ban {
+                    target: BanTarget::from_name(row.get(0))?,
+                    value: row.get(1),
+                    expires_at: expires_at.map(|t| t.timestamp() as u64),
+                    reason: row.get(3),
+                })
+            })
+            .collect())
+    }
+
+    async fn get_allowed_pubkeys(&self) -> Result<Vec<String>> {
+        let rows = sqlx::query(r#"SELECT pub_key FROM "allowed_pubkey""#)
+            .fetch_all(&self.conn)
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| hex::encode(row.get::<Vec<u8>, _>(0)))
+            .collect())
+    }
+
+    async fn set_pubkey_allowed(&self, pubkey: &str, allowed: bool) -> Result<()> {
+        let pubkey = hex::decode(pubkey)?;
+        if allowed {
+            sqlx::query(r#"INSERT INTO "allowed_pubkey" (pub_key, created_at) VALUES ($1, now()) ON CONFLICT (pub_key) DO NOTHING"#)
+                .bind(pubkey)
+                .execute(&self.conn)
+                .await?;
+        } else {
+            sqlx::query(r#"DELETE FROM "allowed_pubkey" WHERE pub_key = $1"#)
+                .bind(pubkey)
+                .execute(&self.conn)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn get_reputation(&self, pubkey: &str) -> Result<ReputationRecord> {
+        let row = sqlx::query(r#"SELECT first_seen, reports, spam_hits FROM "reputation" WHERE pub_key = $1"#)
+            .bind(hex::decode(pubkey)?)
+            .fetch_optional(&self.conn)
+            .await?;
+        Ok(row
+            .map(|row| {
+                let first_seen: Option<DateTime<Utc>> = row.get(0);
+                let reports: i64 = row.get(1);
+                let spam_hits: i64 = row.get(2);
+                ReputationRecord {
+                    first_seen: first_seen.map(|t| t.timestamp() as u64),
+                    reports: reports as u64,
+                    spam_hits: spam_hits as u64,
+                }
+            })
+            .unwrap_or_default())
+    }
+
+    async fn update_reputation(&self, pubkey: &str, change: ReputationChange) -> Result<()> {
+        let (first_seen, reports, spam_hits) = match change {
+            ReputationChange::Seen(t) => (Some(Utc.timestamp_opt(t as i64, 0).unwrap()), None, 0i64),
+            ReputationChange::Reported(n) => (None, Some(n as i64), 0),
+            ReputationChange::Spam => (None, None, 1),
+        };
+        sqlx::query(r#"INSERT INTO "reputation" (pub_key, first_seen, reports, spam_hits) VALUES ($1, $2, COALESCE($3, 0), $4)
+ON CONFLICT (pub_key) DO UPDATE SET first_seen = COALESCE("reputation".first_seen, EXCLUDED.first_seen),
+reports = COALESCE($3, "reputation".reports), spam_hits = "reputation".spam_hits + EXCLUDED.spam_hits"#)
+            .bind(hex::decode(pubkey)?)
+            .bind(first_seen)
+            .bind(reports)
+            .bind(spam_hits)
+            .execute(&self.conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn add_report(&self, report: &Report) -> Result<()> {
+        let event_id = match &report.event_id {
+            Some(id) => hex::decode(id)?,
+            None => vec![],
+        };
+        sqlx::query(r#"INSERT INTO "report" (reporter, pub_key, event_id, report_type, created_at) VALUES ($1, $2, $3, $4, $5)
+ON CONFLICT (reporter, pub_key, event_id) DO UPDATE SET report_type = EXCLUDED.report_type, created_at = EXCLUDED.created_at"#)
+            .bind(hex::decode(&report.reporter)?)
+            .bind(hex::decode(&report.pubkey)?)
+            .bind(event_id)
+            .bind(&report.report_type)
+            .bind(Utc.timestamp_opt(report.created_at as i64, 0).unwrap())
+            .execute(&self.conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_reports(&self, event_id: Option<&str>) -> Result<Vec<Report>> {
+        let sql = r#"SELECT reporter, pub_key, event_id, report_type, created_at FROM "report""#;
+        let rows = match event_id {
+            Some(id) => sqlx::query(&format!("{sql} WHERE event_id = $1"))
+                .bind(hex::decode(id)?)
+                .fetch_all(&self.conn)
+                .await?,
+            None => sqlx::query(sql).fetch_all(&self.conn).await?,
+        };
+        Ok(rows
+            .iter()
+            .map(|row| {
+                let event_id: Vec<u8> = row.get(2);
+                let created_at: DateTime<Utc> = row.get(4);
+                Report {
+                    reporter: hex::encode(row.get::<Vec<u8>, _>(0)),
+                    pubkey: hex::encode(row.get::<Vec<u8>, _>(1)),
+                    event_id: (!event_id.is_empty()).then(|| hex::encode(event_id)),
+                    report_type: row.get(3),
+                    created_at: created_at.timestamp() as u64,
+                }
+            })
+            .collect())
+    }
+
+    async fn hide_event(&self, id: &str) -> Result<bool> {
+        let res = sqlx::query(r#"UPDATE "event" SET hidden = 1::bit(1) WHERE id = $1 AND hidden != 1::bit(1)"#)
+            .bind(hex::decode(id)?)
+            .execute(&self.conn)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn get_account(&self, pubkey: &str) -> Result<Option<Account>> {
+        let row = sqlx::query(r#"SELECT admitted, balance_msat, created_at FROM "account" WHERE pub_key = $1"#)
+            .bind(hex::decode(pubkey)?)
+            .fetch_optional(&self.conn)
+            .await?;
+        Ok(row.map(|row| {
+            let balance_msat: i64 = row.get(1);
+            let created_at: DateTime<Utc> = row.get(2);
+            Account {
+                pubkey: pubkey.to_owned(),
+                admitted: row.get(0),
+                balance_msat: balance_msat as u64,
+                created_at: created_at.timestamp() as u64,
+            }
+        }))
+    }
+
+    async fn add_invoice(&self, invoice: &Invoice) -> Result<()> {
+        let pubkey = hex::decode(&invoice.pubkey)?;
+        let created_at = Utc.timestamp_opt(invoice.created_at as i64, 0).unwrap();
+        let mut tx = self.conn.begin().await?;
+        sqlx::query(r#"INSERT INTO "account" (pub_key, admitted, created_at) VALUES ($1, FALSE, $2) ON CONFLICT (pub_key) DO NOTHING"#)
+            .bind(&pubkey)
+            .bind(created_at)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query(r#"INSERT INTO "invoice" (payment_hash, pub_key, bolt11, amount_msat, topup, paid, created_at, expires_at) VALUES ($1, $2, $3, $4, $5, FALSE, $6, $7)"#)
+            .bind(hex::decode(&invoice.payment_hash)?)
+            .bind(&pubkey)
+            .bind(&invoice.bolt11)
+            .bind(invoice.amount_msat as i64)
+            .bind(invoice.topup)
+            .bind(created_at)
+            .bind(Utc.timestamp_opt(invoice.expires_at as i64, 0).unwrap())
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_unpaid_invoices(&self) -> Result<Vec<Invoice>> {
+        let rows = sqlx::query(r#"SELECT payment_hash, pub_key, bolt11, amount_msat, topup, created_at, expires_at FROM "invoice" WHERE paid = FALSE"#)
+            .fetch_all(&self.conn)
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| {
+                let amount_msat: i64 = row.get(3);
+                let created_at: DateTime<Utc> = row.get(5);
+                let expires_at: DateTime<Utc> = row.get(6);
+                Invoice {
+                    payment_hash: hex::encode(row.get::<Vec<u8>, _>(0)),
+                    pubkey: hex::encode(row.get::<Vec<u8>, _>(1)),
+                    bolt11: row.get(2),
+                    amount_msat: amount_msat as u64,
+                    topup: row.get(4),
+                    paid: false,
+                    created_at: created_at.timestamp() as u64,
+                    expires_at: expires_at.timestamp() as u64,
+                }
+            })
+            .collect())
+    }
+
+    async fn invoice_paid(&self, payment_hash: &str) -> Result<bool> {
+        let mut tx = self.conn.begin().await?;
+        let row = sqlx::query(r#"UPDATE "invoice" SET paid = TRUE WHERE payment_hash = $1 AND paid = FALSE RETURNING pub_key, CASE WHEN topup THEN amount_msat ELSE 0 END"#)
+            .bind(hex::decode(payment_hash)?)
+            .fetch_optional(&mut tx)
+            .await?;
+        let Some(row) = row else {
+            return Ok(false);
+        };
+        sqlx::query(r#"UPDATE "account" SET admitted = TRUE, balance_msat = balance_msat + $2 WHERE pub_key = $1"#)
+            .bind(row.get::<Vec<u8>, _>(0))
+            .bind(row.get::<i64, _>(1))
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn debit_account(&self, pubkey: &str, amount_msat: u64) -> Result<bool> {
+        let res = sqlx::query(r#"UPDATE "account" SET balance_msat = balance_msat - $2 WHERE pub_key = $1 AND balance_msat >= $2"#)
+            .bind(hex::decode(pubkey)?)
+            .bind(amount_msat as i64)
+            .execute(&self.conn)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn credit_account(&self, pubkey: &str, amount_msat: u64) -> Result<()> {
+        sqlx::query(r#"UPDATE "account" SET balance_msat = balance_msat + $2 WHERE pub_key = $1"#)
+            .bind(hex::decode(pubkey)?)
+            .bind(amount_msat as i64)
+            .execute(&self.conn)
+            .await?;
+        Ok(())
+    }
+
     async fn optimize_db(&self) -> Result<()> {
-        // Not implemented
+        let start = Instant::now();
+        sqlx::query("ANALYZE;").execute(&self.conn).await?;
+        info!("optimize ran in {:?}", start.elapsed());
         Ok(())
     }
 
+    /// Rewrite every table, which locks each while it is copied.
+    async fn compact(&self) -> Result<()> {
+        let start = Instant::now();
+        sqlx::query("VACUUM (FULL, ANALYZE);").execute(&self.conn).await?;
+        info!("vacuum full ran in {:?}", start.elapsed());
+        Ok(())
+    }
+
+    /// Make the space of dead rows reusable, without locking tables.
+    /// Only space at the end of a table is returned to the file
+    /// system.
+    async fn vacuum(&self) -> Result<u64> {
+        let size = "SELECT pg_database_size(current_database())";
+        let before: i64 = sqlx::query_scalar(size).fetch_one(&self.conn).await?;
+        sqlx::query("VACUUM (ANALYZE);").execute(&self.conn).await?;
+        let after: i64 = sqlx::query_scalar(size).fetch_one(&self.conn).await?;
+        Ok((before - after).max(0) as u64)
+    }
+
+    async fn ping(&self) -> Result<()> {
+        sqlx::query("SELECT 1").execute(&self.conn).await?;
+        Ok(())
+    }
+
+    async fn event_summaries(&self, order: ScanOrder, after: Option<&EventSummary>, limit: usize) -> Result<Vec<EventSummary>> {
+        let (cmp, dir) = order.sql();
+        let rows = match after {
+            Some(a) => sqlx::query(&format!(r#"SELECT id, pub_key, kind, created_at FROM "event" WHERE created_at {cmp} $1 OR (created_at = $1 AND id {cmp} $2) ORDER BY created_at {dir}, id {dir} LIMIT $3"#))
+                .bind(Utc.timestamp_opt(a.created_at as i64, 0).unwrap())
+                .bind(hex::decode(&a.id).ok())
+                .bind(limit as i64)
+                .fetch_all(&self.conn)
+                .await?,
+            None => sqlx::query(&format!(r#"SELECT id, pub_key, kind, created_at FROM "event" ORDER BY created_at {dir}, id {dir} LIMIT $1"#))
+                .bind(limit as i64)
+                .fetch_all(&self.conn)
+                .await?,
+        };
+        Ok(rows
+            .iter()
+            .map(|r| EventSummary {
+                id: hex::encode(r.get::<Vec<u8>, _>(0)),
+                pubkey: hex::encode(r.get::<Vec<u8>, _>(1)),
+                kind: r.get::<i32, _>(2) as u64,
+                created_at: r.get::<DateTime<Utc>, _>(3).timestamp() as u64,
+            })
+            .collect())
+    }
+
+    async fn used_bytes(&self) -> Result<u64> {
+        // table files do not shrink until vacuumed, so measure the
+        // stored events themselves.
+        let used: i64 = sqlx::query_scalar(r#"SELECT COALESCE(SUM(pg_column_size("content")), 0)::bigint FROM "event""#)
+            .fetch_one(&self.conn)
+            .await?;
+        Ok(used as u64)
+    }
+
+    async fn author_bytes(&self, pubkey: &str) -> Result<u64> {
+        let used: i64 = sqlx::query_scalar(r#"SELECT COALESCE(SUM(octet_length("content")), 0)::bigint FROM "event" WHERE pub_key = $1 AND hidden != 1::bit(1)"#)
+            .bind(hex::decode(pubkey)?)
+            .fetch_one(&self.conn)
+            .await?;
+        Ok(used as u64)
+    }
+
+    async fn delete_events(&self, ids: &[String]) -> Result<u64> {
+        let ids: Vec<Vec<u8>> = ids.iter().filter_map(|id| hex::decode(id).ok()).collect();
+        // tags and verification records are removed by foreign key cascades.
+        let count = sqlx::query(r#"DELETE FROM "event" WHERE id = ANY($1)"#)
+            .bind(ids)
+            .execute(&self.conn)
+            .await?
+            .rows_affected();
+        Ok(count)
+    }
+
     async fn create_verification_record(&self, event_id: &str, name: &str) -> Result<()> {
         let mut tx = self.conn.begin().await?;
 
@@ -503,17 +1099,94 @@ ON CONFLICT (id) DO NOTHING"#,
             .await?
             .ok_or(error::Error::SqlxError(RowNotFound))
     }
+
+    async fn put_queued_verification(&self, queued: &QueuedVerification) -> Result<()> {
+        sqlx::query(r#"INSERT INTO "verification_queue" (pub_key, event_id, created_at, "event", attempts, retry_at) VALUES ($1, $2, $3, $4, $5, $6)
+ON CONFLICT (pub_key) DO UPDATE SET event_id = EXCLUDED.event_id, created_at = EXCLUDED.created_at, "event" = EXCLUDED."event",
+attempts = EXCLUDED.attempts, retry_at = EXCLUDED.retry_at WHERE EXCLUDED.created_at >= "verification_queue".created_at"#)
+            .bind(hex::decode(&queued.event.pubkey)?)
+            .bind(hex::decode(&queued.event.id)?)
+            .bind(queued.event.created_at as i64)
+            .bind(serde_json::to_string(&queued.event)?)
+            .bind(queued.attempts as i64)
+            .bind(queued.retry_at as i64)
+            .execute(&self.conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_queued_verifications(&self, due: u64, limit: usize) -> Result<Vec<QueuedVerification>> {
+        let rows = sqlx::query(r#"SELECT "event", attempts, retry_at FROM "verification_queue" WHERE retry_at <= $1 ORDER BY retry_at ASC LIMIT $2"#)
+            .bind(due as i64)
+            .bind(limit as i64)
+            .fetch_all(&self.conn)
+            .await?;
+        let mut queued = vec![];
+        for row in rows {
+            let json: String = row.get(0);
+            let attempts: i64 = row.get(1);
+            let retry_at: i64 = row.get(2);
+            queued.push(QueuedVerification {
+                event: serde_json::from_str(&json)?,
+                attempts: attempts as u64,
+                retry_at: retry_at as u64,
+            });
+        }
+        Ok(queued)
+    }
+
+    async fn remove_queued_verification(&self, pubkey: &str, event_id: &str) -> Result<()> {
+        sqlx::query(r#"DELETE FROM "verification_queue" WHERE pub_key = $1 AND event_id = $2"#)
+            .bind(hex::decode(pubkey)?)
+            .bind(hex::decode(event_id)?)
+            .execute(&self.conn)
+            .await?;
+        Ok(())
+    }
 }
 
 /// Create a dynamic SQL query and params from a subscription filter.
 fn query_from_filter(f: &ReqFilter) -> Option<QueryBuilder<Postgres>> {
-    // if the filter is malformed, don't return anything.
-    if f.force_no_match {
+    // if the filter is malformed, or can't match, don't return anything.
+    if planner::plan(f).access == Access::Nothing {
         return None;
     }
 
     let mut query = QueryBuilder::new("SELECT e.\"content\", e.created_at FROM \"event\" e WHERE ");
+    push_filter_conditions(&mut query, f);
+
+    // Apply per-filter limit to this query.
+    // The use of a LIMIT implies a DESC order, to capture only the most recent events.
+    if let Some(lim) = f.limit {
+        query.push(" ORDER BY e.created_at DESC LIMIT ");
+        query.push(lim.min(1000));
+    } else {
+        query.push(" ORDER BY e.created_at ASC LIMIT ");
+        query.push(1000);
+    }
+    Some(query)
+}
+
+/// Create a SQL query counting events matching any of the filters.
+fn count_query_from_filters(filters: &[ReqFilter]) -> Option<QueryBuilder<'_, Postgres>> {
+    let filters: Vec<&ReqFilter> = filters.iter().filter(|f| !f.force_no_match).collect();
+    if filters.is_empty() {
+        return None;
+    }
+    let mut query = QueryBuilder::new("SELECT COUNT(*) FROM \"event\" e WHERE ");
+    for (i, f) in filters.iter().enumerate() {
+        if i > 0 {
+            query.push(" OR ");
+        }
+        query.push("(");
+        push_filter_conditions(&mut query, f);
+        query.push(")");
+    }
+    Some(query)
+}
 
+/// Append the WHERE conditions for a filter to a query.
+fn push_filter_conditions<'a>(query: &mut QueryBuilder<'a, Postgres>, f: &'a ReqFilter) {
     let mut push_and = false;
     // Query for "authors", allowing prefix matches
     if let Some(auth_vec) = &f.authors {
@@ -657,30 +1330,52 @@ fn query_from_filter(f: &ReqFilter) -> Option<QueryBuilder<Postgres>> {
     }
 
     // Query for tags
-    if let Some(map) = &f.tags {
-        if !map.is_empty() {
-            if push_and {
-                query.push(" AND ");
-            }
-            push_and = true;
-
-            for (key, val) in map.iter() {
-                query.push("e.id IN (SELECT ee.id FROM \"event\" ee LEFT JOIN tag t on ee.id = t.event_id WHERE ee.hidden != 1::bit(1) and (t.\"name\" = ")
-                    .push_bind(key.to_string())
-                    .push(" AND (value in (");
+    for (key, val) in f.tag_conditions() {
+        if push_and {
+            query.push(" AND ");
+        }
+        push_and = true;
+        query.push("e.id IN (SELECT ee.id FROM \"event\" ee LEFT JOIN tag t on ee.id = t.event_id WHERE ee.hidden != 1::bit(1) and (t.\"name\" = ")
+            .push_bind(key.to_string())
+            .push(" AND (");
 
-                // plain value match first
-                let mut tag_query = query.separated(", ");
-                for v in val.iter() {
-                    if (v.len() % 2 != 0) && !is_lower_hex(v) {
-                        tag_query.push_bind(v.as_bytes());
-                    } else {
-                        tag_query.push_bind(hex::decode(v).ok());
-                    }
-                }
-                query.push("))))");
+        // plain values are matched against value, and lowercase hex
+        // values (stored decoded) against value_hex.
+        let (hex_vals, plain_vals): (Vec<&String>, Vec<&String>) = val
+            .iter()
+            .partition(|v| is_lower_hex(v) && v.len() % 2 == 0);
+        if !plain_vals.is_empty() {
+            query.push("value IN (");
+            let mut tag_query = query.separated(", ");
+            for v in &plain_vals {
+                tag_query.push_bind(v.as_bytes().to_vec());
+            }
+            query.push(")");
+        }
+        if !hex_vals.is_empty() {
+            if !plain_vals.is_empty() {
+                query.push(" OR ");
+            }
+            query.push("value_hex IN (");
+            let mut tag_query = query.separated(", ");
+            for v in &hex_vals {
+                tag_query.push_bind(hex::decode(v).ok());
             }
+            query.push(")");
         }
+        query.push(")))");
+    }
+
+    // Query for full-text search
+    if let Some(search) = &f.search {
+        if push_and {
+            query.push(" AND ");
+        }
+        push_and = true;
+        query
+            .push("e.search_tsv @@ plainto_tsquery('simple', ")
+            .push_bind(search)
+            .push(")");
     }
 
     // Query for timestamp
@@ -711,17 +1406,6 @@ fn query_from_filter(f: &ReqFilter) -> Option<QueryBuilder<Postgres>> {
     } else {
         query.push("e.hidden != 1::bit(1)");
     }
-
-    // Apply per-filter limit to this query.
-    // The use of a LIMIT implies a DESC order, to capture only the most recent events.
-    if let Some(lim) = f.limit {
-        query.push(" ORDER BY e.created_at DESC LIMIT ");
-        query.push(lim.min(1000));
-    } else {
-        query.push(" ORDER BY e.created_at ASC LIMIT ");
-        query.push(1000);
-    }
-    Some(query)
 }
 
 impl FromRow<'_, PgRow> for VerificationRecord {
@@ -733,7 +1417,10 @@ impl FromRow<'_, PgRow> for VerificationRecord {
             address: hex::encode(row.get::<'_, Vec<u8>, &str>("pub_key")),
             event: hex::encode(row.get::<'_, Vec<u8>, &str>("event_id")),
             event_created: row.get::<'_, DateTime<Utc>, &str>("created_at").timestamp() as u64,
-            last_success: None,
+            last_success: match row.try_get::<'_, DateTime<Utc>, &str>("verified_at") {
+                Ok(x) => Some(x.timestamp() as u64),
+                _ => None,
+            },
             last_failure: match row.try_get::<'_, DateTime<Utc>, &str>("failed_at") {
                 Ok(x) => Some(x.timestamp() as u64),
                 _ => None,
@@ -35,6 +35,21 @@ pub async fn run_migrations(db: &PostgresPool) -> crate::error::Result<usize> {
         m002::rebuild_tags(db).await?;
     }
     run_migration(m003::migration(), db).await;
+    run_migration(m004::migration(), db).await;
+    run_migration(m005::migration(), db).await;
+    run_migration(m006::migration(), db).await;
+    run_migration(m007::migration(), db).await;
+    run_migration(m008::migration(), db).await;
+    run_migration(m009::migration(), db).await;
+    run_migration(m010::migration(), db).await;
+    run_migration(m011::migration(), db).await;
+    run_migration(m012::migration(), db).await;
+    run_migration(m013::migration(), db).await;
+    // hex tag values used to be written to the value column; move
+    // them to value_hex, where they are now written and queried.
+    if run_migration(m014::migration(), db).await == MigrationResult::Upgraded {
+        m002::rebuild_tags(db).await?;
+    }
     Ok(current_version(db).await as usize)
 }
 
@@ -256,3 +271,266 @@ ALTER TABLE tag ADD CONSTRAINT unique_constraint_name UNIQUE (event_id, "name",
         }
     }
 }
+
+mod m004 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 4;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Add full-text search column (NIP-50)
+ALTER TABLE "event" ADD COLUMN search_tsv tsvector;
+UPDATE "event" SET search_tsv = to_tsvector('simple', coalesce(convert_from("content", 'UTF8')::jsonb->>'content', ''));
+CREATE INDEX event_search_tsv_idx ON "event" USING gin (search_tsv);
+        "#,
+            ],
+        }
+    }
+}
+
+mod m005 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 5;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Relay-based groups (NIP-29)
+CREATE TABLE "relay_group" (
+	id varchar NOT NULL,
+	created_at timestamp with time zone NOT NULL,
+	CONSTRAINT relay_group_pkey PRIMARY KEY (id)
+);
+CREATE TABLE "group_member" (
+	group_id varchar NOT NULL,
+	pub_key bytea NOT NULL,
+	"role" varchar NOT NULL,
+	CONSTRAINT group_member_pkey PRIMARY KEY (group_id, pub_key),
+	CONSTRAINT group_member_fk FOREIGN KEY (group_id) REFERENCES "relay_group"(id) ON DELETE CASCADE
+);
+        "#,
+            ],
+        }
+    }
+}
+
+mod m006 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 6;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Banned IP addresses and pubkeys
+CREATE TABLE "ban" (
+	target varchar NOT NULL,
+	value varchar NOT NULL,
+	expires_at timestamp with time zone NULL,
+	reason varchar NOT NULL,
+	CONSTRAINT ban_pkey PRIMARY KEY (target, value)
+);
+        "#,
+            ],
+        }
+    }
+}
+
+mod m007 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 7;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Reputation of event authors
+CREATE TABLE "reputation" (
+	pub_key bytea NOT NULL,
+	first_seen timestamp with time zone NULL,
+	reports int8 NOT NULL DEFAULT 0,
+	spam_hits int8 NOT NULL DEFAULT 0,
+	CONSTRAINT reputation_pkey PRIMARY KEY (pub_key)
+);
+        "#,
+            ],
+        }
+    }
+}
+
+mod m008 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 8;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Reports (NIP-56) of pubkeys and events; event_id is empty for
+-- reports of a pubkey.
+CREATE TABLE "report" (
+	reporter bytea NOT NULL,
+	pub_key bytea NOT NULL,
+	event_id bytea NOT NULL,
+	report_type varchar NOT NULL,
+	created_at timestamp with time zone NOT NULL,
+	CONSTRAINT report_pkey PRIMARY KEY (reporter, pub_key, event_id)
+);
+CREATE INDEX report_event_idx ON "report" USING btree (event_id);
+        "#,
+            ],
+        }
+    }
+}
+
+mod m009 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 9;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Accounts of pubkeys that have requested paid admission
+CREATE TABLE "account" (
+	pub_key bytea NOT NULL,
+	admitted bool NOT NULL DEFAULT FALSE,
+	created_at timestamp with time zone NOT NULL,
+	CONSTRAINT account_pkey PRIMARY KEY (pub_key)
+);
+-- Lightning invoices issued for admission
+CREATE TABLE "invoice" (
+	payment_hash bytea NOT NULL,
+	pub_key bytea NOT NULL,
+	bolt11 varchar NOT NULL,
+	amount_msat int8 NOT NULL,
+	paid bool NOT NULL DEFAULT FALSE,
+	created_at timestamp with time zone NOT NULL,
+	expires_at timestamp with time zone NOT NULL,
+	CONSTRAINT invoice_pkey PRIMARY KEY (payment_hash)
+);
+CREATE INDEX invoice_paid_idx ON "invoice" USING btree (paid);
+        "#,
+            ],
+        }
+    }
+}
+
+mod m010 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 10;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Balances for paying per event, and invoices that top them up
+ALTER TABLE "account" ADD COLUMN balance_msat int8 NOT NULL DEFAULT 0;
+ALTER TABLE "invoice" ADD COLUMN topup bool NOT NULL DEFAULT FALSE;
+        "#,
+            ],
+        }
+    }
+}
+
+mod m011 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 11;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- The newest events of some kinds, or by some authors, are read in
+-- order from an index, instead of sorting every match.  The pubkey
+-- index is a prefix of the new one.
+CREATE INDEX event_kind_created_at_idx ON "event" (kind, created_at);
+CREATE INDEX event_pub_key_created_at_idx ON "event" (pub_key, created_at);
+DROP INDEX event_pub_key_idx;
+        "#,
+            ],
+        }
+    }
+}
+
+mod m012 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 12;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Pubkeys in the whitelist, changed while the relay runs
+CREATE TABLE "allowed_pubkey" (
+	pub_key bytea NOT NULL,
+	created_at timestamp with time zone NOT NULL,
+	CONSTRAINT allowed_pubkey_pkey PRIMARY KEY (pub_key)
+);
+        "#,
+            ],
+        }
+    }
+}
+
+mod m013 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 13;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Metadata events waiting for NIP-05 verification
+CREATE TABLE "verification_queue" (
+	pub_key bytea NOT NULL,
+	event_id bytea NOT NULL,
+	created_at int8 NOT NULL,
+	"event" text NOT NULL,
+	attempts int8 NOT NULL DEFAULT 0,
+	retry_at int8 NOT NULL,
+	CONSTRAINT verification_queue_pkey PRIMARY KEY (pub_key)
+);
+CREATE INDEX verification_queue_retry_idx ON "verification_queue" USING btree (retry_at);
+        "#,
+            ],
+        }
+    }
+}
+
+mod m014 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 14;
+
+    pub fn migration() -> impl Migration {
+        // the tags are rebuilt after this runs
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![],
+        }
+    }
+}
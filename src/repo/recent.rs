@@ -0,0 +1,498 @@
+//! Recently written events, held in memory
+//!
+//! Clients that reconnect commonly ask for everything since they were
+//! last online, a few minutes ago.  Events created within a configured
+//! window are kept in memory as they are written, and subscriptions
+//! whose filters all start inside that window are answered without
+//! touching the database.  Older versions of replaceable events, and
+//! events deleted by their authors, are dropped from memory as the
+//! database hides them.
+use crate::bans::Ban;
+use crate::db::QueryResult;
+use crate::error::Result;
+use crate::event::Event;
+use crate::groups::{Group, GroupUpdate};
+use crate::mirror::RecentIds;
+use crate::nip05::{QueuedVerification, VerificationRecord};
+use crate::payment::{Account, Invoice};
+use crate::repo::{EventSummary, NostrRepo, ScanOrder};
+use crate::reports::Report;
+use crate::reputation::{ReputationChange, ReputationRecord};
+use crate::subscription::{ReqFilter, Subscription};
+use crate::utils::{is_hex, unix_time};
+use async_trait::async_trait;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+/// Number of deleted event ids (with their authors) remembered, so
+/// that deleted events written again are not served.
+const DELETED_IDS: usize = 100_000;
+
+/// Events are ordered by creation time, then id.
+type EventKey = (u64, String);
+
+/// Identity of a replaceable event: author, kind, and `d` tag
+type ReplaceKey = (String, u64, Option<String>);
+
+fn replace_key(e: &Event) -> Option<ReplaceKey> {
+    (e.is_replaceable() || e.is_param_replaceable()).then(|| (e.pubkey.clone(), e.kind, e.distinct_param()))
+}
+
+/// Ids deleted by a deletion event (NIP-09)
+fn deletion_targets(e: &Event) -> Vec<String> {
+    e.tag_values_by_name("e")
+        .into_iter()
+        .filter(|x| is_hex(x) && x.len() == 64)
+        .collect()
+}
+
+struct Window {
+    /// length of the window, in seconds
+    length: u64,
+    /// events created before this time may be in the database, but
+    /// not in memory
+    complete_from: u64,
+    events: BTreeMap<EventKey, (Event, String)>,
+    ids: HashMap<String, EventKey>,
+    replaceable: HashMap<ReplaceKey, EventKey>,
+    deleted: RecentIds,
+}
+
+impl Window {
+    fn new(length: u64, complete_from: u64) -> Self {
+        Window {
+            length,
+            complete_from,
+            events: BTreeMap::new(),
+            ids: HashMap::new(),
+            replaceable: HashMap::new(),
+            deleted: RecentIds::new(DELETED_IDS),
+        }
+    }
+
+    fn cutoff(&self, now: u64) -> u64 {
+        now.saturating_sub(self.length).max(self.complete_from)
+    }
+
+    /// Check if every filter only matches events in memory.
+    fn covers(&self, filters: &[ReqFilter], now: u64) -> bool {
+        let cutoff = self.cutoff(now);
+        !filters.is_empty() && filters.iter().all(|f| f.since.is_some_and(|s| s >= cutoff))
+    }
+
+    /// Add an event that was written to the database.
+    fn insert(&mut self, e: &Event, now: u64) {
+        if e.created_at < self.cutoff(now) || self.ids.contains_key(&e.id) {
+            return;
+        }
+        if e.kind == 5 {
+            for id in deletion_targets(e) {
+                let targeted = self.ids.get(&id).and_then(|k| self.events.get(k));
+                if targeted.is_some_and(|(t, _)| t.pubkey == e.pubkey && t.kind != 5) {
+                    self.remove(&id);
+                }
+                self.deleted.insert(&format!("{id}:{}", e.pubkey));
+            }
+        } else if self.deleted.contains(&format!("{}:{}", e.id, e.pubkey)) {
+            return;
+        }
+        let key: EventKey = (e.created_at, e.id.clone());
+        if let Some(rk) = replace_key(e) {
+            // the database only accepts the newest version.
+            if let Some(old) = self.replaceable.insert(rk, key.clone()) {
+                self.remove(&old.1);
+            }
+        }
+        let Ok(json) = serde_json::to_string(e) else {
+            return;
+        };
+        self.ids.insert(e.id.clone(), key.clone());
+        self.events.insert(key, (e.clone(), json));
+    }
+
+    fn remove(&mut self, id: &str) {
+        let Some(key) = self.ids.remove(id) else {
+            return;
+        };
+        if let Some((e, _)) = self.events.remove(&key) {
+            if let Some(rk) = replace_key(&e) {
+                if self.replaceable.get(&rk) == Some(&key) {
+                    self.replaceable.remove(&rk);
+                }
+            }
+        }
+    }
+
+    /// Drop events created before the window.
+    fn evict(&mut self, now: u64) {
+        let cutoff = self.cutoff(now);
+        while let Some(entry) = self.events.first_entry() {
+            if entry.key().0 >= cutoff {
+                break;
+            }
+            let id = entry.key().1.clone();
+            self.remove(&id);
+        }
+    }
+
+    /// Events matching any filter, newest first, keeping only as many
+    /// as each filter's limit allows.
+    fn query(&self, filters: &[ReqFilter]) -> Vec<String> {
+        let mut remaining: Vec<Option<u64>> = filters.iter().map(|f| f.limit).collect();
+        let mut results = vec![];
+        for (e, json) in self.events.values().rev() {
+            if remaining.iter().all(|r| *r == Some(0)) {
+                break;
+            }
+            let mut wanted = false;
+            for (f, rem) in filters.iter().zip(remaining.iter_mut()) {
+                if rem.is_none_or(|r| r > 0) && f.interested_in_event(e) {
+                    wanted = true;
+                    if let Some(r) = rem {
+                        *r -= 1;
+                    }
+                }
+            }
+            if wanted {
+                results.push(json.clone());
+            }
+        }
+        results
+    }
+}
+
+/// A repository that answers queries for recent events from memory.
+/// Everything else is passed through to the database.
+pub struct RecentRepo {
+    inner: Arc<dyn NostrRepo>,
+    window: Arc<RwLock<Window>>,
+}
+
+impl RecentRepo {
+    /// Keep events created in the last `length`.  Events dated up to
+    /// `future` ahead may have been written before the relay started,
+    /// so the window is only complete once that has passed.
+    pub fn new(inner: Arc<dyn NostrRepo>, length: Duration, future: Duration) -> Self {
+        info!("serving events from the last {:?} from memory", length);
+        let complete_from = unix_time() + future.as_secs();
+        RecentRepo {
+            inner,
+            window: Arc::new(RwLock::new(Window::new(length.as_secs(), complete_from))),
+        }
+    }
+
+    fn record(&self, events: &[Event], counts: &[u64]) {
+        let now = unix_time();
+        let mut window = self.window.write().unwrap();
+        for (e, n) in events.iter().zip(counts) {
+            if *n > 0 {
+                window.insert(e, now);
+            }
+        }
+        window.evict(now);
+    }
+}
+
+#[async_trait]
+impl NostrRepo for RecentRepo {
+    async fn start(&self) -> Result<()> {
+        self.inner.start().await
+    }
+
+    async fn migrate_up(&self) -> Result<usize> {
+        self.inner.migrate_up().await
+    }
+
+    async fn write_event(&self, e: &Event) -> Result<u64> {
+        let count = self.inner.write_event(e).await?;
+        self.record(std::slice::from_ref(e), &[count]);
+        Ok(count)
+    }
+
+    async fn write_events(&self, events: &[Event]) -> Result<Vec<u64>> {
+        let counts = self.inner.write_events(events).await?;
+        self.record(events, &counts);
+        Ok(counts)
+    }
+
+    async fn query_subscription(
+        &self,
+        sub: Subscription,
+        client_id: String,
+        query_tx: tokio::sync::mpsc::Sender<QueryResult>,
+        mut abandon_query_rx: tokio::sync::oneshot::Receiver<()>,
+    ) -> Result<()> {
+        let start = Instant::now();
+        let results = {
+            let window = self.window.read().unwrap();
+            window
+                .covers(&sub.filters, unix_time())
+                .then(|| window.query(&sub.filters))
+        };
+        let Some(results) = results else {
+            return self
+                .inner
+                .query_subscription(sub, client_id, query_tx, abandon_query_rx)
+                .await;
+        };
+        tokio::spawn(async move {
+            // any client that doesn't accept results for 2 seconds
+            // gets dropped.
+            let abort_cutoff = Duration::from_secs(2);
+            let row_count = results.len();
+            for event in results {
+                if abandon_query_rx.try_recv().is_ok() {
+                    debug!("query cancelled by client (cid: {}, sub: {:?})", client_id, sub.id);
+                    return;
+                }
+                let res = QueryResult {
+                    sub_id: sub.get_id(),
+                    event,
+                };
+                if tokio::time::timeout(abort_cutoff, query_tx.send(res)).await.is_err() {
+                    info!("aborting recent query due to slow client (cid: {}, sub: {:?})", client_id, sub.id);
+                    return;
+                }
+            }
+            debug!(
+                "query answered from memory in {:?} (cid: {}, sub: {:?}, rows: {})",
+                start.elapsed(),
+                client_id,
+                sub.id,
+                row_count
+            );
+            query_tx
+                .send(QueryResult {
+                    sub_id: sub.get_id(),
+                    event: "EOSE".to_string(),
+                })
+                .await
+                .ok();
+        });
+        Ok(())
+    }
+
+    async fn count_events_by_filter(&self, filters: Vec<ReqFilter>) -> Result<u64> {
+        self.inner.count_events_by_filter(filters).await
+    }
+
+    async fn is_event_deleted(&self, id: &str) -> Result<bool> {
+        self.inner.is_event_deleted(id).await
+    }
+
+    async fn get_relay_list(&self, pub_key: &str) -> Result<Option<Event>> {
+        self.inner.get_relay_list(pub_key).await
+    }
+
+    async fn apply_group_update(&self, update: &GroupUpdate) -> Result<()> {
+        self.inner.apply_group_update(update).await
+    }
+
+    async fn get_groups(&self) -> Result<Vec<Group>> {
+        self.inner.get_groups().await
+    }
+
+    async fn add_ban(&self, ban: &Ban) -> Result<()> {
+        self.inner.add_ban(ban).await
+    }
+
+    async fn get_bans(&self) -> Result<Vec<Ban>> {
+        self.inner.get_bans().await
+    }
+
+    async fn get_allowed_pubkeys(&self) -> Result<Vec<String>> {
+        self.inner.get_allowed_pubkeys().await
+    }
+
+    async fn set_pubkey_allowed(&self, pubkey: &str, allowed: bool) -> Result<()> {
+        self.inner.set_pubkey_allowed(pubkey, allowed).await
+    }
+
+    async fn get_reputation(&self, pubkey: &str) -> Result<ReputationRecord> {
+        self.inner.get_reputation(pubkey).await
+    }
+
+    async fn update_reputation(&self, pubkey: &str, change: ReputationChange) -> Result<()> {
+        self.inner.update_reputation(pubkey, change).await
+    }
+
+    async fn add_report(&self, report: &Report) -> Result<()> {
+        self.inner.add_report(report).await
+    }
+
+    async fn get_reports(&self, event_id: Option<&str>) -> Result<Vec<Report>> {
+        self.inner.get_reports(event_id).await
+    }
+
+    async fn hide_event(&self, id: &str) -> Result<bool> {
+        self.window.write().unwrap().remove(id);
+        self.inner.hide_event(id).await
+    }
+
+    async fn get_account(&self, pubkey: &str) -> Result<Option<Account>> {
+        self.inner.get_account(pubkey).await
+    }
+
+    async fn add_invoice(&self, invoice: &Invoice) -> Result<()> {
+        self.inner.add_invoice(invoice).await
+    }
+
+    async fn get_unpaid_invoices(&self) -> Result<Vec<Invoice>> {
+        self.inner.get_unpaid_invoices().await
+    }
+
+    async fn invoice_paid(&self, payment_hash: &str) -> Result<bool> {
+        self.inner.invoice_paid(payment_hash).await
+    }
+
+    async fn debit_account(&self, pubkey: &str, amount_msat: u64) -> Result<bool> {
+        self.inner.debit_account(pubkey, amount_msat).await
+    }
+
+    async fn credit_account(&self, pubkey: &str, amount_msat: u64) -> Result<()> {
+        self.inner.credit_account(pubkey, amount_msat).await
+    }
+
+    async fn optimize_db(&self) -> Result<()> {
+        self.inner.optimize_db().await
+    }
+
+    async fn compact(&self) -> Result<()> {
+        self.inner.compact().await
+    }
+
+    async fn vacuum(&self) -> Result<u64> {
+        self.inner.vacuum().await
+    }
+
+    async fn backup(&self, dest: &Path) -> Result<bool> {
+        self.inner.backup(dest).await
+    }
+
+    async fn ping(&self) -> Result<()> {
+        self.inner.ping().await
+    }
+
+    async fn check_integrity(&self) -> Result<Vec<String>> {
+        self.inner.check_integrity().await
+    }
+
+    async fn event_summaries(&self, order: ScanOrder, after: Option<&EventSummary>, limit: usize) -> Result<Vec<EventSummary>> {
+        self.inner.event_summaries(order, after, limit).await
+    }
+
+    async fn used_bytes(&self) -> Result<u64> {
+        self.inner.used_bytes().await
+    }
+
+    async fn author_bytes(&self, pubkey: &str) -> Result<u64> {
+        self.inner.author_bytes(pubkey).await
+    }
+
+    async fn delete_events(&self, ids: &[String]) -> Result<u64> {
+        {
+            let mut window = self.window.write().unwrap();
+            ids.iter().for_each(|id| window.remove(id));
+        }
+        self.inner.delete_events(ids).await
+    }
+
+    async fn create_verification_record(&self, event_id: &str, name: &str) -> Result<()> {
+        self.inner.create_verification_record(event_id, name).await
+    }
+
+    async fn update_verification_timestamp(&self, id: u64) -> Result<()> {
+        self.inner.update_verification_timestamp(id).await
+    }
+
+    async fn fail_verification(&self, id: u64) -> Result<()> {
+        self.inner.fail_verification(id).await
+    }
+
+    async fn delete_verification(&self, id: u64) -> Result<()> {
+        self.inner.delete_verification(id).await
+    }
+
+    async fn get_latest_user_verification(&self, pub_key: &str) -> Result<VerificationRecord> {
+        self.inner.get_latest_user_verification(pub_key).await
+    }
+
+    async fn get_oldest_user_verification(&self, before: u64) -> Result<VerificationRecord> {
+        self.inner.get_oldest_user_verification(before).await
+    }
+
+    async fn put_queued_verification(&self, queued: &QueuedVerification) -> Result<()> {
+        self.inner.put_queued_verification(queued).await
+    }
+
+    async fn get_queued_verifications(&self, due: u64, limit: usize) -> Result<Vec<QueuedVerification>> {
+        self.inner.get_queued_verifications(due, limit).await
+    }
+
+    async fn remove_queued_verification(&self, pubkey: &str, event_id: &str) -> Result<()> {
+        self.inner.remove_queued_verification(pubkey, event_id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(id: char, kind: u64, created_at: u64) -> Event {
+        let mut e = Event::simple_event();
+        e.id = id.to_string().repeat(64);
+        e.kind = kind;
+        e.created_at = created_at;
+        e
+    }
+
+    fn filters(s: &str) -> Vec<ReqFilter> {
+        serde_json::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn only_filters_inside_the_window_are_covered() {
+        let mut window = Window::new(600, 1000);
+        assert!(!window.covers(&filters(r#"[{"since":900}]"#), 1200));
+        assert!(window.covers(&filters(r#"[{"since":1000}]"#), 1200));
+        assert!(!window.covers(&filters(r#"[{"since":1500},{"kinds":[1]}]"#), 2000));
+        assert!(window.covers(&filters(r#"[{"since":1500},{"since":1400}]"#), 2000));
+        window.insert(&event('a', 1, 1300), 2000);
+        window.insert(&event('b', 1, 1500), 2000);
+        window.evict(2000);
+        assert_eq!(window.events.len(), 1);
+    }
+
+    #[test]
+    fn query_respects_limits_newest_first() {
+        let mut window = Window::new(600, 0);
+        for (i, id) in ['a', 'b', 'c'].into_iter().enumerate() {
+            window.insert(&event(id, 1, 1000 + i as u64), 1000);
+        }
+        window.insert(&event('d', 7, 1003), 1000);
+        let results = window.query(&filters(r#"[{"kinds":[1],"limit":2},{"kinds":[7]}]"#));
+        assert_eq!(results.len(), 3);
+        assert!(results[0].contains(&"d".repeat(64)));
+        assert!(results[1].contains(&"c".repeat(64)));
+        assert!(results[2].contains(&"b".repeat(64)));
+    }
+
+    #[test]
+    fn replaced_and_deleted_events_are_dropped() {
+        let mut window = Window::new(600, 0);
+        window.insert(&event('a', 0, 1000), 1000);
+        window.insert(&event('b', 0, 1001), 1000);
+        assert_eq!(window.query(&filters(r#"[{"kinds":[0]}]"#)).len(), 1);
+
+        let mut deletion = event('c', 5, 1002);
+        deletion.tags = vec![vec!["e".to_owned(), "b".repeat(64)]];
+        window.insert(&deletion, 1000);
+        assert!(window.query(&filters(r#"[{"kinds":[0]}]"#)).is_empty());
+        // a deleted event written again is not served
+        window.insert(&event('b', 0, 1001), 1000);
+        assert!(window.query(&filters(r#"[{"kinds":[0]}]"#)).is_empty());
+    }
+}
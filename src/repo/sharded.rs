@@ -0,0 +1,633 @@
+//! SQLite storage split into monthly shard files
+//!
+//! Regular events are stored in a file for the month of their
+//! `created_at` (e.g. `nostr-2024-01.db`), so that no single database
+//! keeps growing.  Replaceable and parameterized replaceable events,
+//! deletions, and NIP-05 and group state are kept in `nostr.db`, since
+//! they must be found regardless of time.  Queries are sent to
+//! `nostr.db` and each shard their time range covers, newest first,
+//! and the results are merged.
+use crate::bans::Ban;
+use crate::config::Settings;
+use crate::db::QueryResult;
+use crate::error::Result;
+use crate::event::Event;
+use crate::groups::{Group, GroupUpdate};
+use crate::nip05::{QueuedVerification, VerificationRecord};
+use crate::payment::{Account, Invoice};
+use crate::repo::sqlite::SqliteRepo;
+use crate::repo::{EventSummary, NostrRepo, ScanOrder};
+use crate::reports::Report;
+use crate::reputation::{ReputationChange, ReputationRecord};
+use crate::server::NostrMetrics;
+use crate::subscription::{ReqFilter, Subscription};
+use async_trait::async_trait;
+use chrono::{Datelike, TimeZone, Utc};
+use std::collections::{BTreeMap, HashSet};
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use tokio::task;
+use tracing::{debug, info, warn};
+
+/// Shard files are named `nostr-YYYY-MM.db`
+const SHARD_PREFIX: &str = "nostr-";
+const SHARD_SUFFIX: &str = ".db";
+
+/// Results buffered from each shard while a query runs
+const SHARD_QUERY_QUEUE: usize = 1000;
+
+/// Month of a timestamp, counted from year 0
+fn month_of(ts: u64) -> u32 {
+    i64::try_from(ts)
+        .ok()
+        .and_then(|t| Utc.timestamp_opt(t, 0).single())
+        .map_or(1970 * 12, |d| d.year() as u32 * 12 + d.month0())
+}
+
+/// Timestamp at which a month begins
+fn month_start(month: u32) -> u64 {
+    Utc.with_ymd_and_hms((month / 12) as i32, month % 12 + 1, 1, 0, 0, 0)
+        .single()
+        .map_or(0, |d| d.timestamp().max(0) as u64)
+}
+
+fn shard_file(month: u32) -> String {
+    format!("{}{:04}-{:02}{}", SHARD_PREFIX, month / 12, month % 12 + 1, SHARD_SUFFIX)
+}
+
+/// Month of a shard file, if the name is one.
+fn shard_month(file: &str) -> Option<u32> {
+    let (year, month) = file
+        .strip_prefix(SHARD_PREFIX)?
+        .strip_suffix(SHARD_SUFFIX)?
+        .split_once('-')?;
+    if year.len() != 4 || month.len() != 2 {
+        return None;
+    }
+    let (year, month): (u32, u32) = (year.parse().ok()?, month.parse().ok()?);
+    (1..=12).contains(&month).then_some(year * 12 + month - 1)
+}
+
+/// Shard for an event, or `None` if it belongs in `nostr.db`.
+fn shard_for(e: &Event) -> Option<u32> {
+    if e.is_replaceable() || e.is_param_replaceable() || e.kind == 5 {
+        None
+    } else {
+        Some(month_of(e.created_at))
+    }
+}
+
+/// Check if a filter could match events from a month.
+fn filter_covers(f: &ReqFilter, month: u32) -> bool {
+    f.since.is_none_or(|t| t < month_start(month + 1)) && f.until.is_none_or(|t| t >= month_start(month))
+}
+
+/// Check if every filter has a limit, that is met by events created at
+/// or after `since`.  Older shards cannot add anything to the results.
+fn limits_met(filters: &[ReqFilter], matches: &[(Event, String)], since: u64) -> bool {
+    filters.iter().all(|f| {
+        f.limit.is_some_and(|l| {
+            matches
+                .iter()
+                .filter(|(e, _)| e.created_at >= since && f.interested_in_event(e))
+                .count() as u64
+                >= l
+        })
+    })
+}
+
+/// Events to return from the merged matches, newest first, keeping
+/// only as many as each filter's limit allows.
+fn select_results(filters: &[ReqFilter], mut matches: Vec<(Event, String)>) -> Vec<String> {
+    matches.sort_by(|(a, _), (b, _)| b.created_at.cmp(&a.created_at).then_with(|| a.id.cmp(&b.id)));
+    let mut remaining: Vec<Option<u64>> = filters.iter().map(|f| f.limit).collect();
+    let mut results = vec![];
+    for (e, json) in matches {
+        let mut wanted = false;
+        for (f, rem) in filters.iter().zip(remaining.iter_mut()) {
+            if f.interested_in_event(&e) && rem.is_none_or(|r| r > 0) {
+                wanted = true;
+                if let Some(r) = rem {
+                    *r -= 1;
+                }
+            }
+        }
+        if wanted {
+            results.push(json);
+        }
+    }
+    results
+}
+
+/// Run a subscription query on one database, collecting the results.
+async fn collect_results(repo: &SqliteRepo, sub: &Subscription, client_id: &str) -> Result<Vec<String>> {
+    let (query_tx, mut query_rx) = tokio::sync::mpsc::channel(SHARD_QUERY_QUEUE);
+    let (_abandon_tx, abandon_rx) = tokio::sync::oneshot::channel();
+    repo.query_subscription(sub.clone(), client_id.to_owned(), query_tx, abandon_rx)
+        .await?;
+    let mut results = vec![];
+    while let Some(res) = query_rx.recv().await {
+        if res.event == "EOSE" {
+            break;
+        }
+        results.push(res.event);
+    }
+    Ok(results)
+}
+
+pub struct ShardedRepo {
+    settings: Settings,
+    metrics: NostrMetrics,
+    /// Database for events that are not sharded, and relay state
+    main: SqliteRepo,
+    /// Shards by month
+    shards: RwLock<BTreeMap<u32, SqliteRepo>>,
+}
+
+impl ShardedRepo {
+    /// Open `nostr.db` and every existing shard in the data directory.
+    #[must_use]
+    pub fn new(settings: &Settings, metrics: NostrMetrics) -> ShardedRepo {
+        let main = SqliteRepo::new(settings, metrics.clone());
+        let mut settings = settings.clone();
+        // older shards are rarely read, so keep few idle connections.
+        settings.database.min_conn = settings.database.min_conn.min(1);
+        let mut shards = BTreeMap::new();
+        match std::fs::read_dir(&settings.database.data_directory) {
+            Ok(entries) => {
+                for entry in entries.flatten() {
+                    let file = entry.file_name().to_string_lossy().to_string();
+                    if let Some(month) = shard_month(&file) {
+                        shards.insert(month, SqliteRepo::with_file(&settings, metrics.clone(), &file));
+                    }
+                }
+            }
+            Err(e) => warn!("could not list database shards: {:?}", e),
+        }
+        info!("opened {} monthly database shards", shards.len());
+        ShardedRepo {
+            settings,
+            metrics,
+            main,
+            shards: RwLock::new(shards),
+        }
+    }
+
+    /// Shard for a month, created if it does not exist.
+    async fn shard(&self, month: u32) -> Result<SqliteRepo> {
+        if let Some(repo) = self.shards.read().await.get(&month) {
+            return Ok(repo.clone());
+        }
+        let mut shards = self.shards.write().await;
+        if let Some(repo) = shards.get(&month) {
+            return Ok(repo.clone());
+        }
+        let settings = self.settings.clone();
+        let metrics = self.metrics.clone();
+        let file = shard_file(month);
+        info!("creating database shard {}", file);
+        let repo = task::spawn_blocking(move || SqliteRepo::with_file(&settings, metrics, &file)).await?;
+        repo.start().await?;
+        repo.migrate_up().await?;
+        shards.insert(month, repo.clone());
+        Ok(repo)
+    }
+
+    async fn all_shards(&self) -> Vec<SqliteRepo> {
+        self.shards.read().await.values().cloned().collect()
+    }
+
+    /// Shards any of the filters could match, newest first, with the
+    /// time each begins.
+    async fn shards_for(&self, filters: &[ReqFilter]) -> Vec<(u64, SqliteRepo)> {
+        self.shards
+            .read()
+            .await
+            .iter()
+            .rev()
+            .filter(|(m, _)| filters.iter().any(|f| filter_covers(f, **m)))
+            .map(|(m, repo)| (month_start(*m), repo.clone()))
+            .collect()
+    }
+
+    /// Store events in a shard, hiding any that were already deleted.
+    async fn write_to_shard(&self, month: u32, events: &[Event]) -> Result<Vec<u64>> {
+        let shard = self.shard(month).await?;
+        let mut counts = shard.write_events(events).await?;
+        for (e, count) in events.iter().zip(counts.iter_mut()) {
+            if *count > 0
+                && self.main.deletion_recorded(e).await?
+                && shard.hide_events(&e.pubkey, std::slice::from_ref(&e.id)).await? > 0
+            {
+                *count = 0;
+            }
+        }
+        Ok(counts)
+    }
+
+    /// Apply deletions stored in `nostr.db` to every shard.
+    async fn apply_deletions(&self, events: &[Event]) -> Result<()> {
+        let deletions: Vec<&Event> = events.iter().filter(|e| e.kind == 5).collect();
+        if deletions.is_empty() {
+            return Ok(());
+        }
+        for shard in self.all_shards().await {
+            for e in &deletions {
+                let hidden = shard.hide_events(&e.pubkey, &e.tag_values_by_name("e")).await?;
+                if hidden > 0 {
+                    info!("hid {} deleted events in shard for author {:?}", hidden, e.get_author_prefix());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl NostrRepo for ShardedRepo {
+    async fn start(&self) -> Result<()> {
+        self.main.start().await?;
+        for shard in self.all_shards().await {
+            shard.start().await?;
+        }
+        Ok(())
+    }
+
+    async fn migrate_up(&self) -> Result<usize> {
+        let version = self.main.migrate_up().await?;
+        for shard in self.all_shards().await {
+            shard.migrate_up().await?;
+        }
+        Ok(version)
+    }
+
+    async fn write_event(&self, e: &Event) -> Result<u64> {
+        Ok(self.write_events(std::slice::from_ref(e)).await?.iter().sum())
+    }
+
+    /// Persist a batch of events, in one transaction per database
+    async fn write_events(&self, events: &[Event]) -> Result<Vec<u64>> {
+        // positions of the events stored in each database
+        let mut main_idx = vec![];
+        let mut by_month: BTreeMap<u32, Vec<usize>> = BTreeMap::new();
+        for (i, e) in events.iter().enumerate() {
+            match shard_for(e) {
+                Some(month) => by_month.entry(month).or_default().push(i),
+                None => main_idx.push(i),
+            }
+        }
+        let pick = |idx: &[usize]| -> Vec<Event> { idx.iter().map(|i| events[*i].clone()).collect() };
+        let mut counts = vec![0; events.len()];
+        for (month, idx) in by_month {
+            let written = self.write_to_shard(month, &pick(&idx)).await?;
+            idx.iter().zip(written).for_each(|(i, n)| counts[*i] = n);
+        }
+        // deletions are applied after the shards are written, so they
+        // reach events in the same batch.
+        if !main_idx.is_empty() {
+            let main_events = pick(&main_idx);
+            let written = self.main.write_events(&main_events).await?;
+            main_idx.iter().zip(written).for_each(|(i, n)| counts[*i] = n);
+            self.apply_deletions(&main_events).await?;
+        }
+        Ok(counts)
+    }
+
+    async fn query_subscription(
+        &self,
+        sub: Subscription,
+        client_id: String,
+        query_tx: tokio::sync::mpsc::Sender<QueryResult>,
+        mut abandon_query_rx: tokio::sync::oneshot::Receiver<()>,
+    ) -> Result<()> {
+        let shards = self.shards_for(&sub.filters).await;
+        if shards.is_empty() {
+            return self
+                .main
+                .query_subscription(sub, client_id, query_tx, abandon_query_rx)
+                .await;
+        }
+        let main = self.main.clone();
+        tokio::spawn(async move {
+            let start = Instant::now();
+            let mut matches: Vec<(Event, String)> = vec![];
+            let mut seen = HashSet::new();
+            let mut shard_count = 0;
+            // events in nostr.db may be from any time, so it is always
+            // searched; shards are searched until older ones cannot
+            // change the results.
+            let databases = std::iter::once((None, main)).chain(shards.into_iter().map(|(t, s)| (Some(t), s)));
+            for (since, repo) in databases {
+                if abandon_query_rx.try_recv().is_ok() {
+                    debug!("sharded query cancelled by client (cid: {}, sub: {:?})", client_id, sub.id);
+                    return;
+                }
+                let results = match collect_results(&repo, &sub, &client_id).await {
+                    Ok(results) => results,
+                    Err(e) => {
+                        warn!("shard query failed (cid: {}, sub: {:?}): {:?}", client_id, sub.id, e);
+                        continue;
+                    }
+                };
+                for json in results {
+                    let Ok(mut e) = serde_json::from_str::<Event>(&json) else {
+                        continue;
+                    };
+                    e.build_index();
+                    if seen.insert(e.id.clone()) {
+                        matches.push((e, json));
+                    }
+                }
+                if let Some(since) = since {
+                    shard_count += 1;
+                    if limits_met(&sub.filters, &matches, since) {
+                        break;
+                    }
+                }
+            }
+            let results = select_results(&sub.filters, matches);
+            debug!(
+                "sharded query completed in {:?} (cid: {}, sub: {:?}, shards: {}, rows: {})",
+                start.elapsed(),
+                client_id,
+                sub.id,
+                shard_count,
+                results.len()
+            );
+            // any client that doesn't accept results for 2 seconds
+            // gets dropped.
+            let abort_cutoff = Duration::from_secs(2);
+            for event in results.into_iter().chain(std::iter::once("EOSE".to_owned())) {
+                if abandon_query_rx.try_recv().is_ok() {
+                    return;
+                }
+                let res = QueryResult {
+                    sub_id: sub.get_id(),
+                    event,
+                };
+                if tokio::time::timeout(abort_cutoff, query_tx.send(res)).await.is_err() {
+                    info!("aborting sharded query due to slow client (cid: {}, sub: {:?})", client_id, sub.id);
+                    return;
+                }
+            }
+        });
+        Ok(())
+    }
+
+    async fn count_events_by_filter(&self, filters: Vec<ReqFilter>) -> Result<u64> {
+        let mut count = self.main.count_events_by_filter(filters.clone()).await?;
+        for (_, shard) in self.shards_for(&filters).await {
+            count += shard.count_events_by_filter(filters.clone()).await?;
+        }
+        Ok(count)
+    }
+
+    async fn is_event_deleted(&self, id: &str) -> Result<bool> {
+        if self.main.is_event_deleted(id).await? {
+            return Ok(true);
+        }
+        for shard in self.all_shards().await {
+            if shard.is_event_deleted(id).await? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    async fn get_relay_list(&self, pub_key: &str) -> Result<Option<Event>> {
+        self.main.get_relay_list(pub_key).await
+    }
+
+    async fn apply_group_update(&self, update: &GroupUpdate) -> Result<()> {
+        self.main.apply_group_update(update).await
+    }
+
+    async fn get_groups(&self) -> Result<Vec<Group>> {
+        self.main.get_groups().await
+    }
+
+    async fn add_ban(&self, ban: &Ban) -> Result<()> {
+        self.main.add_ban(ban).await
+    }
+
+    async fn get_bans(&self) -> Result<Vec<Ban>> {
+        self.main.get_bans().await
+    }
+
+    async fn get_allowed_pubkeys(&self) -> Result<Vec<String>> {
+        self.main.get_allowed_pubkeys().await
+    }
+
+    async fn set_pubkey_allowed(&self, pubkey: &str, allowed: bool) -> Result<()> {
+        self.main.set_pubkey_allowed(pubkey, allowed).await
+    }
+
+    async fn get_reputation(&self, pubkey: &str) -> Result<ReputationRecord> {
+        self.main.get_reputation(pubkey).await
+    }
+
+    async fn update_reputation(&self, pubkey: &str, change: ReputationChange) -> Result<()> {
+        self.main.update_reputation(pubkey, change).await
+    }
+
+    async fn add_report(&self, report: &Report) -> Result<()> {
+        self.main.add_report(report).await
+    }
+
+    async fn get_reports(&self, event_id: Option<&str>) -> Result<Vec<Report>> {
+        self.main.get_reports(event_id).await
+    }
+
+    async fn hide_event(&self, id: &str) -> Result<bool> {
+        let mut hidden = self.main.hide_event(id).await?;
+        for shard in self.all_shards().await {
+            hidden |= shard.hide_event(id).await?;
+        }
+        Ok(hidden)
+    }
+
+    async fn get_account(&self, pubkey: &str) -> Result<Option<Account>> {
+        self.main.get_account(pubkey).await
+    }
+
+    async fn add_invoice(&self, invoice: &Invoice) -> Result<()> {
+        self.main.add_invoice(invoice).await
+    }
+
+    async fn get_unpaid_invoices(&self) -> Result<Vec<Invoice>> {
+        self.main.get_unpaid_invoices().await
+    }
+
+    async fn invoice_paid(&self, payment_hash: &str) -> Result<bool> {
+        self.main.invoice_paid(payment_hash).await
+    }
+
+    async fn debit_account(&self, pubkey: &str, amount_msat: u64) -> Result<bool> {
+        self.main.debit_account(pubkey, amount_msat).await
+    }
+
+    async fn credit_account(&self, pubkey: &str, amount_msat: u64) -> Result<()> {
+        self.main.credit_account(pubkey, amount_msat).await
+    }
+
+    async fn optimize_db(&self) -> Result<()> {
+        self.main.optimize_db().await?;
+        for shard in self.all_shards().await {
+            shard.optimize_db().await?;
+        }
+        Ok(())
+    }
+
+    async fn compact(&self) -> Result<()> {
+        self.main.compact().await?;
+        for shard in self.all_shards().await {
+            shard.compact().await?;
+        }
+        Ok(())
+    }
+
+    async fn vacuum(&self) -> Result<u64> {
+        let mut reclaimed = self.main.vacuum().await?;
+        for shard in self.all_shards().await {
+            reclaimed += shard.vacuum().await?;
+        }
+        Ok(reclaimed)
+    }
+
+    async fn ping(&self) -> Result<()> {
+        self.main.ping().await?;
+        for shard in self.all_shards().await {
+            shard.ping().await?;
+        }
+        Ok(())
+    }
+
+    async fn check_integrity(&self) -> Result<Vec<String>> {
+        let mut problems = self.main.check_integrity().await?;
+        for shard in self.all_shards().await {
+            problems.extend(shard.check_integrity().await?);
+        }
+        Ok(problems)
+    }
+
+    async fn event_summaries(&self, order: ScanOrder, after: Option<&EventSummary>, limit: usize) -> Result<Vec<EventSummary>> {
+        let mut summaries = self.main.event_summaries(order, after, limit).await?;
+        for shard in self.all_shards().await {
+            summaries.extend(shard.event_summaries(order, after, limit).await?);
+        }
+        summaries.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+        if order == ScanOrder::NewestFirst {
+            summaries.reverse();
+        }
+        summaries.truncate(limit);
+        Ok(summaries)
+    }
+
+    async fn used_bytes(&self) -> Result<u64> {
+        let mut used = self.main.used_bytes().await?;
+        for shard in self.all_shards().await {
+            used += shard.used_bytes().await?;
+        }
+        Ok(used)
+    }
+
+    async fn author_bytes(&self, pubkey: &str) -> Result<u64> {
+        let mut used = self.main.author_bytes(pubkey).await?;
+        for shard in self.all_shards().await {
+            used += shard.author_bytes(pubkey).await?;
+        }
+        Ok(used)
+    }
+
+    async fn delete_events(&self, ids: &[String]) -> Result<u64> {
+        let mut count = self.main.delete_events(ids).await?;
+        for shard in self.all_shards().await {
+            count += shard.delete_events(ids).await?;
+        }
+        Ok(count)
+    }
+
+    async fn create_verification_record(&self, event_id: &str, name: &str) -> Result<()> {
+        self.main.create_verification_record(event_id, name).await
+    }
+
+    async fn update_verification_timestamp(&self, id: u64) -> Result<()> {
+        self.main.update_verification_timestamp(id).await
+    }
+
+    async fn fail_verification(&self, id: u64) -> Result<()> {
+        self.main.fail_verification(id).await
+    }
+
+    async fn delete_verification(&self, id: u64) -> Result<()> {
+        self.main.delete_verification(id).await
+    }
+
+    async fn get_latest_user_verification(&self, pub_key: &str) -> Result<VerificationRecord> {
+        self.main.get_latest_user_verification(pub_key).await
+    }
+
+    async fn get_oldest_user_verification(&self, before: u64) -> Result<VerificationRecord> {
+        self.main.get_oldest_user_verification(before).await
+    }
+
+    async fn put_queued_verification(&self, queued: &QueuedVerification) -> Result<()> {
+        self.main.put_queued_verification(queued).await
+    }
+
+    async fn get_queued_verifications(&self, due: u64, limit: usize) -> Result<Vec<QueuedVerification>> {
+        self.main.get_queued_verifications(due, limit).await
+    }
+
+    async fn remove_queued_verification(&self, pubkey: &str, event_id: &str) -> Result<()> {
+        self.main.remove_queued_verification(pubkey, event_id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shard_months() {
+        // 2024-02-29T12:00:00Z
+        let month = month_of(1_709_208_000);
+        assert_eq!(shard_file(month), "nostr-2024-02.db");
+        assert_eq!(shard_month("nostr-2024-02.db"), Some(month));
+        assert_eq!(month_start(month), 1_706_745_600);
+        assert_eq!(month_start(month + 1), 1_709_251_200);
+        assert_eq!(shard_file(month_of(0)), "nostr-1970-01.db");
+        assert_eq!(shard_month("nostr.db"), None);
+        assert_eq!(shard_month("nostr-2024-13.db"), None);
+        assert_eq!(shard_month("nostr-2024-02.db-wal"), None);
+    }
+
+    #[test]
+    fn filters_cover_months() {
+        let month = month_of(1_709_208_000);
+        let mut f: ReqFilter = serde_json::from_str(r#"{"since":1709251200}"#).unwrap();
+        assert!(!filter_covers(&f, month));
+        assert!(filter_covers(&f, month + 1));
+        f.since = None;
+        f.until = Some(1_706_745_599);
+        assert!(!filter_covers(&f, month));
+        assert!(filter_covers(&f, month - 1));
+    }
+
+    #[test]
+    fn results_respect_limits() {
+        let events: Vec<(Event, String)> = (0..4)
+            .map(|i| {
+                let mut e = Event::simple_event();
+                e.id = format!("{i}");
+                e.created_at = 100 + i;
+                e.build_index();
+                (e, format!("{i}"))
+            })
+            .collect();
+        let filter: ReqFilter = serde_json::from_str(r#"{"limit":2}"#).unwrap();
+        assert!(limits_met(std::slice::from_ref(&filter), &events, 102));
+        assert!(!limits_met(std::slice::from_ref(&filter), &events, 103));
+        assert_eq!(select_results(&[filter], events), vec!["3", "2"]);
+    }
+}
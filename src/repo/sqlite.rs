@@ -1,13 +1,19 @@
 //! Event persistence and querying
 //use crate::config::SETTINGS;
+use crate::bans::{Ban, BanTarget};
 use crate::config::Settings;
 use crate::error::Result;
-use crate::event::{single_char_tagname, Event};
+use crate::event::{searchable_tagname, Event};
+use crate::groups::{Group, GroupRole, GroupUpdate};
 use crate::hexrange::hex_range;
 use crate::hexrange::HexSearch;
 use crate::repo::sqlite_migration::{STARTUP_SQL,upgrade_db};
-use crate::utils::{is_hex, is_lower_hex};
-use crate::nip05::{Nip05Name, VerificationRecord};
+use crate::utils::{is_hex, is_lower_hex, unix_time};
+use crate::nip05::{Nip05Name, QueuedVerification, VerificationRecord};
+use crate::nip65::KIND_RELAY_LIST;
+use crate::payment::{Account, Invoice};
+use crate::reports::Report;
+use crate::reputation::{ReputationChange, ReputationRecord};
 use crate::subscription::{ReqFilter, Subscription};
 use crate::server::NostrMetrics;
 use hex;
@@ -15,10 +21,11 @@ use r2d2;
 use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::params;
 use rusqlite::types::ToSql;
-use rusqlite::OpenFlags;
+use rusqlite::{OpenFlags, OptionalExtension, Transaction};
 use tokio::sync::{Mutex, MutexGuard, Semaphore};
 use std::fmt::Write as _;
 use std::path::Path;
+use std::collections::HashSet;
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
@@ -28,12 +35,17 @@ use tracing::{debug, info, trace, warn};
 use async_trait::async_trait;
 use crate::db::QueryResult;
 
-use crate::repo::{now_jitter, NostrRepo};
+use crate::repo::planner::{self, Access};
+use crate::repo::{now_jitter, EventId, EventSummary, NostrRepo, ScanOrder};
 
 pub type SqlitePool = r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>;
 pub type PooledConnection = r2d2::PooledConnection<r2d2_sqlite::SqliteConnectionManager>;
 pub const DB_FILE: &str = "nostr.db";
 
+/// Free pages returned to the file system by each step of an
+/// incremental vacuum.
+const VACUUM_STEP_PAGES: i64 = 1000;
+
 #[derive(Clone)]
 pub struct SqliteRepo {
     /// Metrics
@@ -50,30 +62,41 @@ pub struct SqliteRepo {
     write_in_progress: Arc<Mutex<u64>>,
     /// Semaphore for readers to acquire blocking threads
     reader_threads_ready: Arc<Semaphore>,
+    /// Index tags with multi-letter names
+    multi_letter_tags: bool,
 }
 
 impl SqliteRepo {
     // build all the pools needed
     #[must_use] pub fn new(settings: &Settings, metrics: NostrMetrics) -> SqliteRepo {
-        let write_pool = build_pool(
+        SqliteRepo::with_file(settings, metrics, DB_FILE)
+    }
+
+    /// Build the pools for a database file in the data directory,
+    /// other than the default `nostr.db`.
+    #[must_use] pub fn with_file(settings: &Settings, metrics: NostrMetrics, file: &str) -> SqliteRepo {
+        let write_pool = build_pool_file(
             "writer",
             settings,
+            file,
             OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
             1,
             2,
             false,
         );
-        let maint_pool = build_pool(
+        let maint_pool = build_pool_file(
             "maintenance",
             settings,
+            file,
             OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
             1,
             2,
             true,
         );
-        let read_pool = build_pool(
+        let read_pool = build_pool_file(
             "reader",
             settings,
+            file,
             OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
             settings.database.min_conn,
             settings.database.max_conn,
@@ -90,6 +113,7 @@ impl SqliteRepo {
         let max_conn = settings.database.max_conn as usize;
         let reader_threads_ready = Arc::new(Semaphore::new(max_conn));
         SqliteRepo {
+            multi_letter_tags: settings.options.multi_letter_tags,
             metrics,
             read_pool,
             write_pool,
@@ -100,13 +124,70 @@ impl SqliteRepo {
         }
     }
 
+    /// Hide events from an author, for a deletion (NIP-09) that is
+    /// stored in another database.
+    pub async fn hide_events(&self, author: &str, ids: &[String]) -> Result<u64> {
+        let author = hex::decode(author)?;
+        let ids: Vec<Vec<u8>> = ids
+            .iter()
+            .filter(|x| is_hex(x) && x.len() == 64)
+            .filter_map(|x| hex::decode(x).ok())
+            .collect();
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        let _write_guard = self.write_in_progress.lock().await;
+        let conn = self.write_pool.get()?;
+        task::spawn_blocking(move || {
+            let query = format!(
+                "UPDATE event SET hidden=TRUE WHERE kind!=5 AND author=? AND event_hash IN ({})",
+                repeat_vars(ids.len())
+            );
+            let mut params: Vec<Box<dyn ToSql>> = vec![Box::new(author)];
+            ids.into_iter().for_each(|x| params.push(Box::new(x)));
+            let mut stmt = conn.prepare(&query)?;
+            Ok(stmt.execute(rusqlite::params_from_iter(params))? as u64)
+        })
+        .await?
+    }
+
+    /// Check if a deletion (NIP-09) from its author refers to an event.
+    pub async fn deletion_recorded(&self, e: &Event) -> Result<bool> {
+        let conn = self.read_pool.get()?;
+        let pubkey_blob = hex::decode(&e.pubkey).ok();
+        let id_blob = hex::decode(&e.id).ok();
+        task::spawn_blocking(move || {
+            let mut stmt = conn.prepare_cached(
+                "SELECT e.id FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.author=? AND t.name='e' AND e.kind=5 AND t.value_hex=? LIMIT 1;")?;
+            Ok(stmt.exists(params![pubkey_blob, id_blob])?)
+        })
+        .await?
+    }
+
     /// Persist an event to the database, returning rows added.
-    pub fn persist_event(conn: &mut PooledConnection, e: &Event) -> Result<u64> {
+    pub fn persist_event(conn: &mut PooledConnection, e: &Event, multi_letter_tags: bool) -> Result<u64> {
+        Ok(SqliteRepo::persist_events(conn, std::slice::from_ref(e), multi_letter_tags)?.iter().sum())
+    }
+
+    /// Persist a batch of events in a single transaction, returning
+    /// rows added for each.  Tags with multi-letter names are indexed
+    /// if `multi_letter_tags` is set.
+    pub fn persist_events(conn: &mut PooledConnection, events: &[Event], multi_letter_tags: bool) -> Result<Vec<u64>> {
         // enable auto vacuum
         conn.execute_batch("pragma auto_vacuum = FULL")?;
 
         // start transaction
         let tx = conn.transaction()?;
+        let mut counts = Vec::with_capacity(events.len());
+        for e in events {
+            counts.push(SqliteRepo::persist_in_tx(&tx, e, multi_letter_tags)?);
+        }
+        tx.commit()?;
+        Ok(counts)
+    }
+
+    /// Persist an event within a transaction, returning rows added.
+    fn persist_in_tx(tx: &Transaction, e: &Event, multi_letter_tags: bool) -> Result<u64> {
         // get relevant fields from event and convert to blobs.
         let id_blob = hex::decode(&e.id).ok();
         let pubkey_blob: Option<Vec<u8>> = hex::decode(&e.pubkey).ok();
@@ -147,7 +228,6 @@ impl SqliteRepo {
         if ins_count == 0 {
             // if the event was a duplicate, no need to insert event or
             // pubkey references.
-            tx.rollback().ok();
             return Ok(ins_count);
         }
         // remember primary key of the event most recently inserted.
@@ -158,27 +238,33 @@ impl SqliteRepo {
             if tag.len() >= 2 {
                 let tagname = &tag[0];
                 let tagval = &tag[1];
-                // only single-char tags are searchable
-                let tagchar_opt = single_char_tagname(tagname);
-                match &tagchar_opt {
-                    Some(_) => {
-                        // if tagvalue is lowercase hex;
-                        if is_lower_hex(tagval) && (tagval.len() % 2 == 0) {
-                            tx.execute(
-                                "INSERT OR IGNORE INTO tag (event_id, name, value_hex) VALUES (?1, ?2, ?3)",
-                                params![ev_id, &tagname, hex::decode(tagval).ok()],
-                            )?;
-                        } else {
-                            tx.execute(
-                                "INSERT OR IGNORE INTO tag (event_id, name, value) VALUES (?1, ?2, ?3)",
-                                params![ev_id, &tagname, &tagval],
-                            )?;
-                        }
+                // only single-char tags are searchable, unless
+                // multi-letter tags are indexed
+                if searchable_tagname(tagname, multi_letter_tags) {
+                    // if tagvalue is lowercase hex;
+                    if is_lower_hex(tagval) && (tagval.len() % 2 == 0) {
+                        tx.execute(
+                            "INSERT OR IGNORE INTO tag (event_id, name, value_hex) VALUES (?1, ?2, ?3)",
+                            params![ev_id, &tagname, hex::decode(tagval).ok()],
+                        )?;
+                    } else {
+                        tx.execute(
+                            "INSERT OR IGNORE INTO tag (event_id, name, value) VALUES (?1, ?2, ?3)",
+                            params![ev_id, &tagname, &tagval],
+                        )?;
                     }
-                    None => {}
                 }
             }
         }
+        // parameterized replaceable events without a `d` value are
+        // keyed by the empty string; record that so that newer
+        // versions can locate (and replace) this one.
+        if e.distinct_param().as_deref() == Some("") && !e.tags.iter().any(|t| t.len() >= 2 && t[0] == "d" && t[1].is_empty()) {
+            tx.execute(
+                "INSERT OR IGNORE INTO tag (event_id, name, value_hex) VALUES (?1, 'd', ?2)",
+                params![ev_id, Vec::<u8>::new()],
+            )?;
+        }
         // if this event is replaceable update, remove other replaceable
         // event with the same kind from the same author that was issued
         // earlier than this.
@@ -186,7 +272,7 @@ impl SqliteRepo {
             let author = hex::decode(&e.pubkey).ok();
             // this is a backwards check - hide any events that were older.
             let update_count = tx.execute(
-                "DELETE FROM event WHERE kind=? and author=? and id NOT IN (SELECT id FROM event INDEXED BY author_kind_index WHERE kind=? AND author=? ORDER BY created_at DESC LIMIT 1)",
+                "DELETE FROM event WHERE kind=? and author=? and id NOT IN (SELECT id FROM event INDEXED BY author_kind_created_at_index WHERE kind=? AND author=? ORDER BY created_at DESC LIMIT 1)",
                 params![e.kind, author, e.kind, author],
             )?;
             if update_count > 0 {
@@ -262,9 +348,72 @@ impl SqliteRepo {
                 ins_count = 0;
             }
         }
-        tx.commit()?;
         Ok(ins_count)
     }
+
+    /// Run each filter of a subscription as its own query, at the same
+    /// time, sending their results as they arrive.  The number of
+    /// queries running across all clients is still bounded by the
+    /// reader semaphore.  Events matched by several filters are only
+    /// sent once, and EOSE is sent once every query has completed.
+    fn query_filters_concurrently(
+        &self,
+        sub: Subscription,
+        client_id: String,
+        query_tx: tokio::sync::mpsc::Sender<QueryResult>,
+        mut abandon_query_rx: tokio::sync::oneshot::Receiver<()>,
+    ) {
+        let filter_count = sub.filters.len();
+        let (merged_tx, mut merged_rx) = tokio::sync::mpsc::channel::<QueryResult>(query_tx.max_capacity());
+        let mut abandon_txs = vec![];
+        for f in &sub.filters {
+            let single = Subscription {
+                id: sub.id.clone(),
+                filters: vec![f.clone()],
+            };
+            let (abandon_tx, abandon_rx) = tokio::sync::oneshot::channel();
+            abandon_txs.push(abandon_tx);
+            let repo = self.clone();
+            let client_id = client_id.clone();
+            let merged_tx = merged_tx.clone();
+            tokio::spawn(async move {
+                repo.query_subscription(single, client_id, merged_tx, abandon_rx).await.ok();
+            });
+        }
+        drop(merged_tx);
+        tokio::spawn(async move {
+            // any client that doesn't accept results for 2 seconds
+            // gets dropped.
+            let abort_cutoff = Duration::from_secs(2);
+            let mut seen = HashSet::new();
+            let mut complete = 0;
+            while let Some(res) = merged_rx.recv().await {
+                if abandon_query_rx.try_recv().is_ok() {
+                    abandon_txs.into_iter().for_each(|tx| {
+                        tx.send(()).ok();
+                    });
+                    return;
+                }
+                if res.event == "EOSE" {
+                    complete += 1;
+                    if complete < filter_count {
+                        continue;
+                    }
+                } else if let Ok(e) = serde_json::from_str::<EventId>(&res.event) {
+                    if !seen.insert(e.id) {
+                        continue;
+                    }
+                }
+                if tokio::time::timeout(abort_cutoff, query_tx.send(res)).await.is_err() {
+                    info!(cid = %client_id, sub_id = %sub.id, "aborting database query due to slow client");
+                    abandon_txs.into_iter().for_each(|tx| {
+                        tx.send(()).ok();
+                    });
+                    return;
+                }
+            }
+        });
+    }
 }
 
 #[async_trait]
@@ -289,9 +438,27 @@ impl NostrRepo for SqliteRepo {
         //let mut conn = self.write_pool.get()?;
         let pool = self.write_pool.clone();
         let e = e.clone();
+        let multi_letter_tags = self.multi_letter_tags;
         let event_count = task::spawn_blocking(move || {
             let mut conn = pool.get()?;
-            SqliteRepo::persist_event(&mut conn, &e)
+            SqliteRepo::persist_event(&mut conn, &e, multi_letter_tags)
+        }).await?;
+        self.metrics
+            .write_events
+            .observe(start.elapsed().as_secs_f64());
+        event_count
+    }
+
+    /// Persist a batch of events in one transaction
+    async fn write_events(&self, events: &[Event]) -> Result<Vec<u64>> {
+        let start = Instant::now();
+        let _write_guard = self.write_in_progress.lock().await;
+        let pool = self.write_pool.clone();
+        let events = events.to_vec();
+        let multi_letter_tags = self.multi_letter_tags;
+        let event_count = task::spawn_blocking(move || {
+            let mut conn = pool.get()?;
+            SqliteRepo::persist_events(&mut conn, &events, multi_letter_tags)
         }).await?;
         self.metrics
             .write_events
@@ -312,6 +479,10 @@ impl NostrRepo for SqliteRepo {
         query_tx: tokio::sync::mpsc::Sender<QueryResult>,
         mut abandon_query_rx: tokio::sync::oneshot::Receiver<()>,
     ) -> Result<()> {
+        if sub.filters.len() > 1 {
+            self.query_filters_concurrently(sub, client_id, query_tx, abandon_query_rx);
+            return Ok(());
+        }
         let pre_spawn_start = Instant::now();
         // if we let every request spawn a thread, we'll exhaust the
         // thread pool waiting for queries to finish under high load.
@@ -329,8 +500,10 @@ impl NostrRepo for SqliteRepo {
             // if the queue time was very long (>5 seconds), spare the DB and abort.
             if db_queue_time > Duration::from_secs(5) {
                 info!(
-                    "shedding DB query load queued for {:?} (cid: {}, sub: {:?})",
-                    db_queue_time, client_id, sub.id
+                    cid = %client_id,
+                    sub_id = %sub.id,
+                    queued_ms = db_queue_time.as_millis() as u64,
+                    "shedding DB query load"
                 );
                 metrics.query_aborts.with_label_values(&["loadshed"]).inc();
                 return Ok(());
@@ -338,13 +511,15 @@ impl NostrRepo for SqliteRepo {
             // otherwise, report queuing time if it is slow
             else if db_queue_time > Duration::from_secs(1) {
                 debug!(
-                    "(slow) DB query queued for {:?} (cid: {}, sub: {:?})",
-                    db_queue_time, client_id, sub.id
+                    cid = %client_id,
+                    sub_id = %sub.id,
+                    queued_ms = db_queue_time.as_millis() as u64,
+                    "(slow) DB query queued"
                 );
             }
             // check before getting a DB connection if the client still wants the results
             if abandon_query_rx.try_recv().is_ok() {
-                debug!("query cancelled by client (before execution) (cid: {}, sub: {:?})", client_id, sub.id);
+                debug!(cid = %client_id, sub_id = %sub.id, "query cancelled by client (before execution)");
                 return Ok(());
             }
 
@@ -362,6 +537,14 @@ impl NostrRepo for SqliteRepo {
                 for filter in sub.filters.iter() {
                     let filter_start = Instant::now();
                     filter_count += 1;
+                    let plan = planner::plan(filter);
+                    if plan.access == Access::Nothing {
+                        continue;
+                    }
+                    if plan.degraded(filter) {
+                        debug!(cid = %client_id, sub_id = %sub.id, limit = ?plan.limit, "limiting unbounded filter");
+                    }
+                    let filter = &plan.limited(filter);
                     let sql_gen_elapsed = start.elapsed();
                     let (q, p, idx) = query_from_filter(filter);
                     if sql_gen_elapsed > Duration::from_millis(10) {
@@ -384,15 +567,22 @@ impl NostrRepo for SqliteRepo {
                         slow_first_event = first_event_elapsed >= slow_cutoff;
                         if first_result {
                             debug!(
-                                "first result in {:?} (cid: {}, sub: {:?}, filter: {}) [used index: {:?}]",
-                                first_event_elapsed, client_id, sub.id, filter_count, idx
+                                cid = %client_id,
+                                sub_id = %sub.id,
+                                filter_index = filter_count,
+                                duration_ms = first_event_elapsed.as_millis() as u64,
+                                index = ?idx,
+                                "first result"
                             );
                             // logging for slow queries; show filter and SQL.
                             // to reduce logging; only show 1/16th of clients (leading 0)
                             if slow_first_event && client_id.starts_with('0') {
                                 debug!(
-                                    "filter first result in {:?} (slow): {} (cid: {}, sub: {:?})",
-                                    first_event_elapsed, serde_json::to_string(&filter)?, client_id, sub.id
+                                    cid = %client_id,
+                                    sub_id = %sub.id,
+                                    filter = %serde_json::to_string(&filter)?,
+                                    duration_ms = first_event_elapsed.as_millis() as u64,
+                                    "filter first result (slow)"
                                 );
                             }
                             first_result = false;
@@ -402,7 +592,7 @@ impl NostrRepo for SqliteRepo {
                             {
                                 if self.checkpoint_in_progress.try_lock().is_err() {
                                     // lock was held, abort this query
-                                    debug!("query aborted due to checkpoint (cid: {}, sub: {:?})", client_id, sub.id);
+                                    debug!(cid = %client_id, sub_id = %sub.id, "query aborted due to checkpoint");
                                     metrics.query_aborts.with_label_values(&["checkpoint"]).inc();
                                     return Ok(());
                                 }
@@ -411,7 +601,7 @@ impl NostrRepo for SqliteRepo {
 
                         // check if this is still active; every 100 rows
                         if row_count % 100 == 0 && abandon_query_rx.try_recv().is_ok() {
-                            debug!("query cancelled by client (cid: {}, sub: {:?})", client_id, sub.id);
+                            debug!(cid = %client_id, sub_id = %sub.id, "query cancelled by client");
                             return Ok(());
                         }
                         row_count += 1;
@@ -425,8 +615,7 @@ impl NostrRepo for SqliteRepo {
                             trace!("db reader thread is stalled");
                             if last_successful_send + abort_cutoff < Instant::now() {
                                 // the queue has been full for too long, abort
-                                info!("aborting database query due to slow client (cid: {}, sub: {:?})",
-                                      client_id, sub.id);
+                                info!(cid = %client_id, sub_id = %sub.id, "aborting database query due to slow client");
                                 metrics.query_aborts.with_label_values(&["slowclient"]).inc();
                                 let ok: Result<()> = Ok(());
                                 return ok;
@@ -434,12 +623,12 @@ impl NostrRepo for SqliteRepo {
                             // check if a checkpoint is trying to run, and abort
                             if self.checkpoint_in_progress.try_lock().is_err() {
                                 // lock was held, abort this query
-                                debug!("query aborted due to checkpoint (cid: {}, sub: {:?})", client_id, sub.id);
+                                debug!(cid = %client_id, sub_id = %sub.id, "query aborted due to checkpoint");
                                 metrics.query_aborts.with_label_values(&["checkpoint"]).inc();
                                 return Ok(());
                             }
                             // give the queue a chance to clear before trying again
-                            debug!("query thread sleeping due to full query_tx (cid: {}, sub: {:?})", client_id, sub.id);
+                            debug!(cid = %client_id, sub_id = %sub.id, "query thread sleeping due to full query_tx");
                             thread::sleep(Duration::from_millis(500));
                         }
                         // TODO: we could use try_send, but we'd have to juggle
@@ -459,8 +648,11 @@ impl NostrRepo for SqliteRepo {
                     // if the filter took too much db_time, print out the JSON.
                     if filter_start.elapsed() > slow_cutoff && client_id.starts_with('0') {
                         debug!(
-                            "query filter req (slow): {} (cid: {}, sub: {:?}, filter: {})",
-                            serde_json::to_string(&filter)?, client_id, sub.id, filter_count
+                            cid = %client_id,
+                            sub_id = %sub.id,
+                            filter = %serde_json::to_string(&filter)?,
+                            filter_index = filter_count,
+                            "query filter req (slow)"
                         );
                     }
 
@@ -470,12 +662,12 @@ impl NostrRepo for SqliteRepo {
             }
             drop(sem); // new query can begin
             debug!(
-                "query completed in {:?} (cid: {}, sub: {:?}, db_time: {:?}, rows: {})",
-                pre_spawn_start.elapsed(),
-                client_id,
-                sub.id,
-                start.elapsed(),
-                row_count
+                cid = %client_id,
+                sub_id = %sub.id,
+                duration_ms = pre_spawn_start.elapsed().as_millis() as u64,
+                db_ms = start.elapsed().as_millis() as u64,
+                rows = row_count,
+                "query completed"
             );
             query_tx
                 .blocking_send(QueryResult {
@@ -492,6 +684,415 @@ impl NostrRepo for SqliteRepo {
         Ok(())
     }
 
+    /// Count the distinct events matching any of the given filters.
+    async fn count_events_by_filter(&self, filters: Vec<ReqFilter>) -> Result<u64> {
+        if filters.is_empty() {
+            return Ok(0);
+        }
+        let start = Instant::now();
+        let pool = self.read_pool.clone();
+        let metrics = self.metrics.clone();
+        task::spawn_blocking(move || {
+            let (q, p, idxs) = query_from_filters(&filters);
+            let count_query = format!("SELECT count(*) FROM ({q})");
+            let conn = pool.get()?;
+            let mut stmt = conn.prepare_cached(&count_query)?;
+            let count: u64 = stmt.query_row(rusqlite::params_from_iter(p), |row| row.get(0))?;
+            debug!(
+                "count query completed in {:?} (count: {}, used indexes: {:?})",
+                start.elapsed(),
+                count,
+                idxs
+            );
+            metrics.query_db.observe(start.elapsed().as_secs_f64());
+            Ok(count)
+        })
+        .await?
+    }
+
+    /// Check if an event has been deleted by its author (NIP-09).
+    async fn is_event_deleted(&self, id: &str) -> Result<bool> {
+        let conn = self.read_pool.get()?;
+        let id_blob = hex::decode(id).ok();
+        tokio::task::spawn_blocking(move || {
+            // deleted events are retained, but hidden; other hidden
+            // events lack a deletion from their author.
+            let mut stmt = conn.prepare_cached(
+                "SELECT d.id FROM event e INDEXED BY event_hash_index JOIN event d ON d.author=e.author JOIN tag t ON d.id=t.event_id \
+                 WHERE e.event_hash=?1 AND e.hidden=TRUE AND d.kind=5 AND t.name='e' AND t.value_hex=?1 LIMIT 1;")?;
+            Ok(stmt.exists(params![id_blob])?)
+        }).await?
+    }
+
+    /// Get the latest relay list (NIP-65) published by a pubkey
+    async fn get_relay_list(&self, pub_key: &str) -> Result<Option<Event>> {
+        let conn = self.read_pool.get()?;
+        let pub_key = hex::decode(pub_key).ok();
+        tokio::task::spawn_blocking(move || {
+            // relay lists are replaceable, so there is at most one per author.
+            let mut stmt = conn.prepare_cached(
+                "SELECT e.content FROM event e INDEXED BY author_kind_created_at_index WHERE e.author=? AND e.kind=? AND e.hidden!=TRUE ORDER BY e.created_at DESC LIMIT 1;")?;
+            let mut rows = stmt.query(params![pub_key, KIND_RELAY_LIST])?;
+            match rows.next()? {
+                Some(row) => {
+                    let content: String = row.get(0)?;
+                    Ok(Some(serde_json::from_str(&content)?))
+                }
+                None => Ok(None),
+            }
+        }).await?
+    }
+
+    /// Persist a change to group state (NIP-29)
+    async fn apply_group_update(&self, update: &GroupUpdate) -> Result<()> {
+        let mut conn = self.write_pool.get()?;
+        let update = update.clone();
+        tokio::task::spawn_blocking(move || {
+            let tx = conn.transaction()?;
+            match &update {
+                GroupUpdate::Create { group, admin } => {
+                    tx.execute(
+                        "INSERT OR IGNORE INTO relay_group (id, created_at) VALUES (?, strftime('%s','now'));",
+                        params![group])?;
+                    tx.execute(
+                        "INSERT OR REPLACE INTO group_member (group_id, pubkey, role) VALUES (?, ?, ?);",
+                        params![group, hex::decode(admin).ok(), GroupRole::Admin.as_str()])?;
+                }
+                GroupUpdate::Delete { group } => {
+                    tx.execute("DELETE FROM relay_group WHERE id=?;", params![group])?;
+                }
+                GroupUpdate::SetMember { group, pubkey, role: Some(role) } => {
+                    tx.execute(
+                        "INSERT OR REPLACE INTO group_member (group_id, pubkey, role) VALUES (?, ?, ?);",
+                        params![group, hex::decode(pubkey).ok(), role.as_str()])?;
+                }
+                GroupUpdate::SetMember { group, pubkey, role: None } => {
+                    tx.execute(
+                        "DELETE FROM group_member WHERE group_id=? AND pubkey=?;",
+                        params![group, hex::decode(pubkey).ok()])?;
+                }
+            }
+            tx.commit()?;
+            debug!("applied group update: {:?}", update);
+            let ok: Result<()> = Ok(());
+            ok
+        }).await?
+    }
+
+    /// Get all groups and their members
+    async fn get_groups(&self) -> Result<Vec<Group>> {
+        let conn = self.read_pool.get()?;
+        tokio::task::spawn_blocking(move || {
+            let mut groups: Vec<Group> = vec![];
+            let mut stmt = conn.prepare("SELECT g.id, m.pubkey, m.role FROM relay_group g LEFT JOIN group_member m ON g.id=m.group_id ORDER BY g.id;")?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let id: String = row.get(0)?;
+                if groups.last().map(|g| &g.id) != Some(&id) {
+                    groups.push(Group { id, ..Default::default() });
+                }
+                let pubkey: Option<Vec<u8>> = row.get(1)?;
+                let role: Option<String> = row.get(2)?;
+                if let (Some(g), Some(pk), Some(r)) = (groups.last_mut(), pubkey, role.as_deref().and_then(GroupRole::from_name)) {
+                    g.members.insert(hex::encode(pk), r);
+                }
+            }
+            Ok(groups)
+        }).await?
+    }
+
+    /// Store a ban, and remove expired ones
+    async fn add_ban(&self, ban: &Ban) -> Result<()> {
+        let conn = self.write_pool.get()?;
+        let ban = ban.clone();
+        tokio::task::spawn_blocking(move || {
+            conn.execute(
+                "DELETE FROM ban WHERE expires_at <= ?;",
+                params![unix_time()])?;
+            conn.execute(
+                "INSERT OR REPLACE INTO ban (target, value, expires_at, reason) VALUES (?, ?, ?, ?);",
+                params![ban.target.as_str(), ban.value, ban.expires_at, ban.reason])?;
+            let ok: Result<()> = Ok(());
+            ok
+        }).await?
+    }
+
+    /// Get all bans that have not expired
+    async fn get_bans(&self) -> Result<Vec<Ban>> {
+        let conn = self.read_pool.get()?;
+        tokio::task::spawn_blocking(move || {
+            let mut stmt = conn.prepare(
+                "SELECT target, value, expires_at, reason FROM ban WHERE expires_at IS NULL OR expires_at > ?;")?;
+            let mut rows = stmt.query(params![unix_time()])?;
+            let mut bans = vec![];
+            while let Some(row) = rows.next()? {
+                let target: String = row.get(0)?;
+                if let Some(target) = BanTarget::from_name(&target) {
+                    bans.push(Ban {
+                        target,
+                        value: row.get(1)?,
+                        expires_at: row.get(2)?,
+                        reason: row.get(3)?,
+                    });
+                }
+            }
+            Ok(bans)
+        }).await?
+    }
+
+    /// Get the pubkeys stored in the whitelist
+    async fn get_allowed_pubkeys(&self) -> Result<Vec<String>> {
+        let conn = self.read_pool.get()?;
+        tokio::task::spawn_blocking(move || {
+            let mut stmt = conn.prepare("SELECT pubkey FROM allowed_pubkey;")?;
+            let mut rows = stmt.query([])?;
+            let mut pubkeys = vec![];
+            while let Some(row) = rows.next()? {
+                let pubkey: Vec<u8> = row.get(0)?;
+                pubkeys.push(hex::encode(pubkey));
+            }
+            Ok(pubkeys)
+        }).await?
+    }
+
+    /// Add a pubkey to the stored whitelist, or remove it
+    async fn set_pubkey_allowed(&self, pubkey: &str, allowed: bool) -> Result<()> {
+        let conn = self.write_pool.get()?;
+        let pubkey = hex::decode(pubkey)?;
+        tokio::task::spawn_blocking(move || {
+            if allowed {
+                conn.execute(
+                    "INSERT OR IGNORE INTO allowed_pubkey (pubkey, created_at) VALUES (?, ?);",
+                    params![pubkey, unix_time()])?;
+            } else {
+                conn.execute("DELETE FROM allowed_pubkey WHERE pubkey = ?;", params![pubkey])?;
+            }
+            let ok: Result<()> = Ok(());
+            ok
+        }).await?
+    }
+
+    /// Get the stored reputation of a pubkey
+    async fn get_reputation(&self, pubkey: &str) -> Result<ReputationRecord> {
+        let conn = self.read_pool.get()?;
+        let pubkey_blob = hex::decode(pubkey)?;
+        tokio::task::spawn_blocking(move || {
+            let record = conn.query_row(
+                "SELECT first_seen, reports, spam_hits FROM reputation WHERE pubkey=?;",
+                params![pubkey_blob],
+                |r| Ok(ReputationRecord {
+                    first_seen: r.get(0)?,
+                    reports: r.get(1)?,
+                    spam_hits: r.get(2)?,
+                })).optional()?;
+            Ok(record.unwrap_or_default())
+        }).await?
+    }
+
+    /// Update the stored reputation of a pubkey
+    async fn update_reputation(&self, pubkey: &str, change: ReputationChange) -> Result<()> {
+        let conn = self.write_pool.get()?;
+        let pubkey_blob = hex::decode(pubkey)?;
+        tokio::task::spawn_blocking(move || {
+            conn.execute(
+                "INSERT OR IGNORE INTO reputation (pubkey) VALUES (?);",
+                params![pubkey_blob])?;
+            match change {
+                ReputationChange::Seen(t) => conn.execute(
+                    "UPDATE reputation SET first_seen=? WHERE pubkey=? AND first_seen IS NULL;",
+                    params![t, pubkey_blob])?,
+                ReputationChange::Reported(n) => conn.execute(
+                    "UPDATE reputation SET reports=? WHERE pubkey=?;",
+                    params![n, pubkey_blob])?,
+                ReputationChange::Spam => conn.execute(
+                    "UPDATE reputation SET spam_hits=spam_hits+1 WHERE pubkey=?;",
+                    params![pubkey_blob])?,
+            };
+            let ok: Result<()> = Ok(());
+            ok
+        }).await?
+    }
+
+    /// Store a report, replacing any earlier one for the same target
+    async fn add_report(&self, report: &Report) -> Result<()> {
+        let conn = self.write_pool.get()?;
+        let reporter = hex::decode(&report.reporter)?;
+        let pubkey = hex::decode(&report.pubkey)?;
+        let event_hash = match &report.event_id {
+            Some(id) => hex::decode(id)?,
+            None => vec![],
+        };
+        let report = report.clone();
+        tokio::task::spawn_blocking(move || {
+            conn.execute(
+                "INSERT OR REPLACE INTO report (reporter, pubkey, event_hash, report_type, created_at) VALUES (?, ?, ?, ?, ?);",
+                params![reporter, pubkey, event_hash, report.report_type, report.created_at])?;
+            let ok: Result<()> = Ok(());
+            ok
+        }).await?
+    }
+
+    /// Get all reports, or those of one event
+    async fn get_reports(&self, event_id: Option<&str>) -> Result<Vec<Report>> {
+        let conn = self.read_pool.get()?;
+        let event_hash = event_id.map(hex::decode).transpose()?;
+        tokio::task::spawn_blocking(move || {
+            let sql = "SELECT reporter, pubkey, event_hash, report_type, created_at FROM report";
+            let mut stmt = match event_hash {
+                Some(_) => conn.prepare(&format!("{sql} WHERE event_hash=?;"))?,
+                None => conn.prepare(&format!("{sql};"))?,
+            };
+            let mut rows = match &event_hash {
+                Some(h) => stmt.query(params![h])?,
+                None => stmt.query([])?,
+            };
+            let mut reports = vec![];
+            while let Some(row) = rows.next()? {
+                let event_hash: Vec<u8> = row.get(2)?;
+                reports.push(Report {
+                    reporter: hex::encode(row.get::<_, Vec<u8>>(0)?),
+                    pubkey: hex::encode(row.get::<_, Vec<u8>>(1)?),
+                    event_id: (!event_hash.is_empty()).then(|| hex::encode(event_hash)),
+                    report_type: row.get(3)?,
+                    created_at: row.get(4)?,
+                });
+            }
+            Ok(reports)
+        }).await?
+    }
+
+    /// Get the distinct pubkeys that have reported a pubkey
+    async fn get_reporters(&self, pubkey: &str) -> Result<Vec<String>> {
+        let conn = self.read_pool.get()?;
+        let pubkey_blob = hex::decode(pubkey)?;
+        tokio::task::spawn_blocking(move || {
+            let mut stmt = conn.prepare("SELECT DISTINCT reporter FROM report WHERE pubkey=?;")?;
+            let reporters = stmt
+                .query_map(params![pubkey_blob], |r| r.get::<_, Vec<u8>>(0))?
+                .map(|r| r.map(hex::encode))
+                .collect::<rusqlite::Result<Vec<String>>>()?;
+            Ok(reporters)
+        }).await?
+    }
+
+    /// Hide an event from queries
+    async fn hide_event(&self, id: &str) -> Result<bool> {
+        let id = hex::decode(id)?;
+        let _write_guard = self.write_in_progress.lock().await;
+        let conn = self.write_pool.get()?;
+        task::spawn_blocking(move || {
+            let count = conn.execute(
+                "UPDATE event SET hidden=TRUE WHERE event_hash=? AND hidden!=TRUE;",
+                params![id])?;
+            Ok(count > 0)
+        }).await?
+    }
+
+    /// Get the account of a pubkey
+    async fn get_account(&self, pubkey: &str) -> Result<Option<Account>> {
+        let conn = self.read_pool.get()?;
+        let pubkey_blob = hex::decode(pubkey)?;
+        let pubkey = pubkey.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let account = conn.query_row(
+                "SELECT admitted, balance_msat, created_at FROM account WHERE pubkey=?;",
+                params![pubkey_blob],
+                |r| Ok(Account {
+                    pubkey,
+                    admitted: r.get(0)?,
+                    balance_msat: r.get(1)?,
+                    created_at: r.get(2)?,
+                })).optional()?;
+            Ok(account)
+        }).await?
+    }
+
+    /// Store a new invoice, and an account for its pubkey
+    async fn add_invoice(&self, invoice: &Invoice) -> Result<()> {
+        let mut conn = self.write_pool.get()?;
+        let payment_hash = hex::decode(&invoice.payment_hash)?;
+        let pubkey = hex::decode(&invoice.pubkey)?;
+        let invoice = invoice.clone();
+        tokio::task::spawn_blocking(move || {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT OR IGNORE INTO account (pubkey, admitted, created_at) VALUES (?, FALSE, ?);",
+                params![pubkey, invoice.created_at])?;
+            tx.execute(
+                "INSERT INTO invoice (payment_hash, pubkey, bolt11, amount_msat, topup, paid, created_at, expires_at) VALUES (?, ?, ?, ?, ?, FALSE, ?, ?);",
+                params![payment_hash, pubkey, invoice.bolt11, invoice.amount_msat, invoice.topup, invoice.created_at, invoice.expires_at])?;
+            tx.commit()?;
+            let ok: Result<()> = Ok(());
+            ok
+        }).await?
+    }
+
+    /// Get all unpaid invoices
+    async fn get_unpaid_invoices(&self) -> Result<Vec<Invoice>> {
+        let conn = self.read_pool.get()?;
+        tokio::task::spawn_blocking(move || {
+            let mut stmt = conn.prepare(
+                "SELECT payment_hash, pubkey, bolt11, amount_msat, topup, created_at, expires_at FROM invoice WHERE paid=FALSE;")?;
+            let mut rows = stmt.query([])?;
+            let mut invoices = vec![];
+            while let Some(row) = rows.next()? {
+                invoices.push(Invoice {
+                    payment_hash: hex::encode(row.get::<_, Vec<u8>>(0)?),
+                    pubkey: hex::encode(row.get::<_, Vec<u8>>(1)?),
+                    bolt11: row.get(2)?,
+                    amount_msat: row.get(3)?,
+                    topup: row.get(4)?,
+                    paid: false,
+                    created_at: row.get(5)?,
+                    expires_at: row.get(6)?,
+                });
+            }
+            Ok(invoices)
+        }).await?
+    }
+
+    /// Mark an invoice paid, and admit its pubkey
+    async fn invoice_paid(&self, payment_hash: &str) -> Result<bool> {
+        let mut conn = self.write_pool.get()?;
+        let payment_hash = hex::decode(payment_hash)?;
+        tokio::task::spawn_blocking(move || {
+            let tx = conn.transaction()?;
+            let count = tx.execute(
+                "UPDATE invoice SET paid=TRUE WHERE payment_hash=? AND paid=FALSE;",
+                params![payment_hash])?;
+            if count > 0 {
+                tx.execute(
+                    "UPDATE account SET admitted=TRUE, balance_msat=balance_msat+(SELECT CASE WHEN topup THEN amount_msat ELSE 0 END FROM invoice WHERE payment_hash=?) WHERE pubkey=(SELECT pubkey FROM invoice WHERE payment_hash=?);",
+                    params![payment_hash, payment_hash])?;
+            }
+            tx.commit()?;
+            Ok(count > 0)
+        }).await?
+    }
+
+    /// Debit the balance of a pubkey, if it is high enough
+    async fn debit_account(&self, pubkey: &str, amount_msat: u64) -> Result<bool> {
+        let conn = self.write_pool.get()?;
+        let pubkey = hex::decode(pubkey)?;
+        tokio::task::spawn_blocking(move || {
+            let count = conn.execute(
+                "UPDATE account SET balance_msat=balance_msat-? WHERE pubkey=? AND balance_msat>=?;",
+                params![amount_msat, pubkey, amount_msat])?;
+            Ok(count > 0)
+        }).await?
+    }
+
+    async fn credit_account(&self, pubkey: &str, amount_msat: u64) -> Result<()> {
+        let conn = self.write_pool.get()?;
+        let pubkey = hex::decode(pubkey)?;
+        tokio::task::spawn_blocking(move || {
+            conn.execute(
+                "UPDATE account SET balance_msat=balance_msat+? WHERE pubkey=?;",
+                params![amount_msat, pubkey])?;
+            Ok(())
+        }).await?
+    }
+
     /// Perform normal maintenance
     async fn optimize_db(&self) -> Result<()> {
         let conn = self.write_pool.get()?;
@@ -503,6 +1104,202 @@ impl NostrRepo for SqliteRepo {
         Ok(())
     }
 
+    /// Rebuild the database file without free pages, and empty the
+    /// write-ahead log.  A database without auto-vacuum is switched
+    /// to incremental auto-vacuum, so that it can be vacuumed online
+    /// afterwards.
+    async fn compact(&self) -> Result<()> {
+        let conn = self.write_pool.get()?;
+        task::spawn_blocking(move || {
+            let start = Instant::now();
+            let mode: i64 = conn.query_row("PRAGMA auto_vacuum;", [], |r| r.get(0))?;
+            if mode == 0 {
+                conn.execute_batch("PRAGMA auto_vacuum = INCREMENTAL;")?;
+            }
+            conn.execute_batch("VACUUM; PRAGMA wal_checkpoint(TRUNCATE); PRAGMA optimize;")?;
+            info!("vacuum ran in {:?}", start.elapsed());
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Return free pages to the file system a few at a time, each
+    /// step in its own transaction, if the database uses incremental
+    /// auto-vacuum.  With full auto-vacuum, pages are returned as
+    /// they are freed.
+    async fn vacuum(&self) -> Result<u64> {
+        let pool = self.write_pool.clone();
+        task::spawn_blocking(move || {
+            let start = Instant::now();
+            let free_pages = |conn: &PooledConnection| -> Result<i64> {
+                Ok(conn.query_row("PRAGMA freelist_count;", [], |r| r.get(0))?)
+            };
+            let conn = pool.get()?;
+            let mode: i64 = conn.query_row("PRAGMA auto_vacuum;", [], |r| r.get(0))?;
+            let page_size: i64 = conn.query_row("PRAGMA page_size;", [], |r| r.get(0))?;
+            let before = free_pages(&conn)?;
+            drop(conn);
+            let mut remaining = before;
+            if mode == 2 {
+                // release the connection between steps, so the
+                // writer is not held up.
+                while remaining > 0 {
+                    let conn = pool.get()?;
+                    conn.execute_batch(&format!("PRAGMA incremental_vacuum({VACUUM_STEP_PAGES});"))?;
+                    let left = free_pages(&conn)?;
+                    if left >= remaining {
+                        break;
+                    }
+                    remaining = left;
+                }
+            } else if mode == 0 && before > 0 {
+                info!("{} free pages can only be reclaimed by the compact command", before);
+            }
+            pool.get()?.execute_batch("PRAGMA optimize;")?;
+            debug!("incremental vacuum ran in {:?}", start.elapsed());
+            Ok(((before - remaining).max(0) * page_size) as u64)
+        })
+        .await?
+    }
+
+    /// Copy the database with the online backup API.  All pages are
+    /// copied in one step, within a single read transaction, so that
+    /// writes made meanwhile (which do not wait for it, in WAL mode)
+    /// cannot restart the copy.
+    async fn backup(&self, dest: &Path) -> Result<bool> {
+        let conn = self.read_pool.get()?;
+        let dest = dest.to_owned();
+        task::spawn_blocking(move || {
+            let start = Instant::now();
+            let mut out = rusqlite::Connection::open(&dest)?;
+            let backup = rusqlite::backup::Backup::new(&conn, &mut out)?;
+            // retry while the source is busy or locked.
+            while backup.step(-1)? != rusqlite::backup::StepResult::Done {
+                thread::sleep(Duration::from_millis(100));
+            }
+            drop(backup);
+            info!("backup ran in {:?}", start.elapsed());
+            Ok(true)
+        })
+        .await?
+    }
+
+    /// Check that the database answers a trivial query
+    async fn ping(&self) -> Result<()> {
+        let conn = self.read_pool.get()?;
+        task::spawn_blocking(move || {
+            conn.query_row("SELECT 1;", [], |r| r.get::<_, i64>(0))?;
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Check pages and indexes, and for tags of missing events
+    async fn check_integrity(&self) -> Result<Vec<String>> {
+        let conn = self.read_pool.get()?;
+        task::spawn_blocking(move || {
+            let mut problems = vec![];
+            let mut stmt = conn.prepare("PRAGMA integrity_check;")?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let msg: String = row.get(0)?;
+                if msg != "ok" {
+                    problems.push(msg);
+                }
+            }
+            let mut stmt = conn.prepare("PRAGMA foreign_key_check;")?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let table: String = row.get(0)?;
+                let rowid: Option<i64> = row.get(1)?;
+                let parent: String = row.get(2)?;
+                problems.push(format!("{table} row {rowid:?} refers to a missing {parent} row"));
+            }
+            Ok(problems)
+        })
+        .await?
+    }
+
+    /// Get summaries of stored events
+    async fn event_summaries(&self, order: ScanOrder, after: Option<&EventSummary>, limit: usize) -> Result<Vec<EventSummary>> {
+        let conn = self.read_pool.get()?;
+        let (cmp, dir) = order.sql();
+        let after = after.map(|a| (a.created_at as i64, hex::decode(&a.id).ok()));
+        task::spawn_blocking(move || {
+            let map_row = |r: &rusqlite::Row| -> rusqlite::Result<EventSummary> {
+                Ok(EventSummary {
+                    id: hex::encode(r.get::<_, Vec<u8>>(0)?),
+                    pubkey: hex::encode(r.get::<_, Vec<u8>>(1)?),
+                    kind: r.get(2)?,
+                    created_at: r.get(3)?,
+                })
+            };
+            let summaries = if let Some((created_at, id)) = after {
+                let mut stmt = conn.prepare_cached(&format!(
+                    "SELECT event_hash, author, kind, created_at FROM event WHERE created_at {cmp} ?1 OR (created_at = ?1 AND event_hash {cmp} ?2) ORDER BY created_at {dir}, event_hash {dir} LIMIT ?3;"))?;
+                let rows = stmt.query_map(params![created_at, id, limit], map_row)?;
+                rows.collect::<rusqlite::Result<Vec<EventSummary>>>()?
+            } else {
+                let mut stmt = conn.prepare_cached(&format!(
+                    "SELECT event_hash, author, kind, created_at FROM event ORDER BY created_at {dir}, event_hash {dir} LIMIT ?;"))?;
+                let rows = stmt.query_map(params![limit], map_row)?;
+                rows.collect::<rusqlite::Result<Vec<EventSummary>>>()?
+            };
+            Ok(summaries)
+        })
+        .await?
+    }
+
+    /// Pages in use (deleted rows leave pages on the freelist)
+    async fn used_bytes(&self) -> Result<u64> {
+        let conn = self.read_pool.get()?;
+        task::spawn_blocking(move || {
+            let used: u64 = conn.query_row(
+                "SELECT (p.page_count - f.freelist_count) * s.page_size FROM pragma_page_count() p, pragma_freelist_count() f, pragma_page_size() s;",
+                [],
+                |r| r.get(0),
+            )?;
+            Ok(used)
+        })
+        .await?
+    }
+
+    async fn author_bytes(&self, pubkey: &str) -> Result<u64> {
+        let conn = self.read_pool.get()?;
+        let pubkey_blob = hex::decode(pubkey)?;
+        task::spawn_blocking(move || {
+            let used: u64 = conn.query_row(
+                "SELECT COALESCE(SUM(length(content)), 0) FROM event WHERE author=? AND hidden!=TRUE;",
+                params![pubkey_blob],
+                |r| r.get(0),
+            )?;
+            Ok(used)
+        })
+        .await?
+    }
+
+    /// Permanently remove events
+    async fn delete_events(&self, ids: &[String]) -> Result<u64> {
+        let ids: Vec<Vec<u8>> = ids.iter().filter_map(|id| hex::decode(id).ok()).collect();
+        let _write_guard = self.write_in_progress.lock().await;
+        let mut conn = self.write_pool.get()?;
+        task::spawn_blocking(move || {
+            let tx = conn.transaction()?;
+            let mut count = 0;
+            {
+                // tags and verification records are removed by
+                // foreign key cascades.
+                let mut stmt = tx.prepare("DELETE FROM event WHERE event_hash=?;")?;
+                for id in ids {
+                    count += stmt.execute(params![id])? as u64;
+                }
+            }
+            tx.commit()?;
+            Ok(count)
+        })
+        .await?
+    }
+
     /// Create a new verification record connected to a specific event
     async fn create_verification_record(&self, event_id: &str, name: &str) -> Result<()> {
         let e = hex::decode(event_id).ok();
@@ -665,39 +1462,86 @@ impl NostrRepo for SqliteRepo {
             Ok(vr)
         }).await?
     }
-}
 
-/// Decide if there is an index that should be used explicitly
-fn override_index(f: &ReqFilter) -> Option<String> {
-    if f.ids.is_some() {
-        return Some("event_hash_index".into());
+    /// Queue a metadata event for NIP-05 verification
+    async fn put_queued_verification(&self, queued: &QueuedVerification) -> Result<()> {
+        let conn = self.write_pool.get()?;
+        let pubkey = hex::decode(&queued.event.pubkey)?;
+        let event_id = hex::decode(&queued.event.id)?;
+        let json = serde_json::to_string(&queued.event)?;
+        let (created_at, attempts, retry_at) = (queued.event.created_at, queued.attempts, queued.retry_at);
+        tokio::task::spawn_blocking(move || {
+            conn.execute(
+                "INSERT INTO verification_queue (pubkey, event_id, created_at, event, attempts, retry_at) VALUES (?, ?, ?, ?, ?, ?) \
+                 ON CONFLICT (pubkey) DO UPDATE SET event_id=excluded.event_id, created_at=excluded.created_at, event=excluded.event, \
+                 attempts=excluded.attempts, retry_at=excluded.retry_at WHERE excluded.created_at >= verification_queue.created_at;",
+                params![pubkey, event_id, created_at, json, attempts, retry_at])?;
+            let ok: Result<()> = Ok(());
+            ok
+        }).await?
     }
-    // queries for multiple kinds default to kind_index, which is
-    // significantly slower than kind_created_at_index.
-    if let Some(ks) = &f.kinds {
-        if f.ids.is_none() &&
-            ks.len() > 1 &&
-            f.since.is_none() &&
-            f.until.is_none() &&
-            f.tags.is_none() &&
-            f.authors.is_none() {
-                return Some("kind_created_at_index".into());
+
+    /// Get queued verifications that are due
+    async fn get_queued_verifications(&self, due: u64, limit: usize) -> Result<Vec<QueuedVerification>> {
+        let conn = self.read_pool.get()?;
+        tokio::task::spawn_blocking(move || {
+            let mut stmt = conn.prepare(
+                "SELECT event, attempts, retry_at FROM verification_queue WHERE retry_at <= ? ORDER BY retry_at ASC LIMIT ?;")?;
+            let mut rows = stmt.query(params![due, limit])?;
+            let mut queued = vec![];
+            while let Some(row) = rows.next()? {
+                let json: String = row.get(0)?;
+                queued.push(QueuedVerification {
+                    event: serde_json::from_str(&json)?,
+                    attempts: row.get(1)?,
+                    retry_at: row.get(2)?,
+                });
             }
+            Ok(queued)
+        }).await?
     }
-    // if there is an author, it is much better to force the authors index.
-    if f.authors.is_some() {
-        if f.since.is_none() && f.until.is_none() && f.limit.is_none() {
-            if f.kinds.is_none() {
+
+    /// Remove a queued verification
+    async fn remove_queued_verification(&self, pubkey: &str, event_id: &str) -> Result<()> {
+        let conn = self.write_pool.get()?;
+        let pubkey = hex::decode(pubkey)?;
+        let event_id = hex::decode(event_id)?;
+        tokio::task::spawn_blocking(move || {
+            conn.execute(
+                "DELETE FROM verification_queue WHERE pubkey = ? AND event_id = ?;",
+                params![pubkey, event_id])?;
+            let ok: Result<()> = Ok(());
+            ok
+        }).await?
+    }
+}
+
+/// Decide if there is an index that should be used explicitly, for
+/// the planned access to a filter.
+fn override_index(f: &ReqFilter, access: Access) -> Option<String> {
+    match access {
+        Access::Nothing | Access::Tag(_) => None,
+        Access::Ids => Some("event_hash_index".into()),
+        // if there is an author, it is much better to force the authors index.
+        Access::Authors => {
+            if f.kinds.is_some() {
+                // events of these kinds by each author are read in
+                // order of creation, so the newest come first.
+                return Some("author_kind_created_at_index".into());
+            }
+            if f.since.is_none() && f.until.is_none() && f.limit.is_none() {
                 // with no use of kinds/created_at, just author
                 return Some("author_index".into());
             }
-            // prefer author_kind if there are kinds
-            return Some("author_kind_index".into());
+            // finally, prefer author_created_at if time is provided
+            Some("author_created_at_index".into())
         }
-        // finally, prefer author_created_at if time is provided
-        return Some("author_created_at_index".into());
+        // queries for kinds default to kind_index, which is
+        // significantly slower than kind_created_at_index.
+        Access::Kinds => Some("kind_created_at_index".into()),
+        Access::Scan if f.since.is_some() || f.until.is_some() => Some("created_at_index".into()),
+        Access::Scan => None,
     }
-    None
 }
 
 /// Create a dynamic SQL subquery and params from a subscription filter (and optional explicit index used)
@@ -707,8 +1551,9 @@ fn query_from_filter(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>, Option<Stri
     // hexadecimal characters.  Strings that require escaping (tag
     // names/values) use parameters.
 
-    // if the filter is malformed, don't return anything.
-    if f.force_no_match {
+    // if the filter is malformed, or can't match, don't return anything.
+    let access = planner::plan(f).access;
+    if access == Access::Nothing {
         let empty_query = "SELECT e.content FROM event e WHERE 1=0".to_owned();
         // query parameters for SQLite
         let empty_params: Vec<Box<dyn ToSql>> = vec![];
@@ -716,7 +1561,7 @@ fn query_from_filter(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>, Option<Stri
     }
 
     // check if the index needs to be overriden
-    let idx_name = override_index(f);
+    let idx_name = override_index(f, access);
     let idx_stmt = idx_name.as_ref().map_or_else(|| "".to_owned(), |i| format!("INDEXED BY {i}"));
     let mut query = format!("SELECT e.content FROM event e {idx_stmt}");
     // query parameters for SQLite
@@ -798,63 +1643,72 @@ fn query_from_filter(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>, Option<Stri
         }
     }
     // Query for tags
-    if let Some(map) = &f.tags {
-        for (key, val) in map.iter() {
-            let mut str_vals: Vec<Box<dyn ToSql>> = vec![];
-            let mut blob_vals: Vec<Box<dyn ToSql>> = vec![];
-            for v in val {
-                if (v.len() % 2 == 0) && is_lower_hex(v) {
-                    if let Ok(h) = hex::decode(v) {
-                        blob_vals.push(Box::new(h));
-                    }
-                } else {
-                    str_vals.push(Box::new(v.clone()));
+    for (key, val) in &f.tag_conditions() {
+        let mut str_vals: Vec<Box<dyn ToSql>> = vec![];
+        let mut blob_vals: Vec<Box<dyn ToSql>> = vec![];
+        for v in val {
+            if (v.len() % 2 == 0) && is_lower_hex(v) {
+                if let Ok(h) = hex::decode(v) {
+                    blob_vals.push(Box::new(h));
                 }
+            } else {
+                str_vals.push(Box::new(v.clone()));
             }
-            // do not mix value and value_hex; this is a temporary special case.
-            if str_vals.is_empty() {
-                // create clauses with "?" params for each tag value being searched
-                let blob_clause = format!("value_hex IN ({})", repeat_vars(blob_vals.len()));
-                // find evidence of the target tag name/value existing for this event.
-                let tag_clause = format!(
-                    "e.id IN (SELECT t.event_id FROM tag t WHERE (name=? AND {blob_clause}))",
-                );
-                // add the tag name as the first parameter
-                params.push(Box::new(key.to_string()));
-                // add all tag values that are blobs as params
-                params.append(&mut blob_vals);
-                filter_components.push(tag_clause);
-            } else if blob_vals.is_empty() {
-                // create clauses with "?" params for each tag value being searched
-                let str_clause = format!("value IN ({})", repeat_vars(str_vals.len()));
-                // find evidence of the target tag name/value existing for this event.
-                let tag_clause = format!(
+        }
+        // do not mix value and value_hex; this is a temporary special case.
+        if str_vals.is_empty() {
+            // create clauses with "?" params for each tag value being searched
+            let blob_clause = format!("value_hex IN ({})", repeat_vars(blob_vals.len()));
+            // find evidence of the target tag name/value existing for this event.
+            let tag_clause = format!(
+                "e.id IN (SELECT t.event_id FROM tag t WHERE (name=? AND {blob_clause}))",
+            );
+            // add the tag name as the first parameter
+            params.push(Box::new(key.to_string()));
+            // add all tag values that are blobs as params
+            params.append(&mut blob_vals);
+            filter_components.push(tag_clause);
+        } else if blob_vals.is_empty() {
+            // create clauses with "?" params for each tag value being searched
+            let str_clause = format!("value IN ({})", repeat_vars(str_vals.len()));
+            // find evidence of the target tag name/value existing for this event.
+            let tag_clause = format!(
 		            "e.id IN (SELECT t.event_id FROM tag t WHERE (name=? AND {str_clause}))",
-                );
-                // add the tag name as the first parameter
-                params.push(Box::new(key.to_string()));
-                // add all tag values that are blobs as params
-                params.append(&mut str_vals);
-                filter_components.push(tag_clause);
-            } else {
-                debug!("mixed string/blob query");
-                // create clauses with "?" params for each tag value being searched
-                let str_clause = format!("value IN ({})", repeat_vars(str_vals.len()));
-                let blob_clause = format!("value_hex IN ({})", repeat_vars(blob_vals.len()));
-                // find evidence of the target tag name/value existing for this event.
-                let tag_clause = format!(
+            );
+            // add the tag name as the first parameter
+            params.push(Box::new(key.to_string()));
+            // add all tag values that are blobs as params
+            params.append(&mut str_vals);
+            filter_components.push(tag_clause);
+        } else {
+            debug!("mixed string/blob query");
+            // create clauses with "?" params for each tag value being searched
+            let str_clause = format!("value IN ({})", repeat_vars(str_vals.len()));
+            let blob_clause = format!("value_hex IN ({})", repeat_vars(blob_vals.len()));
+            // find evidence of the target tag name/value existing for this event.
+            let tag_clause = format!(
 		            "e.id IN (SELECT t.event_id FROM tag t WHERE (name=? AND ({str_clause} OR {blob_clause})))",
-                );
-                // add the tag name as the first parameter
-                params.push(Box::new(key.to_string()));
-                // add all tag values that are plain strings as params
-                params.append(&mut str_vals);
-                // add all tag values that are blobs as params
-                params.append(&mut blob_vals);
-                filter_components.push(tag_clause);
-            }
+            );
+            // add the tag name as the first parameter
+            params.push(Box::new(key.to_string()));
+            // add all tag values that are plain strings as params
+            params.append(&mut str_vals);
+            // add all tag values that are blobs as params
+            params.append(&mut blob_vals);
+            filter_components.push(tag_clause);
         }
     }
+    // Query for full-text search
+    if let Some(terms) = f.search_terms() {
+        // quote every term, so that FTS5 query syntax in the search
+        // string is matched literally.
+        let fts_query: Vec<String> = terms
+            .iter()
+            .map(|t| format!("\"{}\"", t.replace('"', "\"\"")))
+            .collect();
+        filter_components.push("e.id IN (SELECT rowid FROM event_fts WHERE event_fts MATCH ?)".to_owned());
+        params.push(Box::new(fts_query.join(" ")));
+    }
     // Query for timestamp
     if f.since.is_some() {
         let created_clause = format!("created_at > {}", f.since.unwrap());
@@ -882,8 +1736,8 @@ fn query_from_filter(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>, Option<Stri
     (query, params, idx_name)
 }
 
-/// Create a dynamic SQL query string and params from a subscription.
-fn _query_from_sub(sub: &Subscription) -> (String, Vec<Box<dyn ToSql>>, Vec<String>) {
+/// Create a dynamic SQL query string and params from a set of filters.
+fn query_from_filters(filters: &[ReqFilter]) -> (String, Vec<Box<dyn ToSql>>, Vec<String>) {
     // build a dynamic SQL query for an entire subscription, based on
     // SQL subqueries for filters.
     let mut subqueries: Vec<String> = Vec::new();
@@ -891,7 +1745,7 @@ fn _query_from_sub(sub: &Subscription) -> (String, Vec<Box<dyn ToSql>>, Vec<Stri
     // subquery params
     let mut params: Vec<Box<dyn ToSql>> = vec![];
     // for every filter in the subscription, generate a subquery
-    for f in &sub.filters {
+    for f in filters {
         let (f_subquery, mut f_params, index) = query_from_filter(f);
         if let Some(i) = index {
             indexes.push(i);
@@ -902,7 +1756,7 @@ fn _query_from_sub(sub: &Subscription) -> (String, Vec<Box<dyn ToSql>>, Vec<Stri
     // encapsulate subqueries into select statements
     let subqueries_selects: Vec<String> = subqueries
         .iter()
-        .map(|s| format!("SELECT distinct content, created_at FROM ({s})"))
+        .map(|s| format!("SELECT distinct content FROM ({s})"))
         .collect();
     let query: String = subqueries_selects.join(" UNION ");
     (query, params,indexes)
@@ -920,9 +1774,22 @@ pub fn build_pool(
     min_size: u32,
     max_size: u32,
     wait_for_db: bool,
+) -> SqlitePool {
+    build_pool_file(name, settings, DB_FILE, flags, min_size, max_size, wait_for_db)
+}
+
+/// Build a connection pool for a database file in the data directory.
+pub fn build_pool_file(
+    name: &str,
+    settings: &Settings,
+    file: &str,
+    flags: OpenFlags,
+    min_size: u32,
+    max_size: u32,
+    wait_for_db: bool,
 ) -> SqlitePool {
     let db_dir = &settings.database.data_directory;
-    let full_path = Path::new(db_dir).join(DB_FILE);
+    let full_path = Path::new(db_dir).join(file);
 
     // small hack; if the database doesn't exist yet, that means the
     // writer thread hasn't finished.  Give it a chance to work.  This
@@ -950,8 +1817,8 @@ pub fn build_pool(
         .build(manager)
         .unwrap();
     info!(
-        "Built a connection pool {:?} (min={}, max={})",
-        name, min_size, max_size
+        "Built a connection pool {:?} for {} (min={}, max={})",
+        name, file, min_size, max_size
     );
     pool
 }
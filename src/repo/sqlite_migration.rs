@@ -22,7 +22,7 @@ pragma mmap_size = 17179869184; -- cap mmap at 16GB
 "##;
 
 /// Latest database version
-pub const DB_VERSION: usize = 15;
+pub const DB_VERSION: usize = 25;
 
 /// Schema definition
 const INIT_SQL: &str = formatcp!(
@@ -55,11 +55,11 @@ CREATE INDEX IF NOT EXISTS author_index ON event(author);
 CREATE INDEX IF NOT EXISTS kind_index ON event(kind);
 CREATE INDEX IF NOT EXISTS created_at_index ON event(created_at);
 CREATE INDEX IF NOT EXISTS delegated_by_index ON event(delegated_by);
-CREATE INDEX IF NOT EXISTS event_composite_index ON event(kind,created_at);
 CREATE INDEX IF NOT EXISTS kind_author_index ON event(kind,author);
 CREATE INDEX IF NOT EXISTS kind_created_at_index ON event(kind,created_at);
 CREATE INDEX IF NOT EXISTS author_created_at_index ON event(author,created_at);
 CREATE INDEX IF NOT EXISTS author_kind_index ON event(author,kind);
+CREATE INDEX IF NOT EXISTS author_kind_created_at_index ON event(author,kind,created_at);
 
 -- Tag Table
 -- Tag values are stored as either a BLOB (if they come in as a
@@ -90,10 +90,106 @@ FOREIGN KEY(metadata_event) REFERENCES event(id) ON UPDATE CASCADE ON DELETE CAS
 );
 CREATE INDEX IF NOT EXISTS user_verification_name_index ON user_verification(name);
 CREATE INDEX IF NOT EXISTS user_verification_event_index ON user_verification(metadata_event);
+
+-- Relay-based groups (NIP-29)
+CREATE TABLE IF NOT EXISTS relay_group (
+id TEXT PRIMARY KEY, -- group identifier (h tag value)
+created_at INTEGER NOT NULL -- when the group was created
+);
+CREATE TABLE IF NOT EXISTS group_member (
+id INTEGER PRIMARY KEY,
+group_id TEXT NOT NULL, -- group identifier
+pubkey BLOB NOT NULL, -- member pubkey
+role TEXT NOT NULL, -- "admin" or "member"
+FOREIGN KEY(group_id) REFERENCES relay_group(id) ON UPDATE CASCADE ON DELETE CASCADE
+);
+CREATE UNIQUE INDEX IF NOT EXISTS group_member_index ON group_member(group_id,pubkey);
+
+-- Banned IP addresses and pubkeys
+CREATE TABLE IF NOT EXISTS ban (
+target TEXT NOT NULL, -- "ip" or "pubkey"
+value TEXT NOT NULL, -- IP address, or hex pubkey
+expires_at INTEGER, -- when the ban ends (never, if null)
+reason TEXT NOT NULL,
+PRIMARY KEY(target, value)
+);
+
+-- Pubkeys allowed to publish, when the pubkey whitelist is enabled
+CREATE TABLE IF NOT EXISTS allowed_pubkey (
+pubkey BLOB PRIMARY KEY,
+created_at INTEGER NOT NULL
+);
+
+-- Metadata events waiting for NIP-05 verification
+CREATE TABLE IF NOT EXISTS verification_queue (
+pubkey BLOB PRIMARY KEY,
+event_id BLOB NOT NULL,
+created_at INTEGER NOT NULL,
+event TEXT NOT NULL,
+attempts INTEGER NOT NULL DEFAULT 0,
+retry_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS verification_queue_retry_index ON verification_queue(retry_at);
+
+-- Reputation of event authors
+CREATE TABLE IF NOT EXISTS reputation (
+pubkey BLOB PRIMARY KEY,
+first_seen INTEGER, -- when an event from the pubkey was first stored
+reports INTEGER NOT NULL DEFAULT 0, -- reports (NIP-56) naming the pubkey
+spam_hits INTEGER NOT NULL DEFAULT 0 -- events rejected as spam
+);
+
+-- Reports (NIP-56) of pubkeys and events
+CREATE TABLE IF NOT EXISTS report (
+reporter BLOB NOT NULL,
+pubkey BLOB NOT NULL, -- reported pubkey
+event_hash BLOB NOT NULL, -- reported event (empty, if none)
+report_type TEXT NOT NULL,
+created_at INTEGER NOT NULL,
+PRIMARY KEY(reporter, pubkey, event_hash)
+);
+CREATE INDEX IF NOT EXISTS report_event_index ON report(event_hash);
+
+-- Accounts of pubkeys that have requested paid admission
+CREATE TABLE IF NOT EXISTS account (
+pubkey BLOB PRIMARY KEY,
+admitted INTEGER NOT NULL DEFAULT 0, -- admission fee has been paid
+balance_msat INTEGER NOT NULL DEFAULT 0, -- credit for publishing events
+created_at INTEGER NOT NULL
+);
+
+-- Lightning invoices issued for admission and top-ups
+CREATE TABLE IF NOT EXISTS invoice (
+payment_hash BLOB PRIMARY KEY,
+pubkey BLOB NOT NULL,
+bolt11 TEXT NOT NULL,
+amount_msat INTEGER NOT NULL,
+topup INTEGER NOT NULL DEFAULT 0, -- credits the balance, instead of paying for admission
+paid INTEGER NOT NULL DEFAULT 0,
+created_at INTEGER NOT NULL,
+expires_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS invoice_paid_index ON invoice(paid);
+
+-- Full-text search (NIP-50)
+{}
 "##,
-    DB_VERSION
+    DB_VERSION,
+    FTS_SQL
 );
 
+/// Full-text search index over event content, kept in sync with the
+/// event table by triggers.
+const FTS_SQL: &str = r##"
+CREATE VIRTUAL TABLE IF NOT EXISTS event_fts USING fts5(content);
+CREATE TRIGGER IF NOT EXISTS event_fts_insert AFTER INSERT ON event BEGIN
+  INSERT INTO event_fts(rowid, content) VALUES (new.id, json_extract(new.content, '$.content'));
+END;
+CREATE TRIGGER IF NOT EXISTS event_fts_delete AFTER DELETE ON event BEGIN
+  DELETE FROM event_fts WHERE rowid=old.id;
+END;
+"##;
+
 /// Determine the current application database schema version.
 pub fn curr_db_version(conn: &mut Connection) -> Result<usize> {
     let query = "PRAGMA user_version;";
@@ -201,6 +297,36 @@ pub fn upgrade_db(conn: &mut PooledConnection) -> Result<usize> {
             if curr_version == 14 {
                 curr_version = mig_14_to_15(conn)?;
             }
+            if curr_version == 15 {
+                curr_version = mig_15_to_16(conn)?;
+            }
+            if curr_version == 16 {
+                curr_version = mig_16_to_17(conn)?;
+            }
+            if curr_version == 17 {
+                curr_version = mig_17_to_18(conn)?;
+            }
+            if curr_version == 18 {
+                curr_version = mig_18_to_19(conn)?;
+            }
+            if curr_version == 19 {
+                curr_version = mig_19_to_20(conn)?;
+            }
+            if curr_version == 20 {
+                curr_version = mig_20_to_21(conn)?;
+            }
+            if curr_version == 21 {
+                curr_version = mig_21_to_22(conn)?;
+            }
+            if curr_version == 22 {
+                curr_version = mig_22_to_23(conn)?;
+            }
+            if curr_version == 23 {
+                curr_version = mig_23_to_24(conn)?;
+            }
+            if curr_version == 24 {
+                curr_version = mig_24_to_25(conn)?;
+            }
 
             if curr_version == DB_VERSION {
                 info!(
@@ -652,3 +778,249 @@ PRAGMA user_version = 15;
     }
     Ok(15)
 }
+
+fn mig_15_to_16(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 15->16");
+    let upgrade_sql = formatcp!(
+        r##"
+{}
+INSERT INTO event_fts(rowid, content) SELECT id, json_extract(content, '$.content') FROM event;
+PRAGMA user_version = 16;
+"##,
+        FTS_SQL
+    );
+    info!("building full-text search index; this may take awhile...");
+    match conn.execute_batch(upgrade_sql) {
+        Ok(()) => {
+            info!("database schema upgraded v15 -> v16");
+        }
+        Err(err) => {
+            error!("update failed: {}", err);
+            panic!("database could not be upgraded");
+        }
+    }
+    Ok(16)
+}
+
+fn mig_16_to_17(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 16->17");
+    let upgrade_sql = r##"
+CREATE TABLE IF NOT EXISTS relay_group (
+id TEXT PRIMARY KEY,
+created_at INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS group_member (
+id INTEGER PRIMARY KEY,
+group_id TEXT NOT NULL,
+pubkey BLOB NOT NULL,
+role TEXT NOT NULL,
+FOREIGN KEY(group_id) REFERENCES relay_group(id) ON UPDATE CASCADE ON DELETE CASCADE
+);
+CREATE UNIQUE INDEX IF NOT EXISTS group_member_index ON group_member(group_id,pubkey);
+PRAGMA user_version = 17;
+"##;
+    match conn.execute_batch(upgrade_sql) {
+        Ok(()) => {
+            info!("database schema upgraded v16 -> v17");
+        }
+        Err(err) => {
+            error!("update failed: {}", err);
+            panic!("database could not be upgraded");
+        }
+    }
+    Ok(17)
+}
+
+fn mig_17_to_18(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 17->18");
+    let upgrade_sql = r##"
+CREATE TABLE IF NOT EXISTS ban (
+target TEXT NOT NULL,
+value TEXT NOT NULL,
+expires_at INTEGER,
+reason TEXT NOT NULL,
+PRIMARY KEY(target, value)
+);
+PRAGMA user_version = 18;
+"##;
+    match conn.execute_batch(upgrade_sql) {
+        Ok(()) => {
+            info!("database schema upgraded v17 -> v18");
+        }
+        Err(err) => {
+            error!("update failed: {}", err);
+            panic!("database could not be upgraded");
+        }
+    }
+    Ok(18)
+}
+
+fn mig_18_to_19(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 18->19");
+    let upgrade_sql = r##"
+CREATE TABLE IF NOT EXISTS reputation (
+pubkey BLOB PRIMARY KEY,
+first_seen INTEGER,
+reports INTEGER NOT NULL DEFAULT 0,
+spam_hits INTEGER NOT NULL DEFAULT 0
+);
+PRAGMA user_version = 19;
+"##;
+    match conn.execute_batch(upgrade_sql) {
+        Ok(()) => {
+            info!("database schema upgraded v18 -> v19");
+        }
+        Err(err) => {
+            error!("update failed: {}", err);
+            panic!("database could not be upgraded");
+        }
+    }
+    Ok(19)
+}
+
+fn mig_19_to_20(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 19->20");
+    let upgrade_sql = r##"
+CREATE TABLE IF NOT EXISTS report (
+reporter BLOB NOT NULL,
+pubkey BLOB NOT NULL,
+event_hash BLOB NOT NULL,
+report_type TEXT NOT NULL,
+created_at INTEGER NOT NULL,
+PRIMARY KEY(reporter, pubkey, event_hash)
+);
+CREATE INDEX IF NOT EXISTS report_event_index ON report(event_hash);
+PRAGMA user_version = 20;
+"##;
+    match conn.execute_batch(upgrade_sql) {
+        Ok(()) => {
+            info!("database schema upgraded v19 -> v20");
+        }
+        Err(err) => {
+            error!("update failed: {}", err);
+            panic!("database could not be upgraded");
+        }
+    }
+    Ok(20)
+}
+
+fn mig_20_to_21(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 20->21");
+    let upgrade_sql = r##"
+CREATE TABLE IF NOT EXISTS account (
+pubkey BLOB PRIMARY KEY,
+admitted INTEGER NOT NULL DEFAULT 0,
+created_at INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS invoice (
+payment_hash BLOB PRIMARY KEY,
+pubkey BLOB NOT NULL,
+bolt11 TEXT NOT NULL,
+amount_msat INTEGER NOT NULL,
+paid INTEGER NOT NULL DEFAULT 0,
+created_at INTEGER NOT NULL,
+expires_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS invoice_paid_index ON invoice(paid);
+PRAGMA user_version = 21;
+"##;
+    match conn.execute_batch(upgrade_sql) {
+        Ok(()) => {
+            info!("database schema upgraded v20 -> v21");
+        }
+        Err(err) => {
+            error!("update failed: {}", err);
+            panic!("database could not be upgraded");
+        }
+    }
+    Ok(21)
+}
+
+fn mig_21_to_22(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 21->22");
+    let upgrade_sql = r##"
+ALTER TABLE account ADD COLUMN balance_msat INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE invoice ADD COLUMN topup INTEGER NOT NULL DEFAULT 0;
+PRAGMA user_version = 22;
+"##;
+    match conn.execute_batch(upgrade_sql) {
+        Ok(()) => {
+            info!("database schema upgraded v21 -> v22");
+        }
+        Err(err) => {
+            error!("update failed: {}", err);
+            panic!("database could not be upgraded");
+        }
+    }
+    Ok(22)
+}
+
+fn mig_22_to_23(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 22->23");
+    // the latest events by some authors, of some kinds, can be read
+    // in order from a single index.  event_composite_index was a
+    // duplicate of kind_created_at_index.
+    let upgrade_sql = r##"
+CREATE INDEX IF NOT EXISTS author_kind_created_at_index ON event(author,kind,created_at);
+DROP INDEX IF EXISTS event_composite_index;
+PRAGMA user_version = 23;
+"##;
+    info!("creating author/kind/created_at index; this may take awhile...");
+    match conn.execute_batch(upgrade_sql) {
+        Ok(()) => {
+            info!("database schema upgraded v22 -> v23");
+        }
+        Err(err) => {
+            error!("update failed: {}", err);
+            panic!("database could not be upgraded");
+        }
+    }
+    Ok(23)
+}
+
+fn mig_23_to_24(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 23->24");
+    let upgrade_sql = r##"
+CREATE TABLE IF NOT EXISTS allowed_pubkey (
+pubkey BLOB PRIMARY KEY,
+created_at INTEGER NOT NULL
+);
+PRAGMA user_version = 24;
+"##;
+    match conn.execute_batch(upgrade_sql) {
+        Ok(()) => {
+            info!("database schema upgraded v23 -> v24");
+        }
+        Err(err) => {
+            error!("update failed: {}", err);
+            panic!("database could not be upgraded");
+        }
+    }
+    Ok(24)
+}
+
+fn mig_24_to_25(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 24->25");
+    let upgrade_sql = r##"
+CREATE TABLE IF NOT EXISTS verification_queue (
+pubkey BLOB PRIMARY KEY,
+event_id BLOB NOT NULL,
+created_at INTEGER NOT NULL,
+event TEXT NOT NULL,
+attempts INTEGER NOT NULL DEFAULT 0,
+retry_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS verification_queue_retry_index ON verification_queue(retry_at);
+PRAGMA user_version = 25;
+"##;
+    match conn.execute_batch(upgrade_sql) {
+        Ok(()) => {
+            info!("database schema upgraded v24 -> v25");
+        }
+        Err(err) => {
+            error!("update failed: {}", err);
+            panic!("database could not be upgraded");
+        }
+    }
+    Ok(25)
+}
@@ -0,0 +1,96 @@
+//! Summary of what a relay database holds
+use crate::error::Result;
+use crate::repo::{NostrRepo, ScanOrder};
+use crate::utils::unix_time;
+use std::collections::HashSet;
+
+/// Number of events read from the database at a time.
+const BATCH_SIZE: usize = 10_000;
+
+/// Counts of stored records.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RepoStats {
+    /// Stored events, including hidden ones
+    pub events: u64,
+    /// Distinct authors of stored events
+    pub authors: u64,
+    /// Approximate bytes used by events
+    pub bytes: u64,
+    /// Bans that have not expired
+    pub bans: u64,
+    /// Pubkeys in the stored whitelist
+    pub allowed_pubkeys: u64,
+    pub groups: u64,
+}
+
+impl std::fmt::Display for RepoStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "events: {}", self.events)?;
+        writeln!(f, "authors: {}", self.authors)?;
+        writeln!(f, "event bytes: {}", self.bytes)?;
+        writeln!(f, "active bans: {}", self.bans)?;
+        writeln!(f, "whitelisted pubkeys: {}", self.allowed_pubkeys)?;
+        write!(f, "groups: {}", self.groups)
+    }
+}
+
+/// Count what is stored.  Every event is scanned, which takes a
+/// while for a large database.
+pub async fn repo_stats(repo: &dyn NostrRepo) -> Result<RepoStats> {
+    let mut stats = RepoStats::default();
+    let mut authors = HashSet::new();
+    let mut last = None;
+    loop {
+        let page = repo
+            .event_summaries(ScanOrder::OldestFirst, last.as_ref(), BATCH_SIZE)
+            .await?;
+        stats.events += page.len() as u64;
+        authors.extend(page.iter().map(|e| e.pubkey.clone()));
+        if page.len() < BATCH_SIZE {
+            break;
+        }
+        last = page.last().cloned();
+    }
+    stats.authors = authors.len() as u64;
+    stats.bytes = repo.used_bytes().await?;
+    let now = unix_time();
+    stats.bans = repo.get_bans().await?.iter().filter(|b| b.is_active(now)).count() as u64;
+    stats.allowed_pubkeys = repo.get_allowed_pubkeys().await?.len() as u64;
+    stats.groups = repo.get_groups().await?.len() as u64;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+    use crate::event::Event;
+    use crate::repo::check::check_events;
+    use crate::repo::memory::MemoryRepo;
+    use crate::server::create_metrics;
+    use secp256k1::{KeyPair, Secp256k1};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn invalid_events_are_found() {
+        let (_, metrics) = create_metrics();
+        let repo = Arc::new(MemoryRepo::new(&Settings::default(), metrics));
+        let secp = Secp256k1::new();
+        let keypair = KeyPair::new(&secp, &mut secp256k1::rand::thread_rng());
+        for n in 0..3 {
+            let mut e = Event::new_signed(&keypair, 1, vec![], n.to_string());
+            if n == 1 {
+                e.content = "tampered".to_owned();
+            }
+            repo.write_event(&e).await.unwrap();
+        }
+        let stats = repo_stats(repo.as_ref()).await.unwrap();
+        assert_eq!((stats.events, stats.authors), (3, 1));
+        let checked = check_events(repo.as_ref(), false).await.unwrap();
+        assert_eq!((checked.checked, checked.invalid, checked.deleted), (3, 1, 0));
+        assert_eq!(checked.index_problems, 0);
+        let checked = check_events(repo.as_ref(), true).await.unwrap();
+        assert_eq!(checked.deleted, 1);
+        assert_eq!(repo_stats(repo.as_ref()).await.unwrap().events, 2);
+    }
+}
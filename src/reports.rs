@@ -0,0 +1,314 @@
+//! Reports of pubkeys and events (NIP-56)
+//!
+//! Each stored report names the reported pubkey, and possibly one of
+//! its events.  Reports are kept once per reporter and target, and
+//! summarized for relay admins.  Events reported by enough trusted
+//! reporters can be hidden from queries automatically.  Reports are
+//! also kept when reputation is enabled, which counts each reporter
+//! of a pubkey once.
+use crate::config::{self, Settings};
+use crate::event::Event;
+use crate::repo::NostrRepo;
+use crate::reputation::{Reputations, Standing};
+use crate::utils::is_lower_hex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// Report (NIP-56)
+pub const KIND_REPORT: u64 = 1984;
+
+/// Report type, for reports that do not give one
+const UNSPECIFIED_TYPE: &str = "other";
+
+/// A report of a pubkey, or one of its events
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct Report {
+    /// Pubkey of the report's author
+    pub reporter: String,
+    /// Reported pubkey
+    pub pubkey: String,
+    /// Reported event, if any
+    pub event_id: Option<String>,
+    /// Type of report ("spam", "illegal", ...)
+    pub report_type: String,
+    pub created_at: u64,
+}
+
+impl Report {
+    /// Reports made by an event.  An event may report several
+    /// events, all by the pubkey in its first `p` tag, or if it
+    /// reports no events, several pubkeys.  Self-reports are ignored.
+    #[must_use]
+    pub fn from_event(event: &Event) -> Vec<Report> {
+        if event.kind != KIND_REPORT {
+            return vec![];
+        }
+        let targets = |name: &str| -> Vec<(String, Option<String>)> {
+            event
+                .tags
+                .iter()
+                .filter(|t| t.len() > 1 && t[0] == name && t[1].len() == 64 && is_lower_hex(&t[1]))
+                .map(|t| (t[1].clone(), t.get(2).filter(|r| !r.is_empty()).cloned()))
+                .collect()
+        };
+        let pubkeys = targets("p");
+        let events = targets("e");
+        let report = |pubkey: &str, event_id: Option<String>, report_type: Option<String>| Report {
+            reporter: event.pubkey.clone(),
+            pubkey: pubkey.to_owned(),
+            event_id,
+            report_type: report_type.unwrap_or_else(|| UNSPECIFIED_TYPE.to_owned()),
+            created_at: event.created_at,
+        };
+        let reports = match pubkeys.first() {
+            None => vec![],
+            Some((author, author_type)) if !events.is_empty() => events
+                .into_iter()
+                .map(|(id, t)| report(author, Some(id), t.or_else(|| author_type.clone())))
+                .collect(),
+            Some(_) => pubkeys
+                .into_iter()
+                .map(|(pk, t)| report(&pk, None, t))
+                .collect(),
+        };
+        reports
+            .into_iter()
+            .filter(|r| r.pubkey != event.pubkey)
+            .collect()
+    }
+}
+
+/// Reports of a pubkey, or one of its events
+#[derive(Serialize, PartialEq, Eq, Debug, Clone)]
+pub struct ReportSummary {
+    pub pubkey: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_id: Option<String>,
+    /// Distinct reporters
+    pub reporters: u64,
+    /// Reporters by report type
+    pub types: BTreeMap<String, u64>,
+    /// Time of the latest report
+    pub last_reported: u64,
+}
+
+/// Summarize reports by target, most reported first.
+#[must_use]
+pub fn summarize(reports: &[Report]) -> Vec<ReportSummary> {
+    let mut targets: HashMap<(&str, Option<&str>), ReportSummary> = HashMap::new();
+    for r in reports {
+        let s = targets
+            .entry((&r.pubkey, r.event_id.as_deref()))
+            .or_insert_with(|| ReportSummary {
+                pubkey: r.pubkey.clone(),
+                event_id: r.event_id.clone(),
+                reporters: 0,
+                types: BTreeMap::new(),
+                last_reported: 0,
+            });
+        s.reporters += 1;
+        *s.types.entry(r.report_type.clone()).or_default() += 1;
+        s.last_reported = s.last_reported.max(r.created_at);
+    }
+    let mut summaries: Vec<ReportSummary> = targets.into_values().collect();
+    summaries.sort_by(|a, b| {
+        b.reporters
+            .cmp(&a.reporters)
+            .then(b.last_reported.cmp(&a.last_reported))
+    });
+    summaries
+}
+
+/// Stored reports, and moderation based on them.
+pub struct Reports {
+    settings: config::Reports,
+    repo: Arc<dyn NostrRepo>,
+    reputations: Arc<Reputations>,
+}
+
+impl Reports {
+    #[must_use]
+    pub fn new(settings: &Settings, repo: Arc<dyn NostrRepo>, reputations: Arc<Reputations>) -> Self {
+        Reports {
+            settings: settings.reports.clone(),
+            repo,
+            reputations,
+        }
+    }
+
+    /// Check if a pubkey may list reports.
+    #[must_use]
+    pub fn is_admin(&self, pubkey: &str) -> bool {
+        self.settings
+            .admin_pubkeys
+            .as_ref()
+            .is_some_and(|pks| pks.iter().any(|pk| pk == pubkey))
+    }
+
+    /// Summaries of all stored reports.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the reports could not be loaded.
+    pub async fn summaries(&self) -> crate::error::Result<Vec<ReportSummary>> {
+        Ok(summarize(&self.repo.get_reports(None).await?))
+    }
+
+    /// Store the reports made by a newly stored event, hide events
+    /// that enough trusted reporters have reported, and rescore the
+    /// reported pubkeys.
+    pub async fn event_stored(&self, event: &Event) {
+        let rescore = self.reputations.settings().enabled;
+        if !(self.settings.enabled || rescore) || event.kind != KIND_REPORT {
+            return;
+        }
+        let mut reported = vec![];
+        for report in Report::from_event(event) {
+            if let Err(e) = self.repo.add_report(&report).await {
+                warn!("could not store report: {:?}", e);
+                continue;
+            }
+            if let (true, Some(id)) = (self.settings.enabled, &report.event_id) {
+                self.hide_if_reported(id).await;
+            }
+            if !reported.contains(&report.pubkey) {
+                reported.push(report.pubkey);
+            }
+        }
+        if rescore {
+            for pubkey in &reported {
+                self.reputations.reported(pubkey).await;
+            }
+        }
+    }
+
+    async fn is_trusted(&self, pubkey: &str) -> bool {
+        let listed = self
+            .settings
+            .trusted_reporters
+            .as_ref()
+            .is_some_and(|pks| pks.iter().any(|pk| pk == pubkey));
+        listed || self.reputations.standing(pubkey).await == Standing::Trusted
+    }
+
+    async fn hide_if_reported(&self, id: &str) {
+        let Some(threshold) = self.settings.hide_threshold.filter(|t| *t > 0) else {
+            return;
+        };
+        let reports = match self.repo.get_reports(Some(id)).await {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("could not load reports: {:?}", e);
+                return;
+            }
+        };
+        let mut trusted = 0;
+        for r in &reports {
+            if self.is_trusted(&r.reporter).await {
+                trusted += 1;
+            }
+        }
+        if trusted < threshold {
+            return;
+        }
+        match self.repo.hide_event(id).await {
+            Ok(true) => info!("hid event {:?} reported by {} trusted reporters", id, trusted),
+            Ok(false) => {}
+            Err(e) => warn!("could not hide reported event: {:?}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::memory::MemoryRepo;
+    use crate::reputation::ReputationChange;
+    use crate::server::create_metrics;
+
+    const ALICE: &str = "aa4fc8665f5696e33db7e1a572e3b0f5b3d615837b0f362dcb1c8068b098c7b4";
+    const BOB: &str = "bb4fc8665f5696e33db7e1a572e3b0f5b3d615837b0f362dcb1c8068b098c7b4";
+    const NOTE: &str = "ee4fc8665f5696e33db7e1a572e3b0f5b3d615837b0f362dcb1c8068b098c7b4";
+
+    fn report_event(reporter: &str, tags: Vec<Vec<&str>>) -> Event {
+        let mut e = Event::simple_event();
+        e.kind = KIND_REPORT;
+        e.pubkey = reporter.to_owned();
+        e.created_at = 100;
+        e.tags = tags
+            .into_iter()
+            .map(|t| t.into_iter().map(str::to_owned).collect())
+            .collect();
+        e
+    }
+
+    #[test]
+    fn reports_from_event() {
+        // a reported note is attributed to its author
+        let e = report_event(ALICE, vec![vec!["e", NOTE, "illegal"], vec!["p", BOB]]);
+        assert_eq!(
+            Report::from_event(&e),
+            vec![Report {
+                reporter: ALICE.to_owned(),
+                pubkey: BOB.to_owned(),
+                event_id: Some(NOTE.to_owned()),
+                report_type: "illegal".to_owned(),
+                created_at: 100,
+            }]
+        );
+        // self-reports and malformed pubkeys are ignored
+        let e = report_event(ALICE, vec![vec!["p", ALICE, "spam"], vec!["p", "xyz"], vec!["p", BOB]]);
+        let reports = Report::from_event(&e);
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].pubkey, BOB);
+        assert_eq!(reports[0].report_type, "other");
+    }
+
+    #[test]
+    fn reports_are_summarized_by_target() {
+        let mut reports = Report::from_event(&report_event(ALICE, vec![vec!["p", BOB, "spam"]]));
+        reports.extend(Report::from_event(&report_event(NOTE, vec![vec!["p", BOB, "spam"]])));
+        reports.extend(Report::from_event(&report_event(
+            ALICE,
+            vec![vec!["e", NOTE, "nudity"], vec!["p", BOB]],
+        )));
+        let summaries = summarize(&reports);
+        assert_eq!(summaries.len(), 2);
+        assert_eq!(summaries[0].event_id, None);
+        assert_eq!(summaries[0].reporters, 2);
+        assert_eq!(summaries[0].types.get("spam"), Some(&2));
+        assert_eq!(summaries[1].event_id.as_deref(), Some(NOTE));
+    }
+
+    #[tokio::test]
+    async fn reporters_count_once() {
+        let mut settings = Settings::default();
+        settings.reputation.enabled = true;
+        let (_, metrics) = create_metrics();
+        let repo: Arc<dyn NostrRepo> = Arc::new(MemoryRepo::new(&settings, metrics));
+        let reputations = Arc::new(Reputations::new(&settings, repo.clone()));
+        let reports = Reports::new(&settings, repo.clone(), reputations.clone());
+        let reports_of_bob = || async { repo.get_reputation(BOB).await.unwrap().reports };
+        // repeated reports from one pubkey count once
+        for _ in 0..3 {
+            reports
+                .event_stored(&report_event(ALICE, vec![vec!["p", BOB, "spam"]]))
+                .await;
+        }
+        reports
+            .event_stored(&report_event(ALICE, vec![vec!["e", NOTE], vec!["p", BOB]]))
+            .await;
+        assert_eq!(reports_of_bob().await, 1);
+        // untrusted reporters are not counted
+        repo.update_reputation(NOTE, ReputationChange::Spam)
+            .await
+            .unwrap();
+        reports
+            .event_stored(&report_event(NOTE, vec![vec!["p", BOB]]))
+            .await;
+        assert_eq!(reports_of_bob().await, 1);
+        assert_eq!(reputations.standing(BOB).await, Standing::Normal);
+    }
+}
@@ -0,0 +1,263 @@
+//! Reputation of event authors
+//!
+//! Each pubkey is scored by how long the relay has known it, whether
+//! it has a valid NIP-05 verification, how many others have reported
+//! it (NIP-56), and how often it has been caught sending spam.  The counts are stored
+//! in the database.  Trusted authors are spared rate limits and proof
+//! of work; untrusted authors face stricter ones.
+use crate::config::{self, Settings};
+use crate::event::Event;
+use crate::repo::NostrRepo;
+use crate::utils::unix_time;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// How long a computed score is used before it is looked up again.
+const CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Most cached scores before expired ones are removed.
+const CACHE_SIZE: usize = 10_000;
+
+/// Stored reputation of a pubkey
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Default)]
+pub struct ReputationRecord {
+    /// When the relay first stored an event from the pubkey
+    pub first_seen: Option<u64>,
+    /// Distinct reporters (NIP-56) of the pubkey, other than
+    /// untrusted ones
+    pub reports: u64,
+    /// Events from the pubkey rejected as spam
+    pub spam_hits: u64,
+}
+
+/// Change to a stored reputation
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ReputationChange {
+    /// An event was stored at this time; only the first is kept
+    Seen(u64),
+    /// The pubkey has been reported by this many counted reporters
+    Reported(u64),
+    Spam,
+}
+
+impl ReputationRecord {
+    /// Apply a change to the record.
+    pub fn apply(&mut self, change: ReputationChange) {
+        match change {
+            ReputationChange::Seen(t) => {
+                self.first_seen.get_or_insert(t);
+            }
+            ReputationChange::Reported(n) => self.reports = n,
+            ReputationChange::Spam => self.spam_hits += 1,
+        }
+    }
+
+    /// Score the record, as of the given time.
+    #[must_use]
+    pub fn score(&self, settings: &config::Reputation, verified: bool, now: u64) -> i64 {
+        let days = self.first_seen.map_or(0, |t| now.saturating_sub(t) / 86400);
+        let mut score = days.min(settings.max_age_days) as i64;
+        if verified {
+            score += settings.verified_points;
+        }
+        score -= self.reports as i64 * settings.report_penalty;
+        score -= self.spam_hits as i64 * settings.spam_penalty;
+        score
+    }
+}
+
+/// How an author is treated, according to their score
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Standing {
+    Trusted,
+    Normal,
+    Untrusted,
+}
+
+impl Standing {
+    #[must_use]
+    pub fn from_score(settings: &config::Reputation, score: i64) -> Standing {
+        if score >= settings.trusted_score {
+            Standing::Trusted
+        } else if score <= settings.untrusted_score {
+            Standing::Untrusted
+        } else {
+            Standing::Normal
+        }
+    }
+}
+
+struct Cached {
+    at: Instant,
+    record: ReputationRecord,
+    standing: Standing,
+}
+
+/// Author reputations, cached from the database.
+pub struct Reputations {
+    settings: config::Reputation,
+    verified_users: config::VerifiedUsers,
+    repo: Arc<dyn NostrRepo>,
+    cache: Mutex<HashMap<String, Cached>>,
+}
+
+impl Reputations {
+    #[must_use]
+    pub fn new(settings: &Settings, repo: Arc<dyn NostrRepo>) -> Self {
+        Reputations {
+            settings: settings.reputation.clone(),
+            verified_users: settings.verified_users.clone(),
+            repo,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    #[must_use]
+    pub fn settings(&self) -> &config::Reputation {
+        &self.settings
+    }
+
+    /// How many times an event counts against its author's rate limit.
+    #[must_use]
+    pub fn rate_cost(&self, standing: Standing) -> u32 {
+        match standing {
+            Standing::Trusted => 0,
+            Standing::Normal => 1,
+            Standing::Untrusted => self.settings.untrusted_rate_cost.max(1),
+        }
+    }
+
+    /// How an author should be treated.  All authors are treated
+    /// normally when reputation is disabled.
+    pub async fn standing(&self, pubkey: &str) -> Standing {
+        if !self.settings.enabled {
+            return Standing::Normal;
+        }
+        if let Some(c) = self.cache.lock().unwrap().get(pubkey) {
+            if c.at.elapsed() < CACHE_TTL {
+                return c.standing;
+            }
+        }
+        let record = match self.repo.get_reputation(pubkey).await {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("could not load reputation: {:?}", e);
+                return Standing::Normal;
+            }
+        };
+        let verified = self.verified_users.is_active()
+            && self
+                .repo
+                .get_latest_user_verification(pubkey)
+                .await
+                .is_ok_and(|uv| uv.is_valid(&self.verified_users));
+        let score = record.score(&self.settings, verified, unix_time());
+        let standing = Standing::from_score(&self.settings, score);
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= CACHE_SIZE {
+            cache.retain(|_, c| c.at.elapsed() < CACHE_TTL);
+        }
+        cache.insert(
+            pubkey.to_owned(),
+            Cached {
+                at: Instant::now(),
+                record,
+                standing,
+            },
+        );
+        standing
+    }
+
+    /// Record a change to an author's reputation.
+    pub async fn record(&self, pubkey: &str, change: ReputationChange) {
+        if !self.settings.enabled {
+            return;
+        }
+        {
+            let mut cache = self.cache.lock().unwrap();
+            // authors already seen need no update.
+            let seen = |c: &Cached| c.record.first_seen.is_some();
+            if matches!(change, ReputationChange::Seen(_)) && cache.get(pubkey).is_some_and(seen) {
+                return;
+            }
+            // rescore on next use.
+            cache.remove(pubkey);
+        }
+        if let Err(e) = self.repo.update_reputation(pubkey, change).await {
+            warn!("could not update reputation: {:?}", e);
+        }
+    }
+
+    /// Update reputations for a newly stored event: its author has
+    /// been seen.
+    pub async fn event_stored(&self, event: &Event) {
+        if !self.settings.enabled {
+            return;
+        }
+        self.record(&event.pubkey, ReputationChange::Seen(unix_time()))
+            .await;
+    }
+
+    /// Rescore a pubkey after it was reported, from its stored
+    /// reports.  Each reporter counts once, however many reports they
+    /// make, and untrusted reporters are not counted.
+    pub async fn reported(&self, pubkey: &str) {
+        if !self.settings.enabled {
+            return;
+        }
+        let reporters = match self.repo.get_reporters(pubkey).await {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("could not load reporters: {:?}", e);
+                return;
+            }
+        };
+        let mut counted = 0;
+        for reporter in &reporters {
+            if self.standing(reporter).await != Standing::Untrusted {
+                counted += 1;
+            }
+        }
+        self.record(pubkey, ReputationChange::Reported(counted))
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> config::Reputation {
+        Settings::default().reputation
+    }
+
+    #[test]
+    fn score_from_record() {
+        let s = settings();
+        let now = 1_000 * 86400;
+        let mut r = ReputationRecord::default();
+        assert_eq!(r.score(&s, false, now), 0);
+        r.apply(ReputationChange::Seen(now - 10 * 86400));
+        r.apply(ReputationChange::Seen(now));
+        assert_eq!(r.first_seen, Some(now - 10 * 86400));
+        assert_eq!(r.score(&s, false, now), 10);
+        assert_eq!(r.score(&s, true, now), 30);
+        // age points are capped
+        assert_eq!(r.score(&s, false, now + 100 * 86400), 30);
+        r.apply(ReputationChange::Reported(2));
+        r.apply(ReputationChange::Reported(1));
+        r.apply(ReputationChange::Spam);
+        assert_eq!(r.score(&s, false, now), -5);
+    }
+
+    #[test]
+    fn standing_from_score() {
+        let s = settings();
+        assert_eq!(Standing::from_score(&s, 30), Standing::Trusted);
+        assert_eq!(Standing::from_score(&s, 0), Standing::Normal);
+        assert_eq!(Standing::from_score(&s, -10), Standing::Untrusted);
+    }
+}
@@ -0,0 +1,322 @@
+//! Event retention policy
+//!
+//! Retention rules limit how long, and how many, events are kept.  An
+//! event is governed by the first configured rule that covers its kind
+//! and author, or otherwise by the relay-wide limits.  The policy is
+//! applied by a background task, which walks stored events from newest
+//! to oldest and removes any that exceed the limits of their rule, or
+//! that are older than the tier of their author keeps.
+use crate::config::{KindRange, PubkeyClass, RetentionRule, Settings};
+use crate::error::Result;
+use crate::event::is_replaceable_kind;
+use crate::repo::{EventSummary, NostrRepo, ScanOrder};
+use crate::tiers::Tiers;
+use std::collections::{HashMap, HashSet};
+use tracing::{debug, info, warn};
+
+/// Number of events examined at a time.
+const PAGE_SIZE: usize = 1000;
+
+/// Events examined per eviction step; the size is re-checked after each.
+const EVICT_BATCH: usize = 100;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    kinds: Option<Vec<KindRange>>,
+    authors: Option<PubkeyClass>,
+    /// Maximum age, in seconds
+    max_age: Option<u64>,
+    max_count: Option<usize>,
+}
+
+impl Rule {
+    fn from_config(r: &RetentionRule) -> Rule {
+        let max_age = r.max_age_duration().map(|d| d.as_secs());
+        if r.max_age.is_some() && max_age.is_none() {
+            warn!("ignoring unparseable retention max_age: {:?}", r.max_age);
+        }
+        Rule {
+            kinds: r.kinds.clone(),
+            authors: r.authors,
+            max_age,
+            max_count: r.max_count,
+        }
+    }
+
+    fn covers(&self, kind: u64, whitelisted: bool) -> bool {
+        let kind_match = self
+            .kinds
+            .as_ref()
+            .is_none_or(|ks| ks.iter().any(|k| k.contains(kind)));
+        let author_match = match self.authors {
+            None => true,
+            Some(PubkeyClass::Whitelisted) => whitelisted,
+            Some(PubkeyClass::Others) => !whitelisted,
+        };
+        kind_match && author_match
+    }
+
+    fn is_limited(&self) -> bool {
+        self.max_age.is_some() || self.max_count.is_some()
+    }
+}
+
+/// Retention rules, compiled from the configuration.
+#[derive(Debug, Clone)]
+pub struct RetentionPolicy {
+    rules: Vec<Rule>,
+    /// Pubkeys in the authorization whitelist
+    whitelist: HashSet<String>,
+    /// Pubkeys whose events are never removed
+    exempt: HashSet<String>,
+}
+
+impl RetentionPolicy {
+    #[must_use]
+    pub fn from_settings(settings: &Settings) -> RetentionPolicy {
+        let r = &settings.retention;
+        let mut rules: Vec<Rule> = r.rules.iter().flatten().map(Rule::from_config).collect();
+        // the relay-wide limits cover everything else
+        rules.push(Rule {
+            kinds: None,
+            authors: None,
+            max_age: r.persist_days.map(|d| d as u64 * 86400),
+            max_count: r.max_events,
+        });
+        RetentionPolicy {
+            rules,
+            whitelist: settings.authorization.pubkey_whitelist.iter().flatten().cloned().collect(),
+            exempt: r.whitelist_addresses.iter().flatten().cloned().collect(),
+        }
+    }
+
+    /// Are any events ever removed?
+    #[must_use]
+    pub fn is_active(&self) -> bool {
+        self.rules.iter().any(Rule::is_limited)
+    }
+
+    /// Is an event kept regardless of database size?  Only the latest
+    /// version of replaceable events is stored, so these are all kept,
+    /// along with events from whitelisted pubkeys.
+    fn is_protected(&self, e: &EventSummary) -> bool {
+        is_replaceable_kind(e.kind) || self.whitelist.contains(&e.pubkey) || self.exempt.contains(&e.pubkey)
+    }
+
+    /// Index of the rule governing an event.
+    fn rule_for(&self, e: &EventSummary) -> Option<usize> {
+        let whitelisted = self.whitelist.contains(&e.pubkey);
+        self.rules.iter().position(|r| r.covers(e.kind, whitelisted))
+    }
+}
+
+/// Tracks how many events each rule has kept, while walking events
+/// from newest to oldest.
+struct Pruner<'a> {
+    policy: &'a RetentionPolicy,
+    now: u64,
+    kept: Vec<usize>,
+}
+
+impl<'a> Pruner<'a> {
+    fn new(policy: &'a RetentionPolicy, now: u64) -> Self {
+        Pruner {
+            policy,
+            now,
+            kept: vec![0; policy.rules.len()],
+        }
+    }
+
+    /// Is an event older than its author's tier keeps?
+    fn expired_for_tier(&self, e: &EventSummary, oldest_kept: Option<&u64>) -> bool {
+        !self.policy.exempt.contains(&e.pubkey) && oldest_kept.is_some_and(|t| e.created_at < *t)
+    }
+
+    /// Should this event be removed?  Events must be presented newest
+    /// first.
+    fn expired(&mut self, e: &EventSummary) -> bool {
+        if self.policy.exempt.contains(&e.pubkey) {
+            return false;
+        }
+        let Some(i) = self.policy.rule_for(e) else {
+            return false;
+        };
+        let rule = &self.policy.rules[i];
+        let too_old = rule
+            .max_age
+            .is_some_and(|age| e.created_at < self.now.saturating_sub(age));
+        let too_many = rule.max_count.is_some_and(|max| self.kept[i] >= max);
+        if too_old || too_many {
+            true
+        } else {
+            self.kept[i] += 1;
+            false
+        }
+    }
+}
+
+/// Remove every event that the policy, or the tier of its author, does
+/// not retain, returning the number removed.
+pub async fn prune(repo: &dyn NostrRepo, policy: &RetentionPolicy, tiers: Option<&Tiers>, now: u64) -> Result<u64> {
+    let mut pruner = Pruner::new(policy, now);
+    let mut removed = 0;
+    let mut last: Option<EventSummary> = None;
+    loop {
+        let page = repo
+            .event_summaries(ScanOrder::NewestFirst, last.as_ref(), PAGE_SIZE)
+            .await?;
+        let oldest_kept = match tiers {
+            Some(t) => t.oldest_kept(&page, now).await,
+            None => HashMap::new(),
+        };
+        let expired: Vec<String> = page
+            .iter()
+            .filter(|e| pruner.expired_for_tier(e, oldest_kept.get(&e.pubkey)) || pruner.expired(e))
+            .map(|e| e.id.clone())
+            .collect();
+        if !expired.is_empty() {
+            removed += repo.delete_events(&expired).await?;
+        }
+        if page.len() < PAGE_SIZE {
+            break;
+        }
+        last = page.last().cloned();
+    }
+    debug!("retention removed {} events", removed);
+    Ok(removed)
+}
+
+/// Remove the oldest unprotected events until the database uses no
+/// more than `max_bytes`, returning the number removed.
+pub async fn evict_oldest(repo: &dyn NostrRepo, policy: &RetentionPolicy, max_bytes: u64) -> Result<u64> {
+    let mut removed = 0;
+    let mut last: Option<EventSummary> = None;
+    loop {
+        let used = repo.used_bytes().await?;
+        if used <= max_bytes {
+            break;
+        }
+        let page = repo
+            .event_summaries(ScanOrder::OldestFirst, last.as_ref(), EVICT_BATCH)
+            .await?;
+        if page.is_empty() {
+            warn!("database uses {} bytes, over the {} byte cap, but no events can be removed", used, max_bytes);
+            break;
+        }
+        let ids: Vec<String> = page
+            .iter()
+            .filter(|e| !policy.is_protected(e))
+            .map(|e| e.id.clone())
+            .collect();
+        if !ids.is_empty() {
+            removed += repo.delete_events(&ids).await?;
+        }
+        last = page.last().cloned();
+    }
+    if removed > 0 {
+        info!("removed {} of the oldest events, to stay under {} bytes", removed, max_bytes);
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(id: &str, pubkey: &str, kind: u64, created_at: u64) -> EventSummary {
+        EventSummary {
+            id: id.to_owned(),
+            pubkey: pubkey.to_owned(),
+            kind,
+            created_at,
+        }
+    }
+
+    fn rule(kinds: Option<Vec<KindRange>>, max_age: Option<&str>, max_count: Option<usize>) -> RetentionRule {
+        RetentionRule {
+            kinds,
+            authors: None,
+            max_age: max_age.map(str::to_owned),
+            max_count,
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_applies() {
+        let mut settings = Settings::default();
+        settings.retention.persist_days = Some(1);
+        settings.retention.rules = Some(vec![
+            // metadata is kept forever
+            rule(Some(vec![KindRange::Single(0), KindRange::Range([10000, 19999])]), None, None),
+            rule(Some(vec![KindRange::Range([0, 9])]), Some("1 hour"), None),
+        ]);
+        let policy = RetentionPolicy::from_settings(&settings);
+        assert!(policy.is_active());
+        let now = 1_000_000;
+        let mut p = Pruner::new(&policy, now);
+        assert!(!p.expired(&summary("a", "x", 0, 0)));
+        assert!(!p.expired(&summary("b", "x", 10002, 0)));
+        assert!(!p.expired(&summary("c", "x", 1, now - 60)));
+        assert!(p.expired(&summary("d", "x", 1, now - 7200)));
+        // the relay-wide limit covers other kinds
+        assert!(!p.expired(&summary("e", "x", 30023, now - 7200)));
+        assert!(p.expired(&summary("f", "x", 30023, now - 2 * 86400)));
+    }
+
+    #[test]
+    fn counts_and_exemptions() {
+        let mut settings = Settings::default();
+        settings.authorization.pubkey_whitelist = Some(vec!["w".to_owned()]);
+        settings.retention.whitelist_addresses = Some(vec!["keep".to_owned()]);
+        settings.retention.rules = Some(vec![RetentionRule {
+            authors: Some(PubkeyClass::Others),
+            ..rule(None, None, Some(2))
+        }]);
+        let policy = RetentionPolicy::from_settings(&settings);
+        let mut p = Pruner::new(&policy, 100);
+        let expired: Vec<bool> = [
+            summary("1", "o", 1, 10),
+            summary("2", "keep", 1, 9),
+            summary("3", "w", 1, 8),
+            summary("4", "o", 1, 7),
+            summary("5", "o", 1, 6),
+            summary("6", "w", 1, 5),
+        ]
+        .iter()
+        .map(|e| p.expired(e))
+        .collect();
+        assert_eq!(expired, vec![false, false, false, false, true, false]);
+        // tier retention spares exempt pubkeys
+        assert!(p.expired_for_tier(&summary("7", "o", 1, 5), Some(&6)));
+        assert!(!p.expired_for_tier(&summary("8", "o", 1, 5), Some(&5)));
+        assert!(!p.expired_for_tier(&summary("9", "keep", 1, 5), Some(&6)));
+        assert!(!p.expired_for_tier(&summary("10", "o", 1, 5), None));
+        // nothing is removed by default
+        assert!(!RetentionPolicy::from_settings(&Settings::default()).is_active());
+    }
+
+    #[test]
+    fn protected_from_eviction() {
+        let mut settings = Settings::default();
+        settings.authorization.pubkey_whitelist = Some(vec!["w".to_owned()]);
+        settings.retention.whitelist_addresses = Some(vec!["keep".to_owned()]);
+        let policy = RetentionPolicy::from_settings(&settings);
+        assert!(!policy.is_protected(&summary("a", "x", 1, 0)));
+        assert!(policy.is_protected(&summary("b", "x", 0, 0)));
+        assert!(policy.is_protected(&summary("c", "x", 10002, 0)));
+        assert!(policy.is_protected(&summary("d", "x", 30023, 0)));
+        assert!(policy.is_protected(&summary("e", "w", 1, 0)));
+        assert!(policy.is_protected(&summary("f", "keep", 1, 0)));
+    }
+
+    #[test]
+    fn kind_ranges_from_config() {
+        let r: RetentionRule =
+            serde_json::from_str(r#"{"kinds":[1,[30000,39999]],"authors":"whitelisted","max_age":"2 days"}"#)
+                .unwrap();
+        assert_eq!(r.kinds, Some(vec![KindRange::Single(1), KindRange::Range([30000, 39999])]));
+        assert_eq!(r.authors, Some(PubkeyClass::Whitelisted));
+        assert_eq!(Rule::from_config(&r).max_age, Some(2 * 86400));
+        assert!(r.kinds.unwrap()[1].contains(30023));
+    }
+}
@@ -1,33 +1,76 @@
 //! Server process
 use crate::close::Close;
 use crate::close::CloseCmd;
-use crate::config::{Settings, VerifiedUsersMode};
+use crate::cluster;
+use crate::config::{Listener, Settings, VerifiedUsersMode};
 use crate::conn;
+use crate::connlimit::{ConnectionLimits, ConnectionPermit};
 use crate::db;
 use crate::db::SubmittedEvent;
 use crate::error::{Error, Result};
-use crate::event::Event;
+use crate::event::{BroadcastEvent, Event};
 use crate::event::EventCmd;
+use crate::event::EventWrapper;
+use crate::access::{AccessRules, Refusal};
+use crate::admin::AdminChannel;
+use crate::announce;
+use crate::http_client;
+use crate::inject;
+use crate::relay::{Embedding, RelayParts};
+use crate::backup;
+use crate::bans::{Abuse, BanRegistry, BanTarget};
+use crate::groups::GroupRegistry;
+use crate::health::HealthChecks;
+use crate::identity::{self, RelayIdentity};
 use crate::info::RelayInfo;
+use crate::labels::{self, TopLabels};
+use crate::maintenance;
+use crate::matcher::Matcher;
+use crate::media::{self, MediaStore};
+use crate::message::OutboundMessage;
+use crate::mirror;
+use crate::nauthz::{self, AdmissionClient};
+use crate::negentropy::{self, NegClose, NegMsg, NegOpen, NegentropyStorage};
 use crate::nip05;
-use crate::notice::Notice;
-use crate::repo::NostrRepo;
-use crate::subscription::Subscription;
-use futures::SinkExt;
+use crate::nip65::RelayList;
+use crate::notice::{EventResultStatus, Notice};
+use crate::outbox::Outbox;
+use crate::payment::{self, Payments};
+use crate::ratelimit::EventRateLimiter;
+use crate::reload;
+use crate::replication;
+use crate::repo::{self, NostrRepo};
+use crate::reports::Reports;
+use crate::reputation::{Reputations, Standing};
+use crate::stats::{ConnectionStats, RateLimits, RelayCmd};
+use crate::status::{self, RelayStatus};
+use crate::subscription::{CountCmd, Subscription};
+use crate::throttle::Throttle;
+use crate::tls::{self, TlsConn, TlsIncoming};
+use crate::tiers::{TierRefusal, Tiers};
+use crate::utils::is_lower_hex;
+use crate::verify::SignatureVerifier;
+use crate::whitelist::{self, Whitelist};
+use futures::future::{join_all, LocalBoxFuture};
 use futures::StreamExt;
+use governor::clock::DefaultClock;
+use governor::state::{InMemoryState, NotKeyed};
 use governor::{Jitter, Quota, RateLimiter};
 use http::header::HeaderMap;
 use hyper::header::ACCEPT;
+use hyper::body::HttpBody;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::upgrade::Upgraded;
 use hyper::{
-    header, server::conn::AddrStream, upgrade, Body, Request, Response, Server, StatusCode,
+    header, server::conn::AddrStream, upgrade, Body, Method, Request, Response, Server, StatusCode,
 };
 use prometheus::IntCounterVec;
 use prometheus::IntGauge;
 use prometheus::{Encoder, Histogram, HistogramOpts, IntCounter, Opts, Registry, TextEncoder};
+use serde::de::IgnoredAny;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use socket2::{Domain, Socket, Type};
 use std::collections::HashMap;
 use std::convert::Infallible;
 use std::net::SocketAddr;
@@ -38,30 +81,70 @@ use std::sync::Arc;
 use std::time::Duration;
 use std::time::Instant;
 use tokio::runtime::Builder;
-use tokio::sync::broadcast::{self, Receiver, Sender};
+use tokio::sync::broadcast::{self, Receiver};
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
+use tokio::sync::watch;
 use tokio_tungstenite::WebSocketStream;
-use tracing::{debug, error, info, trace, warn};
+use tracing::{debug, error, field, info, info_span, trace, warn, Instrument};
 use tungstenite::error::CapacityError::MessageTooLong;
 use tungstenite::error::Error as WsError;
 use tungstenite::handshake;
 use tungstenite::protocol::Message;
 use tungstenite::protocol::WebSocketConfig;
 
+/// The client's IP address, from the configured header if present,
+/// or else the socket address.
+fn client_ip(request: &Request<Body>, settings: &Settings, remote_addr: SocketAddr) -> String {
+    settings
+        .network
+        .remote_ip_header
+        .as_ref()
+        .and_then(|x| get_header_string(x, request.headers()))
+        .unwrap_or_else(|| remote_addr.ip().to_string())
+}
+
+/// Authors listed on the dashboard.
+const TOP_AUTHORS: usize = 20;
+
+/// Most events returned by each filter of a REST query.
+const MAX_QUERY_EVENTS: u64 = 500;
+
+/// Longest an event published over HTTP waits to be written.
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Most bytes read from a request to inject events
+const MAX_INJECT_BYTES: usize = 16 * 1024 * 1024;
+
 /// Handle arbitrary HTTP requests, including for `WebSocket` upgrades.
 #[allow(clippy::too_many_arguments)]
 async fn handle_web_request(
     mut request: Request<Body>,
     repo: Arc<dyn NostrRepo>,
-    settings: Settings,
+    settings_rx: watch::Receiver<Settings>,
     remote_addr: SocketAddr,
-    broadcast: Sender<Event>,
+    serve_metrics: bool,
+    matcher: Matcher,
+    verifier: SignatureVerifier,
+    conn_limits: ConnectionLimits,
+    event_limiter: Arc<EventRateLimiter>,
+    access: Arc<AccessRules>,
+    bans: Arc<BanRegistry>,
+    reputations: Arc<Reputations>,
+    reports: Arc<Reports>,
+    status: Arc<RelayStatus>,
+    health: Arc<HealthChecks>,
+    admin: Option<Arc<AdminChannel>>,
+    payments: Option<Arc<Payments>>,
+    tiers: Option<Arc<Tiers>>,
     event_tx: tokio::sync::mpsc::Sender<SubmittedEvent>,
     shutdown: Receiver<()>,
     registry: Registry,
     metrics: NostrMetrics,
+    groups: Arc<GroupRegistry>,
+    admission: Option<AdmissionClient>,
 ) -> Result<Response<Body>, Infallible> {
+    let settings = settings_rx.borrow().clone();
     match (
         request.uri().path(),
         request.headers().contains_key(header::UPGRADE),
@@ -69,6 +152,53 @@ async fn handle_web_request(
         // Request for / as websocket
         ("/", true) => {
             trace!("websocket with upgrade request");
+            let remote_ip = client_ip(&request, &settings, remote_addr);
+            // refuse connections from banned addresses
+            if bans.is_banned(BanTarget::Ip, &remote_ip) {
+                info!("refusing connection from banned address {}", remote_ip);
+                metrics.rejected_connections.with_label_values(&["banned"]).inc();
+                return Ok(Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .header("Content-Type", "text/plain")
+                    .body(Body::from("this address is banned"))
+                    .unwrap());
+            }
+            // refuse clients by their headers, if configured
+            let origin = get_header_string("origin", request.headers());
+            let user_agent = get_header_string("user-agent", request.headers());
+            if let Err(refusal) = access.check(origin.as_deref(), user_agent.as_deref()) {
+                info!(
+                    "refusing connection from {} ({:?}, {:?}): {}",
+                    remote_ip, origin, user_agent, refusal.label()
+                );
+                metrics
+                    .rejected_connections
+                    .with_label_values(&[refusal.label()])
+                    .inc();
+                let status = match refusal {
+                    Refusal::Blocked => StatusCode::FORBIDDEN,
+                    Refusal::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+                };
+                return Ok(http_error(status, refusal.message()));
+            }
+            // refuse connections over the configured limits
+            let permit = match conn_limits.admit(&remote_ip) {
+                Ok(permit) => permit,
+                Err(rejection) => {
+                    info!("refusing connection from {}: {}", remote_ip, rejection.label());
+                    metrics
+                        .rejected_connections
+                        .with_label_values(&[rejection.label()])
+                        .inc();
+                    return Ok(Response::builder()
+                        .status(StatusCode::SERVICE_UNAVAILABLE)
+                        .header("Content-Type", "text/plain")
+                        .body(Body::from(rejection.message()))
+                        .unwrap());
+                }
+            };
+            // the connection's fields are included in everything it logs
+            let span = info_span!("conn", ip = %remote_ip, cid = field::Empty, pubkey = field::Empty);
             //assume request is a handshake, so create the handshake response
             let response = match handshake::server::create_response_with_body(&request, || {
                 Body::empty()
@@ -97,33 +227,39 @@ async fn handle_web_request(
                                     Some(config),
                                 )
                                 .await;
-                                let origin = get_header_string("origin", request.headers());
-                                let user_agent = get_header_string("user-agent", request.headers());
-                                // determine the remote IP from headers if the exist
-                                let header_ip = settings
-                                    .network
-                                    .remote_ip_header
-                                    .as_ref()
-                                    .and_then(|x| get_header_string(x, request.headers()));
-                                // use the socket addr as a backup
-                                let remote_ip =
-                                    header_ip.unwrap_or_else(|| remote_addr.ip().to_string());
                                 let client_info = ClientInfo {
                                     remote_ip,
                                     user_agent,
                                     origin,
                                 };
-                                // spawn a nostr server with our websocket
-                                tokio::spawn(nostr_server(
+                                // spawn a nostr server with our websocket,
+                                // counted until it ends
+                                let server = nostr_server(
                                     repo,
                                     client_info,
-                                    settings,
+                                    settings_rx,
                                     ws_stream,
-                                    broadcast,
+                                    matcher,
+                                    verifier,
+                                    event_limiter,
+                                    bans,
+                                    reputations,
+                                    admin,
+                                    status,
+                                    tiers,
                                     event_tx,
                                     shutdown,
                                     metrics,
-                                ));
+                                    groups,
+                                    admission,
+                                );
+                                tokio::spawn(
+                                    async move {
+                                        server.await;
+                                        drop(permit);
+                                    }
+                                    .instrument(span),
+                                );
                             }
                             // todo: trace, don't print...
                             Err(e) => println!(
@@ -157,7 +293,7 @@ async fn handle_web_request(
                     if mt_str.contains("application/nostr+json") {
                         // build a relay info response
                         debug!("Responding to server info request");
-                        let rinfo = RelayInfo::from(settings.info);
+                        let rinfo = RelayInfo::from(settings);
                         let b = Body::from(serde_json::to_string_pretty(&rinfo).unwrap());
                         return Ok(Response::builder()
                             .status(200)
@@ -174,7 +310,23 @@ async fn handle_web_request(
                 .body(Body::from("Please use a Nostr client to connect."))
                 .unwrap())
         }
-        ("/metrics", false) => {
+        // Liveness and readiness, for probes
+        (path @ ("/healthz" | "/readyz"), false) => {
+            let health = health.check(path == "/readyz").await;
+            let status = if health.ok {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            };
+            Ok(Response::builder()
+                .status(status)
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_string(&health).unwrap()))
+                .unwrap())
+        }
+        ("/metrics", false) if serve_metrics => {
+            // the write queue is measured when scraped.
+            metrics.write_queue.set((event_tx.max_capacity() - event_tx.capacity()) as i64);
             let mut buffer = vec![];
             let encoder = TextEncoder::new();
             let metric_families = registry.gather();
@@ -186,6 +338,220 @@ async fn handle_web_request(
                 .body(Body::from(buffer))
                 .unwrap())
         }
+        // Subscriptions and publishing over plain HTTP
+        ("/events", false) if settings.network.http_transport && request.method() == Method::GET => {
+            let remote_ip = client_ip(&request, &settings, remote_addr);
+            if bans.is_banned(BanTarget::Ip, &remote_ip) {
+                metrics.rejected_connections.with_label_values(&["banned"]).inc();
+                return Ok(http_error(StatusCode::FORBIDDEN, "this address is banned"));
+            }
+            let permit = match conn_limits.admit(&remote_ip) {
+                Ok(permit) => permit,
+                Err(rejection) => {
+                    info!("refusing event stream for {}: {}", remote_ip, rejection.label());
+                    metrics
+                        .rejected_connections
+                        .with_label_values(&[rejection.label()])
+                        .inc();
+                    return Ok(http_error(StatusCode::SERVICE_UNAVAILABLE, rejection.message()));
+                }
+            };
+            let mut conn = conn::ClientConn::new(remote_ip);
+            conn.set_limits(&settings.limits);
+            let span = http_span(&conn);
+            let query = request.uri().query().unwrap_or_default();
+            Ok(handle_event_stream(query, conn, permit, repo, settings, matcher, shutdown, metrics, groups)
+                .instrument(span)
+                .await)
+        }
+        ("/publish", false) if settings.network.http_transport && request.method() == Method::POST => {
+            let remote_ip = client_ip(&request, &settings, remote_addr);
+            if bans.is_banned(BanTarget::Ip, &remote_ip) {
+                metrics.rejected_connections.with_label_values(&["banned"]).inc();
+                return Ok(http_error(StatusCode::FORBIDDEN, "this address is banned"));
+            }
+            let mut conn = conn::ClientConn::new(remote_ip);
+            conn.set_limits(&settings.limits);
+            conn.set_tier(tiers.as_ref().and_then(|t| t.default_tier()));
+            let span = http_span(&conn);
+            Ok(handle_publish(
+                request,
+                conn,
+                &repo,
+                &settings,
+                &verifier,
+                &event_limiter,
+                &bans,
+                &reputations,
+                admin.as_deref(),
+                &status,
+                &event_tx,
+                &metrics,
+            )
+            .instrument(span)
+            .await)
+        }
+        (path, false)
+            if settings.network.http_transport
+                && request.method() == Method::GET
+                && (path == "/req" || path.starts_with("/e/")) =>
+        {
+            let remote_ip = client_ip(&request, &settings, remote_addr);
+            if bans.is_banned(BanTarget::Ip, &remote_ip) {
+                metrics.rejected_connections.with_label_values(&["banned"]).inc();
+                return Ok(http_error(StatusCode::FORBIDDEN, "this address is banned"));
+            }
+            let mut conn = conn::ClientConn::new(remote_ip);
+            conn.set_limits(&settings.limits);
+            let span = http_span(&conn);
+            Ok(handle_rest_query(&request, conn, &repo, &settings, &metrics, &groups)
+                .instrument(span)
+                .await)
+        }
+        // Request for a relay list (NIP-65)
+        (path, false) if path.starts_with("/relay-lists/") => {
+            let pubkey = path.trim_start_matches("/relay-lists/");
+            if pubkey.len() != 64 || !is_lower_hex(pubkey) {
+                return Ok(Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::from("Invalid pubkey."))
+                    .unwrap());
+            }
+            let relay_list = match repo.get_relay_list(pubkey).await {
+                Ok(Some(e)) => RelayList::try_from(&e).ok(),
+                Ok(None) => None,
+                Err(e) => {
+                    warn!("relay list query failed: {:?}", e);
+                    return Ok(Response::builder()
+                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                        .body(Body::from("Relay list could not be retrieved."))
+                        .unwrap());
+                }
+            };
+            match relay_list {
+                Some(rl) => Ok(Response::builder()
+                    .status(StatusCode::OK)
+                    .header("Content-Type", "application/json")
+                    .header("Access-Control-Allow-Origin", "*")
+                    .body(Body::from(serde_json::to_string(&rl).unwrap()))
+                    .unwrap()),
+                None => Ok(Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::from("No relay list found."))
+                    .unwrap()),
+            }
+        }
+        // Aggregated reports (NIP-56), for relay admins
+        ("/admin/reports", false) if settings.reports.enabled && request.method() == Method::GET => {
+            Ok(handle_admin_reports(&request, &settings, &reports).await)
+        }
+        // Relay dashboard, for admins
+        ("/dashboard", false) if settings.admin.dashboard => match media::relay_http_url(&settings) {
+            Some(base) => Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "text/html; charset=utf-8")
+                .body(Body::from(status::dashboard_page(&base)))
+                .unwrap()),
+            None => Ok(http_error(StatusCode::INTERNAL_SERVER_ERROR, "relay URL is not configured")),
+        },
+        ("/admin/status" | "/admin/status/authors" | "/admin/status/refusals", false)
+            if settings.admin.dashboard && request.method() == Method::GET =>
+        {
+            Ok(handle_admin_status(&request, &settings, &repo, &status, &conn_limits).await)
+        }
+        // Events from trusted local services
+        ("/inject", false) if settings.injection.secret.is_some() && request.method() == Method::POST => {
+            Ok(handle_inject(request, &settings, remote_addr, &event_tx).await)
+        }
+        // Immediate backup, for admins
+        ("/admin/backup", false) if request.method() == Method::POST => {
+            Ok(handle_admin_backup(&request, &settings, &repo).await)
+        }
+        // Paid admission
+        ("/join", false) if payments.is_some() => Ok(handle_join(&request, payments.as_deref().unwrap()).await),
+        (path, false) if payments.is_some() && path.starts_with("/lnurlp/") => {
+            Ok(handle_lnurl(&request, payments.as_deref().unwrap()).await)
+        }
+        // File uploads (NIP-96)
+        ("/upload", false)
+            if (settings.media.enabled || settings.media.blossom)
+                && request.method() == Method::OPTIONS =>
+        {
+            Ok(media_preflight())
+        }
+        ("/upload", false) if settings.media.enabled && request.method() == Method::POST => {
+            Ok(handle_upload(request, &settings).await)
+        }
+        // Blob uploads (Blossom)
+        ("/upload", false) if settings.media.blossom && request.method() == Method::PUT => {
+            Ok(handle_blob_upload(request, &settings).await)
+        }
+        ("/.well-known/nostr/nip96.json", false) if settings.media.enabled => {
+            let base = media::base_url(&settings).unwrap_or_default();
+            let info = json!({
+                "api_url": format!("{base}/upload"),
+                "download_url": format!("{base}/media"),
+            });
+            Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "application/json")
+                .header("Access-Control-Allow-Origin", "*")
+                .body(Body::from(info.to_string()))
+                .unwrap())
+        }
+        // Request for an uploaded file
+        (path, false) if settings.media.enabled && path.starts_with("/media/") => {
+            let hash = path.trim_start_matches("/media/");
+            match MediaStore::new(&settings).load(hash).await {
+                Ok(Some((data, mime))) => Ok(Response::builder()
+                    .status(StatusCode::OK)
+                    .header("Content-Type", mime)
+                    .header("Access-Control-Allow-Origin", "*")
+                    .body(Body::from(data))
+                    .unwrap()),
+                Ok(None) => Ok(Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::from("File not found."))
+                    .unwrap()),
+                Err(e) => {
+                    warn!("could not read media file: {:?}", e);
+                    Ok(Response::builder()
+                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                        .body(Body::from("File could not be retrieved."))
+                        .unwrap())
+                }
+            }
+        }
+        // Request for a blob (Blossom)
+        (path, false) if settings.media.blossom && media::blob_hash(path).is_some() => {
+            let hash = media::blob_hash(path).unwrap_or_default();
+            match *request.method() {
+                Method::GET | Method::HEAD => {
+                    match MediaStore::new(&settings).load(hash).await {
+                        Ok(Some((data, mime))) => {
+                            let res = Response::builder()
+                                .status(StatusCode::OK)
+                                .header("Content-Type", mime)
+                                .header("Content-Length", data.len())
+                                .header("Access-Control-Allow-Origin", "*");
+                            if request.method() == Method::HEAD {
+                                Ok(res.body(Body::empty()).unwrap())
+                            } else {
+                                Ok(res.body(Body::from(data)).unwrap())
+                            }
+                        }
+                        Ok(None) => Ok(blob_error(StatusCode::NOT_FOUND, "blob not found")),
+                        Err(e) => {
+                            warn!("could not read blob: {:?}", e);
+                            Ok(blob_error(StatusCode::INTERNAL_SERVER_ERROR, "blob could not be retrieved"))
+                        }
+                    }
+                }
+                Method::DELETE => Ok(handle_blob_delete(&request, &settings, hash).await),
+                Method::OPTIONS => Ok(media_preflight()),
+                _ => Ok(blob_error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")),
+            }
+        }
         (_, _) => {
             //handle any other url
             Ok(Response::builder()
@@ -196,14 +562,800 @@ async fn handle_web_request(
     }
 }
 
+/// The join page for paid admission: a form asking for a pubkey, an
+/// invoice to admit one, or the balance of an admitted pubkey.
+async fn handle_join(request: &Request<Body>, payments: &Payments) -> Response<Body> {
+    let html = |status: StatusCode, page: String| {
+        Response::builder()
+            .status(status)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(Body::from(page))
+            .unwrap()
+    };
+    let query = request.uri().query().unwrap_or_default();
+    let Some(value) = payment::query_param(query, "pubkey").filter(|v| !v.is_empty()) else {
+        return html(StatusCode::OK, payments.join_form(None));
+    };
+    let Some(pubkey) = payment::parse_pubkey(value) else {
+        return html(StatusCode::BAD_REQUEST, payments.join_form(Some("That is not a valid pubkey.")));
+    };
+    if payments.is_admitted(&pubkey).await {
+        let Some(topup) = payment::query_param(query, "topup").filter(|t| !t.is_empty()) else {
+            return html(StatusCode::OK, payments.admitted_page(&pubkey).await);
+        };
+        let amount = topup.parse::<u64>().unwrap_or_default().saturating_mul(1000);
+        if let Some(msg) = payments.amount_error(&pubkey, amount).await {
+            return html(StatusCode::BAD_REQUEST, payments.join_form(Some(&msg)));
+        }
+        return match payments.topup_invoice(&pubkey, amount).await {
+            Ok(invoice) => html(StatusCode::OK, payments.invoice_page(&invoice)),
+            Err(e) => {
+                warn!("could not issue top-up invoice: {:?}", e);
+                html(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    payments.join_form(Some("An invoice could not be created.  Please try again later.")),
+                )
+            }
+        };
+    }
+    match payments.admission_invoice(&pubkey).await {
+        Ok(Some(invoice)) => html(StatusCode::OK, payments.invoice_page(&invoice)),
+        Ok(None) => html(StatusCode::OK, payments.admitted_page(&pubkey).await),
+        Err(e) => {
+            warn!("could not issue admission invoice: {:?}", e);
+            html(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                payments.join_form(Some("An invoice could not be created.  Please try again later.")),
+            )
+        }
+    }
+}
+
+/// LNURL-pay (LUD-06) for paid admission and top-ups: a pay request
+/// at `/lnurlp/<pubkey>`, and invoices from its callback.
+async fn handle_lnurl(request: &Request<Body>, payments: &Payments) -> Response<Body> {
+    let json = |body: serde_json::Value| {
+        Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .header("Access-Control-Allow-Origin", "*")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+    let lnurl_error = |reason: &str| json(json!({"status": "ERROR", "reason": reason}));
+    let path = request.uri().path().trim_start_matches("/lnurlp/");
+    let (pubkey, callback) = match path.strip_suffix("/callback") {
+        Some(pk) => (pk, true),
+        None => (path, false),
+    };
+    let Some(pubkey) = payment::parse_pubkey(pubkey) else {
+        return lnurl_error("invalid pubkey");
+    };
+    if !callback {
+        return json(payments.lnurl_pay_request(&pubkey).await);
+    }
+    let query = request.uri().query().unwrap_or_default();
+    let Some(amount) = payment::query_param(query, "amount").and_then(|a| a.parse::<u64>().ok()) else {
+        return lnurl_error("missing amount");
+    };
+    if let Some(msg) = payments.amount_error(&pubkey, amount).await {
+        return lnurl_error(&msg);
+    }
+    match payments.lnurl_invoice(&pubkey, amount).await {
+        Ok(invoice) => json(json!({"pr": invoice.bolt11, "routes": []})),
+        Err(e) => {
+            warn!("could not issue invoice: {:?}", e);
+            lnurl_error("an invoice could not be created")
+        }
+    }
+}
+
+/// List aggregated reports for an admin, authorized with an HTTP
+/// auth event (NIP-98).
+async fn handle_admin_reports(request: &Request<Body>, settings: &Settings, reports: &Reports) -> Response<Body> {
+    let text = |status: StatusCode, msg: &'static str| {
+        Response::builder()
+            .status(status)
+            .header("Content-Type", "text/plain")
+            .body(Body::from(msg))
+            .unwrap()
+    };
+    let pubkey = match http_auth_pubkey(request, settings) {
+        Ok(pk) => pk,
+        Err(res) => return *res,
+    };
+    if !reports.is_admin(&pubkey) {
+        return text(StatusCode::FORBIDDEN, "pubkey is not an admin");
+    }
+    match reports.summaries().await {
+        Ok(summaries) => Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::to_string(&summaries).unwrap()))
+            .unwrap(),
+        Err(e) => {
+            warn!("could not load reports: {:?}", e);
+            text(StatusCode::INTERNAL_SERVER_ERROR, "reports could not be retrieved")
+        }
+    }
+}
+
+/// Stream the events a subscription would receive as Server-Sent
+/// Events, for clients that cannot use websockets.  Filters are a JSON
+/// array in the `filters` query parameter, with an optional
+/// subscription `id`, and each message carries the `EVENT` or `EOSE`
+/// message a websocket client would receive.
+#[allow(clippy::too_many_arguments)]
+async fn handle_event_stream(
+    query: &str,
+    mut conn: conn::ClientConn,
+    permit: ConnectionPermit,
+    repo: Arc<dyn NostrRepo>,
+    settings: Settings,
+    matcher: Matcher,
+    shutdown: Receiver<()>,
+    metrics: NostrMetrics,
+    groups: Arc<GroupRegistry>,
+) -> Response<Body> {
+    let params: HashMap<String, String> = form_urlencoded::parse(query.as_bytes()).into_owned().collect();
+    let Some(filters) = params.get("filters") else {
+        return http_error(StatusCode::BAD_REQUEST, "missing filters");
+    };
+    let id = params.get("id").map_or("sse", String::as_str);
+    let mut sub = match Subscription::from_filters(id, filters) {
+        Ok(s) => s,
+        Err(e) => return http_error(StatusCode::BAD_REQUEST, &format!("invalid filters: {e}")),
+    };
+    sub.clamp_limits(settings.limits.max_limit);
+    metrics.cmd_req.inc();
+    // event streams cannot authenticate
+    let auth = &settings.authorization;
+    if auth.nip42_auth && only_private_messages(&sub, auth.private_inbox, auth.dm_read_protection) {
+        return http_error(
+            StatusCode::FORBIDDEN,
+            "private messages are only served to their participants",
+        );
+    }
+    if let Err(e) = conn.subscribe(sub.clone()) {
+        info!(sub_id = %sub.id, error = %e, "subscription error");
+        return http_error(StatusCode::BAD_REQUEST, &e.to_string());
+    }
+    let (sender, body) = Body::channel();
+    tokio::spawn(
+        async move {
+            stream_events(sender, conn, sub, repo, settings, matcher, shutdown, metrics, groups).await;
+            drop(permit);
+        }
+        .in_current_span(),
+    );
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .header("Access-Control-Allow-Origin", "*")
+        .body(body)
+        .unwrap()
+}
+
+/// Span for a client using plain HTTP, with the same fields as a
+/// websocket connection.
+fn http_span(conn: &conn::ClientConn) -> tracing::Span {
+    info_span!("conn", ip = %conn.ip(), cid = %conn.get_client_prefix(), pubkey = field::Empty)
+}
+
+/// Send stored, then new, events matching a subscription to an event
+/// stream, until the client goes away or the relay shuts down.
+#[allow(clippy::too_many_arguments)]
+async fn stream_events(
+    mut sender: hyper::body::Sender,
+    conn: conn::ClientConn,
+    sub: Subscription,
+    repo: Arc<dyn NostrRepo>,
+    settings: Settings,
+    matcher: Matcher,
+    mut shutdown: Receiver<()>,
+    metrics: NostrMetrics,
+    groups: Arc<GroupRegistry>,
+) {
+    let orig_start = Instant::now();
+    let cid = conn.get_client_prefix();
+    info!(sub_id = %sub.id, "new event stream");
+    metrics.connections.inc();
+    let private_inbox = settings.authorization.private_inbox;
+    let dm_read_protection = settings.authorization.dm_read_protection;
+    let sub_id = sub.id.clone();
+    let (matcher_conn, mut matched_rx) = matcher.connect(settings.limits.broadcast_buffer);
+    matcher_conn.subscribe(sub.clone());
+    let (query_tx, mut query_rx) = mpsc::channel::<db::QueryResult>(20_000);
+    let (abandon_query_tx, abandon_query_rx) = oneshot::channel::<()>();
+    if sub.needs_historical_events() {
+        repo.query_subscription(sub, cid.clone(), query_tx, abandon_query_rx).await.ok();
+    }
+    // comments keep proxies from closing a quiet stream, and find
+    // clients that have gone away.
+    let keepalive_dur = Duration::from_secs(settings.network.ping_interval_seconds.into());
+    let mut keepalive = tokio::time::interval_at(tokio::time::Instant::now() + keepalive_dur, keepalive_dur);
+    let mut sent_count: usize = 0;
+    loop {
+        let data = tokio::select! {
+            _ = shutdown.recv() => {
+                metrics.disconnects.with_label_values(&["shutdown"]).inc();
+                break;
+            },
+            _ = keepalive.tick() => ":\n\n".to_owned(),
+            Some(query_result) = query_rx.recv() => {
+                if query_result.event == "EOSE" {
+                    format!("data: {}\n\n", OutboundMessage::Eose { sub_id: &sub_id }.to_json())
+                } else if !is_visible_json(&query_result.event, &conn, &groups, &settings) {
+                    continue;
+                } else {
+                    sent_count += 1;
+                    metrics.sent_events.with_label_values(&["db"]).inc();
+                    format!("data: {}\n\n", OutboundMessage::Event { sub_id: &sub_id, event: &query_result.event }.to_json())
+                }
+            },
+            Some(matched) = matched_rx.recv() => {
+                let event = matched.event;
+                if !groups.can_read(&event, None) || is_withheld_dm(&conn, &event, private_inbox, dm_read_protection) {
+                    continue;
+                }
+                sent_count += 1;
+                metrics.sent_events.with_label_values(&["realtime"]).inc();
+                format!("data: {}\n\n", OutboundMessage::Event { sub_id: &sub_id, event: event.json() }.to_json())
+            },
+        };
+        if sender.send_data(data.into()).await.is_err() {
+            metrics.disconnects.with_label_values(&["normal"]).inc();
+            break;
+        }
+    }
+    abandon_query_tx.send(()).ok();
+    info!(
+        received = sent_count,
+        duration_ms = orig_start.elapsed().as_millis() as u64,
+        "stopping event stream"
+    );
+}
+
+/// Publish an event sent with HTTP POST, for clients that cannot use
+/// websockets.  The body is an event, or an `EVENT` message, and the
+/// response is the `OK` message a websocket client would receive.
+#[allow(clippy::too_many_arguments)]
+async fn handle_publish(
+    request: Request<Body>,
+    conn: conn::ClientConn,
+    repo: &Arc<dyn NostrRepo>,
+    settings: &Settings,
+    verifier: &SignatureVerifier,
+    event_limiter: &EventRateLimiter,
+    bans: &BanRegistry,
+    reputations: &Reputations,
+    admin: Option<&AdminChannel>,
+    status: &RelayStatus,
+    event_tx: &mpsc::Sender<SubmittedEvent>,
+    metrics: &NostrMetrics,
+) -> Response<Body> {
+    let origin = get_header_string("origin", request.headers());
+    let user_agent = get_header_string("user-agent", request.headers());
+    // read no more than a websocket message may hold
+    let max_bytes = settings.limits.max_ws_message_bytes.unwrap_or(usize::MAX);
+    let mut body = request.into_body();
+    let mut msg = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let Ok(chunk) = chunk else {
+            return http_error(StatusCode::BAD_REQUEST, "could not read event");
+        };
+        if msg.len() + chunk.len() > max_bytes {
+            record_abuse(repo, bans, metrics, conn.ip(), Abuse::Oversized).await;
+            return http_error(StatusCode::PAYLOAD_TOO_LARGE, "event exceeded max size");
+        }
+        msg.extend_from_slice(&chunk);
+    }
+    let msg = String::from_utf8_lossy(&msg);
+    let msg = msg.trim();
+    // a bare event is published as if sent in an EVENT message
+    let msg = if msg.starts_with('{') {
+        format!("[\"EVENT\",{msg}]")
+    } else {
+        msg.to_owned()
+    };
+    let ec = match convert_to_msg(&msg, settings.limits.max_event_bytes) {
+        Ok(NostrMessage::EventMsg(ec)) => ec,
+        Ok(_) => return http_error(StatusCode::BAD_REQUEST, "only events can be published"),
+        Err(Error::EventMaxLengthError(..)) => {
+            record_abuse(repo, bans, metrics, conn.ip(), Abuse::Oversized).await;
+            return http_error(StatusCode::PAYLOAD_TOO_LARGE, "event exceeded max size");
+        }
+        Err(_) => {
+            record_abuse(repo, bans, metrics, conn.ip(), Abuse::ParseError).await;
+            return http_error(StatusCode::BAD_REQUEST, "could not parse event");
+        }
+    };
+    let evid = ec.event_id().to_owned();
+    metrics.cmd_event.inc();
+    let e = match verifier.verify(ec).await {
+        Ok(EventWrapper::WrappedEvent(e)) => e,
+        Ok(EventWrapper::WrappedAuth(_)) => {
+            return http_error(StatusCode::BAD_REQUEST, "authentication requires a websocket")
+        }
+        Err(e) => {
+            info!(error = %e, "client sent an invalid event");
+            let notice = Notice::invalid(evid, &format!("{e}"));
+            status.notice_sent(&notice);
+            metrics.notice_sent(&notice);
+            return notice_response(&notice);
+        }
+    };
+    let notice = match admit_event(&e, &conn, repo, settings, bans, reputations, event_limiter, admin, metrics).await {
+        Admission::Store { broadcast } => {
+            status.event_received(&e);
+            metrics.event_received(&e);
+            // wait for the database writer's verdict
+            let (notice_tx, mut notice_rx) = mpsc::channel::<Notice>(4);
+            let submit_event = SubmittedEvent {
+                event: e,
+                notice_tx,
+                source_ip: conn.ip().to_string(),
+                auth_pubkey: None,
+                origin,
+                user_agent,
+                broadcast,
+            };
+            match db::submit_event(event_tx, submit_event) {
+                Ok(()) => match tokio::time::timeout(PUBLISH_TIMEOUT, notice_rx.recv()).await {
+                    Ok(Some(notice)) => notice,
+                    _ => Notice::error(evid, "event could not be written in time"),
+                },
+                Err(notice) => notice,
+            }
+        }
+        Admission::ShadowBanned => Notice::saved(evid),
+        Admission::Reply(notice) => notice,
+    };
+    status.notice_sent(&notice);
+    metrics.notice_sent(&notice);
+    notice_response(&notice)
+}
+
+/// Fetch events with a REST query, for clients that do not speak
+/// websocket: `/e/<id>` returns one event, and `/req?filter=...`
+/// returns a JSON array of the events matching a filter, or an array
+/// of filters.
+async fn handle_rest_query(
+    request: &Request<Body>,
+    mut conn: conn::ClientConn,
+    repo: &Arc<dyn NostrRepo>,
+    settings: &Settings,
+    metrics: &NostrMetrics,
+    groups: &GroupRegistry,
+) -> Response<Body> {
+    let single = request.uri().path().strip_prefix("/e/");
+    let filters = match single {
+        Some(id) if id.len() == 64 && is_lower_hex(id) => json!([{ "ids": [id] }]).to_string(),
+        Some(_) => return http_error(StatusCode::BAD_REQUEST, "invalid event id"),
+        None => {
+            let query = request.uri().query().unwrap_or_default();
+            let params: HashMap<String, String> = form_urlencoded::parse(query.as_bytes()).into_owned().collect();
+            match params.get("filter").map(|f| f.trim()) {
+                Some(f) if f.starts_with('{') => format!("[{f}]"),
+                Some(f) => f.to_owned(),
+                None => return http_error(StatusCode::BAD_REQUEST, "missing filter"),
+            }
+        }
+    };
+    let mut sub = match Subscription::from_filters("rest", &filters) {
+        Ok(s) => s,
+        Err(e) => return http_error(StatusCode::BAD_REQUEST, &format!("invalid filter: {e}")),
+    };
+    for f in &mut sub.filters {
+        f.limit = Some(f.limit.map_or(MAX_QUERY_EVENTS, |l| l.min(MAX_QUERY_EVENTS)));
+        f.clamp_limit(settings.limits.max_limit);
+    }
+    // the same limits as a websocket subscription
+    if let Err(e) = conn.subscribe(sub.clone()) {
+        return http_error(StatusCode::BAD_REQUEST, &e.to_string());
+    }
+    metrics.cmd_req.inc();
+    let events = match repo::collect_events(repo.as_ref(), sub, conn.get_client_prefix()).await {
+        Ok(events) => events,
+        Err(e) => {
+            warn!("REST query failed: {:?}", e);
+            return http_error(StatusCode::INTERNAL_SERVER_ERROR, "events could not be retrieved");
+        }
+    };
+    let events: Vec<String> = events
+        .into_iter()
+        .filter(|e| is_visible_json(e, &conn, groups, settings))
+        .collect();
+    metrics.sent_events.with_label_values(&["db"]).inc_by(events.len() as u64);
+    let body = match (single, events.first()) {
+        (Some(_), Some(event)) => event.clone(),
+        (Some(_), None) => return http_error(StatusCode::NOT_FOUND, "event not found"),
+        (None, _) => format!("[{}]", events.join(",")),
+    };
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+/// A relay message, as the response to an HTTP request
+fn notice_response(notice: &Notice) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Body::from(OutboundMessage::from(notice).to_json()))
+        .unwrap()
+}
+
+/// Plain text error response
+fn http_error(status: StatusCode, message: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "text/plain")
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Body::from(message.to_owned()))
+        .unwrap()
+}
+
+/// The pubkey authorizing a request with an HTTP auth event (NIP-98),
+/// or the response refusing it.
+fn http_auth_pubkey(request: &Request<Body>, settings: &Settings) -> std::result::Result<String, Box<Response<Body>>> {
+    let Some(base) = media::relay_http_url(settings) else {
+        return Err(Box::new(http_error(StatusCode::INTERNAL_SERVER_ERROR, "relay URL is not configured")));
+    };
+    let url = format!("{base}{}", request.uri().path());
+    let auth = get_header_string("authorization", request.headers());
+    match auth.map(|a| media::verify_http_auth(&a, &url, request.method().as_str(), &[])) {
+        Some(Ok(pk)) => Ok(pk),
+        _ => Err(Box::new(http_error(StatusCode::UNAUTHORIZED, "invalid authorization"))),
+    }
+}
+
+/// Relay status for the dashboard, for admins authorized with an
+/// HTTP auth event (NIP-98).
+async fn handle_admin_status(
+    request: &Request<Body>,
+    settings: &Settings,
+    repo: &Arc<dyn NostrRepo>,
+    status: &RelayStatus,
+    conn_limits: &ConnectionLimits,
+) -> Response<Body> {
+    let pubkey = match http_auth_pubkey(request, settings) {
+        Ok(pk) => pk,
+        Err(res) => return *res,
+    };
+    if !settings.admin.pubkeys.iter().flatten().any(|pk| pk == &pubkey) {
+        return http_error(StatusCode::FORBIDDEN, "pubkey is not an admin");
+    }
+    let body = match request.uri().path() {
+        "/admin/status/authors" => json!(status.top_authors(TOP_AUTHORS)),
+        "/admin/status/refusals" => json!(status.refusals()),
+        _ => {
+            let (connections, addresses) = conn_limits.open();
+            let database_bytes = match repo.used_bytes().await {
+                Ok(b) => Some(b),
+                Err(e) => {
+                    warn!("could not measure database: {:?}", e);
+                    None
+                }
+            };
+            json!({
+                "connections": connections,
+                "client_addresses": addresses,
+                "events_per_minute": status.events_per_minute(),
+                "database_bytes": database_bytes,
+            })
+        }
+    };
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+/// Publish events from a trusted local service, authorized with the
+/// injection secret.  The body holds one event per line, and the
+/// response the `OK` message for each, one per line.
+async fn handle_inject(
+    request: Request<Body>,
+    settings: &Settings,
+    remote_addr: SocketAddr,
+    event_tx: &mpsc::Sender<SubmittedEvent>,
+) -> Response<Body> {
+    let cfg = &settings.injection;
+    // proxy headers are not trusted here.
+    let ip = remote_addr.ip().to_canonical();
+    if cfg.local_only && !ip.is_loopback() {
+        return http_error(StatusCode::FORBIDDEN, "events may only be injected from this host");
+    }
+    let auth = get_header_string("authorization", request.headers());
+    if auth.as_deref().and_then(|a| a.strip_prefix("Bearer ")) != cfg.secret.as_deref() {
+        return http_error(StatusCode::UNAUTHORIZED, "invalid authorization");
+    }
+    let Ok(body) = http_client::read_limited(request.into_body(), MAX_INJECT_BYTES).await else {
+        return http_error(StatusCode::PAYLOAD_TOO_LARGE, "request too large");
+    };
+    let verdicts = inject::inject_lines(event_tx, &String::from_utf8_lossy(&body), &ip.to_string(), cfg.verify_signatures).await;
+    let lines: String = verdicts
+        .iter()
+        .map(|n| format!("{}\n", OutboundMessage::from(n).to_json()))
+        .collect();
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/x-ndjson")
+        .body(Body::from(lines))
+        .unwrap()
+}
+
+/// Make a backup of the database, for admins authorized with an HTTP
+/// auth event (NIP-98).  The backup continues if the request is
+/// abandoned.
+async fn handle_admin_backup(request: &Request<Body>, settings: &Settings, repo: &Arc<dyn NostrRepo>) -> Response<Body> {
+    let pubkey = match http_auth_pubkey(request, settings) {
+        Ok(pk) => pk,
+        Err(res) => return *res,
+    };
+    if !settings.admin.pubkeys.iter().flatten().any(|pk| pk == &pubkey) {
+        return http_error(StatusCode::FORBIDDEN, "pubkey is not an admin");
+    }
+    let (repo, settings) = (repo.clone(), settings.clone());
+    let made = tokio::task::spawn(async move { backup::backup(repo.as_ref(), &settings).await }).await;
+    match made {
+        Ok(Ok(name)) => Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json!({ "backup": name }).to_string()))
+            .unwrap(),
+        Ok(Err(e)) => {
+            warn!("admin backup failed: {:?}", e);
+            http_error(StatusCode::INTERNAL_SERVER_ERROR, &format!("backup failed: {e}"))
+        }
+        Err(e) => {
+            warn!("admin backup failed: {:?}", e);
+            http_error(StatusCode::INTERNAL_SERVER_ERROR, "backup failed")
+        }
+    }
+}
+
+/// Store a file upload (NIP-96), authorized with an HTTP auth event
+/// (NIP-98).
+async fn handle_upload(request: Request<Body>, settings: &Settings) -> Response<Body> {
+    let base = match media::base_url(settings) {
+        Some(b) => b,
+        None => {
+            return upload_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "media URL is not configured",
+            )
+        }
+    };
+    let auth = get_header_string("authorization", request.headers());
+    let content_type = get_header_string("content-type", request.headers());
+    let max_bytes = settings.media.max_upload_bytes;
+    // reject oversized uploads before reading them
+    let declared_len = get_header_string("content-length", request.headers())
+        .and_then(|l| l.parse::<usize>().ok());
+    if let (Some(max), Some(len)) = (max_bytes, declared_len) {
+        if len > max {
+            return upload_error(StatusCode::PAYLOAD_TOO_LARGE, "file is too large");
+        }
+    }
+    let body = match hyper::body::to_bytes(request.into_body()).await {
+        Ok(b) => b,
+        Err(_) => return upload_error(StatusCode::BAD_REQUEST, "could not read upload"),
+    };
+    if max_bytes.iter().any(|max| body.len() > *max) {
+        return upload_error(StatusCode::PAYLOAD_TOO_LARGE, "file is too large");
+    }
+    let upload_url = format!("{base}/upload");
+    let pubkey = match auth.map(|a| media::verify_http_auth(&a, &upload_url, "POST", &body)) {
+        Some(Ok(pk)) => pk,
+        _ => return upload_error(StatusCode::UNAUTHORIZED, "invalid authorization"),
+    };
+    if let Some(whitelist) = &settings.authorization.pubkey_whitelist {
+        if !whitelist.contains(&pubkey) {
+            return upload_error(StatusCode::FORBIDDEN, "pubkey is not allowed to upload");
+        }
+    }
+    let store = MediaStore::new(settings);
+    // forms carry the file in a "file" field, otherwise the body is the file
+    let (data, mime) = match content_type.as_deref().and_then(media::multipart_boundary) {
+        Some(boundary) => match media::multipart_file(&body, &boundary) {
+            Some(f) => f,
+            None => return upload_error(StatusCode::BAD_REQUEST, "missing file field"),
+        },
+        None => (body.to_vec(), content_type),
+    };
+    match store.within_quota(&pubkey, data.len(), settings.media.quota_bytes).await {
+        Ok(true) => {}
+        Ok(false) => return upload_error(StatusCode::PAYLOAD_TOO_LARGE, "upload quota exceeded"),
+        Err(e) => {
+            warn!("could not check upload quota: {:?}", e);
+            return upload_error(StatusCode::INTERNAL_SERVER_ERROR, "file could not be stored");
+        }
+    }
+    let stored = match store.store(&data, mime.as_deref()).await {
+        Ok(hash) => store.add_owner(&pubkey, &hash).await.map(|_| hash),
+        Err(e) => Err(e),
+    };
+    match stored {
+        Ok(hash) => {
+            info!("stored upload {} ({} bytes) from: {:?}", hash, data.len(), pubkey);
+            let url = format!("{base}/media/{hash}");
+            let mime = mime.as_deref().unwrap_or(media::DEFAULT_MIME);
+            let res = media::upload_response(&url, &hash, mime, data.len());
+            Response::builder()
+                .status(StatusCode::CREATED)
+                .header("Content-Type", "application/json")
+                .header("Access-Control-Allow-Origin", "*")
+                .body(Body::from(res.to_string()))
+                .unwrap()
+        }
+        Err(e) => {
+            warn!("could not store upload: {:?}", e);
+            upload_error(StatusCode::INTERNAL_SERVER_ERROR, "file could not be stored")
+        }
+    }
+}
+
+/// Store a blob upload (Blossom).
+async fn handle_blob_upload(request: Request<Body>, settings: &Settings) -> Response<Body> {
+    let base = match media::base_url(settings) {
+        Some(b) => b,
+        None => return blob_error(StatusCode::INTERNAL_SERVER_ERROR, "media URL is not configured"),
+    };
+    let auth = get_header_string("authorization", request.headers());
+    let mime = get_header_string("content-type", request.headers());
+    let max_bytes = settings.media.max_upload_bytes;
+    // reject oversized uploads before reading them
+    let declared_len = get_header_string("content-length", request.headers())
+        .and_then(|l| l.parse::<usize>().ok());
+    if let (Some(max), Some(len)) = (max_bytes, declared_len) {
+        if len > max {
+            return blob_error(StatusCode::PAYLOAD_TOO_LARGE, "blob is too large");
+        }
+    }
+    let body = match hyper::body::to_bytes(request.into_body()).await {
+        Ok(b) => b,
+        Err(_) => return blob_error(StatusCode::BAD_REQUEST, "could not read upload"),
+    };
+    if max_bytes.iter().any(|max| body.len() > *max) {
+        return blob_error(StatusCode::PAYLOAD_TOO_LARGE, "blob is too large");
+    }
+    // the auth event must name the blob being uploaded
+    let hash = media::hash_hex(&body);
+    let pubkey = match auth.map(|a| media::verify_blob_auth(&a, "upload", Some(&hash))) {
+        Some(Ok(pk)) => pk,
+        _ => return blob_error(StatusCode::UNAUTHORIZED, "invalid authorization"),
+    };
+    if let Some(whitelist) = &settings.authorization.pubkey_whitelist {
+        if !whitelist.contains(&pubkey) {
+            return blob_error(StatusCode::FORBIDDEN, "pubkey is not allowed to upload");
+        }
+    }
+    let store = MediaStore::new(settings);
+    match store.within_quota(&pubkey, body.len(), settings.media.quota_bytes).await {
+        Ok(true) => {}
+        Ok(false) => return blob_error(StatusCode::PAYLOAD_TOO_LARGE, "upload quota exceeded"),
+        Err(e) => {
+            warn!("could not check upload quota: {:?}", e);
+            return blob_error(StatusCode::INTERNAL_SERVER_ERROR, "blob could not be stored");
+        }
+    }
+    let stored = match store.store(&body, mime.as_deref()).await {
+        Ok(hash) => store.add_owner(&pubkey, &hash).await,
+        Err(e) => Err(e),
+    };
+    match stored {
+        Ok(()) => {
+            info!("stored blob {} ({} bytes) from: {:?}", hash, body.len(), pubkey);
+            let mime = mime.as_deref().unwrap_or(media::DEFAULT_MIME);
+            let desc = media::blob_descriptor(&base, &hash, mime, body.len());
+            Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "application/json")
+                .header("Access-Control-Allow-Origin", "*")
+                .body(Body::from(desc.to_string()))
+                .unwrap()
+        }
+        Err(e) => {
+            warn!("could not store blob: {:?}", e);
+            blob_error(StatusCode::INTERNAL_SERVER_ERROR, "blob could not be stored")
+        }
+    }
+}
+
+/// Delete a blob (Blossom).  Only removes the requesting pubkey's
+/// copy, if others have uploaded the same blob.
+async fn handle_blob_delete(request: &Request<Body>, settings: &Settings, hash: &str) -> Response<Body> {
+    let auth = get_header_string("authorization", request.headers());
+    let pubkey = match auth.map(|a| media::verify_blob_auth(&a, "delete", Some(hash))) {
+        Some(Ok(pk)) => pk,
+        _ => return blob_error(StatusCode::UNAUTHORIZED, "invalid authorization"),
+    };
+    match MediaStore::new(settings).delete(&pubkey, hash).await {
+        Ok(true) => {
+            info!("deleted blob {} for: {:?}", hash, pubkey);
+            Response::builder()
+                .status(StatusCode::OK)
+                .header("Access-Control-Allow-Origin", "*")
+                .body(Body::empty())
+                .unwrap()
+        }
+        Ok(false) => blob_error(StatusCode::NOT_FOUND, "blob not found"),
+        Err(e) => {
+            warn!("could not delete blob: {:?}", e);
+            blob_error(StatusCode::INTERNAL_SERVER_ERROR, "blob could not be deleted")
+        }
+    }
+}
+
+/// Allow browser clients to send authorization headers to media
+/// endpoints.
+fn media_preflight() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Headers", "Authorization, Content-Type")
+        .header("Access-Control-Allow-Methods", "GET, HEAD, POST, PUT, DELETE")
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Blossom error response
+fn blob_error(status: StatusCode, reason: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("X-Reason", reason)
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Body::from(reason.to_owned()))
+        .unwrap()
+}
+
+/// NIP-96 error response
+fn upload_error(status: StatusCode, message: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Body::from(
+            json!({"status": "error", "message": message}).to_string(),
+        ))
+        .unwrap()
+}
+
 fn get_header_string(header: &str, headers: &HeaderMap) -> Option<String> {
     headers
         .get(header)
         .and_then(|x| x.to_str().ok().map(std::string::ToString::to_string))
 }
 
+/// Bind a listening socket.  IPv6 sockets only accept IPv6, so that
+/// IPv4 and IPv6 addresses can share a port.
+fn bind_listener(addr: &SocketAddr) -> std::io::Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(*addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&(*addr).into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
 // return on a control-c or internally requested shutdown signal
-async fn ctrl_c_or_signal(mut shutdown_signal: Receiver<()>) {
+async fn ctrl_c_or_signal(mut shutdown_signal: Receiver<()>, handle_signals: bool) {
+    if !handle_signals {
+        shutdown_signal.recv().await.ok();
+        info!("Shutting down webserver as requested");
+        return;
+    }
     let mut term_signal = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
         .expect("could not define signal");
     loop {
@@ -225,7 +1377,11 @@ async fn ctrl_c_or_signal(mut shutdown_signal: Receiver<()>) {
     }
 }
 
-fn create_metrics() -> (Registry, NostrMetrics) {
+/// Most origins and user agents with their own metric labels.
+const TOP_CLIENT_LABELS: usize = 20;
+
+/// Create the Prometheus registry, and the metrics registered in it.
+pub fn create_metrics() -> (Registry, NostrMetrics) {
     // setup prometheus registry
     let registry = Registry::new();
 
@@ -249,8 +1405,28 @@ fn create_metrics() -> (Registry, NostrMetrics) {
         vec!["source"].as_slice(),
     )
     .unwrap();
+    let dropped_events = IntCounter::with_opts(Opts::new(
+        "nostr_events_dropped_total",
+        "Realtime events dropped for slow clients",
+    ))
+    .unwrap();
+    let write_queue = IntGauge::with_opts(Opts::new(
+        "nostr_write_queue_events",
+        "Events waiting to be written; new events are refused when it reaches event_persist_buffer",
+    ))
+    .unwrap();
+    let missed_events = IntCounter::with_opts(Opts::new(
+        "nostr_events_missed_total",
+        "Realtime events not matched or queued for clients, because matching fell behind",
+    ))
+    .unwrap();
     let connections =
         IntCounter::with_opts(Opts::new("nostr_connections_total", "New connections")).unwrap();
+    let rejected_connections = IntCounterVec::new(
+        Opts::new("nostr_connections_rejected_total", "Connections refused by limits"),
+        vec!["reason"].as_slice(),
+    )
+    .unwrap();
     let db_connections = IntGauge::with_opts(Opts::new(
         "nostr_db_connections",
         "Active database connections",
@@ -266,60 +1442,161 @@ fn create_metrics() -> (Registry, NostrMetrics) {
         IntCounter::with_opts(Opts::new("nostr_cmd_event_total", "EVENT commands")).unwrap();
     let cmd_close =
         IntCounter::with_opts(Opts::new("nostr_cmd_close_total", "CLOSE commands")).unwrap();
+    let cmd_count =
+        IntCounter::with_opts(Opts::new("nostr_cmd_count_total", "COUNT commands")).unwrap();
+    let cmd_neg =
+        IntCounter::with_opts(Opts::new("nostr_cmd_neg_total", "NEG-OPEN commands")).unwrap();
+    let ephemeral_events = IntCounter::with_opts(Opts::new(
+        "nostr_ephemeral_events_total",
+        "Ephemeral events broadcast",
+    ))
+    .unwrap();
     let disconnects = IntCounterVec::new(
         Opts::new("nostr_disconnects_total", "Client disconnects"),
         vec!["reason"].as_slice(),
     )
     .unwrap();
+    let rate_limited_events = IntCounterVec::new(
+        Opts::new("nostr_events_rate_limited_total", "Events refused by rate limits"),
+        vec!["limit"].as_slice(),
+    )
+    .unwrap();
+    let bans = IntCounterVec::new(
+        Opts::new("nostr_bans_total", "Client addresses banned for abuse"),
+        vec!["reason"].as_slice(),
+    )
+    .unwrap();
     let spams = IntCounterVec::new(
         Opts::new("nostr_spams_total", "EVENT spams"),
         vec!["author"].as_slice(),
     )
     .unwrap();
+    let received_events = IntCounterVec::new(
+        Opts::new("nostr_events_received_total", "Valid events received from clients"),
+        vec!["kind"].as_slice(),
+    )
+    .unwrap();
+    let rejected_events = IntCounterVec::new(
+        Opts::new("nostr_events_rejected_total", "Events refused"),
+        vec!["reason"].as_slice(),
+    )
+    .unwrap();
+    let client_origins = IntCounterVec::new(
+        Opts::new("nostr_connections_by_origin_total", "Connections by client Origin"),
+        vec!["origin"].as_slice(),
+    )
+    .unwrap();
+    let client_agents = IntCounterVec::new(
+        Opts::new("nostr_connections_by_user_agent_total", "Connections by client User-Agent"),
+        vec!["user_agent"].as_slice(),
+    )
+    .unwrap();
+    let verify_cache = IntCounterVec::new(
+        Opts::new("nostr_verify_cache_total", "Events found (hit) or not (miss) among those recently verified"),
+        vec!["result"].as_slice(),
+    )
+    .unwrap();
 
+    let db_vacuums = IntCounter::with_opts(Opts::new(
+        "nostr_db_vacuums_total",
+        "Online database vacuums",
+    ))
+    .unwrap();
+    let db_reclaimed_bytes = IntCounter::with_opts(Opts::new(
+        "nostr_db_reclaimed_bytes_total",
+        "Bytes released by online database vacuums",
+    ))
+    .unwrap();
     registry.register(Box::new(query_sub.clone())).unwrap();
     registry.register(Box::new(query_db.clone())).unwrap();
     registry.register(Box::new(write_events.clone())).unwrap();
     registry.register(Box::new(sent_events.clone())).unwrap();
+    registry.register(Box::new(dropped_events.clone())).unwrap();
+    registry.register(Box::new(missed_events.clone())).unwrap();
+    registry.register(Box::new(write_queue.clone())).unwrap();
     registry.register(Box::new(connections.clone())).unwrap();
+    registry.register(Box::new(rejected_connections.clone())).unwrap();
     registry.register(Box::new(db_connections.clone())).unwrap();
     registry.register(Box::new(query_aborts.clone())).unwrap();
     registry.register(Box::new(cmd_req.clone())).unwrap();
     registry.register(Box::new(cmd_event.clone())).unwrap();
     registry.register(Box::new(cmd_close.clone())).unwrap();
+    registry.register(Box::new(cmd_count.clone())).unwrap();
+    registry.register(Box::new(cmd_neg.clone())).unwrap();
+    registry.register(Box::new(ephemeral_events.clone())).unwrap();
     registry.register(Box::new(disconnects.clone())).unwrap();
     registry.register(Box::new(spams.clone())).unwrap();
+    registry.register(Box::new(rate_limited_events.clone())).unwrap();
+    registry.register(Box::new(bans.clone())).unwrap();
+    registry.register(Box::new(received_events.clone())).unwrap();
+    registry.register(Box::new(rejected_events.clone())).unwrap();
+    registry.register(Box::new(client_origins.clone())).unwrap();
+    registry.register(Box::new(client_agents.clone())).unwrap();
+    registry.register(Box::new(verify_cache.clone())).unwrap();
+    registry.register(Box::new(db_vacuums.clone())).unwrap();
+    registry.register(Box::new(db_reclaimed_bytes.clone())).unwrap();
     let metrics = NostrMetrics {
         query_sub,
         query_db,
         write_events,
         sent_events,
+        dropped_events,
+        missed_events,
+        write_queue,
         connections,
+        rejected_connections,
         db_connections,
         disconnects,
         query_aborts,
         cmd_req,
         cmd_event,
         cmd_close,
+        cmd_count,
+        cmd_neg,
+        ephemeral_events,
         spams,
+        rate_limited_events,
+        bans,
+        received_events,
+        rejected_events,
+        client_origins,
+        client_agents,
+        verify_cache,
+        db_vacuums,
+        db_reclaimed_bytes,
+        origin_labels: Arc::new(TopLabels::new(TOP_CLIENT_LABELS)),
+        agent_labels: Arc::new(TopLabels::new(TOP_CLIENT_LABELS)),
     };
     (registry, metrics)
 }
 
 /// Start running a Nostr relay server.
 pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Result<(), Error> {
+    run_server(settings, shutdown_rx, None)
+}
+
+/// Run the relay until it shuts down.  An embedded relay is sent the
+/// parts its handle needs once it has started, does not handle
+/// signals, and may serve only its handle.
+pub(crate) fn run_server(
+    settings: &Settings,
+    shutdown_rx: MpscReceiver<()>,
+    embedding: Option<Embedding>,
+) -> Result<(), Error> {
     trace!("Config: {:?}", settings);
     // do some config validation.
     if !Path::new(&settings.database.data_directory).is_dir() {
         error!("Database directory does not exist");
         return Err(Error::DatabaseDirError);
     }
-    let addr = format!(
-        "{}:{}",
-        settings.network.address.trim(),
-        settings.network.port
-    );
-    let socket_addr = addr.parse().expect("listening address not valid");
+    let listeners: Vec<(SocketAddr, Listener)> = settings
+        .network
+        .all_listeners()
+        .into_iter()
+        .map(|l| (l.socket_addr().expect("listening address not valid"), l))
+        .collect();
+    // serve TLS directly, if a certificate is configured
+    let tls_resolver = tls::resolver_for(&settings.network)?;
     // address whitelisting settings
     if let Some(addr_whitelist) = &settings.authorization.pubkey_whitelist {
         info!(
@@ -327,6 +1604,9 @@ pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Resul
             addr_whitelist.len()
         );
     }
+    if let Some(domains) = &settings.authorization.nip05_domains {
+        info!("Event publishing allowed to pubkeys verified at {:?}", domains);
+    }
     // check if NIP-05 enforced user verification is on
     if settings.verified_users.is_active() {
         info!(
@@ -346,11 +1626,9 @@ pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Resul
             info!("NIP-05 domain blacklist: {:?}", bl);
         }
     }
-    if (settings.antispam.use_keywords()) {
-        info!(
-            "Use keywords to antispam, keywords: {:?}",
-            settings.antispam.keywords
-        );
+    let spam_policies = settings.antispam.policies();
+    if !spam_policies.is_empty() {
+        info!("antispam policies: {:?}", spam_policies);
     }
     // configure tokio runtime
     let rt = Builder::new_multi_thread()
@@ -378,66 +1656,283 @@ pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Resul
         let persist_buffer_limit = settings.limits.event_persist_buffer;
         let verified_users_active = settings.verified_users.is_active();
         let settings = settings.clone();
-        info!("listening on: {}", socket_addr);
+        // settings that change while running are sent to every task
+        // and connection that uses them.
+        let (settings_tx, settings_rx) = watch::channel(settings.clone());
+        let settings_tx = Arc::new(settings_tx);
         // all client-submitted valid events are broadcast to every
         // other client on this channel.  This should be large enough
         // to accomodate slower readers (messages are dropped if
         // clients can not keep up).
-        let (bcast_tx, _) = broadcast::channel::<Event>(broadcast_buffer_limit);
+        let (bcast_tx, _) = broadcast::channel::<BroadcastEvent>(broadcast_buffer_limit);
         // validated events that need to be persisted are sent to the
         // database on via this channel.
         let (event_tx, event_rx) = mpsc::channel::<SubmittedEvent>(persist_buffer_limit);
         // establish a channel for letting all threads now about a
         // requested server shutdown.
         let (invoke_shutdown, shutdown_listen) = broadcast::channel::<()>(1);
-        // create a channel for sending any new metadata event.  These
-        // will get processed relatively slowly (a potentially
-        // multi-second blocking HTTP call) on a single thread, so we
-        // buffer requests on the channel.  No harm in dropping events
-        // here, since we are protecting against DoS.  This can make
-        // it difficult to setup initial metadata in bulk, since
-        // overwhelming this will drop events and won't register
-        // metadata events.
+        // create a channel for sending any new metadata event.  The
+        // verifier queues them in the database, to be checked by a
+        // pool of workers, so that metadata published in bulk is not
+        // dropped while slow HTTP requests are made.
         let (metadata_tx, metadata_rx) = broadcast::channel::<Event>(4096);
 
         let (registry, metrics) = create_metrics();
         // build a repository for events
         let repo = db::build_repo(&settings, metrics.clone()).await;
+        // the pubkey whitelist is stored in the database, seeded from
+        // the config file.
+        let whitelist = Arc::new(Whitelist::new(repo.clone(), settings_tx.clone()));
+        if let Err(e) = whitelist.seed().await {
+            warn!("could not load the pubkey whitelist: {:?}", e);
+        }
+        let settings = settings_tx.borrow().clone();
+        if let Some(wl) = &settings.authorization.pubkey_whitelist {
+            info!("{} pubkey(s) in the stored whitelist", wl.len());
+        }
+        tokio::task::spawn(whitelist::sync(whitelist.clone(), invoke_shutdown.subscribe()));
+        // load group membership, if groups are enabled
+        let group_list = if settings.groups.enabled {
+            repo.get_groups().await.unwrap_or_else(|e| {
+                warn!("could not load groups: {:?}", e);
+                vec![]
+            })
+        } else {
+            vec![]
+        };
+        let groups = Arc::new(GroupRegistry::new(&settings.groups, group_list));
+        // load banned addresses and pubkeys
+        let ban_list = repo.get_bans().await.unwrap_or_else(|e| {
+            warn!("could not load bans: {:?}", e);
+            vec![]
+        });
+        let bans = Arc::new(BanRegistry::new(&settings.bans, ban_list));
+        // author reputations, for scaling admission requirements
+        let reputations = Arc::new(Reputations::new(&settings, repo.clone()));
+        // reports (NIP-56), and moderation based on them
+        let reports = Arc::new(Reports::new(&settings, repo.clone(), reputations.clone()));
+        // recent activity, for the dashboard
+        let status = Arc::new(RelayStatus::new());
+        // the relay's own keypair, for publishing events as itself
+        let identity = match RelayIdentity::new(&settings, event_tx.clone()) {
+            Ok(i) => i.map(Arc::new),
+            Err(e) => {
+                warn!("relay identity disabled: {:?}", e);
+                None
+            }
+        };
+        // commands from admins, by direct message to the relay
+        let admin = match AdminChannel::new(
+            &settings,
+            repo.clone(),
+            bans.clone(),
+            whitelist.clone(),
+            metrics.clone(),
+            bcast_tx.clone(),
+            identity.clone(),
+        ) {
+            Ok(Some(a)) => {
+                info!("accepting admin commands by direct message to {}", a.pubkey());
+                if !settings.authorization.nip42_auth {
+                    warn!("admin commands require nip42_auth; commands will be refused");
+                }
+                Some(Arc::new(a))
+            }
+            Ok(None) => None,
+            Err(e) => {
+                warn!("admin commands disabled: {:?}", e);
+                None
+            }
+        };
+        // paid admission, if enabled
+        let payments = match Payments::new(&settings, repo.clone()) {
+            Ok(p) => p.map(Arc::new),
+            Err(e) => {
+                warn!("paid admission disabled: {:?}", e);
+                None
+            }
+        };
+        if let Some(p) = &payments {
+            info!("paid admission enabled, join at {}", p.join_url());
+            tokio::task::spawn(payment::check_invoices(p.clone(), invoke_shutdown.subscribe()));
+        }
+        // tiers of clients, with their own limits
+        let tiers = Tiers::new(&settings, repo.clone()).map(Arc::new);
+        // external authorization of connections
+        let admission = nauthz::client_for(&settings.grpc.endpoint, settings.grpc.admit_connections);
+        if settings.authorization.private_inbox && !settings.authorization.nip42_auth {
+            warn!("private inbox mode requires nip42_auth; gift wraps will not be served");
+        }
+        if settings.authorization.dm_read_protection && !settings.authorization.nip42_auth {
+            warn!("DM read protection requires nip42_auth; DMs will not be served");
+        }
         // start the database writer task.  Give it a channel for
         // writing events, and for publishing events that have been
         // written (to all connected clients).
         tokio::task::spawn(db::db_writer(
             repo.clone(),
-            settings.clone(),
+            settings_rx.clone(),
             event_rx,
             bcast_tx.clone(),
             metadata_tx.clone(),
             shutdown_listen,
             metrics.clone(),
+            groups.clone(),
+            bans.clone(),
+            reputations.clone(),
+            reports.clone(),
+            payments.clone(),
         ));
         info!("db writer created");
+        // publish the relay's profile, group lists and scheduled
+        // announcements, as the relay.
+        if let Some(identity) = &identity {
+            info!("relay pubkey is {}", identity.pubkey());
+            if settings.identity.publish_profile {
+                let identity = identity.clone();
+                let info = settings.info.clone();
+                tokio::task::spawn(async move {
+                    if let Err(e) = identity.publish_profile(&info).await {
+                        warn!("could not publish the relay's profile: {:?}", e);
+                    }
+                });
+            }
+            tokio::task::spawn(identity::publish_groups(
+                identity.clone(),
+                groups.clone(),
+                settings.clone(),
+                bcast_tx.subscribe(),
+                invoke_shutdown.subscribe(),
+            ));
+            tokio::task::spawn(announce::announcement_scheduler(
+                identity.clone(),
+                settings.clone(),
+                invoke_shutdown.subscribe(),
+            ));
+        } else if settings.identity.announcements.is_some() {
+            warn!("announcements require identity.secret_key; none will be published");
+        }
+        // remove expired events, if a retention policy is configured.
+        tokio::task::spawn(db::db_pruner(
+            repo.clone(),
+            settings.clone(),
+            tiers.clone(),
+            invoke_shutdown.subscribe(),
+        ));
+        // reclaim free space during the vacuum window, if configured.
+        tokio::task::spawn(maintenance::vacuum_scheduler(
+            repo.clone(),
+            settings.clone(),
+            metrics.clone(),
+            invoke_shutdown.subscribe(),
+        ));
+        // back up the database periodically, if configured.
+        tokio::task::spawn(backup::backup_scheduler(
+            repo.clone(),
+            settings.clone(),
+            invoke_shutdown.subscribe(),
+        ));
+        // forward accepted events to peer relays, if configured.
+        tokio::task::spawn(replication::replicate(
+            settings.clone(),
+            bcast_tx.subscribe(),
+            invoke_shutdown.subscribe(),
+        ));
+        // share events with other nodes, if clustered.
+        tokio::task::spawn(cluster::cluster(
+            settings.clone(),
+            bcast_tx.clone(),
+            invoke_shutdown.subscribe(),
+        ));
+        // check event signatures off the async runtime.
+        let verifier = SignatureVerifier::start(settings.limits.signature_threads, metrics.verify_cache.clone());
+        // copy events from upstream relays, if configured.
+        tokio::task::spawn(mirror::mirror(
+            settings.clone(),
+            event_tx.clone(),
+            verifier.clone(),
+            invoke_shutdown.subscribe(),
+        ));
+        // match new events against the subscriptions of every client.
+        let matcher = Matcher::start(bcast_tx.subscribe(), invoke_shutdown.subscribe());
+        // report whether the database, writer and matcher are keeping up.
+        let health = Arc::new(HealthChecks::new(
+            repo.clone(),
+            event_tx.clone(),
+            bcast_tx.clone(),
+            broadcast_buffer_limit,
+        ));
+        // limit how fast authors and addresses may publish events.
+        let event_limiter = Arc::new(EventRateLimiter::new(
+            settings.limits.event_rates.as_deref().unwrap_or_default(),
+        ));
+        // refuse or limit clients by their headers.
+        let access = Arc::new(AccessRules::new(
+            settings.limits.client_rules.as_deref().unwrap_or_default(),
+        ));
+        let limiter_prune = event_limiter.clone();
+        let access_reload = access.clone();
+        let mut limiter_settings = settings_rx.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            let limits = limiter_settings.borrow_and_update().limits.clone();
+            let (mut rules, mut client_rules) = (limits.event_rates, limits.client_rules);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => limiter_prune.prune(),
+                    Ok(()) = limiter_settings.changed() => {
+                        let reloaded = limiter_settings.borrow_and_update().limits.clone();
+                        if reloaded.event_rates != rules {
+                            limiter_prune.set_rules(reloaded.event_rates.as_deref().unwrap_or_default());
+                            rules = reloaded.event_rates;
+                        }
+                        if reloaded.client_rules != client_rules {
+                            access_reload.set_rules(reloaded.client_rules.as_deref().unwrap_or_default());
+                            client_rules = reloaded.client_rules;
+                        }
+                    },
+                }
+            }
+        });
+        // count open connections, to enforce connection limits.
+        let conn_limits = ConnectionLimits::new(
+            settings.limits.max_connections,
+            settings.limits.max_connections_per_ip,
+        );
+        // move old events into cold storage, if enabled.
+        tokio::task::spawn(db::db_archiver(
+            repo.clone(),
+            settings.clone(),
+            invoke_shutdown.subscribe(),
+        ));
 
         // create a nip-05 verifier thread; if enabled.
-        if settings.verified_users.mode != VerifiedUsersMode::Disabled {
+        if settings.verified_users.mode != VerifiedUsersMode::Disabled
+            || settings.authorization.nip05_domains.is_some()
+        {
             let verifier_opt = nip05::Verifier::new(
                 repo.clone(),
                 metadata_rx,
                 bcast_tx.clone(),
                 settings.clone(),
             );
-            if let Ok(mut v) = verifier_opt {
-                if verified_users_active {
-                    tokio::task::spawn(async move {
-                        info!("starting up NIP-05 verifier...");
-                        v.run().await;
-                    });
+            match verifier_opt {
+                Ok(mut v) => {
+                    if verified_users_active || settings.authorization.nip05_domains.is_some() {
+                        tokio::task::spawn(async move {
+                            info!("starting up NIP-05 verifier...");
+                            v.run().await;
+                        });
+                    }
                 }
+                Err(e) => warn!("NIP-05 verifier disabled: {:?}", e),
             }
         }
 
-        // listen for (external to tokio) shutdown request
+        // listen for (external to tokio) shutdown request, on a
+        // thread of its own, since waiting for it blocks.
         let controlled_shutdown = invoke_shutdown.clone();
-        tokio::spawn(async move {
+        std::thread::spawn(move || {
             info!("control message listener started");
             match shutdown_rx.recv() {
                 Ok(()) => {
@@ -449,54 +1944,154 @@ pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Resul
                 }
             };
         });
-        // listen for ctrl-c interruupts
-        let ctrl_c_shutdown = invoke_shutdown.clone();
-        // listener for webserver shutdown
-        let webserver_shutdown_listen = invoke_shutdown.subscribe();
-
-        tokio::spawn(async move {
-            tokio::signal::ctrl_c().await.unwrap();
-            info!("shutting down due to SIGINT (main)");
-            ctrl_c_shutdown.send(()).ok();
-        });
+        // listen for ctrl-c interruupts, unless embedded in an
+        // application that handles them itself.
+        let handle_signals = embedding.is_none();
+        if handle_signals {
+            let ctrl_c_shutdown = invoke_shutdown.clone();
+            tokio::spawn(async move {
+                tokio::signal::ctrl_c().await.unwrap();
+                info!("shutting down due to SIGINT (main)");
+                ctrl_c_shutdown.send(()).ok();
+            });
+        }
         // spawn a task to check the pool size.
         //let pool_monitor = pool.clone();
         //tokio::spawn(async move {db::monitor_pool("reader", pool_monitor).await;});
 
         // A `Service` is needed for every connection, so this
         // creates one from our `handle_request` function.
-        let make_svc = make_service_fn(|conn: &AddrStream| {
+        let new_service = |remote_addr: SocketAddr, serve_metrics: bool| {
             let repo = repo.clone();
-            let remote_addr = conn.remote_addr();
-            let bcast = bcast_tx.clone();
+            let matcher = matcher.clone();
+            let verifier = verifier.clone();
+            let conn_limits = conn_limits.clone();
+            let event_limiter = event_limiter.clone();
+            let access = access.clone();
+            let bans = bans.clone();
+            let reputations = reputations.clone();
+            let reports = reports.clone();
+            let status = status.clone();
+            let health = health.clone();
+            let admin = admin.clone();
+            let payments = payments.clone();
+            let tiers = tiers.clone();
             let event = event_tx.clone();
             let stop = invoke_shutdown.clone();
-            let settings = settings.clone();
+            let settings_rx = settings_rx.clone();
             let registry = registry.clone();
             let metrics = metrics.clone();
-            async move {
-                // service_fn converts our function into a `Service`
-                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
-                    handle_web_request(
-                        request,
-                        repo.clone(),
-                        settings.clone(),
-                        remote_addr,
-                        bcast.clone(),
-                        event.clone(),
-                        stop.subscribe(),
-                        registry.clone(),
-                        metrics.clone(),
-                    )
-                }))
+            let groups = groups.clone();
+            let admission = admission.clone();
+            // service_fn converts our function into a `Service`
+            service_fn(move |request: Request<Body>| {
+                handle_web_request(
+                    request,
+                    repo.clone(),
+                    settings_rx.clone(),
+                    remote_addr,
+                    serve_metrics,
+                    matcher.clone(),
+                    verifier.clone(),
+                    conn_limits.clone(),
+                    event_limiter.clone(),
+                    access.clone(),
+                    bans.clone(),
+                    reputations.clone(),
+                    reports.clone(),
+                    status.clone(),
+                    health.clone(),
+                    admin.clone(),
+                    payments.clone(),
+                    tiers.clone(),
+                    event.clone(),
+                    stop.subscribe(),
+                    registry.clone(),
+                    metrics.clone(),
+                    groups.clone(),
+                    admission.clone(),
+                )
+            })
+        };
+        // apply changes to the config file, if it was read from one.
+        if let Some(path) = &settings.config_file {
+            tokio::task::spawn(reload::watch_config(
+                path.clone(),
+                settings_tx,
+                invoke_shutdown.subscribe(),
+            ));
+        }
+        if let Some(resolver) = &tls_resolver {
+            info!("serving TLS with certificate from {:?}", settings.network.tls_cert);
+            tokio::task::spawn(tls::reload_certs(resolver.clone(), invoke_shutdown.subscribe()));
+        }
+        // hand the embedding application what it needs, and serve
+        // only it, if asked to.
+        if let Some(embedding) = embedding {
+            let mut stopped = invoke_shutdown.subscribe();
+            let parts = RelayParts {
+                runtime: tokio::runtime::Handle::current(),
+                settings: settings.clone(),
+                repo: repo.clone(),
+                event_tx: event_tx.clone(),
+                verifier: verifier.clone(),
+                matcher: matcher.clone(),
+                identity: identity.clone(),
+            };
+            embedding.ready.send(parts).ok();
+            if !embedding.listen {
+                stopped.recv().await.ok();
+                return;
             }
-        });
-        let server = Server::bind(&socket_addr)
-            .serve(make_svc)
-            .with_graceful_shutdown(ctrl_c_or_signal(webserver_shutdown_listen));
+        }
+        // every listener is served by the same service.
+        let mut servers: Vec<LocalBoxFuture<'_, hyper::Result<()>>> = vec![];
+        for (addr, listener) in &listeners {
+            let serve_metrics = listener.metrics.unwrap_or(true);
+            let new_service = &new_service;
+            let shutdown = ctrl_c_or_signal(invoke_shutdown.subscribe(), handle_signals);
+            let socket = match bind_listener(addr) {
+                Ok(s) => s,
+                Err(e) => {
+                    eprintln!("could not listen on {addr}: {e}");
+                    return;
+                }
+            };
+            if let Some(resolver) = tls_resolver.as_ref().filter(|_| listener.tls.unwrap_or(true)) {
+                let incoming = match TlsIncoming::new(socket, resolver.clone()) {
+                    Ok(i) => i,
+                    Err(e) => {
+                        eprintln!("could not listen on {addr}: {e}");
+                        return;
+                    }
+                };
+                let make_svc = make_service_fn(move |conn: &TlsConn| {
+                    let svc = tls::remote_addr(conn).map(|a| new_service(a, serve_metrics));
+                    async move { svc.ok_or_else(|| Error::CustomError("client disconnected".to_owned())) }
+                });
+                servers.push(Box::pin(Server::builder(incoming).serve(make_svc).with_graceful_shutdown(shutdown)));
+                info!("listening on: {} (TLS)", addr);
+            } else {
+                let builder = match Server::from_tcp(socket) {
+                    Ok(b) => b,
+                    Err(e) => {
+                        eprintln!("could not listen on {addr}: {e}");
+                        return;
+                    }
+                };
+                let make_svc = make_service_fn(move |conn: &AddrStream| {
+                    let svc = new_service(conn.remote_addr(), serve_metrics);
+                    async move { Ok::<_, Infallible>(svc) }
+                });
+                servers.push(Box::pin(builder.serve(make_svc).with_graceful_shutdown(shutdown)));
+                info!("listening on: {}", addr);
+            }
+        }
         // run hyper in this thread.  This is why the thread does not return.
-        if let Err(e) = server.await {
-            eprintln!("server error: {e}");
+        for result in join_all(servers).await {
+            if let Err(e) = result {
+                eprintln!("server error: {e}");
+            }
         }
     });
     Ok(())
@@ -510,12 +2105,47 @@ pub enum NostrMessage {
     EventMsg(EventCmd),
     /// A `REQ` message
     SubMsg(Subscription),
+    /// A `NEG-OPEN` message
+    NegOpenMsg(NegOpen),
+    /// A `NEG-MSG` message
+    NegDataMsg(NegMsg),
+    /// A `NEG-CLOSE` message
+    NegCloseMsg(NegClose),
+    /// A `RELAY` extension command (before `CLOSE`, which has the
+    /// same shape)
+    RelayMsg(RelayCmd),
     /// A `CLOSE` message
     CloseMsg(CloseCmd),
+    /// A `COUNT` message
+    CountMsg(CountCmd),
 }
 
 /// Convert Message to `NostrMessage`
 fn convert_to_msg(msg: &str, max_bytes: Option<usize>) -> Result<NostrMessage> {
+    // events are the bulk of client messages; parse them without
+    // trying each message type in turn.
+    if is_command(msg, "EVENT") {
+        if let Some(max_size) = max_bytes {
+            if msg.len() > max_size && max_size > 0 {
+                return Err(Error::EventMaxLengthError(msg.len(), unparsed_event_id(msg)));
+            }
+        }
+        return EventCmd::from_json(msg).map(NostrMessage::EventMsg).map_err(|e| {
+            trace!("event parse error: {:?}", e);
+            // an event with a readable id can still be answered with
+            // an OK message.
+            match unparsed_event_id(msg) {
+                Some(id) => {
+                    let reason = match e {
+                        Error::JsonParseFailed(je) => je.to_string(),
+                        e => e.to_string(),
+                    };
+                    Error::EventParseError(id, format!("could not parse event: {reason}"))
+                }
+                None => Error::ProtoParseError,
+            }
+        });
+    }
     let parsed_res: Result<NostrMessage> =
         serde_json::from_str(msg).map_err(std::convert::Into::into);
     match parsed_res {
@@ -528,7 +2158,7 @@ fn convert_to_msg(msg: &str, max_bytes: Option<usize>) -> Result<NostrMessage> {
                 if let Some(max_size) = max_bytes {
                     // check length, ensure that some max size is set.
                     if msg.len() > max_size && max_size > 0 {
-                        return Err(Error::EventMaxLengthError(msg.len()));
+                        return Err(Error::EventMaxLengthError(msg.len(), unparsed_event_id(msg)));
                     }
                 }
             }
@@ -537,19 +2167,184 @@ fn convert_to_msg(msg: &str, max_bytes: Option<usize>) -> Result<NostrMessage> {
         Err(e) => {
             trace!("proto parse error: {:?}", e);
             trace!("parse error on message: {:?}", msg.trim());
+            // a subscription with invalid filters can be closed.
+            if is_command(msg, "REQ") {
+                if let Some(sub_id) = unparsed_sub_id(msg) {
+                    // parsing the subscription alone tells what was
+                    // wrong with it.
+                    let reason = match serde_json::from_str(msg).and_then(serde_json::from_value::<Subscription>) {
+                        Err(sub_err) => sub_err.to_string(),
+                        Ok(_) => e.to_string(),
+                    };
+                    return Err(Error::SubParseError(sub_id, reason));
+                }
+            }
             Err(Error::ProtoParseError)
         }
     }
 }
 
-/// Turn a string into a NOTICE message ready to send over a `WebSocket`
+/// Does a message start with a command, such as `EVENT`?
+fn is_command(msg: &str, cmd: &str) -> bool {
+    msg.trim_start()
+        .strip_prefix('[')
+        .and_then(|m| m.trim_start().strip_prefix('"'))
+        .and_then(|m| m.strip_prefix(cmd))
+        .is_some_and(|m| m.starts_with('"'))
+}
+
+/// Subscription id of a `REQ` message that could not be parsed, if
+/// it has one.
+fn unparsed_sub_id(msg: &str) -> Option<String> {
+    let parts: Vec<serde_json::Value> = serde_json::from_str(msg).ok()?;
+    parts.get(1)?.as_str().map(str::to_owned)
+}
+
+/// Id of an `EVENT` message that could not be parsed, if it has one.
+fn unparsed_event_id(msg: &str) -> Option<String> {
+    #[derive(Deserialize)]
+    struct EventId {
+        id: String,
+    }
+    let (_, event): (IgnoredAny, EventId) = serde_json::from_str(msg).ok()?;
+    Some(event.id)
+}
+
+/// Turn a relay message into a `WebSocket` message
+fn make_message(msg: &OutboundMessage) -> Message {
+    Message::text(msg.to_json())
+}
+
+/// Turn a notice into a message ready to send over a `WebSocket`
 fn make_notice_message(notice: &Notice) -> Message {
-    let json = match notice {
-        Notice::Message(ref msg) => json!(["NOTICE", msg]),
-        Notice::EventResult(ref res) => json!(["OK", res.id, res.status.to_bool(), res.msg]),
-    };
+    make_message(&OutboundMessage::from(notice))
+}
+
+/// Turn a negentropy reply into a `NEG-MSG` message
+fn make_neg_message(id: &str, reply: &[u8]) -> Message {
+    make_message(&OutboundMessage::NegMsg { sub_id: id, message: &hex::encode(reply) })
+}
+
+/// Turn a reason for ending a negentropy sync into a `NEG-ERR` message
+fn make_neg_err_message(id: &str, reason: &str) -> Message {
+    make_message(&OutboundMessage::NegErr { sub_id: id, reason })
+}
+
+/// Could a serialized event be a direct message?  Avoids parsing
+/// every query result when DMs are protected.
+fn may_be_dm_json(event_json: &str) -> bool {
+    event_json.contains("\"kind\":4,") || event_json.contains("\"kind\":1059,")
+}
+
+/// Should a private message be withheld from a client?  Gift wraps
+/// in private inbox mode are only for their recipient, and protected
+/// DMs are only for their author or recipient.
+fn is_withheld_dm(conn: &conn::ClientConn, event: &Event, private_inbox: bool, dm_read_protection: bool) -> bool {
+    (private_inbox && event.is_gift_wrap() && !conn.is_gift_wrap_recipient(event))
+        || (dm_read_protection && event.is_direct_message() && !conn.is_dm_participant(event))
+}
+
+/// May a serialized event be sent to a client?  Group events are only
+/// for members, and private messages only for their participants.
+fn is_visible_json(event_json: &str, conn: &conn::ClientConn, groups: &GroupRegistry, settings: &Settings) -> bool {
+    let private_inbox = settings.authorization.private_inbox;
+    let dm_read_protection = settings.authorization.dm_read_protection;
+    groups.can_read_json(event_json, conn.auth_pubkey())
+        && !((private_inbox || dm_read_protection)
+            && may_be_dm_json(event_json)
+            && serde_json::from_str::<Event>(event_json)
+                .iter()
+                .any(|e| is_withheld_dm(conn, e, private_inbox, dm_read_protection)))
+}
+
+/// Count the events matching a request that a client may read.  If
+/// some events are only for group members or message participants,
+/// the matching events are fetched (as a REST query would be) and
+/// checked one by one.
+async fn count_visible_events(
+    repo: &Arc<dyn NostrRepo>,
+    mut sub: Subscription,
+    conn: &conn::ClientConn,
+    groups: &GroupRegistry,
+    settings: &Settings,
+) -> Result<u64> {
+    let auth = &settings.authorization;
+    if !groups.is_enabled() && !auth.private_inbox && !auth.dm_read_protection {
+        return repo.count_events_by_filter(sub.filters).await;
+    }
+    for f in &mut sub.filters {
+        f.limit = Some(f.limit.map_or(MAX_QUERY_EVENTS, |l| l.min(MAX_QUERY_EVENTS)));
+    }
+    let events = repo::collect_events(repo.as_ref(), sub, conn.get_client_prefix()).await?;
+    Ok(events.iter().filter(|e| is_visible_json(e, conn, groups, settings)).count() as u64)
+}
+
+/// Does a subscription only request protected private messages?
+/// Unauthenticated clients would receive none of them.
+fn only_private_messages(s: &Subscription, private_inbox: bool, dm_read_protection: bool) -> bool {
+    s.filters.iter().all(|f| {
+        f.kinds.as_ref().is_some_and(|kinds| {
+            !kinds.is_empty()
+                && kinds.iter().all(|k| {
+                    (*k == 1059 && (private_inbox || dm_read_protection)) || (*k == 4 && dm_read_protection)
+                })
+        })
+    })
+}
+
+/// Bandwidth for a client, in bytes per second, by its authenticated
+/// pubkey.  Whitelisted pubkeys are not limited.
+fn client_bandwidth(settings: &Settings, auth_pubkey: Option<&String>) -> Option<u32> {
+    let limits = &settings.limits;
+    match auth_pubkey {
+        None => limits.client_bytes_per_sec,
+        Some(pk) if settings.authorization.pubkey_whitelist.as_ref().is_some_and(|wl| wl.contains(pk)) => None,
+        Some(_) => limits.auth_client_bytes_per_sec.or(limits.client_bytes_per_sec),
+    }
+}
 
-    Message::text(json.to_string())
+/// Check if an event lacks the proof of work (NIP-13) required of
+/// its author, returning the required difficulty.  Trusted authors
+/// need none, and untrusted authors need more.
+async fn missing_pow(
+    repo: &Arc<dyn NostrRepo>,
+    settings: &Settings,
+    event: &Event,
+    auth_pubkey: Option<&String>,
+    standing: Standing,
+) -> Option<u32> {
+    let base = settings.options.min_pow_difficulty.unwrap_or(0);
+    let min = match standing {
+        Standing::Trusted => 0,
+        Standing::Normal => base,
+        Standing::Untrusted => base + settings.reputation.untrusted_extra_pow,
+    };
+    if min == 0 {
+        return None;
+    }
+    if event.has_pow(min) {
+        return None;
+    }
+    if settings.options.pow_exempt_whitelisted {
+        let whitelisted = |pk: &String| {
+            settings
+                .authorization
+                .pubkey_whitelist
+                .as_ref()
+                .is_some_and(|wl| wl.contains(pk))
+        };
+        if whitelisted(&event.pubkey) || auth_pubkey.is_some_and(whitelisted) {
+            return None;
+        }
+    }
+    if settings.options.pow_exempt_verified && settings.verified_users.is_active() {
+        if let Ok(uv) = repo.get_latest_user_verification(&event.pubkey).await {
+            if uv.is_valid(&settings.verified_users) {
+                return None;
+            }
+        }
+    }
+    Some(min)
 }
 
 struct ClientInfo {
@@ -558,40 +2353,176 @@ struct ClientInfo {
     origin: Option<String>,
 }
 
+/// Count abuse from a client address, and store the ban once it has
+/// abused the relay too often.
+pub async fn record_abuse(
+    repo: &Arc<dyn NostrRepo>,
+    bans: &BanRegistry,
+    metrics: &NostrMetrics,
+    ip: &str,
+    abuse: Abuse,
+) {
+    if let Some(ban) = bans.record_abuse(ip, abuse) {
+        info!("banning address {} ({})", ip, ban.reason);
+        metrics.bans.with_label_values(&[abuse.label()]).inc();
+        if let Err(e) = repo.add_ban(&ban).await {
+            warn!("could not store ban: {:?}", e);
+        }
+    }
+}
+
+/// What to do with a valid event from a client
+enum Admission {
+    /// Write the event to the database, sending it to subscribers
+    /// if `broadcast` is set
+    Store { broadcast: bool },
+    /// Tell the client the event was saved, without storing it
+    ShadowBanned,
+    /// Reply to the client, without storing the event
+    Reply(Notice),
+}
+
+/// Check an event from a client before it is written, running admin
+/// commands.  Websocket and HTTP clients share these checks.
+#[allow(clippy::too_many_arguments)]
+async fn admit_event(
+    e: &Event,
+    conn: &conn::ClientConn,
+    repo: &Arc<dyn NostrRepo>,
+    settings: &Settings,
+    bans: &BanRegistry,
+    reputations: &Reputations,
+    event_limiter: &EventRateLimiter,
+    admin: Option<&AdminChannel>,
+    metrics: &NostrMetrics,
+) -> Admission {
+    // check if the author is banned.
+    if bans.is_banned(BanTarget::Pubkey, &e.pubkey) {
+        info!(id = %e.get_event_id_prefix(), pubkey = %e.pubkey, "event from a banned pubkey");
+        return Admission::Reply(Notice::blocked(e.id.clone(), "this pubkey is banned"));
+    }
+    if bans.is_shadow_banned(&e.pubkey) {
+        info!(id = %e.get_event_id_prefix(), pubkey = %e.pubkey, "event from a shadow-banned pubkey");
+        return Admission::ShadowBanned;
+    }
+    // commands to the relay are run, not stored.
+    if let Some(admin) = admin.filter(|a| a.is_command(e)) {
+        if conn.auth_pubkey() == Some(&e.pubkey) {
+            admin.handle(e).await;
+            return Admission::Reply(Notice::saved(e.id.clone()));
+        }
+        info!(id = %e.get_event_id_prefix(), "admin command without authenticating");
+        return Admission::Reply(Notice::auth_required(e.id.clone(), "admin commands require authentication"));
+    }
+    // check if the relay accepts events of this kind.
+    if !settings.limits.accepts_kind(e.kind) {
+        info!(id = %e.get_event_id_prefix(), kind = e.kind, "event kind not accepted");
+        return Admission::Reply(Notice::blocked(e.id.clone(), db::KIND_NOT_ACCEPTED));
+    }
+    // check if the client's tier allows the event.
+    if let Err(refusal) = conn.check_tier(e) {
+        info!(id = %e.get_event_id_prefix(), kind = e.kind, tier = ?conn.tier_name(), ?refusal, "event refused by tier");
+        return Admission::Reply(match refusal {
+            TierRefusal::RateLimited => Notice::rate_limited(e.id.clone(), refusal.message()),
+            _ => Notice::blocked(e.id.clone(), refusal.message()),
+        });
+    }
+    let standing = reputations.standing(&e.pubkey).await;
+    // check if the author or client is publishing too fast.
+    if let Err(limited) = event_limiter.check(e, conn.ip(), reputations.rate_cost(standing)) {
+        info!(id = %e.get_event_id_prefix(), kind = e.kind, limit = limited.label(), "event rate limit exceeded");
+        metrics.rate_limited_events.with_label_values(&[limited.label()]).inc();
+        return Admission::Reply(Notice::rate_limited(e.id.clone(), limited.message()));
+    }
+    // check if the event has enough proof of work.
+    if let Some(min) = missing_pow(repo, settings, e, conn.auth_pubkey(), standing).await {
+        info!(id = %e.get_event_id_prefix(), difficulty = e.pow_difficulty(), required = min, "event without enough proof of work");
+        let msg = format!("difficulty {} is less than {}", e.pow_difficulty(), min);
+        return Admission::Reply(Notice::pow(e.id.clone(), &msg));
+    }
+    // check if the event has too many tags, or tags that are too long.
+    if let Err(msg) = e.check_tag_limits(settings.limits.max_event_tags, settings.limits.max_tag_value_bytes) {
+        info!(id = %e.get_event_id_prefix(), tags = e.tags.len(), "event exceeds tag limits");
+        return Admission::Reply(Notice::invalid(e.id.clone(), &msg));
+    }
+    // check if the event is too far in the future.
+    if !e.is_valid_timestamp(settings.options.reject_future_seconds) {
+        info!(id = %e.get_event_id_prefix(), created_at = e.created_at, "far future-dated event");
+        let fut_sec = settings.options.reject_future_seconds.unwrap_or_default();
+        let msg = format!("The event created_at field is out of the acceptable range (+{fut_sec}sec) for this relay.");
+        return Admission::Reply(Notice::invalid(e.id.clone(), &msg));
+    }
+    // check if the event is too far in the past.  Backdated events
+    // may be stored without being sent to subscribers.
+    if e.is_too_old(settings.options.reject_past_seconds) {
+        if settings.options.store_past_events {
+            debug!(id = %e.get_event_id_prefix(), created_at = e.created_at, "storing backdated event without broadcasting it");
+            return Admission::Store { broadcast: false };
+        }
+        info!(id = %e.get_event_id_prefix(), created_at = e.created_at, "backdated event");
+        let past_sec = settings.options.reject_past_seconds.unwrap_or_default();
+        let msg = format!("The event created_at field is out of the acceptable range (-{past_sec}sec) for this relay.");
+        return Admission::Reply(Notice::invalid(e.id.clone(), &msg));
+    }
+    Admission::Store { broadcast: true }
+}
+
+/// Limit on subscriptions a client may open, if
+/// `subscriptions_per_min` is set.
+fn subscription_limiter(
+    per_min: Option<u32>,
+) -> Option<RateLimiter<NotKeyed, InMemoryState, DefaultClock>> {
+    let per_min = per_min.and_then(core::num::NonZeroU32::new)?;
+    trace!("Rate limits for sub creation ({}/min)", per_min);
+    Some(RateLimiter::direct(Quota::per_minute(per_min)))
+}
+
 /// Handle new client connections.  This runs through an event loop
 /// for all client communication.
 #[allow(clippy::too_many_arguments)]
 async fn nostr_server(
     repo: Arc<dyn NostrRepo>,
     client_info: ClientInfo,
-    settings: Settings,
-    mut ws_stream: WebSocketStream<Upgraded>,
-    broadcast: Sender<Event>,
+    mut settings_rx: watch::Receiver<Settings>,
+    ws_stream: WebSocketStream<Upgraded>,
+    matcher: Matcher,
+    verifier: SignatureVerifier,
+    event_limiter: Arc<EventRateLimiter>,
+    bans: Arc<BanRegistry>,
+    reputations: Arc<Reputations>,
+    admin: Option<Arc<AdminChannel>>,
+    status: Arc<RelayStatus>,
+    tiers: Option<Arc<Tiers>>,
     event_tx: mpsc::Sender<SubmittedEvent>,
     mut shutdown: Receiver<()>,
     metrics: NostrMetrics,
+    groups: Arc<GroupRegistry>,
+    admission: Option<AdmissionClient>,
 ) {
     // the time this websocket nostr server started
     let orig_start = Instant::now();
-    // get a broadcast channel for clients to communicate on
-    let mut bcast_rx = broadcast.subscribe();
+    // settings may be reloaded while the client is connected
+    let mut settings = settings_rx.borrow_and_update().clone();
+    // queue outgoing messages, so a slow client does not stall us
+    let (ws_sink, mut ws_stream) = ws_stream.split();
+    // limit the bandwidth used in each direction
+    let inbound = Throttle::new(settings.limits.client_bytes_per_sec);
+    let outbound = Arc::new(Throttle::new(settings.limits.client_bytes_per_sec));
+    let (outbox, _) = Outbox::start(ws_sink, settings.limits.outbound_buffer, outbound.clone());
+    // register for new events matching our subscriptions
+    let (matcher_conn, mut matched_rx) = matcher.connect(settings.limits.broadcast_buffer);
     // Track internal client state
     let mut conn = conn::ClientConn::new(client_info.remote_ip);
+    conn.set_limits(&settings.limits);
+    // clients use the default tier until they authenticate
+    conn.set_tier(tiers.as_ref().and_then(|t| t.default_tier()));
     // subscription creation rate limiting
-    let mut sub_lim_opt = None;
+    let mut sub_lim_opt = subscription_limiter(settings.limits.subscriptions_per_min);
     // 100ms jitter when the rate limiter returns
     let jitter = Jitter::up_to(Duration::from_millis(100));
-    let sub_per_min_setting = settings.limits.subscriptions_per_min;
-    if let Some(sub_per_min) = sub_per_min_setting {
-        if sub_per_min > 0 {
-            trace!("Rate limits for sub creation ({}/min)", sub_per_min);
-            let quota_time = core::num::NonZeroU32::new(sub_per_min).unwrap();
-            let quota = Quota::per_minute(quota_time);
-            sub_lim_opt = Some(RateLimiter::direct(quota));
-        }
-    }
     // Use the remote IP as the client identifier
     let cid = conn.get_client_prefix();
+    tracing::Span::current().record("cid", cid.as_str());
     // Create a channel for receiving query results from the database.
     // we will send out the tx handle to any query we generate.
     // this has capacity for some of the larger requests we see, which
@@ -616,28 +2547,78 @@ async fn nostr_server(
     // when these subscriptions are cancelled, make a message
     // available to the executing query so it knows to stop.
     let mut running_queries: HashMap<String, oneshot::Sender<()>> = HashMap::new();
+    // queries resending events that subscriptions missed, with the
+    // time they start from and their generation.  their results come
+    // on their own channel, so that their EOSE is not sent to the
+    // client, tagged with the generation, so that results still queued
+    // from a replay that was restarted are ignored.
+    let mut replays: HashMap<String, (u64, u64, oneshot::Sender<()>)> = HashMap::new();
+    let mut replay_generation: u64 = 0;
+    let (replay_tx, mut replay_rx) = mpsc::channel::<(u64, db::QueryResult)>(20_000);
+    // open negentropy (NIP-77) syncs, with the events each covers.
+    let mut neg_sessions: HashMap<String, NegentropyStorage> = HashMap::new();
     // for stats, keep track of how many events the client published,
     // and how many it received from queries.
     let mut client_published_event_count: usize = 0;
     let mut client_received_event_count: usize = 0;
-    info!("new client connection (cid: {}, ip: {:?})", cid, conn.ip());
+    let mut client_missed_event_count: u64 = 0;
+    let mut client_rate_limited_count: u64 = 0;
+    info!("new client connection");
+    // keep client headers for authorization requests
+    let client_origin = client_info.origin.clone();
+    let client_user_agent = client_info.user_agent.clone();
     let origin = client_info.origin.unwrap_or_else(|| "<unspecified>".into());
     let user_agent = client_info
         .user_agent
         .unwrap_or_else(|| "<unspecified>".into());
-    info!(
-        "cid: {}, origin: {:?}, user-agent: {:?}",
-        cid, origin, user_agent
-    );
+    info!(%origin, %user_agent, "client headers");
 
     // Measure connections
     metrics.connections.inc();
+    metrics.client_connected(client_origin.as_deref(), client_user_agent.as_deref());
+
+    // consult the external authorization service
+    if let Some(mut client) = admission {
+        let info = nauthz::Client {
+            ip_addr: conn.ip().to_string(),
+            origin: client_origin.clone(),
+            user_agent: client_user_agent.clone(),
+        };
+        match client.admit_connection(info).await {
+            Ok(reply) if reply.is_denied() => {
+                info!("connection denied by authorization service");
+                let msg = reply
+                    .message
+                    .unwrap_or_else(|| "connection denied by relay policy".to_owned());
+                outbox.send(make_notice_message(&Notice::message(msg))).await;
+                outbox.send(Message::Close(None)).await;
+                metrics.disconnects.with_label_values(&["denied"]).inc();
+                return;
+            }
+            Ok(_) => {}
+            Err(e) => warn!("authorization service failed, permitting connection: {:?}", e),
+        }
+    }
+
+    // only serve gift wraps to their recipient (NIP-59)
+    let private_inbox = settings.authorization.private_inbox;
+    // only serve DMs to their author or recipient
+    let dm_read_protection = settings.authorization.dm_read_protection;
+
+    // challenge the client to authenticate (NIP-42)
+    if settings.authorization.nip42_auth {
+        conn.generate_auth_challenge();
+        if let Some(challenge) = conn.auth_challenge() {
+            outbox.send(
+                make_notice_message(&Notice::AuthChallenge(challenge.to_string()))).await;
+        }
+    }
 
     loop {
         tokio::select! {
             _ = shutdown.recv() => {
         metrics.disconnects.with_label_values(&["shutdown"]).inc();
-                info!("Close connection down due to shutdown, client: {}, ip: {:?}, connected: {:?}", cid, conn.ip(), orig_start.elapsed());
+                info!(duration_ms = orig_start.elapsed().as_millis() as u64, "closing connection due to shutdown");
                 // server shutting down, exit loop
                 break;
             },
@@ -650,45 +2631,109 @@ async fn nostr_server(
                     break;
                 }
                 // Send a ping
-                ws_stream.send(Message::Ping(Vec::new())).await.ok();
+                outbox.send(Message::Ping(Vec::new())).await;
+            },
+            Ok(()) = settings_rx.changed() => {
+                let reloaded = settings_rx.borrow_and_update().clone();
+                if reloaded.limits.subscriptions_per_min != settings.limits.subscriptions_per_min {
+                    sub_lim_opt = subscription_limiter(reloaded.limits.subscriptions_per_min);
+                }
+                settings = reloaded;
+                let rate = client_bandwidth(&settings, conn.auth_pubkey());
+                inbound.set_rate(rate);
+                outbound.set_rate(rate);
             },
             Some(notice_msg) = notice_rx.recv() => {
-                ws_stream.send(make_notice_message(&notice_msg)).await.ok();
+                status.notice_sent(&notice_msg);
+                metrics.notice_sent(&notice_msg);
+                outbox.send(make_notice_message(&notice_msg)).await;
             },
             Some(query_result) = query_rx.recv() => {
                 // database informed us of a query result we asked for
-                let subesc = query_result.sub_id.replace('"', "");
                 if query_result.event == "EOSE" {
-                    let send_str = format!("[\"EOSE\",\"{subesc}\"]");
-                    ws_stream.send(Message::Text(send_str)).await.ok();
+                    outbox.send(make_message(&OutboundMessage::Eose { sub_id: &query_result.sub_id })).await;
+                } else if !is_visible_json(&query_result.event, &conn, &groups, &settings) {
+                    trace!("withholding event from non-member or non-participant");
                 } else {
                     client_received_event_count += 1;
             metrics.sent_events.with_label_values(&["db"]).inc();
                     // send a result
-                    let send_str = format!("[\"EVENT\",\"{}\",{}]", subesc, &query_result.event);
-                    ws_stream.send(Message::Text(send_str)).await.ok();
+                    outbox.send(make_message(&OutboundMessage::Event { sub_id: &query_result.sub_id, event: &query_result.event })).await;
+                }
+            },
+            Some((generation, replayed)) = replay_rx.recv() => {
+                // only resend events for replays that are still wanted.
+                let wanted = replays.get(&replayed.sub_id).is_some_and(|(_, g, _)| *g == generation);
+                if !wanted {
+                    trace!("ignoring result of a stopped replay");
+                } else if replayed.event == "EOSE" {
+                    replays.remove(&replayed.sub_id);
+                } else if is_visible_json(&replayed.event, &conn, &groups, &settings) {
+                    client_received_event_count += 1;
+                    metrics.sent_events.with_label_values(&["replay"]).inc();
+                    outbox.send(make_message(&OutboundMessage::Event { sub_id: &replayed.sub_id, event: &replayed.event })).await;
                 }
             },
-            // TODO: consider logging the LaggedRecv error
-            Ok(global_event) = bcast_rx.recv() => {
-                // an event has been broadcast to all clients
-                // first check if there is a subscription for this event.
-                for (s, sub) in conn.subscriptions() {
-                    if !sub.interested_in_event(&global_event) {
+            Some(matched) = matched_rx.recv() => {
+                // events were dropped before this one; tell the
+                // client, and resend them if configured to.
+                if let Some(missed) = matched.missed {
+                    client_missed_event_count += missed.events;
+                    metrics.missed_events.inc_by(missed.events);
+                    info!(events = missed.events, since = missed.since, subs = missed.sub_ids.len(), "client missed realtime events");
+                    let replay = settings.limits.replay_missed_events;
+                    for sub_id in missed.sub_ids.iter().filter(|_| replay) {
+                        // the subscription may have closed since.
+                        let Some(sub) = conn.subscriptions().get(sub_id) else {
+                            continue;
+                        };
+                        // a replay already running for the subscription
+                        // is restarted to cover both windows.
+                        let since = replays.remove(sub_id).map_or(missed.since, |(since, _, stop_tx)| {
+                            stop_tx.send(()).ok();
+                            since.min(missed.since)
+                        });
+                        replay_generation += 1;
+                        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+                        replays.insert(sub_id.clone(), (since, replay_generation, stop_tx));
+                        // tag the replay's results with its generation.
+                        let (results_tx, mut results_rx) = mpsc::channel::<db::QueryResult>(1_000);
+                        let (generation, tagged_tx) = (replay_generation, replay_tx.clone());
+                        tokio::spawn(async move {
+                            while let Some(result) = results_rx.recv().await {
+                                if tagged_tx.send((generation, result)).await.is_err() {
+                                    break;
+                                }
+                            }
+                        });
+                        repo.query_subscription(sub.since(since), cid.clone(), results_tx, stop_rx).await.ok();
+                    }
+                    let action = if replay { "they are being sent again" } else { "repeat the subscription to catch up" };
+                    let notice = Notice::message(format!(
+                        "relay fell behind, {} realtime events may have been missed since {}; {action}",
+                        missed.events, missed.since));
+                    outbox.send(make_notice_message(&notice)).await;
+                }
+                // a broadcast event matched some of our subscriptions.
+                let global_event = matched.event;
+                // group events are only sent to members.
+                if !groups.can_read(&global_event, conn.auth_pubkey()) {
+                    continue;
+                }
+                // private messages are only sent to participants.
+                if is_withheld_dm(&conn, &global_event, private_inbox, dm_read_protection) {
+                    continue;
+                }
+                for s in matched.sub_ids {
+                    // the subscription may have closed since the match.
+                    if !conn.subscriptions().contains_key(&s) {
                         continue;
                     }
-                    // TODO: serialize at broadcast time, instead of
-                    // once for each consumer.
-                    if let Ok(event_str) = serde_json::to_string(&global_event) {
-                        trace!("sub match for client: {}, sub: {:?}, event: {:?}",
-                               cid, s,
-                               global_event.get_event_id_prefix());
-                        // create an event response and send it
-                        let subesc = s.replace('"', "");
+                    trace!(sub_id = %s, id = %global_event.get_event_id_prefix(), "sub match for client");
+                    // create an event response and send it
             metrics.sent_events.with_label_values(&["realtime"]).inc();
-                        ws_stream.send(Message::Text(format!("[\"EVENT\",\"{subesc}\",{event_str}]"))).await.ok();
-                    } else {
-                        warn!("could not serialize event: {:?}", global_event.get_event_id_prefix());
+                    if outbox.send_realtime(make_message(&OutboundMessage::Event { sub_id: &s, event: global_event.json() })) {
+                        metrics.dropped_events.inc();
                     }
                 }
             },
@@ -698,11 +2743,12 @@ async fn nostr_server(
                 // Consume text messages from the client, parse into Nostr messages.
                 let nostr_msg = match ws_next {
                     Some(Ok(Message::Text(m))) => {
+                        inbound.consume(m.len()).await;
                         convert_to_msg(&m,settings.limits.max_event_bytes)
                     },
                     Some(Ok(Message::Binary(_))) => {
-                        ws_stream.send(
-                            make_notice_message(&Notice::message("binary messages are not accepted".into()))).await.ok();
+                        outbox.send(
+                            make_notice_message(&Notice::message("binary messages are not accepted".into()))).await;
                         continue;
                     },
                     Some(Ok(Message::Ping(_) | Message::Pong(_))) => {
@@ -711,8 +2757,9 @@ async fn nostr_server(
                         continue;
                     },
                     Some(Err(WsError::Capacity(MessageTooLong{size, max_size}))) => {
-                        ws_stream.send(
-                            make_notice_message(&Notice::message(format!("message too large ({size} > {max_size})")))).await.ok();
+                        record_abuse(&repo, &bans, &metrics, conn.ip(), Abuse::Oversized).await;
+                        outbox.send(
+                            make_notice_message(&Notice::message(format!("message too large ({size} > {max_size})")))).await;
                         continue;
                     },
                     None |
@@ -720,61 +2767,141 @@ async fn nostr_server(
                          Err(WsError::AlreadyClosed | WsError::ConnectionClosed |
                              WsError::Protocol(tungstenite::error::ProtocolError::ResetWithoutClosingHandshake)))
                         => {
-                            debug!("websocket close from client (cid: {}, ip: {:?})",cid, conn.ip());
+                            debug!("websocket close from client");
                 metrics.disconnects.with_label_values(&["normal"]).inc();
                             break;
                         },
                     Some(Err(WsError::Io(e))) => {
                         // IO errors are considered fatal
-                        warn!("IO error (cid: {}, ip: {:?}): {:?}", cid, conn.ip(), e);
+                        warn!(error = ?e, "IO error");
             metrics.disconnects.with_label_values(&["error"]).inc();
 
                         break;
                     }
                     x => {
                         // default condition on error is to close the client connection
-                        info!("unknown error (cid: {}, ip: {:?}): {:?} (closing conn)", cid, conn.ip(), x);
+                        info!(error = ?x, "unknown error, closing connection");
             metrics.disconnects.with_label_values(&["error"]).inc();
 
                         break;
                     }
                 };
 
+                // count abuse towards banning the client address
+                let abuse = match &nostr_msg {
+                    Err(Error::EventMaxLengthError(..)) => Some(Abuse::Oversized),
+                    Err(Error::ProtoParseError | Error::EventParseError(..) | Error::SubParseError(..)) => Some(Abuse::ParseError),
+                    _ => None,
+                };
+                if let Some(abuse) = abuse {
+                    record_abuse(&repo, &bans, &metrics, conn.ip(), abuse).await;
+                }
+                if bans.is_banned(BanTarget::Ip, conn.ip()) {
+                    info!("disconnecting banned client");
+                    outbox.send(make_notice_message(&Notice::message("blocked: this address is banned".into()))).await;
+                    outbox.send(Message::Close(None)).await;
+            metrics.disconnects.with_label_values(&["banned"]).inc();
+                    break;
+                }
+
                 // convert ws_next into proto_next
                 match nostr_msg {
                     Ok(NostrMessage::EventMsg(ec)) => {
                         // An EventCmd needs to be validated to be converted into an Event
                         // handle each type of message
                         let evid = ec.event_id().to_owned();
-                        let parsed : Result<Event> = Result::<Event>::from(ec);
-            metrics.cmd_event.inc();
+                        let parsed : Result<EventWrapper> = verifier.verify(ec).await;
                         match parsed {
-                            Ok(e) => {
+                            Ok(EventWrapper::WrappedEvent(e)) => {
+            metrics.cmd_event.inc();
                                 let id_prefix:String = e.id.chars().take(8).collect();
-                                debug!("successfully parsed/validated event: {:?} (cid: {}, kind: {})", id_prefix, cid, e.kind);
-                                // check if the event is too far in the future.
-                                if e.is_valid_timestamp(settings.options.reject_future_seconds) {
-                                    // Write this to the database.
-                                    let submit_event = SubmittedEvent { event: e.clone(), notice_tx: notice_tx.clone(), source_ip: conn.ip().to_string()};
-                                    event_tx.send(submit_event).await.ok();
-                                    client_published_event_count += 1;
-                                } else {
-                                    info!("client: {} sent a far future-dated event", cid);
-                                    if let Some(fut_sec) = settings.options.reject_future_seconds {
-                                        let msg = format!("The event created_at field is out of the acceptable range (+{fut_sec}sec) for this relay.");
-                                        let notice = Notice::invalid(e.id, &msg);
-                                        ws_stream.send(make_notice_message(&notice)).await.ok();
+                                debug!(id = %id_prefix, kind = e.kind, pubkey = %e.pubkey, "successfully parsed/validated event");
+                                match admit_event(&e, &conn, &repo, &settings, &bans, &reputations, &event_limiter, admin.as_deref(), &metrics).await {
+                                    Admission::Store { broadcast } => {
+                                        status.event_received(&e);
+                                        metrics.event_received(&e);
+                                        // Write this to the database.
+                                        let auth_pubkey = conn.auth_pubkey().cloned();
+                                        let submit_event = SubmittedEvent { event: e.clone(), notice_tx: notice_tx.clone(), source_ip: conn.ip().to_string(), auth_pubkey, origin: client_origin.clone(), user_agent: client_user_agent.clone(), broadcast };
+                                        if let Err(notice) = db::submit_event(&event_tx, submit_event) {
+                                            status.notice_sent(&notice);
+                                            metrics.notice_sent(&notice);
+                                            outbox.send(make_notice_message(&notice)).await;
+                                        } else {
+                                            client_published_event_count += 1;
+                                        }
+                                    },
+                                    Admission::ShadowBanned => {
+                                        // shadow-banned authors see their events accepted,
+                                        // on this connection only.
+                                        outbox.send(make_notice_message(&Notice::saved(e.id.clone()))).await;
+                                        let json = serde_json::to_string(&e).unwrap_or_default();
+                                        for (s, sub) in conn.subscriptions() {
+                                            if sub.interested_in_event(&e) {
+                                                outbox.send(make_message(&OutboundMessage::Event { sub_id: s, event: &json })).await;
+                                            }
+                                        }
+                                    },
+                                    Admission::Reply(notice) => {
+                                        if matches!(&notice, Notice::EventResult(r) if matches!(r.status, EventResultStatus::RateLimited)) {
+                                            client_rate_limited_count += 1;
+                                        }
+                                        status.notice_sent(&notice);
+                                        metrics.notice_sent(&notice);
+                                        outbox.send(make_notice_message(&notice)).await;
+                                    },
+                                }
+                            },
+                            Ok(EventWrapper::WrappedAuth(event)) => {
+                                if !settings.authorization.nip42_auth {
+                                    info!("client sent AUTH, but authentication is disabled");
+                                    outbox.send(make_notice_message(&Notice::invalid(evid, &format!("{}", Error::CommandUnknownError)))).await;
+                                    continue;
+                                }
+                                let id_prefix:String = event.id.chars().take(8).collect();
+                                debug!(id = %id_prefix, "successfully parsed auth");
+                                if let Some(relay_url) = &settings.info.relay_url {
+                                    let was_authenticated = conn.auth_pubkey().is_some();
+                                    match conn.authenticate(&event, relay_url) {
+                                        Ok(()) => {
+                                            if let Some(pubkey) = conn.auth_pubkey() {
+                                                tracing::Span::current().record("pubkey", pubkey.as_str());
+                                            }
+                                            info!("client is authenticated");
+                                            // assign the client to its tier, once.
+                                            if let (Some(tiers), Some(pk), false) = (&tiers, conn.auth_pubkey().cloned(), was_authenticated) {
+                                                let tier = tiers.resolve(&pk).await;
+                                                conn.set_tier(tier);
+                                                debug!(tier = ?conn.tier_name(), "client assigned to tier");
+                                            }
+                                            let rate = client_bandwidth(&settings, conn.auth_pubkey());
+                                            inbound.set_rate(rate);
+                                            outbound.set_rate(rate);
+                                            outbox.send(make_notice_message(&Notice::saved(event.id))).await;
+                                        },
+                                        Err(e) => {
+                                            info!(error = %e, "authentication error");
+                                            outbox.send(make_notice_message(&Notice::restricted(event.id, &format!("authentication error: {e}")))).await;
+                                        },
                                     }
+                                } else {
+                                    error!("AUTH command received, but relay_url is not set in the config file");
+                                    outbox.send(make_notice_message(&Notice::error(event.id, "relay is not configured for authentication"))).await;
                                 }
                             },
                             Err(e) => {
-                                info!("client sent an invalid event (cid: {})", cid);
-                                ws_stream.send(make_notice_message(&Notice::invalid(evid, &format!("{e}")))).await.ok();
+            metrics.cmd_event.inc();
+                                info!(error = %e, "client sent an invalid event");
+                                let notice = Notice::invalid(evid, &format!("{e}"));
+                                status.notice_sent(&notice);
+                                metrics.notice_sent(&notice);
+                                outbox.send(make_notice_message(&notice)).await;
                             }
                         }
                     },
-                    Ok(NostrMessage::SubMsg(s)) => {
-                        debug!("subscription requested (cid: {}, sub: {:?})", cid, s.id);
+                    Ok(NostrMessage::SubMsg(mut s)) => {
+                        debug!(sub_id = %s.id, filters = s.filters.len(), "subscription requested");
+                        s.clamp_limits(settings.limits.max_limit);
                         // subscription handling consists of:
                         // * check for rate limits
                         // * registering the subscription so future events can be matched
@@ -782,31 +2909,102 @@ async fn nostr_server(
                         // * sending a request for a SQL query
                         // Do nothing if the sub already exists.
                         if conn.has_subscription(&s) {
-                            info!("client sent duplicate subscription, ignoring (cid: {}, sub: {:?})", cid, s.id);
+                            info!(sub_id = %s.id, "client sent duplicate subscription, ignoring");
+                        } else if sub_lim_opt.as_ref().is_some_and(|lim| lim.check().is_err()) {
+                metrics.cmd_req.inc();
+                            client_rate_limited_count += 1;
+                            info!(sub_id = %s.id, "client exceeded subscription rate limit");
+                            outbox.send(make_notice_message(&Notice::closed(s.id.clone(), "too many subscriptions, slow down", EventResultStatus::RateLimited))).await;
+                        } else if settings.authorization.nip42_auth && conn.auth_pubkey().is_none()
+                            && only_private_messages(&s, private_inbox, dm_read_protection) {
+                metrics.cmd_req.inc();
+                            outbox.send(make_notice_message(&Notice::closed(s.id.clone(), "private messages are only served to their participants", EventResultStatus::AuthRequired))).await;
                         } else {
                 metrics.cmd_req.inc();
-                            if let Some(ref lim) = sub_lim_opt {
-                                lim.until_ready_with_jitter(jitter).await;
-                            }
                             let (abandon_query_tx, abandon_query_rx) = oneshot::channel::<()>();
                             match conn.subscribe(s.clone()) {
                                 Ok(()) => {
+                                    matcher_conn.subscribe(s.clone());
                                     // when we insert, if there was a previous query running with the same name, cancel it.
                                     if let Some(previous_query) = running_queries.insert(s.id.clone(), abandon_query_tx) {
                                         previous_query.send(()).ok();
                                     }
+                                    if let Some((_, _, previous_replay)) = replays.remove(&s.id) {
+                                        previous_replay.send(()).ok();
+                                    }
                                     if s.needs_historical_events() {
                                         // start a database query.  this spawns a blocking database query on a worker thread.
                                         repo.query_subscription(s, cid.clone(), query_tx.clone(), abandon_query_rx).await.ok();
                                     }
                                 },
                                 Err(e) => {
-                                    info!("Subscription error: {} (cid: {}, sub: {:?})", e, cid, s.id);
-                                    ws_stream.send(make_notice_message(&Notice::message(format!("Subscription error: {e}")))).await.ok();
+                                    info!(sub_id = %s.id, error = %e, "subscription error");
+                                    let status = match e {
+                                        Error::SubMaxExceededError | Error::SubLimitError(_) => EventResultStatus::Blocked,
+                                        _ => EventResultStatus::Invalid,
+                                    };
+                                    outbox.send(make_notice_message(&Notice::closed(s.id.clone(), &e.to_string(), status))).await;
+                                }
+                            }
+                        }
+                    },
+                    Ok(NostrMessage::NegOpenMsg(n)) => {
+                        metrics.cmd_neg.inc();
+                        debug!(sub_id = %n.id, "negentropy sync requested");
+                        if let Some(ref lim) = sub_lim_opt {
+                            lim.until_ready_with_jitter(jitter).await;
+                        }
+                        // opening a sync with the id of an open one replaces it.
+                        neg_sessions.remove(&n.id);
+                        if neg_sessions.len() >= negentropy::MAX_NEG_SESSIONS {
+                            outbox.send(make_neg_err_message(&n.id, "blocked: too many open syncs")).await;
+                            continue;
+                        }
+                        let visible = |e: &Event| groups.can_read(e, conn.auth_pubkey()) && !is_withheld_dm(&conn, e, private_inbox, dm_read_protection);
+                        let max_records = settings.limits.max_negentropy_records;
+                        match negentropy::load_storage(repo.as_ref(), n.filter, max_records, cid.clone(), visible).await {
+                            Ok(Some(storage)) => match storage.reconcile(&n.message) {
+                                Ok(reply) => {
+                                    outbox.send(make_neg_message(&n.id, &reply)).await;
+                                    neg_sessions.insert(n.id, storage);
+                                },
+                                Err(e) => {
+                                    info!(sub_id = %n.id, error = %e, "invalid negentropy message");
+                                    outbox.send(make_neg_err_message(&n.id, &format!("invalid: {e}"))).await;
                                 }
+                            },
+                            Ok(None) => {
+                                info!(sub_id = %n.id, "negentropy sync covers too many events");
+                                outbox.send(make_neg_err_message(&n.id, "blocked: too many events to sync")).await;
+                            },
+                            Err(e) => {
+                                info!(sub_id = %n.id, error = %e, "negentropy query failed");
+                                outbox.send(make_neg_err_message(&n.id, "error: could not query events")).await;
+                            }
+                        }
+                    },
+                    Ok(NostrMessage::NegDataMsg(m)) => {
+                        let reply = match neg_sessions.get(&m.id) {
+                            Some(storage) => storage.reconcile(&m.message),
+                            None => {
+                                outbox.send(make_neg_err_message(&m.id, "closed: unknown sync")).await;
+                                continue;
+                            }
+                        };
+                        match reply {
+                            Ok(reply) => {
+                                outbox.send(make_neg_message(&m.id, &reply)).await;
+                            },
+                            Err(e) => {
+                                info!(sub_id = %m.id, error = %e, "invalid negentropy message");
+                                neg_sessions.remove(&m.id);
+                                outbox.send(make_neg_err_message(&m.id, &format!("invalid: {e}"))).await;
                             }
                         }
                     },
+                    Ok(NostrMessage::NegCloseMsg(c)) => {
+                        neg_sessions.remove(&c.id);
+                    },
                     Ok(NostrMessage::CloseMsg(cc)) => {
                         // closing a request simply removes the subscription.
                         let parsed : Result<Close> = Result::<Close>::from(cc);
@@ -818,28 +3016,105 @@ async fn nostr_server(
                             if let Some(tx) = stop_tx {
                                 tx.send(()).ok();
                             }
+                            if let Some((_, _, tx)) = replays.remove(&c.id) {
+                                tx.send(()).ok();
+                            }
                             // stop checking new events against
                             // the subscription
+                            matcher_conn.unsubscribe(&c.id);
                             conn.unsubscribe(&c);
                         } else {
                             info!("invalid command ignored");
-                            ws_stream.send(make_notice_message(&Notice::message("could not parse command".into()))).await.ok();
+                            outbox.send(make_notice_message(&Notice::message("could not parse command".into()))).await;
+                        }
+                    },
+                    Ok(NostrMessage::RelayMsg(r)) => {
+                        if r.name == "STATS" {
+                            let mut subscriptions: Vec<String> = conn.subscriptions().keys().cloned().collect();
+                            subscriptions.sort();
+                            let stats = ConnectionStats {
+                                connected_seconds: orig_start.elapsed().as_secs(),
+                                pubkey: conn.auth_pubkey().cloned(),
+                                tier: conn.tier_name().map(str::to_owned),
+                                events_sent: client_published_event_count,
+                                events_received: client_received_event_count,
+                                events_missed: client_missed_event_count,
+                                subscriptions,
+                                max_subscriptions: conn.max_subscriptions(),
+                                rate_limits: RateLimits {
+                                    subscriptions_per_min: settings.limits.subscriptions_per_min,
+                                    bytes_per_sec: client_bandwidth(&settings, conn.auth_pubkey()),
+                                    refused: client_rate_limited_count,
+                                },
+                            };
+                            outbox.send(Message::text(stats.to_message())).await;
+                        } else {
+                            outbox.send(make_notice_message(&Notice::message(format!("unknown relay command: {}", r.name)))).await;
+                        }
+                    },
+                    Ok(NostrMessage::CountMsg(c)) => {
+                        metrics.cmd_count.inc();
+                        debug!(sub_id = %c.id, "count requested");
+                        if let Some(ref lim) = sub_lim_opt {
+                            lim.until_ready_with_jitter(jitter).await;
+                        }
+                        // counts are limited like subscriptions, and
+                        // only include events the client could read.
+                        let mut s = Subscription { id: c.id, filters: c.filters };
+                        s.clamp_limits(settings.limits.max_limit);
+                        if let Err(e) = conn.check_count(&s) {
+                            info!(sub_id = %s.id, error = %e, "count refused");
+                            outbox.send(make_notice_message(&Notice::closed(s.id.clone(), &e.to_string(), EventResultStatus::Blocked))).await;
+                        } else if settings.authorization.nip42_auth && conn.auth_pubkey().is_none()
+                            && only_private_messages(&s, private_inbox, dm_read_protection) {
+                            outbox.send(make_notice_message(&Notice::closed(s.id.clone(), "private messages are only served to their participants", EventResultStatus::AuthRequired))).await;
+                        } else {
+                            let sub_id = s.id.clone();
+                            match count_visible_events(&repo, s, &conn, &groups, &settings).await {
+                                Ok(count) => {
+                                    outbox.send(make_message(&OutboundMessage::Count { sub_id: &sub_id, count })).await;
+                                },
+                                Err(e) => {
+                                    info!(sub_id = %sub_id, error = %e, "count query failed");
+                                    outbox.send(make_notice_message(&Notice::message("count query failed".into()))).await;
+                                }
+                            }
                         }
                     },
                     Err(Error::ConnError) => {
-                        debug!("got connection close/error, disconnecting cid: {}, ip: {:?}",cid, conn.ip());
+                        debug!("got connection close/error, disconnecting");
                         break;
                     }
-                    Err(Error::EventMaxLengthError(s)) => {
-                        info!("client sent command larger ({} bytes) than max size (cid: {})", s, cid);
-                        ws_stream.send(make_notice_message(&Notice::message("event exceeded max size".into()))).await.ok();
+                    Err(Error::EventMaxLengthError(s, id)) => {
+                        info!(bytes = s, "client sent command larger than max size");
+                        let notice = match id {
+                            Some(id) => Notice::invalid(id, "event exceeded max size"),
+                            None => Notice::message("event exceeded max size".into()),
+                        };
+                        status.notice_sent(&notice);
+                        metrics.notice_sent(&notice);
+                        outbox.send(make_notice_message(&notice)).await;
+                    },
+                    Err(Error::EventParseError(id, reason)) => {
+                        info!("client sent an event that could not be parsed");
+                        let notice = Notice::invalid(id, &reason);
+                        status.notice_sent(&notice);
+                        metrics.notice_sent(&notice);
+                        outbox.send(make_notice_message(&notice)).await;
+                    },
+                    Err(Error::SubParseError(sub_id, reason)) => {
+                        info!("client sent a subscription that could not be parsed");
+                        outbox.send(make_notice_message(&Notice::closed(sub_id, &reason, EventResultStatus::Invalid))).await;
                     },
                     Err(Error::ProtoParseError) => {
-                        info!("client sent command that could not be parsed (cid: {})", cid);
-                        ws_stream.send(make_notice_message(&Notice::message("could not parse command".into()))).await.ok();
+                        info!("client sent command that could not be parsed");
+                        let notice = Notice::message("could not parse command".into());
+                        status.notice_sent(&notice);
+                        metrics.notice_sent(&notice);
+                        outbox.send(make_notice_message(&notice)).await;
                     },
                     Err(e) => {
-                        info!("got non-fatal error from client (cid: {}, error: {:?}", cid, e);
+                        info!(error = ?e, "got non-fatal error from client");
                     },
                 }
             },
@@ -849,13 +3124,15 @@ async fn nostr_server(
     for (_, stop_tx) in running_queries {
         stop_tx.send(()).ok();
     }
+    for (_, (_, _, stop_tx)) in replays {
+        stop_tx.send(()).ok();
+    }
     info!(
-        "stopping client connection (cid: {}, ip: {:?}, sent: {} events, recv: {} events, connected: {:?})",
-        cid,
-        conn.ip(),
-        client_published_event_count,
-        client_received_event_count,
-        orig_start.elapsed()
+        sent = client_published_event_count,
+        received = client_received_event_count,
+        missed = client_missed_event_count,
+        duration_ms = orig_start.elapsed().as_millis() as u64,
+        "stopping client connection"
     );
 }
 
@@ -866,11 +3143,57 @@ pub struct NostrMetrics {
     pub db_connections: IntGauge,    // database connections in use
     pub write_events: Histogram,     // response time of event writes
     pub sent_events: IntCounterVec,  // count of events sent to clients
+    pub dropped_events: IntCounter,  // count of realtime events dropped for slow clients
+    pub missed_events: IntCounter,   // count of realtime events not delivered to each client by the matcher
+    pub write_queue: IntGauge,       // events waiting for the database writer
     pub connections: IntCounter,     // count of websocket connections
+    pub rejected_connections: IntCounterVec, // count of connections refused by limits
     pub disconnects: IntCounterVec,  // client disconnects
     pub query_aborts: IntCounterVec, // count of queries aborted by server
     pub cmd_req: IntCounter,         // count of REQ commands received
     pub cmd_event: IntCounter,       // count of EVENT commands received
     pub cmd_close: IntCounter,       // count of CLOSE commands received
+    pub cmd_count: IntCounter,       // count of COUNT commands received
+    pub cmd_neg: IntCounter,         // count of NEG-OPEN commands received
+    pub ephemeral_events: IntCounter, // count of ephemeral events broadcast
     pub spams: IntCounterVec,        // count of spams filtered
+    pub rate_limited_events: IntCounterVec, // count of events refused by rate limits
+    pub bans: IntCounterVec,         // count of client addresses banned for abuse
+    pub received_events: IntCounterVec, // count of valid events received, by kind
+    pub rejected_events: IntCounterVec, // count of events refused, by reason
+    pub client_origins: IntCounterVec, // count of websocket connections, by Origin
+    pub client_agents: IntCounterVec, // count of websocket connections, by User-Agent
+    pub verify_cache: IntCounterVec, // count of events found, or not, among those recently verified
+    pub db_vacuums: IntCounter,      // count of online database vacuums
+    pub db_reclaimed_bytes: IntCounter, // bytes released by online vacuums
+    origin_labels: Arc<TopLabels>,
+    agent_labels: Arc<TopLabels>,
+}
+
+impl NostrMetrics {
+    /// Count a valid event from a client.
+    pub fn event_received(&self, event: &Event) {
+        self.received_events
+            .with_label_values(&[&labels::kind_label(event.kind)])
+            .inc();
+    }
+
+    /// Count an event refused, if the notice is a refusal.
+    pub fn notice_sent(&self, notice: &Notice) {
+        if let Notice::EventResult(res) = notice {
+            if !res.status.to_bool() {
+                self.rejected_events.with_label_values(&[res.status.prefix()]).inc();
+            }
+        }
+    }
+
+    /// Count a websocket connection by its client's headers.
+    pub fn client_connected(&self, origin: Option<&str>, user_agent: Option<&str>) {
+        self.client_origins
+            .with_label_values(&[&self.origin_labels.label(origin)])
+            .inc();
+        self.client_agents
+            .with_label_values(&[&self.agent_labels.label(user_agent)])
+            .inc();
+    }
 }
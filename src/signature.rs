@@ -0,0 +1,89 @@
+//! Event signature schemes
+//!
+//! Events are signed with BIP-340 Schnorr signatures over secp256k1.
+//! Verification goes through the [`SignatureScheme`] trait, so that
+//! other schemes can be added alongside it, and so that many
+//! signatures can be checked at once where a scheme can do that
+//! faster than checking them one by one.
+use crate::error::Error::{EventInvalidSignature, EventMalformedPubkey};
+use crate::error::Result;
+use crate::event::SECP;
+use secp256k1::{schnorr, XOnlyPublicKey};
+use std::str::FromStr;
+use std::thread;
+
+/// Fewest signatures checked by each thread of a batch.
+const MIN_THREAD_BATCH: usize = 32;
+
+/// A signature to check: the hash of the signed message, and the
+/// signature and public key in hex.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignedDigest<'a> {
+    pub digest: [u8; 32],
+    pub sig: &'a str,
+    pub pubkey: &'a str,
+}
+
+/// A way of checking event signatures.
+pub trait SignatureScheme: Send + Sync {
+    /// Name of the scheme, for logging.
+    fn name(&self) -> &'static str;
+
+    /// Check one signature.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the public key is malformed, or the
+    /// signature is not valid.
+    fn verify(&self, signed: &SignedDigest) -> Result<()>;
+
+    /// Check many signatures, returning a result for each, in order.
+    /// By default they are checked one by one.
+    fn verify_batch(&self, batch: &[SignedDigest]) -> Vec<Result<()>> {
+        batch.iter().map(|s| self.verify(s)).collect()
+    }
+}
+
+/// BIP-340 Schnorr signatures over secp256k1 (NIP-01).
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Schnorr;
+
+impl SignatureScheme for Schnorr {
+    fn name(&self) -> &'static str {
+        "schnorr-secp256k1"
+    }
+
+    fn verify(&self, signed: &SignedDigest) -> Result<()> {
+        let pubkey = XOnlyPublicKey::from_str(signed.pubkey).map_err(|_| EventMalformedPubkey)?;
+        let sig = schnorr::Signature::from_str(signed.sig).map_err(|_| EventInvalidSignature)?;
+        let msg =
+            secp256k1::Message::from_slice(&signed.digest).map_err(|_| EventInvalidSignature)?;
+        SECP.verify_schnorr(&sig, &msg, &pubkey)
+            .map_err(|_| EventInvalidSignature)
+    }
+
+    /// The secp256k1 library does not offer BIP-340 batch
+    /// verification, so large batches are split across threads.
+    fn verify_batch(&self, batch: &[SignedDigest]) -> Vec<Result<()>> {
+        let threads = thread::available_parallelism().map_or(1, usize::from);
+        let chunk = batch.len().div_ceil(threads).max(MIN_THREAD_BATCH);
+        if batch.len() <= chunk {
+            return batch.iter().map(|s| self.verify(s)).collect();
+        }
+        thread::scope(|scope| {
+            let checking: Vec<_> = batch
+                .chunks(chunk)
+                .map(|part| {
+                    scope.spawn(move || part.iter().map(|s| self.verify(s)).collect::<Vec<_>>())
+                })
+                .collect();
+            checking
+                .into_iter()
+                .flat_map(|c| c.join().expect("signature verification thread"))
+                .collect()
+        })
+    }
+}
+
+/// The scheme events are signed with.
+pub static SCHNORR: Schnorr = Schnorr;
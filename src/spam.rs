@@ -0,0 +1,649 @@
+//! Spam classification of submitted events
+//!
+//! Each [`SpamPolicy`] looks at events on its own terms (content
+//! keywords, patterns, how fast an author posts, repeated content,
+//! linked domains).
+//! The policies configured in `settings.antispam` are chained, and an
+//! event is rejected (or silently dropped) by the first policy that
+//! flags it.
+use crate::config::{Antispam, SpamPolicyKind};
+use crate::event::Event;
+use bitcoin_hashes::{sha256, Hash};
+use regex::{Regex, RegexSet};
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{info, warn};
+
+/// How often to check a rules file for changes.
+const RULES_RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Content shorter than this is not checked for duplicates, since
+/// short replies and reactions are often identical.
+const DUPLICATE_MIN_LENGTH: usize = 32;
+
+/// What a policy decided about an event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    /// Reject the event, with a reason for the client
+    Reject(String),
+    /// Tell the client the event was accepted, but discard it
+    ShadowReject,
+}
+
+/// A way of recognizing spam.
+pub trait SpamPolicy: Send {
+    /// Name for logging
+    fn name(&self) -> &'static str;
+
+    /// Decide whether an event is spam.  Policies may remember
+    /// events they have seen.
+    fn check(&mut self, event: &Event) -> Verdict;
+}
+
+/// Reject events containing any of a list of keywords (ignoring case).
+pub struct KeywordPolicy {
+    keywords: Vec<String>,
+}
+
+impl KeywordPolicy {
+    #[must_use]
+    pub fn new(keywords: &[String]) -> Self {
+        KeywordPolicy {
+            keywords: keywords.iter().map(|k| k.to_lowercase()).collect(),
+        }
+    }
+}
+
+impl SpamPolicy for KeywordPolicy {
+    fn name(&self) -> &'static str {
+        "keywords"
+    }
+
+    fn check(&mut self, event: &Event) -> Verdict {
+        let content = event.content.to_lowercase();
+        if self.keywords.iter().any(|k| content.contains(k.as_str())) {
+            Verdict::Reject("this event maybe spam, we droped it.".to_owned())
+        } else {
+            Verdict::Accept
+        }
+    }
+}
+
+/// Reject events with content matching any of a set of regular
+/// expressions.  The set may be replaced while in use, when a rules
+/// file changes.
+pub struct RegexPolicy {
+    patterns: Arc<RwLock<RegexSet>>,
+}
+
+impl RegexPolicy {
+    /// Build from patterns, skipping (and logging) invalid ones.
+    #[must_use]
+    pub fn new(patterns: &[String]) -> Self {
+        RegexPolicy {
+            patterns: Arc::new(RwLock::new(compile(patterns))),
+        }
+    }
+
+    /// Start a task that loads rules from a file, in addition to the
+    /// given patterns, whenever it changes.
+    pub fn watch(&self, path: PathBuf, patterns: Vec<String>) {
+        let mut rules = RulesFile {
+            path,
+            patterns,
+            modified: None,
+            missing: false,
+        };
+        // the task ends once the policy is dropped, as when settings
+        // are reloaded.
+        let set = Arc::downgrade(&self.patterns);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RULES_RELOAD_INTERVAL);
+            loop {
+                interval.tick().await;
+                let Some(set) = set.upgrade() else {
+                    return;
+                };
+                rules.reload_if_changed(&set);
+            }
+        });
+    }
+}
+
+impl SpamPolicy for RegexPolicy {
+    fn name(&self) -> &'static str {
+        "regex"
+    }
+
+    fn check(&mut self, event: &Event) -> Verdict {
+        if self.patterns.read().unwrap().is_match(&event.content) {
+            Verdict::Reject("content matches a spam pattern".to_owned())
+        } else {
+            Verdict::Accept
+        }
+    }
+}
+
+/// Compile patterns into a set, skipping (and logging) invalid ones.
+fn compile(patterns: &[String]) -> RegexSet {
+    let valid = patterns.iter().filter(|p| match regex::Regex::new(p) {
+        Ok(_) => true,
+        Err(e) => {
+            warn!("ignoring invalid antispam pattern {:?}: {}", p, e);
+            false
+        }
+    });
+    RegexSet::new(valid).unwrap_or_else(|_| RegexSet::empty())
+}
+
+/// Convert a wildcard pattern into a regular expression.  `*` matches
+/// any text, and `?` any one character; the pattern may match
+/// anywhere in the content, ignoring case.
+#[must_use]
+pub fn wildcard_to_regex(wildcard: &str) -> String {
+    let mut re = String::from("(?i)");
+    for c in wildcard.chars() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            c => re.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    re
+}
+
+/// Parse a rules file into regular expressions.  Each line holds one
+/// rule: a regular expression between slashes (`/t\.me\/\w+/`), or
+/// otherwise a wildcard pattern.  Blank lines, and lines starting
+/// with `#`, are ignored.
+#[must_use]
+pub fn parse_rules(text: &str) -> Vec<String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| match l.strip_prefix('/').and_then(|r| r.strip_suffix('/')) {
+            Some(re) => re.to_owned(),
+            None => wildcard_to_regex(l),
+        })
+        .collect()
+}
+
+/// A rules file, and when it was last loaded.
+struct RulesFile {
+    path: PathBuf,
+    /// Patterns from the configuration, always included
+    patterns: Vec<String>,
+    modified: Option<SystemTime>,
+    /// The file could not be read last time
+    missing: bool,
+}
+
+impl RulesFile {
+    /// Replace the pattern set if the file has changed since it was
+    /// last loaded.  Returns true if the set was replaced.
+    fn reload_if_changed(&mut self, set: &RwLock<RegexSet>) -> bool {
+        let modified = match std::fs::metadata(&self.path).and_then(|m| m.modified()) {
+            Ok(m) => m,
+            Err(e) => {
+                // only complain once, until the file reappears.
+                if !self.missing {
+                    warn!("could not read antispam rules {:?}: {}", self.path, e);
+                    self.missing = true;
+                }
+                return false;
+            }
+        };
+        self.missing = false;
+        if self.modified == Some(modified) {
+            return false;
+        }
+        let text = match std::fs::read_to_string(&self.path) {
+            Ok(t) => t,
+            Err(e) => {
+                warn!("could not read antispam rules {:?}: {}", self.path, e);
+                return false;
+            }
+        };
+        let mut patterns = self.patterns.clone();
+        let rules = parse_rules(&text);
+        info!("loaded {} antispam rules from {:?}", rules.len(), self.path);
+        patterns.extend(rules);
+        *set.write().unwrap() = compile(&patterns);
+        self.modified = Some(modified);
+        true
+    }
+}
+
+/// Reject events from authors posting too many within a short time.
+pub struct BurstPolicy {
+    max_events: usize,
+    window: Duration,
+    /// Times of recent events, by author
+    recent: HashMap<String, VecDeque<Instant>>,
+    last_prune: Instant,
+}
+
+impl BurstPolicy {
+    #[must_use]
+    pub fn new(max_events: usize, window: Duration) -> Self {
+        BurstPolicy {
+            max_events,
+            window,
+            recent: HashMap::new(),
+            last_prune: Instant::now(),
+        }
+    }
+
+    fn check_at(&mut self, event: &Event, now: Instant) -> Verdict {
+        let window = self.window;
+        let within = |t: &Instant| now.saturating_duration_since(*t) < window;
+        // forget authors that have been quiet for a whole window.
+        if now.saturating_duration_since(self.last_prune) >= window {
+            self.recent.retain(|_, times| times.back().is_some_and(within));
+            self.last_prune = now;
+        }
+        let times = self.recent.entry(event.pubkey.clone()).or_default();
+        while times.front().is_some_and(|t| !within(t)) {
+            times.pop_front();
+        }
+        if times.len() >= self.max_events {
+            return Verdict::Reject("posting too fast".to_owned());
+        }
+        times.push_back(now);
+        Verdict::Accept
+    }
+}
+
+impl SpamPolicy for BurstPolicy {
+    fn name(&self) -> &'static str {
+        "burst"
+    }
+
+    fn check(&mut self, event: &Event) -> Verdict {
+        self.check_at(event, Instant::now())
+    }
+}
+
+/// Reject events repeating the content of another recent event, from
+/// any author.
+pub struct DuplicatePolicy {
+    window: Duration,
+    /// When each content hash was first seen
+    seen: HashMap<sha256::Hash, Instant>,
+    last_prune: Instant,
+}
+
+impl DuplicatePolicy {
+    #[must_use]
+    pub fn new(window: Duration) -> Self {
+        DuplicatePolicy {
+            window,
+            seen: HashMap::new(),
+            last_prune: Instant::now(),
+        }
+    }
+
+    fn check_at(&mut self, event: &Event, now: Instant) -> Verdict {
+        let window = self.window;
+        let within = |t: &Instant| now.saturating_duration_since(*t) < window;
+        if now.saturating_duration_since(self.last_prune) >= window {
+            self.seen.retain(|_, t| within(t));
+            self.last_prune = now;
+        }
+        let content = event.content.trim();
+        if content.len() < DUPLICATE_MIN_LENGTH {
+            return Verdict::Accept;
+        }
+        let hash = sha256::Hash::hash(content.as_bytes());
+        match self.seen.get(&hash) {
+            Some(t) if within(t) => Verdict::Reject("duplicate content".to_owned()),
+            _ => {
+                self.seen.insert(hash, now);
+                Verdict::Accept
+            }
+        }
+    }
+}
+
+impl SpamPolicy for DuplicatePolicy {
+    fn name(&self) -> &'static str {
+        "duplicate"
+    }
+
+    fn check(&mut self, event: &Event) -> Verdict {
+        self.check_at(event, Instant::now())
+    }
+}
+
+/// Silently drop events from an author repeating content they have
+/// posted recently.  Content is compared ignoring case, whitespace
+/// and punctuation, so small variations still count as repeats.
+pub struct RepeatPolicy {
+    max_repeats: usize,
+    window: Duration,
+    /// Times each author posted each content, by a hash of both
+    recent: HashMap<sha256::Hash, VecDeque<Instant>>,
+    last_prune: Instant,
+}
+
+impl RepeatPolicy {
+    #[must_use]
+    pub fn new(max_repeats: usize, window: Duration) -> Self {
+        RepeatPolicy {
+            max_repeats,
+            window,
+            recent: HashMap::new(),
+            last_prune: Instant::now(),
+        }
+    }
+
+    fn check_at(&mut self, event: &Event, now: Instant) -> Verdict {
+        // replaceable events are expected to be republished.
+        if event.content.trim().len() < DUPLICATE_MIN_LENGTH
+            || event.is_replaceable()
+            || event.is_param_replaceable()
+        {
+            return Verdict::Accept;
+        }
+        let window = self.window;
+        let within = |t: &Instant| now.saturating_duration_since(*t) < window;
+        if now.saturating_duration_since(self.last_prune) >= window {
+            self.recent.retain(|_, times| times.back().is_some_and(within));
+            self.last_prune = now;
+        }
+        let mut key = event.pubkey.clone();
+        key.extend(normalize(&event.content));
+        let times = self.recent.entry(sha256::Hash::hash(key.as_bytes())).or_default();
+        while times.front().is_some_and(|t| !within(t)) {
+            times.pop_front();
+        }
+        if times.len() > self.max_repeats {
+            return Verdict::ShadowReject;
+        }
+        times.push_back(now);
+        Verdict::Accept
+    }
+}
+
+impl SpamPolicy for RepeatPolicy {
+    fn name(&self) -> &'static str {
+        "repeat"
+    }
+
+    fn check(&mut self, event: &Event) -> Verdict {
+        self.check_at(event, Instant::now())
+    }
+}
+
+/// Content reduced to lowercase letters and digits.
+fn normalize(content: &str) -> impl Iterator<Item = char> + '_ {
+    content
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+}
+
+/// Reject (or silently drop) events linking to blocked domains, or
+/// their subdomains, in their content or tags.  Domains on the allow
+/// list are never blocked, so a subdomain may be allowed within a
+/// blocked domain.
+pub struct DomainPolicy {
+    blocked: Vec<String>,
+    allowed: Vec<String>,
+    shadow: bool,
+    url: Regex,
+}
+
+impl DomainPolicy {
+    #[must_use]
+    pub fn new(blocked: &[String], allowed: &[String], shadow: bool) -> Self {
+        let domains = |ds: &[String]| -> Vec<String> {
+            ds.iter()
+                .map(|d| d.trim().trim_matches('.').to_lowercase())
+                .filter(|d| !d.is_empty())
+                .collect()
+        };
+        DomainPolicy {
+            blocked: domains(blocked),
+            allowed: domains(allowed),
+            shadow,
+            url: Regex::new(r#"(?i)\b[a-z][a-z0-9+.-]*://([^\s/?#<>"'()\[\]]+)"#).unwrap(),
+        }
+    }
+
+    /// Hosts of the URLs in some text.
+    fn hosts<'a>(&'a self, text: &'a str) -> impl Iterator<Item = String> + 'a {
+        self.url.captures_iter(text).filter_map(|c| {
+            let authority = c.get(1)?.as_str();
+            // drop any user info and port.
+            let host = authority.rsplit('@').next()?;
+            let host = host.split(':').next()?;
+            Some(host.trim_end_matches('.').to_lowercase())
+        })
+    }
+
+    fn is_blocked(&self, host: &str) -> bool {
+        let covers = |d: &String| host == d || host.strip_suffix(d.as_str()).is_some_and(|h| h.ends_with('.'));
+        self.blocked.iter().any(covers) && !self.allowed.iter().any(covers)
+    }
+}
+
+impl SpamPolicy for DomainPolicy {
+    fn name(&self) -> &'static str {
+        "domains"
+    }
+
+    fn check(&mut self, event: &Event) -> Verdict {
+        let tag_values = event.tags.iter().flat_map(|t| t.iter().skip(1));
+        let blocked = std::iter::once(&event.content)
+            .chain(tag_values)
+            .flat_map(|text| self.hosts(text))
+            .any(|host| self.is_blocked(&host));
+        match blocked {
+            false => Verdict::Accept,
+            true if self.shadow => Verdict::ShadowReject,
+            true => Verdict::Reject("links to a blocked domain".to_owned()),
+        }
+    }
+}
+
+/// The chain of configured spam policies.
+#[derive(Default)]
+pub struct SpamFilter {
+    policies: Vec<Box<dyn SpamPolicy>>,
+}
+
+impl SpamFilter {
+    #[must_use]
+    pub fn from_settings(settings: &Antispam) -> Self {
+        let policies = settings
+            .policies()
+            .into_iter()
+            .map(|kind| -> Box<dyn SpamPolicy> {
+                match kind {
+                    SpamPolicyKind::Keywords => Box::new(KeywordPolicy::new(
+                        settings.keywords.as_deref().unwrap_or_default(),
+                    )),
+                    SpamPolicyKind::Regex => {
+                        let mut patterns = settings.patterns.clone().unwrap_or_default();
+                        patterns.extend(settings.wildcards.iter().flatten().map(|w| wildcard_to_regex(w)));
+                        let policy = RegexPolicy::new(&patterns);
+                        if let Some(file) = &settings.rules_file {
+                            policy.watch(PathBuf::from(file), patterns);
+                        }
+                        Box::new(policy)
+                    }
+                    SpamPolicyKind::Burst => Box::new(BurstPolicy::new(
+                        settings.burst_events,
+                        Duration::from_secs(settings.burst_seconds),
+                    )),
+                    SpamPolicyKind::Duplicate => Box::new(DuplicatePolicy::new(
+                        Duration::from_secs(settings.duplicate_seconds),
+                    )),
+                    SpamPolicyKind::Repeat => Box::new(RepeatPolicy::new(
+                        settings.repeat_max,
+                        Duration::from_secs(settings.repeat_seconds),
+                    )),
+                    SpamPolicyKind::Domains => Box::new(DomainPolicy::new(
+                        settings.blocked_domains.as_deref().unwrap_or_default(),
+                        settings.allowed_domains.as_deref().unwrap_or_default(),
+                        settings.shadow_blocked_domains,
+                    )),
+                }
+            })
+            .collect();
+        SpamFilter { policies }
+    }
+
+    /// Add a policy to the end of the chain.
+    pub fn push(&mut self, policy: Box<dyn SpamPolicy>) {
+        self.policies.push(policy);
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.policies.is_empty()
+    }
+
+    /// Check an event against each policy in turn, returning the name
+    /// of the first policy to flag it, and its verdict.  Later
+    /// policies do not see flagged events.
+    pub fn check(&mut self, event: &Event) -> Option<(&'static str, Verdict)> {
+        for p in &mut self.policies {
+            let verdict = p.check(event);
+            if verdict != Verdict::Accept {
+                return Some((p.name(), verdict));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(pubkey: &str, content: &str) -> Event {
+        let mut e = Event::simple_event();
+        e.kind = 1;
+        e.pubkey = pubkey.to_owned();
+        e.content = content.to_owned();
+        e
+    }
+
+    #[test]
+    fn content_policies() {
+        let mut filter = SpamFilter::default();
+        filter.push(Box::new(KeywordPolicy::new(&["Free Money".to_owned()])));
+        filter.push(Box::new(RegexPolicy::new(&[
+            r"t\.me/\w+".to_owned(),
+            "(unclosed".to_owned(),
+        ])));
+        assert_eq!(filter.check(&event("a", "get FREE MONEY now")).unwrap().0, "keywords");
+        assert_eq!(filter.check(&event("a", "join t.me/spam")).unwrap().0, "regex");
+        assert!(filter.check(&event("a", "hello world")).is_none());
+    }
+
+    #[test]
+    fn wildcard_rules() {
+        let rules = parse_rules("# comment\n\n  free * now \n/^gm$/\n");
+        assert_eq!(rules.len(), 2);
+        let set = compile(&rules);
+        assert!(set.is_match("Get FREE bitcoin NOW!"));
+        assert!(set.is_match("gm"));
+        assert!(!set.is_match("gm all"));
+        // regex syntax is literal in wildcards
+        assert!(!compile(&[wildcard_to_regex("a.b")]).is_match("axb"));
+    }
+
+    #[test]
+    fn rules_file_is_reloaded() {
+        let path = std::env::temp_dir().join(format!("antispam-{}.txt", std::process::id()));
+        std::fs::write(&path, "casino*\n").unwrap();
+        let mut rules = RulesFile {
+            path: path.clone(),
+            patterns: vec!["^spam$".to_owned()],
+            modified: None,
+            missing: false,
+        };
+        let set = RwLock::new(RegexSet::empty());
+        assert!(rules.reload_if_changed(&set));
+        assert!(!rules.reload_if_changed(&set));
+        assert!(set.read().unwrap().is_match("CASINO bonus"));
+        assert!(set.read().unwrap().is_match("spam"));
+        // rewrite the file, with a later modification time
+        std::fs::write(&path, "lottery*\n").unwrap();
+        let later = rules.modified.unwrap() + Duration::from_secs(1);
+        std::fs::File::options().write(true).open(&path).unwrap().set_modified(later).unwrap();
+        assert!(rules.reload_if_changed(&set));
+        assert!(!set.read().unwrap().is_match("casino"));
+        assert!(set.read().unwrap().is_match("lottery"));
+        // the last rules are kept if the file is removed
+        std::fs::remove_file(&path).unwrap();
+        assert!(!rules.reload_if_changed(&set));
+        assert!(rules.missing);
+        assert!(set.read().unwrap().is_match("lottery"));
+    }
+
+    #[test]
+    fn authors_posting_in_bursts() {
+        let mut p = BurstPolicy::new(2, Duration::from_secs(10));
+        let start = Instant::now();
+        assert_eq!(p.check_at(&event("a", ""), start), Verdict::Accept);
+        assert_eq!(p.check_at(&event("a", ""), start), Verdict::Accept);
+        assert!(matches!(p.check_at(&event("a", ""), start), Verdict::Reject(_)));
+        assert_eq!(p.check_at(&event("b", ""), start), Verdict::Accept);
+        let later = start + Duration::from_secs(10);
+        assert_eq!(p.check_at(&event("a", ""), later), Verdict::Accept);
+    }
+
+    #[test]
+    fn repeated_content() {
+        let mut p = DuplicatePolicy::new(Duration::from_secs(60));
+        let start = Instant::now();
+        let text = "buy followers at a great price, today only!";
+        assert_eq!(p.check_at(&event("a", text), start), Verdict::Accept);
+        assert!(matches!(p.check_at(&event("b", text), start), Verdict::Reject(_)));
+        // short content may repeat
+        assert_eq!(p.check_at(&event("a", "+"), start), Verdict::Accept);
+        assert_eq!(p.check_at(&event("a", "+"), start), Verdict::Accept);
+        let later = start + Duration::from_secs(60);
+        assert_eq!(p.check_at(&event("b", text), later), Verdict::Accept);
+    }
+
+    #[test]
+    fn repeats_by_one_author() {
+        let mut p = RepeatPolicy::new(1, Duration::from_secs(60));
+        let start = Instant::now();
+        let text = "Check out my new project at example.com!";
+        assert_eq!(p.check_at(&event("a", text), start), Verdict::Accept);
+        assert_eq!(p.check_at(&event("a", text), start), Verdict::Accept);
+        // a second repeat is dropped, even with small changes
+        assert_eq!(p.check_at(&event("a", " check  out my NEW project at example.com!!"), start), Verdict::ShadowReject);
+        assert_eq!(p.check_at(&event("a", "Check out my old project at example.com!"), start), Verdict::Accept);
+        // other authors may post the same content
+        assert_eq!(p.check_at(&event("b", text), start), Verdict::Accept);
+        let later = start + Duration::from_secs(60);
+        assert_eq!(p.check_at(&event("a", text), later), Verdict::Accept);
+    }
+
+    #[test]
+    fn blocked_domains() {
+        let blocked = ["bad.example".to_owned(), "Malware.test.".to_owned()];
+        let allowed = ["ok.bad.example".to_owned()];
+        let mut policy = DomainPolicy::new(&blocked, &allowed, false);
+        let mut check = |content: &str| policy.check(&event("a", content));
+        assert!(matches!(check("see https://cdn.BAD.example/x.jpg"), Verdict::Reject(_)));
+        assert!(matches!(check("http://user@malware.test:8080/"), Verdict::Reject(_)));
+        assert_eq!(check("https://ok.bad.example/x"), Verdict::Accept);
+        assert_eq!(check("https://notbad.example/ and bad.example"), Verdict::Accept);
+        // links in tags are checked too
+        let mut e = event("a", "look");
+        e.tags = vec![vec!["imeta".to_owned(), "url https://bad.example/a.png".to_owned()]];
+        let mut shadow = DomainPolicy::new(&blocked, &[], true);
+        assert_eq!(shadow.check(&e), Verdict::ShadowReject);
+    }
+}
@@ -0,0 +1,80 @@
+//! Connection statistics for clients
+//!
+//! A client may send `["RELAY","STATS"]` to see what the relay has
+//! counted for its own connection, which helps when debugging a
+//! client: events published and received, open subscriptions, and
+//! the rate limits that apply to it.  The relay answers with
+//! `["RELAY","STATS",{...}]`.
+use serde::de::Error as DeError;
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// A relay extension command, `["RELAY",<name>]`
+#[derive(Serialize, PartialEq, Eq, Debug, Clone)]
+pub struct RelayCmd {
+    pub name: String,
+}
+
+impl<'de> Deserialize<'de> for RelayCmd {
+    fn deserialize<D>(deserializer: D) -> Result<RelayCmd, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let (cmd, name) = <(String, String)>::deserialize(deserializer)?;
+        if cmd != "RELAY" {
+            return Err(D::Error::custom("not a RELAY command"));
+        }
+        Ok(RelayCmd { name })
+    }
+}
+
+/// Rate limits that apply to a connection
+#[derive(Serialize, PartialEq, Eq, Debug, Clone)]
+pub struct RateLimits {
+    /// Subscriptions allowed per minute
+    pub subscriptions_per_min: Option<u32>,
+    /// Bytes per second, in each direction
+    pub bytes_per_sec: Option<u32>,
+    /// Events and subscriptions refused for exceeding a rate limit
+    pub refused: u64,
+}
+
+/// Statistics of one connection
+#[derive(Serialize, PartialEq, Eq, Debug, Clone)]
+pub struct ConnectionStats {
+    pub connected_seconds: u64,
+    /// Pubkey the client authenticated as (NIP-42)
+    pub pubkey: Option<String>,
+    pub tier: Option<String>,
+    /// Events the client published
+    pub events_sent: usize,
+    /// Events sent to the client, stored and realtime
+    pub events_received: usize,
+    /// Realtime events the client missed because the relay fell
+    /// behind
+    pub events_missed: u64,
+    /// Ids of the open subscriptions
+    pub subscriptions: Vec<String>,
+    pub max_subscriptions: usize,
+    pub rate_limits: RateLimits,
+}
+
+impl ConnectionStats {
+    /// The reply to a `STATS` command.
+    #[must_use]
+    pub fn to_message(&self) -> String {
+        serde_json::json!(["RELAY", "STATS", self]).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relay_command_parse() {
+        let cmd: RelayCmd = serde_json::from_str(r#"["RELAY","STATS"]"#).unwrap();
+        assert_eq!(cmd.name, "STATS");
+        assert!(serde_json::from_str::<RelayCmd>(r#"["CLOSE","STATS"]"#).is_err());
+        assert!(serde_json::from_str::<RelayCmd>(r#"["RELAY"]"#).is_err());
+    }
+}
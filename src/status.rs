@@ -0,0 +1,302 @@
+//! Relay status, for the admin dashboard
+//!
+//! Recent activity is kept in memory: how many events were received
+//! in each of the last minutes, who wrote them, and the notices and
+//! refusals recently sent to clients.  It starts empty when the relay
+//! starts; prometheus metrics are the place for long-term trends.
+use crate::event::Event;
+use crate::notice::Notice;
+use crate::utils::unix_time;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// Minutes of activity kept.
+const WINDOW_MINUTES: u64 = 60;
+
+/// Refusals and notices kept.
+const MAX_REFUSALS: usize = 100;
+
+/// Events received in one minute
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MinuteCount {
+    /// Start of the minute, in seconds since 1970
+    pub minute: u64,
+    pub events: u64,
+}
+
+/// An author of recent events
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AuthorCount {
+    pub pubkey: String,
+    pub events: u64,
+}
+
+/// A notice, or refused event or subscription, sent to a client
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Refusal {
+    pub time: u64,
+    /// `notice`, `event` or `subscription`
+    pub kind: &'static str,
+    /// Event or subscription id
+    pub id: Option<String>,
+    pub message: String,
+}
+
+#[derive(Default)]
+struct Minute {
+    start: u64,
+    events: u64,
+    authors: HashMap<String, u64>,
+}
+
+#[derive(Default)]
+struct Activity {
+    minutes: VecDeque<Minute>,
+    refusals: VecDeque<Refusal>,
+}
+
+impl Activity {
+    /// The minute containing a time, dropping minutes that are no
+    /// longer kept.
+    fn minute(&mut self, now: u64) -> &mut Minute {
+        let start = now - now % 60;
+        let oldest = start.saturating_sub((WINDOW_MINUTES - 1) * 60);
+        while self.minutes.front().is_some_and(|m| m.start < oldest) {
+            self.minutes.pop_front();
+        }
+        match self.minutes.back() {
+            Some(m) if m.start >= start => {}
+            _ => self.minutes.push_back(Minute {
+                start,
+                ..Minute::default()
+            }),
+        }
+        self.minutes.back_mut().unwrap()
+    }
+}
+
+/// Recent relay activity, shared by all connections.
+#[derive(Default)]
+pub struct RelayStatus {
+    activity: Mutex<Activity>,
+}
+
+impl RelayStatus {
+    #[must_use]
+    pub fn new() -> RelayStatus {
+        RelayStatus::default()
+    }
+
+    /// Count an event received from a client.
+    pub fn event_received(&self, event: &Event) {
+        self.event_received_at(&event.pubkey, unix_time());
+    }
+
+    fn event_received_at(&self, pubkey: &str, now: u64) {
+        let mut activity = self.activity.lock().unwrap();
+        let minute = activity.minute(now);
+        minute.events += 1;
+        *minute.authors.entry(pubkey.to_owned()).or_default() += 1;
+    }
+
+    /// Keep a notice sent to a client, if it is a message or a
+    /// refusal.
+    pub fn notice_sent(&self, notice: &Notice) {
+        self.notice_sent_at(notice, unix_time());
+    }
+
+    fn notice_sent_at(&self, notice: &Notice, now: u64) {
+        let (kind, id, message) = match notice {
+            Notice::Message(msg) => ("notice", None, msg),
+            Notice::EventResult(res) if !res.status.to_bool() => ("event", Some(&res.id), &res.msg),
+            Notice::Closed(res) => ("subscription", Some(&res.id), &res.msg),
+            _ => return,
+        };
+        let mut activity = self.activity.lock().unwrap();
+        if activity.refusals.len() >= MAX_REFUSALS {
+            activity.refusals.pop_front();
+        }
+        activity.refusals.push_back(Refusal {
+            time: now,
+            kind,
+            id: id.cloned(),
+            message: message.clone(),
+        });
+    }
+
+    /// Events received in each recent minute, oldest first.
+    #[must_use]
+    pub fn events_per_minute(&self) -> Vec<MinuteCount> {
+        self.events_per_minute_at(unix_time())
+    }
+
+    fn events_per_minute_at(&self, now: u64) -> Vec<MinuteCount> {
+        let mut activity = self.activity.lock().unwrap();
+        activity.minute(now);
+        activity
+            .minutes
+            .iter()
+            .map(|m| MinuteCount {
+                minute: m.start,
+                events: m.events,
+            })
+            .collect()
+    }
+
+    /// The authors of the most recent events, most active first.
+    #[must_use]
+    pub fn top_authors(&self, count: usize) -> Vec<AuthorCount> {
+        self.top_authors_at(count, unix_time())
+    }
+
+    fn top_authors_at(&self, count: usize, now: u64) -> Vec<AuthorCount> {
+        let mut activity = self.activity.lock().unwrap();
+        activity.minute(now);
+        let mut totals: HashMap<&String, u64> = HashMap::new();
+        for m in &activity.minutes {
+            for (pubkey, n) in &m.authors {
+                *totals.entry(pubkey).or_default() += n;
+            }
+        }
+        let mut authors: Vec<AuthorCount> = totals
+            .into_iter()
+            .map(|(pubkey, events)| AuthorCount {
+                pubkey: pubkey.clone(),
+                events,
+            })
+            .collect();
+        authors.sort_by(|a, b| b.events.cmp(&a.events).then_with(|| a.pubkey.cmp(&b.pubkey)));
+        authors.truncate(count);
+        authors
+    }
+
+    /// Recent notices and refusals, newest first.
+    #[must_use]
+    pub fn refusals(&self) -> Vec<Refusal> {
+        let activity = self.activity.lock().unwrap();
+        activity.refusals.iter().rev().cloned().collect()
+    }
+}
+
+/// The dashboard page.  Its data comes from the status endpoints,
+/// which are requested with HTTP auth events (NIP-98) signed by the
+/// admin's browser extension (NIP-07).
+#[must_use]
+pub fn dashboard_page(base_url: &str) -> String {
+    DASHBOARD.replace("{base}", base_url)
+}
+
+const DASHBOARD: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Relay dashboard</title>
+<style>
+body { font-family: sans-serif; margin: 2em; color: #222; }
+table { border-collapse: collapse; margin-bottom: 2em; }
+td, th { padding: 0.2em 0.8em; text-align: left; border-bottom: 1px solid #ddd; }
+#rate { display: flex; align-items: flex-end; height: 80px; gap: 1px; margin-bottom: 2em; }
+#rate div { background: #4a7; width: 8px; }
+.mono { font-family: monospace; }
+</style>
+</head>
+<body>
+<h1>Relay dashboard</h1>
+<p id="error"></p>
+<table>
+<tr><th>Open connections</th><td id="connections"></td></tr>
+<tr><th>Client addresses</th><td id="addresses"></td></tr>
+<tr><th>Events in the last hour</th><td id="hour"></td></tr>
+<tr><th>Database size</th><td id="disk"></td></tr>
+</table>
+<h2>Events per minute</h2>
+<div id="rate"></div>
+<h2>Top authors (last hour)</h2>
+<table id="authors"></table>
+<h2>Recent notices and refusals</h2>
+<table id="refusals"></table>
+<script>
+const base = "{base}";
+async function get(path) {
+  const url = base + path;
+  const auth = await window.nostr.signEvent({
+    kind: 27235, created_at: Math.floor(Date.now() / 1000),
+    tags: [["u", url], ["method", "GET"]], content: ""
+  });
+  const res = await fetch(url, { headers: { Authorization: "Nostr " + btoa(JSON.stringify(auth)) } });
+  if (!res.ok) throw new Error(path + ": " + await res.text());
+  return res.json();
+}
+function row(cells) {
+  const tr = document.createElement("tr");
+  for (const c of cells) { const td = document.createElement("td"); td.textContent = c; tr.appendChild(td); }
+  return tr;
+}
+async function refresh() {
+  try {
+    if (!window.nostr) throw new Error("a NIP-07 browser extension is needed to sign in");
+    const status = await get("/admin/status");
+    document.getElementById("connections").textContent = status.connections;
+    document.getElementById("addresses").textContent = status.client_addresses;
+    document.getElementById("hour").textContent = status.events_per_minute.reduce((n, m) => n + m.events, 0);
+    document.getElementById("disk").textContent = status.database_bytes === null ? "unknown"
+      : (status.database_bytes / 1048576).toFixed(1) + " MiB";
+    const max = Math.max(1, ...status.events_per_minute.map(m => m.events));
+    document.getElementById("rate").replaceChildren(...status.events_per_minute.map(m => {
+      const bar = document.createElement("div");
+      bar.style.height = (100 * m.events / max) + "%";
+      bar.title = new Date(m.minute * 1000).toLocaleTimeString() + ": " + m.events;
+      return bar;
+    }));
+    const authors = await get("/admin/status/authors");
+    document.getElementById("authors").replaceChildren(...authors.map(a => row([a.pubkey, a.events])));
+    document.getElementById("authors").querySelectorAll("td:first-child").forEach(td => td.className = "mono");
+    const refusals = await get("/admin/status/refusals");
+    document.getElementById("refusals").replaceChildren(...refusals.map(r =>
+      row([new Date(r.time * 1000).toLocaleTimeString(), r.kind, r.id || "", r.message])));
+    document.getElementById("error").textContent = "";
+  } catch (e) {
+    document.getElementById("error").textContent = e.message;
+  }
+}
+refresh();
+setInterval(refresh, 60000);
+</script>
+</body>
+</html>
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn activity_is_counted_by_minute() {
+        let status = RelayStatus::new();
+        let start = 1_700_000_040;
+        status.event_received_at("a", start);
+        status.event_received_at("b", start + 10);
+        status.event_received_at("b", start + 70);
+        let minutes = status.events_per_minute_at(start + 70);
+        assert_eq!(minutes.iter().map(|m| m.events).collect::<Vec<_>>(), vec![2, 1]);
+        let top = status.top_authors_at(1, start + 70);
+        assert_eq!(top, vec![AuthorCount { pubkey: "b".to_owned(), events: 2 }]);
+        // an hour later, the first minute is forgotten
+        let later = status.events_per_minute_at(start + 3600);
+        assert_eq!(later.iter().map(|m| m.events).sum::<u64>(), 1);
+    }
+
+    #[test]
+    fn only_refusals_are_kept() {
+        let status = RelayStatus::new();
+        status.notice_sent_at(&Notice::saved("1".to_owned()), 10);
+        status.notice_sent_at(&Notice::blocked("2".to_owned(), "this pubkey is banned"), 11);
+        status.notice_sent_at(&Notice::message("could not parse command".to_owned()), 12);
+        let refusals = status.refusals();
+        assert_eq!(refusals.len(), 2);
+        assert_eq!(refusals[0].kind, "notice");
+        assert_eq!(refusals[1].id.as_deref(), Some("2"));
+        assert_eq!(refusals[1].message, "blocked: this pubkey is banned");
+    }
+}
@@ -1,6 +1,8 @@
 //! Subscription and filter parsing
+use crate::conn::MAX_SUBSCRIPTION_ID_LEN;
 use crate::error::Result;
-use crate::event::Event;
+use crate::event::{Event, MAX_TAG_NAME_LEN};
+use crate::utils::is_hex;
 use serde::de::Unexpected;
 use serde::ser::SerializeMap;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
@@ -36,6 +38,12 @@ pub struct ReqFilter {
     pub limit: Option<u64>,
     /// Set of tags
     pub tags: Option<HashMap<char, HashSet<String>>>,
+    /// Tags that must have every one of the values (NIP-119)
+    pub and_tags: Option<HashMap<char, HashSet<String>>>,
+    /// Tags with names longer than one letter, such as `title`
+    pub long_tags: Option<HashMap<String, HashSet<String>>>,
+    /// Full-text search query (NIP-50)
+    pub search: Option<String>,
     /// Force no matches due to malformed data
     // we can't represent it in the req filter, so we don't want to
     // erroneously match.  This basically indicates the req tried to
@@ -66,6 +74,9 @@ impl Serialize for ReqFilter {
         if let Some(authors) = &self.authors {
             map.serialize_entry("authors", &authors)?;
         }
+        if let Some(search) = &self.search {
+            map.serialize_entry("search", search)?;
+        }
         // serialize tags
         if let Some(tags) = &self.tags {
             for (k,v) in tags {
@@ -73,6 +84,18 @@ impl Serialize for ReqFilter {
                 map.serialize_entry(&format!("#{k}"), &vals)?;
             }
         }
+        if let Some(tags) = &self.and_tags {
+            for (k,v) in tags {
+                let vals:Vec<&String> = v.iter().collect();
+                map.serialize_entry(&format!("&{k}"), &vals)?;
+            }
+        }
+        if let Some(tags) = &self.long_tags {
+            for (k,v) in tags {
+                let vals:Vec<&String> = v.iter().collect();
+                map.serialize_entry(&format!("#{k}"), &vals)?;
+            }
+        }
         map.end()
     }
 }
@@ -97,10 +120,14 @@ impl<'de> Deserialize<'de> for ReqFilter {
             authors: None,
             limit: None,
             tags: None,
+            and_tags: None,
+            long_tags: None,
+            search: None,
             force_no_match: false,
         };
         let empty_string = "".into();
         let mut ts = None;
+        let mut and_ts: Option<HashMap<char, HashSet<String>>> = None;
         // iterate through each key, and assign values that exist
         for (key, val) in filter {
             // ids
@@ -121,7 +148,11 @@ impl<'de> Deserialize<'de> for ReqFilter {
             } else if key == "until" {
                 rf.until = Deserialize::deserialize(val).ok();
             } else if key == "limit" {
-                rf.limit = Deserialize::deserialize(val).ok();
+                if !val.is_null() {
+                    rf.limit = Some(val.as_u64().ok_or_else(|| {
+                        serde::de::Error::custom("limit must be a non-negative integer")
+                    })?);
+                }
             } else if key == "authors" {
                 let raw_authors: Option<Vec<String>>= Deserialize::deserialize(val).ok();
                 if let Some(a) = raw_authors.as_ref() {
@@ -132,6 +163,10 @@ impl<'de> Deserialize<'de> for ReqFilter {
                     }
                 }
                 rf.authors = raw_authors;
+            } else if key == "search" {
+                let raw_search: Option<String> = Deserialize::deserialize(val).ok();
+                // an empty search is the same as no search at all
+                rf.search = raw_search.filter(|s| !s.trim().is_empty());
             } else if key.starts_with('#') && key.len() > 1 && val.is_array() {
                 if let Some(tag_search) = tag_search_char_from_filter(key) {
                     if ts.is_none() {
@@ -145,18 +180,58 @@ impl<'de> Deserialize<'de> for ReqFilter {
                             m.insert(tag_search.to_owned(), hs);
                         }
                     };
+                } else if key.len() - 1 <= MAX_TAG_NAME_LEN {
+                    // tag search that is multi-character
+                    let tag_vals: Option<Vec<String>> = Deserialize::deserialize(val).ok();
+                    if let Some(v) = tag_vals {
+                        rf.long_tags.get_or_insert_with(HashMap::new).insert(key[1..].to_owned(), v.into_iter().collect());
+                    }
                 } else {
-                    // tag search that is multi-character, don't add to subscription
+                    // such tag names are never indexed
                     rf.force_no_match = true;
                     continue;
                 }
+            } else if key.starts_with('&') && key.len() > 1 && val.is_array() {
+                if let Some(tag_search) = tag_search_char_from_filter(key) {
+                    let tag_vals: Option<Vec<String>> = Deserialize::deserialize(val).ok();
+                    // requiring every one of no values is no requirement.
+                    if let Some(v) = tag_vals.filter(|v| !v.is_empty()) {
+                        and_ts.get_or_insert_with(HashMap::new).insert(tag_search, v.into_iter().collect());
+                    }
+                } else {
+                    rf.force_no_match = true;
+                    continue;
+                }
+            }
+        }
+        // values required by an AND tag are ignored in the OR tag
+        // with the same name.
+        if let (Some(m), Some(and_m)) = (ts.as_mut(), and_ts.as_ref()) {
+            for (c, required) in and_m {
+                if let Some(any) = m.get_mut(c) {
+                    any.retain(|v| !required.contains(v));
+                    if any.is_empty() {
+                        m.remove(c);
+                    }
+                }
             }
         }
         rf.tags = ts;
+        rf.and_tags = and_ts;
+        rf.validate().map_err(serde::de::Error::custom)?;
+        rf.canonicalize();
         Ok(rf)
     }
 }
 
+/// Longest event id or pubkey, in hex
+const MAX_HEX_LEN: usize = 64;
+
+/// Check that ids and authors are hex, no longer than a full value.
+fn valid_hex_prefixes(vals: Option<&Vec<String>>) -> bool {
+    vals.is_none_or(|v| v.iter().all(|s| s.len() <= MAX_HEX_LEN && is_hex(s)))
+}
+
 /// Attempt to form a single-char identifier from a tag search filter
 fn tag_search_char_from_filter(tagname: &str) -> Option<char> {
     let tagname_nohash = &tagname[1..];
@@ -177,6 +252,77 @@ fn tag_search_char_from_filter(tagname: &str) -> Option<char> {
     }
 }
 
+/// Check that a subscription id is not empty, has at most
+/// [`MAX_SUBSCRIPTION_ID_LEN`] characters, and no control characters.
+///
+/// # Errors
+///
+/// Will return `Err` with the reason the id is not valid.
+pub fn validate_sub_id(id: &str) -> std::result::Result<(), &'static str> {
+    if id.is_empty() {
+        return Err("subscription id must not be empty");
+    }
+    if id.chars().count() > MAX_SUBSCRIPTION_ID_LEN {
+        return Err("subscription id is too long");
+    }
+    if id.chars().any(char::is_control) {
+        return Err("subscription id must not contain control characters");
+    }
+    Ok(())
+}
+
+/// Parse a `[<cmd>, <sub_id>, <filter>...]` array into a
+/// subscription identifier and deduplicated filters.
+fn parse_filter_request<'de, D>(
+    deserializer: D,
+    expected_cmd: &str,
+) -> Result<(String, Vec<ReqFilter>), D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let mut v: Value = Deserialize::deserialize(deserializer)?;
+    // this shoud be a 3-or-more element array.
+    // verify the first element is a String, matching the command.
+    // get the subscription from the second element.
+    // convert each of the remaining objects into filters
+
+    // check for array
+    let va = v
+        .as_array_mut()
+        .ok_or_else(|| serde::de::Error::custom("not array"))?;
+
+    // check length
+    if va.len() < 3 {
+        return Err(serde::de::Error::custom("not enough fields"));
+    }
+    let mut i = va.iter_mut();
+    // get command ("REQ") and ensure it is a string
+    let req_cmd_str: serde_json::Value = i.next().unwrap().take();
+    let req = req_cmd_str
+        .as_str()
+        .ok_or_else(|| serde::de::Error::custom("first element of request was not a string"))?;
+    if req != expected_cmd {
+        return Err(serde::de::Error::custom(format!("missing {expected_cmd} command")));
+    }
+
+    // ensure sub id is a string
+    let sub_id_str: serde_json::Value = i.next().unwrap().take();
+    let sub_id = sub_id_str
+        .as_str()
+        .ok_or_else(|| serde::de::Error::custom("missing subscription id"))?;
+    validate_sub_id(sub_id).map_err(serde::de::Error::custom)?;
+
+    let mut filters = vec![];
+    for fv in i {
+        let f: ReqFilter = serde_json::from_value(fv.take())
+            .map_err(|e| serde::de::Error::custom(format!("could not parse filter: {e}")))?;
+        // create indexes
+        filters.push(f);
+    }
+    filters.dedup();
+    Ok((sub_id.to_owned(), filters))
+}
+
 impl<'de> Deserialize<'de> for Subscription {
     /// Custom deserializer for subscriptions, which have a more
     /// complex structure than the other message types.
@@ -184,53 +330,45 @@ impl<'de> Deserialize<'de> for Subscription {
     where
         D: Deserializer<'de>,
     {
-        let mut v: Value = Deserialize::deserialize(deserializer)?;
-        // this shoud be a 3-or-more element array.
-        // verify the first element is a String, REQ
-        // get the subscription from the second element.
-        // convert each of the remaining objects into filters
-
-        // check for array
-        let va = v
-            .as_array_mut()
-            .ok_or_else(|| serde::de::Error::custom("not array"))?;
-
-        // check length
-        if va.len() < 3 {
-            return Err(serde::de::Error::custom("not enough fields"));
-        }
-        let mut i = va.iter_mut();
-        // get command ("REQ") and ensure it is a string
-        let req_cmd_str: serde_json::Value = i.next().unwrap().take();
-        let req = req_cmd_str
-            .as_str()
-            .ok_or_else(|| serde::de::Error::custom("first element of request was not a string"))?;
-        if req != "REQ" {
-            return Err(serde::de::Error::custom("missing REQ command"));
-        }
+        let (id, filters) = parse_filter_request(deserializer, "REQ")?;
+        Ok(Subscription { id, filters })
+    }
+}
 
-        // ensure sub id is a string
-        let sub_id_str: serde_json::Value = i.next().unwrap().take();
-        let sub_id = sub_id_str
-            .as_str()
-            .ok_or_else(|| serde::de::Error::custom("missing subscription id"))?;
-
-        let mut filters = vec![];
-        for fv in i {
-            let f: ReqFilter = serde_json::from_value(fv.take())
-                .map_err(|_| serde::de::Error::custom("could not parse filter"))?;
-            // create indexes
-            filters.push(f);
-        }
-        filters.dedup();
-        Ok(Subscription {
-            id: sub_id.to_owned(),
-            filters,
-        })
+/// Request for the number of events matching a set of filters (NIP-45)
+#[derive(Serialize, PartialEq, Eq, Debug, Clone)]
+pub struct CountCmd {
+    pub id: String,
+    pub filters: Vec<ReqFilter>,
+}
+
+impl<'de> Deserialize<'de> for CountCmd {
+    /// Count requests share the structure of subscriptions, with a
+    /// `COUNT` command.
+    fn deserialize<D>(deserializer: D) -> Result<CountCmd, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let (id, filters) = parse_filter_request(deserializer, "COUNT")?;
+        Ok(CountCmd { id, filters })
     }
 }
 
 impl Subscription {
+    /// Build a subscription from a JSON array of filters, as given to
+    /// HTTP clients that send filters without a `REQ` message.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the filters are not an array of valid
+    /// filters.
+    pub fn from_filters(id: &str, filters_json: &str) -> Result<Subscription> {
+        let filters: Vec<Value> = serde_json::from_str(filters_json)?;
+        let mut req = vec![Value::from("REQ"), Value::from(id)];
+        req.extend(filters);
+        Ok(serde_json::from_value(Value::Array(req))?)
+    }
+
     /// Get a copy of the subscription identifier.
     #[must_use] pub fn get_id(&self) -> String {
         self.id.clone()
@@ -242,6 +380,27 @@ impl Subscription {
         self.filters.iter().any(|f| f.limit!=Some(0))
     }
 
+    /// Copy of the subscription that only asks for events created
+    /// since a time, including from filters that asked for no stored
+    /// events.
+    #[must_use] pub fn since(&self, since: u64) -> Subscription {
+        let mut sub = self.clone();
+        for f in &mut sub.filters {
+            f.since = Some(f.since.map_or(since, |s| s.max(since)));
+            if f.limit == Some(0) {
+                f.limit = None;
+            }
+        }
+        sub
+    }
+
+    /// Lower the limit of every filter to at most `max` events.
+    pub fn clamp_limits(&mut self, max: Option<u64>) {
+        for f in &mut self.filters {
+            f.clamp_limit(max);
+        }
+    }
+
     /// Determine if this subscription matches a given [`Event`].  Any
     /// individual filter match is sufficient.
     #[must_use] pub fn interested_in_event(&self, event: &Event) -> bool {
@@ -265,6 +424,51 @@ fn prefix_match(prefixes: &[String], target: &str) -> bool {
 }
 
 impl ReqFilter {
+    /// Check that a filter is well formed.
+    fn validate(&self) -> std::result::Result<(), &'static str> {
+        if !valid_hex_prefixes(self.ids.as_ref()) {
+            return Err("ids must be hex, of at most 64 characters");
+        }
+        if !valid_hex_prefixes(self.authors.as_ref()) {
+            return Err("authors must be hex, of at most 64 characters");
+        }
+        if matches!((self.since, self.until), (Some(since), Some(until)) if since > until) {
+            return Err("since must not be later than until");
+        }
+        Ok(())
+    }
+
+    /// Put a filter in a canonical form, with lowercase ids and
+    /// authors, and values sorted and deduplicated, so that equal
+    /// filters compare (and are cached) as equal.
+    fn canonicalize(&mut self) {
+        for hexes in [&mut self.ids, &mut self.authors].into_iter().flatten() {
+            for h in hexes.iter_mut() {
+                h.make_ascii_lowercase();
+            }
+            hexes.sort_unstable();
+            hexes.dedup();
+        }
+        if let Some(kinds) = self.kinds.as_mut() {
+            kinds.sort_unstable();
+            kinds.dedup();
+        }
+    }
+
+    /// Lower the limit of a filter to at most `max` events.
+    pub fn clamp_limit(&mut self, max: Option<u64>) {
+        if let (Some(limit), Some(max)) = (self.limit.as_mut(), max) {
+            *limit = (*limit).min(max);
+        }
+    }
+
+    /// Lowercased terms of the search query, if one was provided.
+    #[must_use] pub fn search_terms(&self) -> Option<Vec<String>> {
+        self.search.as_ref().map(|s| {
+            s.split_whitespace().map(str::to_lowercase).collect()
+        })
+    }
+
     fn ids_match(&self, event: &Event) -> bool {
         self.ids
             .as_ref()
@@ -288,6 +492,14 @@ impl ReqFilter {
     }
 
     fn tag_match(&self, event: &Event) -> bool {
+        // every value of an AND tag must be present.
+        if let Some(map) = &self.and_tags {
+            for (key, vals) in map {
+                if !vals.iter().all(|v| event.has_tag_val(*key, v)) {
+                    return false;
+                }
+            }
+        }
         // get the hashset from the filter.
         if let Some(map) = &self.tags {
             for (key, val) in map.iter() {
@@ -299,10 +511,26 @@ impl ReqFilter {
                 // if there was a match, we move on to the next one.
             }
         }
+        if let Some(map) = &self.long_tags {
+            if !map.iter().all(|(key, val)| event.tag_val_intersect(key, val)) {
+                return false;
+            }
+        }
         // if the tag map is empty, the match succeeds (there was no filter)
         true
     }
 
+    /// Check if every search term appears in the event content.
+    fn search_match(&self, event: &Event) -> bool {
+        match self.search_terms() {
+            Some(terms) => {
+                let content = event.content.to_lowercase();
+                terms.iter().all(|t| content.contains(t.as_str()))
+            }
+            None => true,
+        }
+    }
+
     /// Check if this filter either matches, or does not care about the kind.
     fn kind_match(&self, kind: u64) -> bool {
         self.kinds
@@ -310,6 +538,18 @@ impl ReqFilter {
             .map_or(true, |ks| ks.contains(&kind))
     }
 
+    /// Tag conditions of the filter, each a tag name and values of
+    /// which an event must have at least one: the `#` tags, and every
+    /// value of the `&` tags on its own.
+    #[must_use] pub fn tag_conditions(&self) -> Vec<(String, HashSet<String>)> {
+        let any = self.tags.iter().flatten().map(|(c, vals)| (c.to_string(), vals.clone()));
+        let all = self.and_tags.iter().flatten().flat_map(|(c, vals)| {
+            vals.iter().map(|v| (c.to_string(), HashSet::from([v.clone()])))
+        });
+        let long = self.long_tags.iter().flatten().map(|(name, vals)| (name.clone(), vals.clone()));
+        any.chain(all).chain(long).collect()
+    }
+
     /// Determine if all populated fields in this filter match the provided event.
     #[must_use] pub fn interested_in_event(&self, event: &Event) -> bool {
         //        self.id.as_ref().map(|v| v == &event.id).unwrap_or(true)
@@ -319,6 +559,7 @@ impl ReqFilter {
             && self.kind_match(event.kind)
             && (self.authors_match(event) || self.delegated_authors_match(event))
             && self.tag_match(event)
+            && self.search_match(event)
             && !self.force_no_match
     }
 }
@@ -337,6 +578,29 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn subscription_from_filters() -> Result<()> {
+        let s = Subscription::from_filters("sse", r#"[{"kinds":[1]},{"authors":["abc"]}]"#)?;
+        assert_eq!(s.id, "sse");
+        assert_eq!(s.filters.len(), 2);
+        assert_eq!(s.filters[0].kinds, Some(vec![1]));
+        assert!(Subscription::from_filters("sse", "[]").is_err());
+        assert!(Subscription::from_filters("sse", r#"{"kinds":[1]}"#).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn subscription_since() -> Result<()> {
+        let s = Subscription::from_filters("live", r#"[{"kinds":[1],"limit":0},{"since":2000}]"#)?;
+        assert!(!Subscription::from_filters("live", r#"[{"limit":0}]"#)?.needs_historical_events());
+        let replay = s.since(1000);
+        assert_eq!(replay.filters[0].since, Some(1000));
+        assert_eq!(replay.filters[0].limit, None);
+        assert_eq!(replay.filters[1].since, Some(2000));
+        assert!(replay.needs_historical_events());
+        Ok(())
+    }
+
     #[test]
     fn incorrect_header() {
         let raw_json = "[\"REQUEST\",\"some-id\",\"{}\"]";
@@ -367,6 +631,23 @@ mod tests {
         assert!(serde_json::from_str::<Subscription>(raw_json).is_err());
     }
 
+    #[test]
+    fn count_request_parse() -> Result<()> {
+        let raw_json = r#"["COUNT","some-id",{"kinds":[1]},{"authors":["abc"]}]"#;
+        let c: CountCmd = serde_json::from_str(raw_json)?;
+        assert_eq!(c.id, "some-id");
+        assert_eq!(c.filters.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn count_request_is_not_subscription() {
+        let raw_json = r#"["COUNT","some-id",{"kinds":[1]}]"#;
+        assert!(serde_json::from_str::<Subscription>(raw_json).is_err());
+        let raw_json = r#"["REQ","some-id",{"kinds":[1]}]"#;
+        assert!(serde_json::from_str::<CountCmd>(raw_json).is_err());
+    }
+
     #[test]
     fn legacy_filter() {
         // legacy field in filter
@@ -393,14 +674,14 @@ mod tests {
 
     #[test]
     fn author_filter() -> Result<()> {
-        let raw_json = r#"["REQ","some-id",{"authors": ["test-author-id"]}]"#;
+        let raw_json = r#"["REQ","some-id",{"authors": ["abcd1234"]}]"#;
         let s: Subscription = serde_json::from_str(raw_json)?;
         assert_eq!(s.id, "some-id");
         assert_eq!(s.filters.len(), 1);
         let first_filter = s.filters.get(0).unwrap();
         assert_eq!(
             first_filter.authors,
-            Some(vec!("test-author-id".to_owned()))
+            Some(vec!("abcd1234".to_owned()))
         );
         Ok(())
     }
@@ -446,7 +727,7 @@ mod tests {
     #[test]
     fn interest_id_nomatch() -> Result<()> {
         // subscription with a filter for ID
-        let s: Subscription = serde_json::from_str(r#"["REQ","xyz",{"ids": ["xyz"]}]"#)?;
+        let s: Subscription = serde_json::from_str(r#"["REQ","xyz",{"ids": ["fff"]}]"#)?;
         let e = Event {
             id: "abcde".to_owned(),
             pubkey: "".to_owned(),
@@ -623,6 +904,106 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn search_filter_parse() -> Result<()> {
+        let s: Subscription = serde_json::from_str(r#"["REQ","xyz",{"search":"Nostr Relay"},{"search":"  "}]"#)?;
+        assert_eq!(s.filters.len(), 2);
+        assert_eq!(s.filters[0].search, Some("Nostr Relay".to_owned()));
+        assert_eq!(s.filters[0].search_terms(), Some(vec!["nostr".to_owned(), "relay".to_owned()]));
+        // blank searches are ignored
+        assert_eq!(s.filters[1].search, None);
+        Ok(())
+    }
+
+    #[test]
+    fn interest_search() -> Result<()> {
+        let s: Subscription = serde_json::from_str(r#"["REQ","xyz",{"search":"relay nostr"}]"#)?;
+        let mut e = Event {
+            id: "123".to_owned(),
+            pubkey: "abc".to_owned(),
+            delegated_by: None,
+            created_at: 0,
+            kind: 1,
+            tags: Vec::new(),
+            content: "Running a Nostr relay in Rust".to_owned(),
+            sig: "".to_owned(),
+            tagidx: None,
+        };
+        assert!(s.interested_in_event(&e));
+        e.content = "Running a relay in Rust".to_owned();
+        assert!(!s.interested_in_event(&e));
+        Ok(())
+    }
+
+    #[test]
+    fn interest_and_tags() -> Result<()> {
+        let s: Subscription = serde_json::from_str(r##"["REQ","xyz",{"&t": ["meme", "cat"], "#t": ["cat", "dog"]}]"##)?;
+        let f = &s.filters[0];
+        // values required by the AND tag are dropped from the OR tag.
+        assert_eq!(f.tags.as_ref().unwrap()[&'t'], HashSet::from(["dog".to_owned()]));
+        assert_eq!(f.tag_conditions().len(), 3);
+        let mut e = Event::simple_event();
+        e.tags = vec![vec!["t".to_owned(), "meme".to_owned()], vec!["t".to_owned(), "cat".to_owned()]];
+        e.build_index();
+        assert!(!s.interested_in_event(&e));
+        e.tags.push(vec!["t".to_owned(), "dog".to_owned()]);
+        e.build_index();
+        assert!(s.interested_in_event(&e));
+        e.tags.remove(0);
+        e.build_index();
+        assert!(!s.interested_in_event(&e));
+        Ok(())
+    }
+
+    #[test]
+    fn interest_multi_letter_tag() -> Result<()> {
+        let s: Subscription = serde_json::from_str(r##"["REQ","xyz",{"#title": ["nostr"]}]"##)?;
+        assert!(!s.filters[0].force_no_match);
+        let mut e = Event::simple_event();
+        e.tags = vec![vec!["title".to_owned(), "relays".to_owned()]];
+        e.build_index();
+        assert!(!s.interested_in_event(&e));
+        e.tags.push(vec!["title".to_owned(), "nostr".to_owned()]);
+        e.build_index();
+        assert!(s.interested_in_event(&e));
+        // overly long tag names match nothing
+        let long = format!(r##"["REQ","xyz",{{"#{}": ["nostr"]}}]"##, "t".repeat(65));
+        let s: Subscription = serde_json::from_str(&long)?;
+        assert!(s.filters[0].force_no_match);
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_filters_rejected() {
+        let parse = |s: &str| serde_json::from_str::<Subscription>(s);
+        assert!(parse(r#"["REQ","xyz",{"limit":-1}]"#).is_err());
+        assert!(parse(r#"["REQ","xyz",{"ids":["xyz"]}]"#).is_err());
+        assert!(parse(&format!(r#"["REQ","xyz",{{"authors":["{}"]}}]"#, "a".repeat(65))).is_err());
+        assert!(parse(r#"["REQ","xyz",{"since":20,"until":10}]"#).is_err());
+        assert!(parse(r#"["REQ","xyz",{"limit":null,"since":10,"until":10}]"#).is_ok());
+    }
+
+    #[test]
+    fn subscription_ids_checked() {
+        let parse = |id: &str| serde_json::from_str::<Subscription>(&serde_json::json!(["REQ", id, {}]).to_string());
+        assert!(parse("").is_err());
+        assert!(parse(&"x".repeat(65)).is_err());
+        assert!(parse("a\nb").is_err());
+        assert!(parse(&"é".repeat(64)).is_ok());
+        assert!(parse(r#"a"\b"#).is_ok());
+    }
+
+    #[test]
+    fn filters_canonicalized() -> Result<()> {
+        let mut s: Subscription = serde_json::from_str(r#"["REQ","xyz",{"authors":["BB","aa","bb"],"kinds":[7,1,7],"limit":500}]"#)?;
+        let f = &s.filters[0];
+        assert_eq!(f.authors, Some(vec!["aa".to_owned(), "bb".to_owned()]));
+        assert_eq!(f.kinds, Some(vec![1, 7]));
+        s.clamp_limits(Some(100));
+        assert_eq!(s.filters[0].limit, Some(100));
+        Ok(())
+    }
+
     #[test]
     fn serialize_filter() -> Result<()> {
         let s: Subscription = serde_json::from_str(r##"["REQ","xyz",{"authors":["abc", "bcd"], "since": 10, "until": 20, "limit":100, "#e": ["foo", "bar"], "#d": ["test"]}]"##)?;
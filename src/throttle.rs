@@ -0,0 +1,75 @@
+//! Bandwidth limits for websocket clients
+//!
+//! Each connection has a token bucket of bytes for the messages it
+//! receives, and another for the messages it is sent, refilled at the
+//! rate for the client's status.  Once a bucket is empty, reading
+//! from (or writing to) the client waits for it to refill.
+use governor::{Quota, RateLimiter};
+use std::num::NonZeroU32;
+use std::sync::{Arc, RwLock};
+
+type DirectLimiter = RateLimiter<
+    governor::state::NotKeyed,
+    governor::state::InMemoryState,
+    governor::clock::DefaultClock,
+>;
+
+/// A byte rate limit, which may be changed while in use.
+#[derive(Default)]
+pub struct Throttle {
+    limiter: RwLock<Option<(NonZeroU32, Arc<DirectLimiter>)>>,
+}
+
+impl Throttle {
+    /// Create a throttle allowing this many bytes per second, or any
+    /// number if not set.
+    #[must_use]
+    pub fn new(bytes_per_sec: Option<u32>) -> Self {
+        let throttle = Throttle::default();
+        throttle.set_rate(bytes_per_sec);
+        throttle
+    }
+
+    /// Change the allowed bytes per second.  The bucket starts full.
+    pub fn set_rate(&self, bytes_per_sec: Option<u32>) {
+        let limiter = bytes_per_sec
+            .and_then(NonZeroU32::new)
+            .map(|rate| (rate, Arc::new(RateLimiter::direct(Quota::per_second(rate)))));
+        *self.limiter.write().unwrap() = limiter;
+    }
+
+    /// Wait until a message of this many bytes may be transferred.
+    /// Messages larger than one second's worth wait for a full bucket.
+    pub async fn consume(&self, bytes: usize) {
+        let limiter = self.limiter.read().unwrap().clone();
+        if let Some((rate, limiter)) = limiter {
+            let bytes = u32::try_from(bytes).unwrap_or(u32::MAX).min(rate.get());
+            if let Some(n) = NonZeroU32::new(bytes) {
+                // n never exceeds the burst size.
+                limiter.until_n_ready(n).await.ok();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn transfers_wait_for_the_bucket_to_refill() {
+        let throttle = Throttle::new(Some(1000));
+        let start = Instant::now();
+        // the first second's worth is available immediately.
+        throttle.consume(1000).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+        throttle.consume(200).await;
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        // removing the limit allows any transfer.
+        throttle.set_rate(None);
+        let start = Instant::now();
+        throttle.consume(1_000_000).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+}
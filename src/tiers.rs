@@ -0,0 +1,202 @@
+//! Subscription tiers
+//!
+//! Operators define tiers, such as free, paid and admin, each with its
+//! own publishing rate, event size, allowed kinds and retention.  A
+//! client is assigned once it authenticates (NIP-42), to the first tier
+//! that lists its pubkey, that takes pubkeys which paid for admission,
+//! or that lists the domain of its NIP-05 verification.  Other clients
+//! use the default tier, and clients in no tier use the relay-wide
+//! limits.
+use crate::config::{self, Settings, Tier};
+use crate::event::Event;
+use crate::repo::{EventSummary, NostrRepo};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tracing::warn;
+
+/// Why a tier refused an event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TierRefusal {
+    RateLimited,
+    Kind,
+    TooLarge,
+}
+
+impl TierRefusal {
+    /// Explanation for the client
+    #[must_use]
+    pub fn message(&self) -> &'static str {
+        match self {
+            TierRefusal::RateLimited => "too many events for your tier, slow down",
+            TierRefusal::Kind => "event kind is not allowed for your tier",
+            TierRefusal::TooLarge => "event is too large for your tier",
+        }
+    }
+}
+
+/// Check if a tier allows an event's kind and size.
+///
+/// # Errors
+///
+/// Will return `Err` with the reason the event is refused.
+pub fn check_event(tier: &Tier, event: &Event) -> Result<(), TierRefusal> {
+    if tier.kinds.as_ref().is_some_and(|ks| !ks.iter().any(|k| k.contains(event.kind))) {
+        return Err(TierRefusal::Kind);
+    }
+    if let Some(max) = tier.max_event_bytes {
+        let size = serde_json::to_string(event).map_or(0, |j| j.len());
+        if size > max {
+            return Err(TierRefusal::TooLarge);
+        }
+    }
+    Ok(())
+}
+
+/// Configured tiers, and what is needed to assign clients to them.
+pub struct Tiers {
+    levels: Vec<Arc<Tier>>,
+    default: Option<Arc<Tier>>,
+    verified_users: config::VerifiedUsers,
+    repo: Arc<dyn NostrRepo>,
+}
+
+impl Tiers {
+    /// Load the tiers, if any are configured.
+    #[must_use]
+    pub fn new(settings: &Settings, repo: Arc<dyn NostrRepo>) -> Option<Tiers> {
+        let levels: Vec<Arc<Tier>> = settings.tiers.levels.iter().flatten().cloned().map(Arc::new).collect();
+        if levels.is_empty() {
+            return None;
+        }
+        for t in &levels {
+            if t.max_age.is_some() && t.max_age_duration().is_none() {
+                warn!("ignoring unparseable max_age for tier {:?}: {:?}", t.name, t.max_age);
+            }
+        }
+        let default = settings.tiers.default.as_ref().and_then(|name| {
+            let tier = levels.iter().find(|t| &t.name == name).cloned();
+            if tier.is_none() {
+                warn!("default tier {:?} is not defined", name);
+            }
+            tier
+        });
+        Some(Tiers {
+            levels,
+            default,
+            verified_users: settings.verified_users.clone(),
+            repo,
+        })
+    }
+
+    /// Tier of clients that have not been assigned to another.
+    #[must_use]
+    pub fn default_tier(&self) -> Option<Arc<Tier>> {
+        self.default.clone()
+    }
+
+    /// Find the tier a pubkey belongs to.
+    pub async fn resolve(&self, pubkey: &str) -> Option<Arc<Tier>> {
+        // payments and verifications are only looked up if needed.
+        let mut paid = None;
+        let mut domain = None;
+        for t in &self.levels {
+            if t.pubkeys.iter().flatten().any(|pk| pk == pubkey) {
+                return Some(t.clone());
+            }
+            if t.paid {
+                if paid.is_none() {
+                    paid = Some(self.has_paid(pubkey).await);
+                }
+                if paid == Some(true) {
+                    return Some(t.clone());
+                }
+            }
+            if let Some(domains) = &t.nip05_domains {
+                if domain.is_none() {
+                    domain = Some(self.verified_domain(pubkey).await);
+                }
+                if domain.as_ref().is_some_and(|d| d.as_ref().is_some_and(|d| domains.contains(d))) {
+                    return Some(t.clone());
+                }
+            }
+        }
+        self.default.clone()
+    }
+
+    async fn has_paid(&self, pubkey: &str) -> bool {
+        match self.repo.get_account(pubkey).await {
+            Ok(account) => account.is_some_and(|a| a.admitted),
+            Err(e) => {
+                warn!("could not load account: {:?}", e);
+                false
+            }
+        }
+    }
+
+    async fn verified_domain(&self, pubkey: &str) -> Option<String> {
+        if !self.verified_users.is_active() {
+            return None;
+        }
+        let uv = self.repo.get_latest_user_verification(pubkey).await.ok()?;
+        uv.is_valid(&self.verified_users).then(|| uv.name.domain().to_owned())
+    }
+
+    /// Does any tier limit how long its members' events are kept?
+    #[must_use]
+    pub fn has_retention(&self) -> bool {
+        self.levels.iter().any(|t| t.max_age_duration().is_some())
+    }
+
+    /// The oldest creation time kept for events by the authors of some
+    /// events, as of the given time, for authors whose tier limits
+    /// retention.
+    pub async fn oldest_kept(&self, events: &[EventSummary], now: u64) -> HashMap<String, u64> {
+        let mut oldest = HashMap::new();
+        if !self.has_retention() {
+            return oldest;
+        }
+        let mut seen = HashSet::new();
+        for e in events {
+            if !seen.insert(&e.pubkey) {
+                continue;
+            }
+            if let Some(age) = self.resolve(&e.pubkey).await.and_then(|t| t.max_age_duration()) {
+                oldest.insert(e.pubkey.clone(), now.saturating_sub(age.as_secs()));
+            }
+        }
+        oldest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::KindRange;
+
+    fn tier(kinds: Option<Vec<KindRange>>, max_event_bytes: Option<usize>) -> Tier {
+        Tier {
+            name: "free".to_owned(),
+            pubkeys: None,
+            paid: false,
+            nip05_domains: None,
+            messages_per_sec: None,
+            max_event_bytes,
+            kinds,
+            max_age: None,
+        }
+    }
+
+    #[test]
+    fn kinds_and_sizes_are_checked() {
+        let mut e = Event::simple_event();
+        e.kind = 1;
+        let small = tier(Some(vec![KindRange::Single(1), KindRange::Range([5, 7])]), Some(1000));
+        assert_eq!(check_event(&small, &e), Ok(()));
+        e.kind = 4;
+        assert_eq!(check_event(&small, &e), Err(TierRefusal::Kind));
+        e.kind = 7;
+        e.content = "x".repeat(1000);
+        assert_eq!(check_event(&small, &e), Err(TierRefusal::TooLarge));
+        assert_eq!(check_event(&tier(None, None), &e), Ok(()));
+    }
+}
@@ -0,0 +1,259 @@
+//! TLS termination
+//!
+//! The relay can serve wss:// itself, with a certificate chain and key
+//! read from PEM files.  The files are checked for changes
+//! periodically, so certificates renewed by an ACME client (such as
+//! certbot) are used without restarting the relay.
+use crate::config::Network;
+use crate::error::{Error, Result};
+use hyper::server::accept::Accept;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, info, warn};
+
+/// How often the certificate files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Longest a client may take to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Handshakes completed, but not yet taken by the server.
+const ACCEPT_BACKLOG: usize = 128;
+
+/// A client connection, after the TLS handshake
+pub type TlsConn = TlsStream<TcpStream>;
+
+/// Read a certificate chain and private key from PEM files.
+fn load_key(cert_path: &PathBuf, key_path: &PathBuf) -> Result<CertifiedKey> {
+    let invalid = |what: &str| Error::CustomError(format!("invalid TLS {what}"));
+    let mut certs = BufReader::new(File::open(cert_path)?);
+    let chain: Vec<Certificate> = rustls_pemfile::certs(&mut certs)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if chain.is_empty() {
+        return Err(invalid("certificate"));
+    }
+    let mut keys = BufReader::new(File::open(key_path)?);
+    let key = rustls_pemfile::read_all(&mut keys)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(k)
+            | rustls_pemfile::Item::RSAKey(k)
+            | rustls_pemfile::Item::ECKey(k) => Some(PrivateKey(k)),
+            _ => None,
+        })
+        .ok_or_else(|| invalid("key"))?;
+    let key = sign::any_supported_type(&key).map_err(|_| invalid("key"))?;
+    Ok(CertifiedKey::new(chain, key))
+}
+
+fn modified(path: &PathBuf) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// The current certificate, replaced when its files change.
+pub struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<(Arc<CertifiedKey>, Option<SystemTime>)>,
+}
+
+impl CertResolver {
+    /// Load the certificate and key.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the files cannot be read, or do not hold a
+    /// certificate and a supported private key.
+    pub fn new(cert_path: &str, key_path: &str) -> Result<CertResolver> {
+        let cert_path = PathBuf::from(cert_path);
+        let key_path = PathBuf::from(key_path);
+        let key = load_key(&cert_path, &key_path)?;
+        let loaded = modified(&cert_path).max(modified(&key_path));
+        Ok(CertResolver {
+            cert_path,
+            key_path,
+            current: RwLock::new((Arc::new(key), loaded)),
+        })
+    }
+
+    /// Load the certificate again, if its files have changed since it
+    /// was last loaded.  A renewal that fails to load is logged, and
+    /// the previous certificate is kept.
+    pub fn reload(&self) {
+        let changed = modified(&self.cert_path).max(modified(&self.key_path));
+        if changed <= self.current.read().unwrap().1 {
+            return;
+        }
+        match load_key(&self.cert_path, &self.key_path) {
+            Ok(key) => {
+                info!("loaded renewed TLS certificate from {:?}", self.cert_path);
+                *self.current.write().unwrap() = (Arc::new(key), changed);
+            }
+            Err(e) => warn!("could not load renewed TLS certificate: {:?}", e),
+        }
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().0.clone())
+    }
+}
+
+/// TLS settings for the relay, if a certificate and key are
+/// configured.
+///
+/// # Errors
+///
+/// Will return `Err` if only one of the certificate and key is set,
+/// or they cannot be loaded.
+pub fn resolver_for(network: &Network) -> Result<Option<Arc<CertResolver>>> {
+    match (&network.tls_cert, &network.tls_key) {
+        (Some(cert), Some(key)) => Ok(Some(Arc::new(CertResolver::new(cert, key)?))),
+        (None, None) => Ok(None),
+        _ => Err(Error::CustomError("TLS requires both tls_cert and tls_key".to_owned())),
+    }
+}
+
+/// Periodically pick up renewed certificates.
+pub async fn reload_certs(resolver: Arc<CertResolver>, mut shutdown: tokio::sync::broadcast::Receiver<()>) {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(RELOAD_INTERVAL) => resolver.reload(),
+            _ = shutdown.recv() => return,
+        }
+    }
+}
+
+/// Connections accepted over TLS, for a hyper server.  Handshakes
+/// run in their own tasks, so a slow client does not hold up others.
+pub struct TlsIncoming {
+    rx: mpsc::Receiver<TlsConn>,
+}
+
+impl TlsIncoming {
+    /// Accept connections on a bound socket.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the socket cannot be used with the runtime.
+    pub fn new(listener: std::net::TcpListener, resolver: Arc<CertResolver>) -> Result<TlsIncoming> {
+        let listener = TcpListener::from_std(listener)?;
+        let mut config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(resolver);
+        // websockets are upgraded from HTTP/1.1
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let (tx, rx) = mpsc::channel(ACCEPT_BACKLOG);
+        tokio::spawn(async move {
+            loop {
+                let (stream, remote) = match listener.accept().await {
+                    Ok(s) => s,
+                    Err(e) => {
+                        warn!("could not accept connection: {:?}", e);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+                // the server has stopped taking connections.
+                if tx.is_closed() {
+                    return;
+                }
+                let acceptor = acceptor.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(tls)) => {
+                            tx.send(tls).await.ok();
+                        }
+                        Ok(Err(e)) => debug!("TLS handshake with {} failed: {:?}", remote, e),
+                        Err(_) => debug!("TLS handshake with {} timed out", remote),
+                    }
+                });
+            }
+        });
+        Ok(TlsIncoming { rx })
+    }
+}
+
+impl Accept for TlsIncoming {
+    type Conn = TlsConn;
+    type Error = std::io::Error;
+
+    fn poll_accept(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<std::result::Result<Self::Conn, Self::Error>>> {
+        self.rx.poll_recv(cx).map(|c| c.map(Ok))
+    }
+}
+
+/// Address of the client at the other end of a TLS connection.
+#[must_use]
+pub fn remote_addr(conn: &TlsConn) -> Option<SocketAddr> {
+    conn.get_ref().0.peer_addr().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::x509::{X509NameBuilder, X509};
+
+    // write a self-signed certificate and its key, returning the paths
+    fn self_signed(name: &str) -> (String, String) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_text("CN", "relay.example.com").unwrap();
+        let subject = subject.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_subject_name(&subject).unwrap();
+        cert.set_issuer_name(&subject).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        let dir = std::env::temp_dir();
+        let cert_path = dir.join(format!("{name}-{}.crt", std::process::id()));
+        let key_path = dir.join(format!("{name}-{}.key", std::process::id()));
+        std::fs::write(&cert_path, cert.build().to_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        (cert_path.display().to_string(), key_path.display().to_string())
+    }
+
+    #[test]
+    fn certificates_are_loaded() {
+        let (cert, key) = self_signed("tls-load");
+        let mut network = crate::config::Settings::default().network;
+        assert!(resolver_for(&network).unwrap().is_none());
+        network.tls_cert = Some(cert.clone());
+        assert!(resolver_for(&network).is_err());
+        network.tls_key = Some(key.clone());
+        let resolver = resolver_for(&network).unwrap().unwrap();
+        resolver.reload();
+        // a certificate is not a key
+        assert!(CertResolver::new(&cert, &cert).is_err());
+        std::fs::remove_file(cert).ok();
+        std::fs::remove_file(key).ok();
+    }
+}
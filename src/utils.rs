@@ -26,6 +26,13 @@ pub fn nip19_to_hex(s: &str) -> Result<String, bech32::Error> {
     Ok(hex::encode(data))
 }
 
+/// Extract the lower-cased host from a URL, if it can be parsed.
+#[must_use] pub fn host_str(url: &str) -> Option<String> {
+    url.parse::<http::Uri>()
+        .ok()
+        .and_then(|u| u.host().map(str::to_lowercase))
+}
+
 /// Check if a string contains only lower-case hex chars.
 #[must_use] pub fn is_lower_hex(s: &str) -> bool {
     s.chars().all(|x| {
@@ -43,6 +50,13 @@ mod tests {
         assert_eq!(is_lower_hex(hexstr), true);
     }
 
+    #[test]
+    fn url_host() {
+        assert_eq!(host_str("wss://Relay.Example.com/"), Some("relay.example.com".to_owned()));
+        assert_eq!(host_str("ws://localhost:8080"), Some("localhost".to_owned()));
+        assert_eq!(host_str("not a url"), None);
+    }
+
     #[test]
     fn nip19() {
         let hexkey = "3bf0c63fcb93463407af97a5e5ee64fa883d107ef9e558472c4eb9aaaefa459d";
@@ -0,0 +1,196 @@
+//! Event signature verification
+//!
+//! Checking event ids and signatures is the most CPU intensive part of
+//! accepting an event.  It is done by a pool of dedicated threads,
+//! instead of the task of the connection the event arrived on, so a
+//! client sending many events does not occupy a tokio worker thread.
+//! Each thread takes a batch of the waiting events at a time.
+//!
+//! The same event often arrives from several clients or upstream
+//! relays.  Recently verified events are remembered, and a copy with
+//! the same signature and contents is accepted without checking it
+//! again.
+use crate::error::{Error, Result};
+use crate::event::{EventCmd, EventWrapper};
+use prometheus::IntCounterVec;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{info, trace};
+
+/// Most events verified by a thread at a time
+const BATCH_SIZE: usize = 64;
+
+/// Events waiting for verification, for each thread
+const QUEUE_PER_THREAD: usize = 256;
+
+/// Most bytes of verified events remembered
+const CACHE_BYTES: usize = 16 << 20;
+
+type Job = (EventCmd, oneshot::Sender<Result<EventWrapper>>);
+
+/// A verified event, with what its validity depends on
+struct Verified {
+    sig: String,
+    canonical: String,
+}
+
+/// Recently verified events, by id.  The id is the hash of the
+/// canonical serialization, so an event with the same serialization
+/// and signature as a verified one is valid; the oldest are forgotten
+/// first.
+#[derive(Default)]
+struct VerifiedCache {
+    events: HashMap<String, Verified>,
+    order: VecDeque<String>,
+    bytes: usize,
+}
+
+impl VerifiedCache {
+    fn contains(&self, ec: &EventCmd) -> bool {
+        self.events.get(ec.event_id()).is_some_and(|v| {
+            v.sig == ec.sig() && ec.canonical().is_some_and(|c| v.canonical == c)
+        })
+    }
+
+    fn insert(&mut self, id: String, verified: Verified) {
+        if self.events.contains_key(&id) {
+            return;
+        }
+        self.bytes += id.len() + verified.sig.len() + verified.canonical.len();
+        self.order.push_back(id.clone());
+        self.events.insert(id, verified);
+        while self.bytes > CACHE_BYTES {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            if let Some(v) = self.events.remove(&oldest) {
+                self.bytes -= oldest.len() + v.sig.len() + v.canonical.len();
+            }
+        }
+    }
+}
+
+/// Handle for submitting events to the verification threads.  The
+/// threads stop once every handle is dropped.
+#[derive(Clone)]
+pub struct SignatureVerifier {
+    jobs: mpsc::Sender<Job>,
+    cache: Arc<Mutex<VerifiedCache>>,
+    /// Events found, or not, among those recently verified
+    cache_lookups: IntCounterVec,
+}
+
+impl SignatureVerifier {
+    /// Start verification threads.  If `threads` is zero, one is
+    /// started for each available CPU.  Events are counted in
+    /// `cache_lookups` as a `hit` or `miss` of the recently verified.
+    #[must_use]
+    pub fn start(threads: usize, cache_lookups: IntCounterVec) -> Self {
+        let threads = if threads == 0 {
+            std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get)
+        } else {
+            threads
+        };
+        let (jobs, jobs_rx) = mpsc::channel::<Job>(threads * QUEUE_PER_THREAD);
+        let jobs_rx = Arc::new(Mutex::new(jobs_rx));
+        let cache = Arc::new(Mutex::new(VerifiedCache::default()));
+        info!("starting {} signature verification threads", threads);
+        for i in 0..threads {
+            let jobs_rx = jobs_rx.clone();
+            let cache = cache.clone();
+            std::thread::Builder::new()
+                .name(format!("sig-verify-{i}"))
+                .spawn(move || verify_batches(&jobs_rx, &cache))
+                .expect("could not start signature verification thread");
+        }
+        SignatureVerifier {
+            jobs,
+            cache,
+            cache_lookups,
+        }
+    }
+
+    /// Validate an event command, converting it into an event.
+    pub async fn verify(&self, ec: EventCmd) -> Result<EventWrapper> {
+        if ec.is_event() {
+            let cached = self.cache.lock().unwrap().contains(&ec);
+            let result = if cached { "hit" } else { "miss" };
+            self.cache_lookups.with_label_values(&[result]).inc();
+            if cached {
+                return ec.into_verified();
+            }
+        }
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.jobs
+            .send((ec, reply_tx))
+            .await
+            .map_err(|_| Error::CustomError("signature verification stopped".to_owned()))?;
+        reply_rx
+            .await
+            .map_err(|_| Error::CustomError("signature verification stopped".to_owned()))?
+    }
+}
+
+/// Verify batches of events until the verifier is dropped.
+fn verify_batches(jobs_rx: &Mutex<mpsc::Receiver<Job>>, cache: &Mutex<VerifiedCache>) {
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    loop {
+        {
+            // one thread waits for work, and takes whatever else
+            // is queued along with it.
+            let mut rx = jobs_rx.lock().unwrap();
+            let Some(job) = rx.blocking_recv() else {
+                break;
+            };
+            batch.push(job);
+            while batch.len() < BATCH_SIZE {
+                match rx.try_recv() {
+                    Ok(job) => batch.push(job),
+                    Err(_) => break,
+                }
+            }
+        }
+        trace!("verifying {} events", batch.len());
+        for (ec, reply_tx) in batch.drain(..) {
+            let verified = ec.is_event().then(|| {
+                let canonical = ec.canonical().map(|c| c.into_owned()).unwrap_or_default();
+                (ec.event_id().to_owned(), Verified { sig: ec.sig().to_owned(), canonical })
+            });
+            let result = Result::<EventWrapper>::from(ec);
+            if let (Ok(_), Some((id, verified))) = (&result, verified) {
+                cache.lock().unwrap().insert(id, verified);
+            }
+            reply_tx.send(result).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::Event;
+    use prometheus::Opts;
+
+    fn lookups() -> IntCounterVec {
+        IntCounterVec::new(Opts::new("lookups", "lookups"), &["result"]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn events_are_verified() {
+        let raw_json = r#"{"id":"1384757da583e6129ce831c3d7afc775a33a090578f888dd0d010328ad047d0c","pubkey":"bbbd9711d357df4f4e498841fd796535c95c8e751fa35355008a911c41265fca","created_at":1612650459,"kind":1,"tags":null,"content":"hello world","sig":"59d0cc47ab566e81f72fe5f430bcfb9b3c688cb0093d1e6daa49201c00d28ecc3651468b7938642869ed98c0f1b262998e49a05a6ed056c0d92b193f4e93bc21"}"#;
+        let event: Event = serde_json::from_str(raw_json).unwrap();
+        let lookups = lookups();
+        let verifier = SignatureVerifier::start(2, lookups.clone());
+        let verified = verifier.verify(EventCmd::new(event.clone())).await.unwrap();
+        assert!(matches!(verified, EventWrapper::WrappedEvent(e) if e.id == event.id));
+        // a copy of a verified event is found in the cache
+        assert!(verifier.verify(EventCmd::new(event.clone())).await.is_ok());
+        assert_eq!(lookups.with_label_values(&["hit"]).get(), 1);
+        // but not one with other contents under the same id
+        let mut forged = event;
+        forged.content = "goodbye world".to_owned();
+        assert!(verifier.verify(EventCmd::new(forged)).await.is_err());
+        assert_eq!(lookups.with_label_values(&["miss"]).get(), 2);
+    }
+}
@@ -0,0 +1,163 @@
+//! Pubkey whitelist, stored in the database
+//!
+//! When `authorization.pubkey_whitelist` is set, the pubkeys it lists
+//! are stored in the database, and every stored pubkey may publish.
+//! Admins add and remove pubkeys while the relay runs, with the
+//! `allow` and `disallow` commands or from the command line, without
+//! editing the config file or restarting.  Pubkeys added to the
+//! config file are stored when it is reloaded; removing a pubkey from
+//! the file does not remove it from the database.
+use crate::config::Settings;
+use crate::error::{Error, Result};
+use crate::repo::NostrRepo;
+use crate::utils::{is_lower_hex, is_nip19, nip19_to_hex};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, watch};
+use tracing::{info, warn};
+
+/// How often the whitelist is read from the database, for changes
+/// made from the command line.
+const SYNC_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A pubkey to allow or disallow, in hex or as an npub.
+///
+/// # Errors
+///
+/// Will return `Err` with a message if the value is not a pubkey.
+pub fn parse_pubkey(value: &str) -> std::result::Result<String, String> {
+    if value.len() == 64 && is_lower_hex(value) {
+        Ok(value.to_owned())
+    } else if is_nip19(value) {
+        nip19_to_hex(value).map_err(|_| format!("invalid pubkey: {value}"))
+    } else {
+        Err(format!("not a pubkey: {value}"))
+    }
+}
+
+/// The stored whitelist, and the running settings it is applied to.
+pub struct Whitelist {
+    repo: Arc<dyn NostrRepo>,
+    settings_tx: Arc<watch::Sender<Settings>>,
+}
+
+impl Whitelist {
+    #[must_use]
+    pub fn new(repo: Arc<dyn NostrRepo>, settings_tx: Arc<watch::Sender<Settings>>) -> Self {
+        Whitelist { repo, settings_tx }
+    }
+
+    /// Store the pubkeys listed in the running settings, and replace
+    /// them with every stored pubkey.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the database cannot be read or written.
+    pub async fn seed(&self) -> Result<()> {
+        let listed = self.settings_tx.borrow().authorization.pubkey_whitelist.clone();
+        for pubkey in listed.iter().flatten() {
+            self.repo.set_pubkey_allowed(pubkey, true).await?;
+        }
+        self.refresh().await
+    }
+
+    /// Add a pubkey to the whitelist, or remove it.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the whitelist is not enabled, or the
+    /// database cannot be written.
+    pub async fn set_allowed(&self, pubkey: &str, allowed: bool) -> Result<()> {
+        if self.settings_tx.borrow().authorization.pubkey_whitelist.is_none() {
+            return Err(Error::CustomError("the pubkey whitelist is not enabled".to_owned()));
+        }
+        self.repo.set_pubkey_allowed(pubkey, allowed).await?;
+        self.refresh().await
+    }
+
+    /// Apply the stored whitelist to the running settings, if
+    /// whitelisting is enabled.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the database cannot be read.
+    pub async fn refresh(&self) -> Result<()> {
+        let mut stored = self.repo.get_allowed_pubkeys().await?;
+        stored.sort();
+        self.settings_tx.send_if_modified(|s| match &mut s.authorization.pubkey_whitelist {
+            Some(running) if *running != stored => {
+                *running = stored;
+                true
+            }
+            _ => false,
+        });
+        Ok(())
+    }
+}
+
+/// Keep the running whitelist in step with the database: store
+/// pubkeys added by reloading the config file, and read changes made
+/// from the command line.
+pub async fn sync(whitelist: Arc<Whitelist>, mut shutdown: broadcast::Receiver<()>) {
+    let mut settings_rx = whitelist.settings_tx.subscribe();
+    let listed = |rx: &mut watch::Receiver<Settings>| -> HashSet<String> {
+        let settings = rx.borrow_and_update();
+        settings.authorization.pubkey_whitelist.iter().flatten().cloned().collect()
+    };
+    let mut known = listed(&mut settings_rx);
+    let mut interval = tokio::time::interval(SYNC_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            Ok(()) = settings_rx.changed() => {
+                let running = listed(&mut settings_rx);
+                let added: Vec<&String> = running.difference(&known).collect();
+                if !added.is_empty() {
+                    info!("storing {} pubkey(s) added to the whitelist", added.len());
+                }
+                for pubkey in added {
+                    if let Err(e) = whitelist.repo.set_pubkey_allowed(pubkey, true).await {
+                        warn!("could not store whitelisted pubkey: {:?}", e);
+                    }
+                }
+                known = running;
+            },
+            _ = shutdown.recv() => return,
+        }
+        if whitelist.settings_tx.borrow().authorization.pubkey_whitelist.is_none() {
+            continue;
+        }
+        if let Err(e) = whitelist.refresh().await {
+            warn!("could not load the pubkey whitelist: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::memory::MemoryRepo;
+    use crate::server::create_metrics;
+
+    #[tokio::test]
+    async fn whitelist_is_stored() {
+        let (_, metrics) = create_metrics();
+        let repo = Arc::new(MemoryRepo::new(&Settings::default(), metrics));
+        repo.set_pubkey_allowed("b", true).await.unwrap();
+        let mut settings = Settings::default();
+        let (settings_tx, _) = watch::channel(settings.clone());
+        let whitelist = Whitelist::new(repo.clone(), Arc::new(settings_tx));
+        // stored pubkeys are not used unless whitelisting is enabled
+        whitelist.seed().await.unwrap();
+        assert!(whitelist.set_allowed("c", true).await.is_err());
+        assert_eq!(whitelist.settings_tx.borrow().authorization.pubkey_whitelist, None);
+        settings.authorization.pubkey_whitelist = Some(vec!["a".to_owned()]);
+        whitelist.settings_tx.send_replace(settings);
+        whitelist.seed().await.unwrap();
+        whitelist.set_allowed("c", true).await.unwrap();
+        whitelist.set_allowed("b", false).await.unwrap();
+        let running = whitelist.settings_tx.borrow().authorization.pubkey_whitelist.clone();
+        assert_eq!(running, Some(vec!["a".to_owned(), "c".to_owned()]));
+    }
+}
@@ -7,4 +7,55 @@ mod tests {
         use clap::CommandFactory;
         CLIArgs::command().debug_assert();
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn migrate_args() {
+        use clap::Parser;
+        use nostr_rs_relay::cli::Commands;
+        let args = CLIArgs::try_parse_from(["nostr-rs-relay", "migrate", "--from", "sqlite", "--to", "postgres"]).unwrap();
+        assert_eq!(
+            args.command,
+            Some(Commands::Migrate {
+                from: "sqlite".to_owned(),
+                to: "postgres".to_owned()
+            })
+        );
+        assert!(CLIArgs::try_parse_from(["nostr-rs-relay", "migrate", "--from", "postgres", "--to", "sqlite"]).is_err());
+        assert!(CLIArgs::try_parse_from(["nostr-rs-relay"]).unwrap().command.is_none());
+    }
+
+    #[test]
+    fn export_args() {
+        use clap::Parser;
+        use nostr_rs_relay::cli::Commands;
+        let args = CLIArgs::try_parse_from([
+            "nostr-rs-relay", "export", "--since", "100", "--kinds", "1,7", "--output", "events.jsonl",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.command,
+            Some(Commands::Export {
+                since: Some(100),
+                until: None,
+                kinds: Some(vec![1, 7]),
+                output: "events.jsonl".to_owned()
+            })
+        );
+        assert!(CLIArgs::try_parse_from(["nostr-rs-relay", "export"]).is_err());
+    }
+
+    #[test]
+    fn import_args() {
+        use clap::Parser;
+        use nostr_rs_relay::cli::Commands;
+        let args = CLIArgs::try_parse_from(["nostr-rs-relay", "import", "--skip-verify"]).unwrap();
+        assert_eq!(
+            args.command,
+            Some(Commands::Import {
+                input: None,
+                skip_verify: true,
+                batch_size: 1000
+            })
+        );
+    }
+}
@@ -29,7 +29,7 @@ pub fn start_relay() -> Result<Relay> {
     let port = get_available_port().unwrap();
     info!("Found open port: {}", port);
     // bind to local interface only
-    settings.network.address = "127.0.0.1".to_owned();
+    settings.network.address = config::Addresses::One("127.0.0.1".to_owned());
     settings.network.port = port;
     // create an in-memory DB with multiple readers
     settings.database.in_memory = true;
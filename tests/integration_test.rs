@@ -1,5 +1,10 @@
 use anyhow::Result;
 
+use nostr_rs_relay::config;
+use nostr_rs_relay::event::Event;
+use nostr_rs_relay::relay::{Relay, SubscriptionMessage};
+use nostr_rs_relay::subscription::Subscription;
+use secp256k1::{KeyPair, Secp256k1, XOnlyPublicKey};
 use std::thread;
 use std::time::Duration;
 
@@ -45,3 +50,97 @@ async fn relay_home_page() -> Result<()> {
     let _res = relay.shutdown_tx.send(());
     Ok(())
 }
+
+#[tokio::test]
+async fn embedded_relay() -> Result<()> {
+    let _trace_sub = tracing_subscriber::fmt::try_init();
+    let dir = std::env::temp_dir().join(format!("nostr-rs-relay-embedded-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let secp = Secp256k1::new();
+    let keypair = KeyPair::new(&secp, &mut secp256k1::rand::thread_rng());
+    let mut settings = config::Settings::default();
+    settings.database.data_directory = dir.to_string_lossy().into_owned();
+    settings.identity.secret_key = Some("11".repeat(32));
+    settings.authorization.pubkey_whitelist = Some(vec![XOnlyPublicKey::from_keypair(&keypair).to_string()]);
+    let relay = Relay::builder().settings(settings).listen(false).start()?;
+    let stored = Event::new_signed(&keypair, 1, vec![], "stored".to_owned());
+    assert!(relay.submit(stored.clone()).await?.status.to_bool());
+    // stored events are sent first, then new ones.  The stored event
+    // may also still be on its way to subscribers as a new one.
+    let filters = format!(r#"[{{"authors":["{}"]}}]"#, stored.pubkey);
+    let mut sub = relay.subscribe(Subscription::from_filters("embedded", &filters)?);
+    match sub.recv().await {
+        Some(SubscriptionMessage::Event(e)) => assert_eq!(e.id, stored.id),
+        other => panic!("expected the stored event, got {other:?}"),
+    }
+    let new = Event::new_signed(&keypair, 1, vec![], "new".to_owned());
+    relay.submit(new.clone()).await?;
+    let (mut eose, mut received) = (false, false);
+    while !(eose && received) {
+        match sub.recv().await {
+            Some(SubscriptionMessage::Eose) => eose = true,
+            Some(SubscriptionMessage::Event(e)) if e.id == stored.id => {}
+            Some(SubscriptionMessage::Event(e)) if e.id == new.id => received = true,
+            other => panic!("expected the new event, got {other:?}"),
+        }
+    }
+    // events must be signed
+    let mut forged = Event::new_signed(&keypair, 1, vec![], "forged".to_owned());
+    forged.content = "changed".to_owned();
+    assert!(relay.submit(forged.clone()).await.is_err());
+    // unless injected by a trusted service
+    assert!(relay.inject(forged).await?.status.to_bool());
+    // the relay publishes as itself, though it is not whitelisted
+    let identity = relay.identity().expect("the relay has a keypair");
+    let id = identity.announce("hello").await?;
+    let filters = format!(r#"[{{"ids":["{id}"]}}]"#);
+    let mut sub = relay.subscribe(Subscription::from_filters("announced", &filters)?);
+    match sub.recv().await {
+        Some(SubscriptionMessage::Event(e)) => assert_eq!(e.pubkey, identity.pubkey()),
+        other => panic!("expected the announcement, got {other:?}"),
+    }
+    relay.shutdown()?;
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn events_in_one_batch_are_each_charged() -> Result<()> {
+    let _trace_sub = tracing_subscriber::fmt::try_init();
+    let dir = std::env::temp_dir().join(format!("nostr-rs-relay-charged-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let secp = Secp256k1::new();
+    let keypair = KeyPair::new(&secp, &mut secp256k1::rand::thread_rng());
+    let pubkey = XOnlyPublicKey::from_keypair(&keypair).to_string();
+    let mut settings = config::Settings::default();
+    settings.database.data_directory = dir.to_string_lossy().into_owned();
+    settings.database.write_batch_delay_ms = 200;
+    settings.info.relay_url = Some("wss://relay.example.com".to_owned());
+    settings.pay_to_relay.enabled = true;
+    settings.pay_to_relay.admission_cost = 0;
+    settings.pay_to_relay.cost_per_event = 1000;
+    let relay = Relay::builder().settings(settings).listen(false).start()?;
+    // without an account, there is nothing to pay with
+    let first = Event::new_signed(&keypair, 1, vec![], "first".to_owned());
+    assert!(!relay.submit(first).await?.status.to_bool());
+    // enough balance for one event
+    let db = rusqlite::Connection::open(dir.join("nostr.db"))?;
+    db.execute(
+        "INSERT INTO account (pubkey, admitted, balance_msat, created_at) VALUES (?, 1, 1000, 0);",
+        [hex::decode(&pubkey)?],
+    )?;
+    let events: Vec<Event> = (0..3)
+        .map(|n| Event::new_signed(&keypair, 1, vec![], n.to_string()))
+        .collect();
+    let results = futures::future::join_all(events.into_iter().map(|e| relay.submit(e))).await;
+    let saved = results
+        .into_iter()
+        .filter(|r| r.as_ref().is_ok_and(|r| r.status.to_bool()))
+        .count();
+    assert_eq!(saved, 1);
+    let balance: i64 = db.query_row("SELECT balance_msat FROM account;", [], |r| r.get(0))?;
+    assert_eq!(balance, 0);
+    relay.shutdown()?;
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}